@@ -16,6 +16,7 @@ use super::event::{
     PageFreeBatched,
 };
 use super::event::{RssStat, PercpuAlloc, PercpuFree, AddToPageCache, RemoveFromPageCache};
+use super::event::{MMap, MMapRet, MUnmap, MUnmapRet, Brk, BrkRet};
 
 pub struct Client {
     stream: UnixStream,
@@ -113,6 +114,12 @@ pub enum EventKind {
     PercpuFree(PercpuFree),
     AddToPageCache(AddToPageCache),
     RemoveFromPageCache(RemoveFromPageCache),
+    MMap(MMap),
+    MMapRet(MMapRet),
+    MUnmap(MUnmap),
+    MUnmapRet(MUnmapRet),
+    Brk(Brk),
+    BrkRet(BrkRet),
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -287,6 +294,24 @@ impl Event {
             x if Some(x) == RemoveFromPageCache::DISCRIMINANT => {
                 (EventKind::RemoveFromPageCache(RemoveFromPageCache::from_slice(slice).ok_or(0)?), RemoveFromPageCache::SIZE)
             },
+            x if Some(x) == MMap::DISCRIMINANT => {
+                (EventKind::MMap(MMap::from_slice(slice).ok_or(0)?), MMap::SIZE)
+            },
+            x if Some(x) == MMapRet::DISCRIMINANT => {
+                (EventKind::MMapRet(MMapRet::from_slice(slice).ok_or(0)?), MMapRet::SIZE)
+            },
+            x if Some(x) == MUnmap::DISCRIMINANT => {
+                (EventKind::MUnmap(MUnmap::from_slice(slice).ok_or(0)?), MUnmap::SIZE)
+            },
+            x if Some(x) == MUnmapRet::DISCRIMINANT => {
+                (EventKind::MUnmapRet(MUnmapRet::from_slice(slice).ok_or(0)?), MUnmapRet::SIZE)
+            },
+            x if Some(x) == Brk::DISCRIMINANT => {
+                (EventKind::Brk(Brk::from_slice(slice).ok_or(0)?), Brk::SIZE)
+            },
+            x if Some(x) == BrkRet::DISCRIMINANT => {
+                (EventKind::BrkRet(BrkRet::from_slice(slice).ok_or(0)?), BrkRet::SIZE)
+            },
             _ => return Err(1),
         };
         let slice = &slice[size..];
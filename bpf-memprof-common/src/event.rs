@@ -44,6 +44,13 @@ pub struct CommonHeader {
     pid: u32,
 }
 
+impl CommonHeader {
+    /// The `common_pid` of the tracepoint is the id of the thread, not of the process
+    pub fn tid(&self) -> u32 {
+        self.pid
+    }
+}
+
 impl Pod for CommonHeader {
     const DISCRIMINANT: Option<u32> = None;
     const SIZE: usize = 0x08;
@@ -451,3 +458,167 @@ impl Pod for RemoveFromPageCache {
         })
     }
 }
+
+#[cfg_attr(feature = "client", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MMap {
+    syscall_nr: i32,
+    pub addr: Hex64,
+    pub len: u64,
+    prot: u64,
+    pub flags: u64,
+    pub fd: u64,
+    off: u64,
+}
+
+impl MMap {
+    const MAP_ANONYMOUS: u64 = 0x20;
+
+    pub fn is_anonymous(&self) -> bool {
+        self.flags & Self::MAP_ANONYMOUS != 0
+    }
+}
+
+impl Pod for MMap {
+    const DISCRIMINANT: Option<u32> = Some(18);
+    const SIZE: usize = 0x38;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        Some(MMap {
+            syscall_nr: i32::from_ne_bytes(TryFrom::try_from(&s[0x00..0x04]).unwrap()),
+            addr: Hex64(u64::from_ne_bytes(TryFrom::try_from(&s[0x08..0x10]).unwrap())),
+            len: u64::from_ne_bytes(TryFrom::try_from(&s[0x10..0x18]).unwrap()),
+            prot: u64::from_ne_bytes(TryFrom::try_from(&s[0x18..0x20]).unwrap()),
+            flags: u64::from_ne_bytes(TryFrom::try_from(&s[0x20..0x28]).unwrap()),
+            fd: u64::from_ne_bytes(TryFrom::try_from(&s[0x28..0x30]).unwrap()),
+            off: u64::from_ne_bytes(TryFrom::try_from(&s[0x30..0x38]).unwrap()),
+        })
+    }
+}
+
+#[cfg_attr(feature = "client", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MMapRet {
+    syscall_nr: i32,
+    pub ret: i64,
+}
+
+impl Pod for MMapRet {
+    const DISCRIMINANT: Option<u32> = Some(19);
+    const SIZE: usize = 0x10;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        Some(MMapRet {
+            syscall_nr: i32::from_ne_bytes(TryFrom::try_from(&s[0x00..0x04]).unwrap()),
+            ret: i64::from_ne_bytes(TryFrom::try_from(&s[0x08..0x10]).unwrap()),
+        })
+    }
+}
+
+#[cfg_attr(feature = "client", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MUnmap {
+    syscall_nr: i32,
+    pub addr: Hex64,
+    pub len: u64,
+}
+
+impl Pod for MUnmap {
+    const DISCRIMINANT: Option<u32> = Some(20);
+    const SIZE: usize = 0x18;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        Some(MUnmap {
+            syscall_nr: i32::from_ne_bytes(TryFrom::try_from(&s[0x00..0x04]).unwrap()),
+            addr: Hex64(u64::from_ne_bytes(TryFrom::try_from(&s[0x08..0x10]).unwrap())),
+            len: u64::from_ne_bytes(TryFrom::try_from(&s[0x10..0x18]).unwrap()),
+        })
+    }
+}
+
+#[cfg_attr(feature = "client", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MUnmapRet {
+    syscall_nr: i32,
+    pub ret: i64,
+}
+
+impl Pod for MUnmapRet {
+    const DISCRIMINANT: Option<u32> = Some(23);
+    const SIZE: usize = 0x10;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        Some(MUnmapRet {
+            syscall_nr: i32::from_ne_bytes(TryFrom::try_from(&s[0x00..0x04]).unwrap()),
+            ret: i64::from_ne_bytes(TryFrom::try_from(&s[0x08..0x10]).unwrap()),
+        })
+    }
+}
+
+#[cfg_attr(feature = "client", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Brk {
+    syscall_nr: i32,
+    pub brk: Hex64,
+}
+
+impl Pod for Brk {
+    const DISCRIMINANT: Option<u32> = Some(21);
+    const SIZE: usize = 0x10;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        Some(Brk {
+            syscall_nr: i32::from_ne_bytes(TryFrom::try_from(&s[0x00..0x04]).unwrap()),
+            brk: Hex64(u64::from_ne_bytes(TryFrom::try_from(&s[0x08..0x10]).unwrap())),
+        })
+    }
+}
+
+#[cfg_attr(feature = "client", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrkRet {
+    syscall_nr: i32,
+    pub ret: Hex64,
+}
+
+impl Pod for BrkRet {
+    const DISCRIMINANT: Option<u32> = Some(22);
+    const SIZE: usize = 0x10;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        Some(BrkRet {
+            syscall_nr: i32::from_ne_bytes(TryFrom::try_from(&s[0x00..0x04]).unwrap()),
+            ret: Hex64(u64::from_ne_bytes(TryFrom::try_from(&s[0x08..0x10]).unwrap())),
+        })
+    }
+}
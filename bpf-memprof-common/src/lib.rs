@@ -10,6 +10,7 @@ pub use self::event::{
     PageFreeBatched,
 };
 pub use self::event::{RssStat, PercpuAlloc, PercpuFree, AddToPageCache, RemoveFromPageCache};
+pub use self::event::{MMap, MMapRet, MUnmap, MUnmapRet, Brk, BrkRet};
 
 #[cfg(feature = "client")]
 mod client;
@@ -36,6 +36,18 @@ pub struct App {
     pub add_to_page_cache: ebpf::ProgRef,
     #[prog("tracepoint/filemap/mm_filemap_delete_from_page_cache")]
     pub remove_from_page_cache: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_enter_mmap")]
+    pub enter_mmap: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_exit_mmap")]
+    pub exit_mmap: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_enter_munmap")]
+    pub enter_munmap: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_exit_munmap")]
+    pub exit_munmap: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_enter_brk")]
+    pub enter_brk: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_exit_brk")]
+    pub exit_brk: ebpf::ProgRef,
 }
 
 #[cfg(feature = "kern")]
@@ -44,6 +56,7 @@ use {
     bpf_memprof_common::{
         KFree, KMAlloc, KMAllocNode, CacheAlloc, CacheAllocNode, CacheFree, PageAlloc, PageFree,
         PageFreeBatched, RssStat, PercpuAlloc, PercpuFree, AddToPageCache, RemoveFromPageCache,
+        MMap, MMapRet, MUnmap, MUnmapRet, Brk, BrkRet,
     },
     ebpf::helpers,
 };
@@ -335,6 +348,38 @@ impl App {
     pub fn remove_from_page_cache(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
         self.output_unconditional::<RemoveFromPageCache>(ctx)
     }
+
+    // /sys/kernel/debug/tracing/events/syscalls/sys_enter_mmap/format
+
+    #[inline(always)]
+    pub fn enter_mmap(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output::<MMap>(ctx, true)
+    }
+
+    #[inline(always)]
+    pub fn exit_mmap(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output::<MMapRet>(ctx, false)
+    }
+
+    #[inline(always)]
+    pub fn enter_munmap(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output::<MUnmap>(ctx, false)
+    }
+
+    #[inline(always)]
+    pub fn exit_munmap(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output::<MUnmapRet>(ctx, false)
+    }
+
+    #[inline(always)]
+    pub fn enter_brk(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output::<Brk>(ctx, true)
+    }
+
+    #[inline(always)]
+    pub fn exit_brk(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output::<BrkRet>(ctx, false)
+    }
 }
 
 #[cfg(feature = "user")]
@@ -402,7 +447,7 @@ fn main() {
     use std::{time::Duration, io, sync::{Arc, atomic::{Ordering, AtomicBool}}};
     use tracing::Level;
     use ebpf::RingBufferRegistry;
-    use tezedge_memprof::{Consumer, StackResolver, server};
+    use tezedge_memprof::{Consumer, StackResolver, Watchdog, WatchdogConfig, server};
     //use passfd::FdPassingExt;
 
     sudo::escalate_if_needed().expect("failed to obtain superuser permission");
@@ -435,7 +480,10 @@ fn main() {
     let resolver = StackResolver::spawn(cli.pid());
 
     // spawn a thread-pool serving http requests, using tokio
-    let server = server::run(cli.reporter(), resolver, cli.pid());
+    let server = server::run(cli.reporter(), resolver.clone(), cli.pid());
+
+    // spawn a thread checking the total allocation against the configured limits
+    let _watchdog = Watchdog::spawn(WatchdogConfig::from_env(), cli.reporter(), resolver);
 
     let mut rb = RingBufferRegistry::default();
     let mut cli = cli;
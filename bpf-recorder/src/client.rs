@@ -3,7 +3,7 @@
 
 use std::{
     convert::TryFrom,
-    io::{self, Write},
+    io::{self, Write, BufRead, BufReader},
     mem,
     net::{SocketAddr, IpAddr},
     os::unix::net::UnixStream,
@@ -17,6 +17,8 @@ pub enum SnifferEvent {
     Data {
         id: EventId,
         data: Vec<u8>,
+        // the bytes the syscall transferred, more than the `data` if the payload is limited
+        length: usize,
         net: bool,
         incoming: bool,
     },
@@ -42,6 +44,9 @@ pub enum SnifferEvent {
     GetFd {
         id: EventId,
     },
+    Reset {
+        id: EventId,
+    },
     Debug {
         id: EventId,
         msg: String,
@@ -75,6 +80,22 @@ pub enum SnifferError {
     },
 }
 
+impl SnifferEvent {
+    pub fn id(&self) -> &EventId {
+        match self {
+            SnifferEvent::Data { id, .. } => id,
+            SnifferEvent::Connect { id, .. } => id,
+            SnifferEvent::Bind { id, .. } => id,
+            SnifferEvent::Listen { id } => id,
+            SnifferEvent::Accept { id, .. } => id,
+            SnifferEvent::Close { id } => id,
+            SnifferEvent::GetFd { id } => id,
+            SnifferEvent::Reset { id } => id,
+            SnifferEvent::Debug { id, .. } => id,
+        }
+    }
+}
+
 impl SnifferError {
     fn code(
         id: EventId,
@@ -143,12 +164,14 @@ impl RingBufferData for SnifferEvent {
         let descriptor = DataDescriptor::try_from(value)
             .map_err(|()| SnifferError::SliceTooShort(value.len()))?;
         let data = &value[mem::size_of::<DataDescriptor>()..];
+        let length = descriptor.length as usize;
         match descriptor.tag {
             DataTag::Write => {
                 SnifferError::data(descriptor.id, descriptor.size, data.len(), false, false).map(
                     |(id, size)| SnifferEvent::Data {
                         id,
                         data: data[..size].to_vec(),
+                        length: length.max(size),
                         net: false,
                         incoming: false,
                     },
@@ -159,6 +182,7 @@ impl RingBufferData for SnifferEvent {
                     |(id, size)| SnifferEvent::Data {
                         id,
                         data: data[..size].to_vec(),
+                        length: length.max(size),
                         net: false,
                         incoming: true,
                     },
@@ -169,6 +193,7 @@ impl RingBufferData for SnifferEvent {
                     |(id, size)| SnifferEvent::Data {
                         id,
                         data: data[..size].to_vec(),
+                        length: length.max(size),
                         net: true,
                         incoming: false,
                     },
@@ -179,6 +204,7 @@ impl RingBufferData for SnifferEvent {
                     |(id, size)| SnifferEvent::Data {
                         id,
                         data: data[..size].to_vec(),
+                        length: length.max(size),
                         net: true,
                         incoming: true,
                     },
@@ -215,6 +241,7 @@ impl RingBufferData for SnifferEvent {
             }),
             DataTag::Close => Ok(SnifferEvent::Close { id: descriptor.id }),
             DataTag::GetFd => Ok(SnifferEvent::GetFd { id: descriptor.id }),
+            DataTag::Reset => Ok(SnifferEvent::Reset { id: descriptor.id }),
             DataTag::Debug => {
                 SnifferError::debug(descriptor.id, descriptor.size, data.len()).map(|(id, size)| {
                     let msg = hex::encode(&data[..size]);
@@ -227,6 +254,7 @@ impl RingBufferData for SnifferEvent {
 
 pub struct BpfModuleClient {
     stream: UnixStream,
+    responses: BufReader<UnixStream>,
 }
 
 impl BpfModuleClient {
@@ -237,8 +265,9 @@ impl BpfModuleClient {
         let stream = UnixStream::connect(path)?;
         let fd = stream.recv_fd()?;
         let rb = RingBuffer::new(fd, 0x8000000)?;
+        let responses = BufReader::new(stream.try_clone()?);
 
-        Ok((BpfModuleClient { stream }, rb))
+        Ok((BpfModuleClient { stream, responses }, rb))
     }
 
     pub fn new_sync<P>(path: P) -> io::Result<(Self, RingBufferSync)>
@@ -248,11 +277,41 @@ impl BpfModuleClient {
         let stream = UnixStream::connect(path)?;
         let fd = stream.recv_fd()?;
         let rb = RingBufferSync::new(fd, 0x8000000)?;
+        let responses = BufReader::new(stream.try_clone()?);
 
-        Ok((BpfModuleClient { stream }, rb))
+        Ok((BpfModuleClient { stream, responses }, rb))
     }
 
     pub fn send_command(&mut self, cmd: Command) -> io::Result<()> {
         self.stream.write_fmt(format_args!("{}\n", cmd))
     }
+
+    /// The monotonic clock of the kernel, as the events are stamped
+    pub fn fetch_time(&mut self) -> io::Result<u64> {
+        self.send_command(Command::FetchTime)?;
+        let mut line = String::new();
+        self.responses.read_line(&mut line)?;
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, format!("bad response: {}", line));
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("time"), Some(nanos)) => nanos.parse().map_err(|_| bad()),
+            _ => Err(bad()),
+        }
+    }
+
+    /// The bytes the threads of the process transferred on the watched connections,
+    /// as the kernel saw them, before the data entered the ring buffer
+    pub fn fetch_counter(&mut self, pid: u32) -> io::Result<u64> {
+        self.send_command(Command::FetchCounter { pid })?;
+        let mut line = String::new();
+        self.responses.read_line(&mut line)?;
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, format!("bad response: {}", line));
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("counter"), Some(p), Some(bytes)) if p == pid.to_string() => {
+                bytes.parse().map_err(|_| bad())
+            },
+            _ => Err(bad()),
+        }
+    }
 }
@@ -8,6 +8,11 @@ mod client;
 #[cfg(feature = "client")]
 pub use self::client::{SnifferEvent, SnifferError, SnifferErrorCode, BpfModuleClient};
 
+#[cfg(feature = "client")]
+mod packet;
+#[cfg(feature = "client")]
+pub use self::packet::{PacketCapture, PacketHeader, tcp_flags};
+
 use core::{fmt, mem, ptr, convert::TryFrom};
 
 #[cfg(feature = "user")]
@@ -35,7 +40,19 @@ impl fmt::Display for SocketId {
 pub enum Command {
     WatchPort { port: u16 },
     IgnoreConnection { pid: u32, fd: u32 },
-    FetchCounter,
+    // the connection established before the recorder started, its data is captured from now on
+    WatchConnection { pid: u32, fd: u32, incoming: bool },
+    // the node which bound its port before the recorder started, found by the discovery
+    WatchProcess { pid: u32, port: u16 },
+    // the bytes the threads of the process transferred on the watched connections,
+    // the response is the line `counter {pid} {bytes}`
+    FetchCounter { pid: u32 },
+    // the monotonic clock the events are stamped with, the response is the line `time {nanos}`,
+    // the recorder cuts over from the old module to the new one at that time
+    FetchTime,
+    // only the first `limit` bytes of each read and write on the connections of the port
+    // are copied after the handshake, 0 is the full payload
+    PayloadLimit { port: u16, limit: u32 },
 }
 
 #[cfg(feature = "user")]
@@ -66,7 +83,59 @@ impl FromStr for Command {
                     .map_err(|e| format!("failed to parse fd: {}", e))?;
                 Ok(Command::IgnoreConnection { pid, fd })
             },
-            Some("fetch_counter") => Ok(Command::FetchCounter),
+            Some("watch_connection") => {
+                let pid = words
+                    .next()
+                    .ok_or_else(|| "bad pid".to_string())?
+                    .parse()
+                    .map_err(|e| format!("failed to parse pid: {}", e))?;
+                let fd = words
+                    .next()
+                    .ok_or_else(|| "bad fd".to_string())?
+                    .parse()
+                    .map_err(|e| format!("failed to parse fd: {}", e))?;
+                let incoming = words
+                    .next()
+                    .ok_or_else(|| "bad direction".to_string())?
+                    .parse()
+                    .map_err(|e| format!("failed to parse direction: {}", e))?;
+                Ok(Command::WatchConnection { pid, fd, incoming })
+            },
+            Some("watch_process") => {
+                let pid = words
+                    .next()
+                    .ok_or_else(|| "bad pid".to_string())?
+                    .parse()
+                    .map_err(|e| format!("failed to parse pid: {}", e))?;
+                let port = words
+                    .next()
+                    .ok_or_else(|| "bad port".to_string())?
+                    .parse()
+                    .map_err(|e| format!("failed to parse port: {}", e))?;
+                Ok(Command::WatchProcess { pid, port })
+            },
+            Some("fetch_counter") => {
+                let pid = words
+                    .next()
+                    .ok_or_else(|| "bad pid".to_string())?
+                    .parse()
+                    .map_err(|e| format!("failed to parse pid: {}", e))?;
+                Ok(Command::FetchCounter { pid })
+            },
+            Some("fetch_time") => Ok(Command::FetchTime),
+            Some("payload_limit") => {
+                let port = words
+                    .next()
+                    .ok_or_else(|| "bad port".to_string())?
+                    .parse()
+                    .map_err(|e| format!("failed to parse port: {}", e))?;
+                let limit = words
+                    .next()
+                    .ok_or_else(|| "bad limit".to_string())?
+                    .parse()
+                    .map_err(|e| format!("failed to parse limit: {}", e))?;
+                Ok(Command::PayloadLimit { port, limit })
+            },
             _ => Err("unexpected command".to_string()),
         }
     }
@@ -77,7 +146,13 @@ impl fmt::Display for Command {
         match self {
             Command::WatchPort { port } => write!(f, "watch_port {}", port),
             Command::IgnoreConnection { pid, fd } => write!(f, "ignore_connection {} {}", pid, fd),
-            Command::FetchCounter => write!(f, "fetch_counter"),
+            Command::WatchConnection { pid, fd, incoming } => {
+                write!(f, "watch_connection {} {} {}", pid, fd, incoming)
+            },
+            Command::WatchProcess { pid, port } => write!(f, "watch_process {} {}", pid, port),
+            Command::FetchCounter { pid } => write!(f, "fetch_counter {}", pid),
+            Command::FetchTime => write!(f, "fetch_time"),
+            Command::PayloadLimit { port, limit } => write!(f, "payload_limit {} {}", port, limit),
         }
     }
 }
@@ -86,32 +161,42 @@ impl fmt::Display for Command {
 pub struct DataDescriptor {
     pub id: EventId,
     pub tag: DataTag,
+    // the bytes copied, or the error code
     pub size: i32,
+    // the bytes the syscall transferred, more than copied if the payload is limited
+    pub length: u64,
 }
 
+/// The timestamps are `ktime_get_ns` at the enter and at the exit of the syscall
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct EventId {
     pub socket_id: SocketId,
-    //ts: Range<u64>,
+    ts_start: u64,
     ts: u64,
 }
 
 impl EventId {
     #[inline(always)]
-    pub fn new(socket_id: SocketId, _ts_start: u64, ts_finish: u64) -> Self {
+    pub fn new(socket_id: SocketId, ts_start: u64, ts_finish: u64) -> Self {
         EventId {
             socket_id,
+            ts_start,
             ts: ts_finish,
         }
     }
 
     pub fn ts_start(&self) -> u64 {
-        0
+        self.ts_start
     }
 
     pub fn ts_finish(&self) -> u64 {
         self.ts
     }
+
+    /// Nanoseconds the syscall took
+    pub fn latency(&self) -> u64 {
+        self.ts.saturating_sub(self.ts_start)
+    }
 }
 
 impl fmt::Display for EventId {
@@ -149,4 +234,7 @@ pub enum DataTag {
 
     GetFd,
     Debug,
+
+    // the read or the write failed with `ECONNRESET`
+    Reset,
 }
@@ -27,6 +27,13 @@ pub struct App {
     pub connections: ebpf::HashMapRef<{ mem::size_of::<SocketId>() }, 4>,
     #[hashmap(size = 0x100)]
     pub syscall_contexts: ebpf::HashMapRef<4, 0x20>,
+    // by the thread id, the pid and the bytes the thread transferred on the watched connections,
+    // each thread has its own counter, so the threads do not race for it
+    #[hashmap(size = 0x1000)]
+    pub counters: ebpf::HashMapRef<4, 16>,
+    // by the port, the bytes of each read and write copied after the handshake
+    #[hashmap(size = 64)]
+    pub limits: ebpf::HashMapRef<2, 4>,
     #[prog("tracepoint/syscalls/sys_enter_bind")]
     pub enter_bind: ebpf::ProgRef,
     #[prog("tracepoint/syscalls/sys_exit_bind")]
@@ -70,6 +77,9 @@ mod send;
 #[cfg(feature = "kern")]
 mod address;
 
+#[cfg(feature = "user")]
+mod probe;
+
 #[cfg(feature = "kern")]
 use {
     core::ptr,
@@ -111,22 +121,55 @@ impl App {
             Some(context) => {
                 let SyscallContext { data, ts: ts0 } = context;
                 let ret = ctx.read_here(0x10);
-                self.on_ret(ret, data, ts0, ts1, pid)
+                self.on_ret(ret, data, ts0, ts1, pid, thread_id)
             },
             None => Err(-1),
         }
     }
 
+    // the first reads and writes of the connection are copied in full, they carry the handshake
+    const FULL_PAYLOAD_EVENTS: u32 = 8;
+
+    // the lower byte of the value of the connection is 1 for outgoing or 2 for incoming,
+    // the upper bytes count the reads and writes, only if the payload of the port is limited
     #[inline(always)]
     fn is_connected(&self, socket_id: SocketId) -> bool {
         if let Some(c) = self.connections.get(&socket_id.to_ne_bytes()) {
-            let c = u32::from_ne_bytes(*c);
+            let c = u32::from_ne_bytes(*c) & 0xff;
             c == 1 || c == 2
         } else {
             false
         }
     }
 
+    // the bytes to copy of the read or the write, `usize::MAX` is the full payload
+    #[inline(always)]
+    fn payload_limit(&mut self, socket_id: SocketId) -> usize {
+        let port = match self.processes.get(&socket_id.pid.to_ne_bytes()) {
+            Some(port) => *port,
+            None => return usize::MAX,
+        };
+        let limit = match self.limits.get(&port) {
+            Some(limit) => u32::from_ne_bytes(*limit),
+            None => return usize::MAX,
+        };
+        if limit == 0 {
+            return usize::MAX;
+        }
+        let key = socket_id.to_ne_bytes();
+        let value = match self.connections.get(&key) {
+            Some(value) => u32::from_ne_bytes(*value),
+            None => return usize::MAX,
+        };
+        let events = value >> 8;
+        if events < Self::FULL_PAYLOAD_EVENTS {
+            let value = (value & 0xff) | ((events + 1) << 8);
+            let _ = self.connections.insert(key, value.to_ne_bytes());
+            return usize::MAX;
+        }
+        limit as usize
+    }
+
     #[inline(always)]
     fn is_process(&self, pid: u32) -> bool {
         self.processes.get(&pid.to_ne_bytes()).is_some()
@@ -147,6 +190,24 @@ impl App {
             .insert(socket_id.to_ne_bytes(), v.to_ne_bytes())
     }
 
+    #[inline(always)]
+    fn count(&mut self, pid: u32, thread_id: u32, bytes: u64) -> Result<(), i32> {
+        let key = thread_id.to_ne_bytes();
+        let pid_bytes = pid.to_ne_bytes();
+        let mut total = [0; 8];
+        if let Some(value) = self.counters.get(&key) {
+            // the thread id reused by another process starts over
+            if value[..4] == pid_bytes {
+                total.copy_from_slice(&value[8..]);
+            }
+        }
+        let total = u64::from_ne_bytes(total) + bytes;
+        let mut value = [0; 16];
+        value[..4].copy_from_slice(&pid_bytes);
+        value[8..].copy_from_slice(&total.to_ne_bytes());
+        self.counters.insert(key, value)
+    }
+
     fn forget_connection(&mut self, socket_id: SocketId) -> Result<(), i32> {
         self.connections.remove(&socket_id.to_ne_bytes())?;
         Ok(())
@@ -215,7 +276,32 @@ impl App {
         ts0: u64,
         ts1: u64,
         pid: u32,
+        thread_id: u32,
     ) -> Result<(), i32> {
+        // the peer reset the connection
+        const ECONNRESET: i64 = -104;
+        let data_fd = match data {
+            SyscallContextData::Write { fd, .. } => Some(fd),
+            SyscallContextData::Send { fd, .. } => Some(fd),
+            SyscallContextData::Read { fd, .. } => Some(fd),
+            SyscallContextData::Recv { fd, .. } => Some(fd),
+            _ => None,
+        };
+        if let (true, Some(_)) = (ret > 0, data_fd) {
+            // counted before the data enters the ring buffer, whatever happens to it next
+            let _ = self.count(pid, thread_id, ret as u64);
+        }
+        if let (ECONNRESET, Some(fd)) = (ret, data_fd) {
+            let id = EventId::new(SocketId { pid, fd }, ts0, ts1);
+            send::sized::<typenum::U0, typenum::B0>(
+                id,
+                DataTag::Reset,
+                ptr::null(),
+                0,
+                &mut self.event_queue,
+            );
+            return Ok(());
+        }
         if ret < 0 {
             // TODO: need a better fix
             // EINPROGRESS
@@ -305,12 +391,15 @@ impl App {
             | SyscallContextData::Send { fd, data_ptr }
             | SyscallContextData::Read { fd, data_ptr }
             | SyscallContextData::Recv { fd, data_ptr } => {
-                let id = EventId::new(SocketId { pid, fd }, ts0, ts1);
+                let socket_id = SocketId { pid, fd };
+                let limit = self.payload_limit(socket_id);
+                let id = EventId::new(socket_id, ts0, ts1);
                 send::dyn_sized::<typenum::B0>(
                     id,
                     data.tag(),
                     data_ptr as *mut u8,
                     ret as usize,
+                    limit,
                     &mut self.event_queue,
                 );
                 Ok(())
@@ -447,8 +536,9 @@ fn main() {
         kind::{AppItemKindMut, AppItem},
     };
     use std::{
+        collections::{BTreeMap, BTreeSet},
         fs,
-        io::{Error, BufReader, BufRead},
+        io::{Error, BufReader, BufRead, Write},
         os::unix::{fs::PermissionsExt, net::UnixListener},
         process,
         str::FromStr,
@@ -461,6 +551,21 @@ fn main() {
     ctrlc::set_handler(move || process::exit(0)).expect("failed to setup ctrl+c handler");
     tracing_subscriber::fmt().with_max_level(Level::INFO).init();
 
+    // `--probe` only checks the kernel
+    let probe = probe::Probe::run();
+    log::info!("{}", probe);
+    let problems = probe.problems();
+    for problem in &problems {
+        log::error!("{}", problem);
+    }
+    if std::env::args().any(|arg| arg == "--probe") {
+        process::exit(if problems.is_empty() { 0 } else { 1 });
+    }
+    if !problems.is_empty() {
+        log::error!("the bpf module cannot run on this kernel, nothing is captured");
+        process::exit(1);
+    }
+
     let socket = "/tmp/bpf-sniffer.sock";
     let _ = fs::remove_file(socket);
     let _ = fs::create_dir("/tmp");
@@ -475,13 +580,13 @@ fn main() {
     static CODE: &[u8] = include_bytes!(concat!("../", env!("BPF_CODE_RECORDER")));
 
     let mut skeleton = Skeleton::<App>::open("bpf-recorder\0", CODE)
-        .unwrap_or_else(|code| panic!("failed to open bpf: {}", code));
+        .unwrap_or_else(|code| panic!("failed to open bpf: {}, {}", code, probe));
     skeleton
         .load()
-        .unwrap_or_else(|code| panic!("failed to load bpf: {}", code));
+        .unwrap_or_else(|code| panic!("failed to load bpf: {}, {}", code, probe));
     skeleton
         .attach()
-        .unwrap_or_else(|code| panic!("failed to attach bpf: {}", code));
+        .unwrap_or_else(|code| panic!("failed to attach bpf: {}, {}", code, probe));
     log::info!("attached bpf module");
 
     let fd = match skeleton.app.event_queue.kind_mut() {
@@ -496,6 +601,11 @@ fn main() {
         .send_fd(fd)
         .expect("failed to send ring buffer access");
 
+    let mut responses = stream
+        .try_clone()
+        .expect("failed to clone the stream for responses");
+    // the threads of each process ever seen, the counter of the thread outlives the thread
+    let mut threads = BTreeMap::<u32, BTreeSet<u32>>::new();
     let stream = BufReader::new(stream);
     for line in stream.lines() {
         // handle line
@@ -504,7 +614,63 @@ fn main() {
                 log::info!("command: {}", line);
                 Command::from_str(&line)
             } {
-                Ok(Command::FetchCounter) => (),
+                Ok(Command::FetchCounter { pid }) => {
+                    let known = threads.entry(pid).or_default();
+                    if let Ok(tasks) = fs::read_dir(format!("/proc/{}/task", pid)) {
+                        known.extend(tasks.filter_map(|task| {
+                            task.ok()?.file_name().to_str()?.parse::<u32>().ok()
+                        }));
+                    }
+                    let pid_bytes = pid.to_ne_bytes();
+                    let mut bytes = 0;
+                    for thread_id in known.iter() {
+                        if let Some(value) = skeleton.app.counters.get(&thread_id.to_ne_bytes()) {
+                            if value[..4] == pid_bytes {
+                                let mut total = [0; 8];
+                                total.copy_from_slice(&value[8..]);
+                                bytes += u64::from_ne_bytes(total);
+                            }
+                        }
+                    }
+                    let response = format!("counter {} {}\n", pid, bytes);
+                    if let Err(error) = responses.write_all(response.as_bytes()) {
+                        tracing::error!("failed to respond, error {}", error);
+                    }
+                },
+                Ok(Command::FetchTime) => {
+                    // the same clock as `ktime_get_ns`
+                    let mut time = libc::timespec {
+                        tv_sec: 0,
+                        tv_nsec: 0,
+                    };
+                    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+                    let nanos = (time.tv_sec as u64) * 1_000_000_000 + (time.tv_nsec as u64);
+                    let response = format!("time {}\n", nanos);
+                    if let Err(error) = responses.write_all(response.as_bytes()) {
+                        tracing::error!("failed to respond, error {}", error);
+                    }
+                },
+                Ok(Command::PayloadLimit { port, limit }) => {
+                    let result = if limit == 0 {
+                        skeleton.app.limits.remove(&port.to_ne_bytes())
+                    } else {
+                        skeleton
+                            .app
+                            .limits
+                            .insert(port.to_ne_bytes(), limit.to_ne_bytes())
+                    };
+                    match result {
+                        Ok(()) => (),
+                        Err(code) => {
+                            tracing::error!(
+                                "failed to limit the payload of port {}, code {}, error {}",
+                                port,
+                                code,
+                                Error::last_os_error(),
+                            );
+                        },
+                    }
+                },
                 Ok(Command::WatchPort { port }) => {
                     match skeleton
                         .app
@@ -536,6 +702,44 @@ fn main() {
                         },
                     }
                 },
+                Ok(Command::WatchConnection { pid, fd, incoming }) => {
+                    let socket_id = SocketId { pid, fd };
+                    // the same values as `reg_connection` stores
+                    let v = if incoming { 2u32 } else { 1u32 };
+                    match skeleton
+                        .app
+                        .connections
+                        .insert(socket_id.to_ne_bytes(), v.to_ne_bytes())
+                    {
+                        Ok(()) => (),
+                        Err(code) => {
+                            tracing::error!(
+                                "failed to watch connection {}, code {}, error {}",
+                                socket_id,
+                                code,
+                                Error::last_os_error(),
+                            );
+                        },
+                    }
+                },
+                Ok(Command::WatchProcess { pid, port }) => {
+                    // the same value as `reg_process` stores
+                    match skeleton
+                        .app
+                        .processes
+                        .insert(pid.to_ne_bytes(), port.to_ne_bytes())
+                    {
+                        Ok(()) => (),
+                        Err(code) => {
+                            tracing::error!(
+                                "failed to watch process {}, code {}, error {}",
+                                pid,
+                                code,
+                                Error::last_os_error(),
+                            );
+                        },
+                    }
+                },
                 Err(error) => {
                     tracing::warn!("bad command: {}", error);
                 },
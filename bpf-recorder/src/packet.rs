@@ -0,0 +1,301 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    convert::TryFrom,
+    ffi::CString,
+    io, mem,
+    net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr},
+    os::unix::io::{AsRawFd, RawFd},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The flags of the tcp header
+pub mod tcp_flags {
+    pub const FIN: u8 = 0x01;
+    pub const SYN: u8 = 0x02;
+    pub const RST: u8 = 0x04;
+    pub const PSH: u8 = 0x08;
+    pub const ACK: u8 = 0x10;
+    pub const URG: u8 = 0x20;
+    pub const ECE: u8 = 0x40;
+    pub const CWR: u8 = 0x80;
+}
+
+/// The ip and tcp header of the packet, the payload is not captured
+#[derive(Debug, Clone)]
+pub struct PacketHeader {
+    /// unix nanoseconds, when the recorder received the packet
+    pub timestamp: u64,
+    /// the index of the interface the packet came on, or went out of
+    pub interface: i32,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    /// the ttl of ipv4, the hop limit of ipv6
+    pub ttl: u8,
+    /// the length of the ip packet with the headers
+    pub length: u16,
+    /// always set for ipv6, the routers never fragment it
+    pub dont_fragment: bool,
+    /// see `tcp_flags`
+    pub flags: u8,
+    /// as is, not scaled
+    pub window: u16,
+    /// the options, usually the syn carries them
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+}
+
+impl PacketHeader {
+    /// The `bytes` begin with the ip header, `None` if it is not tcp, the fragment,
+    /// or the headers are cut
+    pub fn parse(bytes: &[u8], timestamp: u64) -> Option<Self> {
+        let be16 = |b: &[u8], offset: usize| u16::from_be_bytes([b[offset], b[offset + 1]]);
+
+        let (source, destination, ttl, length, dont_fragment, tcp) = match bytes.first()? >> 4 {
+            4 => {
+                let header_length = ((bytes[0] & 0x0f) as usize) * 4;
+                if bytes.len() < header_length + 20 || header_length < 20 || bytes[9] != 6 {
+                    return None;
+                }
+                // the fragment other than the first has no tcp header
+                if be16(bytes, 6) & 0x1fff != 0 {
+                    return None;
+                }
+                let source = <[u8; 4]>::try_from(&bytes[12..16]).unwrap();
+                let destination = <[u8; 4]>::try_from(&bytes[16..20]).unwrap();
+                (
+                    IpAddr::V4(Ipv4Addr::from(source)),
+                    IpAddr::V4(Ipv4Addr::from(destination)),
+                    bytes[8],
+                    be16(bytes, 2),
+                    bytes[6] & 0x40 != 0,
+                    &bytes[header_length..],
+                )
+            },
+            // the extension headers are not followed
+            6 => {
+                if bytes.len() < 60 || bytes[6] != 6 {
+                    return None;
+                }
+                let source = <[u8; 16]>::try_from(&bytes[8..24]).unwrap();
+                let destination = <[u8; 16]>::try_from(&bytes[24..40]).unwrap();
+                (
+                    IpAddr::V6(Ipv6Addr::from(source)),
+                    IpAddr::V6(Ipv6Addr::from(destination)),
+                    bytes[7],
+                    be16(bytes, 4).saturating_add(40),
+                    true,
+                    &bytes[40..],
+                )
+            },
+            _ => return None,
+        };
+
+        let data_offset = ((tcp[12] >> 4) as usize) * 4;
+        let mut options = tcp.get(20..data_offset.min(tcp.len())).unwrap_or(&[]);
+        let (mut mss, mut window_scale) = (None, None);
+        while let Some((&kind, rest)) = options.split_first() {
+            match kind {
+                0 => break,
+                1 => {
+                    options = rest;
+                    continue;
+                },
+                _ => (),
+            }
+            let length = match rest.first() {
+                Some(&length) => length as usize,
+                None => break,
+            };
+            if length < 2 || options.len() < length {
+                break;
+            }
+            match (kind, length) {
+                (2, 4) => mss = Some(be16(options, 2)),
+                (3, 3) => window_scale = Some(options[2]),
+                _ => (),
+            }
+            options = &options[length..];
+        }
+
+        Some(PacketHeader {
+            timestamp,
+            interface: 0,
+            source: SocketAddr::new(source, be16(tcp, 0)),
+            destination: SocketAddr::new(destination, be16(tcp, 2)),
+            ttl,
+            length,
+            dont_fragment,
+            flags: tcp[13],
+            window: be16(tcp, 14),
+            mss,
+            window_scale,
+        })
+    }
+}
+
+/// The raw packet socket, the link layer header is removed by the kernel,
+/// needs `CAP_NET_RAW`, sees the interfaces of the network namespace it is opened in
+pub struct PacketCapture {
+    fd: RawFd,
+    // the loopback shows each packet twice, as sent and as received
+    loopback: i32,
+}
+
+impl PacketCapture {
+    // the ip and tcp headers with the options fit, the payload is cut
+    const SNAPLEN: usize = 128;
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    // from `linux/if_packet.h`
+    const PACKET_FANOUT: libc::c_int = 18;
+    const PACKET_FANOUT_HASH: u32 = 0;
+    const PACKET_FANOUT_FLAG_DEFRAG: u32 = 0x8000;
+
+    /// Every interface if the `interface` is `None`, the packet tells its interface anyway
+    pub fn open(interface: Option<&str>) -> io::Result<Self> {
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                protocol as i32,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let capture = PacketCapture {
+            fd,
+            loopback: Self::index("lo").unwrap_or(0),
+        };
+
+        if let Some(interface) = interface {
+            let mut address = unsafe { mem::zeroed::<libc::sockaddr_ll>() };
+            address.sll_family = libc::AF_PACKET as u16;
+            address.sll_protocol = protocol;
+            address.sll_ifindex = Self::index(interface)?;
+            let r = unsafe {
+                libc::bind(
+                    fd,
+                    &address as *const libc::sockaddr_ll as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+                )
+            };
+            if r < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let timeout = libc::timeval {
+            tv_sec: Self::TIMEOUT.as_secs() as libc::time_t,
+            tv_usec: Self::TIMEOUT.subsec_micros() as libc::suseconds_t,
+        };
+        let r = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(capture)
+    }
+
+    /// The sockets of the same interface in the same `group` share the packets,
+    /// the kernel hashes the flow, so the packets of one connection go to the same socket
+    /// in order, the fragments are reassembled before the hashing
+    pub fn join_fanout(&self, group: u16) -> io::Result<()> {
+        let mode = Self::PACKET_FANOUT_HASH | Self::PACKET_FANOUT_FLAG_DEFRAG;
+        let value = (group as u32) | (mode << 16);
+        let r = unsafe {
+            libc::setsockopt(
+                self.fd,
+                libc::SOL_PACKET,
+                Self::PACKET_FANOUT,
+                &value as *const u32 as *const libc::c_void,
+                mem::size_of::<u32>() as libc::socklen_t,
+            )
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn index(interface: &str) -> io::Result<i32> {
+        let name = CString::new(interface)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => Err(io::Error::last_os_error()),
+            index => Ok(index as i32),
+        }
+    }
+
+    /// Waits a second at most, `None` if no packet came, or it is not tcp
+    pub fn recv(&mut self) -> io::Result<Option<PacketHeader>> {
+        let mut buffer = [0; Self::SNAPLEN];
+        let mut address = unsafe { mem::zeroed::<libc::sockaddr_ll>() };
+        let mut address_len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+        let r = unsafe {
+            libc::recvfrom(
+                self.fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+                &mut address as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                &mut address_len,
+            )
+        };
+        if r < 0 {
+            let error = io::Error::last_os_error();
+            return match error.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Ok(None),
+                io::ErrorKind::Interrupted => Ok(None),
+                _ => Err(error),
+            };
+        }
+        if address.sll_ifindex == self.loopback && address.sll_pkttype == libc::PACKET_OUTGOING {
+            return Ok(None);
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let header = PacketHeader::parse(&buffer[..(r as usize)], timestamp);
+        Ok(header.map(|header| PacketHeader {
+            interface: address.sll_ifindex,
+            ..header
+        }))
+    }
+
+    /// The name of the interface by its index, `None` if there is no such interface
+    pub fn interface_name(index: i32) -> Option<String> {
+        let mut name = [0; libc::IF_NAMESIZE];
+        let r = unsafe { libc::if_indextoname(index as u32, name.as_mut_ptr()) };
+        if r.is_null() {
+            return None;
+        }
+        let length = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+        let name = name[..length].iter().map(|c| *c as u8).collect::<Vec<_>>();
+        Some(String::from_utf8_lossy(&name).into_owned())
+    }
+}
+
+impl AsRawFd for PacketCapture {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for PacketCapture {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright (c) SimpleStaking and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{fmt, fs, path::Path};
+
+/// What the kernel offers to the bpf module, checked before the module is loaded,
+/// so the missing feature is reported by its name rather than by the code the loader fails with
+pub struct Probe {
+    /// like `5.10.0-8-amd64`
+    pub release: String,
+    pub version: Option<(u32, u32)>,
+    /// `/sys/kernel/btf/vmlinux`, the module does not need it, but the diagnostic tells
+    pub btf: bool,
+    /// the syscall tracepoints the module attaches to, which are absent
+    pub missing_tracepoints: Vec<String>,
+}
+
+impl Probe {
+    /// The ring buffer map and its helpers
+    pub const MIN_VERSION: (u32, u32) = (5, 8);
+
+    pub const TRACEPOINTS: &'static [&'static str] = &[
+        "bind", "connect", "accept4", "close", "write", "read", "sendto", "recvfrom",
+    ];
+
+    pub fn run() -> Self {
+        let release = fs::read_to_string("/proc/sys/kernel/osrelease")
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default();
+        let version = {
+            let mut numbers = release
+                .split(|c: char| !c.is_ascii_digit())
+                .map(|n| n.parse::<u32>());
+            match (numbers.next(), numbers.next()) {
+                (Some(Ok(major)), Some(Ok(minor))) => Some((major, minor)),
+                _ => None,
+            }
+        };
+        let btf = Path::new("/sys/kernel/btf/vmlinux").exists();
+        // the tracefs is mounted in either place, the debugfs is older
+        let roots = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+        let root = roots.iter().find(|root| Path::new(root).join("events").is_dir());
+        let missing_tracepoints = Self::TRACEPOINTS
+            .iter()
+            .flat_map(|name| vec![format!("sys_enter_{}", name), format!("sys_exit_{}", name)])
+            .filter(|tracepoint| match root {
+                Some(root) => !Path::new(root)
+                    .join("events/syscalls")
+                    .join(tracepoint)
+                    .is_dir(),
+                // cannot tell without the tracefs, the load tells
+                None => false,
+            })
+            .collect();
+        Probe {
+            release,
+            version,
+            btf,
+            missing_tracepoints,
+        }
+    }
+
+    /// The reasons the module cannot work, empty if it can
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        // the unknown version is left to the load
+        if self.version.map_or(false, |version| version < Self::MIN_VERSION) {
+            problems.push(format!(
+                "kernel {} is older than {}.{}, it has no bpf ring buffer",
+                self.release,
+                Self::MIN_VERSION.0,
+                Self::MIN_VERSION.1,
+            ));
+        }
+        if !self.missing_tracepoints.is_empty() {
+            problems.push(format!(
+                "the kernel has no syscall tracepoints {}, it needs CONFIG_FTRACE_SYSCALLS",
+                self.missing_tracepoints.join(", "),
+            ));
+        }
+        problems
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "kernel: {}, btf: {}, missing tracepoints: {}",
+            self.release,
+            if self.btf { "yes" } else { "no" },
+            self.missing_tracepoints.len(),
+        )
+    }
+}
@@ -11,6 +11,22 @@ pub fn sized<S, K>(id: EventId, tag: DataTag, data: *const u8, len: usize, rb: &
 where
     S: Unsigned,
     K: Bit,
+{
+    limited::<S, K>(id, tag, data, len, len, rb)
+}
+
+// copies `len` bytes at most, `length` is what the syscall transferred
+#[inline(always)]
+fn limited<S, K>(
+    id: EventId,
+    tag: DataTag,
+    data: *const u8,
+    len: usize,
+    length: usize,
+    rb: &mut RingBufferRef,
+) where
+    S: Unsigned,
+    K: Bit,
 {
     if let Ok(mut buffer) = rb.reserve(S::U64 as usize + mem::size_of::<DataDescriptor>()) {
         let p_buffer = buffer.as_mut().as_mut_ptr() as *mut DataDescriptor;
@@ -42,7 +58,12 @@ where
         } else {
             result as i32
         };
-        let descriptor = DataDescriptor { id, tag, size };
+        let descriptor = DataDescriptor {
+            id,
+            tag,
+            size,
+            length: length as u64,
+        };
         unsafe {
             ptr::write(p_buffer, descriptor);
         }
@@ -53,7 +74,12 @@ where
 
     // failed to allocate buffer, try allocate smaller buffer to report error
     if let Ok(mut buffer) = rb.reserve(mem::size_of::<DataDescriptor>()) {
-        let descriptor = DataDescriptor { id, tag, size: -90 };
+        let descriptor = DataDescriptor {
+            id,
+            tag,
+            size: -90,
+            length: length as u64,
+        };
         unsafe {
             ptr::write(buffer.as_mut().as_mut_ptr() as *mut _, descriptor);
         }
@@ -61,67 +87,81 @@ where
     }
 }
 
-type SizeOfDataDescriptor = typenum::U24;
+type SizeOfDataDescriptor = typenum::U40;
 type DecByDataDescriptor<S> = <S as Sub<SizeOfDataDescriptor>>::Output;
 
 #[inline(always)]
-fn sized_inner<S, K>(id: EventId, tag: DataTag, data: *const u8, len: usize, rb: &mut RingBufferRef)
-where
+fn sized_inner<S, K>(
+    id: EventId,
+    tag: DataTag,
+    data: *const u8,
+    len: usize,
+    length: usize,
+    rb: &mut RingBufferRef,
+) where
     S: Unsigned + Sub<SizeOfDataDescriptor>,
     DecByDataDescriptor<S>: Unsigned,
     K: Bit,
 {
-    sized::<DecByDataDescriptor<S>, K>(id, tag, data, len, rb)
+    limited::<DecByDataDescriptor<S>, K>(id, tag, data, len, length, rb)
 }
 
+/// Copies `limit` bytes at most of the `length` the syscall transferred
 #[inline(always)]
-pub fn dyn_sized<K>(id: EventId, tag: DataTag, data: *const u8, len: usize, rb: &mut RingBufferRef)
-where
+pub fn dyn_sized<K>(
+    id: EventId,
+    tag: DataTag,
+    data: *const u8,
+    length: usize,
+    limit: usize,
+    rb: &mut RingBufferRef,
+) where
     K: Bit,
 {
-    // data len 124 happens often, let's have special case 148 = 124 + sizeof DataDescriptor
+    let len = length.min(limit);
+    // data len 124 happens often, let's have special case 164 = 124 + sizeof DataDescriptor
     let length_to_send = len + mem::size_of::<DataDescriptor>();
-    if length_to_send <= typenum::U148::USIZE {
-        sized_inner::<typenum::U148, K>(id, tag, data, len, rb)
+    if length_to_send <= typenum::U164::USIZE {
+        sized_inner::<typenum::U164, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U8>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U8>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U8>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U9>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U9>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U9>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U10>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U10>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U10>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U11>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U11>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U11>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U12>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U12>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U12>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U13>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U13>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U13>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U14>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U14>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U14>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U15>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U15>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U15>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U16>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U16>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U16>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U17>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U17>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U17>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U18>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U18>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U18>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U19>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U19>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U19>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U20>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U20>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U20>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U21>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U21>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U21>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U22>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U22>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U22>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U23>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U23>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U23>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U24>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U24>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U24>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U25>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U25>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U25>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U26>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U26>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U26>, K>(id, tag, data, len, length, rb)
     } else if length_to_send <= Shleft::<typenum::U1, typenum::U27>::USIZE {
-        sized_inner::<Shleft<typenum::U1, typenum::U27>, K>(id, tag, data, len, rb)
+        sized_inner::<Shleft<typenum::U1, typenum::U27>, K>(id, tag, data, len, length, rb)
     }
 }
@@ -13,6 +13,7 @@ use std::{
     task::{Context, Poll},
     pin::Pin,
     os::unix::io::AsRawFd,
+    time::{Duration, Instant},
 };
 use futures::Stream;
 use tokio::io::{Interest, unix::AsyncFd};
@@ -316,14 +317,24 @@ impl RingBufferSync {
         }
     }
 
-    fn wait(&self, running: &AtomicBool) {
+    fn wait(&self, running: &AtomicBool, deadline: Option<Instant>) {
         let mut fds = libc::pollfd {
             fd: self.as_raw_fd(),
             events: libc::POLLIN,
             revents: 0,
         };
         while running.load(Ordering::Relaxed) {
-            match unsafe { libc::poll(&mut fds, 1, 1_000) } {
+            let timeout = match deadline {
+                None => 1_000,
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now()).as_millis();
+                    if left == 0 {
+                        break;
+                    }
+                    left.min(1_000) as i32
+                },
+            };
+            match unsafe { libc::poll(&mut fds, 1, timeout) } {
                 0 => log::debug!("ringbuf wait timeout"),
                 1 => {
                     if fds.revents & libc::POLLIN != 0 {
@@ -354,7 +365,7 @@ impl RingBufferSync {
             }
             match self.read() {
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    self.wait(running);
+                    self.wait(running, None);
                     if !running.load(Ordering::Relaxed) {
                         break Ok(SmallVec::new());
                     }
@@ -364,6 +375,30 @@ impl RingBufferSync {
             tries += 1;
         }
     }
+
+    /// Like `read_blocking`, but gives up after the `timeout`, the result is empty then,
+    /// so the caller can do its periodic work while there is no data
+    pub fn read_timeout<D>(
+        &mut self,
+        running: &AtomicBool,
+        timeout: Duration,
+    ) -> io::Result<SmallVec<[D; 64]>>
+    where
+        D: RingBufferData,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.read() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if !running.load(Ordering::Relaxed) || Instant::now() >= deadline {
+                        break Ok(SmallVec::new());
+                    }
+                    self.wait(running, Some(deadline));
+                },
+                x => break x,
+            }
+        }
+    }
 }
 
 impl Drop for RingBufferObserver {
@@ -38,15 +38,7 @@ where
         nonce: Nonce,
     ) -> Nonce {
         let bytes = self.as_bytes().unwrap();
-        let mut nonce = nonce;
-        for bytes in bytes.as_slice().chunks(0xffe0) {
-            let temp = key.encrypt(&bytes, &nonce).unwrap();
-            let chunk = BinaryChunk::from_content(&temp).unwrap().raw().clone();
-            stream.write_all(&chunk).unwrap();
-            nonce = nonce.increment();
-        }
-
-        nonce
+        write_raw(&bytes, stream, key, nonce)
     }
 
     fn read_msg(
@@ -80,6 +72,41 @@ where
     }
 }
 
+/// Encrypts the message bytes, possibly tampered, and writes them in chunks, returns the next nonce
+pub fn write_raw(bytes: &[u8], stream: &mut impl Write, key: &PrecomputedKey, nonce: Nonce) -> Nonce {
+    try_write_raw(bytes, stream, key, nonce).unwrap()
+}
+
+/// The same as `write_raw`, but the error of the stream, like the closed connection, is returned
+pub fn try_write_raw(
+    bytes: &[u8],
+    stream: &mut impl Write,
+    key: &PrecomputedKey,
+    nonce: Nonce,
+) -> io::Result<Nonce> {
+    let mut nonce = nonce;
+    for bytes in bytes.chunks(0xffe0) {
+        let temp = key.encrypt(&bytes, &nonce).unwrap();
+        let chunk = BinaryChunk::from_content(&temp).unwrap().raw().clone();
+        stream.write_all(&chunk)?;
+        nonce = nonce.increment();
+    }
+
+    Ok(nonce)
+}
+
+/// Writes the biggest chunk the length header can hold, bigger than any chunk `write_raw` makes,
+/// the content is zeros, returns the next nonce
+pub fn write_oversized(stream: &mut impl Write, key: &PrecomputedKey, nonce: Nonce) -> Nonce {
+    // the encryption adds 16 bytes
+    let temp = key.encrypt(&vec![0; 0xffff - 16], &nonce).unwrap();
+    let mut chunk = (temp.len() as u16).to_be_bytes().to_vec();
+    chunk.extend_from_slice(&temp);
+    stream.write_all(&chunk).unwrap();
+
+    nonce.increment()
+}
+
 pub struct ChunkBuffer {
     len: usize,
     data: [u8; 0x10000],
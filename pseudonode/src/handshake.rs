@@ -64,3 +64,10 @@ pub fn responder(this: u16, stream: &mut TcpStream, identity_json: &str, version
     let pair = generate_nonces(responder_chunk.raw(), initiator_chunk.raw(), true).unwrap();
     (key, pair)
 }
+
+/// Generates a fresh identity with the proof of work of the given difficulty, returns its json
+pub fn generate_identity(expected_pow: f64) -> String {
+    use tezos_identity::Identity;
+
+    Identity::generate(expected_pow).unwrap().as_json().unwrap()
+}
@@ -6,4 +6,4 @@
 mod buffer;
 pub mod handshake;
 
-pub use self::buffer::{ChunkBuffer, Message};
+pub use self::buffer::{ChunkBuffer, Message, write_raw, try_write_raw, write_oversized};
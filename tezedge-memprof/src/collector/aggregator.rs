@@ -1,10 +1,11 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{sync::Arc, collections::HashMap};
+use std::{sync::Arc, collections::HashMap, ops::Range};
 use serde::{Serialize, Deserialize};
-use bpf_memprof_common::{Hex64, Hex32, Stack};
-use crate::{Tracker, Page};
+use bpf_memprof_common::{Hex64, Hex32, Stack, MMap};
+use crate::{Tracker, Page, RegionKind};
+use super::regions::Regions;
 
 #[derive(Clone, Hash, PartialEq, Eq, Debug)]
 pub struct FuncPath(Arc<Vec<Hex64>>);
@@ -13,6 +14,10 @@ impl FuncPath {
     pub fn new(stack: &Stack) -> Self {
         FuncPath(Arc::new(stack.ips().to_vec()))
     }
+
+    pub fn ips(&self) -> &[Hex64] {
+        self.0.as_ref()
+    }
 }
 
 #[derive(Clone, Hash, PartialEq, Eq)]
@@ -23,6 +28,7 @@ pub struct PageAddress(u32);
 
 pub struct PageInfo {
     func_path_index: FuncPathIndex,
+    tid: u32,
     order: u8,
     is_allocated: bool,
     is_cache: bool,
@@ -58,6 +64,7 @@ pub struct Aggregator {
     paths: HashMap<FuncPath, FuncPathIndex>,
     pages: HashMap<PageAddress, PageInfo>,
     groups: HashMap<FuncPathIndex, Usage>,
+    regions: Regions,
     dump: Option<Vec<RawEvent>>,
 }
 
@@ -74,7 +81,7 @@ impl Aggregator {
         }
     }
 
-    pub fn track_alloc(&mut self, page: u32, order: u8, stack: &Stack) {
+    pub fn track_alloc(&mut self, page: u32, order: u8, stack: &Stack, tid: u32) {
         if let Some(dump) = &mut self.dump {
             dump.push(RawEvent::Alloc { page, order });
         }
@@ -93,11 +100,13 @@ impl Aggregator {
             .entry(address)
             .or_insert_with(|| PageInfo {
                 func_path_index: index.clone(),
+                tid,
                 order,
                 is_allocated: false,
                 is_cache: false,
             });
         let pages_count = 1 << info.order;
+        info.tid = tid;
 
         let old_index = &info.func_path_index;
         if *old_index != *index {
@@ -209,19 +218,68 @@ impl Aggregator {
         }
     }
 
+    pub fn track_mmap(&mut self, tid: u32, v: &MMap, stack: &Stack) {
+        self.regions.enter_mmap(tid, v, stack);
+    }
+
+    pub fn track_mmap_ret(&mut self, tid: u32, ret: i64) {
+        self.regions.exit_mmap(tid, ret);
+    }
+
+    pub fn track_munmap(&mut self, tid: u32, addr: u64, len: u64) {
+        self.regions.enter_munmap(tid, addr, len);
+    }
+
+    pub fn track_munmap_ret(&mut self, tid: u32, ret: i64) {
+        self.regions.exit_munmap(tid, ret);
+    }
+
+    pub fn track_brk(&mut self, tid: u32, brk: u64, stack: &Stack) {
+        self.regions.enter_brk(tid, brk, stack);
+    }
+
+    pub fn track_brk_ret(&mut self, tid: u32, ret: u64) {
+        self.regions.exit_brk(tid, ret);
+    }
+
     pub fn report(&self) -> impl Iterator<Item = (u64, u64, &[Hex64])> {
         self.groups.iter().map(|(_, usage)| (
             (usage.value as u64) * 4,
             (usage.cache_value as u64) * 4,
-            usage.func_path.0.as_ref().as_ref(),
+            usage.func_path.ips(),
         ))
     }
+
+    pub fn report_by_thread(&self) -> impl Iterator<Item = (u32, u64, u64, &[Hex64])> {
+        let mut values = HashMap::<(u32, &FuncPathIndex), (u64, u64)>::new();
+        for (_, info) in &self.pages {
+            if info.is_allocated {
+                let pages_count = 1u64 << info.order;
+                let (value, cache_value) = values.entry((info.tid, &info.func_path_index)).or_default();
+                *value += pages_count * 4;
+                if info.is_cache {
+                    *cache_value += pages_count * 4;
+                }
+            }
+        }
+        let groups = &self.groups;
+        values
+            .into_iter()
+            .filter_map(move |((tid, index), (value, cache_value))| {
+                let usage = groups.get(index)?;
+                Some((tid, value, cache_value, usage.func_path.ips()))
+            })
+    }
+
+    pub fn regions_report(&self) -> impl Iterator<Item = (Range<u64>, RegionKind, &[Hex64])> {
+        self.regions.report()
+    }
 }
 
 impl Tracker for Aggregator {
-    fn track_alloc(&mut self, page: Page, stack: &Stack, flags: Hex32, pid: u32) {
+    fn track_alloc(&mut self, page: Page, stack: &Stack, flags: Hex32, pid: u32, tid: u32) {
         let _ = (flags, pid);
-        Self::track_alloc(self, page.pfn(), page.order(), stack)
+        Self::track_alloc(self, page.pfn(), page.order(), stack, tid)
     }
 
     fn track_free(&mut self, page: Page, pid: u32) {
@@ -4,6 +4,7 @@
 use std::ops::Deref;
 use std::sync::{Arc, Mutex, atomic::{Ordering, AtomicU32}};
 use bpf_memprof_common::{EventKind, Event};
+use crate::{ThreadReport, RegionReport, RegionReportEntry};
 use super::{Reporter, StackResolver, FrameReport, aggregator::Aggregator};
 
 impl Reporter for Aggregator {
@@ -34,6 +35,39 @@ impl Reporter for Aggregator {
         report
 
     }
+
+    fn thread_report<R>(&self, resolver: R, threshold: u64, reverse: bool) -> ThreadReport<R>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        let mut report = ThreadReport::new(resolver);
+        for (tid, value, cache_value, stack) in self.report_by_thread() {
+            if reverse {
+                report.insert(tid, stack.iter().rev(), value, cache_value);
+            } else {
+                report.insert(tid, stack.iter(), value, cache_value);
+            }
+        }
+        report.strip(threshold);
+
+        report
+    }
+
+    fn region_report<R>(&self, resolver: R) -> RegionReport<R>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        let mut report = RegionReport::new(resolver);
+        for (range, kind, stack) in self.regions_report() {
+            report.regions.push(RegionReportEntry {
+                range,
+                kind,
+                stack: stack.to_vec(),
+            });
+        }
+
+        report
+    }
 }
 
 #[derive(Default)]
@@ -65,7 +99,9 @@ impl Consumer {
         };
 
         if let Some(last) = &self.last {
-            if last.eq(&event.event) {
+            // the successful `munmap` of the different threads returns the same
+            let exit = matches!(event.event, EventKind::MUnmapRet(_));
+            if last.eq(&event.event) && !exit {
                 log::trace!("repeat");
                 return;
             }
@@ -74,7 +110,7 @@ impl Consumer {
             &EventKind::PageAlloc(ref v) if v.pfn.0 != 0 => {
                 self.has_pid = true;
                 self.pid.store(event.pid, Ordering::SeqCst);
-                self.aggregator.lock().unwrap().track_alloc(v.pfn.0 as u32, v.order as u8, &event.stack);
+                self.aggregator.lock().unwrap().track_alloc(v.pfn.0 as u32, v.order as u8, &event.stack, event.header.tid());
             }
             &EventKind::PageFree(ref v) if v.pfn.0 != 0 && self.has_pid => {
                 self.aggregator.lock().unwrap().track_free(v.pfn.0 as u32);
@@ -88,6 +124,24 @@ impl Consumer {
             &EventKind::RssStat(ref v) if v.member == 1 && self.has_pid => {
                 self.aggregator.lock().unwrap().track_rss_anon(v.size as _);
             }
+            &EventKind::MMap(ref v) => {
+                self.aggregator.lock().unwrap().track_mmap(event.header.tid(), v, &event.stack);
+            },
+            &EventKind::MMapRet(ref v) => {
+                self.aggregator.lock().unwrap().track_mmap_ret(event.header.tid(), v.ret);
+            },
+            &EventKind::MUnmap(ref v) => {
+                self.aggregator.lock().unwrap().track_munmap(event.header.tid(), v.addr.0, v.len);
+            },
+            &EventKind::MUnmapRet(ref v) => {
+                self.aggregator.lock().unwrap().track_munmap_ret(event.header.tid(), v.ret);
+            },
+            &EventKind::Brk(ref v) => {
+                self.aggregator.lock().unwrap().track_brk(event.header.tid(), v.brk.0, &event.stack);
+            },
+            &EventKind::BrkRet(ref v) => {
+                self.aggregator.lock().unwrap().track_brk_ret(event.header.tid(), v.ret.0);
+            },
             _ => (),
         }
         self.last = Some(event.event);
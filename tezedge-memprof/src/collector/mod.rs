@@ -6,5 +6,7 @@ use super::{Reporter, StackResolver, FrameReport};
 mod aggregator;
 pub use self::aggregator::{Aggregator, RawEvent};
 
+mod regions;
+
 mod consumer;
 pub use self::consumer::Consumer;
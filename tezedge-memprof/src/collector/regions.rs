@@ -0,0 +1,207 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{collections::{BTreeMap, HashMap}, ops::Range};
+use bpf_memprof_common::{Hex64, Stack, MMap};
+use crate::RegionKind;
+use super::aggregator::FuncPath;
+
+#[derive(Clone)]
+struct Region {
+    end: u64,
+    kind: RegionKind,
+    func_path: FuncPath,
+}
+
+/// Tracks the memory mapped by `mmap` and `brk` syscalls,
+/// the syscall arguments arrive on enter and the resulting address on exit,
+/// so the enter event is kept per thread until the exit event arrives.
+/// The `munmap` is applied on exit too, only if it succeeded.
+#[derive(Default)]
+pub struct Regions {
+    pending_mmap: HashMap<u32, (RegionKind, u64, FuncPath)>,
+    pending_munmap: HashMap<u32, Range<u64>>,
+    pending_brk: HashMap<u32, FuncPath>,
+    brk: Option<u64>,
+    regions: BTreeMap<u64, Region>,
+}
+
+// the range of the mapping, `None` if it wraps the address space
+fn range(start: u64, len: u64) -> Option<Range<u64>> {
+    let len = len.checked_add(0xfff)? & !0xfff;
+    Some(start..start.checked_add(len)?)
+}
+
+impl Regions {
+    pub fn enter_mmap(&mut self, tid: u32, v: &MMap, stack: &Stack) {
+        let kind = if v.is_anonymous() {
+            RegionKind::Anonymous
+        } else {
+            RegionKind::File
+        };
+        self.pending_mmap.insert(tid, (kind, v.len, FuncPath::new(stack)));
+    }
+
+    pub fn exit_mmap(&mut self, tid: u32, ret: i64) {
+        if let Some((kind, len, func_path)) = self.pending_mmap.remove(&tid) {
+            // negative value is the error code
+            if ret < 0 || len == 0 {
+                return;
+            }
+            let range = match range(ret as u64, len) {
+                Some(v) => v,
+                None => return,
+            };
+            // `MAP_FIXED` silently replaces the old mapping
+            self.remove(range.clone());
+            let region = Region {
+                end: range.end,
+                kind,
+                func_path,
+            };
+            self.regions.insert(range.start, region);
+        }
+    }
+
+    pub fn enter_munmap(&mut self, tid: u32, addr: u64, len: u64) {
+        match range(addr, len) {
+            Some(range) => self.pending_munmap.insert(tid, range),
+            // the kernel rejects it, but forget the previous one of this thread
+            None => self.pending_munmap.remove(&tid),
+        };
+    }
+
+    pub fn exit_munmap(&mut self, tid: u32, ret: i64) {
+        if let Some(range) = self.pending_munmap.remove(&tid) {
+            if ret == 0 {
+                self.remove(range);
+            }
+        }
+    }
+
+    pub fn enter_brk(&mut self, tid: u32, brk: u64, stack: &Stack) {
+        // `brk(0)` only queries the current program break
+        if brk != 0 {
+            self.pending_brk.insert(tid, FuncPath::new(stack));
+        }
+    }
+
+    pub fn exit_brk(&mut self, tid: u32, ret: u64) {
+        let func_path = self.pending_brk.remove(&tid);
+        match (self.brk.replace(ret), func_path) {
+            (Some(old), Some(func_path)) if ret > old => {
+                let region = Region {
+                    end: ret,
+                    kind: RegionKind::Heap,
+                    func_path,
+                };
+                self.regions.insert(old, region);
+            },
+            (Some(old), _) if ret < old => self.remove(ret..old),
+            _ => (),
+        }
+    }
+
+    fn remove(&mut self, range: Range<u64>) {
+        let overlapping = self.regions
+            .range(..range.end)
+            .filter(|(_, region)| region.end > range.start)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+        for start in overlapping {
+            let region = self.regions.remove(&start).unwrap();
+            if start < range.start {
+                let head = Region {
+                    end: range.start,
+                    ..region.clone()
+                };
+                self.regions.insert(start, head);
+            }
+            if region.end > range.end {
+                self.regions.insert(range.end, region);
+            }
+        }
+    }
+
+    pub fn report(&self) -> impl Iterator<Item = (Range<u64>, RegionKind, &[Hex64])> {
+        self.regions
+            .iter()
+            .map(|(start, region)| (*start..region.end, region.kind, region.func_path.ips()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bpf_memprof_common::{Stack, MMap, Pod};
+    use crate::RegionKind;
+    use super::Regions;
+
+    fn mmap(regions: &mut Regions, addr: u64, len: u64, flags: u64) {
+        let mut bytes = [0; 0x38];
+        bytes[0x10..0x18].clone_from_slice(&len.to_ne_bytes());
+        bytes[0x20..0x28].clone_from_slice(&flags.to_ne_bytes());
+        let v = MMap::from_slice(&bytes).unwrap();
+        regions.enter_mmap(1, &v, &Stack::from_frames(&[1]));
+        regions.exit_mmap(1, addr as i64);
+    }
+
+    fn munmap(regions: &mut Regions, addr: u64, len: u64, ret: i64) {
+        regions.enter_munmap(1, addr, len);
+        regions.exit_munmap(1, ret);
+    }
+
+    #[test]
+    fn munmap_split() {
+        let mut regions = Regions::default();
+        mmap(&mut regions, 0x10000, 0x4000, 0x20);
+        munmap(&mut regions, 0x11000, 0x1000, 0);
+
+        let r = regions.report().map(|(range, kind, _)| (range, kind)).collect::<Vec<_>>();
+        assert_eq!(
+            r,
+            vec![
+                (0x10000..0x11000, RegionKind::Anonymous),
+                (0x12000..0x14000, RegionKind::Anonymous),
+            ],
+        );
+    }
+
+    #[test]
+    fn brk_grow_shrink() {
+        let mut regions = Regions::default();
+        let stack = Stack::from_frames(&[1]);
+        regions.enter_brk(1, 0, &stack);
+        regions.exit_brk(1, 0x20000);
+        regions.enter_brk(1, 0x28000, &stack);
+        regions.exit_brk(1, 0x28000);
+        regions.enter_brk(1, 0x24000, &stack);
+        regions.exit_brk(1, 0x24000);
+
+        let r = regions.report().map(|(range, kind, _)| (range, kind)).collect::<Vec<_>>();
+        assert_eq!(r, vec![(0x20000..0x24000, RegionKind::Heap)]);
+    }
+
+    #[test]
+    fn munmap_failed() {
+        let mut regions = Regions::default();
+        mmap(&mut regions, 0x10000, 0x4000, 0x20);
+        // EINVAL
+        munmap(&mut regions, 0x11000, 0x1000, -22);
+
+        let r = regions.report().map(|(range, _, _)| range).collect::<Vec<_>>();
+        assert_eq!(r, vec![0x10000..0x14000]);
+    }
+
+    #[test]
+    fn overflow_ignored() {
+        let mut regions = Regions::default();
+        mmap(&mut regions, 0x10000, 0x4000, 0x20);
+        mmap(&mut regions, 0x20000, u64::MAX, 0x20);
+        mmap(&mut regions, -0x1000i64 as u64, 0x2000, 0x20);
+        munmap(&mut regions, 0x10000, u64::MAX, 0);
+        munmap(&mut regions, u64::MAX - 0xfff, 0x2000, 0);
+
+        let r = regions.report().map(|(range, _, _)| range).collect::<Vec<_>>();
+        assert_eq!(r, vec![0x10000..0x14000]);
+    }
+}
@@ -3,10 +3,10 @@
 
 use std::ops::Deref;
 use bpf_memprof_common::{Hex32, Stack};
-use super::{page::Page, report::FrameReport, stack::StackResolver};
+use super::{page::Page, report::{FrameReport, RegionReport, ThreadReport}, stack::StackResolver};
 
 pub trait Tracker {
-    fn track_alloc(&mut self, page: Page, stack: &Stack, flags: Hex32, pid: u32);
+    fn track_alloc(&mut self, page: Page, stack: &Stack, flags: Hex32, pid: u32, tid: u32);
     fn track_free(&mut self, page: Page, pid: u32);
     fn mark_page_cache(&mut self, page: Page, b: bool);
 }
@@ -22,4 +22,25 @@ pub trait Reporter {
     ) -> FrameReport<R>
     where
         R: Deref<Target = StackResolver>;
+
+    /// Same as `tree_report`, but the tree is built separately for each thread
+    fn thread_report<R>(
+        &self,
+        resolver: R,
+        threshold: u64,
+        reverse: bool,
+    ) -> ThreadReport<R>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        let _ = (threshold, reverse);
+        ThreadReport::new(resolver)
+    }
+
+    fn region_report<R>(&self, resolver: R) -> RegionReport<R>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        RegionReport::new(resolver)
+    }
 }
@@ -169,7 +169,7 @@ pub struct AllocationState {
 }
 
 impl Tracker for AllocationState {
-    fn track_alloc(&mut self, page: Page, stack: &Stack, _flags: Hex32, pid: u32) {
+    fn track_alloc(&mut self, page: Page, stack: &Stack, _flags: Hex32, pid: u32, _tid: u32) {
         self.pid = Some(pid);
         let stack = StackShort::new(stack);
         self.group.insert(page, stack);
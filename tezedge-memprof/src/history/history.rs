@@ -8,7 +8,7 @@ use super::{
     page::Page,
     error::ErrorReport,
     page_history::{PageHistory, AllocError, FreeError},
-    report::FrameReport,
+    report::{FrameReport, ThreadReport},
     stack::StackResolver,
     abstract_tracker::{Tracker, Reporter},
 };
@@ -37,15 +37,17 @@ pub struct History<H> {
     error_report: ErrorReport,
     group: HashMap<StackShort, HashMap<Page, H>>,
     last_stack: HashMap<Page, StackShort>,
+    last_thread: HashMap<Page, u32>,
 }
 
 impl<H> Tracker for History<H>
 where
     H: PageHistory + Default,
 {
-    fn track_alloc(&mut self, page: Page, stack: &Stack, flags: Hex32, pid: u32) {
+    fn track_alloc(&mut self, page: Page, stack: &Stack, flags: Hex32, pid: u32, tid: u32) {
         let _ = pid;
         let stack = StackShort::new(stack);
+        self.last_thread.insert(page.clone(), tid);
 
         // if we have a last_stack for some page then `self.group` contains entry for this stack
         // and the entry contains history for the page, so unwrap here is ok
@@ -81,6 +83,7 @@ where
                     self.group.remove(&stack);
                 }
                 self.last_stack.remove(&page);
+                self.last_thread.remove(&page);
             }
         } else {
             // self.error_report.without_alloc(&page);
@@ -146,6 +149,42 @@ where
 
         report
     }
+
+    fn thread_report<R>(
+        &self,
+        resolver: R,
+        threshold: u64,
+        reverse: bool,
+    ) -> ThreadReport<R>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        let mut values = HashMap::<(u32, &StackShort), (u64, u64)>::new();
+        for (stack, group) in &self.group {
+            for (page, history) in group {
+                if history.is_allocated(None) {
+                    let tid = self.last_thread.get(page).cloned().unwrap_or(0);
+                    let (value, cache_value) = values.entry((tid, stack)).or_default();
+                    *value += page.size_kib();
+                    if history.page_cache() {
+                        *cache_value += page.size_kib();
+                    }
+                }
+            }
+        }
+
+        let mut report = ThreadReport::new(resolver);
+        for ((tid, stack), (value, cache_value)) in values {
+            if reverse {
+                report.insert(tid, stack.0.iter().rev(), value, cache_value);
+            } else {
+                report.insert(tid, stack.0.iter(), value, cache_value);
+            }
+        }
+        report.strip(threshold);
+
+        report
+    }
 }
 
 impl<H> History<H>
@@ -172,7 +211,7 @@ where
 
     #[cfg(test)]
     fn is_empty(&self) -> bool {
-        self.last_stack.is_empty() && self.group.is_empty()
+        self.last_stack.is_empty() && self.last_thread.is_empty() && self.group.is_empty()
     }
 }
 
@@ -186,7 +225,7 @@ mod test {
         let mut h = History::<EventLast>::default();
         for _ in 0..0x100 {
             for i in 1..100 {
-                h.track_alloc(Page::new(Hex64(i), 0), &Stack::from_frames(&[i / 3]), Hex32(0), 0);
+                h.track_alloc(Page::new(Hex64(i), 0), &Stack::from_frames(&[i / 3]), Hex32(0), 0, 0);
             }
             for i in 1..100 {
                 h.track_free(Page::new(Hex64(i), 0), 0);
@@ -17,7 +17,7 @@ pub use self::{
     page::Page,
     page_history::{PageHistory, EventLast},
     history::History,
-    report::FrameReport,
+    report::{FrameReport, ThreadReport, RegionReport, RegionReportEntry, RegionKind},
 };
 
 #[cfg(test)]
@@ -1,7 +1,7 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{collections::{HashMap, BTreeMap}, ops::Deref, cmp::Ordering};
+use std::{collections::{HashMap, BTreeMap}, ops::{Deref, Range}, cmp::Ordering};
 use bpf_memprof_common::Hex64;
 use serde::ser::{self, SerializeSeq};
 use super::stack::{SymbolInfo, StackResolver};
@@ -53,6 +53,10 @@ pub struct FrameReportSorted {
 }
 
 impl FrameReportInner {
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
     pub fn insert<'a, StackIter>(&mut self, stack: StackIter, value: u64, cache_value: u64)
     where
         StackIter: Iterator<Item = &'a Hex64>,
@@ -83,6 +87,25 @@ impl FrameReportInner {
         self.cache_under_threshold = cache_under_threshold;
     }
 
+    /// Calls `f` for each node of the tree with the path to the node
+    /// and the value that belongs to the node itself, not to its children
+    pub fn visit<F>(&self, path: &mut Vec<Hex64>, f: &mut F)
+    where
+        F: FnMut(&[Hex64], u64, u64),
+    {
+        let (mut value, mut cache_value) = (self.value, self.cache_value);
+        for (key, frame) in &self.frames {
+            value -= frame.value;
+            cache_value -= frame.cache_value;
+            path.push(*key);
+            frame.visit(path, f);
+            path.pop();
+        }
+        if value != 0 || cache_value != 0 {
+            f(path, value, cache_value);
+        }
+    }
+
     pub fn sorted(&self, resolver: &StackResolver, name: Option<SymbolInfo>) -> FrameReportSorted {
         let mut frames = BTreeMap::new();
         let mut unknown = self.value - self.under_threshold;
@@ -129,6 +152,10 @@ impl<R> FrameReport<R> {
     pub fn cache_value(&self) -> u64 {
         self.inner.cache_value
     }
+
+    pub fn resolver(&self) -> &R {
+        &self.resolver
+    }
 }
 
 impl ser::Serialize for FrameReportSorted {
@@ -233,3 +260,159 @@ where
         sorted.serialize(serializer)
     }
 }
+
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RegionKind {
+    Anonymous,
+    File,
+    Heap,
+}
+
+pub struct RegionReportEntry {
+    pub range: Range<u64>,
+    pub kind: RegionKind,
+    pub stack: Vec<Hex64>,
+}
+
+/// Memory obtained by `mmap` and `brk` directly, bypassing the page allocator report
+pub struct RegionReport<R> {
+    resolver: R,
+    pub(crate) regions: Vec<RegionReportEntry>,
+}
+
+impl<R> RegionReport<R> {
+    pub fn new(resolver: R) -> Self {
+        RegionReport { resolver, regions: Vec::new() }
+    }
+
+    /// Total size of the regions in KiB
+    pub fn value(&self) -> u64 {
+        self.regions
+            .iter()
+            .map(|region| (region.range.end - region.range.start) / 1024)
+            .sum()
+    }
+}
+
+impl<R> ser::Serialize for RegionReport<R>
+where
+    R: Deref<Target = StackResolver>,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        #[derive(serde::Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Region {
+            start: Hex64,
+            end: Hex64,
+            kind: RegionKind,
+            name: Option<String>,
+            value: u64,
+            frames: Vec<SymbolInfo>,
+        }
+
+        let mut regions = self.regions
+            .iter()
+            .map(|region| Region {
+                start: Hex64(region.range.start),
+                end: Hex64(region.range.end),
+                kind: region.kind,
+                name: self.resolver.region_name(region.range.start),
+                value: (region.range.end - region.range.start) / 1024,
+                frames: region.stack
+                    .iter()
+                    .filter_map(|ip| self.resolver.resolve(ip.0))
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        regions.sort_by(|a, b| b.value.cmp(&a.value));
+
+        let mut seq = serializer.serialize_seq(Some(regions.len()))?;
+        for region in &regions {
+            seq.serialize_element(region)?;
+        }
+        seq.end()
+    }
+}
+
+pub struct ThreadReport<R> {
+    resolver: R,
+    pub(crate) threads: BTreeMap<u32, FrameReportInner>,
+    names: HashMap<u32, String>,
+}
+
+impl<R> ThreadReport<R> {
+    pub fn new(resolver: R) -> Self {
+        ThreadReport {
+            resolver,
+            threads: BTreeMap::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn insert<'a, StackIter>(&mut self, tid: u32, stack: StackIter, value: u64, cache_value: u64)
+    where
+        StackIter: Iterator<Item = &'a Hex64>,
+    {
+        self.threads.entry(tid).or_default().insert(stack, value, cache_value);
+    }
+
+    pub(crate) fn strip(&mut self, threshold: u64) {
+        for (_, inner) in &mut self.threads {
+            inner.strip(threshold);
+        }
+    }
+
+    /// Read the names of the threads, like `tokio-runtime-w`, from `/proc/<pid>/task/<tid>/comm`
+    pub fn with_names(mut self, pid: u32) -> Self {
+        use std::fs;
+
+        for (tid, _) in &self.threads {
+            if let Ok(name) = fs::read_to_string(format!("/proc/{}/task/{}/comm", pid, tid)) {
+                self.names.insert(*tid, name.trim_end_matches('\n').to_string());
+            }
+        }
+        self
+    }
+}
+
+impl<R> ser::Serialize for ThreadReport<R>
+where
+    R: Deref<Target = StackResolver>,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        #[derive(serde::Serialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Thread<'a> {
+            tid: u32,
+            name: Option<&'a str>,
+            value: u64,
+            cache_value: u64,
+            tree: FrameReportSorted,
+        }
+
+        let mut threads = self.threads
+            .iter()
+            .map(|(tid, inner)| Thread {
+                tid: *tid,
+                name: self.names.get(tid).map(String::as_str),
+                value: inner.value,
+                cache_value: inner.cache_value,
+                tree: inner.sorted(&self.resolver, None),
+            })
+            .collect::<Vec<_>>();
+        threads.sort_by(|a, b| b.value.cmp(&a.value));
+
+        let mut seq = serializer.serialize_seq(Some(threads.len()))?;
+        for thread in &threads {
+            seq.serialize_element(thread)?;
+        }
+        seq.end()
+    }
+}
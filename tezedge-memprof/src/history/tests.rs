@@ -15,7 +15,7 @@ where
     pages.fold(history, |mut h, i| {
         let stack = Stack::from_frames(&[stack(i)]);
         let page = Page::new(Hex64(i), 0);
-        h.track_alloc(page, &stack, Hex32(0), 0);
+        h.track_alloc(page, &stack, Hex32(0), 0, 0);
         h
     })
 }
@@ -86,7 +86,7 @@ where
         let page_i = rand::random::<u64>() % 0x1000;
         pages.insert(page_i);
         let page = Page::new(Hex64(page_i), 0);
-        history.track_alloc(page, &stack, Hex32(0), 0);
+        history.track_alloc(page, &stack, Hex32(0), 0, 0);
     }
 
     let (value, cache) = history.short_report();
@@ -123,7 +123,7 @@ where
         let page_i = rand::random::<u64>() % 0x1000;
         pages.insert(page_i);
         let page = Page::new(Hex64(page_i), 0);
-        history.track_alloc(page, &stack, Hex32(0), 0);
+        history.track_alloc(page, &stack, Hex32(0), 0, 0);
     }
 
     let (value, cache) = history.short_report();
@@ -154,7 +154,7 @@ where
         let page_i = rand::random::<u64>() % 0x1000;
         pages.insert(page_i);
         let page = Page::new(Hex64(page_i), 0);
-        history.track_alloc(page, &stack, Hex32(0), 0);
+        history.track_alloc(page, &stack, Hex32(0), 0, 0);
         if rand::random::<bool>() {
             cache_pages.insert(page_i);
             history.mark_page_cache(page, true);
@@ -208,7 +208,7 @@ where
         let page_i = rand::random::<u64>() % 0x1000;
         pages.insert(page_i);
         let page = Page::new(Hex64(page_i), 0);
-        history.track_alloc(page, &stack, Hex32(0), 0);
+        history.track_alloc(page, &stack, Hex32(0), 0, 0);
         if rand::random::<bool>() {
             cache_pages.insert(page_i);
             history.mark_page_cache(page, true);
@@ -246,6 +246,26 @@ where
     let _ = serde_json::to_string_pretty(&tree).unwrap();
 }
 
+fn alloc_by_thread<T>()
+where
+    T: Default + Tracker + Reporter,
+{
+    let mut history = T::default();
+    for i in 0..0x1000 {
+        let stack = Stack::from_frames(&[i % 0x10]);
+        let page = Page::new(Hex64(i), 0);
+        history.track_alloc(page, &stack, Hex32(0), 0, (i % 4) as u32);
+    }
+    let resolver = StackResolver::mock();
+
+    let report = history.thread_report(&resolver, 0, false);
+    assert_eq!(report.threads.len(), 4);
+    for (_, tree) in &report.threads {
+        assert_eq!(tree.value(), 0x400 * 4);
+    }
+    let _ = serde_json::to_string_pretty(&report).unwrap();
+}
+
 #[test]
 fn alloc_simple() {
     alloc::<AllocationState>()
@@ -390,3 +410,13 @@ fn alloc_in_different_stacks_history() {
 fn alloc_in_different_stacks_aggregator() {
     alloc_in_different_stacks::<Aggregator>()
 }
+
+#[test]
+fn alloc_by_thread_history() {
+    alloc_by_thread::<History<EventLast>>()
+}
+
+#[test]
+fn alloc_by_thread_aggregator() {
+    alloc_by_thread::<Aggregator>()
+}
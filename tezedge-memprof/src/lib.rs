@@ -10,13 +10,19 @@ pub use self::state::{AtomicState, Reporter as StateReporter};
 
 mod history;
 pub use self::history::{Page, History, AllocationState, FrameReport, EventLast, Tracker, Reporter};
+pub use self::history::{ThreadReport, RegionReport, RegionReportEntry, RegionKind};
 
 mod stack;
 pub use self::stack::StackResolver;
 
 mod table;
 
+mod pprof;
+
 pub mod server;
 
+mod watchdog;
+pub use self::watchdog::{Watchdog, WatchdogConfig};
+
 mod collector;
 pub use self::collector::{Consumer, Aggregator, RawEvent};
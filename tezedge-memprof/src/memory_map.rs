@@ -18,6 +18,16 @@ impl ProcessMap {
             .collect()
     }
 
+    /// The name of the mapping the address belongs to, either a file path or remark like `[heap]`
+    pub fn region_name(&self, address: usize) -> Option<String> {
+        self.0.iter()
+            .find(|entry| entry.range.contains(&address))
+            .and_then(|entry| match &entry.name {
+                EntryName::Remark(remark) => Some(remark.clone()),
+                name => name.string(),
+            })
+    }
+
     pub fn find(&self, ip: usize) -> Option<(String, usize)> {
         self.0.iter()
             .find_map(|entry| {
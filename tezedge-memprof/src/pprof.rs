@@ -0,0 +1,194 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Encoding of the allocation tree as a gzip-compressed pprof profile,
+//! see https://github.com/google/pprof/blob/master/proto/profile.proto
+
+use std::{collections::HashMap, io::{self, Write}, ops::Deref, time::SystemTime};
+use flate2::{write::GzEncoder, Compression};
+use super::{FrameReport, StackResolver};
+
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(((field as u64) << 3) | (wire_type as u64));
+    }
+
+    fn uint64(&mut self, field: u32, v: u64) {
+        if v != 0 {
+            self.key(field, 0);
+            self.varint(v);
+        }
+    }
+
+    fn int64(&mut self, field: u32, v: i64) {
+        self.uint64(field, v as u64)
+    }
+
+    fn bytes(&mut self, field: u32, v: &[u8]) {
+        self.key(field, 2);
+        self.varint(v.len() as u64);
+        self.0.extend_from_slice(v);
+    }
+
+    fn message(&mut self, field: u32, v: Message) {
+        self.bytes(field, &v.0)
+    }
+
+    fn packed(&mut self, field: u32, v: &[u64]) {
+        let mut inner = Message::default();
+        for x in v {
+            inner.varint(*x);
+        }
+        self.message(field, inner);
+    }
+}
+
+#[derive(Default)]
+struct StringTable {
+    strings: Vec<String>,
+    indexes: HashMap<String, i64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        let mut table = StringTable::default();
+        // the first string in the table must be empty
+        table.index("");
+        table
+    }
+
+    fn index(&mut self, s: &str) -> i64 {
+        if let Some(index) = self.indexes.get(s) {
+            return *index;
+        }
+        let index = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.indexes.insert(s.to_string(), index);
+        index
+    }
+}
+
+fn value_type(strings: &mut StringTable, ty: &str, unit: &str) -> Message {
+    let mut m = Message::default();
+    m.int64(1, strings.index(ty));
+    m.int64(2, strings.index(unit));
+    m
+}
+
+/// The report values are in KiB, the profile has two sample values in bytes,
+/// memory in use and the part of it which is page cache.
+pub fn encode<R>(report: &FrameReport<R>) -> io::Result<Vec<u8>>
+where
+    R: Deref<Target = StackResolver>,
+{
+    let resolver = report.resolver();
+    let mut strings = StringTable::new();
+    let mut profile = Message::default();
+
+    profile.message(1, value_type(&mut strings, "inuse_space", "bytes"));
+    profile.message(1, value_type(&mut strings, "cache_space", "bytes"));
+
+    // address -> location id, function name -> function id
+    let mut locations = HashMap::new();
+    let mut functions = HashMap::new();
+    let mut location_messages = Vec::new();
+    let mut function_messages = Vec::new();
+
+    let mut path = Vec::new();
+    report.inner.visit(&mut path, &mut |stack, value, cache_value| {
+        let mut location_ids = Vec::with_capacity(stack.len());
+        for ip in stack {
+            let next_id = locations.len() as u64 + 1;
+            let id = *locations.entry(ip.0).or_insert_with(|| {
+                let mut location = Message::default();
+                location.uint64(1, next_id);
+                location.uint64(3, ip.0);
+                if let Some(info) = resolver.resolve(ip.0) {
+                    let name = info
+                        .function_name()
+                        .map(str::to_string)
+                        .unwrap_or_else(|| format!("{:016x}", ip.0));
+                    let next_function_id = functions.len() as u64 + 1;
+                    let function_id = *functions.entry(name.clone()).or_insert_with(|| {
+                        let mut function = Message::default();
+                        function.uint64(1, next_function_id);
+                        function.int64(2, strings.index(&name));
+                        function.int64(3, strings.index(&name));
+                        function.int64(4, strings.index(info.executable()));
+                        function_messages.push(function);
+                        next_function_id
+                    });
+                    let mut line = Message::default();
+                    line.uint64(1, function_id);
+                    location.message(4, line);
+                }
+                location_messages.push(location);
+                next_id
+            });
+            location_ids.push(id);
+        }
+
+        let mut sample = Message::default();
+        sample.packed(1, &location_ids);
+        sample.packed(2, &[value * 1024, cache_value * 1024]);
+        profile.message(2, sample);
+    });
+
+    for location in location_messages {
+        profile.message(4, location);
+    }
+    for function in function_messages {
+        profile.message(5, function);
+    }
+    let period_type = value_type(&mut strings, "space", "bytes");
+    for s in &strings.strings {
+        profile.bytes(6, s.as_bytes());
+    }
+    let time_nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or(0);
+    profile.int64(9, time_nanos);
+    profile.message(11, period_type);
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&profile.0)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use bpf_memprof_common::{Hex64, Hex32, Stack};
+    use flate2::read::GzDecoder;
+    use crate::{Aggregator, Page, Tracker, Reporter, StackResolver};
+
+    #[test]
+    fn encode_gzip() {
+        let mut aggregator = Aggregator::default();
+        for i in 1..10 {
+            let stack = Stack::from_frames(&[i % 3, 0x100]);
+            Tracker::track_alloc(&mut aggregator, Page::new(Hex64(i), 0), &stack, Hex32(0), 0, 0);
+        }
+        let resolver = StackResolver::mock();
+        let report = aggregator.tree_report(&resolver, 0, false);
+        let profile = super::encode(&report).unwrap();
+
+        let mut decoded = Vec::new();
+        GzDecoder::new(profile.as_slice()).read_to_end(&mut decoded).unwrap();
+        // the first field is the sample type
+        assert_eq!(decoded[0], (1 << 3) | 2);
+        assert!(decoded.windows(b"inuse_space".len()).any(|w| w == b"inuse_space"));
+    }
+}
@@ -12,7 +12,7 @@ use warp::{
     http::StatusCode,
 };
 use serde::{Serialize, Deserialize};
-use super::{StackResolver, Reporter};
+use super::{StackResolver, Reporter, pprof};
 
 pub fn run<T>(
     reporter: Arc<Mutex<T>>,
@@ -38,9 +38,16 @@ where
 {
     use warp::reply::with;
 
+    let json = tree(reporter.clone(), resolver.clone(), pid.clone())
+        .or(regions(reporter.clone(), resolver.clone()))
+        .or(get_pid(pid))
+        .or(openapi())
+        .with(with::header("Content-Type", "application/json"));
+    let binary = pprof(reporter, resolver)
+        .with(with::header("Content-Type", "application/octet-stream"));
+
     warp::get()
-        .and(tree(reporter, resolver, pid.clone()).or(get_pid(pid)).or(openapi()))
-        .with(with::header("Content-Type", "application/json"))
+        .and(json.or(binary))
         .with(with::header("Access-Control-Allow-Origin", "*"))
 }
 
@@ -78,11 +85,19 @@ fn tree<T>(
 where
     T: Reporter + Send + 'static,
 {
+    #[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "camelCase")]
+    enum GroupBy {
+        Stack,
+        Thread,
+    }
+
     #[derive(Deserialize)]
     struct Params {
         threshold: Option<u64>,
         reverse: Option<bool>,
         short: Option<bool>,
+        group_by: Option<GroupBy>,
     }
 
     #[derive(Serialize)]
@@ -91,6 +106,7 @@ where
         total: u64,
         cache: u64,
         anon: u64,
+        mapped: u64,
         system_report_anon: u64,
     }
 
@@ -101,14 +117,25 @@ where
             let history = history.lock().unwrap();
             if params.short.unwrap_or(false) {
                 let (total, cache) = history.short_report();
+                let mapped = history.region_report(&*resolver).value();
                 let system_report_anon = rss_anon(pid.clone()).unwrap_or(0);
                 let report = ShortReport {
                     total,
                     cache,
                     anon: total - cache,
+                    mapped,
                     system_report_anon,
                 };
                 reply::with_status(reply::json(&report), StatusCode::OK)
+            } else if params.group_by == Some(GroupBy::Thread) {
+                let report = history
+                    .thread_report(
+                        resolver,
+                        params.threshold.unwrap_or(512),
+                        params.reverse.unwrap_or(false),
+                    )
+                    .with_names(pid.load(Ordering::Relaxed));
+                reply::with_status(reply::json(&report), StatusCode::OK)
             } else {
                 let report = history.tree_report(
                     resolver,
@@ -120,6 +147,46 @@ where
         })
 }
 
+fn regions<T>(
+    history: Arc<Mutex<T>>,
+    resolver: Arc<RwLock<StackResolver>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    T: Reporter + Send + 'static,
+{
+    warp::path!("v1" / "regions")
+        .and(warp::query::query())
+        .map(move |()| -> WithStatus<Json> {
+            let resolver = resolver.read().unwrap();
+            let history = history.lock().unwrap();
+            let report = history.region_report(resolver);
+            reply::with_status(reply::json(&report), StatusCode::OK)
+        })
+}
+
+fn pprof<T>(
+    history: Arc<Mutex<T>>,
+    resolver: Arc<RwLock<StackResolver>>,
+) -> impl Filter<Extract = (WithStatus<Vec<u8>>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    T: Reporter + Send + 'static,
+{
+    warp::path!("v1" / "pprof")
+        .and(warp::query::query())
+        .map(move |()| -> WithStatus<Vec<u8>> {
+            let resolver = resolver.read().unwrap();
+            let history = history.lock().unwrap();
+            let report = history.tree_report(resolver, 0, false);
+            match pprof::encode(&report) {
+                Ok(profile) => reply::with_status(profile, StatusCode::OK),
+                Err(error) => reply::with_status(
+                    error.to_string().into_bytes(),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ),
+            }
+        })
+}
+
 pub fn openapi() -> impl Filter<Extract=(WithStatus<Json>, ), Error=Rejection> + Clone + Sync + Send + 'static {
     warp::path!("openapi" / "memory-profiler-openapi.json")
         .and(warp::query::query())
@@ -19,6 +19,16 @@ pub struct SymbolInfo {
     function_category: String,
 }
 
+impl SymbolInfo {
+    pub fn executable(&self) -> &str {
+        &self.executable
+    }
+
+    pub fn function_name(&self) -> Option<&str> {
+        self.function_name.as_deref()
+    }
+}
+
 #[derive(Default)]
 pub struct StackResolver {
     files: HashMap<String, SymbolTable>,
@@ -147,6 +157,10 @@ impl StackResolver {
         self.mock.as_ref().map(|&()| ((0, "mock"), Some(format!("func_{}", address))))
     }
 
+    pub fn region_name(&self, address: u64) -> Option<String> {
+        self.map.as_ref()?.region_name(address as usize)
+    }
+
     pub fn resolve(&self, address: u64) -> Option<SymbolInfo> {
         let ((offset, filename), name) = self
             .try_resolve(address)
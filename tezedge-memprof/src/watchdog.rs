@@ -0,0 +1,242 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::VecDeque,
+    env,
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::Duration,
+};
+use serde::Serialize;
+use bpf_memprof_common::Hex64;
+use super::{Reporter, StackResolver, stack::SymbolInfo};
+
+/// Configured by environment variables:
+/// `MEMPROF_ALERT_LIMIT_KIB` total tracked allocation that triggers the alert,
+/// `MEMPROF_ALERT_GROWTH_MINUTES` how many minutes of monotonic growth triggers the alert,
+/// `MEMPROF_ALERT_WEBHOOK` url where the alert is posted, the alert is only logged otherwise.
+#[derive(Default, Clone)]
+pub struct WatchdogConfig {
+    pub limit_kib: Option<u64>,
+    pub growth_minutes: Option<usize>,
+    pub webhook: Option<String>,
+}
+
+impl WatchdogConfig {
+    pub fn from_env() -> Self {
+        WatchdogConfig {
+            limit_kib: env::var("MEMPROF_ALERT_LIMIT_KIB").ok().and_then(|s| s.parse().ok()),
+            growth_minutes: env::var("MEMPROF_ALERT_GROWTH_MINUTES")
+                .ok()
+                .and_then(|s| s.parse().ok()),
+            webhook: env::var("MEMPROF_ALERT_WEBHOOK").ok(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.limit_kib.is_some() || self.growth_minutes.is_some()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum AlertReason {
+    Limit { limit_kib: u64 },
+    Growth { minutes: usize, from_kib: u64 },
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AlertStack {
+    value: u64,
+    cache_value: u64,
+    frames: Vec<AlertFrame>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum AlertFrame {
+    Resolved(SymbolInfo),
+    Address(Hex64),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Alert {
+    reason: AlertReason,
+    total_kib: u64,
+    stacks: Vec<AlertStack>,
+}
+
+const TOP_STACKS: usize = 5;
+
+struct State {
+    config: WatchdogConfig,
+    limit_fired: bool,
+    samples: VecDeque<u64>,
+}
+
+impl State {
+    fn check(&mut self, total: u64) -> Option<AlertReason> {
+        if let Some(limit_kib) = self.config.limit_kib {
+            if total > limit_kib {
+                if !self.limit_fired {
+                    self.limit_fired = true;
+                    return Some(AlertReason::Limit { limit_kib });
+                }
+            } else {
+                self.limit_fired = false;
+            }
+        }
+
+        if let Some(minutes) = self.config.growth_minutes {
+            if self.samples.back().map(|last| total <= *last).unwrap_or(false) {
+                self.samples.clear();
+            }
+            self.samples.push_back(total);
+            // need `minutes + 1` samples to observe `minutes` intervals of growth
+            if self.samples.len() > minutes {
+                let from_kib = self.samples.pop_front().unwrap_or(0);
+                self.samples.clear();
+                self.samples.push_back(total);
+                return Some(AlertReason::Growth { minutes, from_kib });
+            }
+        }
+
+        None
+    }
+}
+
+fn top_stacks<T>(reporter: &T, resolver: &StackResolver) -> Vec<AlertStack>
+where
+    T: Reporter,
+{
+    let report = reporter.tree_report(resolver, 0, false);
+    let mut stacks = Vec::new();
+    let mut path = Vec::new();
+    report.inner.visit(&mut path, &mut |stack, value, cache_value| {
+        stacks.push((stack.to_vec(), value, cache_value));
+    });
+    stacks.sort_by(|a, b| b.1.cmp(&a.1));
+    stacks
+        .into_iter()
+        .take(TOP_STACKS)
+        .map(|(stack, value, cache_value)| AlertStack {
+            value,
+            cache_value,
+            frames: stack
+                .into_iter()
+                .map(|ip| match resolver.resolve(ip.0) {
+                    Some(info) => AlertFrame::Resolved(info),
+                    None => AlertFrame::Address(ip),
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+fn fire(config: &WatchdogConfig, alert: &Alert) {
+    let payload = serde_json::to_string(alert).unwrap_or_default();
+    log::error!("memory alert: {}", payload);
+    if let Some(url) = &config.webhook {
+        let client = reqwest::blocking::Client::new();
+        match client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(payload)
+            .send()
+        {
+            Ok(response) if !response.status().is_success() => {
+                log::error!("alert webhook responded {}", response.status());
+            },
+            Ok(_) => (),
+            Err(error) => log::error!("failed to post alert to webhook: {}", error),
+        }
+    }
+}
+
+pub struct Watchdog;
+
+impl Watchdog {
+    /// Checks the total tracked allocation once per minute
+    pub fn spawn<T>(
+        config: WatchdogConfig,
+        reporter: Arc<Mutex<T>>,
+        resolver: Arc<RwLock<StackResolver>>,
+    ) -> Option<thread::JoinHandle<()>>
+    where
+        T: Reporter + Send + 'static,
+    {
+        if !config.enabled() {
+            return None;
+        }
+
+        let handle = thread::spawn(move || {
+            let mut state = State {
+                config,
+                limit_fired: false,
+                samples: VecDeque::new(),
+            };
+            loop {
+                thread::sleep(Duration::from_secs(60));
+
+                let (total, _) = reporter.lock().unwrap().short_report();
+                if let Some(reason) = state.check(total) {
+                    let stacks = {
+                        let resolver = resolver.read().unwrap();
+                        let reporter = reporter.lock().unwrap();
+                        top_stacks(&*reporter, &resolver)
+                    };
+                    let alert = Alert {
+                        reason,
+                        total_kib: total,
+                        stacks,
+                    };
+                    fire(&state.config, &alert);
+                }
+            }
+        });
+
+        Some(handle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use super::{State, WatchdogConfig, AlertReason};
+
+    fn state(limit_kib: Option<u64>, growth_minutes: Option<usize>) -> State {
+        State {
+            config: WatchdogConfig {
+                limit_kib,
+                growth_minutes,
+                webhook: None,
+            },
+            limit_fired: false,
+            samples: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn limit_fires_once() {
+        let mut s = state(Some(100), None);
+        assert!(s.check(50).is_none());
+        assert!(matches!(s.check(150), Some(AlertReason::Limit { .. })));
+        assert!(s.check(160).is_none());
+        assert!(s.check(50).is_none());
+        assert!(matches!(s.check(150), Some(AlertReason::Limit { .. })));
+    }
+
+    #[test]
+    fn growth() {
+        let mut s = state(None, Some(3));
+        assert!(s.check(10).is_none());
+        assert!(s.check(20).is_none());
+        assert!(s.check(15).is_none());
+        assert!(s.check(16).is_none());
+        assert!(s.check(17).is_none());
+        assert!(matches!(s.check(18), Some(AlertReason::Growth { from_kib: 15, .. })));
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use serde::Serialize;
+use serde_json::Value;
+use crypto::blake2b;
+use super::tables::peer;
+
+/// Replaces the addresses, the public keys and the peer ids by pseudonyms,
+/// the same value gets the same pseudonym within one anonymizer, the pseudonyms of
+/// the keys are salted, so different exports are not linkable.
+/// The raw bytes are dropped, the keys cannot be replaced there without re-encoding.
+pub struct Anonymizer {
+    salt: [u8; 32],
+    ips: HashMap<IpAddr, IpAddr>,
+    keys: HashMap<[u8; 32], [u8; 32]>,
+    peer_ids: HashMap<String, String>,
+}
+
+impl Default for Anonymizer {
+    fn default() -> Self {
+        Anonymizer::new()
+    }
+}
+
+impl Anonymizer {
+    const RAW_FIELDS: [&'static str; 2] = ["original_bytes", "decrypted_bytes"];
+    const KEY_FIELDS: [&'static str; 2] = ["public_key", "proof_of_work_stamp"];
+
+    pub fn new() -> Self {
+        Anonymizer {
+            salt: rand::random(),
+            ips: HashMap::new(),
+            keys: HashMap::new(),
+            peer_ids: HashMap::new(),
+        }
+    }
+
+    /// The json of the value with the pseudonyms
+    pub fn anonymize<T>(mut self, value: &T) -> Value
+    where
+        T: Serialize,
+    {
+        let mut value = serde_json::to_value(value).unwrap_or_default();
+        self.value(&mut value);
+        value
+    }
+
+    fn value(&mut self, value: &mut Value) {
+        match value {
+            Value::Object(fields) => {
+                for (name, field) in fields.iter_mut() {
+                    if Self::RAW_FIELDS.contains(&name.as_str()) {
+                        *field = Value::Array(vec![]);
+                    } else if Self::KEY_FIELDS.contains(&name.as_str()) {
+                        self.key_field(field);
+                    } else {
+                        self.value(field);
+                    }
+                }
+            },
+            Value::Array(items) => items.iter_mut().for_each(|item| self.value(item)),
+            Value::String(s) => *s = self.text(s),
+            _ => (),
+        }
+    }
+
+    // the key is either the hex string or the array of bytes
+    fn key_field(&mut self, field: &mut Value) {
+        match field {
+            Value::String(s) => {
+                let key = hex::decode(&s)
+                    .ok()
+                    .and_then(|v| <[u8; 32]>::try_from(v).ok());
+                if let Some(key) = key {
+                    *s = hex::encode(self.key(key));
+                }
+            },
+            Value::Array(items) => {
+                let bytes = items
+                    .iter()
+                    .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()))
+                    .collect::<Option<Vec<u8>>>()
+                    .and_then(|v| <[u8; 32]>::try_from(v).ok());
+                match bytes {
+                    Some(key) => {
+                        let pseudonym = self.key(key);
+                        *items = pseudonym.iter().map(|&b| Value::from(b)).collect();
+                    },
+                    // the proof of work stamp is not 32 bytes
+                    None => items.iter_mut().for_each(|b| *b = Value::from(0)),
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn key(&mut self, key: [u8; 32]) -> [u8; 32] {
+        if let Some(pseudonym) = self.keys.get(&key) {
+            return *pseudonym;
+        }
+        let pseudonym = self.hash(&key);
+        // the peer id of the key maps to the peer id of the pseudonym
+        if let (Ok(id), Ok(pseudo_id)) = (peer::peer_id(&key), peer::peer_id(&pseudonym)) {
+            self.peer_ids.insert(id, pseudo_id);
+        }
+        self.keys.insert(key, pseudonym);
+        pseudonym
+    }
+
+    fn hash(&self, data: &[u8]) -> [u8; 32] {
+        let mut input = self.salt.to_vec();
+        input.extend_from_slice(data);
+        blake2b::digest_256(&input)
+            .ok()
+            .and_then(|v| <[u8; 32]>::try_from(v).ok())
+            .unwrap_or_default()
+    }
+
+    fn peer_id(&mut self, id: &str) -> String {
+        if let Some(pseudonym) = self.peer_ids.get(id) {
+            return pseudonym.clone();
+        }
+        let pseudonym = peer::peer_id(&self.hash(id.as_bytes())).unwrap_or_default();
+        self.peer_ids.insert(id.to_string(), pseudonym.clone());
+        pseudonym
+    }
+
+    fn ip(&mut self, ip: IpAddr) -> IpAddr {
+        if ip.is_loopback() || ip.is_unspecified() {
+            return ip;
+        }
+        let n = self.ips.len() as u32 + 1;
+        *self.ips.entry(ip).or_insert_with(|| match ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::from(0x0a00_0000 | (n & 0x00ff_ffff))),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::from((0xfd00 << 112) | n as u128)),
+        })
+    }
+
+    // the word is replaced if it is the address or the peer id
+    fn word(&mut self, word: &str) -> Option<String> {
+        if let Ok(ip) = word.parse::<IpAddr>() {
+            return Some(self.ip(ip).to_string());
+        }
+        if let Ok(addr) = word.parse::<SocketAddr>() {
+            return Some(SocketAddr::new(self.ip(addr.ip()), addr.port()).to_string());
+        }
+        if word.len() == 36 && word.starts_with("idt") && word.is_ascii() {
+            return Some(self.peer_id(word));
+        }
+        None
+    }
+
+    // the addresses and the peer ids in the free text, like the log message
+    fn text(&mut self, text: &str) -> String {
+        let punctuation = |c: char| matches!(c, ',' | ';' | '(' | ')' | '"' | '\'' | '<' | '>');
+        text.split(' ')
+            .map(|token| {
+                let word = token.trim_matches(punctuation);
+                match self.word(word) {
+                    Some(pseudonym) => token.replacen(word, &pseudonym, 1),
+                    None => token.to_string(),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
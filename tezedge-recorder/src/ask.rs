@@ -0,0 +1,270 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+};
+use serde::Serialize;
+use super::{
+    database::{DatabaseFetch, ConnectionsFilter, MessagesFilter},
+    tables::{
+        connection::{self, AckInfo, Comments, TerminationKind},
+        message::{MessageFrontend, TezosMessage},
+        peer,
+    },
+};
+
+/// The peer as the operator knows it, the address, the ip or the peer id
+pub enum Peer {
+    Addr(SocketAddr),
+    Ip(IpAddr),
+    Id(String),
+}
+
+impl Peer {
+    pub fn parse(s: &str) -> Self {
+        if let Ok(addr) = s.parse() {
+            Peer::Addr(addr)
+        } else if let Ok(ip) = s.parse() {
+            Peer::Ip(ip)
+        } else {
+            Peer::Id(s.to_string())
+        }
+    }
+
+    fn matches(&self, value: &connection::Value) -> bool {
+        match self {
+            Peer::Addr(addr) => value.remote_addr() == *addr,
+            Peer::Ip(ip) => value.remote_addr().ip() == *ip,
+            Peer::Id(id) => peer::peer_id(value.peer_pk()).map_or(false, |p| &p == id),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LastHead {
+    pub message: MessageFrontend,
+    /// the decoded `current_head`, absent if it cannot be decoded
+    pub head: Option<TezosMessage>,
+}
+
+#[derive(Serialize)]
+pub struct Advertiser {
+    pub remote_addr: SocketAddr,
+    pub peer_id: Option<String>,
+    pub messages: u64,
+    /// unix nanoseconds
+    pub first_seen: u128,
+    pub last_seen: u128,
+}
+
+#[derive(Serialize)]
+pub struct Disconnection {
+    pub connection: String,
+    pub remote_addr: SocketAddr,
+    /// the human readable explanation, the most certain first
+    pub reasons: Vec<String>,
+    pub termination: Option<TerminationKind>,
+    pub incoming_ack: Option<AckInfo>,
+    pub outgoing_ack: Option<AckInfo>,
+    /// the last `disconnect` message of the connection
+    pub disconnect: Option<MessageFrontend>,
+    pub comments: Comments,
+}
+
+/// The answers to the common questions of the operator,
+/// each one is composed of the queries to the existing stores
+pub struct Ask<'a, Db> {
+    db: &'a Db,
+}
+
+impl<'a, Db> Ask<'a, Db>
+where
+    Db: DatabaseFetch,
+{
+    // the messages mentioning the block which are inspected
+    const ADVERTISED_LIMIT: u64 = 0x1000;
+
+    pub fn new(db: &'a Db) -> Self {
+        Ask { db }
+    }
+
+    fn db_error(error: Db::Error) -> String {
+        format!("database error: {}", error)
+    }
+
+    /// Every connection, the oldest first
+    fn all_connections(&self) -> Result<Vec<(connection::Key, connection::Value)>, String> {
+        let filter = ConnectionsFilter {
+            limit: Some(u64::MAX),
+            nack_motive: None,
+            termination: None,
+            handshake_slower_than: None,
+            after: None,
+            external: Some(false),
+        };
+        self.db.fetch_connections(&filter).map_err(Self::db_error)
+    }
+
+    fn connections(
+        &self,
+        peer: &Peer,
+    ) -> Result<Vec<(connection::Key, connection::Value)>, String> {
+        let mut connections = self.all_connections()?;
+        connections.retain(|(_, value)| peer.matches(value));
+        Ok(connections)
+    }
+
+    fn messages(&self, filter: MessagesFilter) -> Result<Vec<MessageFrontend>, String> {
+        self.db.fetch_messages(&filter).map_err(Self::db_error)
+    }
+
+    /// The latest `current_head` the peer sent
+    pub fn last_head_from(&self, peer: &Peer) -> Result<Option<LastHead>, String> {
+        let mut addrs = match peer {
+            Peer::Addr(addr) => vec![*addr],
+            _ => self
+                .connections(peer)?
+                .into_iter()
+                .map(|(_, value)| value.remote_addr())
+                .collect(),
+        };
+        addrs.sort();
+        addrs.dedup();
+
+        let mut last = None::<MessageFrontend>;
+        for addr in addrs {
+            let filter = MessagesFilter {
+                limit: Some(1),
+                remote_addr: Some(addr.to_string()),
+                incoming: Some(true),
+                types: Some("current_head".to_string()),
+                ..MessagesFilter::default()
+            };
+            for message in self.messages(filter)? {
+                if last.as_ref().map_or(true, |last| message.id > last.id) {
+                    last = Some(message);
+                }
+            }
+        }
+        let message = match last {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        let head = self
+            .db
+            .fetch_message(message.id)
+            .map_err(Self::db_error)?
+            .and_then(|details| details.message);
+        Ok(Some(LastHead { message, head }))
+    }
+
+    /// The peers which sent the `current_head` or the `current_branch` mentioning the block,
+    /// the earliest first
+    pub fn peers_advertising(&self, block_hash: &str) -> Result<Vec<Advertiser>, String> {
+        let filter = MessagesFilter {
+            limit: Some(Self::ADVERTISED_LIMIT),
+            incoming: Some(true),
+            types: Some("current_head,current_branch".to_string()),
+            block_hash: Some(block_hash.to_string()),
+            fields: Some("id,timestamp,remote_addr".to_string()),
+            ..MessagesFilter::default()
+        };
+        let mut advertisers = BTreeMap::<SocketAddr, Advertiser>::new();
+        for message in self.messages(filter)? {
+            let advertiser = advertisers
+                .entry(message.remote_addr)
+                .or_insert_with(|| Advertiser {
+                    remote_addr: message.remote_addr,
+                    peer_id: None,
+                    messages: 0,
+                    first_seen: message.timestamp,
+                    last_seen: message.timestamp,
+                });
+            advertiser.messages += 1;
+            advertiser.first_seen = advertiser.first_seen.min(message.timestamp);
+            advertiser.last_seen = advertiser.last_seen.max(message.timestamp);
+        }
+        // the latest connection from the address tells the peer id
+        for (_, value) in self.all_connections()? {
+            if let Some(advertiser) = advertisers.get_mut(&value.remote_addr()) {
+                if let Ok(peer_id) = peer::peer_id(value.peer_pk()) {
+                    advertiser.peer_id = Some(peer_id);
+                }
+            }
+        }
+        let mut advertisers = advertisers.into_iter().map(|(_, a)| a).collect::<Vec<_>>();
+        advertisers.sort_by_key(|a| a.first_seen);
+        Ok(advertisers)
+    }
+
+    /// Why the latest connection of the peer ended, or is ending,
+    /// combines the termination, the acknowledge messages and the comments of the connection
+    pub fn why_disconnected(&self, peer: &Peer) -> Result<Option<Disconnection>, String> {
+        let (key, value) = match self.connections(peer)?.pop() {
+            Some(connection) => connection,
+            None => return Ok(None),
+        };
+        let start = (key.ts as u128) * 1_000_000_000 + key.ts_nanos as u128;
+        let filter = MessagesFilter {
+            limit: Some(1),
+            remote_addr: Some(value.remote_addr().to_string()),
+            types: Some("disconnect".to_string()),
+            ..MessagesFilter::default()
+        };
+        let disconnect = self
+            .messages(filter)?
+            .into_iter()
+            .find(|message| message.timestamp >= start);
+
+        let mut reasons = vec![];
+        let acks = value.acks();
+        let nacks = [
+            (&acks.incoming, "the peer"),
+            (&acks.outgoing, "the local node"),
+        ];
+        for (ack, who) in &nacks {
+            match ack {
+                Some(AckInfo::Nack { motive, .. }) => {
+                    reasons.push(format!("{} rejected the connection: {:?}", who, motive));
+                },
+                Some(AckInfo::NackV0) => {
+                    reasons.push(format!("{} rejected the connection", who));
+                },
+                _ => (),
+            }
+        }
+        if let Some(message) = &disconnect {
+            let who = if message.incoming { "the peer" } else { "the local node" };
+            reasons.push(format!("{} sent the disconnect message", who));
+        }
+        let termination = value.termination().map(|t| t.kind);
+        reasons.push(
+            match termination {
+                Some(TerminationKind::Close) => "the local node closed the socket",
+                Some(TerminationKind::Fin) => "the peer closed the socket",
+                Some(TerminationKind::Reset) => "the peer reset the connection",
+                None => "the connection is not closed",
+            }
+            .to_string(),
+        );
+        if value.comments().incoming_wrong_pow.is_some() {
+            reasons.push("the proof of work of the peer is wrong".to_string());
+        }
+        if value.comments().outgoing_wrong_pk {
+            reasons.push("the public key of the local node is wrong".to_string());
+        }
+
+        Ok(Some(Disconnection {
+            connection: key.to_string(),
+            remote_addr: value.remote_addr(),
+            reasons,
+            termination,
+            incoming_ack: acks.incoming.clone(),
+            outgoing_ack: acks.outgoing.clone(),
+            disconnect,
+            comments: value.comments().clone(),
+        }))
+    }
+}
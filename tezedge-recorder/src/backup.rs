@@ -0,0 +1,368 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+use s3::{bucket::Bucket, creds::Credentials, region::Region};
+use super::{
+    database::{self, DatabaseFetch},
+    health::{Health, Status},
+};
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupConfig {
+    // the url of the S3 compatible storage, the AWS endpoint of the `region` if absent
+    endpoint: Option<String>,
+    region: String,
+    bucket: String,
+    // the keys start with the prefix and the node name
+    prefix: Option<String>,
+    // hours between the backups, 24 by default
+    interval_hours: Option<u64>,
+    // how many backups of each node are kept, 7 by default
+    retention: Option<usize>,
+}
+
+impl BackupConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_hours.unwrap_or(24).max(1) * 3600)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("object storage: {}", _0)]
+    Storage(String),
+    #[error("object storage returned {} for {}", _1, _0)]
+    Status(String, u16),
+    #[error("checkpoint: {}", _0)]
+    Checkpoint(String),
+    #[error("{}: {}", _0, _1)]
+    Io(String, io::Error),
+    #[error("bad manifest {}: {}", _0, _1)]
+    Manifest(String, serde_json::Error),
+    #[error("background task: {}", _0)]
+    Task(tokio::task::JoinError),
+    #[error("no backup {}", _0)]
+    NoBackup(String),
+    #[error("{} exists, remove it before the restore", _0)]
+    Exists(String),
+}
+
+fn storage<E>(error: E) -> BackupError
+where
+    E: std::fmt::Display,
+{
+    BackupError::Storage(error.to_string())
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> BackupError + '_ {
+    move |error| BackupError::Io(path.display().to_string(), error)
+}
+
+/// The checkpoint and the removal of the directory block, so they run off the runtime threads
+async fn blocking<F, T>(f: F) -> Result<T, BackupError>
+where
+    F: FnOnce() -> Result<T, BackupError> + Send + 'static,
+    T: Send + 'static,
+{
+    database::blocking(f).await.map_err(BackupError::Task)?
+}
+
+fn remove_dir(path: &Path) -> Result<(), BackupError> {
+    fs::remove_dir_all(path).map_err(io_error(path))
+}
+
+/// The files of the backup and where they are in the bucket,
+/// it is written the last, so the backup without the manifest is incomplete
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    id: String,
+    files: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    name: String,
+    key: String,
+    size: u64,
+}
+
+/// The backups of one node in the bucket, the layout under `{prefix}/{node}/` is:
+/// * `files/{identity}/{name}` the table files, they never change, so they are shared
+///   by the backups and only the new ones are uploaded, which makes the backup incremental,
+///   the `IDENTITY` of the database tells apart the files of the database created anew,
+///   which numbers its files from the start again
+/// * `backups/{id}/{name}` the rest of the files of the backup, they are small
+/// * `backups/{id}/manifest.json`
+pub struct Backup {
+    bucket: Bucket,
+    root: String,
+    retention: usize,
+}
+
+impl Backup {
+    const CHECKPOINT_DIR: &'static str = "backup_checkpoint";
+
+    pub fn new(config: &BackupConfig, node: &str) -> Result<Self, BackupError> {
+        let credentials = Credentials::default().map_err(storage)?;
+        let bucket = match &config.endpoint {
+            Some(endpoint) => {
+                let region = Region::Custom {
+                    region: config.region.clone(),
+                    endpoint: endpoint.clone(),
+                };
+                Bucket::new_with_path_style(&config.bucket, region, credentials)
+            },
+            None => {
+                let region = config.region.parse::<Region>().map_err(storage)?;
+                Bucket::new(&config.bucket, region, credentials)
+            },
+        }
+        .map_err(storage)?;
+        let root = match &config.prefix {
+            Some(prefix) => format!("{}/{}/", prefix.trim_end_matches('/'), node),
+            None => format!("{}/", node),
+        };
+        Ok(Backup {
+            bucket,
+            root,
+            retention: config.retention.unwrap_or(7).max(1),
+        })
+    }
+
+    async fn list(&self, prefix: &str) -> Result<HashMap<String, u64>, BackupError> {
+        let prefix = format!("{}{}", self.root, prefix);
+        let pages = self.bucket.list(prefix, None).await.map_err(storage)?;
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| (object.key, object.size))
+            .collect())
+    }
+
+    async fn put(&self, key: &str, content: &[u8]) -> Result<(), BackupError> {
+        let key = format!("{}{}", self.root, key);
+        let (_, code) = self.bucket.put_object(&key, content).await.map_err(storage)?;
+        if code != 200 {
+            return Err(BackupError::Status(key, code));
+        }
+        Ok(())
+    }
+
+    /// The file is streamed, the table file might be bigger than the memory
+    async fn put_file(&self, key: &str, path: &Path) -> Result<(), BackupError> {
+        let key = format!("{}{}", self.root, key);
+        let mut file = tokio::fs::File::open(path).await.map_err(io_error(path))?;
+        let code = self
+            .bucket
+            .put_object_stream(&mut file, &key)
+            .await
+            .map_err(storage)?;
+        if code != 200 {
+            return Err(BackupError::Status(key, code));
+        }
+        Ok(())
+    }
+
+    async fn get_file(&self, key: &str, path: &Path) -> Result<(), BackupError> {
+        let key = format!("{}{}", self.root, key);
+        let mut file = tokio::fs::File::create(path).await.map_err(io_error(path))?;
+        let code = self
+            .bucket
+            .get_object_stream(&key, &mut file)
+            .await
+            .map_err(storage)?;
+        if code != 200 {
+            return Err(BackupError::Status(key, code));
+        }
+        file.sync_all().await.map_err(io_error(path))
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, BackupError> {
+        let key = format!("{}{}", self.root, key);
+        let (content, code) = self.bucket.get_object(&key).await.map_err(storage)?;
+        if code != 200 {
+            return Err(BackupError::Status(key, code));
+        }
+        Ok(content)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BackupError> {
+        // the key is listed, so it has the root already
+        self.bucket.delete_object(key).await.map_err(storage)?;
+        Ok(())
+    }
+
+    async fn manifest(&self, id: &str) -> Result<Manifest, BackupError> {
+        let key = format!("backups/{}/manifest.json", id);
+        let content = self.get(&key).await?;
+        serde_json::from_slice(&content).map_err(|error| BackupError::Manifest(key, error))
+    }
+
+    /// The complete backups, the oldest first
+    pub async fn ids(&self) -> Result<Vec<String>, BackupError> {
+        let prefix = format!("{}backups/", self.root);
+        Ok(self
+            .list("backups/")
+            .await?
+            .into_iter()
+            .filter_map(|(key, _)| {
+                let id = key.strip_prefix(&prefix)?.strip_suffix("/manifest.json")?;
+                Some(id.to_string())
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect())
+    }
+
+    /// Makes the checkpoint of the database in its directory, uploads it and removes it
+    pub async fn run<Db>(&self, db: Arc<Db>, db_path: &Path) -> Result<String, BackupError>
+    where
+        Db: DatabaseFetch + Sync + Send + 'static,
+    {
+        let id = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let checkpoint = db_path.join(Self::CHECKPOINT_DIR);
+        let path = checkpoint.clone();
+        let identity_path = db_path.join("rocksdb").join("IDENTITY");
+        let identity = blocking(move || {
+            // left by the interrupted backup
+            if path.exists() {
+                remove_dir(&path)?;
+            }
+            let identity = fs::read_to_string(&identity_path).map_err(io_error(&identity_path))?;
+            db.checkpoint(&path)
+                .map_err(|error| BackupError::Checkpoint(error.to_string()))?;
+            Ok(identity.trim().to_string())
+        })
+        .await?;
+        let result = self.upload(&id, &identity, &checkpoint).await;
+        let path = checkpoint.clone();
+        blocking(move || remove_dir(&path)).await?;
+        result?;
+        self.expire().await?;
+        Ok(id)
+    }
+
+    async fn upload(&self, id: &str, identity: &str, checkpoint: &Path) -> Result<(), BackupError> {
+        let uploaded = self.list("files/").await?;
+        let mut files = Vec::new();
+        for entry in fs::read_dir(checkpoint).map_err(io_error(checkpoint))? {
+            let path = entry.map_err(io_error(checkpoint))?.path();
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let size = fs::metadata(&path).map_err(io_error(&path))?.len();
+            let key = if name.ends_with(".sst") {
+                format!("files/{}/{}", identity, name)
+            } else {
+                format!("backups/{}/{}", id, name)
+            };
+            if uploaded.get(&format!("{}{}", self.root, key)) != Some(&size) {
+                self.put_file(&key, &path).await?;
+            }
+            files.push(Entry { name, key, size });
+        }
+        let manifest = Manifest {
+            id: id.to_string(),
+            files,
+        };
+        let content = serde_json::to_vec_pretty(&manifest).unwrap_or_default();
+        self.put(&format!("backups/{}/manifest.json", id), &content)
+            .await
+    }
+
+    /// Removes the backups beyond the retention and the table files no backup refers to
+    async fn expire(&self) -> Result<(), BackupError> {
+        let ids = self.ids().await?;
+        let expired = ids.len().saturating_sub(self.retention);
+        for id in &ids[..expired] {
+            for key in self.list(&format!("backups/{}/", id)).await?.keys() {
+                self.delete(key).await?;
+            }
+            log::info!("backup {} is expired", id);
+        }
+        let mut referenced = BTreeSet::new();
+        for id in &ids[expired..] {
+            let manifest = self.manifest(id).await?;
+            referenced.extend(
+                manifest
+                    .files
+                    .into_iter()
+                    .map(|entry| format!("{}{}", self.root, entry.key)),
+            );
+        }
+        for key in self.list("files/").await?.keys() {
+            if !referenced.contains(key) {
+                self.delete(key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Downloads the backup, the latest if the `id` is `None`, into the database directory,
+    /// the database of the node must not exist, returns the id of the restored backup
+    pub async fn restore(&self, id: Option<&str>, db_path: &Path) -> Result<String, BackupError> {
+        let target = db_path.join("rocksdb");
+        if target.exists() {
+            return Err(BackupError::Exists(target.display().to_string()));
+        }
+        let id = match id {
+            Some(id) => id.to_string(),
+            None => self
+                .ids()
+                .await?
+                .pop()
+                .ok_or_else(|| BackupError::NoBackup(self.root.clone()))?,
+        };
+        let manifest = self.manifest(&id).await.map_err(|error| match error {
+            BackupError::Status(_, 404) => BackupError::NoBackup(id.clone()),
+            error => error,
+        })?;
+        let partial = db_path.join("rocksdb.restoring");
+        if partial.exists() {
+            remove_dir(&partial)?;
+        }
+        fs::create_dir_all(&partial).map_err(io_error(&partial))?;
+        for entry in &manifest.files {
+            self.get_file(&entry.key, &partial.join(&entry.name)).await?;
+        }
+        fs::rename(&partial, &target).map_err(io_error(&target))?;
+        Ok(id)
+    }
+}
+
+/// Backs up the database every `interval`, the first backup is after the first interval,
+/// the outcome is reported as the `backup/{node}` component of the health
+pub async fn schedule<Db>(
+    backup: Backup,
+    db: Arc<Db>,
+    db_path: PathBuf,
+    interval: Duration,
+    health: Arc<Health>,
+    component: String,
+) where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match backup.run(db.clone(), &db_path).await {
+            Ok(id) => {
+                log::info!("backup {} of {} is done", id, db_path.display());
+                health.set(&component, Status::Up);
+            },
+            Err(error) => health.set(&component, Status::Down(error.to_string())),
+        }
+    }
+}
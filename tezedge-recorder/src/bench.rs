@@ -0,0 +1,264 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fs, io,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{Ordering, AtomicU64},
+    },
+    time::{Duration, Instant},
+};
+use serde::Serialize;
+use thiserror::Error;
+use super::{
+    system::Identity,
+    processor::{Connection, ChunkLimits},
+    pipeline::Pipeline,
+    common::Sender,
+    tables::{connection, chunk, message, node_log, peer},
+    database::{
+        Database, DatabaseNew, DatabaseFetch, ConnectionsFilter,
+        rocks::{self, DbError},
+    },
+};
+
+#[derive(Error, Debug)]
+pub enum BenchError {
+    #[error("snapshot: {}", _0)]
+    Snapshot(DbError),
+    #[error("output: {}", _0)]
+    Output(DbError),
+    #[error("cannot clear the output: {}", _0)]
+    Clear(io::Error),
+}
+
+#[derive(Serialize)]
+pub struct Latency {
+    pub count: usize,
+    /// microseconds
+    pub p50: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Latency {
+    fn new(mut samples: Vec<u64>) -> Self {
+        samples.sort_unstable();
+        let percentile = |p: usize| -> u64 {
+            if samples.is_empty() {
+                0
+            } else {
+                samples[((samples.len() * p + 99) / 100).max(1) - 1]
+            }
+        };
+        Latency {
+            count: samples.len(),
+            p50: percentile(50),
+            p99: percentile(99),
+            max: samples.last().cloned().unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub connections: u64,
+    pub chunks: u64,
+    pub bytes: u64,
+    pub messages: u64,
+    /// seconds
+    pub elapsed: f64,
+    pub messages_per_sec: f64,
+    pub bytes_per_sec: f64,
+    /// decryption and parsing of the chunk, without storing the result
+    pub parse: Latency,
+    /// storing one record, a connection, a chunk or a message
+    pub store: Latency,
+    /// kilobytes, the high water mark of the resident memory of the process
+    pub peak_rss: Option<u64>,
+}
+
+/// Measures the time the processor spends in the database
+struct Timed<Db> {
+    inner: Db,
+    samples: Mutex<Vec<u64>>,
+    total: AtomicU64,
+}
+
+impl<Db> Timed<Db> {
+    fn measure<F>(&self, f: F)
+    where
+        F: FnOnce(&Db),
+    {
+        let start = Instant::now();
+        f(&self.inner);
+        let elapsed = start.elapsed();
+        self.total.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.samples.lock().unwrap().push(elapsed.as_micros() as u64);
+    }
+}
+
+impl<Db> Database for Timed<Db>
+where
+    Db: Database,
+{
+    fn store_connection(&self, item: connection::Item) {
+        self.measure(|db| db.store_connection(item))
+    }
+
+    fn update_connection(&self, item: connection::Item) {
+        self.measure(|db| db.update_connection(item))
+    }
+
+    fn store_chunk(&self, item: chunk::Item) {
+        self.measure(|db| db.store_chunk(item))
+    }
+
+    fn store_message(&self, item: message::Item) {
+        self.measure(|db| db.store_message(item))
+    }
+
+    fn count_message(&self, item: message::Item) {
+        self.measure(|db| db.count_message(item))
+    }
+
+    fn store_log(&self, item: node_log::Item) {
+        self.measure(|db| db.store_log(item))
+    }
+
+    fn store_peer(&self, item: peer::Item) {
+        self.measure(|db| db.store_peer(item))
+    }
+
+    fn set_store_limits(&self, message_store_limit: Option<u64>, log_store_limit: Option<u64>) {
+        self.inner.set_store_limits(message_store_limit, log_store_limit)
+    }
+}
+
+/// Feeds the chunks recorded in the `snapshot` through the parser and the storage again,
+/// as fast as possible, into the fresh database at `output`, the `identity` must be
+/// the identity of the node which recorded the snapshot, otherwise nothing is decrypted
+pub fn run<P, Q>(snapshot: P, output: Q, identity: Identity) -> Result<Report, BenchError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let snapshot = rocks::Db::open(snapshot, false, None, None, None, Some(0), None)
+        .map_err(BenchError::Snapshot)?;
+    if output.as_ref().exists() {
+        fs::remove_dir_all(&output).map_err(BenchError::Clear)?;
+    }
+    let output = rocks::Db::open(output, false, None, None, None, Some(0), None)
+        .map_err(BenchError::Output)?;
+    let db = Arc::new(Timed {
+        inner: output,
+        samples: Mutex::new(vec![]),
+        total: AtomicU64::new(0),
+    });
+    let pipeline = Pipeline::default();
+    let stage = pipeline.stage("processor", None);
+
+    let filter = ConnectionsFilter {
+        limit: Some(u64::MAX),
+        nack_motive: None,
+        termination: None,
+        handshake_slower_than: None,
+        after: None,
+        external: None,
+    };
+    let mut parse = vec![];
+    let (mut connections, mut chunks, mut bytes) = (0, 0, 0);
+    let mut elapsed = Duration::default();
+    let cns = snapshot
+        .fetch_connections(&filter)
+        .map_err(BenchError::Snapshot)?;
+    for (key, value) in cns {
+        let incoming = value.initiator().incoming();
+        let mut cn = Connection::new(
+            value.remote_addr(),
+            incoming,
+            identity.clone(),
+            ChunkLimits::default(),
+            db.clone(),
+            stage.clone(),
+            Arc::default(),
+            Arc::default(),
+            None,
+        );
+        connections += 1;
+        for (sender, chunk) in ordered(&snapshot, key).map_err(BenchError::Snapshot)? {
+            let stored_before = db.total.load(Ordering::Relaxed);
+            let start = Instant::now();
+            cn.handle_data(&chunk.bytes, true, sender.incoming());
+            let total = start.elapsed();
+            let stored = db.total.load(Ordering::Relaxed) - stored_before;
+            let parsed = total.saturating_sub(Duration::from_nanos(stored));
+            parse.push(parsed.as_micros() as u64);
+            elapsed += total;
+            chunks += 1;
+            bytes += chunk.bytes.len() as u64;
+        }
+        cn.join();
+    }
+
+    let messages = pipeline.report().iter().map(|s| s.processed).sum::<u64>();
+    let seconds = elapsed.as_secs_f64();
+    let rate = |x: u64| if seconds > 0.0 { x as f64 / seconds } else { 0.0 };
+    let store = std::mem::take(&mut *db.samples.lock().unwrap());
+    Ok(Report {
+        connections,
+        chunks,
+        bytes,
+        messages,
+        elapsed: seconds,
+        messages_per_sec: rate(messages),
+        bytes_per_sec: rate(bytes),
+        parse: Latency::new(parse),
+        store: Latency::new(store),
+        peak_rss: peak_rss(),
+    })
+}
+
+/// The chunks of the connection in the order they were captured, the first chunk
+/// of each side goes first, the handshake needs both connection messages
+fn ordered(
+    snapshot: &rocks::Db,
+    cn_id: connection::Key,
+) -> Result<Vec<(Sender, chunk::Value)>, DbError> {
+    let side = |sender: Sender| -> Result<Vec<(Sender, chunk::Value)>, DbError> {
+        let mut chunks = vec![];
+        loop {
+            let key = chunk::Key {
+                cn_id: cn_id.clone(),
+                counter: chunks.len() as u64,
+                sender: sender.clone(),
+            };
+            match snapshot.fetch_chunk(&key)? {
+                Some(value) => chunks.push((sender.clone(), value)),
+                None => break Ok(chunks),
+            }
+        }
+    };
+    let mut local = side(Sender::Local)?.into_iter();
+    let mut remote = side(Sender::Remote)?.into_iter();
+    let mut chunks = local.next().into_iter().chain(remote.next()).collect::<Vec<_>>();
+    let mut rest = local.chain(remote).collect::<Vec<_>>();
+    // stable, so the chunks of the same second keep their order
+    rest.sort_by_key(|(_, value)| value.timestamp());
+    chunks.extend(rest);
+    Ok(chunks)
+}
+
+/// `VmHWM` of `/proc/self/status`, kilobytes
+fn peak_rss() -> Option<u64> {
+    fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find(|line| line.starts_with("VmHWM:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
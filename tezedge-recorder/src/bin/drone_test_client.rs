@@ -0,0 +1,190 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+#![forbid(unsafe_code)]
+
+use std::{
+    net::{SocketAddr, TcpStream},
+    path::PathBuf,
+    time::Duration,
+    fs, io, process, thread,
+};
+use structopt::StructOpt;
+use pseudonode::{ChunkBuffer, Message, handshake, write_raw};
+use crypto::nonce::NoncePair;
+use tezos_messages::p2p::{
+    binary_message::BinaryMessage,
+    encoding::{
+        ack::AckMessage,
+        metadata::MetadataMessage,
+        peer::{PeerMessage, PeerMessageResponse},
+        version::NetworkVersion,
+    },
+};
+use tezedge_recorder::{common::MessageCategory, conversation::Conversation};
+
+/// Connects to the node or to the replayer, performs the handshake and sends peer messages,
+/// the debugger sitting in between should capture the whole conversation
+#[derive(StructOpt)]
+struct Args {
+    /// The address of the target
+    #[structopt(long, default_value = "127.0.0.1:9732")]
+    target: SocketAddr,
+    /// The port to advertise in the connection message
+    #[structopt(long, default_value = "9733")]
+    port: u16,
+    /// The identity json, a fresh one is generated if not given
+    #[structopt(long)]
+    identity: Option<PathBuf>,
+    /// The proof of work difficulty of the generated identity
+    #[structopt(long, default_value = "26.0")]
+    pow: f64,
+    #[structopt(long, default_value = "TEZOS_MAINNET")]
+    chain: String,
+    /// How many peer messages to send
+    #[structopt(long, default_value = "16")]
+    count: u64,
+    /// The messages to send in turn, `operation` or `bootstrap`
+    #[structopt(long, default_value = "operation,bootstrap", use_delimiter = true)]
+    mix: Vec<String>,
+    /// Pause between the messages, milliseconds
+    #[structopt(long, default_value = "100")]
+    interval_ms: u64,
+    /// Expect the target to send each message back and compare it with the sent one
+    #[structopt(long)]
+    echo: bool,
+    /// How long to wait for the echo, milliseconds
+    #[structopt(long, default_value = "5000")]
+    timeout_ms: u64,
+    /// The scenario file exported from the debugger, `.yaml` or `.cbor`, send the peer messages
+    /// the remote peer sent at the recorded pace instead of the `mix`
+    #[structopt(long)]
+    conversation: Option<PathBuf>,
+}
+
+fn peer_message(kind: &str) -> PeerMessage {
+    match kind {
+        "operation" => {
+            let operation = serde_json::from_str(include_str!("operation_example.json")).unwrap();
+            PeerMessage::Operation(operation)
+        },
+        "bootstrap" => PeerMessage::Bootstrap,
+        kind => {
+            log::error!("unknown message kind {}", kind);
+            process::exit(2);
+        },
+    }
+}
+
+/// The peer messages of the remote peer with the pause after each one
+fn recorded(conversation: &Conversation) -> Vec<(Vec<u8>, Duration)> {
+    let sent = conversation
+        .brief()
+        .into_iter()
+        .filter(|m| m.incoming && matches!(m.category, MessageCategory::P2p))
+        .collect::<Vec<_>>();
+    sent.iter()
+        .enumerate()
+        .map(|(i, m)| {
+            let bytes = conversation.bytes(m.id as usize).unwrap();
+            let next = sent.get(i + 1).map_or(m.timestamp, |n| n.timestamp);
+            let pause = Duration::from_nanos(next.saturating_sub(m.timestamp) as u64);
+            (bytes, pause)
+        })
+        .collect()
+}
+
+fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .init();
+
+    let args = Args::from_args();
+    let identity = match &args.identity {
+        Some(path) => fs::read_to_string(path).unwrap(),
+        None => {
+            log::info!("generating identity, pow {}", args.pow);
+            handshake::generate_identity(args.pow)
+        },
+    };
+    let conversation = args.conversation.as_ref().map(|path| Conversation::load(path).unwrap());
+    let version = match conversation.as_ref().and_then(|c| c.remote_version.as_ref()) {
+        Some(v) => {
+            NetworkVersion::new(v.chain_name.clone(), v.distributed_db_version, v.p2p_version)
+        },
+        None => NetworkVersion::new(args.chain.clone(), 0, 1),
+    };
+    let messages = match &conversation {
+        Some(conversation) => recorded(conversation),
+        None => {
+            let interval = Duration::from_millis(args.interval_ms);
+            args.mix
+                .iter()
+                .cycle()
+                .take(args.count as usize)
+                .map(|kind| {
+                    let message = PeerMessageResponse::from(peer_message(kind));
+                    (message.as_bytes().unwrap(), interval)
+                })
+                .collect()
+        },
+    };
+
+    let mut stream = TcpStream::connect(args.target).unwrap();
+    let (key, NoncePair { local, remote }) =
+        handshake::initiator(args.port, &mut stream, &identity, version);
+    let mut buffer = ChunkBuffer::default();
+
+    let local = MetadataMessage::new(false, false).write_msg(&mut stream, &key, local);
+    let (remote, _msg) =
+        MetadataMessage::read_msg(&mut stream, &mut buffer, &key, remote, false).unwrap();
+
+    let mut local = AckMessage::Ack.write_msg(&mut stream, &key, local);
+    let (mut remote, _msg) =
+        AckMessage::read_msg(&mut stream, &mut buffer, &key, remote, false).unwrap();
+    log::info!("handshake with {} done", args.target);
+
+    stream
+        .set_read_timeout(Some(Duration::from_millis(args.timeout_ms)))
+        .unwrap();
+
+    let (mut sent, mut echoed, mut mismatched) = (0, 0, 0);
+    for (i, (bytes, pause)) in messages.into_iter().enumerate() {
+        local = write_raw(&bytes, &mut stream, &key, local);
+        sent += 1;
+
+        if args.echo {
+            let r = PeerMessageResponse::read_msg(
+                &mut stream,
+                &mut buffer,
+                &key,
+                remote.clone(),
+                true,
+            );
+            match r {
+                Ok((r, msg)) => {
+                    remote = r;
+                    echoed += 1;
+                    if msg.as_bytes().map_or(true, |actual| actual != bytes) {
+                        let actual = serde_json::to_string(msg.message()).unwrap();
+                        log::error!("message {} differs: {}", i, actual);
+                        mismatched += 1;
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    log::error!("no echo of message {}", i);
+                },
+                Err(e) => {
+                    log::error!("cannot read the echo of message {}: {}", i, e);
+                    break;
+                },
+            }
+        }
+        thread::sleep(pause);
+    }
+
+    log::info!("sent: {}, echoed: {}, mismatched: {}", sent, echoed, mismatched);
+    if args.echo && (echoed != sent || mismatched != 0) {
+        process::exit(1);
+    }
+}
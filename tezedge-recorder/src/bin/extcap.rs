@@ -0,0 +1,163 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! Wireshark extcap, copy or link the binary into the extcap directory of Wireshark,
+//! it follows the running recorder over http and feeds the decrypted messages
+//! as the pcapng stream, one packet per message, the payload is the json of the message
+
+use std::{
+    env,
+    fs::OpenOptions,
+    io::{self, Write},
+    thread,
+    time::Duration,
+};
+use serde_json::Value;
+
+const INTERFACE: &str = "tezedge-recorder";
+// `DLT_USER0`, Wireshark shows it as json if the payload protocol of `User 0` is `json`
+const LINKTYPE: u16 = 147;
+const BATCH: u64 = 100;
+
+fn main() -> anyhow::Result<()> {
+    let flag = |name: &str| env::args().any(|a| a == name);
+    let arg = |name: &str| env::args().skip_while(|a| a != name).nth(1);
+
+    if flag("--extcap-interfaces") {
+        println!("extcap {{version=0.1}}{{help=https://github.com/tezedge/tezedge-debugger}}");
+        println!("interface {{value={}}}{{display=TezEdge recorder p2p messages}}", INTERFACE);
+    } else if flag("--extcap-dlts") {
+        println!(
+            "dlt {{number={}}}{{name=USER0}}{{display=Tezos p2p messages as json}}",
+            LINKTYPE,
+        );
+    } else if flag("--extcap-config") {
+        println!(
+            "arg {{number=0}}{{call=--url}}{{display=Recorder url}}{{type=string}}\
+             {{default=http://localhost:17732}}{{tooltip=The http_v2 address of the recorder}}",
+        );
+        println!(
+            "arg {{number=1}}{{call=--node-name}}{{display=Node name}}{{type=string}}\
+             {{default=tezedge}}",
+        );
+    } else if flag("--capture") {
+        let fifo = arg("--fifo").ok_or_else(|| anyhow::anyhow!("`--fifo <path>` is required"))?;
+        let url = arg("--url").unwrap_or_else(|| "http://localhost:17732".to_string());
+        let node_name = arg("--node-name").unwrap_or_else(|| "tezedge".to_string());
+        // the capture filter is the list of message types, like `/v2/p2p?types=`
+        let types = arg("--extcap-capture-filter").filter(|s| !s.trim().is_empty());
+        let output = OpenOptions::new().write(true).open(fifo)?;
+        capture(output, &url, &node_name, types.as_deref())?;
+    } else {
+        anyhow::bail!("run by Wireshark, see `--extcap-interfaces`");
+    }
+
+    Ok(())
+}
+
+/// Polls the recorder for the messages newer than the moment the capture started,
+/// stops when Wireshark closes the pipe
+fn capture<W>(mut output: W, url: &str, node_name: &str, types: Option<&str>) -> anyhow::Result<()>
+where
+    W: Write,
+{
+    let client = reqwest::blocking::Client::new();
+    let get = |path: &str, query: &[(&str, String)]| -> anyhow::Result<Value> {
+        let mut query = query.to_vec();
+        query.push(("node_name", node_name.to_string()));
+        let response = client
+            .get(format!("{}{}", url.trim_end_matches('/'), path))
+            .query(&query)
+            .send()?
+            .error_for_status()?;
+        Ok(response.json()?)
+    };
+
+    write_header(&mut output)?;
+
+    let latest = get("/v2/p2p", &[("limit", "1".to_string())])?;
+    let mut cursor = latest[0]["id"].as_u64().map_or(0, |id| id + 1);
+    loop {
+        let mut query = vec![
+            ("direction", "forward".to_string()),
+            ("cursor", cursor.to_string()),
+            ("limit", BATCH.to_string()),
+        ];
+        if let Some(types) = types {
+            query.push(("types", types.to_string()));
+        }
+        let messages = match get("/v2/p2p", &query)? {
+            Value::Array(messages) => messages,
+            _ => vec![],
+        };
+        if messages.is_empty() {
+            thread::sleep(Duration::from_millis(500));
+            continue;
+        }
+        for mut message in messages {
+            let id = message["id"].as_u64().unwrap_or(cursor);
+            cursor = id + 1;
+            let details = get(&format!("/v2/p2p/{}", id), &[])?;
+            message["message"] = details["message"].clone();
+            message["decrypted_bytes"] = details["decrypted_bytes"].clone();
+            message["error"] = details["error"].clone();
+            let timestamp_us = message["timestamp"].as_u64().unwrap_or(0) / 1_000;
+            let payload = serde_json::to_vec(&message)?;
+            match write_packet(&mut output, timestamp_us, &payload) {
+                Err(error) if error.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                r => r?,
+            }
+        }
+    }
+}
+
+fn write_block<W>(output: &mut W, ty: u32, body: &[u8]) -> io::Result<()>
+where
+    W: Write,
+{
+    let padding = (4 - body.len() % 4) % 4;
+    let length = (12 + body.len() + padding) as u32;
+    output.write_all(&ty.to_le_bytes())?;
+    output.write_all(&length.to_le_bytes())?;
+    output.write_all(body)?;
+    output.write_all(&[0; 3][..padding])?;
+    output.write_all(&length.to_le_bytes())
+}
+
+/// The section header block and the description of the only interface
+fn write_header<W>(output: &mut W) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut section = Vec::with_capacity(16);
+    section.extend_from_slice(&0x1a2b_3c4d_u32.to_le_bytes());
+    section.extend_from_slice(&1u16.to_le_bytes());
+    section.extend_from_slice(&0u16.to_le_bytes());
+    // the length of the section is unknown
+    section.extend_from_slice(&(-1i64).to_le_bytes());
+    write_block(output, 0x0a0d_0d0a, &section)?;
+
+    let mut interface = Vec::with_capacity(8);
+    interface.extend_from_slice(&LINKTYPE.to_le_bytes());
+    interface.extend_from_slice(&0u16.to_le_bytes());
+    // no limit of the snapshot length
+    interface.extend_from_slice(&0u32.to_le_bytes());
+    write_block(output, 1, &interface)?;
+    output.flush()
+}
+
+/// The enhanced packet block, the timestamp is in microseconds, the default resolution
+fn write_packet<W>(output: &mut W, timestamp_us: u64, payload: &[u8]) -> io::Result<()>
+where
+    W: Write,
+{
+    let mut packet = Vec::with_capacity(20 + payload.len());
+    packet.extend_from_slice(&0u32.to_le_bytes());
+    packet.extend_from_slice(&((timestamp_us >> 32) as u32).to_le_bytes());
+    packet.extend_from_slice(&(timestamp_us as u32).to_le_bytes());
+    packet.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    packet.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    packet.extend_from_slice(payload);
+    write_block(output, 6, &packet)?;
+    output.flush()
+}
@@ -5,23 +5,139 @@
 #[global_allocator]
 static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
+use std::{
+    env,
+    net::IpAddr,
+    path::PathBuf,
+    process::Command,
+    time::Duration,
+    thread,
+    sync::{
+        Arc,
+        atomic::{Ordering, AtomicBool},
+    },
+    io::ErrorKind,
+    fs,
+};
+use structopt::StructOpt;
+use tezedge_recorder::{
+    System, Overrides, HealthStatus, LoggingConfig, main_loop, bench, verify,
+    database::{Database, DatabaseNew, DatabaseFetch, rocks, remote},
+};
+
+/// Records the p2p traffic and the logs of the tezos nodes, runs the recorder
+/// if no subcommand is given
+#[derive(StructOpt)]
+struct Args {
+    /// The config file, the overrides take precedence over it
+    #[structopt(long, global = true)]
+    config: Option<String>,
+    #[structopt(flatten)]
+    run: RunArgs,
+    #[structopt(subcommand)]
+    command: Option<Subcommand>,
+}
+
+#[derive(StructOpt)]
+struct RunArgs {
+    /// Start the bpf module which intercepts the p2p traffic
+    #[structopt(long)]
+    run_bpf: bool,
+    /// Forward everything to the central recorder, the `db` of each node is its address
+    #[structopt(long)]
+    remote_agent: bool,
+    /// How many threads decrypt and parse the intercepted data
+    #[structopt(long, default_value = "1")]
+    decode_threads: usize,
+    #[structopt(long)]
+    http_address: Option<IpAddr>,
+    #[structopt(long)]
+    http_v2: Option<u16>,
+    #[structopt(long)]
+    p2p_store_limit: Option<u64>,
+    #[structopt(long)]
+    log_store_limit: Option<u64>,
+    #[structopt(long)]
+    p2p_retention_days: Option<u64>,
+    #[structopt(long, possible_values = &["text", "json"])]
+    log_format: Option<String>,
+    /// The directives as `RUST_LOG` takes them
+    #[structopt(long)]
+    log_level: Option<String>,
+}
+
+#[derive(StructOpt)]
+enum Subcommand {
+    /// Generate a fresh identity with the proof of work
+    GenerateIdentity {
+        #[structopt(long, default_value = "26")]
+        pow: f64,
+        #[structopt(long, default_value = "identity.json")]
+        output: String,
+    },
+    /// Restore the backup of the node, the latest one if `--backup` is not given
+    Restore {
+        #[structopt(long)]
+        node: String,
+        #[structopt(long)]
+        backup: Option<String>,
+        /// List the backups instead
+        #[structopt(long)]
+        list: bool,
+    },
+    /// Decrypt the stored chunks again and compare them with the stored plaintext
+    Verify {
+        #[structopt(long)]
+        db: String,
+        #[structopt(long)]
+        node: String,
+        #[structopt(long)]
+        connection: Option<String>,
+    },
+    /// Replay the snapshot through the parser and the storage as fast as possible
+    Bench {
+        /// The database of the recorder to replay
+        snapshot: String,
+        #[structopt(long)]
+        node: String,
+        /// Where to store the replayed data, a temporary directory by default
+        #[structopt(long)]
+        bench_db: Option<PathBuf>,
+    },
+}
+
 fn main() -> anyhow::Result<()> {
-    use std::{
-        env,
-        process::Command,
-        time::Duration,
-        thread,
-        sync::{
-            Arc,
-            atomic::{Ordering, AtomicBool},
-        },
-        io::ErrorKind,
-    };
-    use tezedge_recorder::{System, database::rocks::Db, main_loop};
+    let Args {
+        config,
+        run: args,
+        command,
+    } = Args::from_args();
+    let config = config.as_deref();
 
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .init();
+    match command {
+        Some(Subcommand::GenerateIdentity { pow, output }) => {
+            init_logging(&LoggingConfig::default())?;
+            return generate_identity(pow, &output);
+        },
+        Some(Subcommand::Restore { node, backup, list }) => {
+            init_logging(&LoggingConfig::default())?;
+            return restore(config, &node, backup.as_deref(), list);
+        },
+        Some(Subcommand::Verify { db, node, connection }) => {
+            init_logging(&LoggingConfig::default())?;
+            return verify(config, &db, &node, connection.as_deref());
+        },
+        Some(Subcommand::Bench {
+            snapshot,
+            node,
+            bench_db,
+        }) => {
+            init_logging(&LoggingConfig::default())?;
+            let output = bench_db.unwrap_or_else(|| env::temp_dir().join("tezedge-recorder-bench"));
+            return bench(config, &snapshot, &node, output);
+        },
+        None => (),
+    }
 
     let running = Arc::new(AtomicBool::new(true));
     {
@@ -29,11 +145,116 @@ fn main() -> anyhow::Result<()> {
         ctrlc::set_handler(move || running.store(false, Ordering::Relaxed))?;
     }
 
-    let mut system = System::<Db>::load_config()?;
+    // the command line takes precedence over the environment
+    let overrides = Overrides {
+        http_address: args.http_address,
+        http_v2: args.http_v2,
+        p2p_store_limit: args.p2p_store_limit,
+        log_store_limit: args.log_store_limit,
+        p2p_retention_days: args.p2p_retention_days,
+        log_format: args.log_format.clone(),
+        log_level: args.log_level.clone(),
+    }
+    .or(Overrides::from_env()?);
+
+    if args.remote_agent {
+        run::<remote::Db>(running, &args, config, &overrides)
+    } else {
+        run::<rocks::Db>(running, &args, config, &overrides)
+    }
+}
+
+/// The config is loaded before, so the logging can be configured there
+fn init_logging(config: &LoggingConfig) -> anyhow::Result<()> {
+    use tracing_subscriber::EnvFilter;
+
+    let filter = EnvFilter::try_new(config.level.as_deref().unwrap_or("info"))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.format.as_deref() {
+        None | Some("text") => builder.init(),
+        Some("json") => builder.json().init(),
+        Some(format) => anyhow::bail!("unknown log format {:?}, expected text or json", format),
+    }
+    Ok(())
+}
+
+fn generate_identity(pow: f64, output: &str) -> anyhow::Result<()> {
+    log::info!("generating identity, pow {}", pow);
+    let identity = pseudonode::handshake::generate_identity(pow);
+    fs::write(output, identity)?;
+    log::info!("identity is written to {}", output);
+
+    Ok(())
+}
+
+fn restore(
+    config: Option<&str>,
+    node: &str,
+    backup: Option<&str>,
+    list: bool,
+) -> anyhow::Result<()> {
+    let system = System::<rocks::Db>::load_config(config)?;
+
+    if list {
+        for id in system.backups(node)? {
+            println!("{}", id);
+        }
+    } else {
+        let id = system.restore_backup(node, backup)?;
+        log::info!("backup {} of {} is restored", id, node);
+    }
+
+    Ok(())
+}
+
+fn bench(config: Option<&str>, snapshot: &str, node: &str, output: PathBuf) -> anyhow::Result<()> {
+    let system = System::<rocks::Db>::load_config(config)?;
+    let identity = system.identity(node)?;
+
+    log::info!("replaying {} into {}", snapshot, output.display());
+    let report = bench::run(snapshot, &output, identity)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    Ok(())
+}
+
+fn verify(
+    config: Option<&str>,
+    db: &str,
+    node: &str,
+    connection: Option<&str>,
+) -> anyhow::Result<()> {
+    let system = System::<rocks::Db>::load_config(config)?;
+    let identity = system.identity(node)?;
+
+    let report = verify::run(db, &identity, connection)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    if !report.diverged.is_empty() {
+        anyhow::bail!("{} chunks diverge from the stored plaintext", report.diverged.len());
+    }
+
+    Ok(())
+}
+
+fn run<Db>(
+    running: Arc<AtomicBool>,
+    args: &RunArgs,
+    config: Option<&str>,
+    overrides: &Overrides,
+) -> anyhow::Result<()>
+where
+    Db: Database + DatabaseNew + DatabaseFetch + Sync + Send + 'static,
+{
+    let mut system = System::<Db>::load_config(config)?;
+    system.apply_overrides(overrides);
+    init_logging(&system.logging())?;
+    // the peers blocked by the previous run are not listed, so not blocked anymore
+    let control = system.control();
+    control.clear_firewall();
     system.run_dbs(running.clone());
 
     if system.need_bpf() {
-        let bpf = if env::args().find(|a| a == "--run-bpf").is_some() {
+        let bpf = if args.run_bpf {
             let h = Command::new("bpf-recorder").spawn().or_else(|e| {
                 if e.kind() == ErrorKind::NotFound {
                     Command::new("./target/none/release/bpf-recorder").spawn()
@@ -44,23 +265,27 @@ fn main() -> anyhow::Result<()> {
             match h {
                 Ok(h) => {
                     thread::sleep(Duration::from_millis(500));
-                    if let Err(error) = main_loop::run(&mut system, running) {
+                    if let Err(error) = main_loop::run(&mut system, running, args.decode_threads) {
                         log::error!("cannot intercept p2p messages: {}", error)
                     }
                     Some(h)
                 },
                 Err(error) => {
-                    log::error!("cannot run bpf: {:?}", error);
+                    let reason = format!("cannot run bpf: {:?}", error);
+                    system.health().set("bpf", HealthStatus::Down(reason));
                     None
                 },
             }
         } else {
+            let reason = "p2p capture is not started, run with `--run-bpf`".to_string();
+            system.health().set("bpf", HealthStatus::Down(reason));
             None
         };
 
         let _ = bpf;
     }
     system.join();
+    control.clear_firewall();
 
     Ok(())
 }
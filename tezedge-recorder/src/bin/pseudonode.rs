@@ -3,9 +3,37 @@
 
 #![forbid(unsafe_code)]
 
-use std::{ops::Range, env};
+use std::{ops::Range, env, net::SocketAddr};
 use structopt::StructOpt;
 
+/// Generates encrypted p2p traffic between pairs of pseudo peers over loopback
+/// and reports how much of it the debugger captured
+#[derive(StructOpt)]
+struct TrafficGen {
+    /// The first peer listens on this port, the next one on the following port and so on
+    #[structopt(long, default_value = "29732")]
+    port: u16,
+    /// How many pairs of peers talk simultaneously
+    #[structopt(long, default_value = "4")]
+    peers: u16,
+    /// Messages per second, all peers together
+    #[structopt(long, default_value = "100")]
+    rate: u64,
+    #[structopt(long, default_value = "10")]
+    duration_secs: u64,
+    /// The message kinds to pick randomly, repeat a kind to make it more frequent
+    #[structopt(long, default_value = "operation,bootstrap", use_delimiter = true)]
+    mix: Vec<String>,
+    /// The http address of the debugger, the capture report is skipped if not given
+    #[structopt(long)]
+    debugger: Option<SocketAddr>,
+    #[structopt(long, default_value = "tezedge")]
+    node_name: String,
+    /// How long to let the debugger process the traffic before the report
+    #[structopt(long, default_value = "2")]
+    settle_secs: u64,
+}
+
 #[derive(StructOpt)]
 enum Args {
     Log {
@@ -19,6 +47,7 @@ enum Args {
         this: u16,
         peer: u16,
     },
+    TrafficGen(TrafficGen),
 }
 
 fn main() {
@@ -41,6 +70,182 @@ fn main() {
         Args::P2pResponder { this, peer } => {
             generate_p2p(this, peer, false);
         },
+        Args::TrafficGen(args) => {
+            traffic_gen(args);
+        },
+    }
+}
+
+fn fake_peer_message(kind: &str) -> tezos_messages::p2p::encoding::peer::PeerMessage {
+    use tezos_messages::p2p::encoding::peer::PeerMessage;
+
+    match kind {
+        "operation" => {
+            let operation = serde_json::from_str(include_str!("operation_example.json")).unwrap();
+            PeerMessage::Operation(operation)
+        },
+        "bootstrap" => PeerMessage::Bootstrap,
+        kind => panic!("unknown message kind {}", kind),
+    }
+}
+
+fn traffic_gen(args: TrafficGen) {
+    use std::{
+        net::{TcpListener, TcpStream},
+        time::{Duration, Instant},
+        thread,
+    };
+    use rand::seq::SliceRandom;
+    use pseudonode::{handshake, Message, ChunkBuffer};
+    use crypto::nonce::NoncePair;
+    use tezos_messages::p2p::encoding::{
+        metadata::MetadataMessage, ack::AckMessage, peer::PeerMessageResponse,
+        version::NetworkVersion,
+    };
+
+    let version = NetworkVersion::new("TEZOS_MAINNET".to_string(), 0, 1);
+    let interval = Duration::from_micros(1_000_000 * u64::from(args.peers) / args.rate.max(1));
+    let duration = Duration::from_secs(args.duration_secs);
+
+    let pairs = (0..args.peers)
+        .map(|i| {
+            let port = args.port + i;
+            let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], port))).unwrap();
+
+            let version_r = version.clone();
+            let responder = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let identity = include_str!("../../identity_r.json");
+                let (key, NoncePair { local, remote }) =
+                    handshake::responder(port, &mut stream, identity, version_r);
+                let mut buffer = ChunkBuffer::default();
+                let (remote, _msg) =
+                    MetadataMessage::read_msg(&mut stream, &mut buffer, &key, remote, false)
+                        .unwrap();
+                let local = MetadataMessage::new(false, false).write_msg(&mut stream, &key, local);
+                let (mut remote, _msg) =
+                    AckMessage::read_msg(&mut stream, &mut buffer, &key, remote, false).unwrap();
+                let _ = AckMessage::Ack.write_msg(&mut stream, &key, local);
+
+                let mut received = 0u64;
+                while let Ok((r, _msg)) =
+                    PeerMessageResponse::read_msg(&mut stream, &mut buffer, &key, remote, true)
+                {
+                    remote = r;
+                    received += 1;
+                }
+                received
+            });
+
+            let (version_i, mix) = (version.clone(), args.mix.clone());
+            let initiator = thread::spawn(move || {
+                let mut stream = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port)))
+                    .unwrap();
+                let identity = include_str!("../../identity_i.json");
+                let this = port + 10_000;
+                let (key, NoncePair { local, remote }) =
+                    handshake::initiator(this, &mut stream, identity, version_i);
+                let mut buffer = ChunkBuffer::default();
+                let local = MetadataMessage::new(false, false).write_msg(&mut stream, &key, local);
+                let (remote, _msg) =
+                    MetadataMessage::read_msg(&mut stream, &mut buffer, &key, remote, false)
+                        .unwrap();
+                let mut local = AckMessage::Ack.write_msg(&mut stream, &key, local);
+                let _ = AckMessage::read_msg(&mut stream, &mut buffer, &key, remote, false)
+                    .unwrap();
+
+                let (start, mut sent) = (Instant::now(), 0u64);
+                let mut rng = rand::thread_rng();
+                while start.elapsed() < duration {
+                    let kind = mix.choose(&mut rng).unwrap();
+                    let message = PeerMessageResponse::from(fake_peer_message(kind));
+                    local = message.write_msg(&mut stream, &key, local);
+                    sent += 1;
+                    let next = start + interval * (sent as u32);
+                    if let Some(pause) = next.checked_duration_since(Instant::now()) {
+                        thread::sleep(pause);
+                    }
+                }
+                sent
+            });
+
+            (port, initiator, responder)
+        })
+        .collect::<Vec<_>>();
+
+    let mut report = Vec::with_capacity(pairs.len());
+    for (port, initiator, responder) in pairs {
+        let sent = initiator.join().unwrap();
+        let received = responder.join().unwrap();
+        report.push((port, sent, received));
+    }
+
+    if args.debugger.is_some() {
+        thread::sleep(Duration::from_secs(args.settle_secs));
+    }
+    let (mut total_sent, mut total_captured) = (0, 0);
+    for (port, sent, received) in report {
+        total_sent += sent;
+        match args.debugger {
+            Some(debugger) => {
+                let captured = captured_count(debugger, &args.node_name, port, &args.mix, sent);
+                total_captured += captured;
+                println!(
+                    "peer {}: sent {}, received {}, captured {}, dropped {}",
+                    port,
+                    sent,
+                    received,
+                    captured,
+                    sent.saturating_sub(captured),
+                );
+            },
+            None => println!("peer {}: sent {}, received {}", port, sent, received),
+        }
+    }
+    if args.debugger.is_some() {
+        println!(
+            "total: sent {}, captured {}, dropped {}",
+            total_sent,
+            total_captured,
+            total_sent.saturating_sub(total_captured),
+        );
+    }
+}
+
+/// The number of messages the initiator sent to the peer listening on the port
+/// as the debugger has recorded them
+fn captured_count(
+    debugger: SocketAddr,
+    node_name: &str,
+    port: u16,
+    mix: &[String],
+    sent: u64,
+) -> u64 {
+    use std::{io::{Read, Write}, net::TcpStream};
+
+    let mut types = mix.to_vec();
+    types.sort();
+    types.dedup();
+    let path = format!(
+        "/v2/p2p?node_name={}&remote_addr=127.0.0.1:{}&incoming=false&types={}&limit={}",
+        node_name,
+        port,
+        types.join(","),
+        sent + 1,
+    );
+
+    // plain http/1.0, the connection is closed after the response
+    let mut stream = TcpStream::connect(debugger).unwrap();
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, debugger).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or_default();
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Array(messages)) => messages.len() as u64,
+        _ => {
+            eprintln!("unexpected response of the debugger: {}", response);
+            0
+        },
     }
 }
 
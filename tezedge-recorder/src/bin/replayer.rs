@@ -2,27 +2,539 @@
 // SPDX-License-Identifier: MIT
 
 use std::{
+    collections::{BTreeMap, VecDeque},
     net::{TcpListener, SocketAddr, TcpStream},
-    time::Duration,
-    io,
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+    io, fs, mem, process, thread,
 };
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use serde::{Serialize, Deserialize};
+use structopt::StructOpt;
+use warp::{Filter, Reply, http::StatusCode, reply};
 use tezedge_recorder::{
-    common::MessageCategory,
+    common::{MessageCategory, MessageKind, MessageType},
     database::{DatabaseNew, DatabaseFetch, rocks::Db, MessagesFilter},
-    tables::message::{MessageFrontend, TezosMessage},
+    tables::{
+        connection::{AckInfo, NackMotive},
+        message::{MessageFrontend, TezosMessage},
+    },
+    conversation::Conversation,
 };
-use pseudonode::{ChunkBuffer, Message, handshake};
+use pseudonode::{ChunkBuffer, Message, handshake, try_write_raw, write_oversized};
 use crypto::{
     crypto_box::PrecomputedKey,
     nonce::{Nonce, NoncePair},
 };
-use tezos_messages::p2p::encoding::{
-    ack::AckMessage, metadata::MetadataMessage, peer::PeerMessageResponse,
+use tezos_messages::p2p::{
+    binary_message::{BinaryMessage, BinaryRead},
+    encoding::{
+        ack::AckMessage, connection::ConnectionMessage, metadata::MetadataMessage,
+        peer::PeerMessageResponse, version::NetworkVersion,
+    },
 };
 
+/// How the replayer misbehaves to exercise the resilience of the node,
+/// every rule counts the messages the replayer writes after the handshake
+#[derive(Default, Clone, Deserialize, StructOpt)]
+#[serde(default)]
+struct Scenario {
+    /// Wait before writing each message, milliseconds
+    #[structopt(long)]
+    delay_ms: Option<u64>,
+    /// Skip every Nth message
+    #[structopt(long)]
+    drop_every: Option<u64>,
+    /// Flip a random byte in the body of every Nth message
+    #[structopt(long)]
+    mutate_every: Option<u64>,
+    /// Write a chunk bigger than the protocol allows instead of every Nth message
+    #[structopt(long)]
+    oversized_every: Option<u64>,
+    /// What the node is expected to do, only the file sets them
+    #[structopt(skip)]
+    assertions: Vec<Assertion>,
+}
+
+impl Scenario {
+    /// The rules set in `self` take precedence
+    fn or(self, other: Self) -> Self {
+        Scenario {
+            delay_ms: self.delay_ms.or(other.delay_ms),
+            drop_every: self.drop_every.or(other.drop_every),
+            mutate_every: self.mutate_every.or(other.mutate_every),
+            oversized_every: self.oversized_every.or(other.oversized_every),
+            assertions: if self.assertions.is_empty() {
+                other.assertions
+            } else {
+                self.assertions
+            },
+        }
+    }
+
+    fn hit(every: Option<u64>, counter: u64) -> bool {
+        matches!(every, Some(n) if n != 0 && counter % n == 0)
+    }
+
+    /// How long to keep reading after the last message, the longest wait of the assertions
+    fn linger(&self) -> Duration {
+        let within_ms = |a: &Assertion| match a {
+            Assertion::Response { within_ms, .. } | Assertion::Disconnect { within_ms, .. } => {
+                within_ms.unwrap_or(0)
+            },
+        };
+        Duration::from_millis(self.assertions.iter().map(within_ms).max().unwrap_or(0))
+    }
+}
+
+/// The expectation of the regression test, like
+/// `{expect = "response", kind = "current_branch", after = 8, within_ms = 1000}`,
+/// the time counts from writing the message with the id `after`, or from the handshake
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "expect")]
+enum Assertion {
+    /// the node sends the peer message of the kind
+    Response {
+        kind: String,
+        after: Option<u64>,
+        within_ms: Option<u64>,
+    },
+    /// the node closes the connection, or rejects it with the motive if given
+    Disconnect {
+        motive: Option<NackMotive>,
+        after: Option<u64>,
+        within_ms: Option<u64>,
+    },
+}
+
+/// What happened on the connection during the replay
+enum Event {
+    Written(u64),
+    Response(String),
+    Rejected(Option<NackMotive>),
+    Closed,
+}
+
+struct Timeline {
+    start: Instant,
+    events: Vec<(Duration, Event)>,
+}
+
+impl Timeline {
+    fn new() -> Self {
+        Timeline {
+            start: Instant::now(),
+            events: vec![],
+        }
+    }
+
+    fn push(&mut self, event: Event) {
+        self.events.push((self.start.elapsed(), event));
+    }
+
+    fn closed(&self) -> bool {
+        self.events
+            .iter()
+            .any(|(_, e)| matches!(e, Event::Rejected(_) | Event::Closed))
+    }
+
+    /// Each written message with the responses which came before the next one
+    fn steps(&self) -> Vec<(u64, TargetStep)> {
+        let mut steps = Vec::<(u64, Duration, TargetStep)>::new();
+        for (at, event) in &self.events {
+            match event {
+                Event::Written(id) => steps.push((*id, *at, TargetStep::default())),
+                Event::Response(kind) => {
+                    if let Some((_, written, step)) = steps.last_mut() {
+                        if step.latency_ms.is_none() {
+                            step.latency_ms = Some((*at - *written).as_secs_f64() * 1_000.0);
+                        }
+                        step.responses.push(kind.clone());
+                    }
+                },
+                _ => (),
+            }
+        }
+        steps.into_iter().map(|(id, _, step)| (id, step)).collect()
+    }
+}
+
+#[derive(Serialize)]
+struct Verdict<'a> {
+    assertion: &'a Assertion,
+    passed: bool,
+    detail: String,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    passed: bool,
+    verdicts: Vec<Verdict<'a>>,
+}
+
+impl<'a> Report<'a> {
+    fn new(assertions: &'a [Assertion], timeline: &Timeline) -> Self {
+        let verdicts = assertions
+            .iter()
+            .map(|a| a.check(timeline))
+            .collect::<Vec<_>>();
+        let failed = verdicts.iter().filter(|v| !v.passed).count();
+        if !assertions.is_empty() {
+            log::info!("assertions: {} passed, {} failed", verdicts.len() - failed, failed);
+        }
+        Report {
+            passed: failed == 0,
+            verdicts,
+        }
+    }
+}
+
+/// What one target answered to the written message
+#[derive(Default, Clone, Serialize)]
+struct TargetStep {
+    responses: Vec<String>,
+    /// until the first response
+    latency_ms: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct StepComparison {
+    /// the written message
+    id: u64,
+    /// in the order of the targets, absent if the target did not get that far
+    targets: Vec<Option<TargetStep>>,
+    /// the targets answered with the different kinds of messages
+    diverged: bool,
+}
+
+#[derive(Serialize)]
+struct TargetSummary<'a> {
+    target: SocketAddr,
+    written: usize,
+    responses: usize,
+    closed: bool,
+    /// the assertions of the scenario checked against the target
+    passed: bool,
+    verdicts: Vec<Verdict<'a>>,
+}
+
+/// The replay of the same messages against several targets side by side
+#[derive(Serialize)]
+struct Comparison<'a> {
+    targets: Vec<TargetSummary<'a>>,
+    steps: Vec<StepComparison>,
+    divergences: usize,
+}
+
+impl<'a> Comparison<'a> {
+    fn new(targets: &[SocketAddr], timelines: &[Timeline], assertions: &'a [Assertion]) -> Self {
+        let mut steps = BTreeMap::<u64, Vec<Option<TargetStep>>>::new();
+        for (i, timeline) in timelines.iter().enumerate() {
+            for (id, step) in timeline.steps() {
+                let row = steps
+                    .entry(id)
+                    .or_insert_with(|| vec![None; timelines.len()]);
+                // the jump may write the message again, the first time counts
+                if row[i].is_none() {
+                    row[i] = Some(step);
+                }
+            }
+        }
+        let steps = steps
+            .into_iter()
+            .map(|(id, targets)| {
+                let kinds = targets
+                    .iter()
+                    .map(|t| {
+                        t.as_ref().map(|t| {
+                            let mut kinds = t.responses.clone();
+                            kinds.sort();
+                            kinds
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                StepComparison {
+                    id,
+                    targets,
+                    diverged: kinds.windows(2).any(|w| w[0] != w[1]),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let count = |timeline: &Timeline, f: fn(&Event) -> bool| {
+            timeline.events.iter().filter(|(_, e)| f(e)).count()
+        };
+        let targets = targets
+            .iter()
+            .zip(timelines)
+            .map(|(&target, timeline)| {
+                let Report { passed, verdicts } = Report::new(assertions, timeline);
+                TargetSummary {
+                    target,
+                    written: count(timeline, |e| matches!(e, Event::Written(_))),
+                    responses: count(timeline, |e| matches!(e, Event::Response(_))),
+                    closed: timeline.closed(),
+                    passed,
+                    verdicts,
+                }
+            })
+            .collect();
+        Comparison {
+            targets,
+            divergences: steps.iter().filter(|s| s.diverged).count(),
+            steps,
+        }
+    }
+}
+
+impl Assertion {
+    fn check(&self, timeline: &Timeline) -> Verdict<'_> {
+        let (after, within_ms) = match self {
+            Assertion::Response {
+                after, within_ms, ..
+            } => (after, within_ms),
+            Assertion::Disconnect {
+                after, within_ms, ..
+            } => (after, within_ms),
+        };
+        let verdict = |passed, detail| Verdict {
+            assertion: self,
+            passed,
+            detail,
+        };
+        let since = match after {
+            None => Duration::from_secs(0),
+            Some(id) => {
+                let written = timeline.events.iter().find_map(|(at, e)| match e {
+                    Event::Written(i) if i == id => Some(*at),
+                    _ => None,
+                });
+                match written {
+                    Some(at) => at,
+                    None => return verdict(false, format!("the message {} is not written", id)),
+                }
+            },
+        };
+        let observed = timeline.events.iter().find_map(|(at, e)| {
+            let hit = match (self, e) {
+                (Assertion::Response { kind, .. }, Event::Response(k)) => k == kind,
+                (Assertion::Disconnect { motive: None, .. }, Event::Rejected(_)) => true,
+                (Assertion::Disconnect { motive: None, .. }, Event::Closed) => true,
+                (Assertion::Disconnect { motive, .. }, Event::Rejected(m)) => m == motive,
+                _ => false,
+            };
+            if hit && *at >= since {
+                Some(*at - since)
+            } else {
+                None
+            }
+        });
+        match (observed, within_ms) {
+            (None, _) => verdict(false, "not observed".to_string()),
+            (Some(elapsed), Some(within)) if elapsed > Duration::from_millis(*within) => {
+                verdict(false, format!("observed late, after {} ms", elapsed.as_millis()))
+            },
+            (Some(elapsed), _) => {
+                verdict(true, format!("observed after {} ms", elapsed.as_millis()))
+            },
+        }
+    }
+}
+
+#[derive(StructOpt)]
+struct Args {
+    /// TOML file with the scenario, the command line flags override it
+    #[structopt(long)]
+    scenario: Option<PathBuf>,
+    #[structopt(long, default_value = "/volume/debugger_db/tezedge")]
+    db: String,
+    /// The scenario file exported from the debugger, `.yaml` or `.cbor`,
+    /// replayed instead of the database
+    #[structopt(long)]
+    conversation: Option<PathBuf>,
+    /// The identity json of the replayer instead of the bundled one, the connection message
+    /// and the nonces are derived from it, the recorded ones are never replayed
+    #[structopt(long)]
+    identity: Option<PathBuf>,
+    /// Generate a fresh identity instead of the bundled one
+    #[structopt(long)]
+    generate_identity: bool,
+    /// The proof of work difficulty of the generated identity
+    #[structopt(long, default_value = "26.0")]
+    pow: f64,
+    /// Serve the control api of the replay at the address, like `127.0.0.1:17733`
+    #[structopt(long)]
+    control: Option<SocketAddr>,
+    /// Write the pass/fail report of the assertions of the scenario to the file, json,
+    /// with several targets it is the comparative report
+    #[structopt(long)]
+    report: Option<PathBuf>,
+    /// Connect to the node at the address instead of waiting for it on the port 9732,
+    /// repeat to replay against several nodes at once and compare them
+    #[structopt(long)]
+    target: Vec<SocketAddr>,
+    #[structopt(flatten)]
+    overrides: Scenario,
+}
+
+/// The command of `POST /v2/replay/{id}/control`, like `{"action": "step", "count": 1}`
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", tag = "action")]
+enum Command {
+    Pause,
+    Resume,
+    /// write that many messages, one by default, and pause again
+    Step { count: Option<u64> },
+    /// follow the recorded pace multiplied by the value, as fast as possible if absent
+    Speed { multiplier: Option<f64> },
+    /// continue from the message, the earlier messages may be written again
+    Jump { index: u64 },
+}
+
+/// The message the replayer wrote and the messages the node answered with before the next one
+#[derive(Serialize)]
+struct Step {
+    id: u64,
+    kind: String,
+    responses: Vec<String>,
+}
+
+#[derive(Default, Serialize)]
+struct ControlState {
+    paused: bool,
+    #[serde(skip)]
+    steps: u64,
+    speed: Option<f64>,
+    #[serde(skip)]
+    jump: Option<u64>,
+    /// the message the replayer is about to write
+    position: u64,
+    /// the recent steps, the oldest first
+    steps_log: VecDeque<Step>,
+}
+
+/// Lets the operator pause, step, pace and rewind the replay,
+/// the replay thread waits on it before writing each message
+#[derive(Default)]
+struct Control {
+    state: Mutex<ControlState>,
+    changed: Condvar,
+}
+
+impl Control {
+    const MAX_STEPS: usize = 0x100;
+    // the long silence of the recording is not waited in full
+    const MAX_GAP: Duration = Duration::from_secs(10);
+
+    fn command(&self, command: Command) {
+        let mut state = self.state.lock().unwrap();
+        match command {
+            Command::Pause => state.paused = true,
+            Command::Resume => state.paused = false,
+            Command::Step { count } => {
+                state.paused = true;
+                state.steps += count.unwrap_or(1);
+            },
+            Command::Speed { multiplier } => state.speed = multiplier.filter(|m| *m > 0.0),
+            Command::Jump { index } => state.jump = Some(index),
+        }
+        self.changed.notify_all();
+    }
+
+    /// Blocks while paused, then waits the recorded `gap` at the chosen speed,
+    /// returns the index to jump to, if any
+    fn before_write(&self, id: u64, gap: Duration) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        state.position = id;
+        loop {
+            if let Some(index) = state.jump.take() {
+                return Some(index);
+            }
+            if !state.paused {
+                break;
+            }
+            if state.steps > 0 {
+                state.steps -= 1;
+                break;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+        let speed = state.speed;
+        drop(state);
+        if let Some(speed) = speed {
+            thread::sleep(gap.div_f64(speed).min(Self::MAX_GAP));
+        }
+        None
+    }
+
+    fn written(&self, id: u64, kind: String) {
+        let mut state = self.state.lock().unwrap();
+        if state.steps_log.len() == Self::MAX_STEPS {
+            state.steps_log.pop_front();
+        }
+        state.steps_log.push_back(Step {
+            id,
+            kind,
+            responses: vec![],
+        });
+    }
+
+    fn responded(&self, kind: String) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(step) = state.steps_log.back_mut() {
+            step.responses.push(kind);
+        }
+    }
+}
+
+/// Serves `/v2/replay/{id}/control`, `GET` returns the state of the replay,
+/// `POST` applies the command and returns the state
+fn serve_control(addr: SocketAddr, controls: Vec<Arc<Control>>) {
+    let status = |controls: &[Arc<Control>], id: usize| match controls.get(id) {
+        Some(control) => reply::json(&*control.state.lock().unwrap()).into_response(),
+        None => {
+            let r = &format!("no such replay: {}", id);
+            reply::with_status(reply::json(&r), StatusCode::NOT_FOUND).into_response()
+        },
+    };
+    let get = {
+        let controls = controls.clone();
+        warp::path!("v2" / "replay" / usize / "control")
+            .and(warp::get())
+            .map(move |id| status(&controls, id))
+    };
+    let post = warp::path!("v2" / "replay" / usize / "control")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(move |id, command: Command| {
+            if let Some(control) = controls.get(id) {
+                control.command(command);
+            }
+            status(&controls, id)
+        });
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(warp::serve(get.or(post)).run(addr));
+    });
+}
+
+// the kind of the peer message, `[length(4)][tag(2)]...`
+fn kind_name(bytes: &[u8]) -> String {
+    match bytes.get(4..6) {
+        Some(tag) => {
+            let kind = MessageKind::from_tag(u16::from_be_bytes([tag[0], tag[1]]));
+            MessageType::P2p(kind).name()
+        },
+        None => "unknown".to_string(),
+    }
+}
+
 trait Replayer {
-    fn replay_read(&mut self, id: u64) -> Option<()>;
-    fn replay_write(&mut self, id: u64);
+    /// The kind of the message the node sent, `None` if nothing came in time,
+    /// the error means the connection is closed
+    fn replay_read(&mut self, id: u64) -> io::Result<Option<String>>;
+    fn replay_write(&mut self, id: u64) -> io::Result<()>;
 }
 
 struct State<Rp> {
@@ -79,22 +591,65 @@ impl<Rp> State<Rp>
 where
     Rp: Replayer,
 {
-    pub fn run(self) {
+    /// Keeps reading for `linger` after the last message, unless the node closes the connection
+    pub fn run(self, control: &Control, timeline: &mut Timeline, linger: Duration) {
         let mut s = self;
         while let Some((id, read)) = s.next() {
             if read {
-                if s.replayer.replay_read(id).is_some() {
-                    s.read = s.brief.get(s.write_pos).map(|m| m.incoming).unwrap_or(false);
-                } else {
-                    s.read = false;
+                match s.replayer.replay_read(id) {
+                    Ok(Some(kind)) => {
+                        control.responded(kind.clone());
+                        timeline.push(Event::Response(kind));
+                        s.read = s.brief.get(s.write_pos).map(|m| m.incoming).unwrap_or(false);
+                    },
+                    Ok(None) => s.read = false,
+                    Err(_) => {
+                        timeline.push(Event::Closed);
+                        return;
+                    },
                 }
                 s.read_pos = (id as usize) + 1;
             } else {
-                s.replayer.replay_write(id);
+                // the recorded pause before the message
+                let previous = id.checked_sub(1).and_then(|p| s.brief.get(p as usize));
+                let gap = match (s.brief.get(id as usize), previous) {
+                    (Some(this), Some(previous)) => {
+                        let nanos = this.timestamp.saturating_sub(previous.timestamp);
+                        Duration::from_nanos(nanos as u64)
+                    },
+                    _ => Duration::from_secs(0),
+                };
+                if let Some(index) = control.before_write(id, gap) {
+                    let index = (index as usize).max(6);
+                    s.read_pos = index;
+                    s.write_pos = index;
+                    s.read = s.brief.get(index).map(|m| !m.incoming).unwrap_or(false);
+                    continue;
+                }
+                if s.replayer.replay_write(id).is_err() {
+                    timeline.push(Event::Closed);
+                    return;
+                }
+                timeline.push(Event::Written(id));
+                let kind = s.brief.get(id as usize).and_then(|m| m.kind.clone());
+                let kind = kind.map_or("unknown".to_string(), |k| MessageType::P2p(k).name());
+                control.written(id, kind);
                 s.write_pos = (id as usize) + 1;
                 s.read = s.brief.get(s.write_pos).map(|m| m.incoming).unwrap_or(false);
             }
         }
+        let last = s.brief.last().map_or(0, |m| m.id);
+        let until = Instant::now() + linger;
+        while Instant::now() < until {
+            match s.replayer.replay_read(last) {
+                Ok(Some(kind)) => timeline.push(Event::Response(kind)),
+                Ok(None) => (),
+                Err(_) => {
+                    timeline.push(Event::Closed);
+                    return;
+                },
+            }
+        }
         /*for brief in &s.brief[6..] {
             let id = brief.id;
             if brief.incoming {
@@ -106,23 +661,141 @@ where
     }
 }
 
+/// Where the recorded messages come from
+enum Source {
+    Db(Db),
+    Conversation(Conversation),
+}
+
+impl Source {
+    fn brief(&self) -> Vec<MessageFrontend> {
+        match self {
+            Source::Db(db) => {
+                let mut filter = MessagesFilter::default();
+                filter.cursor = Some(0);
+                filter.limit = Some(100_000);
+                filter.direction = Some("forward".to_string());
+                db.fetch_messages(&filter).unwrap()
+            },
+            Source::Conversation(conversation) => conversation.brief(),
+        }
+    }
+
+    /// The version the local node announced in its connection message
+    fn version(&self) -> NetworkVersion {
+        match self {
+            Source::Db(db) => match db.fetch_message(0).unwrap().unwrap().message.unwrap() {
+                TezosMessage::ConnectionMessage(cm) => cm.version().clone(),
+                _ => panic!(),
+            },
+            Source::Conversation(conversation) => {
+                let bytes = conversation.bytes(0).unwrap();
+                ConnectionMessage::from_bytes(&bytes).unwrap().version().clone()
+            },
+        }
+    }
+
+    /// The decrypted peer message with its length
+    fn bytes(&self, id: u64) -> Vec<u8> {
+        match self {
+            Source::Db(db) => {
+                let message = db.fetch_message(id).unwrap().unwrap();
+                let peer_message = match message.message {
+                    Some(TezosMessage::PeerMessage(v)) => v,
+                    _ => panic!(),
+                };
+                PeerMessageResponse::from(peer_message).as_bytes().unwrap()
+            },
+            Source::Conversation(conversation) => conversation.bytes(id as usize).unwrap(),
+        }
+    }
+}
+
+/// Everything the replay of the connection needs, cloned for each target
+#[derive(Clone)]
+struct Job {
+    source: Arc<Source>,
+    brief: Vec<MessageFrontend>,
+    identity: String,
+    scenario: Scenario,
+    linger: Duration,
+}
+
+impl Job {
+    /// Performs the handshake as the initiator or as the responder and replays the messages
+    fn replay(self, mut stream: TcpStream, initiator: bool, control: &Control) -> Timeline {
+        stream
+            .set_read_timeout(Some(Duration::from_millis(1_000)))
+            .unwrap();
+
+        let version = self.source.version();
+        // the key and the nonces are of this session, the recorded messages are encrypted again
+        let (key, NoncePair { local, remote }) = if initiator {
+            handshake::initiator(9732, &mut stream, &self.identity, version)
+        } else {
+            handshake::responder(9732, &mut stream, &self.identity, version)
+        };
+
+        let mut buffer = ChunkBuffer::default();
+        let mut timeline = Timeline::new();
+        let (local, remote, ack) = if initiator {
+            let local = MetadataMessage::new(false, false).write_msg(&mut stream, &key, local);
+            let (remote, _msg) =
+                MetadataMessage::read_msg(&mut stream, &mut buffer, &key, remote, false).unwrap();
+
+            let local = AckMessage::Ack.write_msg(&mut stream, &key, local);
+            let (remote, ack) =
+                AckMessage::read_msg(&mut stream, &mut buffer, &key, remote, false).unwrap();
+            (local, remote, ack)
+        } else {
+            let (remote, _msg) =
+                MetadataMessage::read_msg(&mut stream, &mut buffer, &key, remote, false).unwrap();
+            let local = MetadataMessage::new(false, false).write_msg(&mut stream, &key, local);
+
+            let (remote, ack) =
+                AckMessage::read_msg(&mut stream, &mut buffer, &key, remote, false).unwrap();
+            let local = AckMessage::Ack.write_msg(&mut stream, &key, local);
+            (local, remote, ack)
+        };
+        let ack = AckInfo::from(&ack);
+        if !matches!(ack, AckInfo::Ack) {
+            log::info!("the node rejected the connection: {:?}", ack);
+            timeline.push(Event::Rejected(ack.nack_motive()));
+            return timeline;
+        }
+
+        let replayer = SimpleReplayer {
+            source: self.source,
+            stream,
+            buffer: ChunkBuffer::default(),
+            key,
+            local,
+            remote,
+            scenario: self.scenario,
+            written: 0,
+            rng: SmallRng::from_entropy(),
+        };
+        if let Some(state) = State::new(self.brief, replayer) {
+            state.run(control, &mut timeline, self.linger);
+        }
+        timeline
+    }
+}
+
 pub struct SimpleReplayer {
-    db: Db,
+    source: Arc<Source>,
     stream: TcpStream,
     buffer: ChunkBuffer,
     key: PrecomputedKey,
     local: Nonce,
     remote: Nonce,
+    scenario: Scenario,
+    written: u64,
+    rng: SmallRng,
 }
 
 impl Replayer for SimpleReplayer {
-    fn replay_read(&mut self, id: u64) -> Option<()> {
-        let message = self.db.fetch_message(id).unwrap().unwrap();
-        let peer_message = match &message.message {
-            &Some(TezosMessage::PeerMessage(ref v)) => v,
-            _ => panic!(),
-        };
-
+    fn replay_read(&mut self, id: u64) -> Option<String> {
         let r = PeerMessageResponse::read_msg(
             &mut self.stream,
             &mut self.buffer,
@@ -134,16 +807,17 @@ impl Replayer for SimpleReplayer {
             Ok(v) => v,
             Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                 log::info!("pending read {}", id);
-                return None;
+                return Ok(None);
             },
             Err(e) => {
-                panic!("{:?}", e);
+                log::info!("connection closed at {}: {}", id, e);
+                return Err(e);
             },
         };
         log::info!("replay read {}", id);
         self.remote = remote;
 
-        let _ = (peer_message, msg.message());
+        let kind = msg.as_bytes().map_or_else(|_| "unknown".to_string(), |b| kind_name(&b));
         /*let read = serde_json::to_string(&msg.message()).unwrap();
         let have = serde_json::to_string(peer_message).unwrap();
         if read != have {
@@ -152,24 +826,35 @@ impl Replayer for SimpleReplayer {
             //panic!();
         }*/
 
-        Some(())
+        Ok(Some(kind))
     }
 
-    fn replay_write(&mut self, id: u64) {
+    fn replay_write(&mut self, id: u64) -> io::Result<()> {
         log::info!("replay write {}", id);
 
-        let message = self.db.fetch_message(id).unwrap().unwrap();
-        let peer_message = match message.message {
-            Some(TezosMessage::PeerMessage(v)) => v,
-            _ => panic!(),
-        };
+        if let Some(delay) = self.scenario.delay_ms {
+            thread::sleep(Duration::from_millis(delay));
+        }
+        self.written += 1;
+        if Scenario::hit(self.scenario.drop_every, self.written) {
+            log::info!("drop {}", id);
+            return Ok(());
+        }
+        if Scenario::hit(self.scenario.oversized_every, self.written) {
+            log::info!("oversized chunk instead of {}", id);
+            self.local = write_oversized(&mut self.stream, &self.key, self.local.clone());
+            return Ok(());
+        }
 
-        let local = PeerMessageResponse::from(peer_message).write_msg(
-            &mut self.stream,
-            &self.key,
-            self.local.clone(),
-        );
-        self.local = local;
+        let mut bytes = self.source.bytes(id);
+        // the first 4 bytes are the length of the message, keep them intact
+        if Scenario::hit(self.scenario.mutate_every, self.written) && bytes.len() > 4 {
+            let pos = self.rng.gen_range(4..bytes.len());
+            log::info!("mutate {} at byte {}", id, pos);
+            bytes[pos] ^= self.rng.gen_range(1..=0xff);
+        }
+        self.local = try_write_raw(&bytes, &mut self.stream, &self.key, self.local.clone())?;
+        Ok(())
     }
 }
 
@@ -178,13 +863,31 @@ fn main() {
         .with_max_level(tracing::Level::INFO)
         .init();
 
-    let db = Db::open("/volume/debugger_db/tezedge", false, None, None).unwrap();
+    let args = Args::from_args();
+    let scenario = match &args.scenario {
+        Some(path) => {
+            let text = fs::read_to_string(path).unwrap();
+            toml::from_str::<Scenario>(&text).unwrap()
+        },
+        None => Scenario::default(),
+    };
+    let identity = match &args.identity {
+        Some(path) => fs::read_to_string(path).unwrap(),
+        None if args.generate_identity => {
+            log::info!("generating identity, pow {}", args.pow);
+            handshake::generate_identity(args.pow)
+        },
+        None => include_str!("../../identity_i.json").to_string(),
+    };
+    let mut scenario = args.overrides.or(scenario);
+    let linger = scenario.linger();
+    let assertions = mem::take(&mut scenario.assertions);
 
-    let mut filter = MessagesFilter::default();
-    filter.cursor = Some(0);
-    filter.limit = Some(100_000);
-    filter.direction = Some("forward".to_string());
-    let brief = db.fetch_messages(&filter).unwrap();
+    let source = match &args.conversation {
+        Some(path) => Source::Conversation(Conversation::load(path).unwrap()),
+        None => Source::Db(Db::open(&args.db, false, None, None, None, Some(0), None).unwrap()),
+    };
+    let brief = source.brief();
 
     assert!(matches!(&brief[0].category, &MessageCategory::Connection));
     assert!(!brief[0].incoming);
@@ -201,39 +904,71 @@ fn main() {
     assert!(matches!(&brief[5].category, &MessageCategory::Ack));
     assert!(brief[5].incoming);
 
-    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], 9732))).unwrap();
-    let (mut stream, _) = listener.accept().unwrap();
-    stream
-        .set_read_timeout(Some(Duration::from_millis(1_000)))
-        .unwrap();
-
-    let version = match db.fetch_message(0).unwrap().unwrap().message.unwrap() {
-        TezosMessage::ConnectionMessage(cm) => Some(cm.version().clone()),
-        _ => None,
+    let job = Job {
+        source: Arc::new(source),
+        brief,
+        identity,
+        scenario,
+        linger,
     };
-    let (key, NoncePair { local, remote }) = handshake::responder(
-        9732,
-        &mut stream,
-        include_str!("../../identity_i.json"),
-        version.unwrap(),
-    );
-
-    let mut buffer = ChunkBuffer::default();
-    let (remote, _msg) =
-        MetadataMessage::read_msg(&mut stream, &mut buffer, &key, remote, false).unwrap();
-    let local = MetadataMessage::new(false, false).write_msg(&mut stream, &key, local);
-
-    let (remote, _msg) =
-        AckMessage::read_msg(&mut stream, &mut buffer, &key, remote, false).unwrap();
-    let local = AckMessage::Ack.write_msg(&mut stream, &key, local);
-
-    let replayer = SimpleReplayer {
-        db,
-        stream,
-        buffer: ChunkBuffer::default(),
-        key,
-        local,
-        remote,
+    let controls = if args.target.is_empty() {
+        vec![Arc::new(Control::default())]
+    } else {
+        args.target.iter().map(|_| Arc::new(Control::default())).collect()
     };
-    State::new(brief, replayer).map(State::run);
+    if let Some(addr) = args.control {
+        serve_control(addr, controls.clone());
+    }
+
+    if args.target.is_empty() {
+        let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], 9732))).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let timeline = job.replay(stream, false, &controls[0]);
+
+        if assertions.is_empty() {
+            return;
+        }
+        let report = Report::new(&assertions, &timeline);
+        output(&args.report, &report);
+        if !report.passed {
+            process::exit(1);
+        }
+    } else {
+        // the same stimulus against every target at once, the replay id is the position
+        let handles = args
+            .target
+            .iter()
+            .zip(controls)
+            .map(|(&target, control)| {
+                let job = job.clone();
+                thread::spawn(move || {
+                    let stream = TcpStream::connect(target).unwrap();
+                    job.replay(stream, true, &control)
+                })
+            })
+            .collect::<Vec<_>>();
+        let timelines = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+
+        let comparison = Comparison::new(&args.target, &timelines, &assertions);
+        output(&args.report, &comparison);
+        log::info!("{} of {} steps diverged", comparison.divergences, comparison.steps.len());
+        if comparison.targets.iter().any(|t| !t.passed) {
+            process::exit(1);
+        }
+    }
+}
+
+/// Prints the report or writes it to the file
+fn output<T>(path: &Option<PathBuf>, report: &T)
+where
+    T: Serialize,
+{
+    let json = serde_json::to_string_pretty(report).unwrap();
+    match path {
+        Some(path) => fs::write(path, json).unwrap(),
+        None => println!("{}", json),
+    }
 }
@@ -3,6 +3,7 @@
 
 use std::{fmt, str::FromStr};
 use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 use thiserror::Error;
 
 pub type Local = typenum::B0;
@@ -10,7 +11,7 @@ pub type Remote = typenum::B1;
 
 /// Determines, if message belongs to communication originated
 /// from remote or local node
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum Initiator {
     Local,
@@ -36,7 +37,7 @@ impl Initiator {
 
 /// Determines, if message itself originated
 /// from remote or local node
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum Sender {
     Local,
@@ -191,9 +192,9 @@ impl FromStr for MessageType {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "connection_message" => Ok(MessageType::Connection),
-            "metadata" => Ok(MessageType::Meta),
-            "ack_message" => Ok(MessageType::Ack),
+            "connection_message" | "connection" => Ok(MessageType::Connection),
+            "metadata" | "meta" => Ok(MessageType::Meta),
+            "ack_message" | "ack" => Ok(MessageType::Ack),
 
             "disconnect" => Ok(MessageType::P2p(MessageKind::Disconnect)),
             "advertise" => Ok(MessageType::P2p(MessageKind::Advertise)),
@@ -228,6 +229,56 @@ impl FromStr for MessageType {
 }
 
 impl MessageType {
+    const P2P_KINDS: [MessageKind; 21] = [
+        MessageKind::Disconnect,
+        MessageKind::Bootstrap,
+        MessageKind::Advertise,
+        MessageKind::SwapRequest,
+        MessageKind::SwapAck,
+        MessageKind::GetCurrentBranch,
+        MessageKind::CurrentBranch,
+        MessageKind::Deactivate,
+        MessageKind::GetCurrentHead,
+        MessageKind::CurrentHead,
+        MessageKind::GetBlockHeaders,
+        MessageKind::BlockHeader,
+        MessageKind::GetOperations,
+        MessageKind::Operation,
+        MessageKind::GetProtocols,
+        MessageKind::Protocol,
+        MessageKind::GetOperationHashesForBlocks,
+        MessageKind::OperationHashesForBlocks,
+        MessageKind::GetOperationsForBlocks,
+        MessageKind::OperationsForBlocks,
+        MessageKind::Unknown,
+    ];
+
+    /// Parses the comma separated list of the types or the categories,
+    /// the `p2p` category stands for every kind of the peer message
+    pub fn parse_list(s: &str) -> Result<Vec<Self>, ParseTypeError> {
+        let mut tys = Vec::new();
+        for ty in s.split(',') {
+            if ty == "p2p" {
+                tys.extend(Self::P2P_KINDS.iter().cloned().map(MessageType::P2p));
+            } else {
+                tys.push(ty.parse()?);
+            }
+        }
+        Ok(tys)
+    }
+
+    /// The name of the kind, like `current_head`, or of the category if it has no kind
+    pub fn name(&self) -> String {
+        let (category, kind) = self.clone().split();
+        let name = match kind {
+            Some(kind) => serde_json::to_value(kind),
+            None => serde_json::to_value(category),
+        };
+        name.ok()
+            .and_then(|name| name.as_str().map(str::to_string))
+            .unwrap_or_default()
+    }
+
     pub fn split(self) -> (MessageCategory, Option<MessageKind>) {
         match self {
             MessageType::Connection => (MessageCategory::Connection, None),
@@ -0,0 +1,321 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tezos_messages::p2p::{encoding::connection::ConnectionMessage, binary_message::BinaryRead};
+use super::{
+    common::Sender,
+    database::{blocking, DatabaseFetch, ConnectionsFilter, FindingsFilter},
+    tables::{
+        chunk,
+        connection::{self, AckInfo},
+        finding::{Item, Rule},
+        peer,
+    },
+};
+
+// the handshake chunks of one side, 0 is the connection message, 1 the metadata, 2 the ack
+type Chunks = [Option<chunk::Value>; 3];
+
+/// Checks the recorded handshakes against the p2p specification, in the order
+/// the connections are recorded, the violations are stored as the findings
+pub struct Checker {
+    // the last checked connection
+    cursor: Option<String>,
+    // the nonces of the recent connection messages, by the connection which sent them
+    nonces: HashMap<[u8; 24], String>,
+    order: VecDeque<[u8; 24]>,
+}
+
+impl Checker {
+    pub const INTERVAL: Duration = Duration::from_secs(10);
+
+    const BATCH: u64 = 0x100;
+
+    const MAX_NONCES: usize = 0x1000;
+
+    // the handshake which is still going on after it is not checked until it is over
+    const PATIENCE_SECS: u64 = 60;
+
+    // `[port(2)][public key(32)][proof of work(24)][nonce(24)]...`
+    const NONCE_OFFSET: usize = 58;
+
+    /// Continues after the connection of the latest finding,
+    /// the connections without findings might be checked again after the restart
+    pub fn new<Db>(db: &Db) -> Result<Self, Db::Error>
+    where
+        Db: DatabaseFetch,
+    {
+        let filter = FindingsFilter {
+            limit: Some(1),
+            connection: None,
+            rule: None,
+            node_name: None,
+        };
+        let cursor = db
+            .fetch_findings(&filter)?
+            .into_iter()
+            .next()
+            .map(|f| f.item.connection);
+        Ok(Checker {
+            cursor,
+            nonces: HashMap::new(),
+            order: VecDeque::new(),
+        })
+    }
+
+    /// Checks the connections recorded since the last pass, returns how many findings stored
+    pub fn pass<Db>(&mut self, db: &Db) -> Result<usize, Db::Error>
+    where
+        Db: DatabaseFetch,
+    {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let filter = ConnectionsFilter {
+            limit: Some(Self::BATCH),
+            nack_motive: None,
+            termination: None,
+            handshake_slower_than: None,
+            after: self.cursor.clone(),
+            external: Some(false),
+        };
+        let mut stored = 0;
+        for (key, value) in db.fetch_connections(&filter)? {
+            let acks = value.acks();
+            let over = acks.incoming.is_some() && acks.outgoing.is_some()
+                || value.termination().is_some()
+                || key.ts + Self::PATIENCE_SECS < now;
+            // the connections are checked in order, the later ones wait for this one
+            if !over {
+                break;
+            }
+
+            let fetch = |incoming: bool| -> Result<Chunks, Db::Error> {
+                let mut chunks = [None, None, None];
+                for (counter, chunk) in chunks.iter_mut().enumerate() {
+                    let chunk_key = chunk::Key {
+                        cn_id: key.clone(),
+                        counter: counter as u64,
+                        sender: Sender::new(incoming),
+                    };
+                    *chunk = db.fetch_chunk(&chunk_key)?;
+                }
+                Ok(chunks)
+            };
+            let (local, remote) = (fetch(false)?, fetch(true)?);
+
+            let findings = self.check(&key, &value, &local, &remote);
+            stored += findings.len();
+            if !findings.is_empty() {
+                db.store_findings(findings)?;
+            }
+            self.cursor = Some(key.to_string());
+        }
+        Ok(stored)
+    }
+
+    fn check(
+        &mut self,
+        key: &connection::Key,
+        value: &connection::Value,
+        local: &Chunks,
+        remote: &Chunks,
+    ) -> Vec<Item> {
+        let connection = key.to_string();
+        let mut findings = vec![];
+        let mut finding = |sender: Sender, counter: u64, rule: Rule, detail: String| {
+            findings.push(Item {
+                connection: connection.clone(),
+                remote_addr: value.remote_addr(),
+                sender,
+                counter,
+                rule,
+                detail,
+            })
+        };
+
+        for (sender, chunks) in &[(Sender::Local, local), (Sender::Remote, remote)] {
+            for (counter, chunk) in chunks.iter().enumerate() {
+                let chunk = match chunk {
+                    Some(chunk) => chunk,
+                    None => continue,
+                };
+                let counter = counter as u64;
+                let expected = chunk.bytes.len().saturating_sub(2);
+                if chunk.bytes.len() < 2 {
+                    let detail = format!("chunk of {} bytes has no length", chunk.bytes.len());
+                    finding(sender.clone(), counter, Rule::ChunkLength, detail);
+                } else {
+                    let length = u16::from_be_bytes([chunk.bytes[0], chunk.bytes[1]]) as usize;
+                    if length != expected {
+                        let detail = format!("length is {}, the chunk has {}", length, expected);
+                        finding(sender.clone(), counter, Rule::ChunkLength, detail);
+                    }
+                }
+            }
+        }
+
+        // nonces
+        let nonce = |chunks: &Chunks| {
+            let plain = &chunks[0].as_ref()?.plain;
+            let nonce = plain.get(Self::NONCE_OFFSET..(Self::NONCE_OFFSET + 24))?;
+            <[u8; 24]>::try_from(nonce).ok()
+        };
+        let (local_nonce, remote_nonce) = (nonce(local), nonce(remote));
+        if local_nonce.is_some() && local_nonce == remote_nonce {
+            let detail = "both connection messages carry the same nonce".to_string();
+            finding(Sender::Remote, 0, Rule::Nonce, detail);
+        }
+        for (sender, nonce) in &[(Sender::Local, local_nonce), (Sender::Remote, remote_nonce)] {
+            let nonce = match nonce {
+                Some(nonce) => nonce,
+                None => continue,
+            };
+            if nonce == &[0; 24] {
+                let detail = "the nonce is zero".to_string();
+                finding(sender.clone(), 0, Rule::Nonce, detail);
+            }
+            match self.nonces.get(nonce) {
+                // the same nonce on both sides is found above
+                Some(previous) if previous == &connection => (),
+                Some(previous) => {
+                    let detail = format!("the nonce is reused from the connection {}", previous);
+                    finding(sender.clone(), 0, Rule::Nonce, detail);
+                },
+                None => {
+                    if self.order.len() == Self::MAX_NONCES {
+                        if let Some(oldest) = self.order.pop_front() {
+                            self.nonces.remove(&oldest);
+                        }
+                    }
+                    self.order.push_back(*nonce);
+                    self.nonces.insert(*nonce, connection.clone());
+                },
+            }
+        }
+        // the recorder has the key if either metadata is decrypted,
+        // the metadata of the other side is encrypted with the wrong nonce then
+        let decrypted = |chunks: &Chunks| chunks[1].as_ref().map(|c| !c.plain.is_empty());
+        let pairs = [
+            (Sender::Local, decrypted(local), decrypted(remote)),
+            (Sender::Remote, decrypted(remote), decrypted(local)),
+        ];
+        for (sender, this, other) in &pairs {
+            if *this == Some(false) && *other == Some(true) {
+                let detail = "the metadata is not encrypted with the implied nonce".to_string();
+                finding(sender.clone(), 1, Rule::Nonce, detail);
+            }
+        }
+
+        // ordering, each step is sent after the same step of the peer is received
+        let times = value.handshake();
+        let before = |a: Option<u64>, b: Option<u64>| a.zip(b).map_or(false, |(a, b)| a < b);
+        let names = ["connection message", "metadata", "ack"];
+        for step in 1..3 {
+            if before(times.sent(step), times.received(step - 1)) {
+                let detail = format!(
+                    "{} sent before the {} of the peer received",
+                    names[step as usize],
+                    names[step as usize - 1],
+                );
+                finding(Sender::Local, step, Rule::Ordering, detail);
+            }
+            if before(times.received(step), times.sent(step - 1)) {
+                let detail = format!(
+                    "{} received before the {} is sent",
+                    names[step as usize],
+                    names[step as usize - 1],
+                );
+                finding(Sender::Remote, step, Rule::Ordering, detail);
+            }
+        }
+
+        // version negotiation
+        let version = |chunks: &Chunks| {
+            let chunk = chunks[0].as_ref()?;
+            Some(ConnectionMessage::from_bytes(&chunk.plain).map(|msg| peer::Version::new(&msg)))
+        };
+        let (local_version, remote_version) = (version(local), version(remote));
+        let versions = [
+            (Sender::Local, &local_version),
+            (Sender::Remote, &remote_version),
+        ];
+        for (sender, version) in &versions {
+            if let Some(Err(error)) = version {
+                let detail = format!("cannot decode the connection message: {}", error);
+                finding(sender.clone(), 0, Rule::VersionNegotiation, detail);
+            }
+        }
+        if let (Some(Ok(local_version)), Some(Ok(remote_version))) = (local_version, remote_version)
+        {
+            if local_version.chain_name != remote_version.chain_name {
+                let acks = value.acks();
+                let pairs = [
+                    (Sender::Local, &acks.outgoing, &remote_version.chain_name),
+                    (Sender::Remote, &acks.incoming, &local_version.chain_name),
+                ];
+                for (sender, ack, chain_name) in &pairs {
+                    if let Some(AckInfo::Ack) = ack {
+                        let detail = format!("acknowledged the unknown chain {:?}", chain_name);
+                        finding(sender.clone(), 2, Rule::VersionNegotiation, detail);
+                    }
+                }
+            }
+        }
+
+        findings
+    }
+}
+
+/// Checks the new connections every `Checker::INTERVAL`
+pub async fn schedule<Db>(db: Arc<Db>, node_name: String)
+where
+    Db: DatabaseFetch + Send + Sync + 'static,
+{
+    let mut checker = {
+        let db = db.clone();
+        match blocking(move || Checker::new(db.as_ref())).await {
+            Ok(Ok(checker)) => checker,
+            Ok(Err(error)) => {
+                log::error!("cannot start conformance checker of {}: {}", node_name, error);
+                return;
+            },
+            Err(error) => {
+                log::error!("conformance checker of {} panicked: {}", node_name, error);
+                return;
+            },
+        }
+    };
+    let mut interval = tokio::time::interval(Checker::INTERVAL);
+    loop {
+        interval.tick().await;
+        let db = db.clone();
+        let result = blocking(move || {
+            let result = checker.pass(db.as_ref());
+            (checker, result)
+        })
+        .await;
+        checker = match result {
+            Ok((checker, Ok(0))) => checker,
+            Ok((checker, Ok(stored))) => {
+                log::info!("conformance of {}: {} new findings", node_name, stored);
+                checker
+            },
+            Ok((checker, Err(error))) => {
+                log::warn!("conformance check of {} failed: {}", node_name, error);
+                checker
+            },
+            Err(error) => {
+                log::error!("conformance checker of {} panicked: {}", node_name, error);
+                return;
+            },
+        };
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{IpAddr, SocketAddr, UdpSocket},
+    mem,
+    process::{Command, ExitStatus, Stdio},
+    sync::{
+        Mutex,
+        atomic::{Ordering, AtomicBool, AtomicU64},
+    },
+    io,
+};
+use serde::Serialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum FirewallError {
+    #[error("failed to run {}: {}", _0, _1)]
+    Spawn(&'static str, io::Error),
+    #[error("{} {} exited with {}", _0, _1, _2)]
+    Status(&'static str, String, ExitStatus),
+    #[error("{} cannot be blocked, {}", _0, _1)]
+    Refused(IpAddr, &'static str),
+}
+
+#[derive(Serialize)]
+pub struct CaptureState {
+    pub paused: bool,
+    pub skipped_bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct IgnoredConnection {
+    pub pid: u32,
+    pub fd: u32,
+}
+
+/// The connections and the peers excluded from the capture at runtime
+#[derive(Serialize)]
+pub struct Ignored {
+    pub connections: Vec<IgnoredConnection>,
+    /// like `51.15.220.7`, any port of the peer, or `51.15.220.7:9732`
+    pub peers: Vec<String>,
+}
+
+/// Runtime switches of the recorder, shared by the http server and the main loop
+#[derive(Default)]
+pub struct Control {
+    blocked: Mutex<BTreeSet<IpAddr>>,
+    paused: AtomicBool,
+    skipped_bytes: AtomicU64,
+    reloads: Mutex<BTreeSet<String>>,
+    // by the pid and the fd
+    ignored_connections: Mutex<BTreeSet<(u32, u32)>>,
+    // the port 0 is any port of the peer
+    ignored_peers: Mutex<BTreeSet<SocketAddr>>,
+    // the main loop stops recording the live connections which became ignored
+    ignored_changed: AtomicBool,
+    // by the port, override the `payload_limit` of the config
+    payload_limits: Mutex<BTreeMap<u16, u32>>,
+    payload_changed: AtomicBool,
+}
+
+impl Control {
+    /// While paused, the captured data is not parsed nor stored, only counted,
+    /// the connections which lose data are not recorded any further, even after resume
+    pub fn set_paused(&self, paused: bool) {
+        if self.paused.swap(paused, Ordering::SeqCst) != paused {
+            log::info!("capture {}", if paused { "paused" } else { "resumed" });
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn skip(&self, bytes: usize) {
+        self.skipped_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn capture_state(&self) -> CaptureState {
+        CaptureState {
+            paused: self.is_paused(),
+            skipped_bytes: self.skipped_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The main loop reads the identity of the node again before it handles the next event,
+    /// the connections established before keep the old one
+    pub fn reload_identity(&self, node_name: String) {
+        self.reloads.lock().unwrap().insert(node_name);
+    }
+
+    pub fn take_reloads(&self) -> BTreeSet<String> {
+        mem::take(&mut *self.reloads.lock().unwrap())
+    }
+
+    pub fn ignored(&self) -> Ignored {
+        Ignored {
+            connections: self
+                .ignored_connections
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|&(pid, fd)| IgnoredConnection { pid, fd })
+                .collect(),
+            peers: self
+                .ignored_peers
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|addr| match addr.port() {
+                    0 => addr.ip().to_string(),
+                    _ => addr.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    /// The connection the process has on the descriptor now, it is not recorded any further,
+    /// what is recorded stays, the entry is dropped when the descriptor is reused
+    pub fn ignore_connection(&self, pid: u32, fd: u32) {
+        self.ignored_connections.lock().unwrap().insert((pid, fd));
+        self.ignored_changed.store(true, Ordering::SeqCst);
+    }
+
+    /// The connection cannot be recorded again, its handshake is missed,
+    /// only the entry is removed
+    pub fn unignore_connection(&self, pid: u32, fd: u32) {
+        self.ignored_connections.lock().unwrap().remove(&(pid, fd));
+    }
+
+    /// The port 0 is any port of the peer, the live connections of the peer
+    /// are not recorded any further, the new ones are not recorded at all
+    pub fn ignore_peer(&self, addr: SocketAddr) {
+        self.ignored_peers.lock().unwrap().insert(addr);
+        self.ignored_changed.store(true, Ordering::SeqCst);
+    }
+
+    /// The new connections of the peer are recorded again
+    pub fn unignore_peer(&self, addr: SocketAddr) {
+        self.ignored_peers.lock().unwrap().remove(&addr);
+    }
+
+    pub fn is_ignored_peer(&self, addr: &SocketAddr) -> bool {
+        let peers = self.ignored_peers.lock().unwrap();
+        peers.contains(addr) || peers.contains(&SocketAddr::new(addr.ip(), 0))
+    }
+
+    pub fn is_ignored_connection(&self, pid: u32, fd: u32) -> bool {
+        self.ignored_connections.lock().unwrap().contains(&(pid, fd))
+    }
+
+    /// The new connection on the descriptor, the ignored one was closed
+    pub fn forget_connection(&self, pid: u32, fd: u32) {
+        self.ignored_connections.lock().unwrap().remove(&(pid, fd));
+    }
+
+    /// Whether something was ignored since the last call
+    pub fn take_ignored_changed(&self) -> bool {
+        self.ignored_changed.swap(false, Ordering::SeqCst)
+    }
+
+    /// The bytes of each read and write of the port captured after the handshake,
+    /// 0 is the full payload, the connections already limited stay so
+    pub fn limit_payload(&self, port: u16, limit: u32) {
+        self.payload_limits.lock().unwrap().insert(port, limit);
+        self.payload_changed.store(true, Ordering::SeqCst);
+    }
+
+    /// The port is limited as the config says
+    pub fn reset_payload(&self, port: u16) {
+        self.payload_limits.lock().unwrap().remove(&port);
+        self.payload_changed.store(true, Ordering::SeqCst);
+    }
+
+    pub fn payload_limits(&self) -> BTreeMap<u16, u32> {
+        self.payload_limits.lock().unwrap().clone()
+    }
+
+    pub fn payload_limit(&self, port: u16) -> Option<u32> {
+        self.payload_limits.lock().unwrap().get(&port).cloned()
+    }
+
+    pub fn take_payload_changed(&self) -> bool {
+        self.payload_changed.swap(false, Ordering::SeqCst)
+    }
+
+    pub fn is_blocked(&self, ip: &IpAddr) -> bool {
+        self.blocked.lock().unwrap().contains(ip)
+    }
+
+    pub fn blocked(&self) -> Vec<IpAddr> {
+        self.blocked.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Drops any further traffic to and from the peer by the firewall,
+    /// new connections of the peer are not recorded, the recorded ones are kept.
+    /// The loopback and the addresses of this host are refused, the node would cut itself off
+    pub fn block(&self, ip: IpAddr) -> Result<(), FirewallError> {
+        let mut blocked = self.blocked.lock().unwrap();
+        if !blocked.contains(&ip) {
+            blockable(ip)?;
+            firewall_block(ip)?;
+            blocked.insert(ip);
+        }
+        Ok(())
+    }
+
+    pub fn unblock(&self, ip: IpAddr) -> Result<(), FirewallError> {
+        let mut blocked = self.blocked.lock().unwrap();
+        if blocked.contains(&ip) {
+            firewall_unblock(ip)?;
+            blocked.remove(&ip);
+        }
+        Ok(())
+    }
+
+    /// Removes the chain of the blocked peers, called at start, the recorder which was killed
+    /// leaves it, and at exit, the rules never outlive the recorder which lists them
+    pub fn clear_firewall(&self) {
+        let mut blocked = self.blocked.lock().unwrap();
+        for &program in PROGRAMS.iter() {
+            teardown(program);
+        }
+        blocked.clear();
+    }
+}
+
+// the rules of the blocked peers, both `INPUT` and `OUTPUT` jump here
+const CHAIN: &str = "TEZEDGE-BLOCK";
+const PROGRAMS: [&str; 2] = ["iptables", "ip6tables"];
+
+fn blockable(ip: IpAddr) -> Result<(), FirewallError> {
+    // the ipv4 address might come mapped into ipv6
+    let mapped = match ip {
+        IpAddr::V6(v6) if !v6.is_loopback() => v6.to_ipv4().map(IpAddr::V4),
+        _ => None,
+    };
+    for ip in Some(ip).into_iter().chain(mapped) {
+        if ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() {
+            return Err(FirewallError::Refused(ip, "it is not a peer address"));
+        }
+        // only the address of this host can be bound
+        if UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok() {
+            return Err(FirewallError::Refused(ip, "it is the address of this host"));
+        }
+    }
+    Ok(())
+}
+
+fn run(program: &'static str, args: &[&str]) -> Result<(), FirewallError> {
+    let status = Command::new(program)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|e| FirewallError::Spawn(program, e))?;
+    if !status.success() {
+        return Err(FirewallError::Status(program, args.join(" "), status));
+    }
+    Ok(())
+}
+
+// creates the chain, unless it exists, and the jumps into it
+fn setup(program: &'static str) -> Result<(), FirewallError> {
+    let _ = run(program, &["-N", CHAIN]);
+    for &builtin in ["INPUT", "OUTPUT"].iter() {
+        if run(program, &["-C", builtin, "-j", CHAIN]).is_err() {
+            run(program, &["-I", builtin, "-j", CHAIN])?;
+        }
+    }
+    Ok(())
+}
+
+// the chain might not exist, or the program, nothing to remove then
+fn teardown(program: &'static str) {
+    for &builtin in ["INPUT", "OUTPUT"].iter() {
+        let _ = run(program, &["-D", builtin, "-j", CHAIN]);
+    }
+    let _ = run(program, &["-F", CHAIN]);
+    let _ = run(program, &["-X", CHAIN]);
+}
+
+fn program(ip: IpAddr) -> &'static str {
+    if ip.is_ipv4() {
+        "iptables"
+    } else {
+        "ip6tables"
+    }
+}
+
+fn firewall_block(ip: IpAddr) -> Result<(), FirewallError> {
+    let program = program(ip);
+    setup(program)?;
+    let ip = ip.to_string();
+    run(program, &["-A", CHAIN, "-s", ip.as_str(), "-j", "DROP"])?;
+    if let Err(error) = run(program, &["-A", CHAIN, "-d", ip.as_str(), "-j", "DROP"]) {
+        // the peer is either blocked both ways or not at all
+        let _ = run(program, &["-D", CHAIN, "-s", ip.as_str(), "-j", "DROP"]);
+        return Err(error);
+    }
+    Ok(())
+}
+
+fn firewall_unblock(ip: IpAddr) -> Result<(), FirewallError> {
+    let program = program(ip);
+    let ip = ip.to_string();
+    let incoming = run(program, &["-D", CHAIN, "-s", ip.as_str(), "-j", "DROP"]);
+    let outgoing = run(program, &["-D", CHAIN, "-d", ip.as_str(), "-j", "DROP"]);
+    incoming.and(outgoing)
+}
@@ -0,0 +1,236 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    sync::Arc,
+    thread,
+};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use serde_json::Value;
+use super::{
+    control::Control,
+    health::Health,
+    pipeline::Pipeline,
+    system::{SharedConfig, NodeOverrides},
+    database::{Database, DatabaseFetch},
+};
+
+/// The request of JSON-RPC 2.0, one per line
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i32,
+    message: String,
+}
+
+impl RpcError {
+    const PARSE: i32 = -32700;
+    const METHOD: i32 = -32601;
+    const PARAMS: i32 = -32602;
+    const INTERNAL: i32 = -32603;
+
+    fn new<E>(code: i32, message: E) -> Self
+    where
+        E: ToString,
+    {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NodeParams {
+    node_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpdateParams {
+    node_name: Option<String>,
+    overrides: NodeOverrides,
+}
+
+/// The control api of the http server on the unix socket, for the deployments
+/// where no tcp port is reachable, the access is governed by the permissions of the file
+pub struct ControlSocket<Db> {
+    pub dbs: HashMap<String, Arc<Db>>,
+    pub control: Arc<Control>,
+    pub health: Arc<Health>,
+    pub pipeline: Arc<Pipeline>,
+    pub config: Arc<SharedConfig>,
+}
+
+impl<Db> ControlSocket<Db>
+where
+    Db: Database + DatabaseFetch + Sync + Send + 'static,
+{
+    pub fn spawn(self, path: &Path) -> io::Result<thread::JoinHandle<()>> {
+        // the socket file of the previous run, binding fails while it exists
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+        let this = Arc::new(self);
+        thread::Builder::new()
+            .name("control-socket".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let this = this.clone();
+                            thread::spawn(move || {
+                                if let Err(error) = this.serve(stream) {
+                                    log::warn!("control socket client: {}", error);
+                                }
+                            });
+                        },
+                        Err(error) => log::error!("control socket: {}", error),
+                    }
+                }
+            })
+    }
+
+    fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(Request { id, method, params }) => match self.call(&method, params) {
+                    Ok(result) => Response {
+                        jsonrpc: "2.0",
+                        id,
+                        result: Some(result),
+                        error: None,
+                    },
+                    Err(error) => Response {
+                        jsonrpc: "2.0",
+                        id,
+                        result: None,
+                        error: Some(error),
+                    },
+                },
+                Err(error) => Response {
+                    jsonrpc: "2.0",
+                    id: Value::Null,
+                    result: None,
+                    error: Some(RpcError::new(RpcError::PARSE, error)),
+                },
+            };
+            let mut bytes = serde_json::to_vec(&response)?;
+            bytes.push(b'\n');
+            writer.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "capture.state" => reply(self.control.capture_state()),
+            "capture.pause" => {
+                self.control.set_paused(true);
+                reply(self.control.capture_state())
+            },
+            "capture.resume" => {
+                self.control.set_paused(false);
+                reply(self.control.capture_state())
+            },
+            "identity.reload" => {
+                let node_name = default_node(parse::<NodeParams>(params)?.node_name);
+                // read it here, so the error is reported to the caller
+                self.config
+                    .identity(&node_name)
+                    .map_err(|error| RpcError::new(RpcError::INTERNAL, error))?;
+                self.control.reload_identity(node_name);
+                Ok(Value::Null)
+            },
+            "config.get" => reply(self.config.snapshot()),
+            "config.update" => {
+                let UpdateParams {
+                    node_name,
+                    overrides,
+                } = parse(params)?;
+                let node_name = default_node(node_name);
+                let db = self.db(&node_name)?;
+                self.config
+                    .update(&node_name, db.as_ref(), overrides)
+                    .map_err(|error| RpcError::new(RpcError::PARAMS, error))?;
+                reply(self.config.snapshot())
+            },
+            "storage.stats" => {
+                let node_name = default_node(parse::<NodeParams>(params)?.node_name);
+                let stats = self
+                    .db(&node_name)?
+                    .fetch_storage_stats()
+                    .map_err(|error| RpcError::new(RpcError::INTERNAL, error))?;
+                reply(stats)
+            },
+            "pipeline" => reply(self.pipeline.report()),
+            "health" => reply(self.health.report()),
+            _ => Err(RpcError::new(
+                RpcError::METHOD,
+                format!("no such method: {:?}", method),
+            )),
+        }
+    }
+
+    fn db(&self, node_name: &str) -> Result<&Arc<Db>, RpcError> {
+        self.dbs.get(node_name).ok_or_else(|| {
+            RpcError::new(RpcError::PARAMS, format!("no such node: {:?}", node_name))
+        })
+    }
+}
+
+// the same default as the http api
+fn default_node(node_name: Option<String>) -> String {
+    node_name.unwrap_or_else(|| "tezedge".to_string())
+}
+
+// absent params are the same as the empty object
+fn parse<T>(params: Value) -> Result<T, RpcError>
+where
+    T: DeserializeOwned,
+{
+    let params = match params {
+        Value::Null => Value::Object(Default::default()),
+        params => params,
+    };
+    serde_json::from_value(params).map_err(|error| RpcError::new(RpcError::PARAMS, error))
+}
+
+fn reply<T>(value: T) -> Result<Value, RpcError>
+where
+    T: Serialize,
+{
+    serde_json::to_value(value).map_err(|error| RpcError::new(RpcError::INTERNAL, error))
+}
@@ -0,0 +1,165 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{fs, net::SocketAddr, path::Path};
+use serde::{Serialize, Deserialize};
+use tezos_messages::p2p::{encoding::connection::ConnectionMessage, binary_message::BinaryRead};
+use super::{
+    common::{Initiator, MessageKind, MessageType},
+    tables::{connection, message::{self, MessageFrontend}, peer},
+};
+
+/// One recorded connection with its decrypted messages, the portable scenario file,
+/// the replayer and the drone test client play it without the database
+#[derive(Serialize, Deserialize)]
+pub struct Conversation {
+    /// the version of the format
+    pub version: u32,
+    pub connection: String,
+    pub remote_addr: SocketAddr,
+    pub initiator: Initiator,
+    pub peer_id: Option<String>,
+    /// as the connection messages announced
+    pub local_version: Option<peer::Version>,
+    pub remote_version: Option<peer::Version>,
+    pub messages: Vec<ConversationMessage>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConversationMessage {
+    /// milliseconds since the first message
+    pub offset_ms: u64,
+    /// sent by the peer
+    pub incoming: bool,
+    /// like `connection_message` or `current_head`
+    pub kind: String,
+    /// the decrypted message as hex
+    pub hex: String,
+}
+
+#[derive(Clone, Copy)]
+pub enum ConversationFormat {
+    Yaml,
+    Cbor,
+}
+
+impl ConversationFormat {
+    pub fn parse(format: Option<&str>) -> Result<Self, String> {
+        match format {
+            None | Some("yaml") => Ok(ConversationFormat::Yaml),
+            Some("cbor") => Ok(ConversationFormat::Cbor),
+            Some(format) => Err(format!("unknown format {:?}, expected yaml or cbor", format)),
+        }
+    }
+
+    /// By the extension of the file, `.cbor` or else yaml
+    pub fn of_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("cbor") => ConversationFormat::Cbor,
+            _ => ConversationFormat::Yaml,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ConversationFormat::Yaml => "application/x-yaml",
+            ConversationFormat::Cbor => "application/cbor",
+        }
+    }
+}
+
+impl Conversation {
+    pub const VERSION: u32 = 1;
+
+    /// The `messages` of the connection in the order of their index,
+    /// with the decrypted bytes of each
+    pub fn new(
+        key: &connection::Key,
+        value: &connection::Value,
+        messages: Vec<(message::Item, Vec<u8>)>,
+    ) -> Self {
+        let start = messages.first().map_or(0, |(item, _)| item.timestamp);
+        let version = |incoming: bool| {
+            let (_, bytes) = messages.iter().find(|(item, _)| {
+                matches!(item.ty, MessageType::Connection) && item.sender.incoming() == incoming
+            })?;
+            let msg = ConnectionMessage::from_bytes(bytes).ok()?;
+            Some(peer::Version::new(&msg))
+        };
+        Conversation {
+            version: Self::VERSION,
+            connection: key.to_string(),
+            remote_addr: value.remote_addr(),
+            initiator: value.initiator().clone(),
+            peer_id: peer::peer_id(value.peer_pk()).ok(),
+            local_version: version(false),
+            remote_version: version(true),
+            messages: messages
+                .iter()
+                .map(|(item, bytes)| ConversationMessage {
+                    offset_ms: item.timestamp.saturating_sub(start),
+                    incoming: item.sender.incoming(),
+                    kind: item.ty.name(),
+                    hex: hex::encode(bytes),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn encode(&self, format: ConversationFormat) -> Result<Vec<u8>, String> {
+        match format {
+            ConversationFormat::Yaml => serde_yaml::to_vec(self).map_err(|e| e.to_string()),
+            ConversationFormat::Cbor => serde_cbor::to_vec(self).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn decode(bytes: &[u8], format: ConversationFormat) -> Result<Self, String> {
+        let this = match format {
+            ConversationFormat::Yaml => {
+                serde_yaml::from_slice::<Self>(bytes).map_err(|e| e.to_string())?
+            },
+            ConversationFormat::Cbor => {
+                serde_cbor::from_slice::<Self>(bytes).map_err(|e| e.to_string())?
+            },
+        };
+        if this.version != Self::VERSION {
+            return Err(format!("unsupported version {}", this.version));
+        }
+        Ok(this)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| e.to_string())?;
+        Self::decode(&bytes, ConversationFormat::of_path(path))
+    }
+
+    /// The messages as if fetched from the database, the id is the position in the scenario
+    pub fn brief(&self) -> Vec<MessageFrontend> {
+        self.messages
+            .iter()
+            .enumerate()
+            .map(|(id, m)| {
+                let ty = m.kind.parse().unwrap_or(MessageType::P2p(MessageKind::Unknown));
+                let (category, kind) = ty.split();
+                MessageFrontend {
+                    id: id as u64,
+                    timestamp: (m.offset_ms as u128) * 1_000_000,
+                    remote_addr: self.remote_addr,
+                    source_type: self.initiator.clone(),
+                    incoming: m.incoming,
+                    category,
+                    kind,
+                    message_preview: None,
+                    size: m.hex.len() / 2,
+                    annotations: vec![],
+                }
+            })
+            .collect()
+    }
+
+    /// The decrypted bytes of the message
+    pub fn bytes(&self, index: usize) -> Result<Vec<u8>, String> {
+        let message = self.messages.get(index).ok_or("no such message")?;
+        hex::decode(&message.hex).map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use serde::Serialize;
+
+#[derive(Clone, Serialize)]
+pub struct CoverageReport {
+    pub pid: u32,
+    /// the bytes the node transferred on the recorded connections as the kernel counted them,
+    /// since the recorder attached to the process
+    pub kernel_bytes: u64,
+    /// the bytes read from the ring buffer
+    pub captured_bytes: u64,
+    /// the bytes given to the parser, without the bytes skipped while paused or dropped
+    /// by the overflown decoder queue
+    pub processed_bytes: u64,
+    /// `processed_bytes / kernel_bytes` in percents, 100 while nothing is transferred,
+    /// the data still in the ring buffer is not processed yet, so it is a bit below 100 under load
+    pub coverage: f64,
+    /// the same during the last interval only
+    pub interval_coverage: f64,
+    /// unix seconds of the last reconciliation
+    pub timestamp: u64,
+}
+
+/// The bytes the kernel saw against the bytes the recorder processed, by the name of the node,
+/// the recorder misses the traffic silently otherwise, when the ring buffer overflows
+#[derive(Default)]
+pub struct Coverage {
+    nodes: Mutex<BTreeMap<String, CoverageReport>>,
+}
+
+impl Coverage {
+    /// The time between the reconciliations
+    pub const INTERVAL: Duration = Duration::from_secs(10);
+
+    pub fn update(&self, node: &str, pid: u32, kernel: u64, captured: u64, processed: u64) {
+        let percents = |processed: u64, kernel: u64| {
+            if kernel == 0 {
+                100.0
+            } else {
+                processed as f64 * 100.0 / kernel as f64
+            }
+        };
+        let mut nodes = self.nodes.lock().unwrap();
+        // the node restarted with the new pid, the counters start over
+        let (last_kernel, last_processed) = match nodes.get(node) {
+            Some(last) if last.pid == pid => (last.kernel_bytes, last.processed_bytes),
+            _ => (0, 0),
+        };
+        let report = CoverageReport {
+            pid,
+            kernel_bytes: kernel,
+            captured_bytes: captured,
+            processed_bytes: processed,
+            coverage: percents(processed, kernel),
+            interval_coverage: percents(
+                processed.saturating_sub(last_processed),
+                kernel.saturating_sub(last_kernel),
+            ),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        };
+        nodes.insert(node.to_string(), report);
+    }
+
+    pub fn report(&self) -> BTreeMap<String, CoverageReport> {
+        self.nodes.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,44 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// Flattens the records into csv, one line per record, the `columns` are the paths
+/// in the json of the record, like `p2p.count`, nested objects are written as json
+pub fn render<T>(records: &[T], columns: &[&str]) -> String
+where
+    T: Serialize,
+{
+    let mut s = columns.join(",");
+    s.push('\n');
+    for record in records {
+        let value = serde_json::to_value(record).unwrap_or(Value::Null);
+        let line = columns
+            .iter()
+            .map(|column| {
+                let field = column
+                    .split('.')
+                    .try_fold(&value, |value, key| value.get(key));
+                escape(field)
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        s.push_str(&line);
+        s.push('\n');
+    }
+    s
+}
+
+fn escape(field: Option<&Value>) -> String {
+    let s = match field {
+        None | Some(Value::Null) => return String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    };
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s
+    }
+}
@@ -0,0 +1,470 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+use serde::{Serialize, Deserialize};
+use lru::LruCache;
+use storage::persistent::{Decoder, Encoder};
+use super::{chunk, message};
+
+/// The cold tier, the messages of the expired day are moved here instead of removed
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveConfig {
+    /// the directory of the segment files, it might be on the cheaper disk
+    pub path: PathBuf,
+    /// the archived days are removed after that many days, kept forever if absent
+    pub retention_days: Option<u64>,
+}
+
+/// The archived message with its chunks, the plain of the chunks is restored
+pub struct Record {
+    pub index: u64,
+    pub item: message::Item,
+    pub chunks: Vec<chunk::Value>,
+}
+
+// * bytes layout: `[first index(8)][last index(8)][offset(8)][length(4)]`
+#[derive(Clone, Copy)]
+struct Block {
+    first: u64,
+    last: u64,
+    offset: u64,
+    length: u32,
+}
+
+impl Block {
+    const SIZE: usize = 28;
+
+    fn ser(&self, v: &mut Vec<u8>) {
+        v.extend_from_slice(&self.first.to_le_bytes());
+        v.extend_from_slice(&self.last.to_le_bytes());
+        v.extend_from_slice(&self.offset.to_le_bytes());
+        v.extend_from_slice(&self.length.to_le_bytes());
+    }
+
+    fn de(b: &[u8]) -> Self {
+        let le64 = |b: &[u8]| u64::from_le_bytes(TryFrom::try_from(b).unwrap());
+        Block {
+            first: le64(&b[0..8]),
+            last: le64(&b[8..16]),
+            offset: le64(&b[16..24]),
+            length: u32::from_le_bytes(TryFrom::try_from(&b[24..28]).unwrap()),
+        }
+    }
+}
+
+/// The messages of one day, the segment file is the lz4 compressed blocks of records,
+/// the index file is the range of the message indexes and the position of each block
+struct Segment {
+    path: PathBuf,
+    blocks: Vec<Block>,
+}
+
+/// Append only, the segment of the day is written once, when the day leaves the database
+pub struct Archive {
+    config: ArchiveConfig,
+    // by the day
+    segments: RwLock<BTreeMap<u64, Segment>>,
+    // the recently decompressed blocks by the day and the number of the block
+    cache: Mutex<LruCache<(u64, usize), Arc<Vec<Record>>>>,
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+impl Archive {
+    const BLOCK_RECORDS: usize = 0x100;
+    const CACHED_BLOCKS: usize = 0x10;
+
+    pub fn open(config: ArchiveConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.path)?;
+        let mut segments = BTreeMap::new();
+        for entry in fs::read_dir(&config.path)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("idx") {
+                continue;
+            }
+            let day = match path.file_stem().and_then(|s| s.to_str()?.parse::<u64>().ok()) {
+                Some(day) => day,
+                None => continue,
+            };
+            let index = fs::read(&path)?;
+            if index.len() % Block::SIZE != 0 {
+                log::warn!("archive index {} is corrupted, ignored", path.display());
+                continue;
+            }
+            let blocks = index.chunks(Block::SIZE).map(Block::de).collect();
+            let path = path.with_extension("seg");
+            segments.insert(day, Segment { path, blocks });
+        }
+        Ok(Archive {
+            config,
+            segments: RwLock::new(segments),
+            cache: Mutex::new(LruCache::new(Self::CACHED_BLOCKS)),
+        })
+    }
+
+    fn encode_record(record: &Record, v: &mut Vec<u8>) -> io::Result<()> {
+        let bad = |error| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", error));
+        let item = record.item.encode().map_err(bad)?;
+        v.extend_from_slice(&record.index.to_le_bytes());
+        v.extend_from_slice(&(item.len() as u32).to_le_bytes());
+        v.extend_from_slice(&item);
+        v.extend_from_slice(&(record.chunks.len() as u32).to_le_bytes());
+        for chunk in &record.chunks {
+            let chunk = chunk.encode().map_err(bad)?;
+            v.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            v.extend_from_slice(&chunk);
+        }
+        Ok(())
+    }
+
+    fn decode_records(mut b: &[u8]) -> io::Result<Vec<Record>> {
+        fn take<'a>(b: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+            if b.len() < len {
+                return Err(invalid("truncated archive record"));
+            }
+            let (head, tail) = b.split_at(len);
+            *b = tail;
+            Ok(head)
+        }
+        let le32 = |b: &[u8]| u32::from_le_bytes(TryFrom::try_from(b).unwrap()) as usize;
+        let bad = |_| invalid("bad archive record");
+
+        let mut records = vec![];
+        while !b.is_empty() {
+            let index = u64::from_le_bytes(TryFrom::try_from(take(&mut b, 8)?).unwrap());
+            let len = le32(take(&mut b, 4)?);
+            let item = message::Item::decode(take(&mut b, len)?).map_err(bad)?;
+            let count = le32(take(&mut b, 4)?);
+            let mut chunks = Vec::with_capacity(count);
+            for _ in 0..count {
+                let len = le32(take(&mut b, 4)?);
+                chunks.push(chunk::Value::decode(take(&mut b, len)?).map_err(bad)?);
+            }
+            records.push(Record {
+                index,
+                item,
+                chunks,
+            });
+        }
+        Ok(records)
+    }
+
+    /// Writes the segment of the day, the `records` go in the order of the index,
+    /// the segment and its index are synced to the disk when it returns
+    pub fn write_day<I>(&self, day: u64, records: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = Record>,
+    {
+        let path = self.config.path.join(format!("{}.seg", day));
+        let index_path = path.with_extension("idx");
+        let tmp = path.with_extension("seg.tmp");
+        let mut file = File::create(&tmp)?;
+
+        let mut blocks = vec![];
+        let mut offset = 0;
+        let mut flush = |raw: &mut Vec<u8>, range: &mut Option<(u64, u64)>| -> io::Result<()> {
+            if let Some((first, last)) = range.take() {
+                let compressed = lz4_flex::compress_prepend_size(raw);
+                file.write_all(&compressed)?;
+                blocks.push(Block {
+                    first,
+                    last,
+                    offset,
+                    length: compressed.len() as u32,
+                });
+                offset += compressed.len() as u64;
+            }
+            raw.clear();
+            Ok(())
+        };
+        let (mut raw, mut range, mut count) = (vec![], None, 0);
+        for record in records {
+            Self::encode_record(&record, &mut raw)?;
+            let first = range.map_or(record.index, |(first, _)| first);
+            range = Some((first, record.index));
+            count += 1;
+            if count % Self::BLOCK_RECORDS == 0 {
+                flush(&mut raw, &mut range)?;
+            }
+        }
+        flush(&mut raw, &mut range)?;
+        file.sync_all()?;
+        drop(file);
+        if blocks.is_empty() {
+            return fs::remove_file(tmp);
+        }
+
+        let mut index = Vec::with_capacity(blocks.len() * Block::SIZE);
+        blocks.iter().for_each(|block| block.ser(&mut index));
+        // the index appears last, the segment without it is not loaded
+        fs::rename(&tmp, &path)?;
+        let index_tmp = index_path.with_extension("idx.tmp");
+        let mut file = File::create(&index_tmp)?;
+        file.write_all(&index)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&index_tmp, &index_path)?;
+        // the renames survive the crash once the directory is synced,
+        // the database removes the day only after that
+        File::open(&self.config.path)?.sync_all()?;
+        log::info!("archived day {}, {} messages", day, count);
+
+        let mut segments = self.segments.write().unwrap();
+        // the day is written again if its removal from the database failed
+        if let Some(old) = segments.insert(day, Segment { path, blocks }) {
+            let mut cache = self.cache.lock().unwrap();
+            for number in 0..old.blocks.len() {
+                cache.pop(&(day, number));
+            }
+        }
+        if let Some(retention_days) = self.config.retention_days {
+            let expired = segments
+                .keys()
+                .take_while(|d| **d + retention_days < day)
+                .cloned()
+                .collect::<Vec<_>>();
+            for day in expired {
+                if let Some(segment) = segments.remove(&day) {
+                    log::info!("archived day {} expires", day);
+                    let _ = fs::remove_file(segment.path.with_extension("idx"));
+                    let _ = fs::remove_file(&segment.path);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, day: u64, number: usize) -> io::Result<Arc<Vec<Record>>> {
+        if let Some(records) = self.cache.lock().unwrap().get(&(day, number)) {
+            return Ok(records.clone());
+        }
+        let (path, block) = {
+            let segments = self.segments.read().unwrap();
+            let segment = segments.get(&day).ok_or_else(|| invalid("no such segment"))?;
+            let block = *segment.blocks.get(number).ok_or_else(|| invalid("no such block"))?;
+            (segment.path.clone(), block)
+        };
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(block.offset))?;
+        let mut compressed = vec![0; block.length as usize];
+        file.read_exact(&mut compressed)?;
+        let raw = lz4_flex::decompress_size_prepended(&compressed)
+            .map_err(|error| invalid(&error.to_string()))?;
+        let records = Arc::new(Self::decode_records(&raw)?);
+        self.cache.lock().unwrap().put((day, number), records.clone());
+        Ok(records)
+    }
+
+    /// The archived message by its index
+    pub fn message(&self, index: u64) -> io::Result<Option<Record>> {
+        let found = self.segments.read().unwrap().iter().find_map(|(day, segment)| {
+            let number = segment
+                .blocks
+                .iter()
+                .position(|b| b.first <= index && index <= b.last)?;
+            Some((*day, number))
+        });
+        let (day, number) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let records = self.read_block(day, number)?;
+        Ok(records.iter().find(|r| r.index == index).map(|r| Record {
+            index: r.index,
+            item: r.item.clone(),
+            chunks: r.chunks.clone(),
+        }))
+    }
+
+    /// The greatest archived index, `None` if nothing is archived
+    pub fn last_index(&self) -> Option<u64> {
+        let segments = self.segments.read().unwrap();
+        segments.values().filter_map(|s| s.blocks.last()).map(|b| b.last).max()
+    }
+
+    /// Every archived message in the index order, or in the reverse order, from the `cursor`,
+    /// the block which cannot be read is skipped
+    pub fn scan(
+        &self,
+        forward: bool,
+        cursor: Option<u64>,
+    ) -> impl Iterator<Item = (u64, message::Item)> + '_ {
+        let mut blocks = {
+            let segments = self.segments.read().unwrap();
+            segments
+                .iter()
+                .flat_map(|(day, segment)| {
+                    segment
+                        .blocks
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, b)| match cursor {
+                            Some(cursor) if forward => b.last >= cursor,
+                            Some(cursor) => b.first <= cursor,
+                            None => true,
+                        })
+                        .map(move |(number, b)| (b.first, *day, number))
+                })
+                .collect::<Vec<_>>()
+        };
+        blocks.sort_unstable();
+        if !forward {
+            blocks.reverse();
+        }
+        blocks
+            .into_iter()
+            .filter_map(move |(_, day, number)| match self.read_block(day, number) {
+                Ok(records) => Some(records),
+                Err(error) => {
+                    log::warn!("cannot read archived block {} of day {}: {}", number, day, error);
+                    None
+                },
+            })
+            .flat_map(move |records| {
+                let mut v = records
+                    .iter()
+                    .filter(|r| match cursor {
+                        Some(cursor) if forward => r.index >= cursor,
+                        Some(cursor) => r.index <= cursor,
+                        None => true,
+                    })
+                    .map(|r| (r.index, r.item.clone()))
+                    .collect::<Vec<_>>();
+                if !forward {
+                    v.reverse();
+                }
+                v
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+    use bytes::Bytes;
+    use super::{
+        Archive, ArchiveConfig, Record,
+        super::{chunk, connection, message::MessageBuilder, common::{Initiator, Sender}},
+    };
+
+    fn config(name: &str, retention_days: Option<u64>) -> ArchiveConfig {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        ArchiveConfig {
+            path,
+            retention_days,
+        }
+    }
+
+    fn records(range: std::ops::Range<u64>) -> impl Iterator<Item = Record> {
+        let cn = connection::Item::new(Initiator::Local, ([127, 0, 0, 1], 9732).into());
+        range.map(move |index| {
+            let builder = match index % 3 {
+                0 => MessageBuilder::connection_message(),
+                1 => MessageBuilder::metadata_message(),
+                _ => MessageBuilder::acknowledge_message(),
+            };
+            let bytes = Bytes::copy_from_slice(&index.to_le_bytes());
+            let chunk = chunk::Item::new(cn.key(), Sender::Local, 0, bytes.clone(), bytes);
+            Record {
+                index,
+                item: builder.build(&Sender::Local, &cn),
+                chunks: vec![chunk.split().1],
+            }
+        })
+    }
+
+    fn files(path: &PathBuf) -> Vec<String> {
+        let mut names = fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn check(archive: &Archive) {
+        for &index in &[1000, 1255, 1256, 1599] {
+            let record = archive.message(index).unwrap().unwrap();
+            assert_eq!(record.index, index);
+            assert_eq!(record.chunks[0].plain[..], index.to_le_bytes()[..]);
+        }
+        assert!(archive.message(999).unwrap().is_none());
+        assert!(archive.message(1600).unwrap().is_none());
+        assert_eq!(archive.last_index(), Some(1599));
+
+        let block = archive.read_block(5, 1).unwrap();
+        assert_eq!(block.len(), Archive::BLOCK_RECORDS);
+        assert_eq!(block[0].index, 1256);
+
+        let forward = archive.scan(true, None).map(|(i, _)| i).collect::<Vec<_>>();
+        assert_eq!(forward, (1000..1600).collect::<Vec<_>>());
+        let backward = archive.scan(false, Some(1300)).map(|(i, _)| i).collect::<Vec<_>>();
+        assert_eq!(backward, (1000..1301).rev().collect::<Vec<_>>());
+        let from = archive.scan(true, Some(1590)).map(|(i, _)| i).collect::<Vec<_>>();
+        assert_eq!(from, (1590..1600).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = config("archive-round-trip", None);
+        let archive = Archive::open(config.clone()).unwrap();
+        archive.write_day(5, records(1000..1600)).unwrap();
+        assert_eq!(files(&config.path), ["5.idx", "5.seg"]);
+        check(&archive);
+
+        // the index is durable, the reopened archive finds the same messages
+        drop(archive);
+        let archive = Archive::open(config.clone()).unwrap();
+        check(&archive);
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[test]
+    fn rewrite_day() {
+        let config = config("archive-rewrite", None);
+        let archive = Archive::open(config.clone()).unwrap();
+        archive.write_day(5, records(0..10)).unwrap();
+        assert_eq!(archive.read_block(5, 0).unwrap()[0].index, 0);
+
+        // the retry after the failed removal from the database, the cached block is stale
+        archive.write_day(5, records(1000..1600)).unwrap();
+        check(&archive);
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[test]
+    fn empty_day() {
+        let config = config("archive-empty", None);
+        let archive = Archive::open(config.clone()).unwrap();
+        archive.write_day(5, records(0..0)).unwrap();
+        assert!(files(&config.path).is_empty());
+        assert_eq!(archive.last_index(), None);
+        assert_eq!(archive.scan(true, None).count(), 0);
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+
+    #[test]
+    fn retention() {
+        let config = config("archive-retention", Some(1));
+        let archive = Archive::open(config.clone()).unwrap();
+        archive.write_day(1, records(0..10)).unwrap();
+        archive.write_day(2, records(10..20)).unwrap();
+        archive.write_day(3, records(20..30)).unwrap();
+        assert_eq!(files(&config.path), ["2.idx", "2.seg", "3.idx", "3.seg"]);
+        assert!(archive.message(5).unwrap().is_none());
+        assert_eq!(archive.message(15).unwrap().unwrap().index, 15);
+        fs::remove_dir_all(&config.path).unwrap();
+    }
+}
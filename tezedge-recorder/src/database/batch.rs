@@ -0,0 +1,217 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use rocksdb::{DB, WriteBatch};
+use storage::persistent::{DBError, Encoder, KeyValueSchema, database::RocksDbKeyValueSchema};
+
+#[derive(Default)]
+struct State {
+    // by the name of the column family, the batch is built on commit,
+    // so the records stay if the write fails
+    records: Vec<(String, Vec<u8>, Vec<u8>)>,
+    entries: usize,
+    // the entries of the failed commit, they do not trigger the next one
+    retained: usize,
+    since: Option<Instant>,
+}
+
+/// Accumulates the writes to several column families and commits them in a single `WriteBatch`,
+/// when the batch has enough entries, or when the oldest entry waits long enough.
+/// The writes are not visible until commit, the readers see them at most `max_delay` late,
+/// `flush_expired` is called periodically, so the batch is committed when the writes stop.
+/// The batch which fails to commit is retried with the next one, up to `RETAINED_BATCHES`.
+pub struct Batcher {
+    state: Mutex<State>,
+    max_entries: usize,
+    max_delay: Duration,
+}
+
+/// Collects the encoded records of one item
+pub struct BatchWriter<'a> {
+    db: &'a DB,
+    records: Vec<(String, Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a> BatchWriter<'a> {
+    pub fn put<S>(&mut self, key: &S::Key, value: &S::Value) -> Result<(), DBError>
+    where
+        S: KeyValueSchema + RocksDbKeyValueSchema,
+    {
+        self.put_cf::<S>(S::name(), key, value)
+    }
+
+    /// Same as `put`, but the column family is given by name, e.g. a day shard of the schema
+    pub fn put_cf<S>(&mut self, name: &str, key: &S::Key, value: &S::Value) -> Result<(), DBError>
+    where
+        S: KeyValueSchema + RocksDbKeyValueSchema,
+    {
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| DBError::MissingColumnFamily { name: S::name() })?;
+        let key = key.encode().map_err(|error| DBError::SchemaError { error })?;
+        let value = value
+            .encode()
+            .map_err(|error| DBError::SchemaError { error })?;
+        self.records.push((name.to_string(), key, value));
+        Ok(())
+    }
+}
+
+impl Batcher {
+    // the failed batches kept for the retry, the entries beyond are lost
+    const RETAINED_BATCHES: usize = 0x10;
+
+    pub fn new(max_entries: usize, max_delay: Duration) -> Self {
+        Batcher {
+            state: Mutex::new(State::default()),
+            max_entries,
+            max_delay,
+        }
+    }
+
+    /// The closure puts the records of one item, they are added to the batch
+    /// only if all of them are encoded, so the item is committed entirely or not at all.
+    /// The error of the commit means the batch is kept and retried later
+    pub fn write<F>(&self, db: &DB, f: F) -> Result<(), DBError>
+    where
+        F: FnOnce(&mut BatchWriter) -> Result<(), DBError>,
+    {
+        let mut writer = BatchWriter {
+            db,
+            records: Vec::new(),
+        };
+        f(&mut writer)?;
+
+        let mut state = self.state.lock().unwrap();
+        state.records.extend(writer.records);
+        state.entries += 1;
+        let since = *state.since.get_or_insert_with(Instant::now);
+        let fresh = state.entries - state.retained;
+        if fresh >= self.max_entries || since.elapsed() >= self.max_delay {
+            self.commit(db, &mut state)?;
+        }
+        Ok(())
+    }
+
+    /// Commits the batch if its oldest entry waits at least `max_delay`
+    pub fn flush_expired(&self, db: &DB) -> Result<(), DBError> {
+        let mut state = self.state.lock().unwrap();
+        match state.since {
+            Some(since) if since.elapsed() >= self.max_delay => self.commit(db, &mut state),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn flush(&self, db: &DB) -> Result<(), DBError> {
+        self.commit(db, &mut self.state.lock().unwrap())
+    }
+
+    fn commit(&self, db: &DB, state: &mut State) -> Result<(), DBError> {
+        if state.entries == 0 {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::default();
+        for (name, key, value) in &state.records {
+            match db.cf_handle(name) {
+                Some(cf) => batch.put_cf(cf, key, value),
+                None => log::warn!("column family {} is gone, the record is dropped", name),
+            }
+        }
+        match db.write(batch) {
+            Ok(()) => {
+                *state = State::default();
+                Ok(())
+            },
+            Err(error) => {
+                if state.entries >= self.max_entries * Self::RETAINED_BATCHES {
+                    log::error!("the batch failed again, {} entries are lost", state.entries);
+                    *state = State::default();
+                } else {
+                    // retried after the delay, or when the batch is full again
+                    state.retained = state.entries;
+                    state.since = Some(Instant::now());
+                }
+                Err(DBError::RocksDBError { error })
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time::Duration};
+    use rocksdb::{DB, Options};
+    use storage::persistent::Encoder;
+    use super::{Batcher, super::node_log};
+
+    fn open(name: &str) -> (DB, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        (DB::open_cf(&opts, &path, &["log_storage"]).unwrap(), path)
+    }
+
+    fn write(batcher: &Batcher, db: &DB, key: u64) {
+        let item = node_log::Item {
+            level: node_log::LogLevel::Info,
+            timestamp: key as u128,
+            section: String::new(),
+            message: format!("message {}", key),
+        };
+        batcher
+            .write(db, |b| b.put::<node_log::Schema>(&key, &item))
+            .unwrap();
+    }
+
+    fn visible(db: &DB, key: u64) -> bool {
+        let cf = db.cf_handle("log_storage").unwrap();
+        db.get_cf(cf, key.encode().unwrap()).unwrap().is_some()
+    }
+
+    #[test]
+    fn max_entries() {
+        let (db, path) = open("batch-max-entries");
+        let batcher = Batcher::new(3, Duration::from_secs(3600));
+        write(&batcher, &db, 0);
+        write(&batcher, &db, 1);
+        assert!(!visible(&db, 0) && !visible(&db, 1));
+        // the third entry fills the batch
+        write(&batcher, &db, 2);
+        assert!((0..3).all(|key| visible(&db, key)));
+        write(&batcher, &db, 3);
+        assert!(!visible(&db, 3));
+        batcher.flush(&db).unwrap();
+        assert!(visible(&db, 3));
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn max_delay() {
+        let (db, path) = open("batch-max-delay");
+        let batcher = Batcher::new(100, Duration::from_millis(200));
+        write(&batcher, &db, 0);
+        batcher.flush_expired(&db).unwrap();
+        assert!(!visible(&db, 0));
+        thread::sleep(Duration::from_millis(250));
+        // the writes stopped, the periodic flush commits the batch
+        batcher.flush_expired(&db).unwrap();
+        assert!(visible(&db, 0));
+
+        // the entry which comes late commits the batch itself
+        write(&batcher, &db, 1);
+        thread::sleep(Duration::from_millis(250));
+        write(&batcher, &db, 2);
+        assert!(visible(&db, 1) && visible(&db, 2));
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::sync::{
+    Mutex,
+    atomic::{Ordering, AtomicU64},
+};
+use lru::LruCache;
+use serde::Serialize;
+use super::tables::message::MessageFrontend;
+
+#[derive(Serialize, Default)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// `hits / (hits + misses)`, zero if nothing is requested yet
+    pub hit_rate: f64,
+}
+
+/// The newest messages are requested again and again by polling clients,
+/// the preview of the message is decoded from its chunks, so the result is kept
+pub struct MessageCache {
+    lru: Option<Mutex<LruCache<u64, MessageFrontend>>>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl MessageCache {
+    /// Zero `capacity` disables the cache
+    pub fn new(capacity: usize) -> Self {
+        MessageCache {
+            lru: Some(capacity)
+                .filter(|&c| c != 0)
+                .map(|c| Mutex::new(LruCache::new(c))),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get_or_insert_with<F>(&self, index: u64, f: F) -> MessageFrontend
+    where
+        F: FnOnce() -> MessageFrontend,
+    {
+        let lru = match &self.lru {
+            Some(lru) => lru,
+            None => return f(),
+        };
+        if let Some(value) = lru.lock().unwrap().get(&index) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return value.clone();
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // computed without the lock, the other thread might compute the same value meanwhile
+        let value = f();
+        lru.lock().unwrap().put(index, value.clone());
+        value
+    }
+
+    /// Does not compute the missing value, does not count
+    pub fn get(&self, index: u64) -> Option<MessageFrontend> {
+        self.lru.as_ref()?.lock().unwrap().get(&index).cloned()
+    }
+
+    pub fn remove(&self, index: u64) {
+        if let Some(lru) = &self.lru {
+            lru.lock().unwrap().pop(&index);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let total = hits + misses;
+        CacheStats {
+            capacity: self.capacity,
+            hits,
+            misses,
+            hit_rate: if total == 0 {
+                0.0
+            } else {
+                hits as f64 / total as f64
+            },
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    sync::Mutex,
+};
+use bytes::Bytes;
+use super::{chunk, common::MessageKind};
+
+// the connection and the sender
+type Side = (u64, u32, bool);
+
+struct Base {
+    counter: u64,
+    plain: Bytes,
+    day: u64,
+    deltas: u32,
+}
+
+#[derive(Default)]
+struct Inner {
+    bases: HashMap<Side, Base>,
+    // the oldest side is forgotten first
+    order: VecDeque<Side>,
+}
+
+/// Stores the `current_head` chunk as the difference from the earlier `current_head` chunk
+/// of the same connection and sender, the base, it is stored as is. The base is replaced
+/// when the difference grows too big, after `MAX_DELTAS` chunks and on the next day,
+/// so the base is not removed with the older shard. The base removed by the store limit
+/// restores the plain of its dependents first, see `Db::release_base`.
+#[derive(Default)]
+pub struct Deltas {
+    enabled: bool,
+    inner: Mutex<Inner>,
+}
+
+impl Deltas {
+    const MAX_SIDES: usize = 0x1000;
+    // the dependents of the base removed by the store limit are restored, at most that many
+    const MAX_DELTAS: u32 = 0x40;
+
+    pub fn new(enabled: bool) -> Self {
+        Deltas {
+            enabled,
+            inner: Mutex::default(),
+        }
+    }
+
+    // the chunk carries the whole `current_head`, `[length(4)][tag(2)]...`
+    fn is_current_head(plain: &[u8]) -> bool {
+        let length = match plain.get(..4).and_then(|b| <[u8; 4]>::try_from(b).ok()) {
+            Some(length) => u32::from_be_bytes(length) as usize,
+            None => return false,
+        };
+        let tag = match plain.get(4..6) {
+            Some(tag) => u16::from_be_bytes([tag[0], tag[1]]),
+            None => return false,
+        };
+        length + 4 == plain.len() && MessageKind::from_tag(tag) == MessageKind::CurrentHead
+    }
+
+    /// The `current_head` chunk stored as is, the later chunks of the side might depend on it
+    pub fn is_base(value: &chunk::Value) -> bool {
+        value.delta_base().is_none() && Self::is_current_head(&value.plain)
+    }
+
+    /// The removed chunk is no longer the base of the later chunks of its side
+    pub fn forget(&self, key: &chunk::Key) {
+        let side = (key.cn_id.ts, key.cn_id.ts_nanos, key.sender.incoming());
+        let mut inner = self.inner.lock().unwrap();
+        if inner.bases.get(&side).map(|base| base.counter) == Some(key.counter) {
+            inner.bases.remove(&side);
+            inner.order.retain(|s| *s != side);
+        }
+    }
+
+    /// Replaces the plain of the value with the difference, if worth it,
+    /// the `day` is the one of the shard the value goes to
+    pub fn compress(&self, key: &chunk::Key, day: u64, value: &mut chunk::Value) {
+        if !self.enabled || !Self::is_current_head(&value.plain) {
+            return;
+        }
+        let side = (key.cn_id.ts, key.cn_id.ts_nanos, key.sender.incoming());
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { bases, order } = &mut *inner;
+        if let Some(base) = bases.get_mut(&side) {
+            if base.day == day
+                && base.deltas < Self::MAX_DELTAS
+                && value.compress(base.counter, &base.plain)
+            {
+                base.deltas += 1;
+                return;
+            }
+        } else {
+            if order.len() == Self::MAX_SIDES {
+                if let Some(oldest) = order.pop_front() {
+                    bases.remove(&oldest);
+                }
+            }
+            order.push_back(side);
+        }
+        let base = Base {
+            counter: key.counter,
+            plain: value.plain.clone(),
+            day,
+            deltas: 0,
+        };
+        bases.insert(side, base);
+    }
+}
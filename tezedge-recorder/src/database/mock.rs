@@ -11,11 +11,17 @@ use anyhow::Result;
 #[rustfmt::skip]
 use super::{
     // core traits
-    Database, DatabaseNew, DatabaseFetch,
+    Database, DatabaseNew, DatabaseFetch, StorageStats, tuning::RocksdbConfig,
     // filters
-    ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter,
+    ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter, LogCountsFilter,
+    AnnotationsFilter, IncidentsFilter, FindingsFilter, BandwidthFilter, GapsFilter,
     // tables
-    connection, chunk, message, node_log,
+    connection, chunk, message, node_log, peer, annotation, session, incident, finding, preset,
+    gap,
+    // secondary indexes
+    log_count, bandwidth,
+    // the scenario
+    Conversation,
 };
 
 pub struct Db {
@@ -30,11 +36,21 @@ impl DatabaseNew for Db {
         log_full_text_index: bool,
         log_store_limit: Option<u64>,
         message_store_limit: Option<u64>,
+        message_retention_days: Option<u64>,
+        message_cache: Option<usize>,
+        tuning: Option<RocksdbConfig>,
     ) -> Result<Self, Self::Error>
     where
         P: AsRef<Path>,
     {
-        let _ = (log_full_text_index, log_store_limit, message_store_limit);
+        let _ = (
+            log_full_text_index,
+            log_store_limit,
+            message_store_limit,
+            message_retention_days,
+            message_cache,
+            tuning,
+        );
 
         Ok(Db {
             file: Mutex::new(File::create(path)?),
@@ -80,6 +96,14 @@ impl Database for Db {
             .unwrap();
     }
 
+    fn count_message(&self, item: message::Item) {
+        self.file
+            .lock()
+            .unwrap()
+            .write_fmt(format_args!("sampled: {:?}", item.ty))
+            .unwrap();
+    }
+
     fn store_log(&self, item: node_log::Item) {
         self.file
             .lock()
@@ -87,6 +111,18 @@ impl Database for Db {
             .write_fmt(format_args!("log: {:?}", item.level))
             .unwrap();
     }
+
+    fn store_peer(&self, item: peer::Item) {
+        self.file
+            .lock()
+            .unwrap()
+            .write_fmt(format_args!("peer: {}", hex::encode(item.pk)))
+            .unwrap();
+    }
+
+    fn set_store_limits(&self, message_store_limit: Option<u64>, log_store_limit: Option<u64>) {
+        let _ = (message_store_limit, log_store_limit);
+    }
 }
 
 impl DatabaseFetch for Db {
@@ -128,4 +164,150 @@ impl DatabaseFetch for Db {
         let _ = filter;
         Ok(vec![])
     }
+
+    fn fetch_log_counts(
+        &self,
+        filter: &LogCountsFilter,
+    ) -> Result<Vec<log_count::LevelCounts>, Self::Error> {
+        let _ = filter;
+        Ok(vec![])
+    }
+
+    fn fetch_peer(&self, pk: &[u8; 32]) -> Result<Option<peer::Details>, Self::Error> {
+        let _ = pk;
+        Ok(None)
+    }
+
+    fn fetch_bandwidth(
+        &self,
+        filter: &BandwidthFilter,
+    ) -> Result<Vec<bandwidth::Series>, Self::Error> {
+        let _ = filter;
+        Ok(vec![])
+    }
+
+    fn fetch_storage_stats(&self) -> Result<StorageStats, Self::Error> {
+        Ok(StorageStats::default())
+    }
+
+    fn store_annotation(&self, item: annotation::Item) -> Result<Option<u64>, Self::Error> {
+        let _ = item;
+        Ok(None)
+    }
+
+    fn fetch_annotations(
+        &self,
+        filter: &AnnotationsFilter,
+    ) -> Result<Vec<annotation::ItemWithId>, Self::Error> {
+        let _ = filter;
+        Ok(vec![])
+    }
+
+    fn store_preset(&self, name: &str, item: preset::Item) -> Result<bool, Self::Error> {
+        let _ = (name, item);
+        Ok(false)
+    }
+
+    fn fetch_presets(&self) -> Result<Vec<preset::ItemWithName>, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn fetch_preset(&self, name: &str) -> Result<Option<preset::Item>, Self::Error> {
+        let _ = name;
+        Ok(None)
+    }
+
+    fn remove_preset(&self, name: &str) -> Result<bool, Self::Error> {
+        let _ = name;
+        Ok(false)
+    }
+
+    fn store_incident(&self, id: Option<u64>, item: incident::Item) -> Result<u64, Self::Error> {
+        let _ = item;
+        Ok(id.unwrap_or(0))
+    }
+
+    fn fetch_incidents(
+        &self,
+        filter: &IncidentsFilter,
+    ) -> Result<Vec<incident::ItemWithId>, Self::Error> {
+        let _ = filter;
+        Ok(vec![])
+    }
+
+    fn store_gap(&self, id: Option<u64>, item: gap::Item) -> Result<u64, Self::Error> {
+        let _ = item;
+        Ok(id.unwrap_or(0))
+    }
+
+    fn fetch_gaps(&self, filter: &GapsFilter) -> Result<Vec<gap::ItemWithId>, Self::Error> {
+        let _ = filter;
+        Ok(vec![])
+    }
+
+    fn store_heartbeat(&self, timestamp: u64) -> Result<(), Self::Error> {
+        let _ = timestamp;
+        Ok(())
+    }
+
+    fn fetch_heartbeat(&self) -> Result<Option<u64>, Self::Error> {
+        Ok(None)
+    }
+
+    fn store_findings(&self, items: Vec<finding::Item>) -> Result<(), Self::Error> {
+        let _ = items;
+        Ok(())
+    }
+
+    fn fetch_findings(
+        &self,
+        filter: &FindingsFilter,
+    ) -> Result<Vec<finding::ItemWithId>, Self::Error> {
+        let _ = filter;
+        Ok(vec![])
+    }
+
+    fn start_session(&self, info: session::Info) -> Result<Option<u64>, Self::Error> {
+        let _ = info;
+        Ok(None)
+    }
+
+    fn stop_session(&self) -> Result<Option<u64>, Self::Error> {
+        Ok(None)
+    }
+
+    fn fetch_sessions(&self) -> Result<Vec<session::ItemWithId>, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn fetch_session_export(&self, id: u64) -> Result<Option<session::Export>, Self::Error> {
+        let _ = id;
+        Ok(None)
+    }
+
+    fn fetch_session_handshakes(
+        &self,
+        id: u64,
+    ) -> Result<Option<Vec<session::Handshake>>, Self::Error> {
+        let _ = id;
+        Ok(None)
+    }
+
+    fn fetch_conversation(
+        &self,
+        cn: &connection::Key,
+    ) -> Result<Option<Conversation>, Self::Error> {
+        let _ = cn;
+        Ok(None)
+    }
+
+    fn remove_session(&self, id: u64) -> Result<bool, Self::Error> {
+        let _ = id;
+        Ok(false)
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<(), Self::Error> {
+        let _ = path;
+        Ok(())
+    }
 }
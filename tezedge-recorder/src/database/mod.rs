@@ -4,36 +4,169 @@
 pub mod rocks;
 pub mod mock;
 pub mod search;
+pub mod remote;
+pub mod cache;
+pub mod tuning;
 
 mod sorted_intersect;
+mod batch;
+mod shards;
+mod delta;
+mod archive;
+mod recovery;
 
-use std::{error::Error, path::Path};
-use serde::Deserialize;
-use super::{tables::*, common};
+use std::{
+    error::Error,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+use super::{tables::*, common, conversation::Conversation};
+
+/// The database is synchronous, the async code runs the queries on the blocking pool of tokio,
+/// so a slow query does not stall the runtime threads serving the other requests
+pub async fn blocking<F, T>(f: F) -> Result<T, tokio::task::JoinError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await
+}
+
+/// Runs the background work of the database, off the write path
+pub async fn schedule_maintenance<Db>(db: Arc<Db>)
+where
+    Db: Database + Send + Sync + 'static,
+{
+    const PERIOD: Duration = Duration::from_secs(1);
+
+    let mut interval = tokio::time::interval(PERIOD);
+    loop {
+        interval.tick().await;
+        let db = db.clone();
+        if let Err(error) = blocking(move || db.maintain()).await {
+            log::error!("the maintenance of the database panicked: {}", error);
+            return;
+        }
+    }
+}
+
+/// Commits the batched writes of the database in time, even if no more writes come
+pub async fn schedule_commit<Db>(db: Arc<Db>)
+where
+    Db: Database + Send + Sync + 'static,
+{
+    // a half of the delay of the batch
+    const PERIOD: Duration = Duration::from_millis(50);
+
+    let mut interval = tokio::time::interval(PERIOD);
+    loop {
+        interval.tick().await;
+        let db = db.clone();
+        if let Err(error) = blocking(move || db.commit_pending()).await {
+            log::error!("committing the batch panicked: {}", error);
+            return;
+        }
+    }
+}
+
+/// The moment the query gives up, it returns what it found so far then,
+/// no deadline by default
+#[derive(Default)]
+pub struct Deadline {
+    at: Option<Instant>,
+    // the query was cut
+    expired: AtomicBool,
+}
+
+impl Clone for Deadline {
+    fn clone(&self) -> Self {
+        Deadline {
+            at: self.at,
+            expired: AtomicBool::new(self.expired()),
+        }
+    }
+}
+
+impl Deadline {
+    pub fn new(timeout: Duration) -> Self {
+        Deadline {
+            at: Some(Instant::now() + timeout),
+            expired: AtomicBool::new(false),
+        }
+    }
+
+    /// Checks the clock, the long loop calls it on each step and stops if it is true
+    pub fn check(&self) -> bool {
+        if self.expired() {
+            return true;
+        }
+        let expired = self.at.map_or(false, |at| Instant::now() >= at);
+        if expired {
+            self.expired.store(true, Ordering::Relaxed);
+        }
+        expired
+    }
+
+    /// Whether the query was cut, its result is partial
+    pub fn expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+}
 
 pub trait Database {
     fn store_connection(&self, item: connection::Item);
     fn update_connection(&self, item: connection::Item);
     fn store_chunk(&self, item: chunk::Item);
     fn store_message(&self, item: message::Item);
+    /// The message is not stored, it is skipped by the sampling, only its bytes are counted
+    fn count_message(&self, item: message::Item);
     fn store_log(&self, item: node_log::Item);
+    fn store_peer(&self, item: peer::Item);
+    /// Changes the limits at runtime, the records beyond a lowered limit are removed at once
+    fn set_store_limits(&self, message_store_limit: Option<u64>, log_store_limit: Option<u64>);
+    /// Commits the batched writes which wait long enough, the database which
+    /// does not batch the writes ignores it
+    fn commit_pending(&self) {}
+    /// Does the background work of the database, like the expiry of the old days,
+    /// the database which has none ignores it
+    fn maintain(&self) {}
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct ConnectionsFilter {
     pub limit: Option<u64>,
+    pub nack_motive: Option<connection::NackMotive>,
+    /// only the connections which ended so, like `reset`
+    pub termination: Option<connection::TerminationKind>,
+    /// only the connections whose handshake took longer, milliseconds
+    pub handshake_slower_than: Option<u64>,
+    /// only the connections after the one, like `1617005682.953928051`
+    pub after: Option<String>,
+    /// only the connections of the node to the other services, or only the peers
+    pub external: Option<bool>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct ChunksFilter {
     pub limit: Option<u64>,
     pub cn: Option<String>,
+    pub sender: Option<common::Sender>,
+    // compatibility
+    pub node_name: Option<String>,
 }
 
-#[derive(Deserialize, Default)]
+#[derive(Deserialize, Default, JsonSchema)]
 pub struct MessagesFilter {
     pub direction: Option<String>,
     pub limit: Option<u64>,
+    // the explorer calls it `cursor_id`
+    #[serde(alias = "cursor_id")]
     pub cursor: Option<u64>,
     pub remote_addr: Option<String>,
     pub source_type: Option<common::Initiator>,
@@ -42,20 +175,195 @@ pub struct MessagesFilter {
     pub from: Option<u64>,
     pub to: Option<u64>,
     pub timestamp: Option<u64>,
+    pub block_hash: Option<String>,
+    pub operation_hash: Option<String>,
+    pub protocol_hash: Option<String>,
+    /// comma separated fields of the message, like `id,timestamp,type,peer`, every field if absent,
+    /// the message is not decoded unless `message_preview` is among them
+    pub fields: Option<String>,
+    /// milliseconds, the query returns what it found until then, the server default if absent
+    pub timeout_ms: Option<u64>,
+    /// the name of the saved preset, the other arguments take precedence
+    pub preset: Option<String>,
+    #[serde(skip)]
+    pub deadline: Deadline,
     // compatibility
     pub node_name: Option<String>,
 }
 
-#[derive(Deserialize)]
+impl MessagesFilter {
+    /// Fills the arguments absent in the query
+    pub fn apply(&mut self, preset: &preset::Item) {
+        let preset = preset.clone();
+        self.remote_addr = self.remote_addr.take().or(preset.remote_addr);
+        self.source_type = self.source_type.take().or(preset.source_type);
+        self.incoming = self.incoming.or(preset.incoming);
+        self.types = self.types.take().or(preset.types);
+        self.block_hash = self.block_hash.take().or(preset.block_hash);
+        self.operation_hash = self.operation_hash.take().or(preset.operation_hash);
+        self.protocol_hash = self.protocol_hash.take().or(preset.protocol_hash);
+        self.fields = self.fields.take().or(preset.fields);
+        self.from = self.from.or(preset.from);
+        self.to = self.to.or(preset.to);
+    }
+
+    /// The projection of `fields`, `None` means every field
+    pub fn projection(&self) -> Result<Option<message::Projection>, String> {
+        message::Projection::parse(self.fields.as_deref())
+    }
+
+    fn preview(&self) -> Result<bool, String> {
+        Ok(self.projection()?.map_or(true, |p| p.contains("message_preview")))
+    }
+}
+
+#[derive(Deserialize, Default, Clone, JsonSchema)]
 pub struct LogsFilter {
     pub direction: Option<String>,
     pub limit: Option<u64>,
+    #[serde(alias = "cursor_id")]
     pub cursor: Option<u64>,
+    #[serde(alias = "level")]
     pub log_level: Option<String>,
+    pub module: Option<String>,
     pub from: Option<u64>,
     pub to: Option<u64>,
     pub timestamp: Option<u64>,
     pub query: Option<String>,
+    /// milliseconds, the query returns what it found until then, the server default if absent
+    pub timeout_ms: Option<u64>,
+    /// the name of the saved preset, the other arguments take precedence
+    pub preset: Option<String>,
+    #[serde(skip)]
+    pub deadline: Deadline,
+    // compatibility
+    pub node_name: Option<String>,
+}
+
+impl LogsFilter {
+    /// Fills the arguments absent in the query
+    pub fn apply(&mut self, preset: &preset::Item) {
+        let preset = preset.clone();
+        self.log_level = self.log_level.take().or(preset.log_level);
+        self.module = self.module.take().or(preset.module);
+        self.query = self.query.take().or(preset.query);
+        self.from = self.from.or(preset.from);
+        self.to = self.to.or(preset.to);
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct PeerFilter {
+    // compatibility
+    pub node_name: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct StorageStatsFilter {
+    // compatibility
+    pub node_name: Option<String>,
+}
+
+/// The `count` is estimated by rocksdb, the `total` is how many records was ever stored
+#[derive(Serialize, Default)]
+pub struct StoreStats {
+    pub count: u64,
+    pub total: u64,
+    pub limit: Option<u64>,
+    /// the records which were not stored, because their day is beyond the retention
+    pub dropped: u64,
+}
+
+#[derive(Serialize, Default)]
+pub struct StorageStats {
+    pub p2p: StoreStats,
+    pub log: StoreStats,
+    /// the in-memory cache of the recently queried messages
+    pub message_cache: cache::CacheStats,
+}
+
+impl StorageStats {
+    pub const CSV_COLUMNS: &'static [&'static str] = &[
+        "p2p.count",
+        "p2p.total",
+        "p2p.limit",
+        "p2p.dropped",
+        "log.count",
+        "log.total",
+        "log.limit",
+        "message_cache.capacity",
+        "message_cache.hits",
+        "message_cache.misses",
+        "message_cache.hit_rate",
+    ];
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct LogCountsFilter {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    /// the response is `{"data": [...], "gaps": [...]}`, with the intervals
+    /// when the capture missed the traffic
+    pub gaps: Option<bool>,
+    // compatibility
+    pub node_name: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct AnnotationsFilter {
+    pub message_id: Option<u64>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub label: Option<String>,
+    // compatibility
+    pub node_name: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct IncidentsFilter {
+    pub limit: Option<u64>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    /// only the incidents of the range, like `51.15.220.0/24`
+    pub range: Option<String>,
+    // compatibility
+    pub node_name: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct GapsFilter {
+    /// only the gaps which end after, unix milliseconds
+    pub from: Option<u64>,
+    /// only the gaps which begin before, unix milliseconds
+    pub to: Option<u64>,
+    // compatibility
+    pub node_name: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct FindingsFilter {
+    pub limit: Option<u64>,
+    /// only the findings of the connection, like `1617005682.953928051`
+    pub connection: Option<String>,
+    pub rule: Option<finding::Rule>,
+    // compatibility
+    pub node_name: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct BandwidthFilter {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub incoming: Option<bool>,
+    /// comma separated, like `current_head,block_header`, or `p2p` for every peer message
+    pub types: Option<String>,
+    pub remote_addr: Option<String>,
+    /// comma separated `direction`, `type` and `peer`, the series is the sum of the bytes
+    /// of the rest, `direction,type` by default
+    pub group_by: Option<String>,
+    /// the response is `{"data": [...], "gaps": [...]}`, with the intervals
+    /// when the capture missed the traffic
+    pub gaps: Option<bool>,
     // compatibility
     pub node_name: Option<String>,
 }
@@ -84,6 +392,104 @@ where
     fn fetch_message(&self, id: u64) -> Result<Option<message::MessageDetails>, Self::Error>;
 
     fn fetch_log(&self, filter: &LogsFilter) -> Result<Vec<node_log::ItemWithId>, Self::Error>;
+
+    fn fetch_log_counts(
+        &self,
+        filter: &LogCountsFilter,
+    ) -> Result<Vec<log_count::LevelCounts>, Self::Error>;
+
+    fn fetch_peer(&self, pk: &[u8; 32]) -> Result<Option<peer::Details>, Self::Error>;
+
+    /// The bytes per minute, one series per group
+    fn fetch_bandwidth(
+        &self,
+        filter: &BandwidthFilter,
+    ) -> Result<Vec<bandwidth::Series>, Self::Error>;
+
+    fn fetch_storage_stats(&self) -> Result<StorageStats, Self::Error>;
+
+    /// The annotations are written by the users through the api, not by the capture,
+    /// `None` if the annotated message does not exist
+    fn store_annotation(&self, item: annotation::Item) -> Result<Option<u64>, Self::Error>;
+
+    fn fetch_annotations(
+        &self,
+        filter: &AnnotationsFilter,
+    ) -> Result<Vec<annotation::ItemWithId>, Self::Error>;
+
+    /// The presets are written by the users through the api, `true` if it replaced the preset
+    fn store_preset(&self, name: &str, item: preset::Item) -> Result<bool, Self::Error>;
+
+    /// In the order of the name
+    fn fetch_presets(&self) -> Result<Vec<preset::ItemWithName>, Self::Error>;
+
+    fn fetch_preset(&self, name: &str) -> Result<Option<preset::Item>, Self::Error>;
+
+    /// `false` if there was no such preset
+    fn remove_preset(&self, name: &str) -> Result<bool, Self::Error>;
+
+    /// Stores the new incident if the `id` is `None`, otherwise replaces the incident,
+    /// it changes until the flood ends
+    fn store_incident(&self, id: Option<u64>, item: incident::Item) -> Result<u64, Self::Error>;
+
+    /// The latest first
+    fn fetch_incidents(
+        &self,
+        filter: &IncidentsFilter,
+    ) -> Result<Vec<incident::ItemWithId>, Self::Error>;
+
+    /// Stores the new gap if the `id` is `None`, otherwise replaces the gap,
+    /// it changes until the capture is back
+    fn store_gap(&self, id: Option<u64>, item: gap::Item) -> Result<u64, Self::Error>;
+
+    /// The oldest first
+    fn fetch_gaps(&self, filter: &GapsFilter) -> Result<Vec<gap::ItemWithId>, Self::Error>;
+
+    /// The recorder is alive at the time, unix milliseconds
+    fn store_heartbeat(&self, timestamp: u64) -> Result<(), Self::Error>;
+
+    /// The last time the recorder was alive, `None` if it never ran
+    fn fetch_heartbeat(&self) -> Result<Option<u64>, Self::Error>;
+
+    /// The violations of the p2p specification the conformance check found
+    fn store_findings(&self, items: Vec<finding::Item>) -> Result<(), Self::Error>;
+
+    /// The latest first
+    fn fetch_findings(
+        &self,
+        filter: &FindingsFilter,
+    ) -> Result<Vec<finding::ItemWithId>, Self::Error>;
+
+    /// Only one session runs at a time, `None` if some session is running already
+    fn start_session(&self, info: session::Info) -> Result<Option<u64>, Self::Error>;
+
+    /// The id of the stopped session, `None` if no session is running
+    fn stop_session(&self) -> Result<Option<u64>, Self::Error>;
+
+    fn fetch_sessions(&self) -> Result<Vec<session::ItemWithId>, Self::Error>;
+
+    fn fetch_session_export(&self, id: u64) -> Result<Option<session::Export>, Self::Error>;
+
+    /// The connections of the session with both connection messages recorded,
+    /// `None` if there is no such session
+    fn fetch_session_handshakes(
+        &self,
+        id: u64,
+    ) -> Result<Option<Vec<session::Handshake>>, Self::Error>;
+
+    /// The connection with its decrypted messages as the portable scenario,
+    /// `None` if there is no such connection
+    fn fetch_conversation(
+        &self,
+        cn: &connection::Key,
+    ) -> Result<Option<Conversation>, Self::Error>;
+
+    /// Removes the session with its messages and logs, `false` if there is no such session
+    fn remove_session(&self, id: u64) -> Result<bool, Self::Error>;
+
+    /// Creates the consistent copy of the database at the `path`, which must not exist,
+    /// the immutable files are hard linked, so it is cheap
+    fn checkpoint(&self, path: &Path) -> Result<(), Self::Error>;
 }
 
 pub trait DatabaseNew
@@ -97,7 +503,28 @@ where
         log_full_text_index: bool,
         log_store_limit: Option<u64>,
         message_store_limit: Option<u64>,
+        message_retention_days: Option<u64>,
+        message_cache: Option<usize>,
+        tuning: Option<tuning::RocksdbConfig>,
     ) -> Result<Self, Self::Error>
     where
         P: AsRef<Path>;
+
+    /// The decoders of the message details, the database which does not decode
+    /// the messages itself ignores them
+    fn set_decoders(&mut self, decoders: message::DecoderRegistry) {
+        let _ = decoders;
+    }
+
+    /// The followers of the primary receive the records it stores,
+    /// the database which does not store the records itself ignores it
+    fn set_feed(&mut self, feed: Arc<remote::replication::Feed>) {
+        let _ = feed;
+    }
+
+    /// The token the capture agent presents to the central recorder,
+    /// the database which stores the records itself ignores it
+    fn set_remote_token(&mut self, token: Option<String>) {
+        let _ = token;
+    }
 }
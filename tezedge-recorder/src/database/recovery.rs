@@ -0,0 +1,207 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{fs, io, path::{Path, PathBuf}};
+use rocksdb::{DB, IteratorMode, WriteBatch};
+use serde::Serialize;
+use storage::persistent::{
+    DBError, Decoder, KeyValueSchema,
+    database::RocksDbKeyValueSchema,
+};
+use super::{
+    connection, node_log, peer, annotation, session, incident, finding, preset, gap, message,
+    chunk,
+    shards::Shards,
+};
+
+/// The entries which failed to decode are moved here, the key is the name of their column family,
+/// zero and their key, the value is their value as is
+pub const QUARANTINE: &str = "quarantine_storage";
+
+// how many of the latest entries of each column family are checked
+const TAIL: usize = 0x1000;
+
+/// The file exists while the database is open, the database was not closed cleanly
+/// if it exists at the open
+pub struct Marker(PathBuf);
+
+impl Marker {
+    const FILE_NAME: &'static str = "open.lock";
+
+    /// Whether the previous run did not close the database
+    pub fn create(path: &Path) -> io::Result<(Self, bool)> {
+        let path = path.join(Self::FILE_NAME);
+        let unclean = path.exists();
+        fs::write(&path, std::process::id().to_string())?;
+        Ok((Marker(path), unclean))
+    }
+
+    pub fn remove(&self) {
+        if let Err(error) = fs::remove_file(&self.0) {
+            log::error!("failed to remove {}: {}", self.0.display(), error);
+        }
+    }
+}
+
+#[derive(Serialize, Default)]
+pub struct ColumnReport {
+    pub name: String,
+    pub checked: usize,
+    /// the unreadable entries at the very end, the writes torn by the crash
+    pub truncated: usize,
+    /// the unreadable entries before the readable ones
+    pub quarantined: usize,
+}
+
+#[derive(Serialize, Default)]
+pub struct Report {
+    pub columns: Vec<ColumnReport>,
+}
+
+impl Report {
+    pub fn damaged(&self) -> bool {
+        self.columns
+            .iter()
+            .any(|c| c.truncated != 0 || c.quarantined != 0)
+    }
+}
+
+fn decodes<S>(key: &[u8], value: &[u8]) -> bool
+where
+    S: KeyValueSchema,
+{
+    S::Key::decode(key).is_ok() && S::Value::decode(value).is_ok()
+}
+
+/// Checks the tail of each column family of the records, the secondary indexes are not checked,
+/// the queries skip the index pointing to the missing record anyway
+pub fn run(db: &DB, shards: &Shards) -> Result<Report, DBError> {
+    type Check = fn(&[u8], &[u8]) -> bool;
+
+    // whether the key grows with the time of the first write, so the last entries are the newest,
+    // the entry rewritten later, like the connection closed, is in the middle, so quarantined
+    let mut columns: Vec<(&str, Check, bool)> = vec![
+        (connection::Schema::name(), decodes::<connection::Schema>, true),
+        (node_log::Schema::name(), decodes::<node_log::Schema>, true),
+        (peer::Schema::name(), decodes::<peer::Schema>, false),
+        (annotation::Schema::name(), decodes::<annotation::Schema>, true),
+        (session::Schema::name(), decodes::<session::Schema>, true),
+        (incident::Schema::name(), decodes::<incident::Schema>, true),
+        (finding::Schema::name(), decodes::<finding::Schema>, true),
+        (preset::Schema::name(), decodes::<preset::Schema>, false),
+        (gap::Schema::name(), decodes::<gap::Schema>, true),
+    ];
+    for (message_name, chunk_name) in shards.names() {
+        columns.push((message_name, decodes::<message::Schema>, true));
+        // the key begins with the connection, the long connection writes after the newer ones
+        columns.push((chunk_name, decodes::<chunk::Schema>, false));
+    }
+
+    let quarantine = db
+        .cf_handle(QUARANTINE)
+        .ok_or(DBError::MissingColumnFamily { name: QUARANTINE })?;
+    let mut report = Report::default();
+    for (name, check, sequential) in columns {
+        let cf = match db.cf_handle(name) {
+            Some(cf) => cf,
+            None => continue,
+        };
+        let mut column = ColumnReport {
+            name: name.to_string(),
+            ..ColumnReport::default()
+        };
+        let mut batch = WriteBatch::default();
+        // the entries are visited from the end, until the first readable one they are torn
+        let mut torn = sequential;
+        for (key, value) in db.iterator_cf(cf, IteratorMode::End).take(TAIL) {
+            column.checked += 1;
+            if check(&key, &value) {
+                torn = false;
+                continue;
+            }
+            if torn {
+                column.truncated += 1;
+            } else {
+                let mut quarantine_key = name.as_bytes().to_vec();
+                quarantine_key.push(0);
+                quarantine_key.extend_from_slice(&key);
+                batch.put_cf(quarantine, quarantine_key, value);
+                column.quarantined += 1;
+            }
+            batch.delete_cf(cf, key);
+        }
+        db.write(batch)
+            .map_err(|error| DBError::RocksDBError { error })?;
+        report.columns.push(column);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use rocksdb::{DB, Options};
+    use storage::persistent::{Encoder, KeyValueSchema, database::RocksDbKeyValueSchema};
+    use super::{QUARANTINE, Shards, node_log, peer};
+
+    fn log(n: u64) -> Vec<u8> {
+        let item = node_log::Item {
+            level: node_log::LogLevel::Info,
+            timestamp: u128::from(n),
+            section: String::new(),
+            message: format!("message {}", n),
+        };
+        item.encode().unwrap()
+    }
+
+    #[test]
+    fn truncate_and_quarantine() {
+        let path = std::env::temp_dir().join(format!("recovery-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let logs = node_log::Schema::name();
+        let peers = peer::Schema::name();
+        let db = DB::open_cf(&opts, &path, &[logs, peers, QUARANTINE]).unwrap();
+        let key = |n: u64| <node_log::Schema as KeyValueSchema>::Key::encode(&n).unwrap();
+
+        let cf = db.cf_handle(logs).unwrap();
+        for n in 0..10 {
+            db.put_cf(cf, key(n), log(n)).unwrap();
+        }
+        // the garbage in the middle and the torn writes at the end
+        db.put_cf(cf, key(5), b"garbage").unwrap();
+        db.put_cf(cf, key(10), b"torn").unwrap();
+        db.put_cf(cf, key(11), b"torn").unwrap();
+        // the peers are not written in the order of the key, nothing is truncated
+        let cf = db.cf_handle(peers).unwrap();
+        db.put_cf(cf, [0xff; 32], b"garbage").unwrap();
+
+        let report = super::run(&db, &Shards::new(Some(1))).unwrap();
+        assert!(report.damaged());
+        let column = |name: &str| report.columns.iter().find(|c| c.name == name).unwrap();
+        let c = column(logs);
+        assert_eq!((c.checked, c.truncated, c.quarantined), (12, 2, 1));
+        let c = column(peers);
+        assert_eq!((c.checked, c.truncated, c.quarantined), (1, 0, 1));
+
+        let cf = db.cf_handle(logs).unwrap();
+        for n in 0..12 {
+            let expected = if n < 10 && n != 5 { Some(log(n)) } else { None };
+            assert_eq!(db.get_cf(cf, key(n)).unwrap(), expected);
+        }
+        let quarantine = db.cf_handle(QUARANTINE).unwrap();
+        let mut moved = logs.as_bytes().to_vec();
+        moved.push(0);
+        moved.extend_from_slice(&key(5));
+        assert_eq!(db.get_cf(quarantine, moved).unwrap(), Some(b"garbage".to_vec()));
+        assert_eq!(db.iterator_cf(quarantine, rocksdb::IteratorMode::Start).count(), 2);
+
+        // the second run finds nothing
+        let report = super::run(&db, &Shards::new(Some(1))).unwrap();
+        assert!(!report.damaged());
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
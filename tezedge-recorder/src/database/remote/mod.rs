@@ -0,0 +1,525 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! The capture agent does not store anything, it forwards the records to the central recorder,
+//! which owns the database, see `protocol` for the format. The follower debugger receives
+//! the records of the primary the same way, see `replication`.
+
+pub mod protocol;
+pub mod replication;
+
+use std::{
+    io::{self, Read, Write, BufReader, BufWriter},
+    net::{IpAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc,
+    },
+    thread,
+    time::Duration,
+};
+#[rustfmt::skip]
+use super::{
+    // core traits
+    Database, DatabaseNew, DatabaseFetch, StorageStats, tuning::RocksdbConfig,
+    // filters
+    ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter, LogCountsFilter,
+    AnnotationsFilter, IncidentsFilter, FindingsFilter, BandwidthFilter, GapsFilter,
+    // tables
+    connection, chunk, message, node_log, peer, annotation, session, incident, finding, preset,
+    gap,
+    // secondary indexes
+    log_count, bandwidth,
+    // the scenario
+    Conversation,
+};
+use self::protocol::{Record, MAX_FRAME_LENGTH};
+
+// records waiting while the connection to the central recorder is slow or lost
+const QUEUE_SIZE: usize = 0x10000;
+
+/// Forwards the records to the central recorder, the `db` in the config
+/// is the address of the central recorder instead of the path, e.g. `db = "10.0.0.2:17800"`.
+/// The records are queued and written by the background thread, so the capture never waits
+/// for the network, the records are dropped if the queue is full.
+pub struct Db {
+    tx: Mutex<mpsc::SyncSender<Vec<u8>>>,
+    dropped: Arc<AtomicU64>,
+    token: Arc<Mutex<Option<String>>>,
+}
+
+impl Db {
+    fn send(&self, record: Record) {
+        let frame = match record.encode() {
+            Ok(frame) => frame,
+            Err(error) => {
+                log::error!("failed to encode the record: {}", error);
+                return;
+            },
+        };
+        if self.tx.lock().unwrap().try_send(frame).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                log::warn!("remote database is behind, dropped {} records", dropped);
+            }
+        }
+    }
+}
+
+struct Forwarder {
+    address: String,
+    token: Arc<Mutex<Option<String>>>,
+    stream: Option<BufWriter<TcpStream>>,
+}
+
+impl Forwarder {
+    fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        let stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => {
+                let mut stream = TcpStream::connect(&self.address)?;
+                stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+                let token = self.token.lock().unwrap().clone();
+                let version = protocol::connect(&mut stream, token.as_deref())?;
+                log::info!(
+                    "connected to remote database: {}, protocol version: {}",
+                    self.address,
+                    version,
+                );
+                BufWriter::new(stream)
+            },
+        };
+        self.stream.get_or_insert(stream).write_all(frame)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stream {
+            Some(stream) => stream.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn run(mut self, rx: mpsc::Receiver<Vec<u8>>, dropped: Arc<AtomicU64>) {
+        while let Ok(frame) = rx.recv() {
+            let mut result = self.write(&frame);
+            // write everything queued meanwhile and flush once
+            while result.is_ok() {
+                match rx.try_recv() {
+                    Ok(frame) => result = self.write(&frame),
+                    Err(_) => break,
+                }
+            }
+            if let Err(error) = result.and_then(|()| self.flush()) {
+                log::error!("remote database {} error: {}", self.address, error);
+                dropped.fetch_add(1, Ordering::Relaxed);
+                self.stream = None;
+                // do not reconnect too often
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+impl DatabaseNew for Db {
+    type Error = io::Error;
+
+    fn open<P>(
+        path: P,
+        log_full_text_index: bool,
+        log_store_limit: Option<u64>,
+        message_store_limit: Option<u64>,
+        message_retention_days: Option<u64>,
+        message_cache: Option<usize>,
+        tuning: Option<RocksdbConfig>,
+    ) -> Result<Self, Self::Error>
+    where
+        P: AsRef<Path>,
+    {
+        let _ = (
+            log_full_text_index,
+            log_store_limit,
+            message_store_limit,
+            message_retention_days,
+            message_cache,
+            tuning,
+        );
+
+        let address = path
+            .as_ref()
+            .to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad remote address"))?
+            .to_string();
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let token = Arc::new(Mutex::new(None));
+        let forwarder = Forwarder {
+            address,
+            token: token.clone(),
+            stream: None,
+        };
+        let dropped_ = dropped.clone();
+        thread::Builder::new()
+            .name("remote-db".to_string())
+            .spawn(move || forwarder.run(rx, dropped_))?;
+
+        Ok(Db {
+            tx: Mutex::new(tx),
+            dropped,
+            token,
+        })
+    }
+
+    fn set_remote_token(&mut self, token: Option<String>) {
+        *self.token.lock().unwrap() = token;
+    }
+}
+
+impl Database for Db {
+    fn store_connection(&self, item: connection::Item) {
+        self.send(Record::Connection(item))
+    }
+
+    fn update_connection(&self, item: connection::Item) {
+        self.send(Record::UpdateConnection(item))
+    }
+
+    fn store_chunk(&self, item: chunk::Item) {
+        self.send(Record::Chunk(item))
+    }
+
+    fn store_message(&self, item: message::Item) {
+        self.send(Record::Message(item))
+    }
+
+    fn count_message(&self, item: message::Item) {
+        self.send(Record::Sampled(item))
+    }
+
+    fn store_log(&self, item: node_log::Item) {
+        self.send(Record::Log(item))
+    }
+
+    fn store_peer(&self, item: peer::Item) {
+        self.send(Record::Peer(item))
+    }
+
+    fn set_store_limits(&self, message_store_limit: Option<u64>, log_store_limit: Option<u64>) {
+        // the central recorder applies its own limits
+        let _ = (message_store_limit, log_store_limit);
+    }
+}
+
+fn not_stored() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "the capture agent does not store records, query the central recorder",
+    )
+}
+
+impl DatabaseFetch for Db {
+    fn fetch_connections(
+        &self,
+        filter: &ConnectionsFilter,
+    ) -> Result<Vec<(connection::Key, connection::Value)>, Self::Error> {
+        let _ = filter;
+        Err(not_stored())
+    }
+
+    fn fetch_chunks_truncated(
+        &self,
+        filter: &ChunksFilter,
+    ) -> Result<Vec<(chunk::Key, chunk::ValueTruncated)>, Self::Error> {
+        let _ = filter;
+        Err(not_stored())
+    }
+
+    fn fetch_chunk(&self, key: &chunk::Key) -> Result<Option<chunk::Value>, Self::Error> {
+        let _ = key;
+        Err(not_stored())
+    }
+
+    fn fetch_messages(
+        &self,
+        filter: &MessagesFilter,
+    ) -> Result<Vec<message::MessageFrontend>, Self::Error> {
+        let _ = filter;
+        Err(not_stored())
+    }
+
+    fn fetch_message(&self, id: u64) -> Result<Option<message::MessageDetails>, Self::Error> {
+        let _ = id;
+        Err(not_stored())
+    }
+
+    fn fetch_log(&self, filter: &LogsFilter) -> Result<Vec<node_log::ItemWithId>, Self::Error> {
+        let _ = filter;
+        Err(not_stored())
+    }
+
+    fn fetch_log_counts(
+        &self,
+        filter: &LogCountsFilter,
+    ) -> Result<Vec<log_count::LevelCounts>, Self::Error> {
+        let _ = filter;
+        Err(not_stored())
+    }
+
+    fn fetch_peer(&self, pk: &[u8; 32]) -> Result<Option<peer::Details>, Self::Error> {
+        let _ = pk;
+        Err(not_stored())
+    }
+
+    fn fetch_bandwidth(
+        &self,
+        filter: &BandwidthFilter,
+    ) -> Result<Vec<bandwidth::Series>, Self::Error> {
+        let _ = filter;
+        Err(not_stored())
+    }
+
+    fn fetch_storage_stats(&self) -> Result<StorageStats, Self::Error> {
+        Err(not_stored())
+    }
+
+    fn store_annotation(&self, item: annotation::Item) -> Result<Option<u64>, Self::Error> {
+        let _ = item;
+        Err(not_stored())
+    }
+
+    fn fetch_annotations(
+        &self,
+        filter: &AnnotationsFilter,
+    ) -> Result<Vec<annotation::ItemWithId>, Self::Error> {
+        let _ = filter;
+        Err(not_stored())
+    }
+
+    fn store_preset(&self, name: &str, item: preset::Item) -> Result<bool, Self::Error> {
+        let _ = (name, item);
+        Err(not_stored())
+    }
+
+    fn fetch_presets(&self) -> Result<Vec<preset::ItemWithName>, Self::Error> {
+        Err(not_stored())
+    }
+
+    fn fetch_preset(&self, name: &str) -> Result<Option<preset::Item>, Self::Error> {
+        let _ = name;
+        Err(not_stored())
+    }
+
+    fn remove_preset(&self, name: &str) -> Result<bool, Self::Error> {
+        let _ = name;
+        Err(not_stored())
+    }
+
+    fn store_incident(&self, id: Option<u64>, item: incident::Item) -> Result<u64, Self::Error> {
+        let _ = (id, item);
+        Err(not_stored())
+    }
+
+    fn fetch_incidents(
+        &self,
+        filter: &IncidentsFilter,
+    ) -> Result<Vec<incident::ItemWithId>, Self::Error> {
+        let _ = filter;
+        Err(not_stored())
+    }
+
+    fn store_gap(&self, id: Option<u64>, item: gap::Item) -> Result<u64, Self::Error> {
+        let _ = (id, item);
+        Err(not_stored())
+    }
+
+    fn fetch_gaps(&self, filter: &GapsFilter) -> Result<Vec<gap::ItemWithId>, Self::Error> {
+        let _ = filter;
+        Err(not_stored())
+    }
+
+    fn store_heartbeat(&self, timestamp: u64) -> Result<(), Self::Error> {
+        let _ = timestamp;
+        Err(not_stored())
+    }
+
+    fn fetch_heartbeat(&self) -> Result<Option<u64>, Self::Error> {
+        Err(not_stored())
+    }
+
+    fn store_findings(&self, items: Vec<finding::Item>) -> Result<(), Self::Error> {
+        let _ = items;
+        Err(not_stored())
+    }
+
+    fn fetch_findings(
+        &self,
+        filter: &FindingsFilter,
+    ) -> Result<Vec<finding::ItemWithId>, Self::Error> {
+        let _ = filter;
+        Err(not_stored())
+    }
+
+    fn start_session(&self, info: session::Info) -> Result<Option<u64>, Self::Error> {
+        let _ = info;
+        Err(not_stored())
+    }
+
+    fn stop_session(&self) -> Result<Option<u64>, Self::Error> {
+        Err(not_stored())
+    }
+
+    fn fetch_sessions(&self) -> Result<Vec<session::ItemWithId>, Self::Error> {
+        Err(not_stored())
+    }
+
+    fn fetch_session_export(&self, id: u64) -> Result<Option<session::Export>, Self::Error> {
+        let _ = id;
+        Err(not_stored())
+    }
+
+    fn fetch_session_handshakes(
+        &self,
+        id: u64,
+    ) -> Result<Option<Vec<session::Handshake>>, Self::Error> {
+        let _ = id;
+        Err(not_stored())
+    }
+
+    fn fetch_conversation(
+        &self,
+        cn: &connection::Key,
+    ) -> Result<Option<Conversation>, Self::Error> {
+        let _ = cn;
+        Err(not_stored())
+    }
+
+    fn remove_session(&self, id: u64) -> Result<bool, Self::Error> {
+        let _ = id;
+        Err(not_stored())
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<(), Self::Error> {
+        let _ = path;
+        Err(not_stored())
+    }
+}
+
+/// Accepts capture agents on the address and stores the records they forward,
+/// the agent must present the `token` if it is not `None`
+pub fn spawn_receiver<Db>(
+    address: IpAddr,
+    port: u16,
+    token: Option<String>,
+    db: Arc<Db>,
+    running: Arc<AtomicBool>,
+) -> io::Result<thread::JoinHandle<()>>
+where
+    Db: Database + Sync + Send + 'static,
+{
+    if token.is_none() && !address.is_loopback() {
+        log::warn!("capture agents are accepted on {}:{} without a token", address, port);
+    }
+    let token = Arc::new(token);
+    let listener = TcpListener::bind((address, port))?;
+    listener.set_nonblocking(true)?;
+    Ok(thread::spawn(move || {
+        while running.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, address)) => {
+                    log::info!("capture agent connected: {}", address);
+                    let db = db.clone();
+                    let running = running.clone();
+                    let token = token.clone();
+                    thread::spawn(move || {
+                        let result = stream
+                            .set_nonblocking(false)
+                            .and_then(|()| stream.set_read_timeout(Some(Duration::from_secs(5))));
+                        match result {
+                            Ok(()) => receive(stream, token.as_deref(), db.as_ref(), &running),
+                            Err(error) => {
+                                log::error!("failed to setup capture agent socket: {}", error)
+                            },
+                        }
+                        log::info!("capture agent disconnected: {}", address);
+                    });
+                },
+                Err(error) => {
+                    if error.kind() == io::ErrorKind::WouldBlock {
+                        thread::sleep(Duration::from_millis(100));
+                    } else {
+                        log::error!("accepting capture agent error: {}", error)
+                    }
+                },
+            }
+        }
+    }))
+}
+
+fn receive<Db>(mut stream: TcpStream, token: Option<&str>, db: &Db, running: &AtomicBool)
+where
+    Db: Database,
+{
+    // the handshake reads exactly what it needs, so the frames are not buffered yet
+    let version = match protocol::accept(&mut stream, token) {
+        Ok(version) => version,
+        Err(error) => {
+            log::error!("capture agent handshake error: {}", error);
+            return;
+        },
+    };
+    log::info!("capture agent protocol version: {}", version);
+    receive_frames(BufReader::new(stream), db, running, "capture agent");
+}
+
+/// Stores the records until the `peer` closes the connection, the number of the frames received
+fn receive_frames<Db>(
+    mut stream: BufReader<TcpStream>,
+    db: &Db,
+    running: &AtomicBool,
+    peer: &str,
+) -> u64
+where
+    Db: Database,
+{
+    let mut length = [0; 4];
+    let mut filled = 0;
+    let mut received = 0;
+    while running.load(Ordering::Relaxed) {
+        // the agent might be idle, so the timeout is fine while waiting for the length
+        match stream.read(&mut length[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(error) => match error.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => (),
+                io::ErrorKind::Interrupted => (),
+                _ => {
+                    log::error!("receiving record error: {}", error);
+                    break;
+                },
+            },
+        }
+        if filled < length.len() {
+            continue;
+        }
+        filled = 0;
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_FRAME_LENGTH {
+            log::error!("{} sent too big frame: {}", peer, length);
+            break;
+        }
+        let mut frame = vec![0; length];
+        // the rest of the frame must follow shortly, timeout is an error
+        if let Err(error) = stream.read_exact(&mut frame) {
+            log::error!("receiving record error: {}", error);
+            break;
+        }
+        received += 1;
+        match Record::decode(&frame) {
+            Ok(Some(record)) => record.store(db),
+            Ok(None) => log::debug!("{} sent unknown record: {}", peer, frame[0]),
+            Err(error) => log::error!("{} sent bad record: {}", peer, error),
+        }
+    }
+    received
+}
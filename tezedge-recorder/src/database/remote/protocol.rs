@@ -0,0 +1,477 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! The wire protocol between the capture agent and the central recorder.
+//!
+//! The agent opens the connection by the header: the magic `TZDR` and the newest protocol version
+//! it speaks (2 bytes little endian). The recorder answers with the version both of them speak
+//! (2 bytes little endian), or zero, if it does not speak any version the agent does,
+//! and closes the connection. So the agent and the recorder can be upgraded independently.
+//!
+//! Since the version 2 the agent sends the token after the reply: the length (2 bytes little
+//! endian) and the bytes, empty if the agent has no token. The recorder answers with one byte,
+//! 1 if the token matches the one in its config, or there is no token in its config,
+//! otherwise 0, and closes the connection. The recorder which requires the token refuses
+//! the agent speaking the version 1.
+//!
+//! Then the agent sends frames: the length (4 bytes little endian, not including itself),
+//! the tag (1 byte) and the record encoded the same way as it is stored in the database,
+//! messages, logs and peers are bincode, connections and chunks are the key and the value,
+//! the key is prefixed by its length (4 bytes little endian).
+//! The recorder skips frames with an unknown tag, so a newer agent may add record kinds.
+
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+};
+use storage::persistent::{Encoder, Decoder, SchemaError};
+use super::super::{Database, connection, chunk, message, node_log, peer};
+
+pub const MAGIC: [u8; 4] = *b"TZDR";
+
+/// The newest version, the agent and the recorder speak every version since `MIN_VERSION`
+pub const VERSION: u16 = 2;
+pub const MIN_VERSION: u16 = 1;
+/// The first version which sends the token
+pub const TOKEN_VERSION: u16 = 2;
+
+pub const MAX_TOKEN_LENGTH: usize = 0x100;
+
+// a chunk is at most 64 KiB, anything much bigger is garbage
+pub const MAX_FRAME_LENGTH: usize = 0x100000;
+
+pub fn write_header<W>(stream: &mut W) -> io::Result<()>
+where
+    W: Write,
+{
+    stream.write_all(&MAGIC)?;
+    stream.write_all(&VERSION.to_le_bytes())?;
+    stream.flush()
+}
+
+/// The newest version the agent speaks
+pub fn read_header<R>(stream: &mut R) -> io::Result<u16>
+where
+    R: Read,
+{
+    let mut header = [0; 6];
+    stream.read_exact(&mut header)?;
+    if header[..4] != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a capture agent",
+        ));
+    }
+    Ok(u16::from_le_bytes([header[4], header[5]]))
+}
+
+/// The version both sides speak, if any, the version without the token is refused
+/// if the token is required
+pub fn negotiate(agent_version: u16, token_required: bool) -> Option<u16> {
+    if agent_version < MIN_VERSION || (token_required && agent_version < TOKEN_VERSION) {
+        None
+    } else {
+        Some(agent_version.min(VERSION))
+    }
+}
+
+pub fn write_reply<W>(stream: &mut W, version: Option<u16>) -> io::Result<()>
+where
+    W: Write,
+{
+    stream.write_all(&version.unwrap_or(0).to_le_bytes())?;
+    stream.flush()
+}
+
+pub fn read_reply<R>(stream: &mut R) -> io::Result<u16>
+where
+    R: Read,
+{
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    match u16::from_le_bytes(reply) {
+        version if (MIN_VERSION..=VERSION).contains(&version) => Ok(version),
+        version => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the recorder does not speak protocol versions {}..={}, answered {}",
+                MIN_VERSION, VERSION, version,
+            ),
+        )),
+    }
+}
+
+pub fn write_token<W>(stream: &mut W, token: &str) -> io::Result<()>
+where
+    W: Write,
+{
+    if token.len() > MAX_TOKEN_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the token is too long"));
+    }
+    stream.write_all(&(token.len() as u16).to_le_bytes())?;
+    stream.write_all(token.as_bytes())?;
+    stream.flush()
+}
+
+pub fn read_token<R>(stream: &mut R) -> io::Result<Vec<u8>>
+where
+    R: Read,
+{
+    let mut length = [0; 2];
+    stream.read_exact(&mut length)?;
+    let length = u16::from_le_bytes(length) as usize;
+    if length > MAX_TOKEN_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the token is too long"));
+    }
+    let mut token = vec![0; length];
+    stream.read_exact(&mut token)?;
+    Ok(token)
+}
+
+/// Any token matches if the `expected` is `None`
+pub fn token_matches(expected: Option<&str>, given: &[u8]) -> bool {
+    let expected = match expected {
+        Some(expected) => expected.as_bytes(),
+        None => return true,
+    };
+    // the comparison takes the same time wherever the first difference is
+    given.len() == expected.len()
+        && given.iter().zip(expected).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+}
+
+/// The agent side of the handshake, the version both sides speak
+pub fn connect<S>(stream: &mut S, token: Option<&str>) -> io::Result<u16>
+where
+    S: Read + Write,
+{
+    write_header(stream)?;
+    let version = read_reply(stream)?;
+    if version >= TOKEN_VERSION {
+        write_token(stream, token.unwrap_or(""))?;
+        let mut verdict = [0];
+        stream.read_exact(&mut verdict)?;
+        if verdict[0] != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the recorder refused the token",
+            ));
+        }
+    }
+    Ok(version)
+}
+
+/// The recorder side of the handshake, the version both sides speak,
+/// the agent must present the `token` if it is not `None`
+pub fn accept<S>(stream: &mut S, token: Option<&str>) -> io::Result<u16>
+where
+    S: Read + Write,
+{
+    let agent_version = read_header(stream)?;
+    let version = negotiate(agent_version, token.is_some());
+    write_reply(stream, version)?;
+    let version = version.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("incompatible protocol version: {}", agent_version),
+        )
+    })?;
+    if version >= TOKEN_VERSION {
+        let given = read_token(stream)?;
+        let matches = token_matches(token, &given);
+        stream.write_all(&[matches as u8])?;
+        stream.flush()?;
+        if !matches {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "bad token"));
+        }
+    }
+    Ok(version)
+}
+
+pub enum Record {
+    Connection(connection::Item),
+    UpdateConnection(connection::Item),
+    Chunk(chunk::Item),
+    Message(message::Item),
+    Log(node_log::Item),
+    Peer(peer::Item),
+    // the message skipped by the sampling, the older recorder drops the record
+    Sampled(message::Item),
+}
+
+fn encode_pair<K, V>(v: &mut Vec<u8>, key: &K, value: &V) -> Result<(), SchemaError>
+where
+    K: Encoder,
+    V: Encoder,
+{
+    let key = key.encode()?;
+    v.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    v.extend_from_slice(&key);
+    v.extend_from_slice(&value.encode()?);
+    Ok(())
+}
+
+fn decode_pair<K, V>(bytes: &[u8]) -> Result<(K, V), SchemaError>
+where
+    K: Decoder,
+    V: Decoder,
+{
+    if bytes.len() < 4 {
+        return Err(SchemaError::DecodeError);
+    }
+    let key_len = u32::from_le_bytes(TryFrom::try_from(&bytes[..4]).unwrap()) as usize;
+    if bytes.len() < 4 + key_len {
+        return Err(SchemaError::DecodeError);
+    }
+    let key = K::decode(&bytes[4..(4 + key_len)])?;
+    let value = V::decode(&bytes[(4 + key_len)..])?;
+    Ok((key, value))
+}
+
+impl Record {
+    const CONNECTION: u8 = 0;
+    const UPDATE_CONNECTION: u8 = 1;
+    const CHUNK: u8 = 2;
+    const MESSAGE: u8 = 3;
+    const LOG: u8 = 4;
+    const PEER: u8 = 5;
+    const SAMPLED: u8 = 6;
+
+    /// The frame including the length
+    pub fn encode(self) -> Result<Vec<u8>, SchemaError> {
+        // the length is filled at the end
+        let mut v = vec![0; 4];
+        match self {
+            Record::Connection(item) => {
+                v.push(Self::CONNECTION);
+                let (key, value) = item.split();
+                encode_pair(&mut v, &key, &value)?;
+            },
+            Record::UpdateConnection(item) => {
+                v.push(Self::UPDATE_CONNECTION);
+                let (key, value) = item.split();
+                encode_pair(&mut v, &key, &value)?;
+            },
+            Record::Chunk(item) => {
+                v.push(Self::CHUNK);
+                let (key, value) = item.split();
+                encode_pair(&mut v, &key, &value)?;
+            },
+            Record::Message(item) => {
+                v.push(Self::MESSAGE);
+                v.extend_from_slice(&item.encode()?);
+            },
+            Record::Log(item) => {
+                v.push(Self::LOG);
+                v.extend_from_slice(&item.encode()?);
+            },
+            Record::Peer(item) => {
+                v.push(Self::PEER);
+                v.extend_from_slice(&item.encode()?);
+            },
+            Record::Sampled(item) => {
+                v.push(Self::SAMPLED);
+                v.extend_from_slice(&item.encode()?);
+            },
+        }
+        // the length does not include itself
+        let length = (v.len() - 4) as u32;
+        v[..4].clone_from_slice(&length.to_le_bytes());
+        Ok(v)
+    }
+
+    /// The frame without the length, `None` if the tag is unknown
+    pub fn decode(bytes: &[u8]) -> Result<Option<Self>, SchemaError> {
+        let (tag, bytes) = bytes.split_first().ok_or(SchemaError::DecodeError)?;
+        let record = match *tag {
+            Self::CONNECTION => {
+                let (key, value) = decode_pair(bytes)?;
+                Record::Connection(connection::Item::unite(key, value))
+            },
+            Self::UPDATE_CONNECTION => {
+                let (key, value) = decode_pair(bytes)?;
+                Record::UpdateConnection(connection::Item::unite(key, value))
+            },
+            Self::CHUNK => {
+                let (key, value) = decode_pair(bytes)?;
+                Record::Chunk(chunk::Item::unite(key, value))
+            },
+            Self::MESSAGE => Record::Message(message::Item::decode(bytes)?),
+            Self::LOG => Record::Log(node_log::Item::decode(bytes)?),
+            Self::PEER => Record::Peer(peer::Item::decode(bytes)?),
+            Self::SAMPLED => Record::Sampled(message::Item::decode(bytes)?),
+            _ => return Ok(None),
+        };
+        Ok(Some(record))
+    }
+
+    pub fn store<Db>(self, db: &Db)
+    where
+        Db: Database,
+    {
+        match self {
+            Record::Connection(item) => db.store_connection(item),
+            Record::UpdateConnection(item) => db.update_connection(item),
+            Record::Chunk(item) => db.store_chunk(item),
+            Record::Message(item) => db.store_message(item),
+            Record::Log(item) => db.store_log(item),
+            Record::Peer(item) => db.store_peer(item),
+            Record::Sampled(item) => db.count_message(item),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{self, Cursor},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+    use bytes::Bytes;
+    use super::{
+        Record, connection, chunk, node_log, MAGIC, VERSION, MIN_VERSION, TOKEN_VERSION,
+        MAX_TOKEN_LENGTH,
+    };
+    use crate::common::{Initiator, Sender};
+
+    type Results = (io::Result<u16>, io::Result<u16>);
+
+    fn handshake(agent: Option<&str>, recorder: Option<&'static str>) -> Results {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let recorder = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            super::accept(&mut stream, recorder)
+        });
+        let mut stream = TcpStream::connect(address).unwrap();
+        let agent = super::connect(&mut stream, agent);
+        (agent, recorder.join().unwrap())
+    }
+
+    #[test]
+    fn header() {
+        let mut v = vec![];
+        super::write_header(&mut v).unwrap();
+        assert_eq!(&v[..4], &MAGIC);
+        assert_eq!(super::read_header(&mut Cursor::new(v)).unwrap(), VERSION);
+
+        let error = super::read_header(&mut Cursor::new(b"HTTP/1".to_vec())).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(super::negotiate(0, false), None);
+        assert_eq!(super::negotiate(MIN_VERSION, false), Some(MIN_VERSION));
+        assert_eq!(super::negotiate(VERSION, false), Some(VERSION));
+        assert_eq!(super::negotiate(VERSION + 1, false), Some(VERSION));
+        // the version which does not send the token is refused if the token is required
+        assert_eq!(super::negotiate(TOKEN_VERSION - 1, true), None);
+        assert_eq!(super::negotiate(TOKEN_VERSION, true), Some(TOKEN_VERSION));
+    }
+
+    #[test]
+    fn reply() {
+        for version in MIN_VERSION..=VERSION {
+            let mut v = vec![];
+            super::write_reply(&mut v, Some(version)).unwrap();
+            assert_eq!(super::read_reply(&mut Cursor::new(v)).unwrap(), version);
+        }
+        let mut v = vec![];
+        super::write_reply(&mut v, None).unwrap();
+        assert!(super::read_reply(&mut Cursor::new(v)).is_err());
+        let v = (VERSION + 1).to_le_bytes().to_vec();
+        assert!(super::read_reply(&mut Cursor::new(v)).is_err());
+    }
+
+    #[test]
+    fn token() {
+        let mut v = vec![];
+        super::write_token(&mut v, "secret").unwrap();
+        assert_eq!(super::read_token(&mut Cursor::new(v)).unwrap(), b"secret");
+
+        let long = "x".repeat(MAX_TOKEN_LENGTH + 1);
+        assert!(super::write_token(&mut vec![], &long).is_err());
+        let mut v = (long.len() as u16).to_le_bytes().to_vec();
+        v.extend_from_slice(long.as_bytes());
+        assert!(super::read_token(&mut Cursor::new(v)).is_err());
+
+        assert!(super::token_matches(None, b""));
+        assert!(super::token_matches(Some("secret"), b"secret"));
+        assert!(!super::token_matches(Some("secret"), b"secreT"));
+        assert!(!super::token_matches(Some("secret"), b"secret1"));
+        assert!(!super::token_matches(Some("secret"), b""));
+    }
+
+    #[test]
+    fn handshake_token() {
+        let (agent, recorder) = handshake(None, None);
+        assert_eq!(agent.unwrap(), VERSION);
+        assert_eq!(recorder.unwrap(), VERSION);
+
+        let (agent, recorder) = handshake(Some("secret"), Some("secret"));
+        assert_eq!(agent.unwrap(), VERSION);
+        assert_eq!(recorder.unwrap(), VERSION);
+
+        let (agent, recorder) = handshake(Some("wrong"), Some("secret"));
+        assert_eq!(agent.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(recorder.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        let (agent, recorder) = handshake(None, Some("secret"));
+        assert!(agent.is_err());
+        assert!(recorder.is_err());
+    }
+
+    #[test]
+    fn handshake_old_agent() {
+        // the agent of the version 1 sends no token
+        let mut input = MAGIC.to_vec();
+        input.extend_from_slice(&1u16.to_le_bytes());
+
+        let mut stream = Cursor::new(input.clone());
+        assert_eq!(super::accept(&mut stream, None).unwrap(), 1);
+
+        let mut stream = Cursor::new(input);
+        assert!(super::accept(&mut stream, Some("secret")).is_err());
+    }
+
+    // the record survives the encoding if it encodes to the same frame again
+    fn round_trip(record: Record) {
+        let frame = record.encode().unwrap();
+        let length = u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]) as usize;
+        assert_eq!(length, frame.len() - 4);
+        let decoded = Record::decode(&frame[4..]).unwrap().unwrap();
+        assert_eq!(decoded.encode().unwrap(), frame);
+    }
+
+    #[test]
+    fn records() {
+        let cn = connection::Item::new(Initiator::Local, "127.0.0.1:9732".parse().unwrap());
+        let cn_id = cn.key();
+        round_trip(Record::Connection(cn.clone()));
+        round_trip(Record::UpdateConnection(cn));
+        let bytes = Bytes::from_static(&[1, 2, 3, 4]);
+        let plain = Bytes::from_static(&[5, 6]);
+        round_trip(Record::Chunk(chunk::Item::new(cn_id, Sender::Remote, 7, bytes, plain)));
+        round_trip(Record::Log(node_log::Item {
+            level: node_log::LogLevel::Warning,
+            timestamp: 1_600_000_000_000_000_000,
+            section: "p2p".to_string(),
+            message: "peer disconnected".to_string(),
+        }));
+    }
+
+    #[test]
+    fn malformed_records() {
+        // the newer agent may add a record kind
+        assert!(Record::decode(&[0xff, 1, 2, 3]).unwrap().is_none());
+        assert!(Record::decode(&[]).is_err());
+        // the key is longer than the frame
+        let mut frame = vec![Record::CONNECTION];
+        frame.extend_from_slice(&100u32.to_le_bytes());
+        frame.extend_from_slice(&[0; 10]);
+        assert!(Record::decode(&frame).is_err());
+        assert!(Record::decode(&[Record::CHUNK, 0, 0]).is_err());
+        assert!(Record::decode(&[Record::LOG, 1]).is_err());
+    }
+}
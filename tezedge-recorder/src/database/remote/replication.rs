@@ -0,0 +1,445 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! The follower debugger tails the records the primary debugger stores and serves the read
+//! queries, so the heavy queries never slow down the capture. The follower connects to
+//! the `replication_port` of the primary and opens the connection by the same header and token
+//! as the capture agent does, then asks for the records from where it stopped, see `Resume`,
+//! then the primary sends the frames, see `protocol`.
+
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, TrySendError, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use super::{
+    Database, QUEUE_SIZE, receive_frames,
+    protocol::{self, Record},
+};
+
+/// The record the follower expects next, the primary numbers the records it publishes,
+/// the `epoch` tells apart the runs of the primary, zero if the follower has nothing yet
+// * bytes layout: `[epoch(8)][next(8)]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Resume {
+    epoch: u64,
+    next: u64,
+}
+
+impl Resume {
+    const SIZE: usize = 16;
+
+    fn write<W>(&self, stream: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let mut b = [0; Self::SIZE];
+        b[..8].copy_from_slice(&self.epoch.to_le_bytes());
+        b[8..].copy_from_slice(&self.next.to_le_bytes());
+        stream.write_all(&b)?;
+        stream.flush()
+    }
+
+    fn read<R>(stream: &mut R) -> io::Result<Self>
+    where
+        R: Read,
+    {
+        let mut b = [0; Self::SIZE];
+        stream.read_exact(&mut b)?;
+        let le64 = |b: &[u8]| u64::from_le_bytes(TryFrom::try_from(b).unwrap());
+        Ok(Resume {
+            epoch: le64(&b[..8]),
+            next: le64(&b[8..]),
+        })
+    }
+}
+
+/// The recent frames, the follower which reconnects gets those it missed
+struct Backlog {
+    capacity: usize,
+    // the number of the first kept frame
+    first: u64,
+    frames: VecDeque<Vec<u8>>,
+    size: usize,
+}
+
+impl Backlog {
+    fn new(capacity: usize) -> Self {
+        Backlog {
+            capacity,
+            first: 0,
+            frames: VecDeque::new(),
+            size: 0,
+        }
+    }
+
+    fn next(&self) -> u64 {
+        self.first + self.frames.len() as u64
+    }
+
+    fn push(&mut self, frame: Vec<u8>) {
+        self.size += frame.len();
+        self.frames.push_back(frame);
+        while self.size > self.capacity {
+            match self.frames.pop_front() {
+                Some(frame) => {
+                    self.size -= frame.len();
+                    self.first += 1;
+                },
+                None => break,
+            }
+        }
+    }
+
+    /// The number of the first frame and the frames from `next`,
+    /// from the first kept frame if those before are gone
+    fn since(&self, next: u64) -> (u64, Vec<Vec<u8>>) {
+        let first = next.max(self.first).min(self.next());
+        let skip = (first - self.first) as usize;
+        (first, self.frames.iter().skip(skip).cloned().collect())
+    }
+}
+
+struct Follower {
+    address: SocketAddr,
+    tx: mpsc::SyncSender<Vec<u8>>,
+}
+
+struct FeedInner {
+    followers: Vec<Follower>,
+    // nothing is kept until the first follower connects
+    backlog: Option<Backlog>,
+}
+
+/// The records the primary stores, copied to the connected followers,
+/// the capture never waits for them. The follower which is behind is disconnected,
+/// it reconnects and gets the records it missed from the backlog
+pub struct Feed {
+    epoch: u64,
+    inner: Mutex<FeedInner>,
+}
+
+impl Default for Feed {
+    fn default() -> Self {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        Feed {
+            epoch: epoch.max(1),
+            inner: Mutex::new(FeedInner {
+                followers: vec![],
+                backlog: None,
+            }),
+        }
+    }
+}
+
+impl Feed {
+    // the bytes of the recent frames kept for the followers which reconnect
+    const BACKLOG_SIZE: usize = 0x4000000;
+
+    /// The record is built and encoded only if some follower has connected
+    pub fn publish<F>(&self, record: F)
+    where
+        F: FnOnce() -> Record,
+    {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.backlog.is_none() {
+            return;
+        }
+        match record().encode() {
+            Ok(frame) => Self::push(&mut inner, frame),
+            Err(error) => log::error!("failed to encode the record: {}", error),
+        }
+    }
+
+    fn push(inner: &mut FeedInner, frame: Vec<u8>) {
+        inner.followers.retain(|follower| match follower.tx.try_send(frame.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                let address = follower.address;
+                log::warn!("follower {} is behind, disconnected, it resumes later", address);
+                false
+            },
+            // the follower is gone
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        if let Some(backlog) = &mut inner.backlog {
+            backlog.push(frame);
+        }
+    }
+
+    /// Where the follower starts, the frames from the backlog it missed and the new frames
+    fn subscribe(
+        &self,
+        address: SocketAddr,
+        resume: Resume,
+    ) -> (Resume, Vec<Vec<u8>>, mpsc::Receiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let mut inner = self.inner.lock().unwrap();
+        let backlog = inner
+            .backlog
+            .get_or_insert_with(|| Backlog::new(Self::BACKLOG_SIZE));
+        let (next, replay) = if resume.epoch == self.epoch {
+            backlog.since(resume.next)
+        } else if resume.epoch != 0 {
+            // the primary restarted, everything kept since then
+            backlog.since(0)
+        } else {
+            // the new follower starts from now on
+            (backlog.next(), vec![])
+        };
+        inner.followers.push(Follower { address, tx });
+        let epoch = self.epoch;
+        (Resume { epoch, next }, replay, rx)
+    }
+}
+
+/// Accepts the followers on the port and sends them the records the primary stores,
+/// on the `address`, the follower must present the `token` if it is not `None`
+pub fn spawn_primary(
+    address: IpAddr,
+    port: u16,
+    token: Option<String>,
+    feed: Arc<Feed>,
+    running: Arc<AtomicBool>,
+) -> io::Result<thread::JoinHandle<()>> {
+    if token.is_none() && !address.is_loopback() {
+        log::warn!("followers are accepted on {}:{} without a token", address, port);
+    }
+    let token = Arc::new(token);
+    let listener = TcpListener::bind((address, port))?;
+    listener.set_nonblocking(true)?;
+    Ok(thread::spawn(move || {
+        while running.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, address)) => {
+                    log::info!("follower connected: {}", address);
+                    let feed = feed.clone();
+                    let running = running.clone();
+                    let token = token.clone();
+                    thread::spawn(move || {
+                        let token = token.as_deref();
+                        if let Err(error) = send(stream, token, &feed, address, &running) {
+                            log::error!("follower {} error: {}", address, error);
+                        }
+                        log::info!("follower disconnected: {}", address);
+                    });
+                },
+                Err(error) => {
+                    if error.kind() == io::ErrorKind::WouldBlock {
+                        thread::sleep(Duration::from_millis(100));
+                    } else {
+                        log::error!("accepting follower error: {}", error)
+                    }
+                },
+            }
+        }
+    }))
+}
+
+fn send(
+    mut stream: TcpStream,
+    token: Option<&str>,
+    feed: &Feed,
+    address: SocketAddr,
+    running: &AtomicBool,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let version = protocol::accept(&mut stream, token)?;
+    let resume = Resume::read(&mut stream)?;
+    let (start, replay, rx) = feed.subscribe(address, resume);
+    log::info!(
+        "follower {} protocol version: {}, from the record {}, {} missed records",
+        address,
+        version,
+        start.next,
+        replay.len(),
+    );
+
+    let mut stream = BufWriter::new(stream);
+    start.write(&mut stream)?;
+    for frame in replay {
+        stream.write_all(&frame)?;
+    }
+    stream.flush()?;
+    while running.load(Ordering::Relaxed) {
+        match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(frame) => {
+                stream.write_all(&frame)?;
+                // write everything queued meanwhile and flush once
+                while let Ok(frame) = rx.try_recv() {
+                    stream.write_all(&frame)?;
+                }
+                stream.flush()?;
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(())
+}
+
+/// Connects to the primary and stores the records it sends, the follower must present
+/// the `token` if the primary requires it. Reconnects if the connection is lost
+/// and resumes from the record it got last
+pub fn spawn_follower<Db>(
+    address: String,
+    token: Option<String>,
+    db: Arc<Db>,
+    running: Arc<AtomicBool>,
+) -> io::Result<thread::JoinHandle<()>>
+where
+    Db: Database + Sync + Send + 'static,
+{
+    thread::Builder::new()
+        .name("follower".to_string())
+        .spawn(move || {
+            let mut resume = Resume { epoch: 0, next: 0 };
+            while running.load(Ordering::Relaxed) {
+                match connect(&address, token.as_deref(), resume) {
+                    Ok((stream, start)) => {
+                        let lost = if resume.epoch == 0 {
+                            0
+                        } else if start.epoch != resume.epoch {
+                            log::warn!("the primary {} restarted", address);
+                            start.next
+                        } else {
+                            start.next.saturating_sub(resume.next)
+                        };
+                        if lost != 0 {
+                            log::warn!("lost {} records of the primary {}", lost, address);
+                        }
+                        let peer = format!("primary {}", address);
+                        let received =
+                            receive_frames(BufReader::new(stream), db.as_ref(), &running, &peer);
+                        resume = Resume {
+                            epoch: start.epoch,
+                            next: start.next + received,
+                        };
+                        log::warn!("disconnected from the primary {}", address);
+                    },
+                    Err(error) => log::error!("cannot follow the primary {}: {}", address, error),
+                }
+                // do not reconnect too often
+                thread::sleep(Duration::from_secs(1));
+            }
+        })
+}
+
+fn connect(address: &str, token: Option<&str>, resume: Resume) -> io::Result<(TcpStream, Resume)> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let version = protocol::connect(&mut stream, token)?;
+    resume.write(&mut stream)?;
+    let start = Resume::read(&mut stream)?;
+    log::info!(
+        "following the primary {}, protocol version: {}, from the record {}",
+        address,
+        version,
+        start.next,
+    );
+    Ok((stream, start))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, sync::mpsc::TryRecvError};
+    use super::{Backlog, Feed, Resume, QUEUE_SIZE};
+
+    fn push(feed: &Feed, frame: u8) {
+        Feed::push(&mut feed.inner.lock().unwrap(), vec![frame]);
+    }
+
+    fn followers(feed: &Feed) -> usize {
+        feed.inner.lock().unwrap().followers.len()
+    }
+
+    #[test]
+    fn resume() {
+        let resume = Resume {
+            epoch: 0x1234,
+            next: 0x5678,
+        };
+        let mut v = vec![];
+        resume.write(&mut v).unwrap();
+        assert_eq!(v.len(), Resume::SIZE);
+        assert_eq!(Resume::read(&mut Cursor::new(v)).unwrap(), resume);
+        assert!(Resume::read(&mut Cursor::new(vec![0; 8])).is_err());
+    }
+
+    #[test]
+    fn backlog() {
+        let mut backlog = Backlog::new(3);
+        (0..5).for_each(|frame| backlog.push(vec![frame]));
+        assert_eq!((backlog.first, backlog.next()), (2, 5));
+        assert_eq!(backlog.since(3), (3, vec![vec![3], vec![4]]));
+        // the frames before are gone
+        assert_eq!(backlog.since(0), (2, vec![vec![2], vec![3], vec![4]]));
+        assert_eq!(backlog.since(7), (5, vec![]));
+    }
+
+    #[test]
+    fn catch_up() {
+        let address = ([127, 0, 0, 1], 17801).into();
+        let feed = Feed::default();
+        feed.publish(|| panic!("nobody follows, the record is not built"));
+
+        let fresh = Resume { epoch: 0, next: 0 };
+        let (start, replay, rx) = feed.subscribe(address, fresh);
+        let now = Resume {
+            epoch: feed.epoch,
+            next: 0,
+        };
+        assert_eq!(start, now);
+        assert!(replay.is_empty());
+        (0..3).for_each(|frame| push(&feed, frame));
+        assert_eq!(rx.try_recv().unwrap(), [0]);
+        assert_eq!(rx.try_recv().unwrap(), [1]);
+
+        // the follower got two frames and disconnected
+        drop(rx);
+        (3..5).for_each(|frame| push(&feed, frame));
+        assert_eq!(followers(&feed), 0);
+        let resume = Resume {
+            epoch: feed.epoch,
+            next: 2,
+        };
+        let (start, replay, rx) = feed.subscribe(address, resume);
+        assert_eq!(start, resume);
+        assert_eq!(replay, [[2], [3], [4]]);
+        push(&feed, 5);
+        assert_eq!(rx.try_recv().unwrap(), [5]);
+
+        // the follower of the previous run of the primary gets everything kept
+        let resume = Resume {
+            epoch: 1,
+            next: 100,
+        };
+        let (start, replay, _rx) = feed.subscribe(address, resume);
+        assert_eq!(start.next, 0);
+        assert_eq!(replay.len(), 6);
+    }
+
+    #[test]
+    fn behind_disconnected() {
+        let address = ([127, 0, 0, 1], 17801).into();
+        let feed = Feed::default();
+        let (_, _, rx) = feed.subscribe(address, Resume { epoch: 0, next: 0 });
+        (0..=QUEUE_SIZE).for_each(|frame| push(&feed, frame as u8));
+        assert_eq!(followers(&feed), 0);
+        // the queued frames are sent before the disconnection, the follower resumes after them
+        assert_eq!(rx.try_iter().count(), QUEUE_SIZE);
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    }
+}
@@ -2,35 +2,56 @@
 // SPDX-License-Identifier: MIT
 
 use std::{
+    collections::{HashSet, BTreeMap},
     net::SocketAddr,
-    ops::Add,
+    ops::{Add, Range},
+    io,
     path::{Path, PathBuf},
-    sync::atomic::{Ordering, AtomicU64},
+    sync::{
+        Arc, Mutex,
+        atomic::{Ordering, AtomicU64},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use rocksdb::{Cache, DB, ReadOptions};
+use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, DB, Options, ReadOptions, WriteBatch};
 use storage::{
     Direction, IteratorMode,
     persistent::{
         self, DBError, DbConfiguration, Decoder, Encoder, KeyValueSchema,
         KeyValueStoreWithSchemaIterator, KeyValueStoreBackend, SchemaError,
-        database::RocksDbKeyValueSchema,
+        database::{RocksDbKeyValueSchema, default_table_options},
     },
 };
 use tantivy::TantivyError;
 use anyhow::Result;
 use thiserror::Error;
 use itertools::Itertools;
-use super::sorted_intersect::sorted_intersect;
+use super::{
+    sorted_intersect::sorted_intersect,
+    batch::Batcher,
+    shards::{Shards, Job, DAY_MS},
+    cache::MessageCache,
+    tuning::RocksdbConfig,
+    delta::Deltas,
+    archive::{Archive, Record},
+    recovery::{self, Marker},
+    remote::{protocol, replication::Feed},
+};
 #[rustfmt::skip]
 use super::{
     // core traits
-    Database, DatabaseNew, DatabaseFetch, search,
+    Database, DatabaseNew, DatabaseFetch, StorageStats, StoreStats, search,
     // filters
-    ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter,
+    ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter, LogCountsFilter,
+    AnnotationsFilter, IncidentsFilter, FindingsFilter, BandwidthFilter, GapsFilter,
     // tables
-    common, connection, chunk, message, node_log,
+    common, connection, chunk, message, node_log, peer, annotation, session, incident, finding,
+    preset, gap,
     // secondary indexes
-    message_ty, message_sender, message_initiator, message_addr, log_level, timestamp,
+    message_ty, message_sender, message_initiator, message_addr, message_hash,
+    log_level, log_module, log_count, bandwidth, timestamp,
+    // the scenario
+    Conversation,
 };
 
 #[derive(Error, Debug)]
@@ -41,6 +62,10 @@ pub enum DbError {
     NoLogIndexer,
     #[error("log indexer: {}", _0)]
     LogIndexer(TantivyError),
+    #[error("archive: {}", _0)]
+    Archive(io::Error),
+    #[error("recovery: {}", _0)]
+    Recovery(io::Error),
 }
 
 impl From<DBError> for DbError {
@@ -55,17 +80,50 @@ impl From<TantivyError> for DbError {
     }
 }
 
+impl From<io::Error> for DbError {
+    fn from(v: io::Error) -> Self {
+        DbError::Archive(v)
+    }
+}
+
 pub struct Db {
     //_cache: Cache,
-    message_store_limit: Option<u64>,
+    // `NO_LIMIT` if there is no limit, the limits can change at runtime
+    message_store_limit: AtomicU64,
     message_counter: AtomicU64,
-    log_store_limit: Option<u64>,
+    log_store_limit: AtomicU64,
     log_counter: AtomicU64,
+    annotation_counter: AtomicU64,
+    incident_counter: AtomicU64,
+    gap_counter: AtomicU64,
+    finding_counter: AtomicU64,
     log_indexer: Option<search::LogIndexer>,
+    message_cache: MessageCache,
+    decoders: message::DecoderRegistry,
+    // the peer is read, updated and written back
+    peer_lock: Mutex<()>,
+    // at most one session runs
+    session_lock: Mutex<()>,
+    // messages and chunks
+    batcher: Batcher,
+    shards: Shards,
+    // the expiry of the old days, done by `maintain`, not before the time
+    jobs: Mutex<Vec<(Instant, Job)>>,
+    deltas: Deltas,
+    // the messages of the expired days
+    archive: Option<Archive>,
+    // the followers of the primary
+    feed: Option<Arc<Feed>>,
+    // removed when the database is closed cleanly
+    marker: Marker,
     inner: DB,
 }
 
 impl Db {
+    const BATCH_MAX_ENTRIES: usize = 256;
+    const BATCH_MAX_DELAY: Duration = Duration::from_millis(100);
+    const JOB_RETRY: Duration = Duration::from_secs(60);
+
     fn as_kv<S>(&self) -> &(impl KeyValueStoreBackend<S> + KeyValueStoreWithSchemaIterator<S>)
     where
         S: KeyValueSchema + RocksDbKeyValueSchema,
@@ -73,6 +131,16 @@ impl Db {
         &self.inner
     }
 
+    const NO_LIMIT: u64 = u64::MAX;
+    const DEFAULT_MESSAGE_CACHE: usize = 1000;
+
+    fn limit(value: &AtomicU64) -> Option<u64> {
+        match value.load(Ordering::SeqCst) {
+            Self::NO_LIMIT => None,
+            limit => Some(limit),
+        }
+    }
+
     fn reserve_message_counter(&self) -> u64 {
         self.message_counter.fetch_add(1, Ordering::SeqCst)
     }
@@ -80,6 +148,76 @@ impl Db {
     fn reserve_log_counter(&self) -> u64 {
         self.log_counter.fetch_add(1, Ordering::SeqCst)
     }
+
+    /// Looks up the key in the shards from the newest day to the oldest
+    fn get_sharded<'a, S, F>(
+        &'a self,
+        cf: F,
+        key: &S::Key,
+    ) -> Result<Option<(&'a ColumnFamily, S::Value)>, DBError>
+    where
+        S: KeyValueSchema,
+        F: Fn(usize) -> Result<&'a ColumnFamily, DBError>,
+    {
+        let key = key.encode().map_err(|error| DBError::SchemaError { error })?;
+        for slot in self.shards.slots() {
+            let cf = cf(slot)?;
+            let value = self
+                .inner
+                .get_cf(cf, &key)
+                .map_err(|error| DBError::RocksDBError { error })?;
+            if let Some(value) = value {
+                let value = S::Value::decode(&value).map_err(|error| DBError::SchemaError { error })?;
+                return Ok(Some((cf, value)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn message_shard(&self, index: u64) -> Result<Option<(&ColumnFamily, message::Item)>, DBError> {
+        self.get_sharded::<message::Schema, _>(
+            |slot| self.shards.message_cf(&self.inner, slot),
+            &index,
+        )
+    }
+
+    fn chunk_shard(
+        &self,
+        key: &chunk::Key,
+    ) -> Result<Option<(&ColumnFamily, chunk::Value)>, DBError> {
+        self.get_sharded::<chunk::Schema, _>(|slot| self.shards.chunk_cf(&self.inner, slot), key)
+    }
+
+    /// The chunk with its plain restored, if it is stored as the difference
+    fn chunk_resolved(&self, key: &chunk::Key) -> Result<Option<chunk::Value>, DBError> {
+        let mut value = match self.chunk_shard(key)? {
+            Some((_, value)) => value,
+            None => return Ok(None),
+        };
+        self.resolve_chunk(key, &mut value)?;
+        Ok(Some(value))
+    }
+
+    fn resolve_chunk(&self, key: &chunk::Key, value: &mut chunk::Value) -> Result<(), DBError> {
+        let base = match value.delta_base() {
+            Some(base) => base,
+            None => return Ok(()),
+        };
+        let base_key = chunk::Key {
+            counter: base,
+            ..key.clone()
+        };
+        let resolved = match self.chunk_shard(&base_key)? {
+            Some((_, base)) if base.delta_base().is_none() => value.resolve(&base.plain),
+            _ => false,
+        };
+        // the base is removed with the older message, the plain is lost as if not decrypted
+        if !resolved {
+            log::warn!("cannot restore the chunk {}, the base {} is missing", key, base);
+            value.plain.clear();
+        }
+        Ok(())
+    }
 }
 
 impl DatabaseNew for Db {
@@ -90,28 +228,80 @@ impl DatabaseNew for Db {
         log_full_text_index: bool,
         log_store_limit: Option<u64>,
         message_store_limit: Option<u64>,
+        message_retention_days: Option<u64>,
+        message_cache: Option<usize>,
+        tuning: Option<RocksdbConfig>,
     ) -> Result<Self, Self::Error>
     where
         P: AsRef<Path>,
     {
-        let cache = Cache::new_lru_cache(1).map_err(|error| DBError::RocksDBError { error })?;
-
-        let cfs = vec![
-            connection::Schema::descriptor(&cache),
-            chunk::Schema::descriptor(&cache),
-            message::Schema::descriptor(&cache),
-            node_log::Schema::descriptor(&cache),
-            message_ty::Schema::descriptor(&cache),
-            message_sender::Schema::descriptor(&cache),
-            message_initiator::Schema::descriptor(&cache),
-            message_addr::Schema::descriptor(&cache),
-            timestamp::MessageSchema::descriptor(&cache),
-            log_level::Schema::descriptor(&cache),
-            timestamp::LogSchema::descriptor(&cache),
+        let tuning = tuning.unwrap_or_default();
+        let cache = tuning
+            .block_cache()
+            .map_err(|error| DBError::RocksDBError { error })?;
+
+        // the default options of the table read through the given cache anyway
+        let default_cf = |name| tuning.descriptor(name, default_table_options(&cache));
+        let own_cf = |name, opts| tuning.descriptor(name, tuning.with_block_cache(opts, &cache));
+        let mut cfs = vec![
+            default_cf(connection::Schema::name()),
+            default_cf(node_log::Schema::name()),
+            own_cf(message_ty::Schema::name(), message_ty::Schema::options()),
+            own_cf(message_sender::Schema::name(), message_sender::Schema::options()),
+            own_cf(message_initiator::Schema::name(), message_initiator::Schema::options()),
+            own_cf(message_addr::Schema::name(), message_addr::Schema::options()),
+            own_cf(message_hash::Schema::name(), message_hash::Schema::options()),
+            default_cf(timestamp::MessageSchema::name()),
+            own_cf(log_level::Schema::name(), log_level::Schema::options()),
+            default_cf(timestamp::LogSchema::name()),
+            own_cf(log_module::Schema::name(), log_module::Schema::options()),
+            own_cf(log_count::Schema::name(), log_count::Schema::options()),
+            own_cf(bandwidth::Schema::name(), bandwidth::Schema::options()),
+            default_cf(peer::Schema::name()),
+            default_cf(annotation::Schema::name()),
+            default_cf(session::Schema::name()),
+            default_cf(incident::Schema::name()),
+            default_cf(finding::Schema::name()),
+            default_cf(preset::Schema::name()),
+            default_cf(gap::Schema::name()),
+            default_cf(gap::HeartbeatSchema::name()),
+            default_cf(recovery::QUARANTINE),
         ];
         let path = PathBuf::from(path.as_ref());
-        let inner =
+        let shards = Shards::new(message_retention_days);
+        let stale = shards.stale(&path.join("rocksdb"));
+        cfs.extend(shards.descriptors(&tuning, &cache));
+        cfs.extend(
+            stale
+                .iter()
+                .map(|name| ColumnFamilyDescriptor::new(name, Options::default())),
+        );
+        let mut inner =
             persistent::database::open_kv(path.join("rocksdb"), cfs, &DbConfiguration::default())?;
+        for (name, value) in tuning.db_options() {
+            inner
+                .set_options(&[(name, value.as_str())])
+                .map_err(|error| DBError::RocksDBError { error })?;
+        }
+        for name in stale {
+            log::info!("drop message shard {} beyond the retention", name);
+            inner
+                .drop_cf(&name)
+                .map_err(|error| DBError::RocksDBError { error })?;
+        }
+        // the unreadable entries would fail the queries and the counters
+        let (marker, unclean) = Marker::create(&path).map_err(DbError::Recovery)?;
+        if unclean {
+            log::warn!("the database {} was not closed cleanly, checking", path.display());
+            let report = recovery::run(&inner, &shards)?;
+            let report_json = serde_json::to_string(&report).unwrap_or_default();
+            if report.damaged() {
+                log::warn!("the database {} is recovered: {}", path.display(), report_json);
+            } else {
+                log::info!("the database {} is intact: {}", path.display(), report_json);
+            }
+        }
+        shards.load(&inner)?;
 
         fn counter<S>(db: &DB) -> Option<S::Key>
         where
@@ -132,20 +322,70 @@ impl DatabaseNew for Db {
             None
         };
 
-        Ok(Db {
-            message_store_limit,
-            message_counter: AtomicU64::new(counter::<message::Schema>(&inner).unwrap_or(0)),
-            log_store_limit,
+        let archive = match tuning.archive() {
+            Some(config) => {
+                if message_retention_days.is_none() {
+                    log::warn!("the archive takes the expired days, but there is no retention");
+                }
+                Some(Archive::open(config.clone())?)
+            },
+            None => None,
+        };
+        // the index continues after the archived messages, even if the database is empty
+        let message_counter = archive
+            .as_ref()
+            .and_then(Archive::last_index)
+            .map_or(0, |last| last + 1)
+            .max(shards.next_message_index(&inner)?);
+
+        let db = Db {
+            message_store_limit: AtomicU64::new(message_store_limit.unwrap_or(Self::NO_LIMIT)),
+            message_counter: AtomicU64::new(message_counter),
+            log_store_limit: AtomicU64::new(log_store_limit.unwrap_or(Self::NO_LIMIT)),
             log_counter: AtomicU64::new(counter::<node_log::Schema>(&inner).unwrap_or(0)),
+            annotation_counter: AtomicU64::new(
+                counter::<annotation::Schema>(&inner).unwrap_or(0),
+            ),
+            incident_counter: AtomicU64::new(counter::<incident::Schema>(&inner).unwrap_or(0)),
+            gap_counter: AtomicU64::new(counter::<gap::Schema>(&inner).unwrap_or(0)),
+            finding_counter: AtomicU64::new(counter::<finding::Schema>(&inner).unwrap_or(0)),
             log_indexer,
+            message_cache: MessageCache::new(message_cache.unwrap_or(Self::DEFAULT_MESSAGE_CACHE)),
+            decoders: message::DecoderRegistry::default(),
+            peer_lock: Mutex::new(()),
+            session_lock: Mutex::new(()),
+            batcher: Batcher::new(Self::BATCH_MAX_ENTRIES, Self::BATCH_MAX_DELAY),
+            shards,
+            jobs: Mutex::new(vec![]),
+            deltas: Deltas::new(tuning.delta_compression()),
+            archive,
+            feed: None,
+            marker,
             inner,
-        })
+        };
+        // the days which expired while the recorder was down, the capture is not started yet
+        let today = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| Shards::message_day(d.as_millis() as u64))
+            .unwrap_or_default();
+        db.queue(db.shards.expired(today));
+        db.maintain();
+        Ok(db)
+    }
+
+    fn set_decoders(&mut self, decoders: message::DecoderRegistry) {
+        self.decoders = decoders;
+    }
+
+    fn set_feed(&mut self, feed: Arc<Feed>) {
+        self.feed = Some(feed);
     }
 }
 
 impl Db {
     pub fn remove_message(&self, index: u64) -> Result<(), DbError> {
-        if let Some(item) = self.as_kv::<message::Schema>().get(&index)? {
+        self.message_cache.remove(index);
+        if let Some((cf, item)) = self.message_shard(index)? {
             let ty_index = message_ty::Item {
                 ty: item.ty.clone(),
                 index,
@@ -168,7 +408,12 @@ impl Db {
             };
 
             for chunk_key in item.chunks() {
-                self.as_kv::<chunk::Schema>().delete(&chunk_key)?;
+                if let Some((cf, value)) = self.chunk_shard(&chunk_key)? {
+                    if Deltas::is_base(&value) {
+                        self.release_base(cf, &chunk_key, &value.plain)?;
+                    }
+                    delete_cf(&self.inner, cf, &chunk_key)?;
+                }
             }
 
             self.as_kv::<message_ty::Schema>().delete(&ty_index)?;
@@ -177,13 +422,72 @@ impl Db {
             self.as_kv::<message_initiator::Schema>()
                 .delete(&initiator_index)?;
             self.as_kv::<message_addr::Schema>().delete(&addr_index)?;
+            for hash in item.hashes {
+                self.as_kv::<message_hash::Schema>()
+                    .delete(&message_hash::Item { hash, index })?;
+            }
             self.as_kv::<timestamp::MessageSchema>()
                 .delete(&timestamp_index)?;
-            self.as_kv::<message::Schema>().delete(&index)?;
+            delete_cf(&self.inner, cf, &index)?;
+        }
+        Ok(())
+    }
+
+    /// The chunks stored as the difference from the base get their plain back before the base
+    /// is removed, otherwise they could not be restored, the dependents are in the same shard
+    fn release_base(
+        &self,
+        cf: &ColumnFamily,
+        key: &chunk::Key,
+        plain: &[u8],
+    ) -> Result<(), DBError> {
+        // no more dependents
+        self.deltas.forget(key);
+        if !self.restore_dependents(cf, key, plain)? {
+            // the next base is not found, so the latest dependents might wait in the batch
+            self.batcher.flush(&self.inner)?;
+            self.restore_dependents(cf, key, plain)?;
         }
         Ok(())
     }
 
+    /// Walks the later chunks of the side until the next base, whether it is found
+    fn restore_dependents(
+        &self,
+        cf: &ColumnFamily,
+        key: &chunk::Key,
+        plain: &[u8],
+    ) -> Result<bool, DBError> {
+        let schema = |error| DBError::SchemaError { error };
+        let encoded = key.encode().map_err(schema)?;
+        let mode = IteratorMode::From(&encoded, Direction::Forward);
+        for (k, v) in self.inner.iterator_cf(cf, mode) {
+            if k[..] == encoded[..] {
+                continue;
+            }
+            let k = chunk::Key::decode(&k).map_err(schema)?;
+            if (k.cn_id.ts, k.cn_id.ts_nanos) != (key.cn_id.ts, key.cn_id.ts_nanos) {
+                break;
+            }
+            if k.sender.incoming() != key.sender.incoming() {
+                continue;
+            }
+            let mut value = chunk::Value::decode(&v).map_err(schema)?;
+            match value.delta_base() {
+                Some(base) if base == key.counter => {
+                    if value.resolve(plain) {
+                        put_cf(&self.inner, cf, &k, &value)?;
+                    }
+                },
+                // the bases of the side only go forward
+                Some(_) => return Ok(true),
+                None if Deltas::is_base(&value) => return Ok(true),
+                None => (),
+            }
+        }
+        Ok(false)
+    }
+
     pub fn remove_log(&self, index: u64) -> Result<(), DbError> {
         if let Some(item) = self.as_kv::<node_log::Schema>().get(&index)? {
             let lv_index = log_level::Item {
@@ -194,18 +498,348 @@ impl Db {
                 timestamp: (item.timestamp / 1_000_000) as u64,
                 index,
             };
+            let count_key = log_count::Item::new(timestamp_index.timestamp, item.level.clone());
 
             self.as_kv::<log_level::Schema>().delete(&lv_index)?;
+            for module in log_module::segments(&item.section) {
+                self.as_kv::<log_module::Schema>()
+                    .delete(&log_module::Item::new(module, index))?;
+            }
+            self.merge_log_count(&count_key, -1)?;
             self.as_kv::<timestamp::LogSchema>()
                 .delete(&timestamp_index)?;
             self.as_kv::<node_log::Schema>().delete(&index)?;
         }
         Ok(())
     }
+
+    /// The shard of the day, the jobs the new day makes due are queued for `maintain`
+    fn acquire_shard(&self, day: u64) -> Option<usize> {
+        let (slot, jobs) = self.shards.acquire(day);
+        self.queue(jobs);
+        slot
+    }
+
+    fn queue(&self, jobs: Vec<Job>) {
+        if !jobs.is_empty() {
+            let now = Instant::now();
+            let mut queue = self.jobs.lock().unwrap();
+            queue.extend(jobs.into_iter().map(|job| (now, job)));
+        }
+    }
+
+    fn run_job(&self, job: Job) -> Result<(), DbError> {
+        match job {
+            Job::Expire { slot, day } => {
+                self.expire_shard(slot, day)?;
+                self.shards.finish(slot, day);
+            },
+        }
+        Ok(())
+    }
+
+    /// Archives the expired day, if there is the archive, then clears the shard and
+    /// the secondary indexes of its messages, each index is cleared by range tombstones,
+    /// one per distinct prefix. Nothing is written into the shard meanwhile, it is read-only,
+    /// the capture goes on in the other shards. The shard is not cleared if the day
+    /// is not archived, the job is retried.
+    fn expire_shard(&self, slot: usize, day: u64) -> Result<(), DbError> {
+        // the batch might still hold some records of the expired day
+        self.batcher.flush(&self.inner)?;
+
+        let cf = self.shards.message_cf(&self.inner, slot)?;
+        if let Some(archive) = &self.archive {
+            let records = self
+                .inner
+                .iterator_cf(cf, rocksdb::IteratorMode::Start)
+                .filter_map(|(k, v)| {
+                    Some((u64::decode(&k).ok()?, message::Item::decode(&v).ok()?))
+                })
+                .map(|(index, item)| {
+                    let chunks = item
+                        .chunks()
+                        .map(|key| self.chunk_resolved(&key).ok().flatten())
+                        .take_while(Option::is_some)
+                        .flatten()
+                        .collect();
+                    Record {
+                        index,
+                        item,
+                        chunks,
+                    }
+                });
+            archive.write_day(day, records).map_err(DbError::Archive)?;
+        }
+        let bound = |mode| {
+            self.inner
+                .iterator_cf(cf, mode)
+                .next()
+                .and_then(|(k, _)| u64::decode(&k).ok())
+        };
+        let first = bound(rocksdb::IteratorMode::Start);
+        let last = bound(rocksdb::IteratorMode::End);
+
+        let mut batch = WriteBatch::default();
+        if let (Some(first), Some(last)) = (first, last) {
+            let mut ranges = HashSet::new();
+            for (_, v) in self.inner.iterator_cf(cf, rocksdb::IteratorMode::Start) {
+                let item = match message::Item::decode(&v) {
+                    Ok(item) => item,
+                    Err(_) => continue,
+                };
+                ranges.insert(index_range::<message_ty::Schema, _>(first, last, |index| {
+                    message_ty::Item {
+                        ty: item.ty.clone(),
+                        index,
+                    }
+                })?);
+                ranges.insert(index_range::<message_sender::Schema, _>(first, last, |index| {
+                    message_sender::Item {
+                        sender: item.sender.clone(),
+                        index,
+                    }
+                })?);
+                ranges.insert(index_range::<message_initiator::Schema, _>(
+                    first,
+                    last,
+                    |index| message_initiator::Item {
+                        initiator: item.initiator.clone(),
+                        index,
+                    },
+                )?);
+                ranges.insert(index_range::<message_addr::Schema, _>(first, last, |index| {
+                    message_addr::Item {
+                        addr: item.remote_addr,
+                        index,
+                    }
+                })?);
+                for hash in &item.hashes {
+                    ranges.insert(index_range::<message_hash::Schema, _>(first, last, |index| {
+                        message_hash::Item {
+                            hash: hash.clone(),
+                            index,
+                        }
+                    })?);
+                }
+            }
+            let begin = timestamp::Item {
+                timestamp: day * DAY_MS,
+                index: 0,
+            };
+            let end = timestamp::Item {
+                timestamp: (day + 1) * DAY_MS,
+                index: 0,
+            };
+            let encode = |item: timestamp::Item| {
+                item.encode()
+                    .map_err(|error| DBError::SchemaError { error })
+            };
+            ranges.insert((timestamp::MessageSchema::name(), encode(begin)?, encode(end)?));
+            // the bandwidth of the day goes with its messages
+            let (begin, end) = bandwidth::Item::day_range(day);
+            ranges.insert((bandwidth::Schema::name(), begin, end));
+
+            for (name, begin, end) in ranges {
+                let cf = self
+                    .inner
+                    .cf_handle(name)
+                    .ok_or(DBError::MissingColumnFamily { name })?;
+                batch.delete_range_cf(cf, begin, end);
+            }
+        }
+        self.shards.clear(&self.inner, &mut batch, slot)?;
+        self.inner
+            .write(batch)
+            .map_err(|error| DBError::RocksDBError { error })?;
+        self.shards.compact(&self.inner, slot)
+    }
+
+    fn sessions(&self) -> Result<Vec<session::ItemWithId>, DBError> {
+        let v = self
+            .as_kv::<session::Schema>()
+            .iterator(IteratorMode::Start)?
+            .filter_map(|(k, v)| match (k, v) {
+                (Ok(id), Ok(item)) => Some(session::ItemWithId { id, item }),
+                (Ok(index), Err(err)) => {
+                    log::warn!("Failed to load session at {:?}: {}", index, err);
+                    None
+                },
+                (Err(err), _) => {
+                    log::warn!("Failed to load session index: {}", err);
+                    None
+                },
+            })
+            .collect();
+        Ok(v)
+    }
+
+    fn running_session(&self) -> Result<Option<session::ItemWithId>, DBError> {
+        Ok(self.sessions()?.into_iter().find(|s| s.item.running()))
+    }
+
+    /// The annotations are few, all of them are read for every query
+    fn annotations(&self) -> Result<Vec<annotation::ItemWithId>, DBError> {
+        let v = self
+            .as_kv::<annotation::Schema>()
+            .iterator(IteratorMode::Start)?
+            .filter_map(|(k, v)| match (k, v) {
+                (Ok(id), Ok(item)) => Some(annotation::ItemWithId { id, item }),
+                (Ok(index), Err(err)) => {
+                    log::warn!("Failed to load annotation at {:?}: {}", index, err);
+                    None
+                },
+                (Err(err), _) => {
+                    log::warn!("Failed to load annotation index: {}", err);
+                    None
+                },
+            })
+            .collect();
+        Ok(v)
+    }
+
+    fn merge_bandwidth(&self, key: &bandwidth::Item, bytes: u64) -> Result<(), DBError> {
+        let key = key.encode().map_err(|error| DBError::SchemaError { error })?;
+        let cf = self
+            .inner
+            .cf_handle(bandwidth::Schema::name())
+            .ok_or_else(|| DBError::MissingColumnFamily {
+                name: bandwidth::Schema::name(),
+            })?;
+        self.inner
+            .merge_cf(cf, key, log_count::Count::bytes(bytes as i64))
+            .map_err(|error| DBError::RocksDBError { error })
+    }
+
+    fn merge_log_count(&self, key: &log_count::Item, delta: i64) -> Result<(), DbError> {
+        let key = key.encode().map_err(|error| DBError::SchemaError { error })?;
+        let cf = self
+            .inner
+            .cf_handle(log_count::Schema::name())
+            .ok_or_else(|| DBError::MissingColumnFamily {
+                name: log_count::Schema::name(),
+            })?;
+        self.inner
+            .merge_cf(cf, key, log_count::Count::bytes(delta))
+            .map_err(|error| DBError::RocksDBError { error })?;
+        Ok(())
+    }
+
+    /// Continues the hot `messages` with the archived ones, they precede the hot ones
+    fn with_archived(
+        &self,
+        filter: &MessagesFilter,
+        mut hot: Vec<message::MessageFrontend>,
+    ) -> Result<Vec<message::MessageFrontend>, DbError> {
+        let archive = match &self.archive {
+            Some(archive) => archive,
+            None => return Ok(hot),
+        };
+        let limit = filter.limit.unwrap_or(100) as usize;
+        let forward = filter.direction == Some("forward".to_string());
+        let need = if forward {
+            let cursor = filter.cursor.unwrap_or(0);
+            archive.last_index().map_or(false, |last| last >= cursor)
+        } else {
+            hot.len() < limit
+        };
+        if !need {
+            return Ok(hot);
+        }
+        let cursor = match (forward, hot.last()) {
+            (false, Some(last)) => Some(last.id.saturating_sub(1)),
+            _ => filter.cursor,
+        };
+        let limit = if forward { limit } else { limit - hot.len() };
+        let mut cold = self.fetch_archived_messages(archive, filter, cursor, limit)?;
+        let v = if forward {
+            cold.extend(hot);
+            cold.truncate(limit);
+            cold
+        } else {
+            hot.extend(cold);
+            hot
+        };
+        Ok(v)
+    }
+
+    /// The archive has no secondary index, every message is checked against the filter
+    fn fetch_archived_messages(
+        &self,
+        archive: &Archive,
+        filter: &MessagesFilter,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<message::MessageFrontend>, DbError> {
+        let invalid = |e: String| DBError::SchemaError {
+            error: SchemaError::DecodeValidationError(e),
+        };
+        let forward = filter.direction == Some("forward".to_string());
+        let types = match &filter.types {
+            Some(ty) => Some(
+                common::MessageType::parse_list(ty).map_err(|e| invalid(e.to_string()))?,
+            ),
+            None => None,
+        };
+        let addr = match &filter.remote_addr {
+            Some(addr) => Some(
+                addr.parse::<SocketAddr>()
+                    .map_err(|e| invalid(e.to_string()))?,
+            ),
+            None => None,
+        };
+        let preview = filter.preview().map_err(invalid)?;
+        let mut hashes = vec![];
+        let kinds = [
+            (message_hash::HashKind::Block, &filter.block_hash),
+            (message_hash::HashKind::Operation, &filter.operation_hash),
+            (message_hash::HashKind::Protocol, &filter.protocol_hash),
+        ];
+        for (kind, hash) in kinds.iter() {
+            if let Some(hash) = hash {
+                hashes.push(message_hash::ContentHash::parse(*kind, hash).map_err(invalid)?);
+            }
+        }
+
+        let mut v = archive
+            .scan(forward, cursor)
+            .take_while(|_| !filter.deadline.check())
+            .filter(|(_, item)| {
+                let ts = item.timestamp;
+                addr.map_or(true, |addr| item.remote_addr == addr)
+                    && filter
+                        .source_type
+                        .as_ref()
+                        .map_or(true, |i| item.initiator.incoming() == i.incoming())
+                    && filter.incoming.map_or(true, |i| item.sender.incoming() == i)
+                    && types.as_ref().map_or(true, |types| types.contains(&item.ty))
+                    && filter.from.map_or(true, |from| ts >= from)
+                    && filter.to.map_or(true, |to| ts <= to)
+                    && filter.timestamp.map_or(true, |t| (ts >= t) == forward || ts == t)
+                    && hashes.iter().all(|hash| item.hashes.contains(hash))
+            })
+            .take(limit)
+            .map(|(index, item)| frontend(item, index, self, preview))
+            .collect::<Vec<_>>();
+        let annotations = self.annotations()?;
+        v.iter_mut().for_each(|m| m.annotate(&annotations));
+        Ok(v)
+    }
+}
+
+impl Db {
+    fn publish<F>(&self, record: F)
+    where
+        F: FnOnce() -> protocol::Record,
+    {
+        if let Some(feed) = &self.feed {
+            feed.publish(record);
+        }
+    }
 }
 
 impl Database for Db {
     fn store_connection(&self, item: connection::Item) {
+        self.publish(|| protocol::Record::Connection(item.clone()));
         let (key, value) = item.split();
         if let Err(error) = self.as_kv::<connection::Schema>().put(&key, &value) {
             log::error!("database error: {}", error);
@@ -213,6 +847,7 @@ impl Database for Db {
     }
 
     fn update_connection(&self, item: connection::Item) {
+        self.publish(|| protocol::Record::UpdateConnection(item.clone()));
         let (key, value) = item.split();
         let kv = self.as_kv::<connection::Schema>();
         if let Err(error) = kv.delete(&key).and_then(|()| kv.put(&key, &value)) {
@@ -221,16 +856,35 @@ impl Database for Db {
     }
 
     fn store_chunk(&self, item: chunk::Item) {
-        let (key, value) = item.split();
-        if let Err(error) = self.as_kv::<chunk::Schema>().put(&key, &value) {
+        self.publish(|| protocol::Record::Chunk(item.clone()));
+        let (key, mut value) = item.split();
+        let day = Shards::chunk_day(value.timestamp());
+        let mut inner = || -> Result<(), DBError> {
+            let slot = match self.acquire_shard(day) {
+                Some(slot) => slot,
+                None => return Ok(()),
+            };
+            self.deltas.compress(&key, day, &mut value);
+            let name = self.shards.chunk_name(slot);
+            self.batcher
+                .write(&self.inner, |b| b.put_cf::<chunk::Schema>(name, &key, &value))
+        };
+        if let Err(error) = inner() {
             log::error!("database error: {}", error);
         }
     }
 
     fn store_message(&self, item: message::Item) {
+        self.publish(|| protocol::Record::Message(item.clone()));
         let index = self.reserve_message_counter();
-        if let Some(store_limit) = self.message_store_limit {
+        if let Some(store_limit) = Self::limit(&self.message_store_limit) {
             if index >= store_limit {
+                // the removed message might be still in the batch
+                if store_limit <= Self::BATCH_MAX_ENTRIES as u64 {
+                    if let Err(error) = self.batcher.flush(&self.inner) {
+                        log::error!("database error: {}", error);
+                    }
+                }
                 if let Err(error) = self.remove_message(index - store_limit) {
                     log::error!("database error: {}", error);
                 }
@@ -257,17 +911,63 @@ impl Database for Db {
             timestamp: item.timestamp,
             index,
         };
-        let inner = || -> Result<(), DbError> {
-            self.as_kv::<message_ty::Schema>().put(&ty_index, &())?;
-            self.as_kv::<message_sender::Schema>()
-                .put(&sender_index, &())?;
-            self.as_kv::<message_initiator::Schema>()
-                .put(&initiator_index, &())?;
-            self.as_kv::<message_addr::Schema>().put(&addr_index, &())?;
-            self.as_kv::<timestamp::MessageSchema>()
-                .put(&timestamp_index, &())?;
-            self.as_kv::<message::Schema>().put(&index, &item)?;
-            Ok(())
+        let bandwidth_key = bandwidth::Item::new(
+            item.timestamp,
+            item.sender.incoming(),
+            item.ty.clone(),
+            item.remote_addr,
+        );
+        let day = Shards::message_day(item.timestamp);
+        let inner = || -> Result<(), DBError> {
+            let slot = match self.acquire_shard(day) {
+                Some(slot) => slot,
+                None => return Ok(()),
+            };
+            // counted even if the message is removed by the store limit later
+            if item.wire_bytes > 0 {
+                self.merge_bandwidth(&bandwidth_key, item.wire_bytes)?;
+            }
+            let name = self.shards.message_name(slot);
+            self.batcher.write(&self.inner, |b| {
+                b.put::<message_ty::Schema>(&ty_index, &())?;
+                b.put::<message_sender::Schema>(&sender_index, &())?;
+                b.put::<message_initiator::Schema>(&initiator_index, &())?;
+                b.put::<message_addr::Schema>(&addr_index, &())?;
+                for hash in &item.hashes {
+                    let hash_index = message_hash::Item {
+                        hash: hash.clone(),
+                        index,
+                    };
+                    b.put::<message_hash::Schema>(&hash_index, &())?;
+                }
+                b.put::<timestamp::MessageSchema>(&timestamp_index, &())?;
+                b.put_cf::<message::Schema>(name, &index, &item)?;
+                Ok(())
+            })
+        };
+        if let Err(error) = inner() {
+            log::error!("database error: {}", error);
+        }
+    }
+
+    fn count_message(&self, item: message::Item) {
+        self.publish(|| protocol::Record::Sampled(item.clone()));
+        if item.wire_bytes == 0 {
+            return;
+        }
+        let bandwidth_key = bandwidth::Item::new(
+            item.timestamp,
+            item.sender.incoming(),
+            item.ty.clone(),
+            item.remote_addr,
+        );
+        let day = Shards::message_day(item.timestamp);
+        let inner = || -> Result<(), DBError> {
+            // the day beyond the retention is not counted as the stored messages
+            if self.acquire_shard(day).is_none() {
+                return Ok(());
+            }
+            self.merge_bandwidth(&bandwidth_key, item.wire_bytes)
         };
         if let Err(error) = inner() {
             log::error!("database error: {}", error);
@@ -275,8 +975,9 @@ impl Database for Db {
     }
 
     fn store_log(&self, item: node_log::Item) {
+        self.publish(|| protocol::Record::Log(item.clone()));
         let index = self.reserve_log_counter();
-        if let Some(store_limit) = self.log_store_limit {
+        if let Some(store_limit) = Self::limit(&self.log_store_limit) {
             if index >= store_limit {
                 if let Err(error) = self.remove_log(index - store_limit) {
                     log::error!("database error: {}", error);
@@ -292,8 +993,14 @@ impl Database for Db {
             timestamp: (item.timestamp / 1_000_000) as u64,
             index,
         };
+        let count_key = log_count::Item::new(timestamp_index.timestamp, item.level.clone());
         let inner = || -> Result<(), DbError> {
             self.as_kv::<log_level::Schema>().put(&lv_index, &())?;
+            for module in log_module::segments(&item.section) {
+                self.as_kv::<log_module::Schema>()
+                    .put(&log_module::Item::new(module, index), &())?;
+            }
+            self.merge_log_count(&count_key, 1)?;
             self.as_kv::<timestamp::LogSchema>()
                 .put(&timestamp_index, &())?;
             self.as_kv::<node_log::Schema>().put(&index, &item)?;
@@ -306,6 +1013,88 @@ impl Database for Db {
             log::error!("database error: {}", error);
         }
     }
+
+    fn store_peer(&self, item: peer::Item) {
+        self.publish(|| protocol::Record::Peer(item.clone()));
+        let key = peer::Key(item.pk);
+        let _guard = self.peer_lock.lock().unwrap();
+        let inner = || -> Result<(), DbError> {
+            let kv = self.as_kv::<peer::Schema>();
+            let mut value = kv.get(&key)?.unwrap_or_else(|| peer::Value::new(&item));
+            value.update(item);
+            kv.put(&key, &value)?;
+            Ok(())
+        };
+        if let Err(error) = inner() {
+            log::error!("database error: {}", error);
+        }
+    }
+
+    fn commit_pending(&self) {
+        if let Err(error) = self.batcher.flush_expired(&self.inner) {
+            log::error!("database error: {}", error);
+        }
+    }
+
+    fn maintain(&self) {
+        let now = Instant::now();
+        let due = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let (due, later) = jobs.drain(..).partition::<Vec<_>, _>(|(at, _)| *at <= now);
+            *jobs = later;
+            due
+        };
+        for (_, job) in due {
+            if let Err(error) = self.run_job(job) {
+                log::error!("{:?} failed, retry later: {}", job, error);
+                let retry = Instant::now() + Self::JOB_RETRY;
+                self.jobs.lock().unwrap().push((retry, job));
+            }
+        }
+    }
+
+    fn set_store_limits(&self, message_store_limit: Option<u64>, log_store_limit: Option<u64>) {
+        // the records from the old boundary up to the new boundary are removed
+        fn excess(counter: &AtomicU64, old: Option<u64>, new: Option<u64>) -> Range<u64> {
+            let counter = counter.load(Ordering::SeqCst);
+            let from = old.map(|old| counter.saturating_sub(old)).unwrap_or(0);
+            let to = new.map(|new| counter.saturating_sub(new)).unwrap_or(0);
+            from..to
+        }
+
+        let new = message_store_limit.unwrap_or(Self::NO_LIMIT);
+        let old = Self::limit(&self.message_store_limit);
+        self.message_store_limit.store(new, Ordering::SeqCst);
+        let range = excess(&self.message_counter, old, message_store_limit);
+        if !range.is_empty() {
+            if let Err(error) = self.batcher.flush(&self.inner) {
+                log::error!("database error: {}", error);
+            }
+        }
+        for index in range {
+            if let Err(error) = self.remove_message(index) {
+                log::error!("database error: {}", error);
+            }
+        }
+
+        let new = log_store_limit.unwrap_or(Self::NO_LIMIT);
+        let old = Self::limit(&self.log_store_limit);
+        self.log_store_limit.store(new, Ordering::SeqCst);
+        for index in excess(&self.log_counter, old, log_store_limit) {
+            if let Err(error) = self.remove_log(index) {
+                log::error!("database error: {}", error);
+            }
+        }
+    }
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        match self.batcher.flush(&self.inner) {
+            Ok(()) => self.marker.remove(),
+            Err(error) => log::error!("database error: {}", error),
+        }
+    }
 }
 
 // TODO: duplicated code
@@ -315,7 +1104,17 @@ impl DatabaseFetch for Db {
         filter: &ConnectionsFilter,
     ) -> Result<Vec<(connection::Key, connection::Value)>, Self::Error> {
         let limit = filter.limit.unwrap_or(100) as usize;
-        let mode = IteratorMode::Start;
+        let invalid = |e: connection::KeyFromStrError| DBError::SchemaError {
+            error: SchemaError::DecodeValidationError(e.to_string()),
+        };
+        let after = match &filter.after {
+            Some(after) => Some(after.parse::<connection::Key>().map_err(invalid)?),
+            None => None,
+        };
+        let mode = match &after {
+            Some(key) => IteratorMode::From(key, Direction::Forward),
+            None => IteratorMode::Start,
+        };
         let vec = self
             .as_kv::<connection::Schema>()
             .iterator(mode)?
@@ -330,6 +1129,27 @@ impl DatabaseFetch for Db {
                     None
                 },
             })
+            .filter(|(_, value)| match &filter.nack_motive {
+                Some(motive) => value.acks().nack_motive() == Some(*motive),
+                None => true,
+            })
+            .filter(|(_, value)| match &filter.termination {
+                Some(kind) => value.termination().map(|t| t.kind) == Some(*kind),
+                None => true,
+            })
+            .filter(|(key, _)| match &after {
+                Some(after) => (key.ts, key.ts_nanos) > (after.ts, after.ts_nanos),
+                None => true,
+            })
+            .filter(|(_, value)| match filter.external {
+                Some(external) => value.is_external() == external,
+                None => true,
+            })
+            .filter(|(_, value)| match filter.handshake_slower_than {
+                // the incomplete handshake cannot be measured
+                Some(ms) => value.handshake().total().map_or(false, |t| t > ms * 1_000_000),
+                None => true,
+            })
             .take(limit)
             .collect();
         Ok(vec)
@@ -347,8 +1167,10 @@ impl DatabaseFetch for Db {
         fn collect_it(
             it: impl Iterator<Item = ItItem>,
             limit: usize,
+            sender: Option<bool>,
         ) -> Vec<(chunk::Key, chunk::ValueTruncated)> {
             it.filter_map(|(k, v)| match (k, v) {
+                (Ok(key), _) if sender.map_or(false, |s| key.sender.incoming() != s) => None,
                 (Ok(key), Ok(value)) => Some((key, chunk::ValueTruncated(value))),
                 (Ok(index), Err(err)) => {
                     log::warn!("Failed to load value at {:?}: {}", index, err);
@@ -364,37 +1186,46 @@ impl DatabaseFetch for Db {
         }
 
         let limit = filter.limit.unwrap_or(100) as usize;
-        if let Some(connection_id) = &filter.cn {
-            let cn_id = connection_id
-                .parse()
-                .map_err(|e: connection::KeyFromStrError| DBError::SchemaError {
-                    error: SchemaError::DecodeValidationError(e.to_string()),
+        let begin = match &filter.cn {
+            Some(connection_id) => {
+                let cn_id = connection_id.parse().map_err(|e: connection::KeyFromStrError| {
+                    DBError::SchemaError {
+                        error: SchemaError::DecodeValidationError(e.to_string()),
+                    }
                 })?;
-            let k = chunk::Key::begin(cn_id);
-            let k_bytes = k.encode().map_err(|error| DBError::SchemaError { error })?;
-            let mode = rocksdb::IteratorMode::From(&k_bytes, rocksdb::Direction::Forward);
+                let k = chunk::Key::begin(cn_id);
+                Some(k.encode().map_err(|error| DBError::SchemaError { error })?)
+            },
+            None => None,
+        };
+        // the shards are merged in the key order
+        let mut iters = Vec::new();
+        for slot in self.shards.slots() {
+            let cf = self.shards.chunk_cf(&self.inner, slot)?;
             let mut opts = ReadOptions::default();
-            opts.set_prefix_same_as_start(true);
-            let cf = self.inner.cf_handle(chunk::Schema::name()).ok_or(
-                DBError::MissingColumnFamily {
-                    name: chunk::Schema::name(),
+            let mode = match &begin {
+                Some(k_bytes) => {
+                    opts.set_prefix_same_as_start(true);
+                    rocksdb::IteratorMode::From(k_bytes, rocksdb::Direction::Forward)
                 },
-            )?;
-            let it = self
-                .inner
-                .iterator_cf_opt(cf, opts, mode)
-                .map(|(k, v)| (chunk::Key::decode(&k), chunk::Value::decode(&v)));
-            Ok(collect_it(it, limit))
-        } else {
-            let it = self
-                .as_kv::<chunk::Schema>()
-                .iterator(IteratorMode::Start)?;
-            Ok(collect_it(it, limit))
+                None => rocksdb::IteratorMode::Start,
+            };
+            iters.push(self.inner.iterator_cf_opt(cf, opts, mode));
+        }
+        let it = iters
+            .into_iter()
+            .kmerge_by(|(a, _), (b, _)| a < b)
+            .map(|(k, v)| (chunk::Key::decode(&k), chunk::Value::decode(&v)));
+        let sender = filter.sender.as_ref().map(|s| s.incoming());
+        let mut chunks = collect_it(it, limit, sender);
+        for (key, chunk::ValueTruncated(value)) in &mut chunks {
+            self.resolve_chunk(key, value)?;
         }
+        Ok(chunks)
     }
 
     fn fetch_chunk(&self, key: &chunk::Key) -> Result<Option<chunk::Value>, Self::Error> {
-        self.as_kv::<chunk::Schema>().get(&key).map_err(Into::into)
+        Ok(self.chunk_resolved(key)?)
     }
 
     fn fetch_messages(
@@ -402,6 +1233,9 @@ impl DatabaseFetch for Db {
         filter: &MessagesFilter,
     ) -> Result<Vec<message::MessageFrontend>, Self::Error> {
         let limit = filter.limit.unwrap_or(100) as usize;
+        let preview = filter.preview().map_err(|e| DBError::SchemaError {
+            error: SchemaError::DecodeValidationError(e),
+        })?;
 
         let forward = filter.direction == Some("forward".to_string());
         let direction = || {
@@ -419,44 +1253,36 @@ impl DatabaseFetch for Db {
             && filter.from.is_none()
             && filter.to.is_none()
             && filter.timestamp.is_none()
+            && filter.block_hash.is_none()
+            && filter.operation_hash.is_none()
+            && filter.protocol_hash.is_none()
         {
-            let mode = if let Some(cursor) = &filter.cursor {
-                IteratorMode::From(cursor, direction())
-            } else {
-                if forward {
-                    IteratorMode::Start
-                } else {
-                    IteratorMode::End
-                }
+            let cursor = match &filter.cursor {
+                Some(cursor) => Some(
+                    cursor
+                        .encode()
+                        .map_err(|error| DBError::SchemaError { error })?,
+                ),
+                None => None,
             };
-            let v = self
-                .as_kv::<message::Schema>()
-                .iterator(mode)?
+            // the shards are merged in the index order
+            let mut iters = Vec::new();
+            for slot in self.shards.slots() {
+                let mode = match &cursor {
+                    Some(cursor) => rocksdb::IteratorMode::From(cursor, direction().into()),
+                    None if forward => rocksdb::IteratorMode::Start,
+                    None => rocksdb::IteratorMode::End,
+                };
+                let cf = self.shards.message_cf(&self.inner, slot)?;
+                iters.push(self.inner.iterator_cf(cf, mode));
+            }
+            let mut v = iters
+                .into_iter()
+                .kmerge_by(|(a, _), (b, _)| (a < b) == forward)
+                .map(|(k, v)| (u64::decode(&k), message::Item::decode(&v)))
                 .take(limit)
                 .filter_map(|(k, v)| match (k, v) {
-                    (Ok(key), Ok(value)) => {
-                        let preview = match details(&value, key, self.as_kv()) {
-                            Ok(details) => match details.json_string() {
-                                Ok(p) => p.map(|mut s| {
-                                    utf8_truncate(&mut s, 100);
-                                    s
-                                }),
-                                Err(error) => {
-                                    log::error!(
-                                        "Failed to deserialize message {:?}, error: {}",
-                                        value,
-                                        error
-                                    );
-                                    None
-                                },
-                            },
-                            Err(error) => {
-                                log::error!("Failed to chunks for {:?}, error: {}", value, error);
-                                None
-                            },
-                        };
-                        Some(message::MessageFrontend::new(value, key, preview))
-                    },
+                    (Ok(key), Ok(value)) => Some(frontend(value, key, self, preview)),
                     (Ok(index), Err(err)) => {
                         log::warn!("Failed to load value at {:?}: {}", index, err);
                         None
@@ -466,23 +1292,25 @@ impl DatabaseFetch for Db {
                         None
                     },
                 })
-                .collect();
+                .collect::<Vec<_>>();
 
-            Ok(v)
+            let annotations = self.annotations()?;
+            v.iter_mut().for_each(|m| m.annotate(&annotations));
+            self.with_archived(filter, v)
         } else {
             let cursor = filter
                 .cursor
                 .clone()
                 .unwrap_or(if forward { 0 } else { u64::MAX });
-            let mut iters: Vec<Box<dyn Iterator<Item = u64>>> = Vec::with_capacity(5);
+            let mut iters: Vec<Box<dyn Iterator<Item = u64>>> = Vec::with_capacity(8);
             if let Some(ty) = &filter.types {
+                let types = common::MessageType::parse_list(ty).map_err(|e| {
+                    DBError::SchemaError {
+                        error: SchemaError::DecodeValidationError(e.to_string()),
+                    }
+                })?;
                 let mut tys = Vec::new();
-                for ty in ty.split(',') {
-                    let ty =
-                        ty.parse::<common::MessageType>()
-                            .map_err(|e| DBError::SchemaError {
-                                error: SchemaError::DecodeValidationError(e.to_string()),
-                            })?;
+                for ty in types {
                     let key = message_ty::Item { ty, index: cursor };
                     let key = key
                         .encode()
@@ -502,7 +1330,7 @@ impl DatabaseFetch for Db {
                         .filter_map(|(k, _)| Some(message_ty::Item::decode(&k).ok()?.index));
                     tys.push(it);
                 }
-                iters.push(Box::new(tys.into_iter().kmerge_by(|x, y| x > y)));
+                iters.push(Box::new(tys.into_iter().kmerge_by(move |x, y| (x < y) == forward)));
             }
             if let Some(sender) = &filter.incoming {
                 let sender = common::Sender::new(*sender);
@@ -579,6 +1407,42 @@ impl DatabaseFetch for Db {
                     .filter_map(|(k, _)| Some(message_addr::Item::decode(&k).ok()?.index));
                 iters.push(Box::new(it));
             }
+            let hashes = [
+                (message_hash::HashKind::Block, &filter.block_hash),
+                (message_hash::HashKind::Operation, &filter.operation_hash),
+                (message_hash::HashKind::Protocol, &filter.protocol_hash),
+            ];
+            for (kind, hash) in hashes.iter() {
+                let hash = match hash {
+                    Some(hash) => message_hash::ContentHash::parse(*kind, hash).map_err(|e| {
+                        DBError::SchemaError {
+                            error: SchemaError::DecodeValidationError(e),
+                        }
+                    })?,
+                    None => continue,
+                };
+                let key = message_hash::Item {
+                    hash,
+                    index: cursor,
+                };
+                let key = key
+                    .encode()
+                    .map_err(|error| DBError::SchemaError { error })?;
+                let mode = rocksdb::IteratorMode::From(&key, direction().into());
+                let cf = self
+                    .inner
+                    .cf_handle(message_hash::Schema::name())
+                    .ok_or_else(|| DBError::MissingColumnFamily {
+                        name: message_hash::Schema::name(),
+                    })?;
+                let mut opts = ReadOptions::default();
+                opts.set_prefix_same_as_start(true);
+                let it = self
+                    .inner
+                    .iterator_cf_opt(cf, opts, mode)
+                    .filter_map(|(k, _)| Some(message_hash::Item::decode(&k).ok()?.index));
+                iters.push(Box::new(it));
+            }
             if filter.from.is_some() || filter.to.is_some() {
                 let mut timestamp = timestamp::Item {
                     timestamp: u64::MAX,
@@ -621,37 +1485,11 @@ impl DatabaseFetch for Db {
                 iters.push(Box::new(it));
             }
 
-            let v = sorted_intersect(iters.as_mut_slice(), limit, forward)
+            let mut v = sorted_intersect(iters.as_mut_slice(), limit, forward, &filter.deadline)
                 .into_iter()
                 .filter_map(
-                    move |index| match self.as_kv::<message::Schema>().get(&index) {
-                        Ok(Some(value)) => {
-                            let preview = match details(&value, index, self.as_kv()) {
-                                Ok(details) => match details.json_string() {
-                                    Ok(p) => p.map(|mut s| {
-                                        utf8_truncate(&mut s, 100);
-                                        s
-                                    }),
-                                    Err(error) => {
-                                        log::error!(
-                                            "Failed to deserialize message {:?}, error: {}",
-                                            value,
-                                            error
-                                        );
-                                        None
-                                    },
-                                },
-                                Err(error) => {
-                                    log::error!(
-                                        "Failed to chunks for {:?}, error: {}",
-                                        value,
-                                        error
-                                    );
-                                    None
-                                },
-                            };
-                            Some(message::MessageFrontend::new(value, index, preview))
-                        },
+                    move |index| match self.message_shard(index) {
+                        Ok(Some((_, value))) => Some(frontend(value, index, self, preview)),
                         Ok(None) => {
                             log::info!("No value at index: {}", index);
                             None
@@ -662,14 +1500,20 @@ impl DatabaseFetch for Db {
                         },
                     },
                 )
-                .collect();
-            Ok(v)
+                .collect::<Vec<_>>();
+            let annotations = self.annotations()?;
+            v.iter_mut().for_each(|m| m.annotate(&annotations));
+            self.with_archived(filter, v)
         }
     }
 
     fn fetch_message(&self, id: u64) -> Result<Option<message::MessageDetails>, Self::Error> {
-        if let Some(brief) = self.as_kv::<message::Schema>().get(&id)? {
-            details(&brief, id, self.as_kv()).map(Some)
+        if let Some((_, brief)) = self.message_shard(id)? {
+            details(&brief, id, self).map(Some)
+        } else if let Some(archive) = &self.archive {
+            Ok(archive.message(id)?.map(|record| {
+                message::MessageDetails::new(id, &record.item.ty, &record.chunks, &self.decoders)
+            }))
         } else {
             Ok(None)
         }
@@ -688,7 +1532,7 @@ impl DatabaseFetch for Db {
         };
 
         if let Some(query) = &filter.query {
-            let result = self
+            let mut result = self
                 .log_indexer
                 .as_ref()
                 .ok_or(DbError::NoLogIndexer)?
@@ -706,11 +1550,14 @@ impl DatabaseFetch for Db {
                         },
                     },
                 )
-                .collect();
+                .collect::<Vec<_>>();
+            let annotations = self.annotations()?;
+            result.iter_mut().for_each(|m| m.annotate(&annotations));
             return Ok(result);
         }
 
         if filter.log_level.is_none()
+            && filter.module.is_none()
             && filter.from.is_none()
             && filter.to.is_none()
             && filter.timestamp.is_none()
@@ -724,7 +1571,7 @@ impl DatabaseFetch for Db {
                     IteratorMode::End
                 }
             };
-            let vec = self
+            let mut vec = self
                 .as_kv::<node_log::Schema>()
                 .iterator(mode)?
                 .filter_map(|(k, v)| match (k, v) {
@@ -739,7 +1586,9 @@ impl DatabaseFetch for Db {
                     },
                 })
                 .take(limit)
-                .collect();
+                .collect::<Vec<_>>();
+            let annotations = self.annotations()?;
+            vec.iter_mut().for_each(|m| m.annotate(&annotations));
             Ok(vec)
         } else {
             let mut iters: Vec<Box<dyn Iterator<Item = u64>>> = Vec::with_capacity(5);
@@ -777,6 +1626,74 @@ impl DatabaseFetch for Db {
                 }
                 iters.push(Box::new(lvs.into_iter().kmerge_by(|x, y| x > y)));
             }
+            if let Some(module) = &filter.module {
+                let cursor = filter
+                    .cursor
+                    .clone()
+                    .unwrap_or(if forward { 0 } else { u64::MAX });
+                let mut modules = Vec::new();
+                for module in module.split(',') {
+                    let key = log_module::Item::new(module.trim(), cursor);
+                    let key = key
+                        .encode()
+                        .map_err(|error| DBError::SchemaError { error })?;
+                    let mode = rocksdb::IteratorMode::From(&key, direction().into());
+                    let cf = self
+                        .inner
+                        .cf_handle(log_module::Schema::name())
+                        .ok_or_else(|| DBError::MissingColumnFamily {
+                            name: log_module::Schema::name(),
+                        })?;
+                    let mut opts = ReadOptions::default();
+                    opts.set_prefix_same_as_start(true);
+                    let it = self
+                        .inner
+                        .iterator_cf_opt(cf, opts, mode)
+                        .filter_map(|(k, _)| Some(log_module::Item::decode(&k).ok()?.index));
+                    modules.push(it);
+                }
+                let it = modules
+                    .into_iter()
+                    .kmerge_by(move |x, y| (x < y) == forward)
+                    .dedup();
+                iters.push(Box::new(it));
+            }
+            let hashes = [
+                (message_hash::HashKind::Block, &filter.block_hash),
+                (message_hash::HashKind::Operation, &filter.operation_hash),
+                (message_hash::HashKind::Protocol, &filter.protocol_hash),
+            ];
+            for (kind, hash) in hashes.iter() {
+                let hash = match hash {
+                    Some(hash) => message_hash::ContentHash::parse(*kind, hash).map_err(|e| {
+                        DBError::SchemaError {
+                            error: SchemaError::DecodeValidationError(e),
+                        }
+                    })?,
+                    None => continue,
+                };
+                let key = message_hash::Item {
+                    hash,
+                    index: cursor,
+                };
+                let key = key
+                    .encode()
+                    .map_err(|error| DBError::SchemaError { error })?;
+                let mode = rocksdb::IteratorMode::From(&key, direction().into());
+                let cf = self
+                    .inner
+                    .cf_handle(message_hash::Schema::name())
+                    .ok_or_else(|| DBError::MissingColumnFamily {
+                        name: message_hash::Schema::name(),
+                    })?;
+                let mut opts = ReadOptions::default();
+                opts.set_prefix_same_as_start(true);
+                let it = self
+                    .inner
+                    .iterator_cf_opt(cf, opts, mode)
+                    .filter_map(|(k, _)| Some(message_hash::Item::decode(&k).ok()?.index));
+                iters.push(Box::new(it));
+            }
             if filter.from.is_some() || filter.to.is_some() {
                 let mut timestamp = timestamp::Item {
                     timestamp: u64::MAX,
@@ -819,7 +1736,7 @@ impl DatabaseFetch for Db {
                 iters.push(Box::new(it));
             }
 
-            let v = sorted_intersect(iters.as_mut_slice(), limit, forward)
+            let mut v = sorted_intersect(iters.as_mut_slice(), limit, forward, &filter.deadline)
                 .into_iter()
                 .filter_map(move |id| match self.as_kv::<node_log::Schema>().get(&id) {
                     Ok(Some(item)) => Some(node_log::ItemWithId::new(item, id)),
@@ -832,26 +1749,673 @@ impl DatabaseFetch for Db {
                         None
                     },
                 })
-                .collect();
+                .collect::<Vec<_>>();
+            let annotations = self.annotations()?;
+            v.iter_mut().for_each(|m| m.annotate(&annotations));
             Ok(v)
         }
     }
+
+    fn fetch_log_counts(
+        &self,
+        filter: &LogCountsFilter,
+    ) -> Result<Vec<log_count::LevelCounts>, Self::Error> {
+        let begin = log_count::Item::new(filter.from.unwrap_or(0), node_log::LogLevel::Trace);
+        let end = filter.to.map(|to| to / 60_000).unwrap_or(u64::MAX);
+
+        let mut counts = Vec::<log_count::LevelCounts>::new();
+        let it = self
+            .as_kv::<log_count::Schema>()
+            .iterator(IteratorMode::From(&begin, Direction::Forward))?
+            .filter_map(|(k, v)| Some((k.ok()?, v.ok()?)))
+            .take_while(|(k, _)| k.minute <= end);
+        for (key, log_count::Count(count)) in it {
+            match counts.last_mut() {
+                Some(last) if last.minute == key.minute * 60_000 => last.add(key.lv, count),
+                _ => {
+                    let mut item = log_count::LevelCounts::new(key.minute);
+                    item.add(key.lv, count);
+                    counts.push(item);
+                },
+            }
+        }
+        Ok(counts)
+    }
+
+    fn fetch_bandwidth(
+        &self,
+        filter: &BandwidthFilter,
+    ) -> Result<Vec<bandwidth::Series>, Self::Error> {
+        let invalid = |e: String| DBError::SchemaError {
+            error: SchemaError::DecodeValidationError(e),
+        };
+        let types = match &filter.types {
+            Some(types) => Some(
+                common::MessageType::parse_list(types)
+                    .map_err(|e| invalid(e.to_string()))?
+                    .into_iter()
+                    .map(|ty| ty.into_int())
+                    .collect::<HashSet<_>>(),
+            ),
+            None => None,
+        };
+        let addr = match &filter.remote_addr {
+            Some(addr) => Some(
+                addr.parse::<SocketAddr>()
+                    .map_err(|e| invalid(e.to_string()))?,
+            ),
+            None => None,
+        };
+        let (mut by_direction, mut by_type, mut by_peer) = (false, false, false);
+        for group in filter.group_by.as_deref().unwrap_or("direction,type").split(',') {
+            match group {
+                "direction" => by_direction = true,
+                "type" => by_type = true,
+                "peer" => by_peer = true,
+                "" => (),
+                group => return Err(invalid(format!("cannot group by {:?}", group)).into()),
+            }
+        }
+
+        // the least key of the minute
+        let begin = bandwidth::Item::new(
+            filter.from.unwrap_or(0),
+            false,
+            common::MessageType::Connection,
+            SocketAddr::from(([0; 16], 0)),
+        );
+        let end = filter.to.map(|to| to / 60_000).unwrap_or(u64::MAX);
+        let mut series = BTreeMap::<String, BTreeMap<u64, u64>>::new();
+        let it = self
+            .as_kv::<bandwidth::Schema>()
+            .iterator(IteratorMode::From(&begin, Direction::Forward))?
+            .filter_map(|(k, v)| Some((k.ok()?, v.ok()?)))
+            .take_while(|(k, _)| k.minute <= end);
+        for (key, log_count::Count(bytes)) in it {
+            if filter.incoming.map_or(false, |incoming| incoming != key.incoming)
+                || types.as_ref().map_or(false, |t| !t.contains(&key.ty.clone().into_int()))
+                || addr.map_or(false, |addr| addr != key.addr)
+            {
+                continue;
+            }
+            let mut target = vec![];
+            if by_direction {
+                let direction = if key.incoming { "incoming" } else { "outgoing" };
+                target.push(direction.to_string());
+            }
+            if by_type {
+                target.push(key.type_name());
+            }
+            if by_peer {
+                target.push(key.addr.to_string());
+            }
+            let target = if target.is_empty() {
+                "total".to_string()
+            } else {
+                target.join("/")
+            };
+            *series
+                .entry(target)
+                .or_default()
+                .entry(key.minute * 60_000)
+                .or_default() += bytes.max(0) as u64;
+        }
+        let series = series
+            .into_iter()
+            .map(|(target, points)| bandwidth::Series {
+                target,
+                datapoints: points.into_iter().map(|(ts, bytes)| (bytes, ts)).collect(),
+            })
+            .collect();
+        Ok(series)
+    }
+
+    fn fetch_peer(&self, pk: &[u8; 32]) -> Result<Option<peer::Details>, Self::Error> {
+        let value = match self.as_kv::<peer::Schema>().get(&peer::Key(*pk))? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let connections = value
+            .connection_keys()
+            .filter_map(|key| match self.as_kv::<connection::Schema>().get(&key) {
+                Ok(Some(cn)) => Some((key, cn)),
+                Ok(None) => None,
+                Err(err) => {
+                    log::warn!("Failed to load connection {}: {}", key, err);
+                    None
+                },
+            })
+            .collect();
+        let (ts, ts_nanos) = value.first_seen;
+        Ok(Some(peer::Details {
+            peer_id: peer::peer_id(pk).unwrap_or_else(|e| e),
+            public_key: hex::encode(pk),
+            first_seen: connection::Key { ts, ts_nanos },
+            addresses: value.addresses,
+            versions: value.versions,
+            connections,
+        }))
+    }
+
+    fn fetch_storage_stats(&self) -> Result<StorageStats, Self::Error> {
+        let estimate = |cf| {
+            self.inner
+                .property_int_value_cf(cf, "rocksdb.estimate-num-keys")
+                .map(Option::unwrap_or_default)
+                .map_err(|error| DBError::RocksDBError { error })
+        };
+        let mut messages = 0;
+        for slot in self.shards.slots() {
+            messages += estimate(self.shards.message_cf(&self.inner, slot)?)?;
+        }
+        let log_cf = self.inner.cf_handle(node_log::Schema::name()).ok_or(
+            DBError::MissingColumnFamily {
+                name: node_log::Schema::name(),
+            },
+        )?;
+        Ok(StorageStats {
+            p2p: StoreStats {
+                count: messages,
+                total: self.message_counter.load(Ordering::SeqCst),
+                limit: Self::limit(&self.message_store_limit),
+                dropped: self.shards.dropped(),
+            },
+            log: StoreStats {
+                count: estimate(log_cf)?,
+                total: self.log_counter.load(Ordering::SeqCst),
+                limit: Self::limit(&self.log_store_limit),
+                dropped: 0,
+            },
+            message_cache: self.message_cache.stats(),
+        })
+    }
+
+    fn store_annotation(&self, mut item: annotation::Item) -> Result<Option<u64>, Self::Error> {
+        if let Some(message_id) = item.message_id {
+            self.batcher.flush(&self.inner)?;
+            match self.message_shard(message_id)? {
+                Some((_, message)) => {
+                    item.from = Some(message.timestamp);
+                    item.to = Some(message.timestamp);
+                },
+                None => return Ok(None),
+            }
+        }
+        let id = self.annotation_counter.fetch_add(1, Ordering::SeqCst);
+        self.as_kv::<annotation::Schema>().put(&id, &item)?;
+        Ok(Some(id))
+    }
+
+    fn fetch_annotations(
+        &self,
+        filter: &AnnotationsFilter,
+    ) -> Result<Vec<annotation::ItemWithId>, Self::Error> {
+        let (from, to) = (filter.from.unwrap_or(0), filter.to.unwrap_or(u64::MAX));
+        let v = self
+            .annotations()?
+            .into_iter()
+            .filter(|a| filter.message_id.map_or(true, |id| a.item.message_id == Some(id)))
+            .filter(|a| filter.label.as_ref().map_or(true, |label| &a.item.label == label))
+            .filter(|a| {
+                let begin = a.item.from.unwrap_or(0);
+                begin <= to && a.item.to.unwrap_or(begin) >= from
+            })
+            .collect();
+        Ok(v)
+    }
+
+    fn store_preset(&self, name: &str, item: preset::Item) -> Result<bool, Self::Error> {
+        let key = preset::Key(name.to_string());
+        let kv = self.as_kv::<preset::Schema>();
+        let replaced = kv.get(&key)?.is_some();
+        kv.put(&key, &item)?;
+        Ok(replaced)
+    }
+
+    fn fetch_presets(&self) -> Result<Vec<preset::ItemWithName>, Self::Error> {
+        let v = self
+            .as_kv::<preset::Schema>()
+            .iterator(IteratorMode::Start)?
+            .filter_map(|(key, value)| match (key, value) {
+                (Ok(preset::Key(name)), Ok(item)) => Some(preset::ItemWithName { name, item }),
+                (Ok(preset::Key(name)), Err(err)) => {
+                    log::warn!("Failed to load preset {}: {}", name, err);
+                    None
+                },
+                (Err(err), _) => {
+                    log::warn!("Failed to load preset name: {}", err);
+                    None
+                },
+            })
+            .collect();
+        Ok(v)
+    }
+
+    fn fetch_preset(&self, name: &str) -> Result<Option<preset::Item>, Self::Error> {
+        Ok(self.as_kv::<preset::Schema>().get(&preset::Key(name.to_string()))?)
+    }
+
+    fn remove_preset(&self, name: &str) -> Result<bool, Self::Error> {
+        let key = preset::Key(name.to_string());
+        let kv = self.as_kv::<preset::Schema>();
+        if kv.get(&key)?.is_none() {
+            return Ok(false);
+        }
+        kv.delete(&key)?;
+        Ok(true)
+    }
+
+    fn store_incident(&self, id: Option<u64>, item: incident::Item) -> Result<u64, Self::Error> {
+        let id = id.unwrap_or_else(|| self.incident_counter.fetch_add(1, Ordering::SeqCst));
+        self.as_kv::<incident::Schema>().put(&id, &item)?;
+        Ok(id)
+    }
+
+    fn fetch_incidents(
+        &self,
+        filter: &IncidentsFilter,
+    ) -> Result<Vec<incident::ItemWithId>, Self::Error> {
+        let (from, to) = (filter.from.unwrap_or(0), filter.to.unwrap_or(u64::MAX));
+        let limit = filter.limit.unwrap_or(100) as usize;
+        let v = self
+            .as_kv::<incident::Schema>()
+            .iterator(IteratorMode::End)?
+            .filter_map(|(k, v)| match (k, v) {
+                (Ok(id), Ok(item)) => Some(incident::ItemWithId { id, item }),
+                (Ok(index), Err(err)) => {
+                    log::warn!("Failed to load incident at {:?}: {}", index, err);
+                    None
+                },
+                (Err(err), _) => {
+                    log::warn!("Failed to load incident index: {}", err);
+                    None
+                },
+            })
+            .filter(|i| filter.range.as_ref().map_or(true, |range| &i.item.range == range))
+            .filter(|i| i.item.overlaps(from, to))
+            .take(limit)
+            .collect();
+        Ok(v)
+    }
+
+    fn store_gap(&self, id: Option<u64>, item: gap::Item) -> Result<u64, Self::Error> {
+        let id = id.unwrap_or_else(|| self.gap_counter.fetch_add(1, Ordering::SeqCst));
+        self.as_kv::<gap::Schema>().put(&id, &item)?;
+        Ok(id)
+    }
+
+    fn fetch_gaps(&self, filter: &GapsFilter) -> Result<Vec<gap::ItemWithId>, Self::Error> {
+        let (from, to) = (filter.from.unwrap_or(0), filter.to.unwrap_or(u64::MAX));
+        let v = self
+            .as_kv::<gap::Schema>()
+            .iterator(IteratorMode::Start)?
+            .filter_map(|(k, v)| match (k, v) {
+                (Ok(id), Ok(item)) => Some(gap::ItemWithId { id, item }),
+                (Ok(index), Err(err)) => {
+                    log::warn!("Failed to load gap at {:?}: {}", index, err);
+                    None
+                },
+                (Err(err), _) => {
+                    log::warn!("Failed to load gap index: {}", err);
+                    None
+                },
+            })
+            .filter(|g| g.item.overlaps(from, to))
+            .collect();
+        Ok(v)
+    }
+
+    fn store_heartbeat(&self, timestamp: u64) -> Result<(), Self::Error> {
+        let key = gap::HeartbeatSchema::KEY;
+        self.as_kv::<gap::HeartbeatSchema>().put(&key, &timestamp)?;
+        Ok(())
+    }
+
+    fn fetch_heartbeat(&self) -> Result<Option<u64>, Self::Error> {
+        let key = gap::HeartbeatSchema::KEY;
+        Ok(self.as_kv::<gap::HeartbeatSchema>().get(&key)?)
+    }
+
+    fn store_findings(&self, items: Vec<finding::Item>) -> Result<(), Self::Error> {
+        for item in items {
+            let id = self.finding_counter.fetch_add(1, Ordering::SeqCst);
+            self.as_kv::<finding::Schema>().put(&id, &item)?;
+        }
+        Ok(())
+    }
+
+    fn fetch_findings(
+        &self,
+        filter: &FindingsFilter,
+    ) -> Result<Vec<finding::ItemWithId>, Self::Error> {
+        let limit = filter.limit.unwrap_or(100) as usize;
+        let v = self
+            .as_kv::<finding::Schema>()
+            .iterator(IteratorMode::End)?
+            .filter_map(|(k, v)| match (k, v) {
+                (Ok(id), Ok(item)) => Some(finding::ItemWithId { id, item }),
+                (Ok(index), Err(err)) => {
+                    log::warn!("Failed to load finding at {:?}: {}", index, err);
+                    None
+                },
+                (Err(err), _) => {
+                    log::warn!("Failed to load finding index: {}", err);
+                    None
+                },
+            })
+            .filter(|f| filter.connection.as_ref().map_or(true, |c| &f.item.connection == c))
+            .filter(|f| filter.rule.map_or(true, |rule| f.item.rule == rule))
+            .take(limit)
+            .collect();
+        Ok(v)
+    }
+
+    fn start_session(&self, info: session::Info) -> Result<Option<u64>, Self::Error> {
+        let _guard = self.session_lock.lock().unwrap();
+        if self.running_session()?.is_some() {
+            return Ok(None);
+        }
+        let id = self
+            .sessions()?
+            .last()
+            .map(|s| s.id + 1)
+            .unwrap_or(0);
+        let item = session::Item::new(
+            info,
+            self.message_counter.load(Ordering::SeqCst),
+            self.log_counter.load(Ordering::SeqCst),
+        );
+        self.as_kv::<session::Schema>().put(&id, &item)?;
+        log::info!("session {} {:?} started", id, item.info.name);
+        Ok(Some(id))
+    }
+
+    fn stop_session(&self) -> Result<Option<u64>, Self::Error> {
+        let _guard = self.session_lock.lock().unwrap();
+        match self.running_session()? {
+            Some(session::ItemWithId { id, mut item }) => {
+                item.stop(
+                    self.message_counter.load(Ordering::SeqCst),
+                    self.log_counter.load(Ordering::SeqCst),
+                );
+                self.as_kv::<session::Schema>().put(&id, &item)?;
+                log::info!("session {} {:?} stopped", id, item.info.name);
+                Ok(Some(id))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn fetch_sessions(&self) -> Result<Vec<session::ItemWithId>, Self::Error> {
+        Ok(self.sessions()?)
+    }
+
+    fn fetch_session_export(&self, id: u64) -> Result<Option<session::Export>, Self::Error> {
+        let item = match self.as_kv::<session::Schema>().get(&id)? {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        // the records removed by the store limit or by the retention are missing
+        let mut messages = Vec::new();
+        for index in item.message_range(self.message_counter.load(Ordering::SeqCst)) {
+            if let Some((_, brief)) = self.message_shard(index)? {
+                messages.push(details(&brief, index, self)?);
+            }
+        }
+        let mut logs = Vec::new();
+        for index in item.log_range(self.log_counter.load(Ordering::SeqCst)) {
+            if let Some(log) = self.as_kv::<node_log::Schema>().get(&index)? {
+                logs.push(node_log::ItemWithId::new(log, index));
+            }
+        }
+        Ok(Some(session::Export {
+            session: session::ItemWithId { id, item },
+            messages,
+            logs,
+        }))
+    }
+
+    fn fetch_session_handshakes(
+        &self,
+        id: u64,
+    ) -> Result<Option<Vec<session::Handshake>>, Self::Error> {
+        let item = match self.as_kv::<session::Schema>().get(&id)? {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        // the connection message is the first message of each side of the connection
+        let mut handshakes = BTreeMap::new();
+        for index in item.message_range(self.message_counter.load(Ordering::SeqCst)) {
+            let brief = match self.message_shard(index)? {
+                Some((_, brief)) => brief,
+                None => continue,
+            };
+            if !matches!(brief.ty, common::MessageType::Connection) {
+                continue;
+            }
+            let cn_id = brief.cn_id();
+            let handshake = handshakes
+                .entry((cn_id.ts, cn_id.ts_nanos))
+                .or_insert_with(|| session::Handshake {
+                    cn_id,
+                    remote_addr: brief.remote_addr,
+                    initiator: brief.initiator.clone(),
+                    local: vec![],
+                    remote: vec![],
+                });
+            let mut bytes = vec![];
+            for key in brief.chunks() {
+                if let Some((_, chunk)) = self.chunk_shard(&key)? {
+                    bytes.extend_from_slice(&chunk.bytes);
+                }
+            }
+            if brief.sender.incoming() {
+                handshake.remote = bytes;
+            } else {
+                handshake.local = bytes;
+            }
+        }
+        Ok(Some(
+            handshakes
+                .into_iter()
+                .map(|(_, handshake)| handshake)
+                .filter(|h| !h.local.is_empty() && !h.remote.is_empty())
+                .collect(),
+        ))
+    }
+
+    fn fetch_conversation(
+        &self,
+        cn: &connection::Key,
+    ) -> Result<Option<Conversation>, Self::Error> {
+        // the longest scenario, the connection might live for days
+        const MAX_MESSAGES: usize = 0x10000;
+
+        let value = match self.as_kv::<connection::Schema>().get(cn)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        // the timestamps of the index are milliseconds
+        let begin = timestamp::Item {
+            timestamp: cn.ts * 1_000 + (cn.ts_nanos / 1_000_000) as u64,
+            index: 0,
+        };
+        let end = value
+            .termination()
+            .map_or(u64::MAX, |t| t.timestamp / 1_000_000 + 1_000);
+        let mut items = Vec::new();
+        let it = self
+            .as_kv::<timestamp::MessageSchema>()
+            .iterator(IteratorMode::From(&begin, Direction::Forward))?
+            .filter_map(|(k, _)| k.ok())
+            .take_while(|k| k.timestamp <= end);
+        for k in it {
+            if items.len() == MAX_MESSAGES {
+                break;
+            }
+            if let Some((_, brief)) = self.message_shard(k.index)? {
+                let id = brief.cn_id();
+                if (id.ts, id.ts_nanos) == (cn.ts, cn.ts_nanos) {
+                    items.push((k.index, brief));
+                }
+            }
+        }
+        items.sort_by_key(|(index, _)| *index);
+
+        let mut messages = Vec::with_capacity(items.len());
+        for (index, brief) in items {
+            let plain = chunks(&brief, index, self)?
+                .into_iter()
+                .map(|c| c.plain)
+                .collect::<Vec<_>>()
+                .concat();
+            messages.push((brief, plain));
+        }
+        Ok(Some(Conversation::new(cn, &value, messages)))
+    }
+
+    fn remove_session(&self, id: u64) -> Result<bool, Self::Error> {
+        let _guard = self.session_lock.lock().unwrap();
+        let item = match self.as_kv::<session::Schema>().get(&id)? {
+            Some(item) => item,
+            None => return Ok(false),
+        };
+        // the running session is stopped by the removal
+        self.as_kv::<session::Schema>().delete(&id)?;
+        self.batcher.flush(&self.inner)?;
+        for index in item.message_range(self.message_counter.load(Ordering::SeqCst)) {
+            self.remove_message(index)?;
+        }
+        for index in item.log_range(self.log_counter.load(Ordering::SeqCst)) {
+            self.remove_log(index)?;
+        }
+        log::info!("session {} {:?} removed", id, item.info.name);
+        Ok(true)
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<(), Self::Error> {
+        use rocksdb::checkpoint::Checkpoint;
+
+        self.batcher.flush(&self.inner)?;
+        Checkpoint::new(&self.inner)
+            .and_then(|checkpoint| checkpoint.create_checkpoint(path))
+            .map_err(|error| DBError::RocksDBError { error }.into())
+    }
 }
 
-fn details(
-    message_item: &message::Item,
-    id: u64,
-    db: &(impl KeyValueStoreBackend<chunk::Schema> + KeyValueStoreWithSchemaIterator<chunk::Schema>),
-) -> Result<message::MessageDetails, DbError> {
+/// The brief of the message with the beginning of its json as the preview,
+/// it is cached, the stored message never changes until it is removed,
+/// without the `preview` the message is not decoded and the brief is not cached
+fn frontend(value: message::Item, index: u64, db: &Db, preview: bool) -> message::MessageFrontend {
+    if preview {
+        return db
+            .message_cache
+            .get_or_insert_with(index, || frontend_uncached(value, index, db));
+    }
+    if let Some(cached) = db.message_cache.get(index) {
+        return cached;
+    }
+    let size = match chunks(&value, index, db) {
+        Ok(chunks) => chunks.iter().map(|c| c.plain.len()).sum(),
+        Err(error) => {
+            log::error!("Failed to chunks for {:?}, error: {}", value, error);
+            0
+        },
+    };
+    message::MessageFrontend::new(value, index, None, size)
+}
+
+fn frontend_uncached(value: message::Item, index: u64, db: &Db) -> message::MessageFrontend {
+    let (preview, size) = match details(&value, index, db) {
+        Ok(details) => match details.json_string() {
+            Ok(p) => {
+                let preview = p.map(|mut s| {
+                    utf8_truncate(&mut s, 100);
+                    s
+                });
+                (preview, details.size())
+            },
+            Err(error) => {
+                log::error!("Failed to deserialize message {:?}, error: {}", value, error);
+                (None, details.size())
+            },
+        },
+        Err(error) => {
+            log::error!("Failed to chunks for {:?}, error: {}", value, error);
+            (None, 0)
+        },
+    };
+    message::MessageFrontend::new(value, index, preview, size)
+}
+
+fn chunks(message_item: &message::Item, id: u64, db: &Db) -> Result<Vec<chunk::Value>, DbError> {
     let mut chunks = Vec::new();
     for key in message_item.chunks() {
-        if let Some(c) = db.get(&key)? {
+        if let Some(c) = db.chunk_resolved(&key)? {
             chunks.push(c);
         } else {
             break;
         }
     }
-    Ok(message::MessageDetails::new(id, &message_item.ty, &chunks))
+    // the archived message carries its chunks
+    if chunks.is_empty() {
+        if let Some(record) = db.archive.as_ref().map(|a| a.message(id)).transpose()?.flatten() {
+            chunks = record.chunks;
+        }
+    }
+    Ok(chunks)
+}
+
+fn details(
+    message_item: &message::Item,
+    id: u64,
+    db: &Db,
+) -> Result<message::MessageDetails, DbError> {
+    let chunks = chunks(message_item, id, db)?;
+    Ok(message::MessageDetails::new(
+        id,
+        &message_item.ty,
+        &chunks,
+        &db.decoders,
+    ))
+}
+
+fn put_cf<K, V>(db: &DB, cf: &ColumnFamily, key: &K, value: &V) -> Result<(), DBError>
+where
+    K: Encoder,
+    V: Encoder,
+{
+    let key = key.encode().map_err(|error| DBError::SchemaError { error })?;
+    let value = value.encode().map_err(|error| DBError::SchemaError { error })?;
+    db.put_cf(cf, key, value)
+        .map_err(|error| DBError::RocksDBError { error })
+}
+
+fn delete_cf<K>(db: &DB, cf: &ColumnFamily, key: &K) -> Result<(), DBError>
+where
+    K: Encoder,
+{
+    let key = key.encode().map_err(|error| DBError::SchemaError { error })?;
+    db.delete_cf(cf, key)
+        .map_err(|error| DBError::RocksDBError { error })
+}
+
+/// The encoded range of the secondary index keys pointing to the messages `first..=last`
+fn index_range<S, F>(
+    first: u64,
+    last: u64,
+    key: F,
+) -> Result<(&'static str, Vec<u8>, Vec<u8>), DBError>
+where
+    S: KeyValueSchema + RocksDbKeyValueSchema,
+    F: Fn(u64) -> S::Key,
+{
+    let encode = |index| {
+        key(index)
+            .encode()
+            .map_err(|error| DBError::SchemaError { error })
+    };
+    Ok((S::name(), encode(first)?, encode(last + 1)?))
 }
 
 fn utf8_truncate(input: &mut String, max_size: usize) {
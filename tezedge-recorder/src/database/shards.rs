@@ -0,0 +1,420 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use rocksdb::{Cache, ColumnFamily, ColumnFamilyDescriptor, DB, IteratorMode, Options, WriteBatch};
+use storage::persistent::{DBError, Decoder, database::RocksDbKeyValueSchema};
+use super::{chunk, message, tuning::RocksdbConfig};
+
+pub const DAY_MS: u64 = 86_400_000;
+const DAY_SECS: u64 = 86_400;
+
+// every key of the message and chunk storage is shorter
+const KEY_UPPER_BOUND: [u8; 0x40] = [0xff; 0x40];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Slot {
+    Empty,
+    Day(u64),
+    // the day is beyond the retention, the shard is being cleared
+    Expiring(u64),
+}
+
+impl Slot {
+    fn day(&self) -> Option<u64> {
+        match self {
+            Slot::Empty => None,
+            Slot::Day(day) | Slot::Expiring(day) => Some(*day),
+        }
+    }
+}
+
+/// The work the new day makes due, it is done off the write path
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Job {
+    /// the day is beyond the retention, the shard is cleared for a later day,
+    /// `finish` frees the shard after
+    Expire { slot: usize, day: u64 },
+}
+
+/// Messages and chunks are stored in a ring of column families, one per day of retention,
+/// one for the current day and a spare one. When a new day begins, the shard of the day
+/// beyond the retention is cleared in background by a single range deletion and compacted,
+/// instead of a tombstone per record, and becomes the spare for the next day.
+/// The day takes any free shard, so the shards survive the change of the retention.
+/// The column families are created at open, because the rocksdb binding can create
+/// and drop them only having exclusive access to the database.
+/// Without retention there is one shard, it is the plain `message_storage` and `chunk_storage`.
+pub struct Shards {
+    retention_days: Option<u64>,
+    // (message, chunk) column family names of each shard
+    names: Vec<(String, String)>,
+    slots: Mutex<Vec<Slot>>,
+    // the records of the expired days, or of the day which found no free shard
+    dropped: AtomicU64,
+}
+
+impl Shards {
+    pub fn new(retention_days: Option<u64>) -> Self {
+        let len = retention_days.map(|d| d as usize + 2).unwrap_or(1);
+        let names = (0..len)
+            .map(|slot| {
+                (
+                    Self::name(message::Schema::name(), slot),
+                    Self::name(chunk::Schema::name(), slot),
+                )
+            })
+            .collect();
+        Shards {
+            retention_days,
+            names,
+            slots: Mutex::new(vec![Slot::Empty; len]),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn name(base: &str, slot: usize) -> String {
+        if slot == 0 {
+            base.to_string()
+        } else {
+            format!("{}_shard_{}", base, slot)
+        }
+    }
+
+    pub fn descriptors(
+        &self,
+        tuning: &RocksdbConfig,
+        cache: &Cache,
+    ) -> Vec<ColumnFamilyDescriptor> {
+        let message_opts = || tuning.with_block_cache(Options::default(), cache);
+        let chunk_opts = || tuning.with_block_cache(chunk::Schema::options(), cache);
+        self.names
+            .iter()
+            .flat_map(|(message, chunk)| {
+                vec![
+                    tuning.descriptor(message, message_opts()),
+                    tuning.descriptor(chunk, chunk_opts()),
+                ]
+            })
+            .collect()
+    }
+
+    /// Shards left by a longer retention, rocksdb refuses to open the database
+    /// without them, so they are opened and dropped right after
+    pub fn stale(&self, path: &Path) -> Vec<String> {
+        let len = self.names.len();
+        DB::list_cf(&Options::default(), path)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|name| {
+                [message::Schema::name(), chunk::Schema::name()]
+                    .iter()
+                    .filter_map(|base| name.strip_prefix(base)?.strip_prefix("_shard_"))
+                    .any(|slot| slot.parse::<usize>().map(|s| s >= len).unwrap_or(false))
+            })
+            .collect()
+    }
+
+    /// Recovers the day of each shard from its first record
+    pub fn load(&self, db: &DB) -> Result<(), DBError> {
+        let mut days = Vec::with_capacity(self.names.len());
+        for slot in 0..self.names.len() {
+            let message_day = db
+                .iterator_cf(self.message_cf(db, slot)?, IteratorMode::Start)
+                .next()
+                .and_then(|(_, v)| message::Item::decode(&v).ok())
+                .map(|item| item.timestamp / DAY_MS);
+            let chunk_day = db
+                .iterator_cf(self.chunk_cf(db, slot)?, IteratorMode::Start)
+                .next()
+                .and_then(|(_, v)| chunk::Value::decode(&v).ok())
+                .map(|value| value.timestamp() / DAY_SECS);
+            days.push(message_day.or(chunk_day));
+        }
+        self.restore(days);
+        Ok(())
+    }
+
+    fn restore(&self, days: Vec<Option<u64>>) {
+        *self.slots.lock().unwrap() = days
+            .into_iter()
+            .map(|day| day.map_or(Slot::Empty, Slot::Day))
+            .collect();
+    }
+
+    /// The index following the last stored message
+    pub fn next_message_index(&self, db: &DB) -> Result<u64, DBError> {
+        let mut next = 0;
+        for slot in 0..self.names.len() {
+            let last = db
+                .iterator_cf(self.message_cf(db, slot)?, IteratorMode::End)
+                .next()
+                .and_then(|(k, _)| u64::decode(&k).ok());
+            if let Some(last) = last {
+                next = next.max(last + 1);
+            }
+        }
+        Ok(next)
+    }
+
+    /// The message and chunk column families of each shard
+    pub fn names(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.names.iter().map(|(m, c)| (m.as_str(), c.as_str()))
+    }
+
+    pub fn message_name(&self, slot: usize) -> &str {
+        &self.names[slot].0
+    }
+
+    pub fn chunk_name(&self, slot: usize) -> &str {
+        &self.names[slot].1
+    }
+
+    pub fn message_cf<'a>(&self, db: &'a DB, slot: usize) -> Result<&'a ColumnFamily, DBError> {
+        db.cf_handle(self.message_name(slot))
+            .ok_or_else(|| DBError::MissingColumnFamily {
+                name: message::Schema::name(),
+            })
+    }
+
+    pub fn chunk_cf<'a>(&self, db: &'a DB, slot: usize) -> Result<&'a ColumnFamily, DBError> {
+        db.cf_handle(self.chunk_name(slot))
+            .ok_or_else(|| DBError::MissingColumnFamily {
+                name: chunk::Schema::name(),
+            })
+    }
+
+    /// The shards from the newest day to the oldest, the queries fan out in this order
+    pub fn slots(&self) -> Vec<usize> {
+        let days = self.slots.lock().unwrap();
+        let mut slots = (0..days.len()).collect::<Vec<_>>();
+        slots.sort_by(|a, b| days[*b].day().cmp(&days[*a].day()));
+        slots
+    }
+
+    pub fn message_day(timestamp_ms: u64) -> u64 {
+        timestamp_ms / DAY_MS
+    }
+
+    pub fn chunk_day(timestamp_secs: u64) -> u64 {
+        timestamp_secs / DAY_SECS
+    }
+
+    /// How many records were dropped, because their day is beyond the retention,
+    /// or no shard was free for it
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The shard where the records of the day are written, and the jobs which are due,
+    /// if this is the first record of a new day. The shard is `None` if the day is beyond
+    /// the retention, or no shard is free yet, the record is dropped then.
+    pub fn acquire(&self, day: u64) -> (Option<usize>, Vec<Job>) {
+        let retention_days = match self.retention_days {
+            Some(retention_days) => retention_days,
+            None => return (Some(0), vec![]),
+        };
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(slot) = slots.iter().position(|s| *s == Slot::Day(day)) {
+            return (Some(slot), vec![]);
+        }
+        let latest = slots.iter().filter_map(Slot::day).max();
+        let jobs = match latest {
+            Some(latest) if latest > day => vec![],
+            _ => Self::expire(&mut slots, retention_days, day),
+        };
+        let expired = latest.map_or(false, |latest| day + retention_days < latest);
+        let free = slots.iter().position(|s| *s == Slot::Empty);
+        match free {
+            Some(slot) if !expired => {
+                slots[slot] = Slot::Day(day);
+                (Some(slot), jobs)
+            },
+            _ => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    log::warn!("no message shard for day {}, dropped {} records", day, dropped);
+                }
+                (None, jobs)
+            },
+        }
+    }
+
+    /// The days beyond the retention of `today`, their shards are not written anymore
+    pub fn expired(&self, today: u64) -> Vec<Job> {
+        match self.retention_days {
+            Some(retention_days) => {
+                Self::expire(&mut self.slots.lock().unwrap(), retention_days, today)
+            },
+            None => vec![],
+        }
+    }
+
+    fn expire(slots: &mut [Slot], retention_days: u64, today: u64) -> Vec<Job> {
+        let mut jobs = vec![];
+        for (slot, state) in slots.iter_mut().enumerate() {
+            if let Slot::Day(day) = *state {
+                if day + retention_days < today {
+                    log::info!("message shard {} expires, day {}", slot, day);
+                    *state = Slot::Expiring(day);
+                    jobs.push(Job::Expire { slot, day });
+                }
+            }
+        }
+        jobs
+    }
+
+    /// The shard of the expired day is cleared, it takes a new day
+    pub fn finish(&self, slot: usize, day: u64) {
+        let mut slots = self.slots.lock().unwrap();
+        if slots[slot] == Slot::Expiring(day) {
+            slots[slot] = Slot::Empty;
+        }
+    }
+
+    /// Deletes everything in the shard by one range tombstone per column family
+    pub fn clear(&self, db: &DB, batch: &mut WriteBatch, slot: usize) -> Result<(), DBError> {
+        let (from, to): (&[u8], &[u8]) = (&[], &KEY_UPPER_BOUND);
+        batch.delete_range_cf(self.message_cf(db, slot)?, from, to);
+        batch.delete_range_cf(self.chunk_cf(db, slot)?, from, to);
+        Ok(())
+    }
+
+    /// Reclaims the space of the cleared shard, the files covered by the tombstone are dropped
+    pub fn compact(&self, db: &DB, slot: usize) -> Result<(), DBError> {
+        let none = None::<&[u8]>;
+        db.compact_range_cf(self.message_cf(db, slot)?, none, none);
+        db.compact_range_cf(self.chunk_cf(db, slot)?, none, none);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rocksdb::{DB, Options};
+    use super::{Shards, Job, Slot};
+
+    #[test]
+    fn new_day_takes_spare() {
+        let shards = Shards::new(Some(2));
+        assert_eq!(shards.names().count(), 4);
+        for day in 10..13 {
+            assert_eq!(shards.acquire(day), (Some((day - 10) as usize), vec![]));
+        }
+        // the spare shard takes the new day, the day beyond the retention expires
+        assert_eq!(shards.acquire(13), (Some(3), vec![Job::Expire { slot: 0, day: 10 }]));
+        // the same day goes into the same shard
+        assert_eq!(shards.acquire(12), (Some(2), vec![]));
+
+        // no shard is free for the next day until the expired one is cleared
+        assert_eq!(shards.acquire(14), (None, vec![Job::Expire { slot: 1, day: 11 }]));
+        assert_eq!(shards.dropped(), 1);
+        shards.finish(0, 10);
+        assert_eq!(shards.acquire(14), (Some(0), vec![]));
+    }
+
+    #[test]
+    fn wrap_around() {
+        let shards = Shards::new(Some(1));
+        let mut pending = vec![];
+        for day in 100..110 {
+            // the expiry of the previous day is done meanwhile
+            for job in pending.drain(..) {
+                let Job::Expire { slot, day } = job;
+                shards.finish(slot, day);
+            }
+            let (slot, jobs) = shards.acquire(day);
+            assert!(slot.is_some(), "day {}", day);
+            // the only expired day is two days before
+            if day >= 102 {
+                assert_eq!(jobs.len(), 1);
+                assert!(matches!(jobs[0], Job::Expire { day: d, .. } if d == day - 2));
+            }
+            pending = jobs;
+        }
+        assert_eq!(shards.dropped(), 0);
+        // the newest day is queried first
+        let slots = shards.slots();
+        let days = shards.slots.lock().unwrap().clone();
+        assert_eq!(days[slots[0]], Slot::Day(109));
+        assert_eq!(days[slots[1]], Slot::Day(108));
+    }
+
+    #[test]
+    fn late_record() {
+        let shards = Shards::new(Some(1));
+        assert!(shards.acquire(20).0.is_some());
+        assert!(shards.acquire(21).0.is_some());
+        // the previous day is still retained
+        assert!(shards.acquire(20).0.is_some());
+        assert_eq!(shards.acquire(22).1, vec![Job::Expire { slot: 0, day: 20 }]);
+        // the late record of the expired day is dropped and counted
+        assert_eq!(shards.acquire(20), (None, vec![]));
+        assert_eq!(shards.acquire(19), (None, vec![]));
+        assert_eq!(shards.dropped(), 2);
+        // the records of the retained day go on
+        assert!(shards.acquire(21).0.is_some());
+    }
+
+    #[test]
+    fn retention_grows() {
+        // was one day of retention, so three shards, two more are new
+        let shards = Shards::new(Some(3));
+        shards.restore(vec![Some(31), Some(32), None, None, None]);
+        assert_eq!(shards.acquire(32).0, Some(1));
+        // the longer retention keeps the old days, the new days take the new shards
+        assert_eq!(shards.acquire(33), (Some(2), vec![]));
+        assert_eq!(shards.acquire(34), (Some(3), vec![]));
+        assert_eq!(shards.acquire(35), (Some(4), vec![Job::Expire { slot: 0, day: 31 }]));
+    }
+
+    #[test]
+    fn retention_shrinks() {
+        // was three days of retention, the shards beyond the new length are dropped at open,
+        // the days which are kept do not stay in the shard of their day number
+        let shards = Shards::new(Some(1));
+        shards.restore(vec![Some(41), Some(43), Some(40)]);
+        let jobs = shards.expired(44);
+        let expected = vec![Job::Expire { slot: 0, day: 41 }, Job::Expire { slot: 2, day: 40 }];
+        assert_eq!(jobs, expected);
+        assert_eq!(shards.acquire(43).0, Some(1));
+        // no shard is free until the expired ones are cleared
+        assert_eq!(shards.acquire(44).0, None);
+        shards.finish(2, 40);
+        assert_eq!(shards.acquire(44).0, Some(2));
+    }
+
+    #[test]
+    fn stale() {
+        let path = std::env::temp_dir().join(format!("shards-stale-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let names = [
+            "message_storage",
+            "chunk_storage",
+            "message_storage_shard_1",
+            "chunk_storage_shard_1",
+            "message_storage_shard_2",
+            "chunk_storage_shard_2",
+            "message_storage_shard_3",
+            "chunk_storage_shard_3",
+        ];
+        drop(DB::open_cf(&opts, &path, &names).unwrap());
+
+        // one day of retention needs three shards
+        let mut stale = Shards::new(Some(1)).stale(&path);
+        stale.sort();
+        assert_eq!(stale, vec!["chunk_storage_shard_3", "message_storage_shard_3"]);
+        assert!(Shards::new(Some(2)).stale(&path).is_empty());
+
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -1,6 +1,8 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use super::Deadline;
+
 /// Module implements sorted intersection algorithm
 /// Intersection is an *set* operation returning values
 /// that are present in both sets
@@ -54,8 +56,13 @@ where
 }*/
 
 /// For given vector of *sorted* iterators, return new vector containing values
-/// present in *every* iterator
-pub fn sorted_intersect<I>(iters: &mut [I], limit: usize, forward: bool) -> Vec<I::Item>
+/// present in *every* iterator, the values found so far if the deadline expires
+pub fn sorted_intersect<I>(
+    iters: &mut [I],
+    limit: usize,
+    forward: bool,
+    deadline: &Deadline,
+) -> Vec<I::Item>
 where
     I: Iterator,
     I::Item: Ord,
@@ -65,7 +72,7 @@ where
         return ret;
     } else if iters.len() == 1 {
         let iter = iters.iter_mut().next().unwrap();
-        ret.extend(iter.take(limit));
+        ret.extend(iter.take(limit).take_while(|_| !deadline.check()));
         return ret;
     }
     let mut heap = Vec::with_capacity(iters.len());
@@ -76,6 +83,9 @@ where
     }
 
     while ret.len() < limit {
+        if deadline.check() {
+            return ret;
+        }
         if is_hit(&heap) {
             // We hit intersected item
             if let Some((item, _)) = heap.pop() {
@@ -0,0 +1,173 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use rocksdb::{BlockBasedOptions, Cache, ColumnFamilyDescriptor, DBCompressionType, Error, Options};
+use super::archive::ArchiveConfig;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// the options of rocksdb as they are
+    Default,
+    /// large memtables and table files, more background jobs, compressed messages and chunks,
+    /// for the long captures of the busy node
+    CaptureHeavy,
+    /// large block cache, so the frequent queries read the memory rather than the disk
+    QueryHeavy,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::Default
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl From<Compression> for DBCompressionType {
+    fn from(v: Compression) -> Self {
+        match v {
+            Compression::None => DBCompressionType::None,
+            Compression::Snappy => DBCompressionType::Snappy,
+            Compression::Lz4 => DBCompressionType::Lz4,
+            Compression::Zstd => DBCompressionType::Zstd,
+        }
+    }
+}
+
+/// The tuning of the database of the node, every key is optional,
+/// the explicit key takes precedence over the `profile`
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RocksdbConfig {
+    profile: Option<Profile>,
+    /// bytes, the size of the memtable of each column family
+    write_buffer_size: Option<usize>,
+    /// bytes, the size of the table file on the level 1, the next levels grow tenfold
+    target_file_size: Option<u64>,
+    max_background_jobs: Option<i32>,
+    /// bytes, shared by all column families
+    block_cache_size: Option<usize>,
+    /// by the name of the column family, like `chunk_storage` or `message_storage`,
+    /// the name applies to all of its daily shards
+    compression: Option<HashMap<String, Compression>>,
+    /// the consecutive `current_head` messages of the peer are stored as the difference
+    /// from the earlier one, restored on read
+    delta_compression: Option<bool>,
+    /// the messages of the day which leaves the `retention_days` are moved to the flat files,
+    /// instead of removed, they are still served, slower
+    archive: Option<ArchiveConfig>,
+}
+
+impl RocksdbConfig {
+    const MB: usize = 1 << 20;
+
+    fn profile(&self) -> Profile {
+        self.profile.unwrap_or_default()
+    }
+
+    fn write_buffer_size(&self) -> Option<usize> {
+        self.write_buffer_size.or(match self.profile() {
+            Profile::Default => None,
+            Profile::CaptureHeavy => Some(128 * Self::MB),
+            Profile::QueryHeavy => Some(32 * Self::MB),
+        })
+    }
+
+    fn target_file_size(&self) -> Option<u64> {
+        self.target_file_size.or(match self.profile() {
+            Profile::Default => None,
+            Profile::CaptureHeavy => Some(256 * Self::MB as u64),
+            Profile::QueryHeavy => Some(64 * Self::MB as u64),
+        })
+    }
+
+    fn max_background_jobs(&self) -> Option<i32> {
+        self.max_background_jobs.or(match self.profile() {
+            Profile::Default => None,
+            Profile::CaptureHeavy => Some(6),
+            Profile::QueryHeavy => Some(2),
+        })
+    }
+
+    fn block_cache_size(&self) -> Option<usize> {
+        self.block_cache_size.or(match self.profile() {
+            Profile::Default => None,
+            Profile::CaptureHeavy => Some(64 * Self::MB),
+            Profile::QueryHeavy => Some(512 * Self::MB),
+        })
+    }
+
+    fn compression(&self, name: &str) -> Option<Compression> {
+        // the daily shards are `{name}_shard_{slot}`
+        let base = name.split("_shard_").next().unwrap_or(name);
+        if let Some(compression) = self.compression.as_ref().and_then(|c| c.get(base)) {
+            return Some(*compression);
+        }
+        match self.profile() {
+            Profile::CaptureHeavy if base == "chunk_storage" || base == "message_storage" => {
+                Some(Compression::Lz4)
+            },
+            _ => None,
+        }
+    }
+
+    pub fn delta_compression(&self) -> bool {
+        self.delta_compression.unwrap_or(self.profile() == Profile::CaptureHeavy)
+    }
+
+    pub fn archive(&self) -> Option<&ArchiveConfig> {
+        self.archive.as_ref()
+    }
+
+    /// The mutable options of the database as a whole, they are set right after the open
+    pub fn db_options(&self) -> Vec<(&'static str, String)> {
+        let mut opts = vec![];
+        if let Some(jobs) = self.max_background_jobs() {
+            opts.push(("max_background_jobs", jobs.to_string()));
+        }
+        opts
+    }
+
+    /// Without the tuning the tables rely on the page cache of the os
+    pub fn block_cache(&self) -> Result<Cache, Error> {
+        Cache::new_lru_cache(self.block_cache_size().unwrap_or(1))
+    }
+
+    /// The column family designed without the block cache reads through the shared one,
+    /// if it is configured, the prefix bloom filter and the index are cached too
+    pub fn with_block_cache(&self, mut opts: Options, cache: &Cache) -> Options {
+        if self.block_cache_size().is_some() {
+            let mut table_opts = BlockBasedOptions::default();
+            table_opts.set_block_cache(cache);
+            table_opts.set_bloom_filter(10, false);
+            table_opts.set_cache_index_and_filter_blocks(true);
+            opts.set_block_based_table_factory(&table_opts);
+        }
+        opts
+    }
+
+    /// Applies the tuning on top of the options the column family is designed with
+    pub fn descriptor(&self, name: &str, mut opts: Options) -> ColumnFamilyDescriptor {
+        if let Some(size) = self.write_buffer_size() {
+            opts.set_write_buffer_size(size);
+        }
+        if let Some(size) = self.target_file_size() {
+            opts.set_target_file_size_base(size);
+            opts.set_max_bytes_for_level_base(size * 10);
+        }
+        if let Some(compression) = self.compression(name) {
+            opts.set_compression_type(compression.into());
+        }
+        ColumnFamilyDescriptor::new(name, opts)
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{collections::BTreeMap, sync::Mutex};
+use serde::Serialize;
+use super::{common::MessageType, tables::peer::Version};
+
+#[derive(Serialize)]
+pub struct DecoderReport {
+    #[serde(rename = "type")]
+    pub ty: String,
+    /// the version the sender announced in its connection message,
+    /// like `TEZOS_MAINNET ddb 0 p2p 1`, `unknown` if it cannot be decoded
+    pub version: String,
+    pub decoded: u64,
+    /// the messages stored as the bytes only, the decoder does not understand them
+    pub failed: u64,
+    /// `decoded` of every message in percents
+    pub coverage: f64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct Counts {
+    decoded: u64,
+    failed: u64,
+    last_error: Option<String>,
+}
+
+/// How many messages of each type and version the node exchanged are decoded,
+/// the protocol upgrade which the decoder does not know shows up as the failed ones
+#[derive(Default)]
+pub struct DecoderStats {
+    counts: Mutex<BTreeMap<(String, String), Counts>>,
+}
+
+impl DecoderStats {
+    pub fn version_name(version: Option<&Version>) -> String {
+        match version {
+            Some(v) => format!(
+                "{} ddb {} p2p {}",
+                v.chain_name, v.distributed_db_version, v.p2p_version,
+            ),
+            None => "unknown".to_string(),
+        }
+    }
+
+    pub fn count<E>(&self, ty: &MessageType, version: &str, result: Result<(), E>)
+    where
+        E: ToString,
+    {
+        let mut counts = self.counts.lock().unwrap();
+        let counts = counts.entry((ty.name(), version.to_string())).or_default();
+        match result {
+            Ok(()) => counts.decoded += 1,
+            Err(error) => {
+                counts.failed += 1;
+                counts.last_error = Some(error.to_string());
+            },
+        }
+    }
+
+    /// The worst coverage first
+    pub fn report(&self) -> Vec<DecoderReport> {
+        let mut report = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((ty, version), counts)| DecoderReport {
+                ty: ty.clone(),
+                version: version.clone(),
+                decoded: counts.decoded,
+                failed: counts.failed,
+                coverage: {
+                    let total = counts.decoded + counts.failed;
+                    counts.decoded as f64 * 100.0 / total.max(1) as f64
+                },
+                last_error: counts.last_error.clone(),
+            })
+            .collect::<Vec<_>>();
+        report.sort_by(|a, b| a.coverage.partial_cmp(&b.coverage).unwrap());
+        report
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashSet,
+    fs,
+    time::Duration,
+};
+use serde::{Serialize, Deserialize};
+
+/// The node is found among the running processes rather than waiting for it to bind
+/// its p2p port, so the node started before the recorder is captured too,
+/// disabled without the `discovery` section
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// the names of the executables, `tezos-node` and `light-node` by default
+    names: Option<Vec<String>>,
+    /// seconds between the scans, 10 by default
+    interval_secs: Option<u64>,
+}
+
+impl DiscoveryConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.unwrap_or(10).max(1))
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.names
+            .clone()
+            .unwrap_or_else(|| vec!["tezos-node".to_string(), "light-node".to_string()])
+    }
+
+    /// The pids of the processes of the given names and the tcp ports each of them
+    /// listens on
+    pub fn scan(&self) -> Vec<(u32, Vec<u16>)> {
+        // the kernel truncates the name in `comm` to 15 bytes
+        let names = self
+            .names()
+            .into_iter()
+            .map(|name| name.chars().take(15).collect::<String>())
+            .collect::<HashSet<_>>();
+        let entries = match fs::read_dir("/proc") {
+            Ok(entries) => entries,
+            Err(error) => {
+                log::error!("cannot scan processes: {}", error);
+                return vec![];
+            },
+        };
+        entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u32>().ok())
+            .filter(|pid| {
+                fs::read_to_string(format!("/proc/{}/comm", pid))
+                    .map(|comm| names.contains(comm.trim_end()))
+                    .unwrap_or(false)
+            })
+            .map(|pid| (pid, listening(pid)))
+            .collect()
+    }
+}
+
+/// The ports of the listening sockets the process has, the process may be gone meanwhile,
+/// nothing is found then
+fn listening(pid: u32) -> Vec<u16> {
+    let inodes = match fs::read_dir(format!("/proc/{}/fd", pid)) {
+        Ok(fds) => fds
+            .filter_map(|fd| {
+                let link = fs::read_link(fd.ok()?.path()).ok()?;
+                let link = link.to_str()?;
+                link.strip_prefix("socket:[")?.strip_suffix(']')?.parse::<u64>().ok()
+            })
+            .collect::<HashSet<_>>(),
+        Err(_) => return vec![],
+    };
+    // the tables are of the network namespace of the process, it works in a container too
+    let mut ports = vec![];
+    for table in &["tcp", "tcp6"] {
+        let content = match fs::read_to_string(format!("/proc/{}/net/{}", pid, table)) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        // `sl local_address rem_address st queues timers retrnsmt uid timeout inode`
+        for line in content.lines().skip(1) {
+            let columns = line.split_whitespace().collect::<Vec<_>>();
+            let (local, state, inode) = match (columns.get(1), columns.get(3), columns.get(9)) {
+                (Some(local), Some(state), Some(inode)) => (local, state, inode),
+                _ => continue,
+            };
+            // `0A` is `TCP_LISTEN`
+            if *state != "0A" || !inode.parse().map_or(false, |i: u64| inodes.contains(&i)) {
+                continue;
+            }
+            let port = local
+                .rsplit(':')
+                .next()
+                .and_then(|port| u16::from_str_radix(port, 16).ok());
+            if let Some(port) = port {
+                if !ports.contains(&port) {
+                    ports.push(port);
+                }
+            }
+        }
+    }
+    ports
+}
@@ -0,0 +1,65 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use serde::Serialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum EncodeError {
+    #[error("json: {}", _0)]
+    Json(#[from] serde_json::Error),
+    #[error("messagepack: {}", _0)]
+    MessagePack(#[from] rmp_serde::encode::Error),
+    #[error("cbor: {}", _0)]
+    Cbor(#[from] serde_cbor::Error),
+}
+
+/// The representation of the response the client asked for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Json,
+    Csv,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    /// By the `format` query argument, or else by the first known type in the `Accept` header
+    pub fn negotiate(format: Option<&str>, accept: Option<&str>) -> Self {
+        match format {
+            Some("json") => Encoding::Json,
+            Some("csv") => Encoding::Csv,
+            Some("msgpack") => Encoding::MessagePack,
+            Some("cbor") => Encoding::Cbor,
+            _ => accept
+                .into_iter()
+                .flat_map(|accept| accept.split(','))
+                .filter_map(|ty| ty.split(';').next())
+                .find_map(|ty| Self::from_media_type(ty.trim()))
+                .unwrap_or(Encoding::Json),
+        }
+    }
+
+    fn from_media_type(ty: &str) -> Option<Self> {
+        match ty {
+            "application/json" => Some(Encoding::Json),
+            "text/csv" => Some(Encoding::Csv),
+            "application/msgpack" | "application/x-msgpack" => Some(Encoding::MessagePack),
+            "application/cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The body and its content type, the csv needs the columns,
+    /// so the value is encoded as json if csv is asked here
+    pub fn encode<T>(&self, value: &T) -> Result<(Vec<u8>, &'static str), EncodeError>
+    where
+        T: Serialize,
+    {
+        match self {
+            Encoding::Json | Encoding::Csv => Ok((serde_json::to_vec(value)?, "application/json")),
+            Encoding::MessagePack => Ok((rmp_serde::to_vec_named(value)?, "application/msgpack")),
+            Encoding::Cbor => Ok((serde_cbor::to_vec(value)?, "application/cbor")),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{convert::TryFrom, net::SocketAddr};
+use serde::Serialize;
+use thiserror::Error;
+use crypto::nonce::{NoncePair, generate_nonces};
+use salsa20::hsalsa20;
+use x25519_dalek::{PublicKey, StaticSecret};
+use super::{
+    common::Initiator,
+    system::Identity,
+    tables::session::Handshake,
+};
+
+/// Everything needed to decrypt the chunks of the connection without the identity,
+/// the chunks are NaCl `crypto_box` with the precomputed key, the nonce of each direction
+/// starts with the given one and is incremented by one, as the big endian number,
+/// for every encrypted chunk of that direction, the first is the metadata chunk
+#[derive(Serialize)]
+pub struct ConnectionKeys {
+    pub connection: String,
+    pub remote_addr: SocketAddr,
+    pub initiator: Initiator,
+    pub precomputed_key: String,
+    pub local_nonce: String,
+    pub remote_nonce: String,
+}
+
+#[derive(Error, Debug)]
+pub enum EscrowError {
+    #[error("connection message is too short: {} bytes", _0)]
+    TooShort(usize),
+    #[error("the connection does not belong to the identity of the node")]
+    ForeignConnection,
+    #[error("cannot derive the nonces")]
+    Nonce,
+}
+
+// the chunk is the 2 bytes length, then the 2 bytes port, then the public key
+const PK_RANGE: std::ops::Range<usize> = 4..36;
+
+/// Derives the keys the same way the node does, from its identity
+/// and from the connection messages of both sides
+pub fn derive(identity: &Identity, handshake: &Handshake) -> Result<ConnectionKeys, EscrowError> {
+    for message in &[&handshake.local, &handshake.remote] {
+        if message.len() < PK_RANGE.end {
+            return Err(EscrowError::TooShort(message.len()));
+        }
+    }
+    if handshake.local[PK_RANGE] != identity.public_key[..] {
+        return Err(EscrowError::ForeignConnection);
+    }
+    let remote_pk = <[u8; 32]>::try_from(&handshake.remote[PK_RANGE]).unwrap_or_default();
+
+    // NaCl `crypto_box_beforenm`, the same as `PrecomputedKey::precompute` the parser uses,
+    // which keeps the key private, so it is exported from here
+    let secret = StaticSecret::from(identity.secret_key);
+    let shared = secret.diffie_hellman(&PublicKey::from(remote_pk));
+    let precomputed_key = hsalsa20(shared.as_bytes().into(), &Default::default());
+
+    // the same nonces as the parser uses
+    let incoming = handshake.initiator.incoming();
+    let NoncePair { local, remote } =
+        generate_nonces(&handshake.local, &handshake.remote, incoming)
+            .map_err(|_| EscrowError::Nonce)?;
+    let local_nonce = hex::encode(local.get_bytes().map_err(|_| EscrowError::Nonce)?);
+    let remote_nonce = hex::encode(remote.get_bytes().map_err(|_| EscrowError::Nonce)?);
+
+    Ok(ConnectionKeys {
+        connection: handshake.cn_id.to_string(),
+        remote_addr: handshake.remote_addr,
+        initiator: handshake.initiator.clone(),
+        precomputed_key: hex::encode(precomputed_key),
+        local_nonce,
+        remote_nonce,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+    use crypto::{
+        crypto_box::{CryptoKey, PrecomputedKey, PublicKey, SecretKey},
+        nonce::Nonce,
+    };
+    use salsa20::{
+        XSalsa20,
+        cipher::{NewCipher, StreamCipher},
+    };
+    use super::{derive, EscrowError, Identity, Initiator, Handshake};
+
+    fn keypair(seed: u8) -> ([u8; 32], [u8; 32]) {
+        let secret = x25519_dalek::StaticSecret::from([seed; 32]);
+        let public = x25519_dalek::PublicKey::from(&secret);
+        (secret.to_bytes(), public.to_bytes())
+    }
+
+    // the length, the port, the public key and the proof of work, as the node sends it
+    fn connection_message(pk: &[u8; 32]) -> Vec<u8> {
+        let mut v = vec![0, 58, 0x26, 0x04];
+        v.extend_from_slice(pk);
+        v.extend_from_slice(&[7; 24]);
+        v
+    }
+
+    // what an offline tool does with the exported keys, NaCl `crypto_box_open_afternm`
+    // without the check of the tag
+    fn open(key: &str, nonce: &str, encrypted: &[u8]) -> Vec<u8> {
+        let key = <[u8; 32]>::try_from(hex::decode(key).unwrap().as_slice()).unwrap();
+        let nonce = <[u8; 24]>::try_from(hex::decode(nonce).unwrap().as_slice()).unwrap();
+        let mut cipher = XSalsa20::new(&key.into(), &nonce.into());
+        // the first 32 bytes of the keystream are the key of the tag
+        let mut v = vec![0; 32];
+        v.extend_from_slice(&encrypted[16..]);
+        cipher.apply_keystream(&mut v);
+        v.split_off(32)
+    }
+
+    #[test]
+    fn decrypts_captured_chunk() {
+        let (local_sk, local_pk) = keypair(1);
+        let (remote_sk, remote_pk) = keypair(2);
+        let identity = Identity {
+            public_key: local_pk,
+            secret_key: local_sk,
+        };
+        let handshake = Handshake {
+            cn_id: Default::default(),
+            remote_addr: "127.0.0.1:9732".parse().unwrap(),
+            initiator: Initiator::Local,
+            local: connection_message(&local_pk),
+            remote: connection_message(&remote_pk),
+        };
+        let keys = derive(&identity, &handshake).unwrap();
+
+        // the remote peer encrypts its first chunk after the connection message
+        let key = PrecomputedKey::precompute(
+            &PublicKey::from_bytes(&local_pk).unwrap(),
+            &SecretKey::from_bytes(&remote_sk).unwrap(),
+        );
+        let nonce = Nonce::new(&hex::decode(&keys.remote_nonce).unwrap());
+        let plain = b"metadata of the remote peer".to_vec();
+        let encrypted = key.encrypt(&plain, &nonce).unwrap();
+        assert_eq!(open(&keys.precomputed_key, &keys.remote_nonce, &encrypted), plain);
+
+        // and the local node its chunk
+        let key = PrecomputedKey::precompute(
+            &PublicKey::from_bytes(&remote_pk).unwrap(),
+            &SecretKey::from_bytes(&local_sk).unwrap(),
+        );
+        let nonce = Nonce::new(&hex::decode(&keys.local_nonce).unwrap());
+        let encrypted = key.encrypt(b"local", &nonce).unwrap();
+        assert_eq!(open(&keys.precomputed_key, &keys.local_nonce, &encrypted), b"local");
+    }
+
+    #[test]
+    fn foreign_connection() {
+        let (local_sk, local_pk) = keypair(1);
+        let (_, remote_pk) = keypair(2);
+        let identity = Identity {
+            public_key: remote_pk,
+            secret_key: local_sk,
+        };
+        let handshake = Handshake {
+            cn_id: Default::default(),
+            remote_addr: "127.0.0.1:9732".parse().unwrap(),
+            initiator: Initiator::Remote,
+            local: connection_message(&local_pk),
+            remote: connection_message(&remote_pk),
+        };
+        assert!(matches!(derive(&identity, &handshake), Err(EscrowError::ForeignConnection)));
+        let handshake = Handshake {
+            local: vec![0; 10],
+            ..handshake
+        };
+        assert!(matches!(derive(&identity, &handshake), Err(EscrowError::TooShort(10))));
+    }
+}
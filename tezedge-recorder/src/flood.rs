@@ -0,0 +1,191 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use bpf_recorder::SocketId;
+use serde::{Serialize, Deserialize};
+use super::{database::DatabaseFetch, tables::incident};
+
+/// The connection flood is detected by the handshakes the node accepts from one address range,
+/// `/24` of IPv4 or `/48` of IPv6, disabled without the `flood_detection` section
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FloodConfig {
+    /// the handshakes per minute from one range, from which it is the incident, 200 by default
+    threshold: Option<u64>,
+}
+
+impl FloodConfig {
+    fn threshold(&self) -> u64 {
+        self.threshold.unwrap_or(200).max(1)
+    }
+}
+
+const MINUTE_MS: u64 = 60_000;
+
+/// Like `51.15.220.0/24`
+fn range(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        },
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
+        },
+    }
+}
+
+// the current minute of the range, and the incident, if the range floods the node
+struct RangeState<Db> {
+    db: Arc<Db>,
+    minute: u64,
+    accepted: u64,
+    closed: u64,
+    addresses: Vec<IpAddr>,
+    incident: Option<(Option<u64>, incident::Item)>,
+}
+
+impl<Db> RangeState<Db>
+where
+    Db: DatabaseFetch,
+{
+    fn store(&mut self) {
+        if let Some((id, item)) = &mut self.incident {
+            match self.db.store_incident(*id, item.clone()) {
+                Ok(new_id) => *id = Some(new_id),
+                Err(error) => log::error!("failed to store the incident: {}", error),
+            }
+        }
+    }
+
+    fn open(&mut self, range: &str) {
+        let mut item = incident::Item::new(range.to_string(), self.minute * MINUTE_MS);
+        for address in &self.addresses {
+            item.add_address(*address);
+        }
+        item.handshakes = self.accepted;
+        self.incident = Some((None, item));
+        self.store();
+    }
+
+    // the minute is over, the incident goes on if the range still floods, ends otherwise
+    fn roll(&mut self, range: &str, minute: u64, threshold: u64) {
+        if minute == self.minute {
+            return;
+        }
+        let flooding = self.accepted >= threshold && minute == self.minute + 1;
+        if let Some((_, item)) = &mut self.incident {
+            item.timeline.push(incident::Minute {
+                timestamp: self.minute * MINUTE_MS,
+                accepted: self.accepted,
+                closed: self.closed,
+            });
+            if !flooding {
+                item.ended = Some((self.minute + 1) * MINUTE_MS);
+                log::warn!("connection flood from {} ended", range);
+            }
+            self.store();
+            if !flooding {
+                self.incident = None;
+            }
+        }
+        self.minute = minute;
+        self.accepted = 0;
+        self.closed = 0;
+        self.addresses.clear();
+    }
+}
+
+/// Counts the handshakes by the node and the address range, the range which reaches
+/// the threshold during a minute is the incident, it lasts while every minute reaches it
+pub struct FloodDetector<Db> {
+    threshold: u64,
+    ranges: HashMap<(String, String), RangeState<Db>>,
+    // the accepted connections, to tell the range of the closed one
+    sockets: HashMap<SocketId, (String, String)>,
+}
+
+impl<Db> FloodDetector<Db>
+where
+    Db: DatabaseFetch,
+{
+    pub fn new(config: &FloodConfig) -> Self {
+        FloodDetector {
+            threshold: config.threshold(),
+            ranges: HashMap::new(),
+            sockets: HashMap::new(),
+        }
+    }
+
+    /// The node accepted the connection, `now` is unix milliseconds
+    pub fn accepted(
+        &mut self,
+        node: &str,
+        db: Arc<Db>,
+        socket_id: SocketId,
+        address: SocketAddr,
+        now: u64,
+    ) {
+        let (minute, threshold) = (now / MINUTE_MS, self.threshold);
+        let key = (node.to_string(), range(address.ip()));
+        self.sockets.insert(socket_id, key.clone());
+        let state = self.ranges.entry(key.clone()).or_insert_with(|| RangeState {
+            db,
+            minute,
+            accepted: 0,
+            closed: 0,
+            addresses: vec![],
+            incident: None,
+        });
+        state.roll(&key.1, minute, threshold);
+        state.accepted += 1;
+        if state.addresses.len() < incident::Item::MAX_ADDRESSES
+            && !state.addresses.contains(&address.ip())
+        {
+            state.addresses.push(address.ip());
+        }
+        match &mut state.incident {
+            Some((_, item)) => {
+                item.handshakes += 1;
+                item.add_address(address.ip());
+            },
+            None if state.accepted == threshold => {
+                log::warn!(
+                    "node {}: connection flood from {}, {} handshakes during the minute",
+                    key.0,
+                    key.1,
+                    threshold,
+                );
+                state.open(&key.1);
+            },
+            None => (),
+        }
+    }
+
+    pub fn closed(&mut self, socket_id: &SocketId, now: u64) {
+        let key = match self.sockets.remove(socket_id) {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(state) = self.ranges.get_mut(&key) {
+            state.roll(&key.1, now / MINUTE_MS, self.threshold);
+            state.closed += 1;
+        }
+    }
+
+    /// Ends the incidents of the ranges which stopped connecting at all,
+    /// and forgets the ranges which are quiet
+    pub fn tick(&mut self, now: u64) {
+        let (minute, threshold) = (now / MINUTE_MS, self.threshold);
+        self.ranges.retain(|(_, range), state| {
+            state.roll(range, minute, threshold);
+            state.incident.is_some() || state.accepted > 0
+        });
+    }
+}
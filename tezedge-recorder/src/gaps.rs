@@ -0,0 +1,177 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! The intervals when the capture missed the traffic of the node, so the analyst does not take
+//! the missing traffic for the silence of the node: the recorder was down, the capture was paused,
+//! or the data was lost by the overflown decoder queue or the ring buffer.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use super::{
+    database::{blocking, DatabaseFetch, GapsFilter},
+    tables::gap::{Item, Kind},
+    control::Control,
+    pipeline::Pipeline,
+    coverage::Coverage,
+};
+
+// the heartbeat is stored and the capture is checked so often
+const TICK: Duration = Duration::from_secs(1);
+
+// the recorder was down if the last heartbeat is older at the start
+const DOWN_AFTER_MS: u64 = 5_000;
+
+// the traffic is lost if the recorder processed less of the bytes the kernel counted,
+// in percents, during the last reconciliation
+const MIN_COVERAGE: f64 = 99.0;
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+struct Tracker {
+    // the gaps which last, with their ids
+    open: Vec<(u64, Item)>,
+    last_tick: u64,
+}
+
+impl Tracker {
+    /// Ends the gaps the previous run left open and stores the downtime
+    fn start<Db>(db: &Db) -> Result<Self, Db::Error>
+    where
+        Db: DatabaseFetch,
+    {
+        let now = now_ms();
+        let heartbeat = db.fetch_heartbeat()?;
+        let filter = GapsFilter {
+            from: None,
+            to: None,
+            node_name: None,
+        };
+        for mut gap in db.fetch_gaps(&filter)? {
+            if gap.item.to.is_none() {
+                gap.item.to = Some(heartbeat.unwrap_or(now).max(gap.item.from));
+                db.store_gap(Some(gap.id), gap.item)?;
+            }
+        }
+        if let Some(heartbeat) = heartbeat {
+            if now.saturating_sub(heartbeat) > DOWN_AFTER_MS {
+                let item = Item {
+                    kind: Kind::Down,
+                    from: heartbeat,
+                    to: Some(now),
+                };
+                db.store_gap(None, item)?;
+            }
+        }
+        db.store_heartbeat(now)?;
+        Ok(Tracker {
+            open: vec![],
+            last_tick: now,
+        })
+    }
+
+    fn tick<Db>(&mut self, db: &Db, paused: bool, lost: bool) -> Result<(), Db::Error>
+    where
+        Db: DatabaseFetch,
+    {
+        let now = now_ms();
+        db.store_heartbeat(now)?;
+        self.track(db, Kind::Paused, paused, now)?;
+        self.track(db, Kind::Lost, lost, now)?;
+        self.last_tick = now;
+        Ok(())
+    }
+
+    fn track<Db>(&mut self, db: &Db, kind: Kind, active: bool, now: u64) -> Result<(), Db::Error>
+    where
+        Db: DatabaseFetch,
+    {
+        let position = self.open.iter().position(|(_, item)| item.kind == kind);
+        match (active, position) {
+            // it happened since the last tick
+            (true, None) => {
+                let item = Item {
+                    kind,
+                    from: self.last_tick,
+                    to: None,
+                };
+                let id = db.store_gap(None, item.clone())?;
+                self.open.push((id, item));
+            },
+            (false, Some(position)) => {
+                let (id, mut item) = self.open.remove(position);
+                item.to = Some(now);
+                db.store_gap(Some(id), item)?;
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// Stores the heartbeat and the gaps of the node every `TICK`
+pub async fn schedule<Db>(
+    db: Arc<Db>,
+    node_name: String,
+    control: Arc<Control>,
+    pipeline: Arc<Pipeline>,
+    coverage: Arc<Coverage>,
+) where
+    Db: DatabaseFetch + Send + Sync + 'static,
+{
+    let mut tracker = {
+        let db = db.clone();
+        match blocking(move || Tracker::start(db.as_ref())).await {
+            Ok(Ok(tracker)) => tracker,
+            Ok(Err(error)) => {
+                log::error!("cannot start gap tracker of {}: {}", node_name, error);
+                return;
+            },
+            Err(error) => {
+                log::error!("gap tracker of {} panicked: {}", node_name, error);
+                return;
+            },
+        }
+    };
+    let mut dropped = pipeline.dropped();
+    // the coverage is reconciled rarely, the verdict holds until the next reconciliation
+    let (mut reconciled, mut low_coverage) = (0, false);
+    let mut interval = tokio::time::interval(TICK);
+    loop {
+        interval.tick().await;
+        let paused = control.is_paused();
+        let dropped_now = pipeline.dropped();
+        if let Some(report) = coverage.report().get(&node_name) {
+            if report.timestamp != reconciled {
+                reconciled = report.timestamp;
+                low_coverage = report.interval_coverage < MIN_COVERAGE;
+            }
+        }
+        let lost = dropped_now > dropped || low_coverage;
+        dropped = dropped_now;
+
+        let db = db.clone();
+        let result = blocking(move || {
+            let result = tracker.tick(db.as_ref(), paused, lost);
+            (tracker, result)
+        })
+        .await;
+        tracker = match result {
+            Ok((tracker, Ok(()))) => tracker,
+            Ok((tracker, Err(error))) => {
+                log::warn!("gap tracker of {} failed: {}", node_name, error);
+                tracker
+            },
+            Err(error) => {
+                log::error!("gap tracker of {} panicked: {}", node_name, error);
+                return;
+            },
+        };
+    }
+}
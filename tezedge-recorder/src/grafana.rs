@@ -0,0 +1,257 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! The contract of the Grafana json datasource (SimpleJSON), the datasource url is
+//! `/grafana/{node name}`, the same requests work for the Infinity datasource.
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use super::{
+    database::{
+        DatabaseFetch, BandwidthFilter, LogCountsFilter, IncidentsFilter, AnnotationsFilter,
+        GapsFilter,
+    },
+    tables::{bandwidth::Series, log_count::LevelCounts},
+};
+
+/// The metrics, which `/search` offers
+pub const TARGETS: &[&str] = &["bandwidth", "log_counts", "incidents"];
+
+// the range of the dashboard, rfc3339
+#[derive(Deserialize)]
+pub struct Range {
+    from: String,
+    to: String,
+}
+
+impl Range {
+    /// Unix milliseconds
+    fn millis(&self) -> Result<(u64, u64), String> {
+        let parse = |s: &str| {
+            chrono::DateTime::parse_from_rfc3339(s)
+                .map(|t| t.timestamp_millis().max(0) as u64)
+                .map_err(|error| format!("invalid time {:?}: {}", s, error))
+        };
+        Ok((parse(&self.from)?, parse(&self.to)?))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Target {
+    target: Option<String>,
+    /// the filter of the metric, like `{"group_by": "peer"}` for the bandwidth,
+    /// older versions of the datasource call it `data`
+    #[serde(default, alias = "data")]
+    payload: Value,
+}
+
+#[derive(Deserialize)]
+pub struct QueryRequest {
+    range: Range,
+    #[serde(default)]
+    targets: Vec<Target>,
+}
+
+#[derive(Deserialize)]
+struct AnnotationQuery {
+    #[serde(default)]
+    query: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AnnotationsRequest {
+    range: Range,
+    annotation: Value,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Annotation {
+    /// the request echoed, the datasource expects it
+    annotation: Value,
+    time: u64,
+    time_end: u64,
+    title: String,
+    text: String,
+    tags: Vec<String>,
+}
+
+/// The time series of the targets of the request, the bandwidth as `/v2/stats/bandwidth`
+/// returns it, the log counts by level, and the handshakes per minute of the incidents by range
+pub fn query<Db>(db: &Db, request: QueryRequest) -> Result<Vec<Series>, String>
+where
+    Db: DatabaseFetch,
+{
+    let database_error = |error: Db::Error| format!("database error: {}", error);
+
+    let (from, to) = request.range.millis()?;
+    let mut series = vec![];
+    for Target { target, payload } in request.targets {
+        match target.as_deref().unwrap_or_default() {
+            "bandwidth" => {
+                let payload = match payload {
+                    Value::Null => Value::Object(Default::default()),
+                    payload => payload,
+                };
+                let mut filter = serde_json::from_value::<BandwidthFilter>(payload)
+                    .map_err(|error| format!("invalid bandwidth filter: {}", error))?;
+                filter.from = Some(from);
+                filter.to = Some(to);
+                series.extend(db.fetch_bandwidth(&filter).map_err(database_error)?);
+            },
+            "log_counts" => {
+                let filter = LogCountsFilter {
+                    from: Some(from),
+                    to: Some(to),
+                    gaps: None,
+                    node_name: None,
+                };
+                let counts = db.fetch_log_counts(&filter).map_err(database_error)?;
+                let levels: [(&str, fn(&LevelCounts) -> u64); 7] = [
+                    ("trace", |c| c.trace),
+                    ("debug", |c| c.debug),
+                    ("info", |c| c.info),
+                    ("notice", |c| c.notice),
+                    ("warning", |c| c.warning),
+                    ("error", |c| c.error),
+                    ("fatal", |c| c.fatal),
+                ];
+                for (name, count) in &levels {
+                    series.push(Series {
+                        target: name.to_string(),
+                        datapoints: counts.iter().map(|c| (count(c), c.minute)).collect(),
+                    });
+                }
+            },
+            "incidents" => {
+                let filter = IncidentsFilter {
+                    limit: Some(1000),
+                    from: Some(from),
+                    to: Some(to),
+                    range: None,
+                    node_name: None,
+                };
+                let incidents = db.fetch_incidents(&filter).map_err(database_error)?;
+                // the latest first, but the series goes forward
+                for incident in incidents.into_iter().rev() {
+                    let datapoints = incident
+                        .item
+                        .timeline
+                        .iter()
+                        .map(|minute| (minute.accepted, minute.timestamp))
+                        .collect();
+                    series.push(Series {
+                        target: incident.item.range,
+                        datapoints,
+                    });
+                }
+            },
+            target => return Err(format!("no such target: {:?}", target)),
+        }
+    }
+    Ok(series)
+}
+
+/// The query of the annotation is `incidents` for the connection floods, `gaps` for the intervals
+/// when the capture missed the traffic, `annotations` for every annotation the users made,
+/// anything else is the label of the annotations
+pub fn annotations<Db>(db: &Db, request: AnnotationsRequest) -> Result<Vec<Annotation>, String>
+where
+    Db: DatabaseFetch,
+{
+    let database_error = |error: Db::Error| format!("database error: {}", error);
+
+    let (from, to) = request.range.millis()?;
+    let query = serde_json::from_value::<AnnotationQuery>(request.annotation.clone())
+        .ok()
+        .and_then(|q| q.query)
+        .unwrap_or_default();
+    let echo = request.annotation;
+    match query.as_str() {
+        "incidents" => {
+            let filter = IncidentsFilter {
+                limit: Some(1000),
+                from: Some(from),
+                to: Some(to),
+                range: None,
+                node_name: None,
+            };
+            let v = db
+                .fetch_incidents(&filter)
+                .map_err(database_error)?
+                .into_iter()
+                .map(|incident| Annotation {
+                    annotation: echo.clone(),
+                    time: incident.item.started,
+                    time_end: incident.item.ended.unwrap_or(to),
+                    title: format!("connection flood from {}", incident.item.range),
+                    text: format!(
+                        "{} handshakes from {} addresses",
+                        incident.item.handshakes,
+                        incident.item.addresses.len(),
+                    ),
+                    tags: vec!["incident".to_string(), incident.item.range],
+                })
+                .collect();
+            Ok(v)
+        },
+        "gaps" => {
+            let filter = GapsFilter {
+                from: Some(from),
+                to: Some(to),
+                node_name: None,
+            };
+            let v = db
+                .fetch_gaps(&filter)
+                .map_err(database_error)?
+                .into_iter()
+                .map(|gap| {
+                    let kind = serde_json::to_value(&gap.item.kind)
+                        .ok()
+                        .and_then(|v| v.as_str().map(str::to_string))
+                        .unwrap_or_default();
+                    Annotation {
+                        annotation: echo.clone(),
+                        time: gap.item.from,
+                        time_end: gap.item.to.unwrap_or(to),
+                        title: format!("capture gap: {}", kind),
+                        text: "the traffic of the interval is not recorded".to_string(),
+                        tags: vec!["gap".to_string(), kind],
+                    }
+                })
+                .collect();
+            Ok(v)
+        },
+        query => {
+            let filter = AnnotationsFilter {
+                message_id: None,
+                from: Some(from),
+                to: Some(to),
+                label: match query {
+                    "" | "annotations" => None,
+                    label => Some(label.to_string()),
+                },
+                node_name: None,
+            };
+            let v = db
+                .fetch_annotations(&filter)
+                .map_err(database_error)?
+                .into_iter()
+                .map(|a| {
+                    let time = a.item.from.unwrap_or(from);
+                    let mut tags = vec![a.item.label.clone()];
+                    tags.extend(a.item.author.clone());
+                    Annotation {
+                        annotation: echo.clone(),
+                        time,
+                        time_end: a.item.to.unwrap_or(time),
+                        title: a.item.label,
+                        text: a.item.note,
+                        tags,
+                    }
+                })
+                .collect();
+            Ok(v)
+        },
+    }
+}
@@ -0,0 +1,311 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write,
+    net::SocketAddr,
+};
+use serde::Deserialize;
+use schemars::JsonSchema;
+use super::{
+    database::{DatabaseFetch, ConnectionsFilter, BandwidthFilter},
+    decoder_stats::DecoderStats,
+    tables::peer,
+};
+
+#[derive(Deserialize, JsonSchema)]
+pub struct GraphFilter {
+    /// unix milliseconds, the connections alive at some moment of the range are included
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    /// `graphml` (default) or `dot`
+    pub format: Option<String>,
+    /// every node if absent
+    pub node_name: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+pub enum GraphFormat {
+    GraphMl,
+    Dot,
+}
+
+impl GraphFormat {
+    pub fn parse(format: Option<&str>) -> Result<Self, String> {
+        match format {
+            None | Some("graphml") => Ok(GraphFormat::GraphMl),
+            Some("dot") => Ok(GraphFormat::Dot),
+            Some(format) => Err(format!("unknown format {:?}, expected graphml or dot", format)),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            GraphFormat::GraphMl => "application/graphml+xml",
+            GraphFormat::Dot => "text/vnd.graphviz",
+        }
+    }
+}
+
+struct Vertex {
+    label: String,
+    // `node` is the recorded node, `peer` is its peer
+    kind: &'static str,
+    addresses: BTreeSet<String>,
+    version: Option<String>,
+}
+
+#[derive(Default)]
+struct Edge {
+    connections: u64,
+    // the peer initiated them
+    incoming: u64,
+    // either side sent nack
+    rejected: u64,
+    bytes: u64,
+}
+
+/// The recorded nodes and their peers, the edge is the connections between the node
+/// and the peer during the range, weighted by the bytes they exchanged
+#[derive(Default)]
+pub struct Graph {
+    vertices: BTreeMap<String, Vertex>,
+    edges: BTreeMap<(String, String), Edge>,
+}
+
+impl Graph {
+    /// The peer is identified by its id, the one whose connection message is missed
+    /// is identified by its ip
+    pub fn build<Db>(
+        dbs: &[(String, &Db)],
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> Result<Self, String>
+    where
+        Db: DatabaseFetch,
+    {
+        let db_error = |error: Db::Error| format!("database error: {}", error);
+
+        let mut graph = Graph::default();
+        for (node_name, db) in dbs {
+            let node_id = format!("node:{}", node_name);
+            graph.vertices.insert(
+                node_id.clone(),
+                Vertex {
+                    label: node_name.clone(),
+                    kind: "node",
+                    addresses: BTreeSet::new(),
+                    version: None,
+                },
+            );
+
+            let filter = BandwidthFilter {
+                from,
+                to,
+                incoming: None,
+                types: None,
+                remote_addr: None,
+                group_by: Some("peer".to_string()),
+                gaps: None,
+                node_name: None,
+            };
+            let bandwidth = db
+                .fetch_bandwidth(&filter)
+                .map_err(db_error)?
+                .into_iter()
+                .map(|series| {
+                    let bytes = series.datapoints.iter().map(|(bytes, _)| *bytes).sum::<u64>();
+                    (series.target, bytes)
+                })
+                .collect::<HashMap<_, _>>();
+
+            let filter = ConnectionsFilter {
+                limit: Some(u64::MAX),
+                nack_motive: None,
+                termination: None,
+                handshake_slower_than: None,
+                after: None,
+                external: Some(false),
+            };
+            let mut addrs = BTreeMap::<String, BTreeSet<SocketAddr>>::new();
+            for (key, value) in db.fetch_connections(&filter).map_err(db_error)? {
+                let start = key.ts * 1_000 + (key.ts_nanos / 1_000_000) as u64;
+                let end = value.termination().map(|t| t.timestamp / 1_000_000);
+                if to.map_or(false, |to| start > to)
+                    || from.zip(end).map_or(false, |(from, end)| end < from)
+                {
+                    continue;
+                }
+
+                let remote_addr = value.remote_addr();
+                let pk = value.peer_pk();
+                let peer_id = if pk == &[0; 32] {
+                    format!("ip:{}", remote_addr.ip())
+                } else {
+                    peer::peer_id(pk).unwrap_or_else(|e| e)
+                };
+                if !graph.vertices.contains_key(&peer_id) {
+                    let details = if pk == &[0; 32] {
+                        None
+                    } else {
+                        db.fetch_peer(pk).map_err(db_error)?
+                    };
+                    let version = details
+                        .as_ref()
+                        .and_then(|details| details.versions.last())
+                        .map(|version| DecoderStats::version_name(Some(version)));
+                    graph.vertices.insert(
+                        peer_id.clone(),
+                        Vertex {
+                            label: peer_id.clone(),
+                            kind: "peer",
+                            addresses: BTreeSet::new(),
+                            version,
+                        },
+                    );
+                }
+                if let Some(vertex) = graph.vertices.get_mut(&peer_id) {
+                    vertex.addresses.insert(remote_addr.ip().to_string());
+                }
+
+                let edge = graph
+                    .edges
+                    .entry((node_id.clone(), peer_id.clone()))
+                    .or_default();
+                edge.connections += 1;
+                if value.initiator().incoming() {
+                    edge.incoming += 1;
+                }
+                if value.acks().nack_motive().is_some() {
+                    edge.rejected += 1;
+                }
+                addrs.entry(peer_id).or_default().insert(remote_addr);
+            }
+            // the bandwidth is counted by the address, the connections may share it
+            for (peer_id, addrs) in addrs {
+                if let Some(edge) = graph.edges.get_mut(&(node_id.clone(), peer_id)) {
+                    edge.bytes = addrs
+                        .iter()
+                        .filter_map(|addr| bandwidth.get(&addr.to_string()))
+                        .sum();
+                }
+            }
+        }
+        Ok(graph)
+    }
+
+    pub fn render(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::GraphMl => self.graphml(),
+            GraphFormat::Dot => self.dot(),
+        }
+    }
+
+    fn graphml(&self) -> String {
+        fn escape(s: &str) -> String {
+            s.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;")
+        }
+
+        let mut s = String::new();
+        s.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        s.push_str("<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+        let keys = [
+            ("label", "node", "string"),
+            ("kind", "node", "string"),
+            ("addresses", "node", "string"),
+            ("version", "node", "string"),
+            ("weight", "edge", "long"),
+            ("connections", "edge", "long"),
+            ("incoming", "edge", "long"),
+            ("rejected", "edge", "long"),
+        ];
+        for (name, domain, ty) in &keys {
+            let _ = writeln!(
+                s,
+                "  <key id=\"{0}\" for=\"{1}\" attr.name=\"{0}\" attr.type=\"{2}\"/>",
+                name, domain, ty,
+            );
+        }
+        s.push_str("  <graph id=\"connections\" edgedefault=\"undirected\">\n");
+        for (id, vertex) in &self.vertices {
+            let _ = writeln!(s, "    <node id=\"{}\">", escape(id));
+            let addresses = vertex.addresses.iter().cloned().collect::<Vec<_>>().join(",");
+            let mut data = vec![
+                ("label", vertex.label.clone()),
+                ("kind", vertex.kind.to_string()),
+                ("addresses", addresses),
+            ];
+            if let Some(version) = &vertex.version {
+                data.push(("version", version.clone()));
+            }
+            for (key, value) in data {
+                let _ = writeln!(s, "      <data key=\"{}\">{}</data>", key, escape(&value));
+            }
+            s.push_str("    </node>\n");
+        }
+        for ((source, target), edge) in &self.edges {
+            let _ = writeln!(
+                s,
+                "    <edge source=\"{}\" target=\"{}\">",
+                escape(source),
+                escape(target),
+            );
+            let data = [
+                ("weight", edge.bytes),
+                ("connections", edge.connections),
+                ("incoming", edge.incoming),
+                ("rejected", edge.rejected),
+            ];
+            for (key, value) in &data {
+                let _ = writeln!(s, "      <data key=\"{}\">{}</data>", key, value);
+            }
+            s.push_str("    </edge>\n");
+        }
+        s.push_str("  </graph>\n</graphml>\n");
+        s
+    }
+
+    fn dot(&self) -> String {
+        fn quote(s: &str) -> String {
+            format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+        }
+
+        let mut s = String::new();
+        s.push_str("graph connections {\n");
+        for (id, vertex) in &self.vertices {
+            let shape = if vertex.kind == "node" { "box" } else { "ellipse" };
+            let addresses = vertex.addresses.iter().cloned().collect::<Vec<_>>().join(",");
+            let _ = write!(
+                s,
+                "  {} [label={}, kind={}, shape={}, addresses={}",
+                quote(id),
+                quote(&vertex.label),
+                quote(vertex.kind),
+                shape,
+                quote(&addresses),
+            );
+            if let Some(version) = &vertex.version {
+                let _ = write!(s, ", version={}", quote(version));
+            }
+            s.push_str("];\n");
+        }
+        for ((source, target), edge) in &self.edges {
+            let _ = writeln!(
+                s,
+                "  {} -- {} [weight={}, connections={}, incoming={}, rejected={}];",
+                quote(source),
+                quote(target),
+                edge.bytes,
+                edge.connections,
+                edge.incoming,
+                edge.rejected,
+            );
+        }
+        s.push_str("}\n");
+        s
+    }
+}
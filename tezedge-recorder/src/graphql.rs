@@ -0,0 +1,590 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{collections::HashMap, convert::TryFrom, sync::Arc};
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, InputObject, Json, Object, Result, Schema,
+    SimpleObject,
+};
+use serde::Serialize;
+use super::{
+    database::{
+        self, DatabaseFetch, ConnectionsFilter, MessagesFilter, LogsFilter, LogCountsFilter,
+    },
+    tables::{connection, message, node_log, peer, log_count},
+};
+
+pub type RecorderSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+/// The schema over the databases of the nodes, the key is the node name
+pub fn schema<Db>(dbs: HashMap<String, Arc<Db>>) -> RecorderSchema
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    let nodes = dbs
+        .into_iter()
+        .map(|(name, db)| (name, db as Arc<dyn Store>))
+        .collect::<Nodes>();
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .data(nodes)
+        .finish()
+}
+
+type Nodes = HashMap<String, Arc<dyn Store>>;
+
+/// The part of the `DatabaseFetch` the resolvers need, it is object safe,
+/// so the schema is not generic over the database
+trait Store: Sync + Send {
+    fn messages(&self, filter: &MessagesFilter) -> Result<Vec<message::MessageFrontend>, String>;
+    fn message(&self, id: u64) -> Result<Option<message::MessageDetails>, String>;
+    fn connections(
+        &self,
+        filter: &ConnectionsFilter,
+    ) -> Result<Vec<(connection::Key, connection::Value)>, String>;
+    fn logs(&self, filter: &LogsFilter) -> Result<Vec<node_log::ItemWithId>, String>;
+    fn log_counts(
+        &self,
+        filter: &LogCountsFilter,
+    ) -> Result<Vec<log_count::LevelCounts>, String>;
+    fn peer(&self, pk: &[u8; 32]) -> Result<Option<peer::Details>, String>;
+    fn stats(&self) -> Result<database::StorageStats, String>;
+}
+
+fn database_error<E>(err: E) -> String
+where
+    E: std::fmt::Display,
+{
+    format!("database error: {}", err)
+}
+
+impl<Db> Store for Db
+where
+    Db: DatabaseFetch + Sync + Send,
+{
+    fn messages(&self, filter: &MessagesFilter) -> Result<Vec<message::MessageFrontend>, String> {
+        self.fetch_messages(filter).map_err(database_error)
+    }
+
+    fn message(&self, id: u64) -> Result<Option<message::MessageDetails>, String> {
+        self.fetch_message(id).map_err(database_error)
+    }
+
+    fn connections(
+        &self,
+        filter: &ConnectionsFilter,
+    ) -> Result<Vec<(connection::Key, connection::Value)>, String> {
+        self.fetch_connections(filter).map_err(database_error)
+    }
+
+    fn logs(&self, filter: &LogsFilter) -> Result<Vec<node_log::ItemWithId>, String> {
+        self.fetch_log(filter).map_err(database_error)
+    }
+
+    fn log_counts(
+        &self,
+        filter: &LogCountsFilter,
+    ) -> Result<Vec<log_count::LevelCounts>, String> {
+        self.fetch_log_counts(filter).map_err(database_error)
+    }
+
+    fn peer(&self, pk: &[u8; 32]) -> Result<Option<peer::Details>, String> {
+        self.fetch_peer(pk).map_err(database_error)
+    }
+
+    fn stats(&self) -> Result<database::StorageStats, String> {
+        self.fetch_storage_stats().map_err(database_error)
+    }
+}
+
+fn node<'a>(ctx: &'a Context<'_>, node_name: Option<String>) -> Result<&'a Arc<dyn Store>> {
+    let node_name = node_name.unwrap_or("tezedge".to_string());
+    ctx.data_unchecked::<Nodes>()
+        .get(&node_name)
+        .ok_or_else(|| format!("no such node: {:?}", node_name).into())
+}
+
+/// The store is synchronous, the query runs on the blocking pool like the rest api handlers
+async fn query<T, F>(ctx: &Context<'_>, node_name: Option<String>, f: F) -> Result<T>
+where
+    F: FnOnce(&dyn Store) -> Result<T, String> + Send + 'static,
+    T: Send + 'static,
+{
+    let store = node(ctx, node_name)?.clone();
+    database::blocking(move || f(store.as_ref()))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(Into::into)
+}
+
+fn to_json<T>(value: &T) -> serde_json::Value
+where
+    T: Serialize,
+{
+    serde_json::to_value(value).unwrap_or_default()
+}
+
+// the name of the unit variant as the rest api renders it
+fn variant<T>(value: &T) -> String
+where
+    T: Serialize,
+{
+    to_json(value).as_str().unwrap_or_default().to_string()
+}
+
+// the enum argument given by its name, as in the rest api
+fn parse_variant<T>(name: Option<String>) -> Result<Option<T>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    match name {
+        None => Ok(None),
+        Some(name) => serde_json::from_value(serde_json::Value::String(name.clone()))
+            .map(Some)
+            .map_err(|_| format!("bad value: {:?}", name).into()),
+    }
+}
+
+/// The arguments are the same as the query arguments of `/v2/p2p`
+#[derive(InputObject, Default)]
+pub struct MessagesInput {
+    direction: Option<String>,
+    limit: Option<u64>,
+    cursor: Option<u64>,
+    remote_addr: Option<String>,
+    source_type: Option<String>,
+    incoming: Option<bool>,
+    types: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+    timestamp: Option<u64>,
+    block_hash: Option<String>,
+    operation_hash: Option<String>,
+    protocol_hash: Option<String>,
+}
+
+impl TryFrom<MessagesInput> for MessagesFilter {
+    type Error = async_graphql::Error;
+
+    fn try_from(v: MessagesInput) -> Result<Self> {
+        Ok(MessagesFilter {
+            direction: v.direction,
+            limit: v.limit,
+            cursor: v.cursor,
+            remote_addr: v.remote_addr,
+            source_type: parse_variant(v.source_type)?,
+            incoming: v.incoming,
+            types: v.types,
+            from: v.from,
+            to: v.to,
+            timestamp: v.timestamp,
+            block_hash: v.block_hash,
+            operation_hash: v.operation_hash,
+            protocol_hash: v.protocol_hash,
+            fields: None,
+            timeout_ms: None,
+            preset: None,
+            deadline: database::Deadline::default(),
+            node_name: None,
+        })
+    }
+}
+
+/// The arguments are the same as the query arguments of `/v2/log`
+#[derive(InputObject, Default)]
+pub struct LogsInput {
+    direction: Option<String>,
+    limit: Option<u64>,
+    cursor: Option<u64>,
+    log_level: Option<String>,
+    module: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+    timestamp: Option<u64>,
+    query: Option<String>,
+}
+
+impl From<LogsInput> for LogsFilter {
+    fn from(v: LogsInput) -> Self {
+        LogsFilter {
+            direction: v.direction,
+            limit: v.limit,
+            cursor: v.cursor,
+            log_level: v.log_level,
+            module: v.module,
+            from: v.from,
+            to: v.to,
+            timestamp: v.timestamp,
+            query: v.query,
+            timeout_ms: None,
+            preset: None,
+            deadline: database::Deadline::default(),
+            node_name: None,
+        }
+    }
+}
+
+pub struct Message {
+    node_name: Option<String>,
+    inner: message::MessageFrontend,
+}
+
+#[Object]
+impl Message {
+    async fn id(&self) -> u64 {
+        self.inner.id
+    }
+
+    /// unix nanoseconds
+    async fn timestamp(&self) -> u64 {
+        self.inner.timestamp as u64
+    }
+
+    async fn remote_addr(&self) -> String {
+        self.inner.remote_addr.to_string()
+    }
+
+    async fn source_type(&self) -> String {
+        variant(&self.inner.source_type)
+    }
+
+    async fn incoming(&self) -> bool {
+        self.inner.incoming
+    }
+
+    async fn category(&self) -> String {
+        variant(&self.inner.category)
+    }
+
+    async fn kind(&self) -> Option<String> {
+        self.inner.kind.as_ref().map(variant)
+    }
+
+    async fn preview(&self) -> Option<&str> {
+        self.inner.message_preview.as_deref()
+    }
+
+    /// the length of the decrypted message in bytes
+    async fn size(&self) -> usize {
+        self.inner.size
+    }
+
+    async fn annotations(&self) -> Json<serde_json::Value> {
+        Json(to_json(&self.inner.annotations))
+    }
+
+    /// The decoded message and its bytes as `/v2/p2p/{id}` returns,
+    /// it is fetched by the id only if requested
+    async fn details(&self, ctx: &Context<'_>) -> Result<Option<Json<serde_json::Value>>> {
+        let id = self.inner.id;
+        let details = query(ctx, self.node_name.clone(), move |s| s.message(id)).await?;
+        Ok(details.map(|d| Json(to_json(&d))))
+    }
+}
+
+pub struct Connection {
+    key: connection::Key,
+    value: serde_json::Value,
+}
+
+impl Connection {
+    fn new(key: connection::Key, value: &connection::Value) -> Self {
+        let nack_motive = value.acks().nack_motive().map(|m| variant(&m));
+        let mut value = to_json(value);
+        value["nack_motive"] = nack_motive.into();
+        Connection { key, value }
+    }
+
+    fn field(&self, name: &str) -> Option<String> {
+        self.value[name].as_str().map(str::to_string)
+    }
+}
+
+#[Object]
+impl Connection {
+    /// the key of the connection, `{seconds}.{nanos}`, as `/v2/chunks` expects
+    async fn id(&self) -> String {
+        self.key.to_string()
+    }
+
+    /// unix seconds
+    async fn timestamp(&self) -> u64 {
+        self.key.ts
+    }
+
+    async fn initiator(&self) -> Option<String> {
+        self.field("initiator")
+    }
+
+    async fn remote_addr(&self) -> Option<String> {
+        self.field("remote_addr")
+    }
+
+    async fn peer_id(&self) -> Option<String> {
+        self.field("peer_id")
+    }
+
+    async fn nack_motive(&self) -> Option<String> {
+        self.field("nack_motive")
+    }
+
+    async fn comments(&self) -> Json<serde_json::Value> {
+        Json(self.value["comments"].clone())
+    }
+
+    /// `{"kind": "reset", "by": "remote", "timestamp": ...}`, absent if the connection is alive
+    async fn termination(&self) -> Json<serde_json::Value> {
+        Json(self.value["termination"].clone())
+    }
+
+    async fn incoming_ack(&self) -> Json<serde_json::Value> {
+        Json(self.value["incoming_ack"].clone())
+    }
+
+    async fn outgoing_ack(&self) -> Json<serde_json::Value> {
+        Json(self.value["outgoing_ack"].clone())
+    }
+
+    /// `{"incoming": {"packets": ..., "ttl_min": ..., "mss": ...}, "outgoing": {...}}`,
+    /// absent unless the `tcp_metadata` is configured
+    async fn tcp(&self) -> Json<serde_json::Value> {
+        Json(self.value["tcp"].clone())
+    }
+
+    /// `{"bucket_secs": 10, "start": ..., "incoming": [...], "outgoing": [...]}`,
+    /// the bytes per bucket, `start` is the unix seconds of the first bucket
+    async fn throughput(&self) -> Json<serde_json::Value> {
+        Json(self.value["throughput"].clone())
+    }
+
+    /// `{"incoming": {"count": ..., "p50": ..., "p90": ..., "p99": ...}, "outgoing": {...}}`,
+    /// the latency of the reads and the writes in nanoseconds
+    async fn syscalls(&self) -> Json<serde_json::Value> {
+        Json(self.value["syscalls"].clone())
+    }
+
+    /// `{"connection_message": ..., "metadata": ..., "ack": ..., "total": ...}`,
+    /// the steps of the handshake in nanoseconds, absent if they are not timed
+    async fn handshake(&self) -> Json<serde_json::Value> {
+        Json(self.value["handshake"].clone())
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Log {
+    id: u64,
+    level: String,
+    /// unix nanoseconds
+    timestamp: u64,
+    section: String,
+    message: String,
+    annotations: Json<serde_json::Value>,
+}
+
+impl From<node_log::ItemWithId> for Log {
+    fn from(v: node_log::ItemWithId) -> Self {
+        Log {
+            id: v.id,
+            level: variant(&v.level),
+            timestamp: v.timestamp as u64,
+            annotations: Json(to_json(&v.annotations)),
+            section: v.section,
+            message: v.message,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct LevelCounts {
+    /// unix timestamp of the minute start, in milliseconds
+    minute: u64,
+    trace: u64,
+    debug: u64,
+    info: u64,
+    notice: u64,
+    warning: u64,
+    error: u64,
+    fatal: u64,
+}
+
+impl From<log_count::LevelCounts> for LevelCounts {
+    fn from(v: log_count::LevelCounts) -> Self {
+        LevelCounts {
+            minute: v.minute,
+            trace: v.trace,
+            debug: v.debug,
+            info: v.info,
+            notice: v.notice,
+            warning: v.warning,
+            error: v.error,
+            fatal: v.fatal,
+        }
+    }
+}
+
+pub struct Peer {
+    inner: peer::Details,
+}
+
+#[Object]
+impl Peer {
+    async fn peer_id(&self) -> &str {
+        &self.inner.peer_id
+    }
+
+    async fn public_key(&self) -> &str {
+        &self.inner.public_key
+    }
+
+    async fn first_seen(&self) -> String {
+        self.inner.first_seen.to_string()
+    }
+
+    async fn addresses(&self) -> Vec<String> {
+        self.inner.addresses.iter().map(ToString::to_string).collect()
+    }
+
+    async fn versions(&self) -> Json<serde_json::Value> {
+        Json(to_json(&self.inner.versions))
+    }
+
+    async fn connections(&self) -> Vec<Connection> {
+        self.inner
+            .connections
+            .iter()
+            .map(|(key, value)| Connection::new(key.clone(), value))
+            .collect()
+    }
+}
+
+/// The `count` is estimated, the `total` is how many records was ever stored
+#[derive(SimpleObject)]
+pub struct StoreStats {
+    count: u64,
+    total: u64,
+    limit: Option<u64>,
+}
+
+impl From<&database::StoreStats> for StoreStats {
+    fn from(v: &database::StoreStats) -> Self {
+        StoreStats {
+            count: v.count,
+            total: v.total,
+            limit: v.limit,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct StorageStats {
+    p2p: StoreStats,
+    log: StoreStats,
+}
+
+pub struct Query;
+
+/// Every field takes the `nodeName`, it is `tezedge` by default, like in the rest api,
+/// the filters are the same, so the queries use the same secondary indexes
+#[Object]
+impl Query {
+    async fn messages(
+        &self,
+        ctx: &Context<'_>,
+        node_name: Option<String>,
+        filter: Option<MessagesInput>,
+    ) -> Result<Vec<Message>> {
+        let filter = MessagesFilter::try_from(filter.unwrap_or_default())?;
+        let messages = query(ctx, node_name.clone(), move |s| s.messages(&filter)).await?;
+        Ok(messages
+            .into_iter()
+            .map(|inner| Message {
+                node_name: node_name.clone(),
+                inner,
+            })
+            .collect())
+    }
+
+    async fn message(
+        &self,
+        ctx: &Context<'_>,
+        node_name: Option<String>,
+        id: u64,
+    ) -> Result<Option<Json<serde_json::Value>>> {
+        let details = query(ctx, node_name, move |s| s.message(id)).await?;
+        Ok(details.map(|d| Json(to_json(&d))))
+    }
+
+    async fn connections(
+        &self,
+        ctx: &Context<'_>,
+        node_name: Option<String>,
+        limit: Option<u64>,
+        nack_motive: Option<String>,
+        termination: Option<String>,
+        handshake_slower_than: Option<u64>,
+    ) -> Result<Vec<Connection>> {
+        let filter = ConnectionsFilter {
+            limit,
+            nack_motive: parse_variant(nack_motive)?,
+            termination: parse_variant(termination)?,
+            handshake_slower_than,
+            after: None,
+            external: None,
+        };
+        let connections = query(ctx, node_name, move |s| s.connections(&filter)).await?;
+        Ok(connections
+            .into_iter()
+            .map(|(key, value)| Connection::new(key, &value))
+            .collect())
+    }
+
+    async fn logs(
+        &self,
+        ctx: &Context<'_>,
+        node_name: Option<String>,
+        filter: Option<LogsInput>,
+    ) -> Result<Vec<Log>> {
+        let filter = LogsFilter::from(filter.unwrap_or_default());
+        let logs = query(ctx, node_name, move |s| s.logs(&filter)).await?;
+        Ok(logs.into_iter().map(Log::from).collect())
+    }
+
+    async fn log_counts(
+        &self,
+        ctx: &Context<'_>,
+        node_name: Option<String>,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> Result<Vec<LevelCounts>> {
+        let filter = LogCountsFilter {
+            from,
+            to,
+            gaps: None,
+            node_name: None,
+        };
+        let counts = query(ctx, node_name, move |s| s.log_counts(&filter)).await?;
+        Ok(counts.into_iter().map(LevelCounts::from).collect())
+    }
+
+    /// The peer by its public key, 32 bytes hex
+    async fn peer(
+        &self,
+        ctx: &Context<'_>,
+        node_name: Option<String>,
+        public_key: String,
+    ) -> Result<Option<Peer>> {
+        let pk = hex::decode(&public_key)
+            .ok()
+            .and_then(|v| <[u8; 32]>::try_from(v).ok())
+            .ok_or_else(|| format!("bad public key: {:?}, expected 32 bytes hex", public_key))?;
+        let peer = query(ctx, node_name, move |s| s.peer(&pk)).await?;
+        Ok(peer.map(|inner| Peer { inner }))
+    }
+
+    async fn stats(&self, ctx: &Context<'_>, node_name: Option<String>) -> Result<StorageStats> {
+        let stats = query(ctx, node_name, |s| s.stats()).await?;
+        Ok(StorageStats {
+            p2p: StoreStats::from(&stats.p2p),
+            log: StoreStats::from(&stats.log),
+        })
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    sync::{
+        Mutex,
+        atomic::{Ordering, AtomicUsize},
+    },
+};
+use serde::Serialize;
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status", content = "reason")]
+pub enum Status {
+    Starting,
+    Up,
+    Down(String),
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    /// no component is down
+    pub healthy: bool,
+    /// every component is up
+    pub ready: bool,
+    pub components: BTreeMap<String, Status>,
+    /// how many captured chunks of data wait for the parsers
+    pub parser_queue: usize,
+}
+
+/// The status of the subsystems for the orchestration probes,
+/// the database directories are checked for writing at every probe
+#[derive(Default)]
+pub struct Health {
+    components: Mutex<BTreeMap<String, Status>>,
+    dirs: Mutex<BTreeMap<String, PathBuf>>,
+    parser_queue: AtomicUsize,
+    parser_capacity: AtomicUsize,
+}
+
+impl Health {
+    pub fn set(&self, component: &str, status: Status) {
+        if let Status::Down(reason) = &status {
+            log::error!("{} is down: {}", component, reason);
+        }
+        self.components
+            .lock()
+            .unwrap()
+            .insert(component.to_string(), status);
+    }
+
+    pub fn watch_dir(&self, component: String, path: PathBuf) {
+        self.dirs.lock().unwrap().insert(component, path);
+    }
+
+    pub fn set_parser_capacity(&self, capacity: usize) {
+        self.parser_capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    pub fn parser_enqueued(&self) {
+        self.parser_queue.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parser_done(&self) {
+        self.parser_queue.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> Report {
+        let mut components = self.components.lock().unwrap().clone();
+        for (component, path) in self.dirs.lock().unwrap().iter() {
+            let probe = path.join(".healthz");
+            let status = match fs::write(&probe, b"").and_then(|()| fs::remove_file(&probe)) {
+                Ok(()) => Status::Up,
+                Err(error) => Status::Down(format!("{} is not writable: {}", path.display(), error)),
+            };
+            components.insert(component.clone(), status);
+        }
+        let parser_queue = self.parser_queue.load(Ordering::Relaxed);
+        let capacity = self.parser_capacity.load(Ordering::Relaxed);
+        if capacity != 0 {
+            let status = if parser_queue >= capacity {
+                Status::Down(format!("the queue is full, {} chunks wait", parser_queue))
+            } else {
+                Status::Up
+            };
+            components.insert("parser".to_string(), status);
+        }
+
+        Report {
+            healthy: !components.values().any(|s| matches!(s, Status::Down(_))),
+            ready: components.values().all(|s| matches!(s, Status::Up)),
+            components,
+            parser_queue,
+        }
+    }
+}
@@ -14,5 +14,37 @@ mod processor;
 pub mod main_loop;
 pub mod database;
 mod server;
+mod graphql;
+mod openapi;
+mod csv;
+mod encoding;
+mod anonymize;
+mod escrow;
+mod limiter;
+mod control;
+mod control_socket;
+mod health;
+mod pipeline;
+mod coverage;
+mod scoring;
+mod decoder_stats;
+mod sampling;
+mod self_monitor;
+mod conformance;
+mod gaps;
+mod graph;
+mod ask;
+pub mod conversation;
+mod flood;
+mod tcp_meta;
+mod grafana;
+mod mailbox;
+mod resume;
+mod netns;
+mod discovery;
+mod backup;
+pub mod bench;
+pub mod verify;
 
-pub use self::system::System;
+pub use self::system::{System, Overrides, ConfigError, LoggingConfig};
+pub use self::health::Status as HealthStatus;
@@ -0,0 +1,170 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use serde::{Serialize, Deserialize};
+use warp::{Filter, Rejection, Reply, http::StatusCode, reject, reply};
+use super::database::Deadline;
+
+/// The limits of the http api, every key is optional, no limit if absent
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimiterConfig {
+    /// how many requests per second a client (ip address) can do in average
+    requests_per_second: Option<f64>,
+    /// how many requests a client can do at once, default is `requests_per_second`
+    burst: Option<f64>,
+    /// how many database queries (message and log filtering) can run at the same time
+    max_concurrent_queries: Option<usize>,
+    /// milliseconds, the message and log query gives up after, unless the request sets
+    /// its own `timeout_ms`
+    query_timeout_ms: Option<u64>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Token bucket per client ip, and the counter of running queries
+pub struct Limiter {
+    config: LimiterConfig,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    queries: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct TooManyRequests;
+
+impl reject::Reject for TooManyRequests {}
+
+/// Holds the slot of running query, releases it on drop
+pub struct QueryPermit(Option<Arc<Limiter>>);
+
+impl Drop for QueryPermit {
+    fn drop(&mut self) {
+        if let Some(limiter) = &self.0 {
+            limiter.queries.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Limiter {
+    // forget the clients who have been idle long enough to refill the bucket
+    const MAX_CLIENTS: usize = 0x1000;
+
+    pub fn new(config: Option<LimiterConfig>) -> Arc<Self> {
+        Arc::new(Limiter {
+            config: config.unwrap_or_default(),
+            buckets: Mutex::new(HashMap::new()),
+            queries: AtomicUsize::new(0),
+        })
+    }
+
+    fn allow(&self, ip: IpAddr) -> bool {
+        let rate = match self.config.requests_per_second {
+            Some(rate) => rate,
+            None => return true,
+        };
+        let burst = self.config.burst.unwrap_or(rate).max(1.0);
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > Self::MAX_CLIENTS {
+            buckets.retain(|_, b| b.tokens + (now - b.last).as_secs_f64() * rate < burst);
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+        });
+        bucket.tokens = (bucket.tokens + (now - bucket.last).as_secs_f64() * rate).min(burst);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn try_query(self: &Arc<Self>) -> Option<QueryPermit> {
+        let max = match self.config.max_concurrent_queries {
+            Some(max) => max,
+            None => return Some(QueryPermit(None)),
+        };
+        let mut current = self.queries.load(Ordering::SeqCst);
+        loop {
+            if current >= max {
+                return None;
+            }
+            match self.queries.compare_exchange(
+                current,
+                current + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return Some(QueryPermit(Some(self.clone()))),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// The deadline of the query, the one of the request or the default
+    pub fn deadline(&self, timeout_ms: Option<u64>) -> Deadline {
+        match timeout_ms.or(self.config.query_timeout_ms) {
+            Some(timeout_ms) => Deadline::new(Duration::from_millis(timeout_ms)),
+            None => Deadline::default(),
+        }
+    }
+
+    /// Rejects the request if the client exceeded its rate
+    pub fn rate(
+        self: &Arc<Self>,
+    ) -> impl Filter<Extract = (), Error = Rejection> + Clone + Send + Sync + 'static {
+        let limiter = self.clone();
+        warp::addr::remote()
+            .and_then(move |addr: Option<SocketAddr>| {
+                let limiter = limiter.clone();
+                async move {
+                    match addr {
+                        Some(addr) if !limiter.allow(addr.ip()) => {
+                            Err(reject::custom(TooManyRequests))
+                        },
+                        _ => Ok(()),
+                    }
+                }
+            })
+            .untuple_one()
+    }
+
+    /// Rejects the request if too many queries are running,
+    /// the permit should be kept until the query is done
+    pub fn query(
+        self: &Arc<Self>,
+    ) -> impl Filter<Extract = (QueryPermit,), Error = Rejection> + Clone + Send + Sync + 'static {
+        let limiter = self.clone();
+        warp::any().and_then(move || {
+            let permit = limiter.try_query();
+            async move { permit.ok_or_else(|| reject::custom(TooManyRequests)) }
+        })
+    }
+}
+
+/// Turns the rejection of the limiter into `429 Too Many Requests`
+pub async fn recover(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<TooManyRequests>().is_some() {
+        let r = reply::json(&"too many requests");
+        let r = reply::with_status(r, StatusCode::TOO_MANY_REQUESTS);
+        Ok(reply::with_header(r, "Retry-After", "1"))
+    } else {
+        Err(rejection)
+    }
+}
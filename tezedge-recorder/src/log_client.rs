@@ -4,14 +4,30 @@
 use std::{
     sync::{
         Arc,
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
     },
     thread, io,
-    net::UdpSocket,
+    io::{Read, BufReader, Seek, SeekFrom},
+    fs::File,
+    net::{UdpSocket, TcpListener},
+    path::{Path, PathBuf},
     time::Duration,
 };
+use rustls::{ServerConfig, ServerSession, StreamOwned, NoClientAuth};
+use inotify::{Inotify, WatchMask, EventMask};
 use super::{database::Database, tables::node_log};
 
+fn store<Db>(db: &Db, data: &[u8])
+where
+    Db: Database,
+{
+    if let Ok(log) = std::str::from_utf8(data) {
+        let msg = syslog_loose::parse_message(log);
+        let item = node_log::Item::from(msg);
+        db.store_log(item);
+    }
+}
+
 pub fn spawn<Db>(
     port: u16,
     db: Arc<Db>,
@@ -26,13 +42,7 @@ where
         let mut buffer = [0u8; 0x10000];
         while running.load(Ordering::Relaxed) {
             match socket.recv(&mut buffer) {
-                Ok(read) => {
-                    if let Ok(log) = std::str::from_utf8(&buffer[..read]) {
-                        let msg = syslog_loose::parse_message(log);
-                        let item = node_log::Item::from(msg);
-                        db.store_log(item);
-                    }
-                },
+                Ok(read) => store(db.as_ref(), &buffer[..read]),
                 Err(error) => {
                     if error.kind() == io::ErrorKind::WouldBlock {
                         log::trace!("receiving log timeout");
@@ -44,3 +54,439 @@ where
         }
     }))
 }
+
+/// Loads the certificate chain and the private key (pkcs8 or rsa) from pem files
+pub fn tls_config(cert_path: &str, key_path: &str) -> io::Result<Arc<ServerConfig>> {
+    use rustls::internal::pemfile;
+
+    let bad_data = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let certs = pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|()| bad_data("failed to parse certificate"))?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|()| bad_data("failed to parse private key"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|()| bad_data("failed to parse private key"))?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| bad_data("no private key"))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|error| bad_data(&error.to_string()))?;
+    Ok(Arc::new(config))
+}
+
+// the clients connected at once, more are refused
+const MAX_TCP_CLIENTS: usize = 64;
+
+// the same as the datagram of the udp path
+const MAX_FRAME: usize = 0x10000;
+
+// decrements the number of the connected clients when the client thread ends
+struct ClientSlot(Arc<AtomicUsize>);
+
+impl ClientSlot {
+    fn acquire(clients: &Arc<AtomicUsize>) -> Option<Self> {
+        if clients.fetch_add(1, Ordering::SeqCst) < MAX_TCP_CLIENTS {
+            Some(ClientSlot(clients.clone()))
+        } else {
+            clients.fetch_sub(1, Ordering::SeqCst);
+            None
+        }
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Syslog over tcp, RFC 6587, optionally wrapped in tls
+pub fn spawn_tcp<Db>(
+    port: u16,
+    tls: Option<Arc<ServerConfig>>,
+    db: Arc<Db>,
+    running: Arc<AtomicBool>,
+) -> io::Result<thread::JoinHandle<()>>
+where
+    Db: Database + Sync + Send + 'static,
+{
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    listener.set_nonblocking(true)?;
+    Ok(thread::spawn(move || {
+        let clients = Arc::new(AtomicUsize::new(0));
+        while running.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, address)) => {
+                    let slot = match ClientSlot::acquire(&clients) {
+                        Some(slot) => slot,
+                        None => {
+                            log::warn!("too many log clients, refused: {}", address);
+                            continue;
+                        },
+                    };
+                    log::info!("log client connected: {}", address);
+                    let db = db.clone();
+                    let running = running.clone();
+                    let tls = tls.clone();
+                    thread::spawn(move || {
+                        let _slot = slot;
+                        let result = stream
+                            .set_nonblocking(false)
+                            .and_then(|()| stream.set_read_timeout(Some(Duration::from_secs(5))));
+                        if let Err(error) = result {
+                            log::error!("failed to setup log client socket: {}", error);
+                            return;
+                        }
+                        match tls {
+                            Some(config) => {
+                                let session = ServerSession::new(&config);
+                                handle_stream(StreamOwned::new(session, stream), db, running)
+                            },
+                            None => handle_stream(stream, db, running),
+                        }
+                        log::info!("log client disconnected: {}", address);
+                    });
+                },
+                Err(error) => {
+                    if error.kind() == io::ErrorKind::WouldBlock {
+                        thread::sleep(Duration::from_millis(100));
+                    } else {
+                        log::error!("accepting log client error: {}", error)
+                    }
+                },
+            }
+        }
+    }))
+}
+
+fn handle_stream<S, Db>(mut stream: S, db: Arc<Db>, running: Arc<AtomicBool>)
+where
+    S: Read,
+    Db: Database,
+{
+    let mut buffer = [0u8; 0x10000];
+    let mut frames = FrameReader::default();
+    while running.load(Ordering::Relaxed) {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => {
+                frames.extend(&buffer[..read]);
+                while let Some(frame) = frames.next_frame() {
+                    store(db.as_ref(), &frame);
+                }
+            },
+            Err(error) => match error.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                    log::trace!("receiving log timeout");
+                },
+                io::ErrorKind::Interrupted => (),
+                _ => {
+                    log::error!("receiving log error: {}", error);
+                    break;
+                },
+            },
+        }
+    }
+}
+
+/// Splits the tcp stream into syslog messages. The message is either prefixed
+/// by its length and a space (octet-counting), or terminated by LF or NUL (non-transparent).
+/// The message longer than `MAX_FRAME` is dropped, so the buffer never grows beyond it.
+#[derive(Default)]
+struct FrameReader {
+    buffer: Vec<u8>,
+    // the rest of the dropped octet-counting message, not yet received
+    skip: usize,
+    // the dropped non-transparent message continues until its terminator
+    discard: bool,
+    dropped: u64,
+}
+
+impl FrameReader {
+    // more than enough to hold the length of any reasonable message
+    const MAX_LENGTH_DIGITS: usize = 9;
+
+    fn extend(&mut self, data: &[u8]) {
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        let mut data = &data[skipped..];
+        if self.discard {
+            match data.iter().position(|b| matches!(b, b'\n' | b'\0')) {
+                Some(end) => {
+                    self.discard = false;
+                    data = &data[(end + 1)..];
+                },
+                None => return,
+            }
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    fn drop_frame(&mut self) {
+        self.dropped += 1;
+        log::warn!(
+            "syslog message is longer than {} bytes, dropped, {} in total",
+            MAX_FRAME,
+            self.dropped,
+        );
+    }
+
+    fn next_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            // skip the trailer of the previous message
+            let start = self
+                .buffer
+                .iter()
+                .position(|b| !matches!(b, b'\n' | b'\r' | b'\0'))
+                .unwrap_or(self.buffer.len());
+            self.buffer.drain(..start);
+
+            if self.buffer.first()?.is_ascii_digit() {
+                let digits = self.buffer.iter().take_while(|b| b.is_ascii_digit()).count();
+                match self.buffer.get(digits) {
+                    Some(b' ') if digits <= Self::MAX_LENGTH_DIGITS => {
+                        let length = std::str::from_utf8(&self.buffer[..digits])
+                            .ok()?
+                            .parse::<usize>()
+                            .ok()?;
+                        let end = digits + 1 + length;
+                        if length > MAX_FRAME {
+                            self.drop_frame();
+                            if self.buffer.len() < end {
+                                self.skip = end - self.buffer.len();
+                                self.buffer.clear();
+                                return None;
+                            }
+                            self.buffer.drain(..end);
+                            continue;
+                        }
+                        if self.buffer.len() < end {
+                            return None;
+                        }
+                        let frame = self.buffer[(digits + 1)..end].to_vec();
+                        self.buffer.drain(..end);
+                        return Some(frame);
+                    },
+                    None if digits <= Self::MAX_LENGTH_DIGITS => return None,
+                    // malformed length, fallback to non-transparent framing
+                    _ => (),
+                }
+            }
+
+            match self.buffer.iter().position(|b| matches!(b, b'\n' | b'\0')) {
+                Some(end) if end > MAX_FRAME => {
+                    self.drop_frame();
+                    self.buffer.drain(..=end);
+                },
+                Some(end) => {
+                    let frame = self.buffer[..end].to_vec();
+                    self.buffer.drain(..=end);
+                    return Some(frame);
+                },
+                None => {
+                    // the terminator is not even close, drop the message and wait for it
+                    if self.buffer.len() > MAX_FRAME {
+                        self.drop_frame();
+                        self.buffer.clear();
+                        self.discard = true;
+                    }
+                    return None;
+                },
+            }
+        }
+    }
+}
+
+/// Follows the log file like `tail -F`. The directory is watched rather than the file,
+/// so the file is reopened when it is rotated (renamed or removed and created again),
+/// and read from the beginning when it is truncated in place.
+pub fn spawn_file<Db>(
+    path: PathBuf,
+    db: Arc<Db>,
+    running: Arc<AtomicBool>,
+) -> io::Result<thread::JoinHandle<()>>
+where
+    Db: Database + Sync + Send + 'static,
+{
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "log file path has no file name"))?
+        .to_owned();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+        _ => PathBuf::from("."),
+    };
+    let mut inotify = Inotify::init()?;
+    inotify.add_watch(&dir, WatchMask::MODIFY | WatchMask::CREATE | WatchMask::MOVED_TO)?;
+
+    Ok(thread::spawn(move || {
+        let mut buffer = [0u8; 0x1000];
+        let mut tail = FileTail::open(&path, true);
+        while running.load(Ordering::Relaxed) {
+            let (mut modified, mut created) = (false, false);
+            match inotify.read_events(&mut buffer) {
+                Ok(events) => {
+                    for event in events.filter(|e| e.name == Some(name.as_os_str())) {
+                        if event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                            created = true;
+                        } else {
+                            modified = true;
+                        }
+                    }
+                },
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => (),
+                Err(error) => {
+                    log::error!("watching log file error: {}", error);
+                    break;
+                },
+            }
+            if !modified && !created {
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            // the rest of the old file first
+            tail.read(db.as_ref());
+            if created {
+                log::info!("log file is recreated: {:?}", path);
+                tail = FileTail::open(&path, false);
+                tail.read(db.as_ref());
+            }
+        }
+    }))
+}
+
+struct FileTail {
+    file: Option<File>,
+    position: u64,
+    partial: Vec<u8>,
+}
+
+impl FileTail {
+    fn open(path: &Path, from_end: bool) -> Self {
+        let (file, position) = match File::open(path) {
+            Ok(mut file) => {
+                let position = if from_end {
+                    file.seek(SeekFrom::End(0)).unwrap_or(0)
+                } else {
+                    0
+                };
+                (Some(file), position)
+            },
+            Err(error) => {
+                log::warn!("cannot open log file {:?}: {}, waiting for it", path, error);
+                (None, 0)
+            },
+        };
+        FileTail {
+            file,
+            position,
+            partial: Vec::new(),
+        }
+    }
+
+    fn read<Db>(&mut self, db: &Db)
+    where
+        Db: Database,
+    {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return,
+        };
+        // truncated in place, e.g. logrotate `copytruncate`
+        if file.metadata().map(|m| m.len() < self.position).unwrap_or(false) {
+            if let Err(error) = file.seek(SeekFrom::Start(0)) {
+                log::error!("reading log file error: {}", error);
+                return;
+            }
+            self.position = 0;
+            self.partial.clear();
+        }
+        match file.read_to_end(&mut self.partial) {
+            Ok(read) => self.position += read as u64,
+            Err(error) => {
+                log::error!("reading log file error: {}", error);
+                return;
+            },
+        }
+        while let Some(end) = self.partial.iter().position(|b| *b == b'\n') {
+            let line = self.partial.drain(..=end).collect::<Vec<_>>();
+            let line = line[..end].strip_suffix(b"\r").unwrap_or(&line[..end]);
+            if !line.is_empty() {
+                store(db, line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FrameReader, MAX_FRAME};
+
+    fn frames(chunks: &[&[u8]]) -> (Vec<Vec<u8>>, FrameReader) {
+        let mut reader = FrameReader::default();
+        let mut frames = vec![];
+        for chunk in chunks {
+            reader.extend(chunk);
+            while let Some(frame) = reader.next_frame() {
+                frames.push(frame);
+            }
+        }
+        (frames, reader)
+    }
+
+    #[test]
+    fn octet_counting() {
+        let (frames, _) = frames(&[b"5 hello11 hello world3 abc"]);
+        assert_eq!(frames, vec![b"hello".to_vec(), b"hello world".to_vec(), b"abc".to_vec()]);
+    }
+
+    #[test]
+    fn non_transparent() {
+        let (frames, reader) = frames(&[b"<13>first\n<13>second\0\r\n<13>third\n<13>partial"]);
+        let expected = vec![b"<13>first".to_vec(), b"<13>second".to_vec(), b"<13>third".to_vec()];
+        assert_eq!(frames, expected);
+        assert_eq!(reader.buffer, b"<13>partial".to_vec());
+    }
+
+    #[test]
+    fn split_reads() {
+        let (frames, _) = frames(&[b"1", b"1 hello", b" world<13", b">next", b"\n"]);
+        assert_eq!(frames, vec![b"hello world".to_vec(), b"<13>next".to_vec()]);
+    }
+
+    #[test]
+    fn malformed_length() {
+        // not followed by a space, or too many digits, the message is terminated by LF
+        let (frames, _) = frames(&[b"12x message\n1234567890 message\n"]);
+        let expected = vec![b"12x message".to_vec(), b"1234567890 message".to_vec()];
+        assert_eq!(frames, expected);
+    }
+
+    #[test]
+    fn oversized_octet_counting() {
+        let length = MAX_FRAME + 1;
+        let header = format!("{} ", length);
+        let body = vec![b'a'; length];
+        let (half, rest) = body.split_at(length / 2);
+        let (frames, reader) = frames(&[header.as_bytes(), half, rest, b"2 ok"]);
+        assert_eq!(frames, vec![b"ok".to_vec()]);
+        assert_eq!(reader.dropped, 1);
+        assert!(reader.buffer.is_empty());
+    }
+
+    #[test]
+    fn oversized_non_transparent() {
+        let body = vec![b'a'; MAX_FRAME];
+        let (frames, reader) = frames(&[&body, &body, b"tail\n<13>ok\n"]);
+        assert_eq!(frames, vec![b"<13>ok".to_vec()]);
+        assert_eq!(reader.dropped, 1);
+        assert!(reader.buffer.len() <= MAX_FRAME);
+    }
+}
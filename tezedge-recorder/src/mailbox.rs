@@ -0,0 +1,121 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, Condvar},
+};
+use serde::{Serialize, Deserialize};
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// the producer waits, so the ring buffer fills up and the kernel drops the events
+    Block,
+    /// the oldest captured data is dropped, its connection is not recorded any further
+    DropOldest,
+}
+
+impl Default for Overflow {
+    fn default() -> Self {
+        Overflow::Block
+    }
+}
+
+/// The queue of each decoder thread, every key is optional
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueueConfig {
+    /// how many events wait for the decoder thread, 4096 by default
+    capacity: Option<usize>,
+    /// what happens when the queue is full, `block` by default
+    overflow: Option<Overflow>,
+}
+
+impl QueueConfig {
+    pub fn capacity(&self) -> usize {
+        self.capacity.unwrap_or(0x1000).max(1)
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow.unwrap_or_default()
+    }
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    closed: bool,
+}
+
+/// The bounded queue between the threads which either blocks or drops the oldest item
+/// when it is full
+pub struct Mailbox<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl<T> Mailbox<T> {
+    pub fn new(config: &QueueConfig) -> Self {
+        Mailbox {
+            state: Mutex::new(State {
+                queue: VecDeque::with_capacity(config.capacity()),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity: config.capacity(),
+            overflow: config.overflow(),
+        }
+    }
+
+    /// Returns the dropped item, only the item the `droppable` accepts is dropped,
+    /// if the queue is full of other items it blocks regardless of the overflow policy,
+    /// `Err` returns the item back if the mailbox is closed
+    pub fn push<F>(&self, item: T, droppable: F) -> Result<Option<T>, T>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let mut state = self.state.lock().unwrap();
+        let mut dropped = None;
+        while !state.closed && state.queue.len() >= self.capacity {
+            if self.overflow == Overflow::DropOldest {
+                if let Some(position) = state.queue.iter().position(|item| droppable(item)) {
+                    dropped = state.queue.remove(position);
+                    break;
+                }
+            }
+            state = self.not_full.wait(state).unwrap();
+        }
+        if state.closed {
+            return Err(item);
+        }
+        state.queue.push_back(item);
+        self.not_empty.notify_one();
+        Ok(dropped)
+    }
+
+    /// `None` if the mailbox is closed and empty
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(item) = state.queue.pop_front() {
+                self.not_full.notify_one();
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.not_empty.wait(state).unwrap();
+        }
+    }
+
+    /// The items in the queue are still delivered, the new ones are refused
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+}
@@ -2,12 +2,20 @@
 // SPDX-License-Identifier: MIT
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet, hash_map::DefaultHasher},
+    fs,
+    hash::{Hash, Hasher},
+    mem,
     net::SocketAddr,
+    os::unix::fs::MetadataExt,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
     sync::{
         Arc,
         atomic::{Ordering, AtomicBool},
     },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use anyhow::Result;
 use bpf_recorder::{BpfModuleClient, SnifferEvent, Command, EventId, SocketId};
@@ -15,82 +23,418 @@ use bpf_recorder::{BpfModuleClient, SnifferEvent, Command, EventId, SocketId};
 use super::{
     processor::Connection,
     database::{Database, DatabaseNew, DatabaseFetch},
+    tables::{
+        chunk, node_log,
+        connection::{self, TerminationKind, TcpStats},
+    },
     system::System,
+    control::Control,
+    health::{Health, Status},
+    pipeline::Stage,
+    mailbox::{Mailbox, QueueConfig},
+    resume::{ResumeStore, Entry},
+    discovery::DiscoveryConfig,
+    coverage::Coverage,
+    flood::FloodDetector,
+    tcp_meta::TcpMeta,
 };
 
-pub fn run<Db>(system: &mut System<Db>, running: Arc<AtomicBool>) -> Result<()>
+/// `decode_threads` is how many threads decrypt and parse the data,
+/// all data of the connection is handled by the same thread
+pub fn run<Db>(
+    system: &mut System<Db>,
+    running: Arc<AtomicBool>,
+    decode_threads: usize,
+) -> Result<()>
+where
+    Db: Database + DatabaseNew + DatabaseFetch + Sync + Send + 'static,
+{
+    let health = system.health();
+    let result = run_inner(system, running, decode_threads);
+    match &result {
+        Ok(()) => health.set("bpf", Status::Down("stopped".to_string())),
+        Err(error) => health.set("bpf", Status::Down(error.to_string())),
+    }
+    result
+}
+
+fn run_inner<Db>(
+    system: &mut System<Db>,
+    running: Arc<AtomicBool>,
+    decode_threads: usize,
+) -> Result<()>
 where
     Db: Database + DatabaseNew + DatabaseFetch + Sync + Send + 'static,
 {
     let (client, mut rb) = BpfModuleClient::new_sync(system.sniffer_path())?;
-    let mut list = ConnectionList::new(client, system);
+    let producer = system.pipeline().stage("producer", None);
+    let (tcp, tcp_threads) = match system.tcp_meta_config() {
+        None => (None, vec![]),
+        Some(config) => match TcpMeta::spawn(&config, running.clone()) {
+            Ok((tcp, handles)) => (Some(tcp), handles),
+            Err(error) => {
+                log::error!("cannot capture the tcp metadata: {}", error);
+                (None, vec![])
+            },
+        },
+    };
+    let mut list = ConnectionList::new(client, system, decode_threads, tcp);
     list.watching()?;
+    list.resume();
+    list.health.set("bpf", Status::Up);
 
+    let discovery = list.system.discovery_config();
+    let mut last_scan = None::<Instant>;
+    let mut last_reconcile = Instant::now();
+    // the events of the new module before the handover are taken from the old one
+    let mut cutover = 0;
     while running.load(Ordering::Relaxed) {
-        let events = rb.read_blocking::<SnifferEvent>(&running)?;
-        for event in events {
-            match event {
-                SnifferEvent::Bind { id, address } => {
-                    // TODO: remove old connections on this port
-                    if let Err(error) = list.system.handle_bind(id.socket_id.pid, address.port()) {
-                        log::error!("failed to handle bind syscall: {}", error);
+        if list.module_replaced() {
+            match BpfModuleClient::new_sync(list.system.sniffer_path()) {
+                Ok((client, new_rb)) => {
+                    let mut old_rb = mem::replace(&mut rb, new_rb);
+                    cutover = list.handover(client)?;
+                    // the old module is detached, what it wrote before the cutover remains
+                    loop {
+                        let timeout = Duration::from_millis(100);
+                        let events = old_rb.read_timeout::<SnifferEvent>(&running, timeout)?;
+                        if events.is_empty() {
+                            break;
+                        }
+                        producer.processed(events.len() as u64);
+                        for event in events {
+                            if event.id().ts_finish() < cutover {
+                                list.handle_event(event);
+                            }
+                            list.orchestrator.processed(1);
+                        }
                     }
+                    log::info!("the new bpf module took over");
                 },
-                SnifferEvent::Listen { id } => {
-                    let _ = id;
-                },
-                SnifferEvent::Connect { id, address } => {
-                    list.handle_connection(id, address, false);
-                },
-                SnifferEvent::Accept {
-                    id,
-                    address,
-                    listen_on_fd,
-                } => {
-                    let _ = listen_on_fd;
-                    list.handle_connection(id, address, true);
+                Err(error) => log::error!("cannot connect to the new bpf module: {}", error),
+            }
+        }
+        if list.control.take_ignored_changed() {
+            list.ignore();
+        }
+        if list.control.take_payload_changed() {
+            list.limit_payload()?;
+        }
+        for node_name in list.control.take_reloads() {
+            if let Err(error) = list.system.reload_identity(&node_name) {
+                log::error!("failed to reload identity of {}: {}", node_name, error);
+            }
+        }
+        if let Some(discovery) = &discovery {
+            if last_scan.map_or(true, |last| last.elapsed() >= discovery.interval()) {
+                list.discover(discovery);
+                last_scan = Some(Instant::now());
+            }
+        }
+        if last_reconcile.elapsed() >= Coverage::INTERVAL {
+            list.reconcile();
+            list.store_tcp();
+            last_reconcile = Instant::now();
+        }
+        if let Some(flood) = &mut list.flood {
+            flood.tick(now_millis());
+        }
+        // wakes up without the data for the periodic work
+        let events = rb.read_timeout::<SnifferEvent>(&running, Duration::from_secs(1))?;
+        producer.processed(events.len() as u64);
+        for event in events {
+            if event.id().ts_finish() >= cutover {
+                list.handle_event(event);
+            }
+            list.orchestrator.processed(1);
+        }
+    }
+    list.join();
+    for handle in tcp_threads {
+        if handle.join().is_err() {
+            log::error!("tcp metadata thread panicked");
+        }
+    }
+
+    Ok(())
+}
+
+enum Job<Db> {
+    // the name of the node and its connection
+    Connect(SocketId, String, Connection<Db>),
+    Data {
+        id: EventId,
+        payload: Vec<u8>,
+        // the bytes the syscall transferred, the payload is shorter if it is limited
+        length: usize,
+        net: bool,
+        incoming: bool,
+    },
+    GetFd(SocketId),
+    End(SocketId, TerminationKind),
+    Tcp(SocketId, TcpStats),
+    Close(SocketId),
+}
+
+impl<Db> Job<Db> {
+    // only the captured data may be dropped, the connection events never
+    fn droppable(&self) -> bool {
+        matches!(self, Job::Data { .. })
+    }
+}
+
+struct Worker<Db> {
+    mailbox: Arc<Mailbox<Job<Db>>>,
+    handle: thread::JoinHandle<()>,
+    stage: Arc<Stage>,
+}
+
+// the producer must not wait for the dead thread
+struct CloseOnExit<Db>(Arc<Mailbox<Job<Db>>>);
+
+impl<Db> Drop for CloseOnExit<Db> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+impl<Db> Worker<Db>
+where
+    Db: Database + Sync + Send + 'static,
+{
+    fn spawn(
+        index: usize,
+        health: Arc<Health>,
+        stage: Arc<Stage>,
+        queue: &QueueConfig,
+        store: Arc<ResumeStore>,
+    ) -> Self {
+        let mailbox = Arc::new(Mailbox::new(queue));
+        let handle = {
+            let mailbox = mailbox.clone();
+            let stage = stage.clone();
+            thread::Builder::new()
+                .name(format!("decoder-{}", index))
+                .spawn(move || Self::run(index, CloseOnExit(mailbox), health, stage, store))
+                .expect("failed to spawn decoder thread")
+        };
+        Worker {
+            mailbox,
+            handle,
+            stage,
+        }
+    }
+
+    fn run(
+        index: usize,
+        mailbox: CloseOnExit<Db>,
+        health: Arc<Health>,
+        stage: Arc<Stage>,
+        store: Arc<ResumeStore>,
+    ) {
+        let mut connections = HashMap::<SocketId, (String, Connection<Db>)>::new();
+        let mut persisted = Instant::now();
+        while let Some(job) = mailbox.0.pop() {
+            health.parser_done();
+            stage.dequeued();
+            match job {
+                Job::Connect(socket_id, node, connection) => {
+                    if let Some((_, old)) = connections.insert(socket_id, (node, connection)) {
+                        old.join();
+                    }
                 },
-                SnifferEvent::Data {
+                Job::Data {
                     id,
-                    data,
+                    payload,
+                    length,
                     net,
                     incoming,
                 } => {
-                    if !data.is_empty() {
-                        list.handle_data(id, data, net, incoming);
+                    if let Some((_, connection)) = connections.get_mut(&id.socket_id) {
+                        let syscall = chunk::SyscallTime {
+                            ktime: id.ts_finish(),
+                            latency: id.latency(),
+                        };
+                        connection.count_syscall(incoming, syscall);
+                        // the malformed data must not stop the recording of the connection
+                        // nor kill the thread with all its connections
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            if payload.len() < length {
+                                connection.handle_partial(length, incoming)
+                            } else {
+                                connection.handle_data(&payload, net, incoming)
+                            }
+                        }));
+                        if result.is_err() {
+                            log::error!("parser of {} panicked, restarting it as uncertain", id);
+                            stage.restarted();
+                            connection.restart();
+                        }
+                    } else {
+                        log::debug!("failed to handle data, connection does not exist: {}", id);
+                    }
+                },
+                Job::GetFd(socket_id) => {
+                    if let Some((_, c)) = connections.remove(&socket_id) {
+                        c.warn_fd_changed();
+                        c.join();
                     }
                 },
-                SnifferEvent::Close { id } => {
-                    list.handle_close(id);
+                Job::End(socket_id, kind) => {
+                    if let Some((_, connection)) = connections.get_mut(&socket_id) {
+                        connection.terminate(kind);
+                    }
                 },
-                SnifferEvent::GetFd { id } => {
-                    list.handle_get_fd(id);
+                Job::Tcp(socket_id, stats) => {
+                    if let Some((_, connection)) = connections.get_mut(&socket_id) {
+                        connection.set_tcp(stats);
+                    }
                 },
-                SnifferEvent::Debug { id, msg } => {
-                    log::warn!("{} {}", id, msg);
+                Job::Close(socket_id) => {
+                    if let Some((_, old)) = connections.remove(&socket_id) {
+                        old.join();
+                    }
                 },
             }
+            stage.processed(1);
+            if store.due(persisted) {
+                store.save(index, Self::resumable(&connections));
+                persisted = Instant::now();
+            }
+        }
+        // the recorder stops, the node keeps running, its connections are resumed at start
+        if store.enabled() {
+            store.save(index, Self::resumable(&connections));
+        }
+        for (_, (_, connection)) in connections {
+            connection.join();
         }
     }
 
-    Ok(())
+    fn resumable(connections: &HashMap<SocketId, (String, Connection<Db>)>) -> Vec<Entry> {
+        connections
+            .iter()
+            .filter_map(|(socket_id, (node, connection))| {
+                Some(Entry {
+                    pid: socket_id.pid,
+                    fd: socket_id.fd,
+                    node: node.clone(),
+                    connection: connection.resumable()?,
+                })
+            })
+            .collect()
+    }
 }
 
 struct ConnectionList<'a, Db> {
     client: BpfModuleClient,
     system: &'a mut System<Db>,
-    connections: HashMap<SocketId, Connection<Db>>,
+    workers: Vec<Worker<Db>>,
+    control: Arc<Control>,
+    health: Arc<Health>,
+    orchestrator: Arc<Stage>,
+    processor: Arc<Stage>,
+    // connections which lost data while the capture was paused or the decoder was overflown
+    skipped: HashSet<SocketId>,
+    store: Arc<ResumeStore>,
+    // by the pid, the bytes read from the ring buffer and the bytes given to the parser
+    bytes: HashMap<u32, (u64, u64)>,
+    coverage: Arc<Coverage>,
+    flood: Option<FloodDetector<Db>>,
+    tcp: Option<Arc<TcpMeta>>,
+    // the recorded connections, the peer and whether it is incoming,
+    // the new module is told to watch them
+    live: HashMap<SocketId, (SocketAddr, bool)>,
+    // the inode of the socket of the module, the new module binds the new socket
+    socket_inode: u64,
 }
 
 impl<'a, Db> ConnectionList<'a, Db>
 where
     Db: Database + DatabaseNew + DatabaseFetch + Sync + Send + 'static,
 {
-    fn new(client: BpfModuleClient, system: &'a mut System<Db>) -> Self {
+    fn new(
+        client: BpfModuleClient,
+        system: &'a mut System<Db>,
+        decode_threads: usize,
+        tcp: Option<Arc<TcpMeta>>,
+    ) -> Self {
+        let health = system.health();
+        let pipeline = system.pipeline();
+        let orchestrator = pipeline.stage("orchestrator", None);
+        let queue = system.decoder_queue();
+        let store = Arc::new(ResumeStore::new(&system.resume_config(), decode_threads.max(1)));
+        let workers = (0..decode_threads.max(1))
+            .map(|i| {
+                let name = format!("decoder-{}", i);
+                let stage = pipeline.stage(&name, Some(queue.capacity()));
+                Worker::spawn(i, health.clone(), stage, &queue, store.clone())
+            })
+            .collect::<Vec<_>>();
+        health.set_parser_capacity(workers.len() * queue.capacity());
+        let coverage = system.coverage();
+        let flood = system.flood_config().map(|config| FloodDetector::new(&config));
+        let socket_inode = fs::metadata(system.sniffer_path())
+            .map(|m| m.ino())
+            .unwrap_or(0);
         ConnectionList {
             client,
+            control: system.control(),
             system,
-            connections: HashMap::new(),
+            workers,
+            health,
+            orchestrator,
+            processor: pipeline.stage("processor", None),
+            skipped: HashSet::new(),
+            store,
+            bytes: HashMap::new(),
+            coverage,
+            flood,
+            tcp,
+            live: HashMap::new(),
+            socket_inode,
+        }
+    }
+
+    fn send(&mut self, socket_id: &SocketId, job: Job<Db>) {
+        let mut hasher = DefaultHasher::new();
+        socket_id.hash(&mut hasher);
+        let index = (hasher.finish() as usize) % self.workers.len();
+        let worker = &self.workers[index];
+        self.health.parser_enqueued();
+        worker.stage.enqueued();
+        match worker.mailbox.push(job, Job::droppable) {
+            Ok(None) => (),
+            Ok(Some(dropped)) => {
+                self.health.parser_done();
+                worker.stage.dequeued();
+                worker.stage.dropped();
+                if let Job::Data { id, length, .. } = dropped {
+                    // the connection is out of sync, stop parsing it, as if the capture was paused
+                    self.control.skip(length);
+                    let socket_id = id.socket_id;
+                    if let Some((_, processed)) = self.bytes.get_mut(&socket_id.pid) {
+                        *processed -= length as u64;
+                    }
+                    if self.skipped.insert(socket_id) {
+                        self.send(&socket_id, Job::Close(socket_id));
+                    }
+                }
+            },
+            Err(_) => {
+                self.health.parser_done();
+                worker.stage.dequeued();
+                log::error!("decoder thread {} is dead", index);
+            },
+        }
+    }
+
+    fn join(self) {
+        for Worker { mailbox, handle, .. } in self.workers {
+            mailbox.close();
+            if handle.join().is_err() {
+                log::error!("decoder thread panicked");
+            }
         }
     }
 
@@ -101,22 +445,198 @@ where
             })?;
         }
 
+        self.limit_payload()
+    }
+
+    /// The config or the override of the control, 0 is the full payload
+    fn limit_payload(&mut self) -> Result<()> {
+        for p2p_config in self.system.p2p_configs() {
+            let port = p2p_config.port;
+            let limit = self
+                .control
+                .payload_limit(port)
+                .unwrap_or_else(|| p2p_config.payload_limit());
+            self.client.send_command(Command::PayloadLimit { port, limit })?;
+        }
+
         Ok(())
     }
 
+    /// The new bpf module is started while the old one is still running
+    fn module_replaced(&mut self) -> bool {
+        match fs::metadata(self.system.sniffer_path()) {
+            Ok(metadata) if metadata.ino() != self.socket_inode => {
+                self.socket_inode = metadata.ino();
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Gives the new module the state of the old one, the ports, the node processes
+    /// and the recorded connections, the ignored connections are just not given,
+    /// then detaches the old module, returns the time of the cutover, the events before it
+    /// are taken from the old module, the events after it from the new one
+    fn handover(&mut self, client: BpfModuleClient) -> Result<u64> {
+        let old = mem::replace(&mut self.client, client);
+        self.watching()?;
+        for (pid, node) in self.system.attached() {
+            if let Some(port) = self.system.p2p_port(&node) {
+                self.client.send_command(Command::WatchProcess { pid, port })?;
+            }
+        }
+        for (&SocketId { pid, fd }, &(_, incoming)) in &self.live {
+            self.client.send_command(Command::WatchConnection { pid, fd, incoming })?;
+        }
+        // the commands are handled in order, the state is given when the time comes
+        let cutover = self.client.fetch_time()?;
+        // the old module exits when its client disconnects
+        drop(old);
+        Ok(cutover)
+    }
+
+    /// Attaches to the node process which is running, but whose bind was not seen,
+    /// the node started before the recorder, or restarted while the capture was down
+    fn discover(&mut self, discovery: &DiscoveryConfig) {
+        let ports = self.system.p2p_configs().map(|c| c.port).collect::<Vec<_>>();
+        for (pid, listening) in discovery.scan() {
+            if self.system.is_attached(pid) {
+                continue;
+            }
+            for port in listening.into_iter().filter(|port| ports.contains(port)) {
+                if let Err(error) = self.system.handle_bind(pid, port) {
+                    log::error!("failed to attach to pid: {}, error: {}", pid, error);
+                    continue;
+                }
+                let (info, db) = match self.system.get_mut(pid) {
+                    Some(v) => v,
+                    // some other node of the same name in the other namespace
+                    None => continue,
+                };
+                let name = info.name().to_string();
+                if let Err(error) = self.client.send_command(Command::WatchProcess { pid, port }) {
+                    log::error!("cannot watch pid: {}, error: {}", pid, error);
+                }
+                let message = format!("discovered the node process, pid: {}, port: {}", pid, port);
+                log::info!("node {}: {}", name, message);
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_nanos())
+                    .unwrap_or(0);
+                db.store_log(node_log::Item {
+                    level: node_log::LogLevel::Info,
+                    timestamp,
+                    section: "discovery".to_string(),
+                    message,
+                });
+                break;
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: SnifferEvent) {
+        match event {
+            SnifferEvent::Bind { id, address } => {
+                // TODO: remove old connections on this port
+                if let Err(error) = self.system.handle_bind(id.socket_id.pid, address.port()) {
+                    log::error!("failed to handle bind syscall: {}", error);
+                }
+            },
+            SnifferEvent::Listen { id } => {
+                let _ = id;
+            },
+            SnifferEvent::Connect { id, address } => {
+                self.handle_connection(id, address, false);
+            },
+            SnifferEvent::Accept {
+                id,
+                address,
+                listen_on_fd,
+            } => {
+                let _ = listen_on_fd;
+                self.handle_connection(id, address, true);
+            },
+            SnifferEvent::Data {
+                id,
+                data,
+                length,
+                net,
+                incoming,
+            } => {
+                if !data.is_empty() {
+                    self.handle_data(id, data, length, net, incoming);
+                } else if incoming {
+                    // the end of the stream, the peer closed its side
+                    self.handle_end(id, TerminationKind::Fin);
+                }
+            },
+            SnifferEvent::Close { id } => {
+                self.handle_end(id.clone(), TerminationKind::Close);
+                self.handle_close(id);
+            },
+            SnifferEvent::Reset { id } => {
+                self.handle_end(id, TerminationKind::Reset);
+            },
+            SnifferEvent::GetFd { id } => {
+                self.handle_get_fd(id);
+            },
+            SnifferEvent::Debug { id, msg } => {
+                log::warn!("{} {}", id, msg);
+            },
+        }
+    }
+
     fn handle_connection(&mut self, event_id: EventId, address: SocketAddr, incoming: bool) {
         let socket_id = event_id.socket_id;
         let pid = socket_id.pid;
         let fd = socket_id.fd;
+        // the descriptor is reused, the ignored connection was closed
+        self.control.forget_connection(pid, fd);
+        // the flood is detected even while the capture is paused
+        if incoming && !self.system.should_ignore(&address) {
+            if let (Some(flood), Some((info, db))) = (&mut self.flood, self.system.get_mut(pid)) {
+                flood.accepted(info.name(), db, socket_id, address, now_millis());
+            }
+        }
+        if self.control.is_paused() && !self.system.should_ignore(&address) {
+            // the handshake is missed, so the connection cannot be decrypted
+            self.skipped.insert(socket_id);
+            return;
+        }
+        self.skipped.remove(&socket_id);
         if !self.system.should_ignore(&address) {
             if let Some((info, db)) = self.system.get_mut(pid) {
-                let connection = Connection::new(address, incoming, info.identity(), db);
-                if let Some(old) = self.connections.insert(socket_id, connection) {
-                    old.join();
+                let (identity, limits) = (info.identity(), info.chunk_limits());
+                let node = info.name().to_string();
+                let processor = self.processor.clone();
+                let decoder = self.system.decoder_stats(&node);
+                let sampler = self.system.sampler(&node);
+                let scores = self.system.peer_scores(&node);
+                let connection = Connection::new(
+                    address,
+                    incoming,
+                    identity,
+                    limits,
+                    db,
+                    processor,
+                    decoder,
+                    sampler,
+                    scores,
+                );
+                if let Some(tcp) = &self.tcp {
+                    tcp.watch(socket_id, address);
                 }
+                self.live.insert(socket_id, (address, incoming));
+                self.send(&socket_id, Job::Connect(socket_id, node, connection));
                 return;
             }
         }
+        // the payload of the other services is never captured, only the fact of the connection
+        if !incoming && !self.control.is_paused() && self.system.should_record_external(&address) {
+            if let Some((_, db)) = self.system.get_mut(pid) {
+                db.store_connection(connection::Item::external(address));
+            }
+        }
         match self
             .client
             .send_command(Command::IgnoreConnection { pid, fd })
@@ -132,29 +652,182 @@ where
         }
     }
 
-    fn handle_data(&mut self, id: EventId, payload: Vec<u8>, net: bool, incoming: bool) {
+    /// The connections which were live when the recorder stopped, the process must still
+    /// have the socket, the capture module watches it again
+    fn resume(&mut self) {
+        for Entry {
+            pid,
+            fd,
+            node,
+            connection,
+        } in self.store.load()
+        {
+            if !Path::new(&format!("/proc/{}/fd/{}", pid, fd)).exists() {
+                continue;
+            }
+            let (identity, db) = match self.system.attach(pid, &node) {
+                Some((info, db)) => (info.identity(), db),
+                None => continue,
+            };
+            let incoming = connection.incoming;
+            let stored = |key: &chunk::Key| matches!(db.fetch_chunk(key), Ok(Some(_)));
+            let processor = self.processor.clone();
+            let decoder = self.system.decoder_stats(&node);
+            let sampler = self.system.sampler(&node);
+            let scores = self.system.peer_scores(&node);
+            let resumed = Connection::resume(
+                connection,
+                identity,
+                db.clone(),
+                processor,
+                decoder,
+                sampler,
+                scores,
+                stored,
+            );
+            let connection = match resumed {
+                Some(connection) => connection,
+                None => {
+                    log::warn!("cannot resume connection {}:{} of {}", pid, fd, node);
+                    continue;
+                },
+            };
+            if let Err(error) = self
+                .client
+                .send_command(Command::WatchConnection { pid, fd, incoming })
+            {
+                log::error!("cannot watch connection {}:{}, error: {}", pid, fd, error);
+                continue;
+            }
+            log::info!("resumed connection {}:{} of {}", pid, fd, node);
+            let socket_id = SocketId { pid, fd };
+            self.live.insert(socket_id, (connection.remote_addr(), incoming));
+            self.send(&socket_id, Job::Connect(socket_id, node, connection));
+        }
+    }
+
+    // the `length` is what the syscall transferred, the bytes are counted by it,
+    // so the limited payload is not taken for the lost traffic
+    fn handle_data(
+        &mut self,
+        id: EventId,
+        payload: Vec<u8>,
+        length: usize,
+        net: bool,
+        incoming: bool,
+    ) {
         if payload.len() > 0x1000000 {
             log::warn!("received from ring buffer big payload {}", payload.len());
         }
-        if let Some(connection) = self.connections.get_mut(&id.socket_id) {
-            connection.handle_data(&payload, net, incoming);
-        } else {
-            log::debug!("failed to handle data, connection does not exist: {}", id);
+        let socket_id = id.socket_id;
+        let (captured, processed) = self.bytes.entry(socket_id.pid).or_default();
+        *captured += length as u64;
+        if self.control.is_paused() || self.skipped.contains(&socket_id) {
+            self.control.skip(length);
+            // the connection is out of sync, stop parsing it, what is recorded stays
+            if self.skipped.insert(socket_id) {
+                self.send(&socket_id, Job::Close(socket_id));
+            }
+            return;
+        }
+        *processed += length as u64;
+        let job = Job::Data {
+            id,
+            payload,
+            length,
+            net,
+            incoming,
+        };
+        self.send(&socket_id, job);
+    }
+
+    /// Compares the bytes the kernel counted for each node process with the bytes
+    /// the recorder got, the difference is the traffic lost in the ring buffer
+    fn reconcile(&mut self) {
+        for (pid, node) in self.system.attached() {
+            let kernel = match self.client.fetch_counter(pid) {
+                Ok(kernel) => kernel,
+                Err(error) => {
+                    log::error!("cannot fetch the counter of pid: {}, error: {}", pid, error);
+                    continue;
+                },
+            };
+            let (captured, processed) = self.bytes.get(&pid).cloned().unwrap_or_default();
+            self.coverage.update(&node, pid, kernel, captured, processed);
+        }
+    }
+
+    /// The live connections which became ignored, the module stops capturing them,
+    /// what is recorded stays
+    fn ignore(&mut self) {
+        let ignored = self
+            .live
+            .iter()
+            .filter(|(socket_id, (address, _))| {
+                self.control.is_ignored_connection(socket_id.pid, socket_id.fd)
+                    || self.control.is_ignored_peer(address)
+            })
+            .map(|(socket_id, _)| *socket_id)
+            .collect::<Vec<_>>();
+        for socket_id in ignored {
+            let SocketId { pid, fd } = socket_id;
+            if let Err(error) = self
+                .client
+                .send_command(Command::IgnoreConnection { pid, fd })
+            {
+                log::error!("cannot ignore connection id: {}, error: {}", socket_id, error);
+            }
+            log::info!("ignored connection {}", socket_id);
+            self.live.remove(&socket_id);
+            if let Some(tcp) = &self.tcp {
+                tcp.take(&socket_id);
+            }
+            self.send(&socket_id, Job::Close(socket_id));
+        }
+    }
+
+    /// The tcp metadata of the live connections is stored periodically, not only at the close
+    fn store_tcp(&mut self) {
+        if let Some(tcp) = self.tcp.clone() {
+            for (socket_id, stats) in tcp.snapshot() {
+                self.send(&socket_id, Job::Tcp(socket_id, stats));
+            }
         }
     }
 
     fn handle_get_fd(&mut self, id: EventId) {
         let socket_id = id.socket_id;
-        if let Some(c) = self.connections.remove(&socket_id) {
-            c.warn_fd_changed();
-            c.join();
+        self.live.remove(&socket_id);
+        if let Some(tcp) = &self.tcp {
+            tcp.take(&socket_id);
+        }
+        self.send(&socket_id, Job::GetFd(socket_id));
+    }
+
+    fn handle_end(&mut self, id: EventId, kind: TerminationKind) {
+        let socket_id = id.socket_id;
+        if !self.skipped.contains(&socket_id) {
+            self.send(&socket_id, Job::End(socket_id, kind));
         }
     }
 
     fn handle_close(&mut self, id: EventId) {
         let socket_id = id.socket_id;
-        if let Some(old) = self.connections.remove(&socket_id) {
-            old.join();
+        self.skipped.remove(&socket_id);
+        self.live.remove(&socket_id);
+        if let Some(flood) = &mut self.flood {
+            flood.closed(&socket_id, now_millis());
+        }
+        if let Some(stats) = self.tcp.as_ref().and_then(|tcp| tcp.take(&socket_id)) {
+            self.send(&socket_id, Job::Tcp(socket_id, stats));
         }
+        self.send(&socket_id, Job::Close(socket_id));
     }
 }
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,77 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+/// The node runs in another network namespace, like in a container. The capture hooks
+/// the syscalls in every namespace, so the namespace only tells which process is the node,
+/// the api is served from the namespace of the recorder
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceConfig {
+    /// any process in the namespace as the recorder sees it, usually the node itself
+    pid: Option<u32>,
+    /// the id or the name of the docker container, it is inspected every time,
+    /// so the restarted container is found again
+    container: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum NamespaceError {
+    #[error("the namespace needs either `pid` or `container`")]
+    Empty,
+    #[error("cannot inspect container {}: {}", _0, _1)]
+    Inspect(String, String),
+    #[error("cannot read the namespace of the process {}: {}", _0, _1)]
+    Proc(u32, io::Error),
+}
+
+impl NamespaceConfig {
+    /// The pid of some process in the namespace
+    pub fn pid(&self) -> Result<u32, NamespaceError> {
+        if let Some(pid) = self.pid {
+            return Ok(pid);
+        }
+        let container = self.container.as_ref().ok_or(NamespaceError::Empty)?;
+        let inspect = |reason: String| NamespaceError::Inspect(container.clone(), reason);
+        let output = Command::new("docker")
+            .args(&["inspect", "--format", "{{.State.Pid}}", container])
+            .output()
+            .map_err(|error| inspect(error.to_string()))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(inspect(stderr.trim().to_string()));
+        }
+        match String::from_utf8_lossy(&output.stdout).trim().parse() {
+            // the stopped container has no process
+            Ok(0) => Err(inspect("the container is not running".to_string())),
+            Ok(pid) => Ok(pid),
+            Err(error) => Err(inspect(format!("{}", error))),
+        }
+    }
+
+    /// Whether the process `pid` is in the namespace
+    pub fn contains(&self, pid: u32) -> Result<bool, NamespaceError> {
+        Ok(netns(self.pid()?)? == netns(pid)?)
+    }
+
+    /// The `path` inside the container as the recorder sees it
+    pub fn resolve(&self, path: &Path) -> Result<PathBuf, NamespaceError> {
+        let root = PathBuf::from(format!("/proc/{}/root", self.pid()?));
+        Ok(root.join(path.strip_prefix("/").unwrap_or(path)))
+    }
+}
+
+/// The inode of the network namespace of the process identifies the namespace
+fn netns(pid: u32) -> Result<u64, NamespaceError> {
+    fs::metadata(format!("/proc/{}/ns/net", pid))
+        .map(|metadata| metadata.ino())
+        .map_err(|error| NamespaceError::Proc(pid, error))
+}
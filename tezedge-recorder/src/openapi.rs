@@ -0,0 +1,107 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use schemars::{
+    JsonSchema,
+    gen::{SchemaGenerator, SchemaSettings},
+    schema::{Schema, SchemaObject},
+};
+use serde_json::{Value, json};
+
+/// Describes the query arguments or the body by the type the handler parses
+pub type Describe = fn(&mut SchemaGenerator) -> Value;
+
+/// The endpoint as the server parses it, the `query` are the types
+/// the query string is deserialized into, they are merged
+pub struct Endpoint {
+    pub method: &'static str,
+    pub path: &'static str,
+    pub query: &'static [Describe],
+    pub body: Option<Describe>,
+}
+
+/// The query arguments are the fields of the type, it should be a struct
+pub fn args<T>(gen: &mut SchemaGenerator) -> Value
+where
+    T: JsonSchema,
+{
+    let object = match T::json_schema(gen) {
+        Schema::Object(SchemaObject {
+            object: Some(object),
+            ..
+        }) => object,
+        _ => return json!([]),
+    };
+    let args = object
+        .properties
+        .iter()
+        .map(|(name, schema)| {
+            let mut arg = json!({
+                "name": name,
+                "in": "query",
+                "required": object.required.contains(name),
+                "schema": schema,
+            });
+            if let Some(description) = arg["schema"]["description"].as_str() {
+                arg["description"] = description.into();
+            }
+            arg
+        })
+        .collect();
+    Value::Array(args)
+}
+
+/// The body is a reference to the schema of the type in the components
+pub fn body<T>(gen: &mut SchemaGenerator) -> Value
+where
+    T: JsonSchema,
+{
+    json!({
+        "required": true,
+        "content": {
+            "application/json": {
+                "schema": gen.subschema_for::<T>(),
+            },
+        },
+    })
+}
+
+/// The document written by hand, with the descriptions and the responses,
+/// the query arguments and the bodies of the `endpoints` are replaced by the generated ones,
+/// so they are always what the server actually parses
+pub fn document(endpoints: &[Endpoint]) -> Value {
+    let mut doc = serde_json::from_str::<Value>(include_str!("../openapi.json")).unwrap();
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    for endpoint in endpoints {
+        let operation = &mut doc["paths"][endpoint.path][endpoint.method];
+        let written = operation["parameters"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let mut parameters = endpoint
+            .query
+            .iter()
+            .flat_map(|describe| match describe(&mut gen) {
+                Value::Array(args) => args,
+                _ => vec![],
+            })
+            .collect::<Vec<_>>();
+        // the description written by hand is kept unless the type has its own
+        for arg in &mut parameters {
+            let same = |p: &&Value| p["in"] == "query" && p["name"] == arg["name"];
+            match written.iter().find(same) {
+                Some(p) if arg["description"].is_null() => {
+                    arg["description"] = p["description"].clone();
+                },
+                _ => (),
+            }
+        }
+        parameters.extend(written.into_iter().filter(|p| p["in"] != "query"));
+        operation["parameters"] = Value::Array(parameters);
+        if let Some(describe) = endpoint.body {
+            operation["requestBody"] = describe(&mut gen);
+        }
+    }
+    doc["components"]["schemas"] = serde_json::to_value(gen.definitions()).unwrap_or_default();
+    doc
+}
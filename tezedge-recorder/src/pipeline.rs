@@ -0,0 +1,121 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{Ordering, AtomicUsize, AtomicU64},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct StageReport {
+    pub name: String,
+    /// the items which wait for the stage, absent if the stage has no queue
+    pub queue: Option<usize>,
+    pub capacity: Option<usize>,
+    pub processed: u64,
+    /// unix milliseconds when the stage processed the last item, absent if nothing yet
+    pub last_activity: Option<u64>,
+    /// how many times the stage recovered from the panic
+    pub restarts: u64,
+    /// the items dropped because the queue was full
+    pub dropped: u64,
+}
+
+/// The counters of one stage of the capture, updated by the stage itself
+pub struct Stage {
+    name: String,
+    capacity: Option<usize>,
+    queue: AtomicUsize,
+    processed: AtomicU64,
+    last_activity: AtomicU64,
+    restarts: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Stage {
+    pub fn enqueued(&self) {
+        self.queue.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dequeued(&self) {
+        self.queue.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn processed(&self, count: u64) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.processed.fetch_add(count, Ordering::Relaxed);
+        self.last_activity.store(now, Ordering::Relaxed);
+    }
+
+    pub fn restarted(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn report(&self) -> StageReport {
+        let last_activity = self.last_activity.load(Ordering::Relaxed);
+        StageReport {
+            name: self.name.clone(),
+            queue: self.capacity.map(|_| self.queue.load(Ordering::Relaxed)),
+            capacity: self.capacity,
+            processed: self.processed.load(Ordering::Relaxed),
+            last_activity: Some(last_activity).filter(|&t| t != 0),
+            restarts: self.restarts.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The stages in the order the captured data goes through them:
+/// `producer` reads the ring buffer, `orchestrator` dispatches the events to the decoders,
+/// `decoder-{N}` decrypts and parses the connections, `processor` builds the messages
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Mutex<Vec<Arc<Stage>>>,
+}
+
+impl Pipeline {
+    /// Registers the stage, the stage of the same name is replaced, so the counters start over
+    /// when the capture restarts, the stage has the queue if it has the `capacity`
+    pub fn stage(&self, name: &str, capacity: Option<usize>) -> Arc<Stage> {
+        let stage = Arc::new(Stage {
+            name: name.to_string(),
+            capacity,
+            queue: AtomicUsize::new(0),
+            processed: AtomicU64::new(0),
+            last_activity: AtomicU64::new(0),
+            restarts: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+        let mut stages = self.stages.lock().unwrap();
+        match stages.iter_mut().find(|s| s.name == name) {
+            Some(s) => *s = stage.clone(),
+            None => stages.push(stage.clone()),
+        }
+        stage
+    }
+
+    /// The items dropped by every stage
+    pub fn dropped(&self) -> u64 {
+        self.stages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|s| s.dropped.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn report(&self) -> Vec<StageReport> {
+        self.stages.lock().unwrap().iter().map(|s| s.report()).collect()
+    }
+}
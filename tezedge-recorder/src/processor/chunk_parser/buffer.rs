@@ -1,21 +1,74 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use bytes::{Bytes, BytesMut};
+use serde::{Serialize, Deserialize};
+use thiserror::Error;
+
+/// The limits of the data buffered before the keys are derived, the data past them
+/// is recorded as is, and the connection is uncertain, each limit is per chunk,
+/// the chunk of the tezos protocol is at most 64 kiB, and may span many segments
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChunkLimits {
+    /// bytes of the connection message with its header, 4096 by default
+    max_connection_message: Option<usize>,
+    /// bytes of one chunk with its header, 65537 by default, the most the header can tell
+    max_chunk: Option<usize>,
+    /// the chunks which follow the connection message before the peer sends its one,
+    /// 16 by default
+    max_pending_chunks: Option<usize>,
+}
+
+impl ChunkLimits {
+    fn max_connection_message(&self) -> usize {
+        self.max_connection_message.unwrap_or(0x1000)
+    }
+
+    fn max_chunk(&self) -> usize {
+        self.max_chunk.unwrap_or(0x10001)
+    }
+
+    fn max_pending_chunks(&self) -> usize {
+        self.max_pending_chunks.unwrap_or(16)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LimitError {
+    #[error("the first chunk of {} bytes cannot be the connection message", _0)]
+    ConnectionMessage(usize),
+    #[error("the chunk of {} bytes exceeds the limit", _0)]
+    Chunk(usize),
+    #[error("more than {} chunks follow the connection message", _0)]
+    Pending(usize),
+}
+
+/// Accumulates the captured data and splits it into chunks, the chunk is split off
+/// the front of the buffer without moving the rest of the data, and without copying it
 pub struct Buffer {
     counter: u64,
-    buffer: Vec<u8>,
+    buffer: BytesMut,
 }
 
 impl Default for Buffer {
     fn default() -> Self {
         Buffer {
             counter: 0,
-            buffer: Vec::with_capacity(0x10000),
+            buffer: BytesMut::with_capacity(0x10000),
         }
     }
 }
 
 impl Buffer {
+    /// The chunks are numbered from the `counter`
+    pub fn starting_at(counter: u64) -> Self {
+        Buffer {
+            counter,
+            ..Buffer::default()
+        }
+    }
+
     pub fn handle_data(&mut self, payload: &[u8]) {
         if self.have_chunk().is_some() {
             log::debug!(
@@ -27,6 +80,11 @@ impl Buffer {
         self.buffer.extend_from_slice(payload);
     }
 
+    /// The number of the next chunk and the beginning of the chunk which is not complete yet
+    pub fn pending(&self) -> (u64, &[u8]) {
+        (self.counter, &self.buffer)
+    }
+
     pub fn remaining(&self) -> usize {
         self.buffer.len()
     }
@@ -43,6 +101,60 @@ impl Buffer {
         }
     }
 
+    /// Checks the chunks buffered before the keys are derived, the first one is
+    /// the connection message, the length in the header is checked, so the chunk
+    /// is rejected as soon as its header is received, not when it is complete
+    pub fn check(&self, limits: &ChunkLimits) -> Result<(), LimitError> {
+        let (mut offset, mut index) = (0, 0);
+        while let Some(len) = self.len(offset) {
+            // the connection message is about a hundred bytes, the first chunk which
+            // tells more is not tezos at all, or the capture started in the middle
+            // of the stream, waiting for it to complete would buffer the garbage
+            if index == 0 && len > limits.max_connection_message() {
+                return Err(LimitError::ConnectionMessage(len));
+            }
+            if len > limits.max_chunk() {
+                return Err(LimitError::Chunk(len));
+            }
+            if index > limits.max_pending_chunks() {
+                return Err(LimitError::Pending(limits.max_pending_chunks()));
+            }
+            offset += len;
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Puts the chunk back to the front of the buffer, it is numbered `counter` again
+    pub fn unread(&mut self, counter: u64, chunk: &[u8]) {
+        let mut buffer = BytesMut::with_capacity(chunk.len() + self.buffer.len());
+        buffer.extend_from_slice(chunk);
+        buffer.extend_from_slice(&self.buffer);
+        self.buffer = buffer;
+        self.counter = counter;
+    }
+
+    /// Searches the first `window` bytes for the beginning of the complete chunk
+    /// which the `accept` recognizes, the chunk has at least the header and the tag
+    pub fn find_boundary<F, T>(&self, window: usize, mut accept: F) -> Option<(usize, T)>
+    where
+        F: FnMut(&[u8]) -> Option<T>,
+    {
+        (0..window.min(self.buffer.len())).find_map(|offset| match self.len(offset) {
+            Some(len) if len >= 18 && self.buffer.len() >= offset + len => {
+                accept(&self.buffer[offset..(offset + len)]).map(|t| (offset, t))
+            },
+            _ => None,
+        })
+    }
+
+    /// Splits off the bytes before the boundary, they are one chunk which cannot be decrypted
+    pub fn split_garbage(&mut self, len: usize) -> (u64, Bytes) {
+        let counter = self.counter;
+        self.counter += 1;
+        (counter, self.buffer.split_to(len).freeze())
+    }
+
     pub fn have_chunk(&self) -> Option<&[u8]> {
         let len = self.len(0)?;
         if self.buffer.len() >= len {
@@ -52,21 +164,19 @@ impl Buffer {
         }
     }
 
-    pub fn cleanup(&mut self) -> Option<(u64, Vec<u8>)> {
-        use std::mem;
-
+    pub fn cleanup(&mut self) -> Option<(u64, Bytes)> {
         if self.buffer.is_empty() {
             return None;
         }
 
         let counter = self.counter;
         self.counter += 1;
-        Some((counter, mem::replace(&mut self.buffer, Vec::new())))
+        Some((counter, self.buffer.split().freeze()))
     }
 }
 
 impl Iterator for Buffer {
-    type Item = (u64, Vec<u8>);
+    type Item = (u64, Bytes);
 
     fn next(&mut self) -> Option<Self::Item> {
         let len = self.len(0)?;
@@ -75,21 +185,9 @@ impl Iterator for Buffer {
         } else {
             let counter = self.counter;
             self.counter += 1;
-
-            let mut new = vec![0; len];
-            new.copy_from_slice(&self.buffer[..len]);
-            assert!(self.buffer.as_ptr() as usize != new.as_ptr() as usize);
-
-            if self.buffer.len() > len {
-                let mut remaining = vec![0; self.buffer.len() - len];
-                remaining.copy_from_slice(&self.buffer[len..]);
-                self.buffer.clear();
-                self.buffer = remaining;
-            } else {
-                self.buffer = Vec::with_capacity(0x10000);
-            }
-
-            Some((counter, new))
+            // the record shares the memory of the buffer, the memory is reused
+            // when the records are dropped
+            Some((counter, self.buffer.split_to(len).freeze()))
         }
     }
 }
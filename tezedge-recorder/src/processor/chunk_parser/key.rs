@@ -1,6 +1,7 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
+use bytes::Bytes;
 use crypto::{
     crypto_box::PrecomputedKey,
     CryptoError,
@@ -67,9 +68,33 @@ impl Keys {
 }
 
 impl Key {
-    pub fn decrypt(&mut self, payload: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    /// The nonce after `count` chunks are decrypted
+    pub fn skip(&mut self, count: u64) {
+        for _ in 0..count {
+            self.nonce = self.nonce.increment();
+        }
+    }
+
+    /// How many nonces ahead of the current one the chunk is encrypted with,
+    /// at most `window`, the key does not change
+    pub fn probe(&self, payload: &[u8], window: u64) -> Option<u64> {
+        if payload.len() < 2 {
+            return None;
+        }
+        let mut nonce = self.nonce.clone();
+        for ahead in 0..=window {
+            if self.key.decrypt(&payload[2..], &nonce).is_ok() {
+                return Some(ahead);
+            }
+            nonce = nonce.increment();
+        }
+        None
+    }
+
+    /// The plain is moved into the record, not copied
+    pub fn decrypt(&mut self, payload: &[u8]) -> Result<Bytes, CryptoError> {
         let plain = self.key.decrypt(&payload[2..], &self.nonce)?;
         self.nonce = self.nonce.increment();
-        Ok(plain)
+        Ok(Bytes::from(plain))
     }
 }
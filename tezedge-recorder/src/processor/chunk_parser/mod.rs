@@ -8,4 +8,8 @@ mod key;
 mod state;
 mod parser;
 
-pub use self::parser::{Handshake, HandshakeOutput, HandshakeDone, ChunkHandler};
+pub use self::{
+    parser::{Handshake, HandshakeOutput, HandshakeDone, SidePosition, ChunkHandler},
+    key::Keys,
+    buffer::ChunkLimits,
+};
@@ -3,7 +3,10 @@
 
 use typenum::Bit;
 use either::Either;
+use serde::{Serialize, Deserialize};
 use super::{
+    key::Key,
+    buffer::ChunkLimits,
     state::{Initial, HaveCm, Uncertain, HaveKey, HaveNotKey, CannotDecrypt, MakeKeyOutput},
     tables::{connection, chunk},
     common::{Local, Remote},
@@ -39,9 +42,9 @@ impl From<MakeKeyOutput> for HandshakeOutput {
 }
 
 impl Handshake {
-    pub fn new(cn_id: &connection::Key, id: Identity) -> Self {
-        let local = Half::Initial(Initial::new(&cn_id, id.clone()));
-        let remote = Half::Initial(Initial::new(&cn_id, id));
+    pub fn new(cn_id: &connection::Key, id: Identity, limits: ChunkLimits) -> Self {
+        let local = Half::Initial(Initial::new(&cn_id, id.clone(), limits));
+        let remote = Half::Initial(Initial::new(&cn_id, id, limits));
         Handshake { local, remote }
     }
 
@@ -76,6 +79,26 @@ impl Handshake {
         }
     }
 
+    // one side exceeded the limits, both sides are recorded as is
+    fn uncertain(
+        l: (Uncertain<Local>, Option<chunk::Item>),
+        r: (Uncertain<Remote>, Option<chunk::Item>),
+        net: bool,
+        cn: &mut connection::Item,
+    ) -> HandshakeOutput {
+        cn.mark_uncertain();
+        let ((l, mut l_chunk), (r, mut r_chunk)) = (l, r);
+        for c in l_chunk.iter_mut().chain(r_chunk.iter_mut()) {
+            c.net(net);
+        }
+        HandshakeOutput {
+            local: HandshakeDone::Uncertain(l),
+            l_chunk,
+            remote: HandshakeDone::Uncertain(r),
+            r_chunk,
+        }
+    }
+
     pub fn handle_data(
         self,
         payload: &[u8],
@@ -90,13 +113,15 @@ impl Handshake {
             } => {
                 if !incoming {
                     match l.handle_data(payload) {
-                        Either::Left(l) => Either::Left(Handshake::initial(l, r)),
-                        Either::Right(l) => Either::Left(Handshake::local_cm(l, r)),
+                        Ok(Either::Left(l)) => Either::Left(Handshake::initial(l, r)),
+                        Ok(Either::Right(l)) => Either::Left(Handshake::local_cm(l, r)),
+                        Err(l) => Either::Right(Self::uncertain(l, r.uncertain(), net, cn)),
                     }
                 } else {
                     match r.handle_data(payload) {
-                        Either::Left(r) => Either::Left(Handshake::initial(l, r)),
-                        Either::Right(r) => Either::Left(Handshake::remote_cm(l, r)),
+                        Ok(Either::Left(r)) => Either::Left(Handshake::initial(l, r)),
+                        Ok(Either::Right(r)) => Either::Left(Handshake::remote_cm(l, r)),
+                        Err(r) => Either::Right(Self::uncertain(l.uncertain(), r, net, cn)),
                     }
                 }
             },
@@ -107,24 +132,13 @@ impl Handshake {
                 if !incoming {
                     match l.handle_data(payload) {
                         Ok(l) => Either::Left(Handshake::local_cm(l, r)),
-                        Err((l, l_chunk)) => {
-                            cn.mark_uncertain();
-                            let (r, mut r_chunk) = r.uncertain();
-                            if let Some(c) = &mut r_chunk {
-                                c.net(net);
-                            }
-                            Either::Right(HandshakeOutput {
-                                local: HandshakeDone::Uncertain(l),
-                                l_chunk,
-                                remote: HandshakeDone::Uncertain(r),
-                                r_chunk,
-                            })
-                        },
+                        Err(l) => Either::Right(Self::uncertain(l, r.uncertain(), net, cn)),
                     }
                 } else {
                     match r.handle_data(payload) {
-                        Either::Left(r) => Either::Left(Handshake::local_cm(l, r)),
-                        Either::Right(r) => Either::Right(l.make_key(r, cn).into()),
+                        Ok(Either::Left(r)) => Either::Left(Handshake::local_cm(l, r)),
+                        Ok(Either::Right(r)) => Either::Right(l.make_key(r, cn).into()),
+                        Err(r) => Either::Right(Self::uncertain(l.uncertain(), r, net, cn)),
                     }
                 }
             },
@@ -135,24 +149,13 @@ impl Handshake {
                 if incoming {
                     match r.handle_data(payload) {
                         Ok(r) => Either::Left(Handshake::remote_cm(l, r)),
-                        Err((r, r_chunk)) => {
-                            cn.mark_uncertain();
-                            let (l, mut l_chunk) = l.uncertain();
-                            if let Some(c) = &mut l_chunk {
-                                c.net(net);
-                            }
-                            Either::Right(HandshakeOutput {
-                                local: HandshakeDone::Uncertain(l),
-                                l_chunk,
-                                remote: HandshakeDone::Uncertain(r),
-                                r_chunk,
-                            })
-                        },
+                        Err(r) => Either::Right(Self::uncertain(l.uncertain(), r, net, cn)),
                     }
                 } else {
                     match l.handle_data(payload) {
-                        Either::Left(l) => Either::Left(Handshake::remote_cm(l, r)),
-                        Either::Right(l) => Either::Right(l.make_key(r, cn).into()),
+                        Ok(Either::Left(l)) => Either::Left(Handshake::remote_cm(l, r)),
+                        Ok(Either::Right(l)) => Either::Right(l.make_key(r, cn).into()),
+                        Err(l) => Either::Right(Self::uncertain(l, r.uncertain(), net, cn)),
                     }
                 }
             },
@@ -161,6 +164,17 @@ impl Handshake {
     }
 }
 
+/// The position in one direction of the connection, enough to resume it after the restart
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SidePosition {
+    /// the number of the next chunk
+    pub counter: u64,
+    /// the beginning of the chunk which is not complete yet
+    pub pending: Vec<u8>,
+    /// the chunks are decrypted, otherwise they are recorded as is
+    pub decrypted: bool,
+}
+
 pub enum HandshakeDone<S>
 where
     S: Bit,
@@ -187,6 +201,46 @@ impl<S> HandshakeDone<S>
 where
     S: Bit,
 {
+    /// The state after the parser panicked, the data is recorded as is, the decryption is lost
+    pub fn resync(cn_id: &connection::Key, id: Identity, counter: u64) -> Self {
+        HandshakeDone::Uncertain(Uncertain::resync(cn_id, id, counter, &[]))
+    }
+
+    pub fn position(&self) -> SidePosition {
+        let ((counter, pending), decrypted) = match self {
+            HandshakeDone::Uncertain(state) => (state.pending(), false),
+            HandshakeDone::HaveKey(state) => (state.pending(), true),
+            HandshakeDone::HaveNotKey(state) => (state.pending(), false),
+            HandshakeDone::CannotDecrypt(state) => (state.pending(), false),
+        };
+        SidePosition {
+            counter,
+            pending: pending.to_vec(),
+            decrypted,
+        }
+    }
+
+    /// The state after the restart, the decryption resumes if the side was decrypted
+    /// and the `key` is derived again, otherwise the data is recorded as is
+    pub fn resume(
+        cn_id: &connection::Key,
+        id: Identity,
+        position: &SidePosition,
+        key: Option<Key>,
+    ) -> Self {
+        let SidePosition {
+            counter,
+            pending,
+            decrypted,
+        } = position;
+        match key {
+            Some(key) if *decrypted => {
+                HandshakeDone::HaveKey(HaveKey::resume(cn_id, id, key, *counter, pending))
+            },
+            _ => HandshakeDone::Uncertain(Uncertain::resync(cn_id, id, *counter, pending)),
+        }
+    }
+
     pub fn handle_data<H>(
         self,
         payload: &[u8],
@@ -212,14 +266,10 @@ where
                 }
                 match temp_state.over() {
                     Ok(state) => HandshakeDone::HaveKey(state),
-                    Err((mut state, position)) => {
+                    Err((state, position)) => {
                         cn.mark_cannot_decrypt::<S>(position);
                         handler.update_cn(cn);
-                        for mut chunk in &mut state {
-                            chunk.net(net);
-                            handler.handle_chunk(chunk, cn);
-                        }
-                        HandshakeDone::CannotDecrypt(state)
+                        Self::recover(state, net, cn, handler)
                     },
                 }
             },
@@ -231,6 +281,34 @@ where
             },
             HandshakeDone::CannotDecrypt(mut state) => {
                 state.handle_data(payload);
+                Self::recover(state, net, cn, handler)
+            },
+        }
+    }
+
+    // the decryption failed, the stream is decrypted again if it resyncs,
+    // otherwise the chunks are recorded as is
+    fn recover<H>(
+        state: CannotDecrypt<S>,
+        net: bool,
+        cn: &mut connection::Item,
+        handler: &mut H,
+    ) -> Self
+    where
+        H: ChunkHandler,
+    {
+        match state.resync() {
+            Ok((state, garbage, skipped)) => {
+                cn.mark_resynced::<S>(skipped);
+                handler.update_cn(cn);
+                if let Some(mut chunk) = garbage {
+                    chunk.net(net);
+                    handler.handle_chunk(chunk, cn);
+                }
+                // the rest of the buffer
+                HandshakeDone::HaveKey(state).handle_data(&[], net, cn, handler)
+            },
+            Err(mut state) => {
                 for mut chunk in &mut state {
                     chunk.net(net);
                     handler.handle_chunk(chunk, cn);
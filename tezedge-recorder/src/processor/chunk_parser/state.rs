@@ -2,11 +2,12 @@
 // SPDX-License-Identifier: MIT
 
 use std::marker::PhantomData;
+use bytes::Bytes;
 use either::Either;
 use thiserror::Error;
 use typenum::{self, Bit};
 use super::{
-    buffer::Buffer,
+    buffer::{Buffer, ChunkLimits},
     key::{Keys, Key},
     tables::{connection, chunk},
     common::{Sender, Local, Remote},
@@ -24,7 +25,7 @@ impl<S> Inner<S>
 where
     S: Bit,
 {
-    fn chunk(&self, counter: u64, bytes: Vec<u8>, plain: Vec<u8>) -> chunk::Item {
+    fn chunk(&self, counter: u64, bytes: Bytes, plain: Bytes) -> chunk::Item {
         chunk::Item::new(
             self.cn_id.clone(),
             Sender::new(S::BOOL),
@@ -38,10 +39,21 @@ where
         self.buffer.handle_data(payload);
     }
 
+    fn resume(cn_id: &connection::Key, id: Identity, counter: u64, pending: &[u8]) -> Self {
+        let mut buffer = Buffer::starting_at(counter);
+        buffer.handle_data(pending);
+        Inner {
+            cn_id: cn_id.clone(),
+            id,
+            buffer,
+            incoming: PhantomData,
+        }
+    }
+
     pub fn cleanup(&mut self) -> Option<chunk::Item> {
         self.buffer
             .cleanup()
-            .map(|(counter, bytes)| self.chunk(counter, bytes, Vec::new()))
+            .map(|(counter, bytes)| self.chunk(counter, bytes, Bytes::new()))
     }
 }
 
@@ -56,10 +68,12 @@ where
 
 pub struct Initial<S> {
     inner: Inner<S>,
+    limits: ChunkLimits,
 }
 
 pub struct HaveCm<S> {
     inner: Inner<S>,
+    limits: ChunkLimits,
 }
 
 pub struct Uncertain<S> {
@@ -83,13 +97,17 @@ pub struct HaveData<S> {
 
 pub struct CannotDecrypt<S> {
     inner: Inner<S>,
+    // the key of the chunk which failed to decrypt
+    key: Key,
+    // the chunks recorded as is since then
+    raw: u64,
 }
 
 impl<S> Initial<S>
 where
     S: Bit,
 {
-    pub fn new(cn_id: &connection::Key, id: Identity) -> Self {
+    pub fn new(cn_id: &connection::Key, id: Identity, limits: ChunkLimits) -> Self {
         Initial {
             inner: Inner {
                 cn_id: cn_id.clone(),
@@ -97,6 +115,7 @@ where
                 buffer: Buffer::default(),
                 incoming: PhantomData,
             },
+            limits,
         }
     }
 
@@ -108,12 +127,23 @@ where
         self.inner.buffer.remaining() == 0
     }
 
-    pub fn handle_data(mut self, payload: &[u8]) -> Either<Self, HaveCm<S>> {
+    #[allow(clippy::type_complexity)]
+    pub fn handle_data(
+        mut self,
+        payload: &[u8],
+    ) -> Result<Either<Self, HaveCm<S>>, (Uncertain<S>, Option<chunk::Item>)> {
         self.inner.handle_data(payload);
+        if let Err(error) = self.inner.buffer.check(&self.limits) {
+            log::warn!("connection {}: {}", self.inner.cn_id, error);
+            return Err(Uncertain::new(self.inner));
+        }
         if self.inner.buffer.have_chunk().is_some() {
-            Either::Right(HaveCm { inner: self.inner })
+            Ok(Either::Right(HaveCm {
+                inner: self.inner,
+                limits: self.limits,
+            }))
         } else {
-            Either::Left(self)
+            Ok(Either::Left(self))
         }
     }
 }
@@ -122,20 +152,25 @@ impl<S> HaveCm<S>
 where
     S: Bit,
 {
-    /// Should not need to call
+    /// Should not need to call, the node waits for the connection message of the peer
     pub fn handle_data(
         mut self,
         payload: &[u8],
     ) -> Result<Self, (Uncertain<S>, Option<chunk::Item>)> {
-        // 128 kiB
-        if self.inner.buffer.remaining() > 0x20000 {
-            Err(Uncertain::new(self.inner))
-        } else {
-            self.inner.handle_data(payload);
-            Ok(self)
+        self.inner.handle_data(payload);
+        match self.inner.buffer.check(&self.limits) {
+            Ok(()) => Ok(self),
+            Err(error) => {
+                log::warn!("connection {}: {}", self.inner.cn_id, error);
+                Err(Uncertain::new(self.inner))
+            },
         }
     }
 
+    pub fn uncertain(self) -> (Uncertain<S>, Option<chunk::Item>) {
+        Uncertain::new(self.inner)
+    }
+
     fn have_key(mut self, key: Key) -> (HaveKey<S>, chunk::Item) {
         let (counter, bytes) = self.inner.buffer.next().unwrap();
         let remaining = self.inner.buffer.remaining();
@@ -145,7 +180,7 @@ where
                 remaining,
             );
         }
-        let plain = bytes[2..].to_vec();
+        let plain = bytes.slice(2..);
         let c = self.inner.chunk(counter, bytes, plain);
         (
             HaveKey {
@@ -254,6 +289,17 @@ where
         (Uncertain { inner }, c)
     }
 
+    /// The parser lost its state, the next chunk is `counter`
+    pub fn resync(cn_id: &connection::Key, id: Identity, counter: u64, pending: &[u8]) -> Self {
+        Uncertain {
+            inner: Inner::resume(cn_id, id, counter, pending),
+        }
+    }
+
+    pub fn pending(&self) -> (u64, &[u8]) {
+        self.inner.buffer.pending()
+    }
+
     pub fn handle_data(&mut self, payload: &[u8]) -> chunk::Item {
         debug_assert!(!payload.is_empty());
         self.inner.handle_data(payload);
@@ -265,6 +311,10 @@ impl<S> HaveNotKey<S>
 where
     S: Bit,
 {
+    pub fn pending(&self) -> (u64, &[u8]) {
+        self.inner.buffer.pending()
+    }
+
     pub fn handle_data(&mut self, payload: &[u8]) -> chunk::Item {
         debug_assert!(!payload.is_empty());
         self.inner.handle_data(payload);
@@ -276,6 +326,27 @@ impl<S> HaveKey<S>
 where
     S: Bit,
 {
+    /// Resumes the decryption after the restart, the `key` is as it was right after
+    /// the handshake, the chunk `counter` is the next one
+    pub fn resume(
+        cn_id: &connection::Key,
+        id: Identity,
+        mut key: Key,
+        counter: u64,
+        pending: &[u8],
+    ) -> Self {
+        // the connection message is not encrypted
+        key.skip(counter.saturating_sub(1));
+        HaveKey {
+            inner: Inner::resume(cn_id, id, counter, pending),
+            key,
+        }
+    }
+
+    pub fn pending(&self) -> (u64, &[u8]) {
+        self.inner.buffer.pending()
+    }
+
     pub fn handle_data(mut self, payload: &[u8]) -> HaveData<S> {
         self.inner.handle_data(payload);
         HaveData {
@@ -300,6 +371,8 @@ where
         match self.key.decrypt(&bytes) {
             Ok(plain) => Some(self.inner.chunk(counter, bytes, plain)),
             Err(_) => {
+                // the chunk stays in the buffer, it is recorded as is unless the stream resyncs
+                self.inner.buffer.unread(counter, &bytes);
                 self.error = Some(counter);
                 None
             },
@@ -313,7 +386,12 @@ where
 {
     pub fn over(self) -> Result<HaveKey<S>, (CannotDecrypt<S>, u64)> {
         if let Some(position) = self.error {
-            Err((CannotDecrypt { inner: self.inner }, position))
+            let state = CannotDecrypt {
+                inner: self.inner,
+                key: self.key,
+                raw: 0,
+            };
+            Err((state, position))
         } else {
             Ok(HaveKey {
                 inner: self.inner,
@@ -323,10 +401,58 @@ where
     }
 }
 
+/// How many chunks the capture may miss, the nonce is tried that far ahead
+const NONCE_WINDOW: u64 = 16;
+
+/// How far from the beginning of the buffer the boundary of the chunk is searched,
+/// when the capture missed the bytes in the middle of the chunk
+const BOUNDARY_WINDOW: usize = 0x400;
+
 impl<S> CannotDecrypt<S>
 where
     S: Bit,
 {
+    pub fn pending(&self) -> (u64, &[u8]) {
+        self.inner.buffer.pending()
+    }
+
+    /// Tries to lock onto the stream again, searches the chunk which decrypts with the nonce
+    /// a few chunks ahead, at the boundary a few bytes ahead, the bytes before the boundary
+    /// are one chunk recorded as is, gives up after `NONCE_WINDOW` chunks are recorded as is,
+    /// returns how many nonces are skipped
+    #[allow(clippy::type_complexity)]
+    pub fn resync(mut self) -> Result<(HaveKey<S>, Option<chunk::Item>, u64), Self> {
+        if self.raw > NONCE_WINDOW {
+            return Err(self);
+        }
+        // each chunk recorded as is may have taken its nonce
+        let (key, window) = (&self.key, NONCE_WINDOW + self.raw);
+        let found = self
+            .inner
+            .buffer
+            .find_boundary(BOUNDARY_WINDOW, |chunk| key.probe(chunk, window));
+        let (offset, ahead) = match found {
+            Some(found) => found,
+            None => return Err(self),
+        };
+        let garbage = if offset > 0 {
+            let (counter, bytes) = self.inner.buffer.split_garbage(offset);
+            Some(self.inner.chunk(counter, bytes, Bytes::new()))
+        } else {
+            None
+        };
+        let mut key = self.key;
+        key.skip(ahead);
+        Ok((
+            HaveKey {
+                inner: self.inner,
+                key,
+            },
+            garbage,
+            ahead,
+        ))
+    }
+
     pub fn handle_data(&mut self, payload: &[u8]) {
         debug_assert!(!payload.is_empty());
         self.inner.handle_data(payload);
@@ -341,6 +467,7 @@ where
 
     fn next(&mut self) -> Option<Self::Item> {
         let (counter, bytes) = self.inner.buffer.next()?;
-        Some(self.inner.chunk(counter, bytes, Vec::new()))
+        self.raw += 1;
+        Some(self.inner.chunk(counter, bytes, Bytes::new()))
     }
 }
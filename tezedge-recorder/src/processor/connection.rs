@@ -3,18 +3,75 @@
 
 use std::{net::SocketAddr, sync::Arc};
 use either::Either;
+use serde::{Serialize, Deserialize};
+use storage::persistent::{Encoder, Decoder};
 use super::{
-    chunk_parser::{Handshake, HandshakeOutput, HandshakeDone, ChunkHandler},
+    chunk_parser::{
+        Handshake, HandshakeOutput, HandshakeDone, SidePosition, Keys, ChunkLimits, ChunkHandler,
+    },
     message_parser::MessageParser,
-    Identity, Database,
-    common::{Local, Remote, Initiator},
-    tables::connection,
+    Identity, Database, Stage,
+    scoring::{PeerScores, Observer},
+    decoder_stats::DecoderStats,
+    sampling::Sampler,
+    common::{Local, Remote, Initiator, Sender},
+    tables::{connection, chunk},
 };
 
+/// Everything to resume the connection after the restart of the recorder, the keys are
+/// not persisted, they are derived again from the identity and the connection messages
+#[derive(Serialize, Deserialize)]
+pub struct Resumable {
+    // the connection record as it is encoded in the database
+    key: Vec<u8>,
+    value: Vec<u8>,
+    pub incoming: bool,
+    // the local and the remote connection messages
+    connection_messages: Option<(Vec<u8>, Vec<u8>)>,
+    local: SidePosition,
+    remote: SidePosition,
+}
+
 pub struct Connection<Db> {
     state: Option<ConnectionState<Db>>,
     item: connection::Item,
     db: Arc<Db>,
+    stage: Arc<Stage>,
+    decoder: Arc<DecoderStats>,
+    sampler: Arc<Sampler>,
+    identity: Identity,
+    // the numbers of the next local and remote chunks, the numbering continues after the restart
+    next_chunk: (u64, u64),
+    stored: bool,
+    // the local and the remote connection messages, the keys are derived from them
+    connection_messages: Option<(Vec<u8>, Vec<u8>)>,
+    observer: Option<Observer>,
+    // the syscall which brought the data being handled
+    syscall: Option<chunk::SyscallTime>,
+    // the payload is limited, the data is only counted
+    partial: bool,
+}
+
+// remembers the number of the next chunk, stamps the chunk with the syscall which completed it
+struct Counted<'a, H> {
+    handler: &'a mut H,
+    next: &'a mut u64,
+    syscall: Option<chunk::SyscallTime>,
+}
+
+impl<'a, H> ChunkHandler for Counted<'a, H>
+where
+    H: ChunkHandler,
+{
+    fn handle_chunk(&mut self, mut chunk: chunk::Item, cn: &mut connection::Item) {
+        *self.next = chunk.counter + 1;
+        chunk.set_syscall(self.syscall);
+        self.handler.handle_chunk(chunk, cn);
+    }
+
+    fn update_cn(&mut self, cn: &connection::Item) {
+        self.handler.update_cn(cn);
+    }
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -32,17 +89,203 @@ impl<Db> Connection<Db>
 where
     Db: Database,
 {
-    pub fn new(remote_addr: SocketAddr, incoming: bool, identity: Identity, db: Arc<Db>) -> Self {
+    /// The `stage` counts the messages the connection produces, the `decoder` counts
+    /// the messages decoded and not, the `sampler` tells which messages are stored,
+    /// the messages of the peer are checked if the `scores` are given,
+    /// the `limits` bound the data buffered during the handshake
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        remote_addr: SocketAddr,
+        incoming: bool,
+        identity: Identity,
+        limits: ChunkLimits,
+        db: Arc<Db>,
+        stage: Arc<Stage>,
+        decoder: Arc<DecoderStats>,
+        sampler: Arc<Sampler>,
+        scores: Option<Arc<PeerScores>>,
+    ) -> Self {
         let item = connection::Item::new(Initiator::new(incoming), remote_addr);
-        let state = ConnectionState::Handshake(Handshake::new(&item.key(), identity));
+        let handshake = Handshake::new(&item.key(), identity.clone(), limits);
+        let state = ConnectionState::Handshake(handshake);
         Connection {
             state: Some(state),
             item,
             db,
+            stage,
+            decoder,
+            sampler,
+            identity,
+            next_chunk: (0, 0),
+            stored: false,
+            connection_messages: None,
+            observer: scores.map(|scores| Observer::new(scores, remote_addr)),
+            syscall: None,
+            partial: false,
+        }
+    }
+
+    /// The connection recorded before the restart, `stored` tells whether the chunk is
+    /// in the database already, so the chunks handled after the state was persisted
+    /// are skipped, the message which spans the restart is lost
+    #[allow(clippy::too_many_arguments)]
+    pub fn resume<F>(
+        resumable: Resumable,
+        identity: Identity,
+        db: Arc<Db>,
+        stage: Arc<Stage>,
+        decoder: Arc<DecoderStats>,
+        sampler: Arc<Sampler>,
+        scores: Option<Arc<PeerScores>>,
+        stored: F,
+    ) -> Option<Self>
+    where
+        F: Fn(&chunk::Key) -> bool,
+    {
+        let key = connection::Key::decode(&resumable.key).ok()?;
+        let value = connection::Value::decode(&resumable.value).ok()?;
+        let item = connection::Item::unite(key, value);
+        let cn_id = item.key();
+        let keys = resumable
+            .connection_messages
+            .as_ref()
+            .and_then(|(l, r)| Keys::new(&identity, l, r, item.initiator.clone()).ok());
+        let (local_key, remote_key) = match keys {
+            Some(Keys { local, remote }) => (Some(local), Some(remote)),
+            None => (None, None),
+        };
+        let catch_up = |mut position: SidePosition, sender: Sender| {
+            let start = position.counter;
+            let key = |counter| chunk::Key {
+                cn_id: cn_id.clone(),
+                counter,
+                sender: sender.clone(),
+            };
+            while stored(&key(position.counter)) {
+                position.counter += 1;
+            }
+            if position.counter != start {
+                // the pending data is in the stored chunks already
+                position.pending.clear();
+            }
+            position
+        };
+        let local = catch_up(resumable.local, Sender::Local);
+        let remote = catch_up(resumable.remote, Sender::Remote);
+        // the connection message of the peer is not seen again, its key is unknown,
+        // so nothing is blamed on it
+        let observer = scores.map(|scores| Observer::new(scores, item.remote_addr));
+        let mp = || {
+            MessageParser::new(
+                db.clone(),
+                stage.clone(),
+                decoder.clone(),
+                sampler.clone(),
+                observer.clone(),
+            )
+        };
+        let state = ConnectionState::HandshakeDone {
+            local: HandshakeDone::resume(&cn_id, identity.clone(), &local, local_key),
+            local_mp: mp(),
+            remote: HandshakeDone::resume(&cn_id, identity.clone(), &remote, remote_key),
+            remote_mp: mp(),
+        };
+        let partial = item.is_partial();
+        Some(Connection {
+            state: Some(state),
+            item,
+            db,
+            stage,
+            decoder,
+            sampler,
+            identity,
+            next_chunk: (local.counter, remote.counter),
+            stored: true,
+            connection_messages: resumable.connection_messages,
+            observer,
+            syscall: None,
+            partial,
+        })
+    }
+
+    /// `None` until the handshake is done, such connection cannot be resumed
+    pub fn resumable(&self) -> Option<Resumable> {
+        let (local, remote) = match self.state.as_ref()? {
+            ConnectionState::Handshake(_) => return None,
+            ConnectionState::HandshakeDone { local, remote, .. } => {
+                (local.position(), remote.position())
+            },
+        };
+        let (key, value) = self.item.clone().split();
+        Some(Resumable {
+            key: key.encode().ok()?,
+            value: value.encode().ok()?,
+            incoming: self.item.initiator.incoming(),
+            connection_messages: self.connection_messages.clone(),
+            local,
+            remote,
+        })
+    }
+
+    fn message_parser(&self) -> MessageParser<Db> {
+        MessageParser::new(
+            self.db.clone(),
+            self.stage.clone(),
+            self.decoder.clone(),
+            self.sampler.clone(),
+            self.observer.clone(),
+        )
+    }
+
+    /// The parser panicked in the middle of `handle_data`, its state is lost,
+    /// the rest of the connection is recorded as the raw chunks, the connection is uncertain
+    pub fn restart(&mut self) {
+        let key = self.item.key();
+        let (local, remote) = self.next_chunk;
+        self.state = Some(ConnectionState::HandshakeDone {
+            local: HandshakeDone::resync(&key, self.identity.clone(), local),
+            local_mp: self.message_parser(),
+            remote: HandshakeDone::resync(&key, self.identity.clone(), remote),
+            remote_mp: self.message_parser(),
+        });
+        self.item.mark_uncertain();
+        if self.stored {
+            self.db.update_connection(self.item.clone());
+        } else {
+            self.db.store_connection(self.item.clone());
+            self.stored = true;
+        }
+    }
+
+    /// The read or the write which brought the next data, the chunks it completes
+    /// are stored with its time
+    pub fn count_syscall(&mut self, incoming: bool, syscall: chunk::SyscallTime) {
+        self.item.count_syscall(incoming, syscall.latency);
+        self.syscall = Some(syscall);
+    }
+
+    /// The module copied only the beginning of the read or the write of `length` bytes,
+    /// the stream cannot be followed any further, the rest of the connection is only counted
+    pub fn handle_partial(&mut self, length: usize, incoming: bool) {
+        let bucket = self.item.count_bytes(incoming, length);
+        let first = !self.partial;
+        if first {
+            self.partial = true;
+            self.item.add_comment().partial = true;
+        }
+        if self.stored && (bucket || first) {
+            self.db.update_connection(self.item.clone());
         }
     }
 
     pub fn handle_data(&mut self, payload: &[u8], net: bool, incoming: bool) {
+        // the bucket is complete, it is stored, the connection is stored at the handshake anyway
+        if self.item.count_bytes(incoming, payload.len()) && self.stored {
+            self.db.update_connection(self.item.clone());
+        }
+        if self.partial {
+            return;
+        }
         let state = match self.state.take().unwrap() {
             ConnectionState::Handshake(h) => {
                 match h.handle_data(payload, net, incoming, &mut self.item) {
@@ -53,13 +296,21 @@ where
                         remote,
                         r_chunk,
                     }) => {
-                        let mut local_mp = MessageParser::new(self.db.clone());
-                        let mut remote_mp = MessageParser::new(self.db.clone());
+                        let mut local_mp = self.message_parser();
+                        let mut remote_mp = self.message_parser();
                         self.db.store_connection(self.item.clone());
-                        if let Some(chunk) = l_chunk {
+                        self.stored = true;
+                        if let (Some(l), Some(r)) = (&l_chunk, &r_chunk) {
+                            self.connection_messages = Some((l.bytes.to_vec(), r.bytes.to_vec()));
+                        }
+                        if let Some(mut chunk) = l_chunk {
+                            self.next_chunk.0 = chunk.counter + 1;
+                            chunk.set_syscall(self.syscall);
                             local_mp.handle_chunk(chunk, &mut self.item);
                         }
-                        if let Some(chunk) = r_chunk {
+                        if let Some(mut chunk) = r_chunk {
+                            self.next_chunk.1 = chunk.counter + 1;
+                            chunk.set_syscall(self.syscall);
                             remote_mp.handle_chunk(chunk, &mut self.item);
                         }
                         ConnectionState::HandshakeDone {
@@ -78,17 +329,27 @@ where
                 mut remote_mp,
             } => {
                 if !incoming {
+                    let mut handler = Counted {
+                        handler: &mut local_mp,
+                        next: &mut self.next_chunk.0,
+                        syscall: self.syscall,
+                    };
                     ConnectionState::HandshakeDone {
-                        local: local.handle_data(payload, net, &mut self.item, &mut local_mp),
+                        local: local.handle_data(payload, net, &mut self.item, &mut handler),
                         local_mp,
                         remote,
                         remote_mp,
                     }
                 } else {
+                    let mut handler = Counted {
+                        handler: &mut remote_mp,
+                        next: &mut self.next_chunk.1,
+                        syscall: self.syscall,
+                    };
                     ConnectionState::HandshakeDone {
                         local,
                         local_mp,
-                        remote: remote.handle_data(payload, net, &mut self.item, &mut remote_mp),
+                        remote: remote.handle_data(payload, net, &mut self.item, &mut handler),
                         remote_mp,
                     }
                 }
@@ -97,6 +358,27 @@ where
         self.state = Some(state);
     }
 
+    /// The headers of the packets captured so far, the connection which did not finish
+    /// the handshake is not recorded
+    pub fn set_tcp(&mut self, tcp: connection::TcpStats) {
+        self.item.set_tcp(tcp);
+        if self.stored {
+            self.db.update_connection(self.item.clone());
+        }
+    }
+
+    /// Records how the connection ended, the connection which did not finish the handshake
+    /// is not recorded at all
+    pub fn terminate(&mut self, kind: connection::TerminationKind) {
+        if self.stored && self.item.terminate(kind) {
+            self.db.update_connection(self.item.clone());
+        }
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.item.remote_addr
+    }
+
     pub fn warn_fd_changed(&self) {
         if !matches!(&self.state, &Some(ConnectionState::Handshake(ref h)) if h.is_empty()) {
             log::warn!(
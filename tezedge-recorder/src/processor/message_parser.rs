@@ -4,25 +4,51 @@
 use std::sync::Arc;
 use super::{
     chunk_parser::ChunkHandler,
-    Database,
-    tables::{connection, chunk, message},
+    Database, Stage,
+    scoring::Observer,
+    decoder_stats::DecoderStats,
+    sampling::Sampler,
+    tables::{connection, chunk, message, message_hash::ContentHash, peer},
 };
 
 pub struct MessageParser<Db> {
     builder: Option<message::MessageBuilder>,
+    // the decrypted bytes of the peer message being built
+    plain: Vec<u8>,
+    // the bytes of the chunks of the message being built as they went over the wire
+    wire: u64,
     error: bool,
+    // the version the sender announced in its connection message
+    version: String,
     db: Arc<Db>,
+    stage: Arc<Stage>,
+    decoder: Arc<DecoderStats>,
+    sampler: Arc<Sampler>,
+    observer: Option<Observer>,
 }
 
 impl<Db> MessageParser<Db>
 where
     Db: Database,
 {
-    pub fn new(db: Arc<Db>) -> Self {
+    pub fn new(
+        db: Arc<Db>,
+        stage: Arc<Stage>,
+        decoder: Arc<DecoderStats>,
+        sampler: Arc<Sampler>,
+        observer: Option<Observer>,
+    ) -> Self {
         MessageParser {
             builder: None,
+            plain: Vec::new(),
+            wire: 0,
             error: false,
+            version: DecoderStats::version_name(None),
             db,
+            stage,
+            decoder,
+            sampler,
+            observer,
         }
     }
 }
@@ -49,21 +75,86 @@ where
             },
         };
 
+        // the steps of the handshake are timed even if they are malformed
+        if chunk.counter <= 2 {
+            if let Some(syscall) = chunk.syscall() {
+                cn.set_handshake_time(chunk.counter, chunk.sender.incoming(), syscall.ktime);
+            }
+        }
+
         if self.error || too_small {
             self.error = true;
+            if let (true, Some(observer)) = (chunk.sender.incoming(), &self.observer) {
+                observer.malformed();
+            }
             if !chunk.bytes.is_empty() {
                 self.db.store_chunk(chunk);
             }
             return;
         }
 
+        if self.builder.is_none() {
+            self.wire = 0;
+        }
+        self.wire += chunk.bytes.len() as u64;
+
         let sender = &chunk.sender;
 
         let message = match chunk.counter {
-            0 => Some(MessageBuilder::connection_message().build(&sender, &cn)),
-            1 => Some(MessageBuilder::metadata_message().build(&sender, &cn)),
-            2 => Some(MessageBuilder::acknowledge_message().build(&sender, &cn)),
+            0 => {
+                use tezos_messages::p2p::{
+                    encoding::connection::ConnectionMessage, binary_message::BinaryRead,
+                };
+
+                let decoded = ConnectionMessage::from_bytes(&chunk.plain);
+                match &decoded {
+                    Ok(msg) => {
+                        let version = peer::Version::new(msg);
+                        self.version = DecoderStats::version_name(Some(&version));
+                        if sender.incoming() {
+                            if let Some(observer) = &self.observer {
+                                observer.connection_message(msg);
+                            }
+                            if let Some(item) = peer::Item::new(cn, msg) {
+                                self.db.store_peer(item);
+                            }
+                        }
+                    },
+                    Err(error) => log::warn!("cannot decode connection message: {}", error),
+                }
+                let message = MessageBuilder::connection_message().build(&sender, &cn);
+                self.decoder.count(&message.ty, &self.version, decoded.map(drop));
+                Some(message)
+            },
+            1 => {
+                use tezos_messages::p2p::{
+                    encoding::metadata::MetadataMessage, binary_message::BinaryRead,
+                };
+
+                let decoded = MetadataMessage::from_bytes(&chunk.plain);
+                let message = MessageBuilder::metadata_message().build(&sender, &cn);
+                self.decoder.count(&message.ty, &self.version, decoded.map(drop));
+                Some(message)
+            },
+            2 => {
+                use tezos_messages::p2p::{encoding::ack::AckMessage, binary_message::BinaryRead};
+
+                let decoded = AckMessage::from_bytes(&chunk.plain);
+                match &decoded {
+                    Ok(ack) => cn.set_ack(sender, connection::AckInfo::from(ack)),
+                    Err(error) => log::warn!("cannot decode ack message: {}", error),
+                }
+                // the handshake is timed as well
+                self.db.update_connection(cn.clone());
+                let message = MessageBuilder::acknowledge_message().build(&sender, &cn);
+                self.decoder.count(&message.ty, &self.version, decoded.map(drop));
+                Some(message)
+            },
             c => {
+                if self.builder.is_none() {
+                    self.plain.clear();
+                }
+                self.plain.extend_from_slice(&chunk.plain);
                 let building_result = self
                     .builder
                     .take()
@@ -92,7 +183,22 @@ where
                     })
                     .link_chunk(chunk.plain.len());
                 match building_result {
-                    Ok(builder_full) => Some(builder_full.build(&sender, &cn)),
+                    Ok(builder_full) => {
+                        use tezos_messages::p2p::{
+                            encoding::peer::PeerMessageResponse, binary_message::BinaryRead,
+                        };
+
+                        let mut message = builder_full.build(&sender, &cn);
+                        let decoded = PeerMessageResponse::from_bytes(&self.plain);
+                        if let Ok(m) = &decoded {
+                            message.hashes = ContentHash::referenced(m);
+                        }
+                        if let Some(observer) = &self.observer {
+                            observer.message(sender.incoming(), decoded.as_ref().ok());
+                        }
+                        self.decoder.count(&message.ty, &self.version, decoded.map(drop));
+                        Some(message)
+                    },
                     Err(builder) => {
                         self.builder = builder;
                         None
@@ -102,8 +208,14 @@ where
         };
 
         self.db.store_chunk(chunk);
-        if let Some(message) = message {
-            self.db.store_message(message);
+        if let Some(mut message) = message {
+            message.wire_bytes = self.wire;
+            if self.sampler.sample(&message.ty, &message.hashes) {
+                self.db.store_message(message);
+            } else {
+                self.db.count_message(message);
+            }
+            self.stage.processed(1);
         }
     }
 
@@ -1,10 +1,16 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use super::{system::Identity, database::Database, tables, common};
+use super::{
+    system::Identity, database::Database, pipeline::Stage, scoring, decoder_stats, sampling, tables,
+    common,
+};
 
 mod chunk_parser;
 mod message_parser;
 mod connection;
 
-pub use self::connection::Connection;
+pub use self::{
+    connection::{Connection, Resumable},
+    chunk_parser::{Keys, ChunkLimits},
+};
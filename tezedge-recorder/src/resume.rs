@@ -0,0 +1,106 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fs, io,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use serde::{Serialize, Deserialize};
+use super::processor::Resumable;
+
+/// The state of the live connections is persisted periodically, so the recorder resumes
+/// decrypting them after the restart, disabled without the `path`
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResumeConfig {
+    /// the file where the state is written
+    path: Option<PathBuf>,
+    /// seconds between the writes, 10 by default
+    interval_secs: Option<u64>,
+}
+
+impl ResumeConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.unwrap_or(10).max(1))
+    }
+}
+
+/// The connection and where it is captured
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub pid: u32,
+    pub fd: u32,
+    pub node: String,
+    pub connection: Resumable,
+}
+
+/// Each decoder thread replaces its own entries, the file has the entries of all threads
+pub struct ResumeStore {
+    path: Option<PathBuf>,
+    interval: Duration,
+    entries: Mutex<Vec<Vec<Entry>>>,
+}
+
+impl ResumeStore {
+    pub fn new(config: &ResumeConfig, decode_threads: usize) -> Self {
+        ResumeStore {
+            path: config.path.clone(),
+            interval: config.interval(),
+            entries: Mutex::new((0..decode_threads).map(|_| vec![]).collect()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Whether the decoder thread which persisted its connections at `last` should do it again
+    pub fn due(&self, last: Instant) -> bool {
+        self.enabled() && last.elapsed() >= self.interval
+    }
+
+    /// The entries persisted before the restart, the file is removed, the connection
+    /// which is not resumed must not be resumed by the next restart either
+    pub fn load(&self) -> Vec<Entry> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return vec![],
+        };
+        let entries = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|error| {
+                log::error!("failed to parse {}: {}", path.display(), error);
+                vec![]
+            }),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => vec![],
+            Err(error) => {
+                log::error!("failed to read {}: {}", path.display(), error);
+                vec![]
+            },
+        };
+        let _ = fs::remove_file(path);
+        entries
+    }
+
+    pub fn save(&self, thread: usize, entries: Vec<Entry>) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let mut all = self.entries.lock().unwrap();
+        if let Some(slot) = all.get_mut(thread) {
+            *slot = entries;
+        }
+        let flat = all.iter().flatten().collect::<Vec<_>>();
+        // the file is replaced at once, the crash in the middle leaves the previous one
+        let tmp = path.with_extension("tmp");
+        let result = serde_json::to_vec(&flat)
+            .map_err(io::Error::from)
+            .and_then(|bytes| fs::write(&tmp, bytes))
+            .and_then(|()| fs::rename(&tmp, path));
+        if let Err(error) = result {
+            log::error!("failed to persist connections to {}: {}", path.display(), error);
+        }
+    }
+}
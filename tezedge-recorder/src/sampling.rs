@@ -0,0 +1,131 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    sync::Mutex,
+};
+use serde::Serialize;
+use super::{
+    common::MessageType,
+    tables::message_hash::{ContentHash, HashKind},
+};
+
+#[derive(Serialize)]
+pub struct SamplingReport {
+    #[serde(rename = "type")]
+    pub ty: String,
+    /// one message of `rate` is stored
+    pub rate: u32,
+    pub seen: u64,
+    pub stored: u64,
+    /// the messages which are not stored, their bytes are still counted in the bandwidth
+    pub sampled: u64,
+}
+
+#[derive(Default)]
+struct Counts {
+    seen: u64,
+    stored: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    counts: BTreeMap<String, Counts>,
+    // the blocks recently announced by the sampled messages, the oldest is forgotten first
+    blocks: HashSet<[u8; 32]>,
+    order: VecDeque<[u8; 32]>,
+}
+
+/// Stores one in `rate` messages of the high volume types, like `current_head`,
+/// the first message which mentions the block is always stored
+#[derive(Default)]
+pub struct Sampler {
+    rates: BTreeMap<String, u32>,
+    inner: Mutex<Inner>,
+}
+
+impl Sampler {
+    const MAX_BLOCKS: usize = 0x1000;
+
+    /// The `rates` by the name of the type, like `current_head`, the rate 0 or 1 stores all
+    pub fn new(rates: &BTreeMap<String, u32>) -> Self {
+        let rates = rates
+            .iter()
+            .filter(|(name, _)| match name.parse::<MessageType>() {
+                Ok(_) => true,
+                Err(_) => {
+                    log::warn!("sampling of unknown message type {:?} is ignored", name);
+                    false
+                },
+            })
+            .filter(|(_, rate)| **rate > 1)
+            .map(|(name, rate)| (name.clone(), *rate))
+            .collect();
+        Sampler {
+            rates,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// Whether the message should be stored, the `hashes` are referenced by the message
+    pub fn sample(&self, ty: &MessageType, hashes: &[ContentHash]) -> bool {
+        if self.rates.is_empty() {
+            return true;
+        }
+        let name = ty.name();
+        let rate = match self.rates.get(&name) {
+            Some(rate) => *rate as u64,
+            None => return true,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let Inner {
+            counts,
+            blocks,
+            order,
+        } = &mut *inner;
+        let block = hashes.iter().find(|hash| hash.kind == HashKind::Block);
+        let first = match block {
+            Some(block) => {
+                let first = blocks.insert(block.hash);
+                if first {
+                    if order.len() == Self::MAX_BLOCKS {
+                        if let Some(oldest) = order.pop_front() {
+                            blocks.remove(&oldest);
+                        }
+                    }
+                    order.push_back(block.hash);
+                }
+                first
+            },
+            None => false,
+        };
+        let counts = counts.entry(name).or_default();
+        let store = first || counts.seen % rate == 0;
+        counts.seen += 1;
+        if store {
+            counts.stored += 1;
+        }
+        store
+    }
+
+    pub fn report(&self) -> Vec<SamplingReport> {
+        let inner = self.inner.lock().unwrap();
+        self.rates
+            .iter()
+            .map(|(name, rate)| {
+                let (seen, stored) = inner
+                    .counts
+                    .get(name)
+                    .map_or((0, 0), |counts| (counts.seen, counts.stored));
+                SamplingReport {
+                    ty: name.clone(),
+                    rate: *rate,
+                    seen,
+                    stored,
+                    sampled: seen - stored,
+                }
+            })
+            .collect()
+    }
+}
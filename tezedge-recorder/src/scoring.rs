@@ -0,0 +1,317 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use serde::{Serialize, Deserialize};
+use tezos_messages::p2p::encoding::{
+    connection::ConnectionMessage,
+    peer::{PeerMessage, PeerMessageResponse},
+};
+use super::tables::peer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// the proof of work stamp of the connection message does not reach the target
+    InvalidPow,
+    /// the chunk is too short for the message it must carry, or the message cannot be decoded
+    MalformedChunk,
+    /// the advertisement repeats only the addresses the peer advertised on the connection before
+    DuplicateAdvertisement,
+    /// the branch or the head is of another chain than the node's one,
+    /// or its level is below the level the peer announced on the connection before
+    NonsensicalBranch,
+}
+
+impl Rule {
+    // as it is serialized
+    fn name(&self) -> &'static str {
+        match self {
+            Rule::InvalidPow => "invalid_pow",
+            Rule::MalformedChunk => "malformed_chunk",
+            Rule::DuplicateAdvertisement => "duplicate_advertisement",
+            Rule::NonsensicalBranch => "nonsensical_branch",
+        }
+    }
+
+    fn default_weight(&self) -> u64 {
+        match self {
+            Rule::InvalidPow => 100,
+            Rule::MalformedChunk => 10,
+            Rule::DuplicateAdvertisement => 1,
+            Rule::NonsensicalBranch => 20,
+        }
+    }
+}
+
+/// The rules the messages of the peers are checked against, each violation adds
+/// the weight of the rule to the score of the peer, disabled without the `peer_scoring` section
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScoringConfig {
+    /// the score from which the peer is suspicious, 100 by default
+    threshold: Option<u64>,
+    /// the proof of work target in bits, the `expected_pow` of the node, 26 by default
+    pow_target: Option<f64>,
+    /// by the name of the rule, like `invalid_pow`, overrides the default weight
+    weights: Option<BTreeMap<String, u64>>,
+    /// the url the alert is posted to, when the peer becomes suspicious
+    webhook: Option<String>,
+}
+
+impl ScoringConfig {
+    fn threshold(&self) -> u64 {
+        self.threshold.unwrap_or(100)
+    }
+
+    fn pow_target(&self) -> f64 {
+        self.pow_target.unwrap_or(26.0)
+    }
+
+    /// The name in `weights` which is not a rule
+    pub fn unknown_rule(&self) -> Option<&str> {
+        const RULES: [Rule; 4] = [
+            Rule::InvalidPow,
+            Rule::MalformedChunk,
+            Rule::DuplicateAdvertisement,
+            Rule::NonsensicalBranch,
+        ];
+        self.weights
+            .as_ref()?
+            .keys()
+            .find(|name| !RULES.iter().any(|rule| rule.name() == name.as_str()))
+            .map(String::as_str)
+    }
+
+    fn weight(&self, rule: Rule) -> u64 {
+        self.weights
+            .as_ref()
+            .and_then(|weights| weights.get(rule.name()).cloned())
+            .unwrap_or_else(|| rule.default_weight())
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct PeerScore {
+    /// hex
+    pub public_key: String,
+    pub peer_id: Option<String>,
+    pub score: u64,
+    pub addresses: Vec<SocketAddr>,
+    /// how many times the peer violated each rule
+    pub violations: BTreeMap<Rule, u64>,
+    /// unix seconds
+    pub last_violation: u64,
+}
+
+#[derive(Serialize)]
+struct Alert<'a> {
+    node_name: &'a str,
+    threshold: u64,
+    peer: &'a PeerScore,
+}
+
+/// The scores of the peers of one node, kept in memory, they start over after the restart
+pub struct PeerScores {
+    node_name: String,
+    config: ScoringConfig,
+    peers: Mutex<HashMap<[u8; 32], PeerScore>>,
+}
+
+impl PeerScores {
+    pub fn new(node_name: String, config: ScoringConfig) -> Self {
+        PeerScores {
+            node_name,
+            config,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn violation(&self, pk: &[u8; 32], address: SocketAddr, rule: Rule) {
+        let threshold = self.config.threshold();
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(*pk).or_insert_with(|| PeerScore {
+            public_key: hex::encode(pk),
+            peer_id: peer::peer_id(pk).ok(),
+            score: 0,
+            addresses: vec![],
+            violations: BTreeMap::new(),
+            last_violation: 0,
+        });
+        let was_suspicious = peer.score >= threshold;
+        peer.score += self.config.weight(rule);
+        *peer.violations.entry(rule).or_default() += 1;
+        peer.last_violation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        if !peer.addresses.contains(&address) {
+            peer.addresses.push(address);
+        }
+        log::debug!("peer {} violated {:?}, score {}", peer.public_key, rule, peer.score);
+        if !was_suspicious && peer.score >= threshold {
+            log::warn!("peer {} is suspicious, score {}", peer.public_key, peer.score);
+            if let Some(url) = self.config.webhook.clone() {
+                let alert = Alert {
+                    node_name: &self.node_name,
+                    threshold,
+                    peer: &*peer,
+                };
+                let body = serde_json::to_vec(&alert).unwrap_or_default();
+                // the decoder does not wait for the receiver of the alert
+                thread::spawn(move || {
+                    let result = reqwest::blocking::Client::new()
+                        .post(&url)
+                        .header("Content-Type", "application/json")
+                        .body(body)
+                        .send()
+                        .and_then(|response| response.error_for_status());
+                    if let Err(error) = result {
+                        log::error!("failed to post the alert to {}: {}", url, error);
+                    }
+                });
+            }
+        }
+    }
+
+    /// The peers whose score reached the threshold, the worst first
+    pub fn suspicious(&self) -> Vec<PeerScore> {
+        let threshold = self.config.threshold();
+        let mut peers = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer| peer.score >= threshold)
+            .cloned()
+            .collect::<Vec<_>>();
+        peers.sort_by(|a, b| b.score.cmp(&a.score));
+        peers
+    }
+}
+
+#[derive(Default)]
+struct ObserverState {
+    // the key of the peer, known from its connection message
+    pk: Option<[u8; 32]>,
+    // the chain the node announces itself
+    chain_id: Option<Vec<u8>>,
+    advertised: HashSet<String>,
+    head_level: Option<i32>,
+    malformed: bool,
+}
+
+/// Checks the messages of one connection, both directions share it,
+/// the messages of the node tell which chain is the right one
+#[derive(Clone)]
+pub struct Observer {
+    scores: Arc<PeerScores>,
+    remote_addr: SocketAddr,
+    state: Arc<Mutex<ObserverState>>,
+}
+
+impl Observer {
+    pub fn new(scores: Arc<PeerScores>, remote_addr: SocketAddr) -> Self {
+        Observer {
+            scores,
+            remote_addr,
+            state: Arc::new(Mutex::new(ObserverState::default())),
+        }
+    }
+
+    fn violation(&self, state: &ObserverState, rule: Rule) {
+        // nothing to blame before the peer tells its key
+        if let Some(pk) = &state.pk {
+            self.scores.violation(pk, self.remote_addr, rule);
+        }
+    }
+
+    pub fn connection_message(&self, msg: &ConnectionMessage) {
+        use std::convert::TryFrom;
+
+        let mut state = self.state.lock().unwrap();
+        state.pk = <[u8; 32]>::try_from(msg.public_key().as_slice()).ok();
+        let target = self.scores.config.pow_target();
+        if !check_pow(msg.public_key(), msg.proof_of_work_stamp(), target) {
+            self.violation(&state, Rule::InvalidPow);
+        }
+    }
+
+    /// The peer sent the chunk which cannot be parsed, it counts once per connection,
+    /// the rest of the connection cannot be parsed either
+    pub fn malformed(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.malformed {
+            state.malformed = true;
+            self.violation(&state, Rule::MalformedChunk);
+        }
+    }
+
+    /// The complete decrypted peer message, `None` if it cannot be decoded
+    pub fn message(&self, incoming: bool, message: Option<&PeerMessageResponse>) {
+        let message = match message {
+            Some(message) => message,
+            None if incoming => return self.malformed(),
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        if !incoming {
+            let chain_id = match message.message() {
+                PeerMessage::GetCurrentBranch(m) => Some(m.chain_id()),
+                PeerMessage::CurrentBranch(m) => Some(m.chain_id()),
+                PeerMessage::GetCurrentHead(m) => Some(m.chain_id()),
+                PeerMessage::CurrentHead(m) => Some(m.chain_id()),
+                _ => None,
+            };
+            if let Some(chain_id) = chain_id {
+                state.chain_id = Some(chain_id.0.clone());
+            }
+            return;
+        }
+        let (chain_id, level) = match message.message() {
+            PeerMessage::Advertise(m) => {
+                let mut fresh = false;
+                for address in m.id() {
+                    fresh |= state.advertised.insert(address.clone());
+                }
+                if !fresh && !m.id().is_empty() {
+                    self.violation(&state, Rule::DuplicateAdvertisement);
+                }
+                return;
+            },
+            PeerMessage::CurrentBranch(m) => {
+                (m.chain_id(), m.current_branch().current_head().level())
+            },
+            PeerMessage::CurrentHead(m) => (m.chain_id(), m.current_block_header().level()),
+            _ => return,
+        };
+        let other_chain = matches!(&state.chain_id, Some(c) if *c != chain_id.0);
+        let backwards = matches!(state.head_level, Some(l) if level < l);
+        state.head_level = Some(level);
+        if other_chain || backwards {
+            self.violation(&state, Rule::NonsensicalBranch);
+        }
+    }
+}
+
+/// Whether the stamp proves the work of at least `target` bits for the key,
+/// the hash is compared with the target as a fraction, as the node does it
+fn check_pow(pk: &[u8], stamp: &[u8], target: f64) -> bool {
+    let mut data = pk.to_vec();
+    data.extend_from_slice(stamp);
+    let hash = match crypto::blake2b::digest_256(&data) {
+        Ok(hash) => hash,
+        Err(_) => return false,
+    };
+    let mut head = [0; 8];
+    head.copy_from_slice(&hash[..8]);
+    // the first 64 bits of the hash are precise enough
+    let fraction = u64::from_be_bytes(head) as f64 / 2f64.powi(64);
+    fraction <= 2f64.powf(-target)
+}
@@ -0,0 +1,148 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use serde::Serialize;
+use super::database::blocking;
+
+#[derive(Clone, Default, Serialize)]
+pub struct SelfReport {
+    /// unix milliseconds of the measurement, 0 until the first one
+    pub timestamp: u64,
+    /// the resident memory in bytes
+    pub rss: u64,
+    /// the cpu time in seconds since the start
+    pub cpu_time: f64,
+    /// the cpu used during the last interval in percents of one core
+    pub cpu: f64,
+    pub threads: u64,
+    pub open_fds: u64,
+    /// the bytes the database of each node takes on the disk
+    pub db_disk: BTreeMap<String, u64>,
+}
+
+/// The resources the recorder itself takes, measured periodically,
+/// to rule out the recorder when the host is short of them
+#[derive(Default)]
+pub struct SelfMonitor {
+    report: Mutex<SelfReport>,
+}
+
+// from `/proc/self/stat`, the cpu times are in the clock ticks
+struct Stat {
+    ticks: u64,
+    threads: u64,
+}
+
+impl SelfMonitor {
+    pub const INTERVAL: Duration = Duration::from_secs(10);
+
+    // the `USER_HZ`, it is 100 on every architecture linux supports
+    const TICKS_PER_SECOND: f64 = 100.0;
+
+    pub fn report(&self) -> SelfReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    fn stat() -> io::Result<Stat> {
+        let stat = fs::read_to_string("/proc/self/stat")?;
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, "bad /proc/self/stat");
+        // the name of the process may contain spaces, the fields follow the last paren
+        let fields = stat[(stat.rfind(')').ok_or_else(bad)? + 1)..]
+            .split_whitespace()
+            .collect::<Vec<_>>();
+        let field = |i: usize| -> io::Result<u64> {
+            fields.get(i).and_then(|f| f.parse().ok()).ok_or_else(bad)
+        };
+        // the state is the field 3, the utime is 14, the stime is 15, the threads is 20
+        Ok(Stat {
+            ticks: field(11)? + field(12)?,
+            threads: field(17)?,
+        })
+    }
+
+    fn rss() -> io::Result<u64> {
+        let status = fs::read_to_string("/proc/self/status")?;
+        let kb = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        Ok(kb * 1024)
+    }
+
+    fn dir_size(path: &Path) -> io::Result<u64> {
+        let mut size = 0;
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                size += Self::dir_size(&entry.path())?;
+            } else {
+                size += metadata.len();
+            }
+        }
+        Ok(size)
+    }
+
+    fn measure(&self, dbs: &BTreeMap<String, PathBuf>) -> io::Result<()> {
+        let stat = Self::stat()?;
+        let rss = Self::rss()?;
+        let open_fds = fs::read_dir("/proc/self/fd")?.count() as u64;
+        let db_disk = dbs
+            .iter()
+            .filter_map(|(name, path)| match Self::dir_size(path) {
+                Ok(size) => Some((name.clone(), size)),
+                // the file removed during the walk, like the compacted sst
+                Err(error) => {
+                    log::debug!("cannot measure {}: {}", path.display(), error);
+                    None
+                },
+            })
+            .collect();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        let mut report = self.report.lock().unwrap();
+        let cpu_time = stat.ticks as f64 / Self::TICKS_PER_SECOND;
+        let cpu = if report.timestamp != 0 && timestamp > report.timestamp {
+            let elapsed = (timestamp - report.timestamp) as f64 / 1000.0;
+            (cpu_time - report.cpu_time) * 100.0 / elapsed
+        } else {
+            0.0
+        };
+        *report = SelfReport {
+            timestamp,
+            rss,
+            cpu_time,
+            cpu,
+            threads: stat.threads,
+            open_fds,
+            db_disk,
+        };
+        Ok(())
+    }
+}
+
+/// Measures every `SelfMonitor::INTERVAL`, the `dbs` are the database directories by the node
+pub async fn schedule(monitor: Arc<SelfMonitor>, dbs: BTreeMap<String, PathBuf>) {
+    let dbs = Arc::new(dbs);
+    let mut interval = tokio::time::interval(SelfMonitor::INTERVAL);
+    loop {
+        interval.tick().await;
+        let (monitor, dbs) = (monitor.clone(), dbs.clone());
+        match blocking(move || monitor.measure(&dbs)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(error)) => log::warn!("cannot measure the recorder itself: {}", error),
+            Err(error) => log::error!("self monitor panicked: {}", error),
+        }
+    }
+}
@@ -1,16 +1,44 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{sync::Arc, collections::HashMap};
+use std::{sync::Arc, collections::{HashMap, VecDeque}, convert::Infallible, time::Duration};
 use anyhow::Result;
+use futures::Stream;
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 use warp::{
-    Filter, Rejection, Reply,
-    reply::{WithStatus, Json, self},
-    http::StatusCode,
+    Filter, Rejection, Reply, reject,
+    reply::{WithStatus, Json, Response, self},
+    http::{StatusCode, HeaderValue},
+    sse,
 };
 use super::{
-    database::{DatabaseFetch, ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter},
-    tables::chunk,
+    csv,
+    graphql,
+    grafana,
+    openapi::{Endpoint, args, body},
+    anonymize::Anonymizer,
+    escrow,
+    encoding::Encoding,
+    limiter::{Limiter, QueryPermit, recover},
+    control::{Control, FirewallError},
+    health::Health,
+    pipeline::Pipeline,
+    coverage::Coverage,
+    scoring::PeerScores,
+    decoder_stats::DecoderStats,
+    sampling::Sampler,
+    self_monitor::SelfMonitor,
+    graph::{Graph, GraphFilter, GraphFormat},
+    ask::{Ask, Peer},
+    conversation::ConversationFormat,
+    system::{SharedConfig, NodeOverrides},
+    database::{
+        self, Database, DatabaseFetch, ConnectionsFilter, ChunksFilter, MessagesFilter, LogsFilter,
+        LogCountsFilter, PeerFilter, StorageStatsFilter, AnnotationsFilter, IncidentsFilter,
+        FindingsFilter, BandwidthFilter, GapsFilter, StorageStats,
+    },
+    tables::{connection, chunk, message, annotation, preset, gap, session, log_count},
 };
 
 fn connections<Db>(
@@ -21,14 +49,19 @@ where
 {
     warp::path!("v3" / "connections")
         .and(warp::query::query())
-        .map(move |filter: ConnectionsFilter| -> WithStatus<Json> {
-            match db.fetch_connections(&filter) {
-                Ok(connections) => reply::with_status(reply::json(&connections), StatusCode::OK),
-                Err(err) => {
-                    let r = &format!("database error: {}", err);
-                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
-                },
-            }
+        .and_then(move |filter: ConnectionsFilter| {
+            let db = db.clone();
+            blocking(move || -> WithStatus<Json> {
+                match db.fetch_connections(&filter) {
+                    Ok(connections) => {
+                        reply::with_status(reply::json(&connections), StatusCode::OK)
+                    },
+                    Err(err) => {
+                        let r = &format!("database error: {}", err);
+                        reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                    },
+                }
+            })
         })
 }
 
@@ -38,15 +71,18 @@ fn chunks<Db>(
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
-    warp::path!("v3" / "chunks").and(warp::query::query()).map(
-        move |filter: ChunksFilter| -> WithStatus<Json> {
-            match db.fetch_chunks_truncated(&filter) {
-                Ok(chunks) => reply::with_status(reply::json(&chunks), StatusCode::OK),
-                Err(err) => {
-                    let r = &format!("database error: {}", err);
-                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
-                },
-            }
+    warp::path!("v3" / "chunks").and(warp::query::query()).and_then(
+        move |filter: ChunksFilter| {
+            let db = db.clone();
+            blocking(move || -> WithStatus<Json> {
+                match db.fetch_chunks_truncated(&filter) {
+                    Ok(chunks) => reply::with_status(reply::json(&chunks), StatusCode::OK),
+                    Err(err) => {
+                        let r = &format!("database error: {}", err);
+                        reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                    },
+                }
+            })
         },
     )
 }
@@ -65,33 +101,61 @@ where
         db.fetch_chunk(&key).map_err(Into::into)
     }
 
-    warp::path!("v3" / "chunk" / String).map(move |chunk_id: String| -> WithStatus<Json> {
-        match inner(&db, chunk_id) {
-            Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
-            Err(err) => {
-                let r = format!("database error: {}", err);
-                reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
-            },
-        }
+    warp::path!("v3" / "chunk" / String).and_then(move |chunk_id: String| {
+        let db = db.clone();
+        blocking(move || -> WithStatus<Json> {
+            match inner(&db, chunk_id) {
+                Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                Err(err) => {
+                    let r = format!("database error: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        })
     })
 }
 
 fn messages<Db>(
     db: Arc<Db>,
+    limiter: Arc<Limiter>,
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
     warp::path!("v3" / "messages")
         .and(warp::query::query())
-        .map(move |filter: MessagesFilter| -> reply::WithStatus<Json> {
-            match db.fetch_messages(&filter) {
-                Ok(messages) => reply::with_status(reply::json(&messages), StatusCode::OK),
-                Err(err) => {
-                    let r = &format!("database error: {}", err);
-                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
-                },
-            }
+        .and(limiter.query())
+        .and_then(move |mut filter: MessagesFilter, permit: QueryPermit| {
+            let db = db.clone();
+            filter.deadline = limiter.deadline(filter.timeout_ms);
+            blocking(move || -> reply::WithStatus<Json> {
+                let _permit = permit;
+                let preset = filter.preset.clone();
+                if let Err(r) = with_preset(&*db, preset, |p| filter.apply(p)) {
+                    return r;
+                }
+                let projection = match filter.projection() {
+                    Ok(projection) => projection,
+                    Err(err) => {
+                        return reply::with_status(reply::json(&err), StatusCode::BAD_REQUEST);
+                    },
+                };
+                match db.fetch_messages(&filter) {
+                    Ok(messages) => {
+                        let json = match &projection {
+                            Some(projection) => reply::json(
+                                &messages.iter().map(|m| m.project(projection)).collect::<Vec<_>>(),
+                            ),
+                            None => reply::json(&messages),
+                        };
+                        reply::with_status(json, query_status(&filter.deadline))
+                    },
+                    Err(err) => {
+                        let r = &format!("database error: {}", err);
+                        reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                    },
+                }
+            })
         })
 }
 
@@ -101,34 +165,48 @@ fn message<Db>(
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
-    warp::path!("v3" / "message" / u64).map(move |id: u64| -> reply::WithStatus<Json> {
-        match db.fetch_message(id) {
-            Ok(message) => reply::with_status(reply::json(&message), StatusCode::OK),
-            Err(err) => {
-                let r = &format!("database error: {}", err);
-                reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
-            },
-        }
+    warp::path!("v3" / "message" / u64).and_then(move |id: u64| {
+        let db = db.clone();
+        blocking(move || -> reply::WithStatus<Json> {
+            match db.fetch_message(id) {
+                Ok(message) => reply::with_status(reply::json(&message), StatusCode::OK),
+                Err(err) => {
+                    let r = &format!("database error: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        })
     })
 }
 
 fn logs<Db>(
     db: Arc<Db>,
+    limiter: Arc<Limiter>,
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
-    warp::path!("v3" / "logs").and(warp::query::query()).map(
-        move |filter: LogsFilter| -> reply::WithStatus<Json> {
-            match db.fetch_log(&filter) {
-                Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
-                Err(err) => {
-                    let r = &format!("database error: {}", err);
-                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
-                },
-            }
-        },
-    )
+    warp::path!("v3" / "logs")
+        .and(warp::query::query())
+        .and(limiter.query())
+        .and_then(move |mut filter: LogsFilter, permit: QueryPermit| {
+            let db = db.clone();
+            filter.deadline = limiter.deadline(filter.timeout_ms);
+            blocking(move || -> reply::WithStatus<Json> {
+                let _permit = permit;
+                let preset = filter.preset.clone();
+                if let Err(r) = with_preset(&*db, preset, |p| filter.apply(p)) {
+                    return r;
+                }
+                match db.fetch_log(&filter) {
+                    Ok(v) => reply::with_status(reply::json(&v), query_status(&filter.deadline)),
+                    Err(err) => {
+                        let r = &format!("database error: {}", err);
+                        reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                    },
+                }
+            })
+        })
 }
 
 pub fn version(
@@ -140,19 +218,249 @@ pub fn version(
     )
 }
 
+/// Every endpoint which parses the query string or the body,
+/// the openapi document is generated from the types they parse
+const ENDPOINTS: &[Endpoint] = &[
+    Endpoint {
+        method: "get",
+        path: "/v2/p2p",
+        query: &[args::<MessagesFilter>, args::<FormatFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/p2p/{id}",
+        query: &[args::<NodeFilter>, args::<FormatFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/p2p/{id}/body",
+        query: &[args::<NodeFilter>, args::<FormatFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/connections/{id}/scenario",
+        query: &[args::<NodeFilter>, args::<ScenarioFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/log",
+        query: &[args::<LogsFilter>, args::<FormatFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/log/tail",
+        query: &[args::<LogsFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/log/counts",
+        query: &[args::<LogCountsFilter>, args::<FormatFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/storage/stats",
+        query: &[args::<StorageStatsFilter>, args::<FormatFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/chunks",
+        query: &[args::<ChunksFilter>, args::<FormatFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/peers/suspicious",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/peers/{public_key}",
+        query: &[args::<PeerFilter>, args::<FormatFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/stats/bandwidth",
+        query: &[args::<BandwidthFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/graph",
+        query: &[args::<GraphFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/ask/last-head-from/{peer}",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/ask/peers-advertising/{block}",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/ask/why-disconnected/{peer}",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/stats/decoder",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/incidents",
+        query: &[args::<IncidentsFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/gaps",
+        query: &[args::<GapsFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/conformance",
+        query: &[args::<FindingsFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/annotations",
+        query: &[args::<AnnotationsFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "post",
+        path: "/v2/annotations",
+        query: &[args::<NodeFilter>],
+        body: Some(body::<annotation::Item>),
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/filters",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/filters/{name}",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "put",
+        path: "/v2/filters/{name}",
+        query: &[args::<NodeFilter>],
+        body: Some(body::<preset::Item>),
+    },
+    Endpoint {
+        method: "delete",
+        path: "/v2/filters/{name}",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "post",
+        path: "/v2/sessions/start",
+        query: &[args::<NodeFilter>],
+        body: Some(body::<SessionStart>),
+    },
+    Endpoint {
+        method: "post",
+        path: "/v2/sessions/stop",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/sessions",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/sessions/{id}/export",
+        query: &[args::<NodeFilter>, args::<ExportFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "get",
+        path: "/v2/sessions/{id}/keys",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "delete",
+        path: "/v2/sessions/{id}",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "post",
+        path: "/v2/capture/ignored",
+        query: &[args::<IgnoredFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "delete",
+        path: "/v2/capture/ignored",
+        query: &[args::<IgnoredFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "post",
+        path: "/v2/control/payload/{port}",
+        query: &[args::<PayloadFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "post",
+        path: "/v2/control/identity",
+        query: &[args::<NodeFilter>],
+        body: None,
+    },
+    Endpoint {
+        method: "put",
+        path: "/v2/config",
+        query: &[args::<NodeFilter>],
+        body: Some(body::<NodeOverrides>),
+    },
+];
+
+/// The document at `/openapi.json`, the old path is kept for compatibility
 pub fn openapi(
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
-    warp::path!("openapi" / "network-recorder-openapi.json")
+    let document = Arc::new(super::openapi::document(ENDPOINTS));
+    warp::path!("openapi.json")
+        .or(warp::path!("openapi" / "network-recorder-openapi.json"))
+        .unify()
         .and(warp::query::query())
         .map(move |()| -> reply::WithStatus<Json> {
-            let s = include_str!("../openapi.json");
-            let d = serde_json::from_str::<serde_json::Value>(s).unwrap();
-            reply::with_status(reply::json(&d), StatusCode::OK)
+            reply::with_status(reply::json(document.as_ref()), StatusCode::OK)
         })
 }
 
 pub fn routes<Db>(
     db: Arc<Db>,
+    limiter: Arc<Limiter>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
@@ -160,115 +468,1930 @@ where
     use warp::reply::with;
 
     warp::get()
+        .and(limiter.rate())
         .and(
             connections(db.clone())
                 .or(chunks(db.clone()))
                 .or(chunk(db.clone()))
-                .or(messages(db.clone()))
+                .or(messages(db.clone(), limiter.clone()))
                 .or(message(db.clone()))
-                .or(logs(db))
+                .or(logs(db, limiter))
                 .or(version().or(openapi())),
         )
         .with(with::header("Content-Type", "application/json"))
+        .recover(recover)
         .with(with::header("Access-Control-Allow-Origin", "*"))
 }
 
+#[derive(Deserialize, JsonSchema)]
+struct FormatFilter {
+    /// The encoding of the response, `json`, `csv`, `msgpack` or `cbor`,
+    /// the same as the `Accept` header, csv is available for the lists only
+    format: Option<String>,
+}
+
+/// The encoding by the `format` query argument or by the `Accept` header
+fn encoding() -> impl Filter<Extract = (Encoding,), Error = Rejection> + Clone + Sync + Send {
+    warp::query::query()
+        .and(warp::header::optional::<String>("accept"))
+        .map(|filter: FormatFilter, accept: Option<String>| {
+            Encoding::negotiate(filter.format.as_deref(), accept.as_deref())
+        })
+}
+
+fn encoded_reply<T>(value: &T, encoding: Encoding) -> Response
+where
+    T: serde::Serialize,
+{
+    match encoding.encode(value) {
+        Ok((body, content_type)) => {
+            reply::with_header(body, "Content-Type", content_type).into_response()
+        },
+        Err(err) => {
+            let r = &format!("encoding error: {}", err);
+            reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        },
+    }
+}
+
+fn csv_reply<T>(records: &[T], columns: &[&str]) -> Response
+where
+    T: serde::Serialize,
+{
+    let body = csv::render(records, columns);
+    reply::with_header(body, "Content-Type", "text/csv; charset=utf-8").into_response()
+}
+
+#[derive(Debug)]
+struct HandlerPanicked;
+
+impl reject::Reject for HandlerPanicked {}
+
+/// The handler which touches the database runs on the blocking pool,
+/// the panic is the internal server error
+async fn blocking<F, T>(f: F) -> Result<T, Rejection>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    database::blocking(f).await.map_err(|error| {
+        log::error!("the handler panicked: {}", error);
+        reject::custom(HandlerPanicked)
+    })
+}
+
+/// The query which hit its deadline is answered with what it found until then
+fn query_status(deadline: &database::Deadline) -> StatusCode {
+    if deadline.expired() {
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::OK
+    }
+}
+
+/// Marks the partial result, the status is `504 Gateway Timeout`
+/// and the header `X-Partial-Result` is set
+fn partial(mut response: Response, deadline: &database::Deadline) -> Response {
+    if deadline.expired() {
+        *response.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+        response
+            .headers_mut()
+            .insert("x-partial-result", HeaderValue::from_static("true"));
+    }
+    response
+}
+
+/// Fills the filter from the preset of the `name`, if any
+fn with_preset<Db, F>(db: &Db, name: Option<String>, apply: F) -> Result<(), WithStatus<Json>>
+where
+    Db: DatabaseFetch,
+    F: FnOnce(&preset::Item),
+{
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+    match db.fetch_preset(&name) {
+        Ok(Some(item)) => {
+            apply(&item);
+            Ok(())
+        },
+        Ok(None) => {
+            let r = &format!("no such preset: {:?}", name);
+            Err(reply::with_status(reply::json(&r), StatusCode::NOT_FOUND))
+        },
+        Err(err) => {
+            let r = &format!("database error: {}", err);
+            Err(reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR))
+        },
+    }
+}
+
 fn p2p<Db>(
     dbs: HashMap<String, Arc<Db>>,
-) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+    limiter: Arc<Limiter>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
-    warp::path!("v2" / "p2p").and(warp::query::query()).map(
-        move |filter: MessagesFilter| -> reply::WithStatus<Json> {
-            let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
-            match dbs.get(&node_name) {
-                Some(db) => match db.fetch_messages(&filter) {
-                    Ok(messages) => reply::with_status(reply::json(&messages), StatusCode::OK),
+    warp::path!("v2" / "p2p")
+        .and(warp::query::query())
+        .and(limiter.query())
+        .and(encoding())
+        .and_then(move |mut filter: MessagesFilter, permit: QueryPermit, encoding: Encoding| {
+            let dbs = dbs.clone();
+            filter.deadline = limiter.deadline(filter.timeout_ms);
+            blocking(move || -> Response {
+                let _permit = permit;
+                let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+                let db = match dbs.get(&node_name) {
+                    Some(db) => db,
+                    None => {
+                        let r = &format!("no such node: {:?}", node_name);
+                        return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                            .into_response();
+                    },
+                };
+                let preset = filter.preset.clone();
+                if let Err(r) = with_preset(&**db, preset, |p| filter.apply(p)) {
+                    return r.into_response();
+                }
+                let projection = match filter.projection() {
+                    Ok(projection) => projection,
+                    Err(err) => {
+                        return reply::with_status(reply::json(&err), StatusCode::BAD_REQUEST)
+                            .into_response();
+                    },
+                };
+                match db.fetch_messages(&filter) {
+                    Ok(messages) => {
+                        let response = match &projection {
+                            Some(projection) if encoding == Encoding::Csv => {
+                                csv_reply(&messages, &projection.columns())
+                            },
+                            None if encoding == Encoding::Csv => {
+                                csv_reply(&messages, message::MessageFrontend::CSV_COLUMNS)
+                            },
+                            Some(projection) => {
+                                let projected = messages
+                                    .iter()
+                                    .map(|m| m.project(projection))
+                                    .collect::<Vec<_>>();
+                                encoded_reply(&projected, encoding)
+                            },
+                            None => encoded_reply(&messages, encoding),
+                        };
+                        partial(response, &filter.deadline)
+                    },
                     Err(err) => {
                         let r = &format!("database error: {}", err);
                         reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                            .into_response()
                     },
-                },
-                None => {
-                    let r = &format!("no such node: {:?}", node_name);
-                    reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
-                },
-            }
-        },
-    )
+                }
+            })
+        })
 }
 
 fn p2p_details<Db>(
     dbs: HashMap<String, Arc<Db>>,
-) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
     warp::path!("v2" / "p2p" / u64)
         .and(warp::query::query())
-        .map(
-            move |id: u64, filter: MessagesFilter| -> reply::WithStatus<Json> {
-                let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+        .and(encoding())
+        .and_then(
+            move |id: u64, filter: NodeFilter, encoding: Encoding| {
+                let dbs = dbs.clone();
+                blocking(move || -> Response {
+                    let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+                    match dbs.get(&node_name) {
+                        Some(db) => match db.fetch_message(id) {
+                            Ok(message) => encoded_reply(&message, encoding),
+                            Err(err) => {
+                                let r = &format!("database error: {}", err);
+                                reply::with_status(
+                                    reply::json(&r),
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                )
+                                .into_response()
+                            },
+                        },
+                        None => {
+                            let r = &format!("no such node: {:?}", node_name);
+                            reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                                .into_response()
+                        },
+                    }
+                })
+            },
+        )
+}
+
+fn p2p_body<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v2" / "p2p" / u64 / "body")
+        .and(warp::query::query())
+        .and(encoding())
+        .and_then(move |id: u64, filter: NodeFilter, encoding: Encoding| {
+            let dbs = dbs.clone();
+            blocking(move || -> Response {
+                let node_name = filter.node_name.unwrap_or("tezedge".to_string());
                 match dbs.get(&node_name) {
                     Some(db) => match db.fetch_message(id) {
-                        Ok(message) => reply::with_status(reply::json(&message), StatusCode::OK),
+                        Ok(Some(message)) => encoded_reply(&message.body(), encoding),
+                        Ok(None) => {
+                            let r = &format!("no such message: {}", id);
+                            reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                                .into_response()
+                        },
                         Err(err) => {
                             let r = &format!("database error: {}", err);
                             reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                                .into_response()
                         },
                     },
                     None => {
                         let r = &format!("no such node: {:?}", node_name);
-                        reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                        reply::with_status(reply::json(&r), StatusCode::NOT_FOUND).into_response()
                     },
                 }
-            },
-        )
+            })
+        })
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ScenarioFilter {
+    /// `yaml`, the default, or `cbor`
+    format: Option<String>,
+}
+
+fn scenario<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v2" / "connections" / connection::Key / "scenario")
+        .and(warp::query::query())
+        .and(warp::query::query())
+        .and_then(move |cn: connection::Key, filter: NodeFilter, scenario: ScenarioFilter| {
+            let dbs = dbs.clone();
+            blocking(move || -> Response {
+                let format = match ConversationFormat::parse(scenario.format.as_deref()) {
+                    Ok(format) => format,
+                    Err(r) => {
+                        return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST)
+                            .into_response();
+                    },
+                };
+                let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+                let db = match dbs.get(&node_name) {
+                    Some(db) => db,
+                    None => {
+                        let r = &format!("no such node: {:?}", node_name);
+                        return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                            .into_response();
+                    },
+                };
+                let conversation = match db.fetch_conversation(&cn) {
+                    Ok(Some(conversation)) => conversation,
+                    Ok(None) => {
+                        let r = &format!("no such connection: {}", cn);
+                        return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                            .into_response();
+                    },
+                    Err(err) => {
+                        let r = &format!("database error: {}", err);
+                        return reply::with_status(
+                            reply::json(&r),
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                        )
+                        .into_response();
+                    },
+                };
+                match conversation.encode(format) {
+                    Ok(body) => {
+                        reply::with_header(body, "Content-Type", format.content_type())
+                            .into_response()
+                    },
+                    Err(err) => {
+                        let r = &format!("encoding error: {}", err);
+                        reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                            .into_response()
+                    },
+                }
+            })
+        })
 }
 
 fn log_old<Db>(
     dbs: HashMap<String, Arc<Db>>,
-) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+    limiter: Arc<Limiter>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
-    warp::path!("v2" / "log").and(warp::query::query()).map(
-        move |filter: LogsFilter| -> reply::WithStatus<Json> {
-            let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
-            match dbs.get(&node_name) {
-                Some(db) => match db.fetch_log(&filter) {
-                    Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+    warp::path!("v2" / "log")
+        .and(warp::query::query())
+        .and(limiter.query())
+        .and(encoding())
+        .and_then(move |mut filter: LogsFilter, permit: QueryPermit, encoding: Encoding| {
+            let dbs = dbs.clone();
+            filter.deadline = limiter.deadline(filter.timeout_ms);
+            blocking(move || -> Response {
+                let _permit = permit;
+                let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+                let db = match dbs.get(&node_name) {
+                    Some(db) => db,
+                    None => {
+                        let r = &format!("no such node: {:?}", node_name);
+                        return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                            .into_response();
+                    },
+                };
+                let preset = filter.preset.clone();
+                if let Err(r) = with_preset(&**db, preset, |p| filter.apply(p)) {
+                    return r.into_response();
+                }
+                match db.fetch_log(&filter) {
+                    Ok(v) => partial(encoded_reply(&v, encoding), &filter.deadline),
                     Err(err) => {
                         let r = &format!("database error: {}", err);
                         reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                            .into_response()
                     },
-                },
-                None => {
-                    let r = &format!("no such node: {:?}", node_name);
-                    reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
-                },
-            }
-        },
-    )
+                }
+            })
+        })
 }
 
-pub fn routes_old<Db>(
+fn log_counts<Db>(
     dbs: HashMap<String, Arc<Db>>,
-) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Sync + Send + 'static
+    limiter: Arc<Limiter>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     Db: DatabaseFetch + Sync + Send + 'static,
 {
-    use warp::reply::with;
+    warp::path!("v2" / "log" / "counts")
+        .and(warp::query::query())
+        .and(limiter.query())
+        .and(encoding())
+        .and_then(move |filter: LogCountsFilter, permit: QueryPermit, encoding: Encoding| {
+            let dbs = dbs.clone();
+            blocking(move || -> Response {
+                let _permit = permit;
+                let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+                match dbs.get(&node_name) {
+                    Some(db) => match db.fetch_log_counts(&filter) {
+                        Ok(_) if filter.gaps == Some(true) && encoding == Encoding::Csv => {
+                            let r = &"the gaps cannot be encoded as csv";
+                            reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST)
+                                .into_response()
+                        },
+                        Ok(v) if filter.gaps == Some(true) => {
+                            match with_gaps(db.as_ref(), v, filter.from, filter.to) {
+                                Ok(v) => encoded_reply(&v, encoding),
+                                Err(err) => {
+                                    let r = &format!("database error: {}", err);
+                                    reply::with_status(
+                                        reply::json(&r),
+                                        StatusCode::INTERNAL_SERVER_ERROR,
+                                    )
+                                    .into_response()
+                                },
+                            }
+                        },
+                        Ok(v) if encoding == Encoding::Csv => {
+                            csv_reply(&v, log_count::LevelCounts::CSV_COLUMNS)
+                        },
+                        Ok(v) => encoded_reply(&v, encoding),
+                        Err(err) => {
+                            let r = &format!("database error: {}", err);
+                            reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                                .into_response()
+                        },
+                    },
+                    None => {
+                        let r = &format!("no such node: {:?}", node_name);
+                        reply::with_status(reply::json(&r), StatusCode::NOT_FOUND).into_response()
+                    },
+                }
+            })
+        })
+}
 
-    warp::get()
-        .and(
-            p2p(dbs.clone())
-                .or(p2p_details(dbs.clone()))
-                .or(log_old(dbs))
-                .or(version())
-                .or(openapi()),
-        )
-        .with(with::header("Content-Type", "application/json"))
+fn connection_chunks<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+    limiter: Arc<Limiter>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v2" / "chunks")
+        .and(warp::query::query())
+        .and(limiter.query())
+        .and(encoding())
+        .and_then(move |filter: ChunksFilter, permit: QueryPermit, encoding: Encoding| {
+            let dbs = dbs.clone();
+            blocking(move || -> Response {
+                let _permit = permit;
+                let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+                if filter.cn.is_none() {
+                    let r = &"the connection `cn` is required";
+                    return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST)
+                        .into_response();
+                }
+                match dbs.get(&node_name) {
+                    Some(db) => match db.fetch_chunks_truncated(&filter) {
+                        Ok(chunks) => encoded_reply(&chunks, encoding),
+                        Err(err) => {
+                            let r = &format!("database error: {}", err);
+                            reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                                .into_response()
+                        },
+                    },
+                    None => {
+                        let r = &format!("no such node: {:?}", node_name);
+                        reply::with_status(reply::json(&r), StatusCode::NOT_FOUND).into_response()
+                    },
+                }
+            })
+        })
+}
+
+fn storage_stats<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v2" / "storage" / "stats")
+        .and(warp::query::query())
+        .and(encoding())
+        .and_then(move |filter: StorageStatsFilter, encoding: Encoding| {
+            let dbs = dbs.clone();
+            blocking(move || -> Response {
+                let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+                match dbs.get(&node_name) {
+                    Some(db) => match db.fetch_storage_stats() {
+                        Ok(v) if encoding == Encoding::Csv => {
+                            csv_reply(&[v], StorageStats::CSV_COLUMNS)
+                        },
+                        Ok(v) => encoded_reply(&v, encoding),
+                        Err(err) => {
+                            let r = &format!("database error: {}", err);
+                            reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                                .into_response()
+                        },
+                    },
+                    None => {
+                        let r = &format!("no such node: {:?}", node_name);
+                        reply::with_status(reply::json(&r), StatusCode::NOT_FOUND).into_response()
+                    },
+                }
+            })
+        })
+}
+
+fn bandwidth<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v2" / "stats" / "bandwidth")
+        .and(warp::query::query())
+        .and_then(move |filter: BandwidthFilter| {
+            let dbs = dbs.clone();
+            blocking(move || -> reply::WithStatus<Json> {
+                let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+                match dbs.get(&node_name) {
+                    Some(db) => match db.fetch_bandwidth(&filter) {
+                        Ok(v) if filter.gaps == Some(true) => {
+                            match with_gaps(db.as_ref(), v, filter.from, filter.to) {
+                                Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                                Err(err) => {
+                                    let r = &format!("database error: {}", err);
+                                    reply::with_status(
+                                        reply::json(&r),
+                                        StatusCode::INTERNAL_SERVER_ERROR,
+                                    )
+                                },
+                            }
+                        },
+                        Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                        Err(err) => {
+                            let r = &format!("database error: {}", err);
+                            reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                        },
+                    },
+                    None => {
+                        let r = &format!("no such node: {:?}", node_name);
+                        reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                    },
+                }
+            })
+        })
+}
+
+/// The nodes and their peers as GraphML or DOT, for Gephi or graphviz
+fn graph<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v2" / "graph")
+        .and(warp::query::query())
+        .and_then(move |filter: GraphFilter| {
+            let dbs = dbs.clone();
+            blocking(move || -> Response {
+                let format = match GraphFormat::parse(filter.format.as_deref()) {
+                    Ok(format) => format,
+                    Err(r) => {
+                        return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST)
+                            .into_response();
+                    },
+                };
+                let mut nodes = dbs
+                    .iter()
+                    .filter(|(name, _)| filter.node_name.as_ref().map_or(true, |n| n == *name))
+                    .map(|(name, db)| (name.clone(), db.as_ref()))
+                    .collect::<Vec<_>>();
+                if let (true, Some(node_name)) = (nodes.is_empty(), &filter.node_name) {
+                    let r = &format!("no such node: {:?}", node_name);
+                    return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                        .into_response();
+                }
+                nodes.sort_by(|a, b| a.0.cmp(&b.0));
+                match Graph::build(&nodes, filter.from, filter.to) {
+                    Ok(graph) => {
+                        let body = graph.render(format);
+                        reply::with_header(body, "Content-Type", format.content_type())
+                            .into_response()
+                    },
+                    Err(r) => reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                        .into_response(),
+                }
+            })
+        })
+}
+
+/// The answer is `404 Not Found` if nothing is recorded about the subject
+fn ask_reply<Db, T, F>(dbs: &HashMap<String, Arc<Db>>, filter: NodeFilter, f: F) -> Response
+where
+    Db: DatabaseFetch,
+    T: serde::Serialize,
+    F: FnOnce(Ask<'_, Db>) -> Result<Option<T>, String>,
+{
+    let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+    let db = match dbs.get(&node_name) {
+        Some(db) => db,
+        None => {
+            let r = &format!("no such node: {:?}", node_name);
+            return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND).into_response();
+        },
+    };
+    match f(Ask::new(db.as_ref())) {
+        Ok(Some(answer)) => reply::json(&answer).into_response(),
+        Ok(None) => {
+            let r = &"nothing is recorded";
+            reply::with_status(reply::json(&r), StatusCode::NOT_FOUND).into_response()
+        },
+        Err(r) => {
+            reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        },
+    }
+}
+
+fn ask<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    let last_head = {
+        let dbs = dbs.clone();
+        warp::path!("v2" / "ask" / "last-head-from" / String)
+            .and(warp::query::query())
+            .and_then(move |peer: String, filter: NodeFilter| {
+                let dbs = dbs.clone();
+                blocking(move || -> Response {
+                    ask_reply(&dbs, filter, |ask| ask.last_head_from(&Peer::parse(&peer)))
+                })
+            })
+    };
+    let advertising = {
+        let dbs = dbs.clone();
+        warp::path!("v2" / "ask" / "peers-advertising" / String)
+            .and(warp::query::query())
+            .and_then(move |block: String, filter: NodeFilter| {
+                let dbs = dbs.clone();
+                blocking(move || -> Response {
+                    ask_reply(&dbs, filter, |ask| ask.peers_advertising(&block).map(Some))
+                })
+            })
+    };
+    let disconnected = warp::path!("v2" / "ask" / "why-disconnected" / String)
+        .and(warp::query::query())
+        .and_then(move |peer: String, filter: NodeFilter| {
+            let dbs = dbs.clone();
+            blocking(move || -> Response {
+                ask_reply(&dbs, filter, |ask| ask.why_disconnected(&Peer::parse(&peer)))
+            })
+        });
+    last_head.or(advertising).unify().or(disconnected).unify()
+}
+
+fn incidents<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v2" / "incidents")
+        .and(warp::query::query())
+        .and_then(move |filter: IncidentsFilter| {
+            let dbs = dbs.clone();
+            blocking(move || -> reply::WithStatus<Json> {
+                let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+                match dbs.get(&node_name) {
+                    Some(db) => match db.fetch_incidents(&filter) {
+                        Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                        Err(err) => {
+                            let r = &format!("database error: {}", err);
+                            reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                        },
+                    },
+                    None => {
+                        let r = &format!("no such node: {:?}", node_name);
+                        reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                    },
+                }
+            })
+        })
+}
+
+fn gaps<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v2" / "gaps")
+        .and(warp::query::query())
+        .and_then(move |filter: GapsFilter| {
+            let dbs = dbs.clone();
+            blocking(move || -> reply::WithStatus<Json> {
+                let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+                match dbs.get(&node_name) {
+                    Some(db) => match db.fetch_gaps(&filter) {
+                        Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                        Err(err) => {
+                            let r = &format!("database error: {}", err);
+                            reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                        },
+                    },
+                    None => {
+                        let r = &format!("no such node: {:?}", node_name);
+                        reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                    },
+                }
+            })
+        })
+}
+
+/// The time series along with the intervals when the capture missed the traffic,
+/// so the missing traffic is not taken for the silence of the node
+#[derive(Serialize)]
+struct WithGaps<T> {
+    data: T,
+    gaps: Vec<gap::ItemWithId>,
+}
+
+fn with_gaps<Db, T>(
+    db: &Db,
+    data: T,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Result<WithGaps<T>, Db::Error>
+where
+    Db: DatabaseFetch,
+{
+    let filter = GapsFilter {
+        from,
+        to,
+        node_name: None,
+    };
+    let gaps = db.fetch_gaps(&filter)?;
+    Ok(WithGaps { data, gaps })
+}
+
+fn conformance<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v2" / "conformance")
+        .and(warp::query::query())
+        .and_then(move |filter: FindingsFilter| {
+            let dbs = dbs.clone();
+            blocking(move || -> reply::WithStatus<Json> {
+                let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+                match dbs.get(&node_name) {
+                    Some(db) => match db.fetch_findings(&filter) {
+                        Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                        Err(err) => {
+                            let r = &format!("database error: {}", err);
+                            reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                        },
+                    },
+                    None => {
+                        let r = &format!("no such node: {:?}", node_name);
+                        reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                    },
+                }
+            })
+        })
+}
+
+fn peer<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    use std::convert::TryFrom;
+
+    warp::path!("v2" / "peers" / String)
+        .and(warp::query::query())
+        .and(encoding())
+        .and_then(
+            move |pk: String, filter: PeerFilter, encoding: Encoding| {
+                let dbs = dbs.clone();
+                blocking(move || -> Response {
+                    let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+                    let db = match dbs.get(&node_name) {
+                        Some(db) => db,
+                        None => {
+                            let r = &format!("no such node: {:?}", node_name);
+                            return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                                .into_response();
+                        },
+                    };
+                    let pk = match hex::decode(&pk)
+                        .ok()
+                        .and_then(|v| <[u8; 32]>::try_from(v).ok())
+                    {
+                        Some(pk) => pk,
+                        None => {
+                            let r = &format!("bad public key: {:?}, expected 32 bytes hex", pk);
+                            return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST)
+                                .into_response();
+                        },
+                    };
+                    match db.fetch_peer(&pk) {
+                        Ok(Some(peer)) => encoded_reply(&peer, encoding),
+                        Ok(None) => {
+                            let r = &format!("no such peer: {}", hex::encode(pk));
+                            reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                                .into_response()
+                        },
+                        Err(err) => {
+                            let r = &format!("database error: {}", err);
+                            reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                                .into_response()
+                        },
+                    }
+                })
+            },
+        )
+}
+
+fn suspicious_peers(
+    scores: HashMap<String, Arc<PeerScores>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+{
+    warp::path!("v2" / "peers" / "suspicious")
+        .and(warp::query::query())
+        .map(move |filter: NodeFilter| -> reply::WithStatus<Json> {
+            let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+            match scores.get(&node_name) {
+                Some(scores) => {
+                    reply::with_status(reply::json(&scores.suspicious()), StatusCode::OK)
+                },
+                None => {
+                    let r = &format!("no such node or its peers are not scored: {:?}", node_name);
+                    reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                },
+            }
+        })
+}
+
+fn decoder_stats(
+    stats: HashMap<String, Arc<DecoderStats>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+{
+    warp::path!("v2" / "stats" / "decoder")
+        .and(warp::query::query())
+        .map(move |filter: NodeFilter| -> reply::WithStatus<Json> {
+            let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+            match stats.get(&node_name) {
+                Some(stats) => reply::with_status(reply::json(&stats.report()), StatusCode::OK),
+                None => {
+                    let r = &format!("no such node: {:?}", node_name);
+                    reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                },
+            }
+        })
+}
+
+fn sampling_stats(
+    samplers: HashMap<String, Arc<Sampler>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+{
+    warp::path!("v2" / "stats" / "sampling")
+        .and(warp::query::query())
+        .map(move |filter: NodeFilter| -> reply::WithStatus<Json> {
+            let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+            match samplers.get(&node_name) {
+                Some(sampler) => {
+                    reply::with_status(reply::json(&sampler.report()), StatusCode::OK)
+                },
+                None => {
+                    let r = &format!("no such node or it does not sample: {:?}", node_name);
+                    reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                },
+            }
+        })
+}
+
+/// The resources the recorder itself takes, measured periodically
+fn self_stats(
+    monitor: Arc<SelfMonitor>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+{
+    warp::path!("v2" / "self").map(move || -> reply::WithStatus<Json> {
+        reply::with_status(reply::json(&monitor.report()), StatusCode::OK)
+    })
+}
+
+fn parse_peer_ip(addr: &str) -> Option<std::net::IpAddr> {
+    use std::net::SocketAddr;
+
+    addr.parse()
+        .ok()
+        .or_else(|| addr.parse::<SocketAddr>().ok().map(|a| a.ip()))
+}
+
+// the port 0 is any port of the peer
+fn parse_ignored_peer(addr: &str) -> Option<std::net::SocketAddr> {
+    use std::net::{IpAddr, SocketAddr};
+
+    addr.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, 0))
+        .or_else(|_| addr.parse())
+        .ok()
+}
+
+/// Changes the firewall of the host, requires the `admin_token`
+fn peer_block(
+    control: Arc<Control>,
+    config: Arc<SharedConfig>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+{
+    warp::path!("v2" / "peers" / String / "block")
+        .and(warp::post().map(|| true).or(warp::delete().map(|| false)).unify())
+        .and(warp::header::optional::<String>("authorization"))
+        .map(move |addr: String, block: bool, auth: Option<String>| -> WithStatus<Json> {
+            if let Err(r) = authorize(&config, auth) {
+                return r;
+            }
+            let ip = match parse_peer_ip(&addr) {
+                Some(ip) => ip,
+                None => {
+                    let r = &format!("bad address: {:?}, expected ip or ip:port", addr);
+                    return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST);
+                },
+            };
+            let result = if block {
+                control.block(ip)
+            } else {
+                control.unblock(ip)
+            };
+            match result {
+                Ok(()) => reply::with_status(reply::json(&control.blocked()), StatusCode::OK),
+                Err(err @ FirewallError::Refused(..)) => {
+                    let r = &err.to_string();
+                    reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST)
+                },
+                Err(err) => {
+                    let r = &format!("firewall error: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                },
+            }
+        })
+}
+
+fn capture(
+    control: Arc<Control>,
+    config: Arc<SharedConfig>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+{
+    let state = {
+        let control = control.clone();
+        warp::path!("v2" / "control" / "capture")
+            .and(warp::get())
+            .map(move || -> reply::WithStatus<Json> {
+                reply::with_status(reply::json(&control.capture_state()), StatusCode::OK)
+            })
+    };
+    let switch = warp::path!("v2" / "control" / "capture" / String)
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .map(move |action: String, auth: Option<String>| -> WithStatus<Json> {
+            if let Err(r) = authorize(&config, auth) {
+                return r;
+            }
+            match action.as_str() {
+                "pause" => control.set_paused(true),
+                "resume" => control.set_paused(false),
+                _ => {
+                    let r = &format!("bad action: {:?}, expected pause or resume", action);
+                    return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST);
+                },
+            }
+            reply::with_status(reply::json(&control.capture_state()), StatusCode::OK)
+        });
+    state.or(switch).unify()
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct IgnoredFilter {
+    /// the connection by the process and its descriptor, both are needed
+    pid: Option<u32>,
+    fd: Option<u32>,
+    /// the peer, `ip` for any port or `ip:port`
+    address: Option<String>,
+}
+
+/// The connections and the peers excluded from the capture, `POST` adds, `DELETE` removes
+fn capture_ignored(
+    control: Arc<Control>,
+    config: Arc<SharedConfig>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+{
+    let list = {
+        let control = control.clone();
+        warp::path!("v2" / "capture" / "ignored")
+            .and(warp::get())
+            .map(move || -> reply::WithStatus<Json> {
+                reply::with_status(reply::json(&control.ignored()), StatusCode::OK)
+            })
+    };
+    let change = warp::path!("v2" / "capture" / "ignored")
+        .and(warp::post().map(|| true).or(warp::delete().map(|| false)).unify())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::query())
+        .map(move |ignore: bool, auth: Option<String>, filter: IgnoredFilter| -> WithStatus<Json> {
+            if let Err(r) = authorize(&config, auth) {
+                return r;
+            }
+            match filter {
+                IgnoredFilter {
+                    pid: Some(pid),
+                    fd: Some(fd),
+                    address: None,
+                } => {
+                    if ignore {
+                        control.ignore_connection(pid, fd)
+                    } else {
+                        control.unignore_connection(pid, fd)
+                    }
+                },
+                IgnoredFilter {
+                    pid: None,
+                    fd: None,
+                    address: Some(address),
+                } => {
+                    let addr = match parse_ignored_peer(&address) {
+                        Some(addr) => addr,
+                        None => {
+                            let r = &format!("bad address: {:?}, expected ip or ip:port", address);
+                            return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST);
+                        },
+                    };
+                    if ignore {
+                        control.ignore_peer(addr)
+                    } else {
+                        control.unignore_peer(addr)
+                    }
+                },
+                _ => {
+                    let r = "expected either `pid` and `fd`, or `address`";
+                    return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST);
+                },
+            }
+            reply::with_status(reply::json(&control.ignored()), StatusCode::OK)
+        });
+    list.or(change).unify()
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct PayloadFilter {
+    /// the bytes of each read and write captured after the handshake, 0 is the full payload
+    limit: u32,
+}
+
+/// The overrides of the `payload_limit` by the port, `POST` sets, `DELETE` goes back to the config
+fn payload(
+    control: Arc<Control>,
+    config: Arc<SharedConfig>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+{
+    let list = {
+        let control = control.clone();
+        warp::path!("v2" / "control" / "payload")
+            .and(warp::get())
+            .map(move || -> reply::WithStatus<Json> {
+                reply::with_status(reply::json(&control.payload_limits()), StatusCode::OK)
+            })
+    };
+    let set = {
+        let control = control.clone();
+        let config = config.clone();
+        warp::path!("v2" / "control" / "payload" / u16)
+            .and(warp::post())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::query::query())
+            .map(move |port: u16, auth: Option<String>, filter: PayloadFilter| -> WithStatus<Json> {
+                if let Err(r) = authorize(&config, auth) {
+                    return r;
+                }
+                control.limit_payload(port, filter.limit);
+                reply::with_status(reply::json(&control.payload_limits()), StatusCode::OK)
+            })
+    };
+    let reset = warp::path!("v2" / "control" / "payload" / u16)
+        .and(warp::delete())
+        .and(warp::header::optional::<String>("authorization"))
+        .map(move |port: u16, auth: Option<String>| -> WithStatus<Json> {
+            if let Err(r) = authorize(&config, auth) {
+                return r;
+            }
+            control.reset_payload(port);
+            reply::with_status(reply::json(&control.payload_limits()), StatusCode::OK)
+        });
+    list.or(set).unify().or(reset).unify()
+}
+
+/// The identity is read again, for the node which regenerated it, requires the `admin_token`
+fn identity_reload(
+    control: Arc<Control>,
+    config: Arc<SharedConfig>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+{
+    warp::path!("v2" / "control" / "identity")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::query())
+        .and_then(move |auth: Option<String>, filter: NodeFilter| {
+            let control = control.clone();
+            let config = config.clone();
+            blocking(move || -> reply::WithStatus<Json> {
+                if let Err(r) = authorize(&config, auth) {
+                    return r;
+                }
+                let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+                // read it here, so the error is reported to the caller
+                match config.identity(&node_name) {
+                    Ok(_) => {
+                        control.reload_identity(node_name);
+                        reply::with_status(reply::json(&()), StatusCode::OK)
+                    },
+                    Err(err) => {
+                        let r = &err.to_string();
+                        reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST)
+                    },
+                }
+            })
+        })
+}
+
+fn probes(
+    health: Arc<Health>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+{
+    let healthz = {
+        let health = health.clone();
+        warp::path!("healthz").map(move || -> reply::WithStatus<Json> {
+            let report = health.report();
+            let status = if report.healthy {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            reply::with_status(reply::json(&report), status)
+        })
+    };
+    let readyz = warp::path!("readyz").map(move || -> reply::WithStatus<Json> {
+        let report = health.report();
+        let status = if report.ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        reply::with_status(reply::json(&report), status)
+    });
+    healthz.or(readyz).unify()
+}
+
+/// The stages of the capture, to tell which one stalled when the messages stop appearing
+fn pipeline(
+    pipeline: Arc<Pipeline>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+{
+    warp::path!("v2" / "pipeline").map(move || -> reply::WithStatus<Json> {
+        reply::with_status(reply::json(&pipeline.report()), StatusCode::OK)
+    })
+}
+
+/// The bytes the kernel saw against the bytes the recorder processed, by the name of the node
+fn coverage(
+    coverage: Arc<Coverage>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+{
+    warp::path!("v2" / "capture" / "coverage").map(move || -> reply::WithStatus<Json> {
+        reply::with_status(reply::json(&coverage.report()), StatusCode::OK)
+    })
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct NodeFilter {
+    node_name: Option<String>,
+}
+
+fn config<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+    config: Arc<SharedConfig>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: Database + Sync + Send + 'static,
+{
+    let get = {
+        let config = config.clone();
+        warp::path!("v2" / "config")
+            .and(warp::get())
+            .map(move || -> reply::WithStatus<Json> {
+                reply::with_status(reply::json(&config.snapshot()), StatusCode::OK)
+            })
+    };
+    // shrinking the limits removes the recorded data
+    let put = warp::path!("v2" / "config")
+        .and(warp::put())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::query())
+        .and(warp::body::json())
+        .and_then(
+            move |auth: Option<String>, filter: NodeFilter, overrides: NodeOverrides| {
+                let dbs = dbs.clone();
+                let config = config.clone();
+                blocking(move || -> reply::WithStatus<Json> {
+                    if let Err(r) = authorize(&config, auth) {
+                        return r;
+                    }
+                    let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+                    let db = match dbs.get(&node_name) {
+                        Some(db) => db,
+                        None => {
+                            let r = &format!("no such node: {:?}", node_name);
+                            return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND);
+                        },
+                    };
+                    match config.update(&node_name, db.as_ref(), overrides) {
+                        Ok(()) => {
+                            reply::with_status(reply::json(&config.snapshot()), StatusCode::OK)
+                        },
+                        Err(err) => {
+                            let r = &err.to_string();
+                            reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST)
+                        },
+                    }
+                })
+            },
+        );
+    get.or(put).unify()
+}
+
+fn annotations<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    let get = {
+        let dbs = dbs.clone();
+        warp::path!("v2" / "annotations")
+            .and(warp::get())
+            .and(warp::query::query())
+            .and_then(move |filter: AnnotationsFilter| {
+                let dbs = dbs.clone();
+                blocking(move || -> reply::WithStatus<Json> {
+                    let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+                    match dbs.get(&node_name) {
+                        Some(db) => match db.fetch_annotations(&filter) {
+                            Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                            Err(err) => {
+                                let r = &format!("database error: {}", err);
+                                reply::with_status(
+                                    reply::json(&r),
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                )
+                            },
+                        },
+                        None => {
+                            let r = &format!("no such node: {:?}", node_name);
+                            reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                        },
+                    }
+                })
+            })
+    };
+    let post = warp::path!("v2" / "annotations")
+        .and(warp::post())
+        .and(warp::query::query())
+        .and(warp::body::json())
+        .and_then(
+            move |filter: NodeFilter, item: annotation::Item| {
+                let dbs = dbs.clone();
+                blocking(move || -> reply::WithStatus<Json> {
+                    let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+                    let db = match dbs.get(&node_name) {
+                        Some(db) => db,
+                        None => {
+                            let r = &format!("no such node: {:?}", node_name);
+                            return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND);
+                        },
+                    };
+                    if let Err(err) = item.validate() {
+                        return reply::with_status(reply::json(&err), StatusCode::BAD_REQUEST);
+                    }
+                    let message_id = item.message_id;
+                    match db.store_annotation(item) {
+                        Ok(Some(id)) => reply::with_status(reply::json(&id), StatusCode::CREATED),
+                        Ok(None) => {
+                            let r = &format!("no such message: {:?}", message_id);
+                            reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                        },
+                        Err(err) => {
+                            let r = &format!("database error: {}", err);
+                            reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                        },
+                    }
+                })
+            },
+        );
+    get.or(post).unify()
+}
+
+fn filters<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    let db_of = {
+        let dbs = dbs.clone();
+        move |filter: NodeFilter| -> Result<Arc<Db>, WithStatus<Json>> {
+            let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+            dbs.get(&node_name).cloned().ok_or_else(|| {
+                let r = &format!("no such node: {:?}", node_name);
+                reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+            })
+        }
+    };
+    let database_error = |err: <Db as DatabaseFetch>::Error| {
+        let r = &format!("database error: {}", err);
+        reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+    };
+
+    let list = {
+        let db_of = db_of.clone();
+        warp::path!("v2" / "filters")
+            .and(warp::get())
+            .and(warp::query::query())
+            .and_then(move |filter: NodeFilter| {
+                let db_of = db_of.clone();
+                blocking(move || -> reply::WithStatus<Json> {
+                    let db = match db_of(filter) {
+                        Ok(db) => db,
+                        Err(r) => return r,
+                    };
+                    match db.fetch_presets() {
+                        Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                        Err(err) => database_error(err),
+                    }
+                })
+            })
+    };
+    let get = {
+        let db_of = db_of.clone();
+        warp::path!("v2" / "filters" / String)
+            .and(warp::get())
+            .and(warp::query::query())
+            .and_then(move |name: String, filter: NodeFilter| {
+                let db_of = db_of.clone();
+                blocking(move || -> reply::WithStatus<Json> {
+                    let db = match db_of(filter) {
+                        Ok(db) => db,
+                        Err(r) => return r,
+                    };
+                    match db.fetch_preset(&name) {
+                        Ok(Some(item)) => {
+                            let item = preset::ItemWithName { name, item };
+                            reply::with_status(reply::json(&item), StatusCode::OK)
+                        },
+                        Ok(None) => {
+                            let r = &format!("no such preset: {:?}", name);
+                            reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                        },
+                        Err(err) => database_error(err),
+                    }
+                })
+            })
+    };
+    let put = {
+        let db_of = db_of.clone();
+        warp::path!("v2" / "filters" / String)
+            .and(warp::put())
+            .and(warp::query::query())
+            .and(warp::body::json())
+            .and_then(move |name: String, filter: NodeFilter, item: preset::Item| {
+                let db_of = db_of.clone();
+                blocking(move || -> reply::WithStatus<Json> {
+                    let db = match db_of(filter) {
+                        Ok(db) => db,
+                        Err(r) => return r,
+                    };
+                    let valid = preset::Item::validate_name(&name).and_then(|()| item.validate());
+                    if let Err(err) = valid {
+                        return reply::with_status(reply::json(&err), StatusCode::BAD_REQUEST);
+                    }
+                    match db.store_preset(&name, item) {
+                        Ok(true) => reply::with_status(reply::json(&name), StatusCode::OK),
+                        Ok(false) => reply::with_status(reply::json(&name), StatusCode::CREATED),
+                        Err(err) => database_error(err),
+                    }
+                })
+            })
+    };
+    let delete = warp::path!("v2" / "filters" / String)
+        .and(warp::delete())
+        .and(warp::query::query())
+        .and_then(move |name: String, filter: NodeFilter| {
+            let db_of = db_of.clone();
+            blocking(move || -> reply::WithStatus<Json> {
+                let db = match db_of(filter) {
+                    Ok(db) => db,
+                    Err(r) => return r,
+                };
+                match db.remove_preset(&name) {
+                    Ok(true) => reply::with_status(reply::json(&name), StatusCode::OK),
+                    Ok(false) => {
+                        let r = &format!("no such preset: {:?}", name);
+                        reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                    },
+                    Err(err) => database_error(err),
+                }
+            })
+        });
+    list.or(get).unify().or(put).unify().or(delete).unify()
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ExportFilter {
+    /// Replace the addresses, the public keys and the peer ids by pseudonyms,
+    /// consistent within the export, and drop the raw bytes
+    anonymize: Option<bool>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct SessionStart {
+    name: String,
+    node_version: Option<String>,
+}
+
+fn sessions<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+    config: Arc<SharedConfig>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    use crypto::blake2b;
+
+    #[derive(Clone, Copy)]
+    enum Action {
+        List,
+        Stop,
+        Export(u64, bool),
+        Remove(u64),
+    }
+
+    let start = {
+        let dbs = dbs.clone();
+        let config = config.clone();
+        warp::path!("v2" / "sessions" / "start")
+            .and(warp::post())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::query::query())
+            .and(warp::body::json())
+            .and_then(
+                move |auth: Option<String>, filter: NodeFilter, start: SessionStart| {
+                    let dbs = dbs.clone();
+                    let config = config.clone();
+                    blocking(move || -> reply::WithStatus<Json> {
+                        if let Err(r) = authorize(&config, auth) {
+                            return r;
+                        }
+                        let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+                        let db = match dbs.get(&node_name) {
+                            Some(db) => db,
+                            None => {
+                                let r = &format!("no such node: {:?}", node_name);
+                                return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND);
+                            },
+                        };
+                        let config = serde_json::to_vec(&config.snapshot()).unwrap_or_default();
+                        let info = session::Info {
+                            name: start.name,
+                            node_version: start.node_version,
+                            debugger_version: env!("GIT_HASH").to_string(),
+                            config_hash: blake2b::digest_256(&config)
+                                .map(hex::encode)
+                                .unwrap_or_default(),
+                        };
+                        match db.start_session(info) {
+                            Ok(Some(id)) => {
+                                reply::with_status(reply::json(&id), StatusCode::CREATED)
+                            },
+                            Ok(None) => {
+                                let r = &"some session is running, stop it first";
+                                reply::with_status(reply::json(&r), StatusCode::CONFLICT)
+                            },
+                            Err(err) => {
+                                let r = &format!("database error: {}", err);
+                                reply::with_status(
+                                    reply::json(&r),
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                )
+                            },
+                        }
+                    })
+                },
+            )
+    };
+    let keys = {
+        let dbs = dbs.clone();
+        let config = config.clone();
+        warp::path!("v2" / "sessions" / u64 / "keys")
+            .and(warp::get())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::query::query())
+            .and_then(
+                move |id: u64, auth: Option<String>, filter: NodeFilter| {
+                    let dbs = dbs.clone();
+                    let config = config.clone();
+                    blocking(move || -> WithStatus<Json> {
+                        if let Err(r) = authorize(&config, auth) {
+                            return r;
+                        }
+                        let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+                        let db = match dbs.get(&node_name) {
+                            Some(db) => db,
+                            None => {
+                                let r = &format!("no such node: {:?}", node_name);
+                                return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND);
+                            },
+                        };
+                        let identity = match config.identity(&node_name) {
+                            Ok(identity) => identity,
+                            Err(err) => {
+                                let r = &err.to_string();
+                                return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND);
+                            },
+                        };
+                        match db.fetch_session_handshakes(id) {
+                            Ok(Some(handshakes)) => {
+                                let keys = handshakes
+                                    .iter()
+                                    .filter_map(|handshake| {
+                                        escrow::derive(&identity, handshake)
+                                            .map_err(|err| {
+                                                let cn = &handshake.cn_id;
+                                                log::warn!("no keys for connection {}: {}", cn, err)
+                                            })
+                                            .ok()
+                                    })
+                                    .collect::<Vec<_>>();
+                                reply::with_status(reply::json(&keys), StatusCode::OK)
+                            },
+                            Ok(None) => {
+                                let r = &format!("no such session: {}", id);
+                                reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                            },
+                            Err(err) => {
+                                let r = &format!("database error: {}", err);
+                                reply::with_status(
+                                    reply::json(&r),
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                )
+                            },
+                        }
+                    })
+                },
+            )
+    };
+    let action = warp::path!("v2" / "sessions")
+        .and(warp::get())
+        .map(|| Action::List)
+        .or(warp::path!("v2" / "sessions" / "stop")
+            .and(warp::post())
+            .map(|| Action::Stop))
+        .unify()
+        .or(warp::path!("v2" / "sessions" / u64 / "export")
+            .and(warp::get())
+            .and(warp::query::query())
+            .map(|id, filter: ExportFilter| {
+                Action::Export(id, filter.anonymize.unwrap_or(false))
+            }))
+        .unify()
+        .or(warp::path!("v2" / "sessions" / u64)
+            .and(warp::delete())
+            .map(Action::Remove))
+        .unify()
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::query())
+        .and_then(move |action: Action, auth: Option<String>, filter: NodeFilter| {
+            let dbs = dbs.clone();
+            let config = config.clone();
+            blocking(move || -> reply::WithStatus<Json> {
+                // only reading needs no token
+                if matches!(action, Action::Stop | Action::Remove(_)) {
+                    if let Err(r) = authorize(&config, auth) {
+                        return r;
+                    }
+                }
+                let node_name = filter.node_name.unwrap_or("tezedge".to_string());
+                let db = match dbs.get(&node_name) {
+                    Some(db) => db,
+                    None => {
+                        let r = &format!("no such node: {:?}", node_name);
+                        return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND);
+                    },
+                };
+                let no_such = |what: String| {
+                    let r = &format!("no such {}", what);
+                    reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                };
+                let r = match action {
+                    Action::List => db
+                        .fetch_sessions()
+                        .map(|v| reply::with_status(reply::json(&v), StatusCode::OK)),
+                    Action::Stop => db.stop_session().map(|id| match id {
+                        Some(id) => reply::with_status(reply::json(&id), StatusCode::OK),
+                        None => no_such("running session".to_string()),
+                    }),
+                    Action::Export(id, anonymize) => db.fetch_session_export(id).map(|v| match v {
+                        Some(v) if anonymize => {
+                            let v = Anonymizer::new().anonymize(&v);
+                            reply::with_status(reply::json(&v), StatusCode::OK)
+                        },
+                        Some(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                        None => no_such(format!("session: {}", id)),
+                    }),
+                    Action::Remove(id) => db.remove_session(id).map(|removed| {
+                        if removed {
+                            reply::with_status(reply::json(&id), StatusCode::OK)
+                        } else {
+                            no_such(format!("session: {}", id))
+                        }
+                    }),
+                };
+                r.unwrap_or_else(|err| {
+                    let r = &format!("database error: {}", err);
+                    reply::with_status(reply::json(&r), StatusCode::INTERNAL_SERVER_ERROR)
+                })
+            })
+        });
+    start.or(keys).unify().or(action).unify()
+}
+
+/// The `Authorization: Bearer <admin_token>` header,
+/// forbidden if the config has no admin token at all
+fn authorize(config: &SharedConfig, header: Option<String>) -> Result<(), WithStatus<Json>> {
+    let token = match config.admin_token() {
+        Some(token) => token,
+        None => {
+            let r = &"no admin_token in the config";
+            return Err(reply::with_status(reply::json(&r), StatusCode::FORBIDDEN));
+        },
+    };
+    let given = header.as_deref().and_then(|h| h.strip_prefix("Bearer "));
+    // the comparison takes the same time wherever the first difference is
+    let matches = given.map_or(false, |given| {
+        given.len() == token.len()
+            && given.bytes().zip(token.bytes()).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+    });
+    if matches {
+        Ok(())
+    } else {
+        let r = &"bad admin token";
+        Err(reply::with_status(reply::json(&r), StatusCode::UNAUTHORIZED))
+    }
+}
+
+/// The query is in the body of the POST request or in the `query` argument of the GET request
+fn graphql<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+    limiter: Arc<Limiter>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    use async_graphql_warp::Response as GraphQLResponse;
+
+    warp::path!("v2" / "graphql")
+        .and(limiter.query())
+        .and(async_graphql_warp::graphql(graphql::schema(dbs)))
+        .and_then(
+            |_permit: QueryPermit, (schema, request): (graphql::RecorderSchema, _)| async move {
+                let response = GraphQLResponse::from(schema.execute(request).await);
+                Ok::<_, Infallible>(response.into_response())
+            },
+        )
+}
+
+/// The json datasource of Grafana, the node is in the path, so the url of the datasource
+/// is `/grafana/{node name}`, the datasource tests it by `GET`
+fn grafana<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+    limiter: Arc<Limiter>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    fn no_such_node(node_name: &str) -> reply::WithStatus<Json> {
+        let r = &format!("no such node: {:?}", node_name);
+        reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+    }
+
+    fn bad_request(error: String) -> reply::WithStatus<Json> {
+        reply::with_status(reply::json(&error), StatusCode::BAD_REQUEST)
+    }
+
+    let test = {
+        let dbs = dbs.clone();
+        warp::path!("grafana" / String)
+            .and(warp::get())
+            .map(move |node_name: String| -> reply::WithStatus<Json> {
+                if dbs.contains_key(&node_name) {
+                    reply::with_status(reply::json(&"ok"), StatusCode::OK)
+                } else {
+                    no_such_node(&node_name)
+                }
+            })
+    };
+    let search = warp::path!("grafana" / String / "search")
+        .and(warp::post())
+        .map(|_node_name: String| -> reply::WithStatus<Json> {
+            reply::with_status(reply::json(&grafana::TARGETS), StatusCode::OK)
+        });
+    let query = {
+        let dbs = dbs.clone();
+        warp::path!("grafana" / String / "query")
+            .and(warp::post())
+            .and(limiter.query())
+            .and(warp::body::json())
+            .and_then(
+                move |node_name: String, permit: QueryPermit, request: grafana::QueryRequest| {
+                    let dbs = dbs.clone();
+                    blocking(move || -> reply::WithStatus<Json> {
+                        let _permit = permit;
+                        match dbs.get(&node_name) {
+                            Some(db) => match grafana::query(db.as_ref(), request) {
+                                Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                                Err(error) => bad_request(error),
+                            },
+                            None => no_such_node(&node_name),
+                        }
+                    })
+                },
+            )
+    };
+    let annotations = warp::path!("grafana" / String / "annotations")
+        .and(warp::post())
+        .and(limiter.query())
+        .and(warp::body::json())
+        .and_then(
+            move |node_name: String, permit: QueryPermit, request: grafana::AnnotationsRequest| {
+                let dbs = dbs.clone();
+                blocking(move || -> reply::WithStatus<Json> {
+                    let _permit = permit;
+                    match dbs.get(&node_name) {
+                        Some(db) => match grafana::annotations(db.as_ref(), request) {
+                            Ok(v) => reply::with_status(reply::json(&v), StatusCode::OK),
+                            Err(error) => bad_request(error),
+                        },
+                        None => no_such_node(&node_name),
+                    }
+                })
+            },
+        );
+    test.or(search)
+        .unify()
+        .or(query)
+        .unify()
+        .or(annotations)
+        .unify()
+}
+
+// how many records is fetched from the database at once while following the logs
+const LOG_TAIL_BATCH: u64 = 100;
+
+/// Polls the database for the records after the `cursor` which match the filter
+fn log_tail_stream<Db>(
+    db: Arc<Db>,
+    filter: LogsFilter,
+    cursor: u64,
+) -> impl Stream<Item = Result<sse::Event, Infallible>> + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    let interval = tokio::time::interval(Duration::from_millis(500));
+    let state = (db, filter, cursor, interval, VecDeque::new());
+    futures::stream::unfold(state, |state| async move {
+        let (db, filter, mut cursor, mut interval, mut pending) = state;
+        while pending.is_empty() {
+            interval.tick().await;
+            let f = LogsFilter {
+                direction: Some("forward".to_string()),
+                cursor: Some(cursor),
+                limit: Some(LOG_TAIL_BATCH),
+                ..filter.clone()
+            };
+            let fetched = {
+                let db = db.clone();
+                database::blocking(move || db.fetch_log(&f)).await
+            };
+            match fetched {
+                Ok(Ok(items)) => pending.extend(items),
+                Ok(Err(err)) => log::error!("database error: {}", err),
+                Err(err) => log::error!("the query panicked: {}", err),
+            }
+            if let Some(last) = pending.back() {
+                cursor = last.id + 1;
+            }
+        }
+        let item = pending.pop_front()?;
+        let event = sse::Event::default()
+            .id(item.id.to_string())
+            .json_data(&item)
+            .unwrap_or_else(|err| sse::Event::default().comment(err.to_string()));
+        Some((Ok(event), (db, filter, cursor, interval, pending)))
+    })
+}
+
+fn log_tail<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: DatabaseFetch + Sync + Send + 'static,
+{
+    warp::path!("v2" / "log" / "tail")
+        .and(warp::query::query())
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .and_then(
+            move |mut filter: LogsFilter, last_event_id: Option<u64>| {
+                let dbs = dbs.clone();
+                blocking(move || -> Response {
+                    let node_name = filter.node_name.clone().unwrap_or("tezedge".to_string());
+                    let db = match dbs.get(&node_name) {
+                        Some(db) => db.clone(),
+                        None => {
+                            let r = &format!("no such node: {:?}", node_name);
+                            return reply::with_status(reply::json(&r), StatusCode::NOT_FOUND)
+                                .into_response();
+                        },
+                    };
+                    let preset = filter.preset.clone();
+                    if let Err(r) = with_preset(&*db, preset, |p| filter.apply(p)) {
+                        return r.into_response();
+                    }
+                    if filter.query.is_some() {
+                        let r = &"full text search cannot be followed";
+                        return reply::with_status(reply::json(&r), StatusCode::BAD_REQUEST)
+                            .into_response();
+                    }
+                    // resume after the last received record, or start from the newest one
+                    let cursor = match last_event_id {
+                        Some(id) => id + 1,
+                        None => {
+                            let newest = LogsFilter {
+                                limit: Some(1),
+                                ..LogsFilter::default()
+                            };
+                            match db.fetch_log(&newest) {
+                                Ok(v) => v.first().map(|item| item.id + 1).unwrap_or(0),
+                                Err(err) => {
+                                    let r = &format!("database error: {}", err);
+                                    return reply::with_status(
+                                        reply::json(&r),
+                                        StatusCode::INTERNAL_SERVER_ERROR,
+                                    )
+                                    .into_response();
+                                },
+                            }
+                        },
+                    };
+                    let stream = log_tail_stream(db, filter, cursor);
+                    sse::reply(sse::keep_alive().stream(stream)).into_response()
+                })
+            },
+        )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn routes_old<Db>(
+    dbs: HashMap<String, Arc<Db>>,
+    limiter: Arc<Limiter>,
+    control: Arc<Control>,
+    health: Arc<Health>,
+    stages: Arc<Pipeline>,
+    capture_coverage: Arc<Coverage>,
+    peer_scores: HashMap<String, Arc<PeerScores>>,
+    decoder: HashMap<String, Arc<DecoderStats>>,
+    samplers: HashMap<String, Arc<Sampler>>,
+    self_monitor: Arc<SelfMonitor>,
+    shared_config: Arc<SharedConfig>,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Sync + Send + 'static
+where
+    Db: Database + DatabaseFetch + Sync + Send + 'static,
+{
+    use warp::reply::with;
+
+    let json = p2p(dbs.clone(), limiter.clone())
+        .or(p2p_details(dbs.clone()))
+        .or(p2p_body(dbs.clone()))
+        .or(log_old(dbs.clone(), limiter.clone()))
+        .or(log_counts(dbs.clone(), limiter.clone()))
+        // before the peer by the key, which would take `suspicious` for a key
+        .or(suspicious_peers(peer_scores))
+        .or(peer(dbs.clone()))
+        .or(storage_stats(dbs.clone()))
+        .or(incidents(dbs.clone()))
+        .or(gaps(dbs.clone()))
+        .or(conformance(dbs.clone()))
+        .or(bandwidth(dbs.clone()))
+        .or(graph(dbs.clone()))
+        .or(ask(dbs.clone()))
+        .or(scenario(dbs.clone()))
+        .or(decoder_stats(decoder))
+        .or(sampling_stats(samplers))
+        .or(self_stats(self_monitor))
+        .or(connection_chunks(dbs.clone(), limiter.clone()))
+        .or(probes(health))
+        .or(pipeline(stages))
+        .or(coverage(capture_coverage))
+        .or(version())
+        .or(openapi())
+        .with(with::default_header("Content-Type", "application/json"));
+
+    let graphql = graphql(dbs.clone(), limiter.clone());
+    let grafana = grafana(dbs.clone(), limiter.clone());
+
+    let control = peer_block(control.clone(), shared_config.clone())
+        .or(identity_reload(control.clone(), shared_config.clone()))
+        .or(capture(control.clone(), shared_config.clone()))
+        .or(capture_ignored(control.clone(), shared_config.clone()))
+        .or(payload(control, shared_config.clone()))
+        .or(config(dbs.clone(), shared_config.clone()))
+        .or(annotations(dbs.clone()))
+        .or(filters(dbs.clone()))
+        .or(sessions(dbs.clone(), shared_config))
+        .with(with::header("Content-Type", "application/json"));
+
+    limiter
+        .rate()
+        .and(warp::get().and(json.or(log_tail(dbs))).or(control).or(graphql).or(grafana))
+        .recover(recover)
         .with(with::header("Access-Control-Allow-Origin", "*"))
 }
@@ -2,49 +2,449 @@
 // SPDX-License-Identifier: MIT
 
 use std::{
-    collections::HashMap,
-    sync::{Arc, atomic::AtomicBool},
-    net::SocketAddr,
-    io, thread,
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    sync::{Arc, Mutex, atomic::AtomicBool},
+    net::{SocketAddr, IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    io, thread, fmt,
 };
-use serde::Deserialize;
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
 use anyhow::Result;
 use thiserror::Error;
 use tokio::{runtime::Runtime, task::JoinHandle};
 use super::{
-    database::{DatabaseNew, DatabaseFetch, Database},
+    database::{
+        self, DatabaseNew, DatabaseFetch, Database, tuning::RocksdbConfig,
+        remote::{self, replication},
+    },
+    limiter::{Limiter, LimiterConfig},
+    control::Control,
+    health::{Health, Status},
+    pipeline::Pipeline,
+    coverage::Coverage,
+    self_monitor::{self, SelfMonitor},
+    conformance,
+    gaps,
+    scoring::{ScoringConfig, PeerScores},
+    decoder_stats::DecoderStats,
+    sampling::Sampler,
+    flood::FloodConfig,
+    tcp_meta::TcpMetaConfig,
+    mailbox::QueueConfig,
+    processor::ChunkLimits,
+    resume::ResumeConfig,
+    netns::{NamespaceConfig, NamespaceError},
+    discovery::DiscoveryConfig,
+    backup::{self, Backup, BackupConfig},
+    control_socket::ControlSocket,
+    tables::message::{DecoderRegistry, MessageDecoder},
     server, log_client,
 };
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct P2pConfig {
-    identity: String,
+    // path to the identity json, or to the data directory of the Octez node, which contains it
+    identity: Option<String>,
+    // the name of the environment variable which holds the identity json
+    identity_env: Option<String>,
+    // the file descriptor of a mounted secret which holds the identity json
+    identity_fd: Option<u32>,
     pub port: u16,
     store_limit: Option<u64>,
+    retention_days: Option<u64>,
+    // how many recently queried messages are kept decoded in memory, 0 disables the cache
+    message_cache: Option<usize>,
+    // the network namespace of the node, if it differs from the one of the recorder,
+    // the `identity` path is inside the namespace then
+    namespace: Option<NamespaceConfig>,
+    // the limits of the data buffered during the handshake
+    chunk_limits: Option<ChunkLimits>,
+    // only the first bytes of each read and write are captured after the handshake,
+    // the full payload if absent or 0
+    payload_limit: Option<u32>,
+    // one in N messages of the type is stored, like `{ current_head = 10 }`,
+    // the first message which mentions the block is always stored
+    sampling: Option<BTreeMap<String, u32>>,
+    // the recorded handshakes are checked against the p2p specification,
+    // the violations are served at `/v2/conformance`
+    conformance: Option<bool>,
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExternalConfig {
+    // the ports of the other services besides ssh, dns, http and https
+    ports: Option<Vec<u16>>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsConfig {
+    cert: String,
+    key: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct LogConfig {
-    port: u16,
+    port: Option<u16>,
+    tcp_port: Option<u16>,
+    tls: Option<TlsConfig>,
+    file: Option<String>,
     disable_search: Option<bool>,
     store_limit: Option<u64>,
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct NodeConfig {
     name: String,
     http_v3: Option<u16>,
     db: String,
     p2p: Option<P2pConfig>,
     log: Option<LogConfig>,
+    // the port where capture agents forward the records of the node
+    remote_port: Option<u16>,
+    // the address where the `remote_port` listens, localhost by default
+    remote_address: Option<IpAddr>,
+    // the central recorder accepts only the capture agents presenting the token,
+    // the capture agent presents it to the central recorder in the `db`
+    remote_token: Option<String>,
+    // the port where the follower debuggers tail the records the primary stores
+    replication_port: Option<u16>,
+    // the address where the `replication_port` listens, localhost by default
+    replication_address: Option<IpAddr>,
+    // the primary accepts only the followers presenting the token,
+    // the follower presents it to the primary in `follow`
+    replication_token: Option<String>,
+    // the address of the `replication_port` of the primary, the follower only serves the queries
+    follow: Option<String>,
+    // the options of the database, `profile` is `default`, `capture-heavy` or `query-heavy`
+    rocksdb: Option<RocksdbConfig>,
 }
 
-#[derive(Clone, Deserialize)]
-struct Config {
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    // the address where the http servers listen, all interfaces by default
+    http_address: Option<IpAddr>,
     http_v2: Option<u16>,
+    api_limits: Option<LimiterConfig>,
+    // how the traffic of the node is captured, only `syscall` is implemented
+    capture_backend: Option<CaptureBackend>,
+    // the queue of each decoder thread
+    decoder_queue: Option<QueueConfig>,
+    // the state of the live connections persisted to resume them after the restart
+    resume: Option<ResumeConfig>,
+    // the running node processes are found periodically
+    discovery: Option<DiscoveryConfig>,
+    // the rules the messages of the peers are checked against
+    peer_scoring: Option<ScoringConfig>,
+    // the handshakes from one address range are counted to detect the connection flood
+    flood_detection: Option<FloodConfig>,
+    // the headers of the tcp packets of the connections are captured from the interface
+    tcp_metadata: Option<TcpMetaConfig>,
+    // the outbound connections of the node to the other services, recorded without the payload
+    external_connections: Option<ExternalConfig>,
+    logging: Option<LoggingConfig>,
+    // periodic backup of the databases to the S3 compatible storage
+    backup: Option<BackupConfig>,
+    // the unix socket of the control api, for the deployments without the tcp access
+    control_socket: Option<PathBuf>,
+    // the bearer token of the endpoints which reveal secrets, they are disabled without it
+    #[serde(default, skip_serializing)]
+    admin_token: Option<String>,
     nodes: Vec<NodeConfig>,
 }
 
+/// The syscall hooks see the data the node reads and writes on every interface,
+/// without the routing, NAT or sysctl changes, the packet based backends are reserved
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureBackend {
+    Syscall,
+    Xdp,
+    Tun,
+}
+
+/// The logs of the recorder itself
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `text` (default) or `json`
+    pub format: Option<String>,
+    /// the level and the levels per module, like `info,tezedge_recorder::database=debug`
+    pub level: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read config {}: {}", _0, _1)]
+    Read(String, io::Error),
+    #[error("failed to parse config {}: {}", _0, _1)]
+    Parse(String, toml::de::Error),
+    #[error("bad config key `{}`: {}", key, reason)]
+    Invalid { key: String, reason: String },
+    #[error("failed to read runtime overrides {}: {}", _0, _1)]
+    ReadOverrides(String, serde_json::Error),
+    #[error("failed to persist runtime overrides {}: {}", _0, _1)]
+    WriteOverrides(String, io::Error),
+}
+
+/// The settings which can be changed at runtime by `PUT /v2/config`, an absent key is unchanged,
+/// they are persisted in the database directory of the node and applied on top of the config file
+#[derive(Default, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NodeOverrides {
+    pub p2p_store_limit: Option<u64>,
+    pub log_store_limit: Option<u64>,
+}
+
+impl NodeOverrides {
+    const FILE_NAME: &'static str = "config_overrides.json";
+
+    fn path(node: &NodeConfig) -> PathBuf {
+        Path::new(&node.db).join(Self::FILE_NAME)
+    }
+
+    fn load(node: &NodeConfig) -> Result<Option<Self>, ConfigError> {
+        let path = Self::path(node);
+        let name = path.display().to_string();
+        match std::fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map(Some)
+                .map_err(|error| ConfigError::ReadOverrides(name, error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(ConfigError::ReadOverrides(name, error.into())),
+        }
+    }
+
+    fn persist(&self, node: &NodeConfig) -> Result<(), ConfigError> {
+        let path = Self::path(node);
+        let text = serde_json::to_string_pretty(self).map_err(io::Error::from);
+        text.and_then(|text| std::fs::write(&path, text))
+            .map_err(|error| ConfigError::WriteOverrides(path.display().to_string(), error))
+    }
+
+    fn merge(self, other: Self) -> Self {
+        NodeOverrides {
+            p2p_store_limit: self.p2p_store_limit.or(other.p2p_store_limit),
+            log_store_limit: self.log_store_limit.or(other.log_store_limit),
+        }
+    }
+}
+
+impl NodeConfig {
+    fn apply(&mut self, overrides: &NodeOverrides) -> Result<(), ConfigError> {
+        let missing = |key: &str, section: &str| ConfigError::Invalid {
+            key: key.to_string(),
+            reason: format!("the node {} has no {} section", self.name, section),
+        };
+        if let Some(limit) = overrides.p2p_store_limit {
+            let p2p = self.p2p.as_mut().ok_or_else(|| missing("p2p_store_limit", "p2p"))?;
+            p2p.store_limit = Some(limit);
+        }
+        if let Some(limit) = overrides.log_store_limit {
+            let log = self.log.as_mut().ok_or_else(|| missing("log_store_limit", "log"))?;
+            log.store_limit = Some(limit);
+        }
+        Ok(())
+    }
+}
+
+/// The effective config, shared with the http server
+pub struct SharedConfig(Mutex<Config>);
+
+impl SharedConfig {
+    pub fn snapshot(&self) -> Config {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Applies the overrides to the node and its database, and persists them
+    pub fn update<Db>(
+        &self,
+        node_name: &str,
+        db: &Db,
+        overrides: NodeOverrides,
+    ) -> Result<(), ConfigError>
+    where
+        Db: Database,
+    {
+        let mut config = self.0.lock().unwrap();
+        let node = config
+            .nodes
+            .iter_mut()
+            .find(|node| node.name == node_name)
+            .ok_or_else(|| ConfigError::Invalid {
+                key: "node_name".to_string(),
+                reason: format!("no such node: {:?}", node_name),
+            })?;
+        let mut updated = node.clone();
+        updated.apply(&overrides)?;
+        let persisted = NodeOverrides::load(&updated)?.unwrap_or_default();
+        overrides.merge(persisted).persist(&updated)?;
+        *node = updated;
+        db.set_store_limits(
+            node.p2p.as_ref().and_then(|p2p| p2p.store_limit),
+            node.log.as_ref().and_then(|log| log.store_limit),
+        );
+        Ok(())
+    }
+
+    pub fn admin_token(&self) -> Option<String> {
+        self.0.lock().unwrap().admin_token.clone()
+    }
+
+    /// Reads the identity of the node again, it is not kept in the config
+    pub fn identity(&self, node_name: &str) -> Result<Identity, NodeError> {
+        self.0.lock().unwrap().identity(node_name)
+    }
+}
+
+impl Config {
+    fn identity(&self, node_name: &str) -> Result<Identity, NodeError> {
+        let p2p = self
+            .nodes
+            .iter()
+            .find(|node| node.name == node_name)
+            .and_then(|node| node.p2p.as_ref())
+            .ok_or_else(|| NodeError::NoP2p(node_name.to_string()))?;
+        NodeInfo::new(p2p, node_name.to_string()).map(|info| info.identity())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: String, reason: &str| ConfigError::Invalid {
+            key,
+            reason: reason.to_string(),
+        };
+
+        if let Some(logging) = &self.logging {
+            if let Some(format) = &logging.format {
+                if format != "text" && format != "json" {
+                    return Err(invalid("logging.format".to_string(), "expected text or json"));
+                }
+            }
+            if let Some(level) = &logging.level {
+                if let Err(error) = tracing_subscriber::EnvFilter::try_new(level) {
+                    return Err(invalid("logging.level".to_string(), &error.to_string()));
+                }
+            }
+        }
+
+        match self.capture_backend {
+            None | Some(CaptureBackend::Syscall) => (),
+            Some(_) => {
+                let reason = "only `syscall` is supported, it needs no routing or NAT changes";
+                return Err(invalid("capture_backend".to_string(), reason));
+            },
+        }
+
+        if let Some(name) = self.peer_scoring.as_ref().and_then(|s| s.unknown_rule()) {
+            let key = format!("peer_scoring.weights.{}", name);
+            return Err(invalid(key, "no such rule"));
+        }
+
+        let (mut names, mut p2p_ports) = (HashSet::new(), HashSet::new());
+        for (i, node) in self.nodes.iter().enumerate() {
+            if !names.insert(&node.name) {
+                return Err(invalid(format!("nodes[{}].name", i), "duplicated node name"));
+            }
+            if let Some(p2p) = &node.p2p {
+                if !p2p_ports.insert(p2p.port) {
+                    return Err(invalid(format!("nodes[{}].p2p.port", i), "duplicated p2p port"));
+                }
+                if p2p.identity.is_none() && p2p.identity_env.is_none() && p2p.identity_fd.is_none()
+                {
+                    let reason = "one of `identity`, `identity_env` or `identity_fd` is required";
+                    return Err(invalid(format!("nodes[{}].p2p.identity", i), reason));
+                }
+            }
+            if node.follow.is_some() && (node.p2p.is_some() || node.log.is_some()) {
+                let reason = "the follower only serves the queries, it has no `p2p` or `log`";
+                return Err(invalid(format!("nodes[{}].follow", i), reason));
+            }
+            if let Some(log) = &node.log {
+                if log.port.is_none() && log.tcp_port.is_none() && log.file.is_none() {
+                    let reason = "one of `port`, `tcp_port` or `file` is required";
+                    return Err(invalid(format!("nodes[{}].log", i), reason));
+                }
+                if log.tls.is_some() && log.tcp_port.is_none() {
+                    let reason = "tls requires `tcp_port`";
+                    return Err(invalid(format!("nodes[{}].log.tls", i), reason));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Overrides the settings of the config, the command line takes precedence
+/// over the environment variables, which take precedence over the config.
+/// Store limits and retention apply to every node.
+#[derive(Default, Clone)]
+pub struct Overrides {
+    pub http_address: Option<IpAddr>,
+    pub http_v2: Option<u16>,
+    pub p2p_store_limit: Option<u64>,
+    pub log_store_limit: Option<u64>,
+    pub p2p_retention_days: Option<u64>,
+    pub log_format: Option<String>,
+    pub log_level: Option<String>,
+}
+
+impl Overrides {
+    /// `HTTP_ADDRESS`, `HTTP_V2_PORT`, `P2P_STORE_LIMIT`, `LOG_STORE_LIMIT`,
+    /// `P2P_RETENTION_DAYS`, `LOG_FORMAT` and `RUST_LOG`, the invalid value is an error
+    /// which names the variable
+    pub fn from_env() -> Result<Self> {
+        fn var<T>(name: &str) -> Result<Option<T>>
+        where
+            T: std::str::FromStr,
+            T::Err: fmt::Display,
+        {
+            match env::var(name) {
+                Ok(s) => s
+                    .parse()
+                    .map(Some)
+                    .map_err(|e| anyhow::anyhow!("environment variable {}={:?}: {}", name, s, e)),
+                Err(_) => Ok(None),
+            }
+        }
+
+        let log_format = var::<String>("LOG_FORMAT")?;
+        if let Some(format) = &log_format {
+            if format != "text" && format != "json" {
+                let e = "expected text or json";
+                anyhow::bail!("environment variable LOG_FORMAT={:?}: {}", format, e);
+            }
+        }
+        Ok(Overrides {
+            http_address: var("HTTP_ADDRESS")?,
+            http_v2: var("HTTP_V2_PORT")?,
+            p2p_store_limit: var("P2P_STORE_LIMIT")?,
+            log_store_limit: var("LOG_STORE_LIMIT")?,
+            p2p_retention_days: var("P2P_RETENTION_DAYS")?,
+            log_format,
+            log_level: var("RUST_LOG")?,
+        })
+    }
+
+    pub fn or(self, other: Self) -> Self {
+        Overrides {
+            http_address: self.http_address.or(other.http_address),
+            http_v2: self.http_v2.or(other.http_v2),
+            p2p_store_limit: self.p2p_store_limit.or(other.p2p_store_limit),
+            log_store_limit: self.log_store_limit.or(other.log_store_limit),
+            p2p_retention_days: self.p2p_retention_days.or(other.p2p_retention_days),
+            log_format: self.log_format.or(other.log_format),
+            log_level: self.log_level.or(other.log_level),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Identity {
     pub public_key: [u8; 32],
@@ -54,10 +454,15 @@ pub struct Identity {
 pub struct NodeInfo {
     identity: Identity,
     name: String,
+    chunk_limits: ChunkLimits,
 }
 
 #[derive(Error, Debug)]
 pub enum NodeError {
+    #[error("no identity, set one of `identity`, `identity_env` or `identity_fd`")]
+    NoIdentity,
+    #[error("failed to read identity from environment variable {}", _0)]
+    IdentityEnv(String),
     #[error("failed to open identity {}", _0)]
     OpenIdentity(io::Error),
     #[error("failed to parse identity {}", _0)]
@@ -66,35 +471,69 @@ pub enum NodeError {
     ParsePk,
     #[error("failed to parse secret key from hex")]
     ParseSk,
+    #[error("no such node or it has no p2p section: {:?}", _0)]
+    NoP2p(String),
+    #[error("namespace: {}", _0)]
+    Namespace(#[from] NamespaceError),
 }
 
 struct NodeServer {
     _server: Option<JoinHandle<()>>,
     log_client: Option<thread::JoinHandle<()>>,
+    log_client_tcp: Option<thread::JoinHandle<()>>,
+    log_file: Option<thread::JoinHandle<()>>,
+    remote_receiver: Option<thread::JoinHandle<()>>,
+    replication: Option<thread::JoinHandle<()>>,
+    follower: Option<thread::JoinHandle<()>>,
 }
 
 pub struct System<Db> {
     config: Config,
-    port_to_pid: HashMap<u16, u32>,
+    pid_by_node: HashMap<String, u32>,
     node_info: HashMap<u32, NodeInfo>,
     node_servers: HashMap<String, NodeServer>,
     node_dbs: HashMap<String, Arc<Db>>,
+    peer_scores: HashMap<String, Arc<PeerScores>>,
+    decoder_stats: HashMap<String, Arc<DecoderStats>>,
+    samplers: HashMap<String, Arc<Sampler>>,
+    decoders: DecoderRegistry,
     _old_server: Option<JoinHandle<()>>,
+    limiter: Arc<Limiter>,
+    control: Arc<Control>,
+    health: Arc<Health>,
+    pipeline: Arc<Pipeline>,
+    coverage: Arc<Coverage>,
+    self_monitor: Arc<SelfMonitor>,
     tokio_rt: Runtime,
 }
 
 impl NodeServer {
     pub fn open_spawn<Db>(
-        db_path: &str,
-        rpc_port: Option<u16>,
-        log_config: &Option<LogConfig>,
-        p2p_config: &Option<P2pConfig>,
+        config: &NodeConfig,
+        http_address: IpAddr,
+        limiter: Arc<Limiter>,
+        decoders: DecoderRegistry,
         rt: &Runtime,
         running: Arc<AtomicBool>,
     ) -> Result<(Self, Arc<Db>)>
     where
         Db: DatabaseNew + Database + DatabaseFetch + Sync + Send + 'static,
     {
+        let NodeConfig {
+            http_v3: rpc_port,
+            db: db_path,
+            p2p: p2p_config,
+            log: log_config,
+            remote_port,
+            remote_address,
+            remote_token,
+            replication_port,
+            replication_address,
+            replication_token,
+            follow,
+            rocksdb: tuning,
+            ..
+        } = config;
         let log_search = !log_config
             .as_ref()
             .and_then(|c| c.disable_search)
@@ -105,23 +544,100 @@ impl NodeServer {
         let message_store_limit = p2p_config
             .as_ref()
             .and_then(|c| c.store_limit);
-        let db = Arc::new(Db::open(db_path, log_search, log_store_limit, message_store_limit)?);
-        let server = if let Some(port) = rpc_port {
-            let addr = ([0, 0, 0, 0], port);
-            Some(rt.spawn(warp::serve(server::routes(db.clone())).run(addr)))
+        let message_retention_days = p2p_config
+            .as_ref()
+            .and_then(|c| c.retention_days);
+        let message_cache = p2p_config
+            .as_ref()
+            .and_then(|c| c.message_cache);
+        let mut db = Db::open(
+            db_path,
+            log_search,
+            log_store_limit,
+            message_store_limit,
+            message_retention_days,
+            message_cache,
+            tuning.clone(),
+        )?;
+        db.set_decoders(decoders);
+        db.set_remote_token(remote_token.clone());
+        let feed = replication_port.map(|_| Arc::new(replication::Feed::default()));
+        if let Some(feed) = &feed {
+            db.set_feed(feed.clone());
+        }
+        let db = Arc::new(db);
+        let server = if let Some(port) = *rpc_port {
+            let addr = (http_address, port);
+            Some(rt.spawn(warp::serve(server::routes(db.clone(), limiter)).run(addr)))
         } else {
             None
         };
-        let log_client = if let Some(log_config) = log_config {
-            Some(log_client::spawn(log_config.port, db.clone(), running)?)
-        } else {
-            None
+        let log_client = match log_config {
+            Some(LogConfig {
+                port: Some(port), ..
+            }) => Some(log_client::spawn(*port, db.clone(), running.clone())?),
+            _ => None,
+        };
+        let log_file = match log_config {
+            Some(LogConfig {
+                file: Some(path), ..
+            }) => Some(log_client::spawn_file(
+                path.into(),
+                db.clone(),
+                running.clone(),
+            )?),
+            _ => None,
+        };
+        let log_client_tcp = match log_config {
+            Some(LogConfig {
+                tcp_port: Some(port),
+                tls,
+                ..
+            }) => {
+                let tls = match tls {
+                    Some(tls) => Some(log_client::tls_config(&tls.cert, &tls.key)?),
+                    None => None,
+                };
+                Some(log_client::spawn_tcp(*port, tls, db.clone(), running.clone())?)
+            },
+            _ => None,
+        };
+
+        let remote_receiver = match remote_port {
+            Some(port) => {
+                let address = remote_address.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+                let token = remote_token.clone();
+                Some(remote::spawn_receiver(address, *port, token, db.clone(), running.clone())?)
+            },
+            None => None,
+        };
+
+        let replication = match (replication_port, feed) {
+            (Some(port), Some(feed)) => {
+                let address = replication_address.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+                let token = replication_token.clone();
+                let running = running.clone();
+                Some(replication::spawn_primary(address, *port, token, feed, running)?)
+            },
+            _ => None,
+        };
+        let follower = match follow {
+            Some(address) => {
+                let (address, token) = (address.clone(), replication_token.clone());
+                Some(replication::spawn_follower(address, token, db.clone(), running)?)
+            },
+            None => None,
         };
 
         Ok((
             NodeServer {
                 _server: server,
                 log_client,
+                log_client_tcp,
+                log_file,
+                remote_receiver,
+                replication,
+                follower,
             },
             db,
         ))
@@ -131,29 +647,78 @@ impl NodeServer {
         if let Some(log_client) = self.log_client {
             log_client.join().unwrap()
         }
+        if let Some(log_client_tcp) = self.log_client_tcp {
+            log_client_tcp.join().unwrap()
+        }
+        if let Some(log_file) = self.log_file {
+            log_file.join().unwrap()
+        }
+        if let Some(remote_receiver) = self.remote_receiver {
+            remote_receiver.join().unwrap()
+        }
+        if let Some(replication) = self.replication {
+            replication.join().unwrap()
+        }
+        if let Some(follower) = self.follower {
+            follower.join().unwrap()
+        }
+    }
+}
+
+impl P2pConfig {
+    /// 0 is the full payload
+    pub fn payload_limit(&self) -> u32 {
+        self.payload_limit.unwrap_or(0)
+    }
+
+    pub fn conformance(&self) -> bool {
+        self.conformance.unwrap_or(false)
+    }
+
+    /// The identity json, the environment variable takes precedence over the descriptor,
+    /// which takes precedence over the path
+    fn read_identity(&self) -> Result<String, NodeError> {
+        use std::fs;
+
+        if let Some(var) = &self.identity_env {
+            return env::var(var).map_err(|_| NodeError::IdentityEnv(var.clone()));
+        }
+        if let Some(fd) = self.identity_fd {
+            let path = format!("/dev/fd/{}", fd);
+            return fs::read_to_string(path).map_err(NodeError::OpenIdentity);
+        }
+        let path = Path::new(self.identity.as_ref().ok_or(NodeError::NoIdentity)?);
+        let path = match &self.namespace {
+            Some(namespace) => namespace.resolve(path)?,
+            None => path.to_path_buf(),
+        };
+        // the Octez node keeps the identity in its data directory
+        let path = if path.is_dir() {
+            path.join("identity.json")
+        } else {
+            path.to_path_buf()
+        };
+        fs::read_to_string(path).map_err(NodeError::OpenIdentity)
     }
 }
 
 impl NodeInfo {
-    pub fn new(identity_path: &str, name: String) -> Result<Self, NodeError> {
-        use std::{fs::File, convert::TryInto};
+    pub fn new(p2p: &P2pConfig, name: String) -> Result<Self, NodeError> {
+        use std::convert::TryInto;
 
+        // the Octez and the TezEdge layouts, only the keys are needed,
+        // the peer id and the proof of work stamp may be absent
         #[derive(Deserialize)]
         pub struct Inner {
-            #[allow(dead_code)]
-            peer_id: String,
             public_key: String,
             secret_key: String,
-            #[allow(dead_code)]
-            proof_of_work_stamp: String,
         }
 
-        let file = File::open(identity_path).map_err(NodeError::OpenIdentity)?;
+        let json = p2p.read_identity()?;
         let Inner {
             public_key,
             secret_key,
-            ..
-        } = serde_json::from_reader(file).map_err(NodeError::ParseIdentity)?;
+        } = serde_json::from_str(&json).map_err(NodeError::ParseIdentity)?;
 
         let identity = Identity {
             public_key: {
@@ -170,36 +735,198 @@ impl NodeInfo {
             },
         };
 
-        Ok(NodeInfo { identity, name })
+        Ok(NodeInfo {
+            identity,
+            name,
+            chunk_limits: p2p.chunk_limits.unwrap_or_default(),
+        })
     }
 
     pub fn identity(&self) -> Identity {
         self.identity.clone()
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn chunk_limits(&self) -> ChunkLimits {
+        self.chunk_limits
+    }
 }
 
 impl<Db> System<Db> {
-    pub fn load_config() -> Result<Self> {
-        use std::{fs::File, io::Read};
+    /// Loads the config from the `path`, or from `config.toml` in the working directory,
+    /// `/etc` or `/home/appuser`
+    pub fn load_config(path: Option<&str>) -> Result<Self> {
+        use std::fs;
 
-        let mut settings_file = File::open("config.toml")
-            .or_else(|_| File::open("/etc/config.toml"))
-            .or_else(|_| File::open("/home/appuser/config.toml"))?;
-        let mut settings_toml = String::new();
-        settings_file.read_to_string(&mut settings_toml)?;
-        let config = toml::from_str(&settings_toml)?;
+        let path = match path {
+            Some(path) => path,
+            None => ["config.toml", "/etc/config.toml", "/home/appuser/config.toml"]
+                .iter()
+                .copied()
+                .find(|path| Path::new(path).exists())
+                .unwrap_or("config.toml"),
+        };
+        let settings_toml =
+            fs::read_to_string(path).map_err(|error| ConfigError::Read(path.to_string(), error))?;
+        let mut config = toml::from_str::<Config>(&settings_toml)
+            .map_err(|error| ConfigError::Parse(path.to_string(), error))?;
+        config.validate()?;
+        for node in &mut config.nodes {
+            if let Some(overrides) = NodeOverrides::load(node)? {
+                log::info!("node {} has runtime overrides", node.name);
+                node.apply(&overrides)?;
+            }
+        }
+
+        let peer_scores = match &config.peer_scoring {
+            Some(scoring) => config
+                .nodes
+                .iter()
+                .filter(|node| node.p2p.is_some())
+                .map(|node| {
+                    let scores = PeerScores::new(node.name.clone(), scoring.clone());
+                    (node.name.clone(), Arc::new(scores))
+                })
+                .collect(),
+            None => HashMap::new(),
+        };
+        let decoder_stats = config
+            .nodes
+            .iter()
+            .filter(|node| node.p2p.is_some())
+            .map(|node| (node.name.clone(), Arc::default()))
+            .collect();
+        let samplers = config
+            .nodes
+            .iter()
+            .filter_map(|node| {
+                let rates = node.p2p.as_ref()?.sampling.as_ref()?;
+                Some((node.name.clone(), Arc::new(Sampler::new(rates))))
+            })
+            .collect();
 
         Ok(System {
+            limiter: Limiter::new(config.api_limits.clone()),
             config,
-            port_to_pid: HashMap::new(),
+            pid_by_node: HashMap::new(),
             node_info: HashMap::new(),
             node_servers: HashMap::new(),
             node_dbs: HashMap::new(),
+            peer_scores,
+            decoder_stats,
+            samplers,
+            decoders: DecoderRegistry::default(),
             _old_server: None,
+            control: Arc::new(Control::default()),
+            health: Arc::new(Health::default()),
+            pipeline: Arc::new(Pipeline::default()),
+            coverage: Arc::new(Coverage::default()),
+            self_monitor: Arc::new(SelfMonitor::default()),
             tokio_rt: Runtime::new().unwrap(),
         })
     }
 
+    pub fn apply_overrides(&mut self, overrides: &Overrides) {
+        if let Some(address) = overrides.http_address {
+            self.config.http_address = Some(address);
+        }
+        if let Some(port) = overrides.http_v2 {
+            self.config.http_v2 = Some(port);
+        }
+        if overrides.log_format.is_some() || overrides.log_level.is_some() {
+            let logging = self.config.logging.get_or_insert_with(LoggingConfig::default);
+            if let Some(format) = &overrides.log_format {
+                logging.format = Some(format.clone());
+            }
+            if let Some(level) = &overrides.log_level {
+                logging.level = Some(level.clone());
+            }
+        }
+        for node in &mut self.config.nodes {
+            if let Some(p2p) = &mut node.p2p {
+                if let Some(limit) = overrides.p2p_store_limit {
+                    p2p.store_limit = Some(limit);
+                }
+                if let Some(days) = overrides.p2p_retention_days {
+                    p2p.retention_days = Some(days);
+                }
+            }
+            if let (Some(log), Some(limit)) = (&mut node.log, overrides.log_store_limit) {
+                log.store_limit = Some(limit);
+            }
+        }
+    }
+
+    pub fn logging(&self) -> LoggingConfig {
+        self.config.logging.clone().unwrap_or_default()
+    }
+
+    pub fn decoder_queue(&self) -> QueueConfig {
+        self.config.decoder_queue.clone().unwrap_or_default()
+    }
+
+    pub fn resume_config(&self) -> ResumeConfig {
+        self.config.resume.clone().unwrap_or_default()
+    }
+
+    pub fn discovery_config(&self) -> Option<DiscoveryConfig> {
+        self.config.discovery.clone()
+    }
+
+    pub fn flood_config(&self) -> Option<FloodConfig> {
+        self.config.flood_detection.clone()
+    }
+
+    pub fn tcp_meta_config(&self) -> Option<TcpMetaConfig> {
+        self.config.tcp_metadata.clone()
+    }
+
+    fn http_address(&self) -> IpAddr {
+        self.config
+            .http_address
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    }
+
+    pub fn control(&self) -> Arc<Control> {
+        self.control.clone()
+    }
+
+    pub fn health(&self) -> Arc<Health> {
+        self.health.clone()
+    }
+
+    pub fn pipeline(&self) -> Arc<Pipeline> {
+        self.pipeline.clone()
+    }
+
+    pub fn coverage(&self) -> Arc<Coverage> {
+        self.coverage.clone()
+    }
+
+    /// `None` if the peers are not scored
+    pub fn peer_scores(&self, node_name: &str) -> Option<Arc<PeerScores>> {
+        self.peer_scores.get(node_name).cloned()
+    }
+
+    pub fn decoder_stats(&self, node_name: &str) -> Arc<DecoderStats> {
+        self.decoder_stats.get(node_name).cloned().unwrap_or_default()
+    }
+
+    /// Stores every message if the node has no `sampling`
+    pub fn sampler(&self, node_name: &str) -> Arc<Sampler> {
+        self.samplers.get(node_name).cloned().unwrap_or_default()
+    }
+
+    /// The decoder of the custom or experimental messages, it is tried before the ones
+    /// registered earlier, the details of the message are cached, so it should be registered
+    /// before the databases are opened and queried
+    pub fn register_decoder(&self, decoder: Arc<dyn MessageDecoder>) {
+        self.decoders.register(decoder);
+    }
+
     pub fn sniffer_path(&self) -> &str {
         "/tmp/bpf-sniffer.sock"
     }
@@ -218,25 +945,83 @@ impl<Db> System<Db> {
         }
     }
 
+    fn backup(&self, node_name: &str) -> Result<(Backup, &NodeConfig)> {
+        let invalid = |key: &str, reason: String| ConfigError::Invalid {
+            key: key.to_string(),
+            reason,
+        };
+        let config = self.config.backup.as_ref().ok_or_else(|| {
+            invalid("backup", "the config has no backup section".to_string())
+        })?;
+        let node = self
+            .config
+            .nodes
+            .iter()
+            .find(|node| node.name == node_name)
+            .ok_or_else(|| invalid("nodes", format!("no such node: {:?}", node_name)))?;
+        Ok((Backup::new(config, node_name)?, node))
+    }
+
+    /// The identity of the node, it decrypts the traffic recorded for the node
+    pub fn identity(&self, node_name: &str) -> Result<Identity, NodeError> {
+        self.config.identity(node_name)
+    }
+
+    /// The ids of the complete backups of the node, the oldest first
+    pub fn backups(&self, node_name: &str) -> Result<Vec<String>> {
+        let (backup, _) = self.backup(node_name)?;
+        Ok(self.tokio_rt.block_on(backup.ids())?)
+    }
+
+    /// Downloads the backup of the node, the latest if the `id` is `None`,
+    /// into the database directory of the node, returns the id of the restored backup
+    pub fn restore_backup(&self, node_name: &str, id: Option<&str>) -> Result<String> {
+        let (backup, node) = self.backup(node_name)?;
+        let db_path = Path::new(&node.db);
+        Ok(self.tokio_rt.block_on(backup.restore(id, db_path))?)
+    }
+
+    /// The port of some other service the node talks to, not of the tezos peer
+    fn is_external_port(&self, port: u16) -> bool {
+        let configured = self
+            .config
+            .external_connections
+            .as_ref()
+            .and_then(|c| c.ports.as_ref())
+            .map_or(false, |ports| ports.contains(&port));
+        matches!(port, 53 | 80 | 443 | 22) || configured
+    }
+
+    /// The outbound connection to the other service which is recorded without the payload,
+    /// only if the `external_connections` is configured
+    pub fn should_record_external(&self, address: &SocketAddr) -> bool {
+        self.config.external_connections.is_some()
+            && !self.control.is_blocked(&address.ip())
+            && self.is_external_port(address.port())
+    }
+
     pub fn should_ignore(&self, address: &SocketAddr) -> bool {
         //use std::net::IpAddr;
 
+        if self.control.is_blocked(&address.ip()) || self.control.is_ignored_peer(address) {
+            return true;
+        }
         match address.port() {
             0 | 65535 => {
                 return true;
             },
             // dns and other well known not tezos
-            53 | 80 | 443 | 22 => {
+            p if self.is_external_port(p) => {
                 return true;
             },
             // ignore syslog
             p => {
-                if self
-                    .config
-                    .nodes
-                    .iter()
-                    .any(|n| n.log.as_ref().map(|l| l.port).unwrap_or(0) == p)
-                {
+                if self.config.nodes.iter().any(|n| {
+                    n.log
+                        .as_ref()
+                        .map(|l| l.port == Some(p) || l.tcp_port == Some(p))
+                        .unwrap_or(false)
+                }) {
                     return true;
                 }
             },
@@ -259,49 +1044,229 @@ where
     Db: DatabaseNew + Database + DatabaseFetch + Sync + Send + 'static,
 {
     pub fn run_dbs(&mut self, running: Arc<AtomicBool>) {
+        let http_address = self.http_address();
         for c in &self.config.nodes {
             let r = running.clone();
             let rt = &self.tokio_rt;
-            match NodeServer::open_spawn(&c.db, c.http_v3, &c.log, &c.p2p, rt, r) {
+            let limiter = self.limiter.clone();
+            let decoders = self.decoders.clone();
+            let component = format!("node/{}", c.name);
+            match NodeServer::open_spawn(c, http_address, limiter, decoders, rt, r) {
                 Ok((server, db)) => {
+                    // the capture agent has the address of the recorder instead
+                    if Path::new(&c.db).is_dir() {
+                        let component = format!("db/{}", c.name);
+                        self.health.watch_dir(component, c.db.clone().into());
+                        self.tokio_rt.spawn(database::schedule_commit(db.clone()));
+                        self.tokio_rt.spawn(database::schedule_maintenance(db.clone()));
+                    }
                     self.node_servers.insert(c.name.clone(), server);
                     self.node_dbs.insert(c.name.clone(), db);
+                    self.health.set(&component, Status::Up);
                 },
                 Err(error) => {
-                    log::error!("{}", error);
+                    self.health.set(&component, Status::Down(error.to_string()));
                 },
             }
         }
 
+        if let Some(config) = &self.config.backup {
+            for c in &self.config.nodes {
+                // the capture agent has no database to back up
+                let db = match self.node_dbs.get(&c.name) {
+                    Some(db) if Path::new(&c.db).is_dir() => db.clone(),
+                    _ => continue,
+                };
+                let component = format!("backup/{}", c.name);
+                match Backup::new(config, &c.name) {
+                    Ok(node_backup) => {
+                        self.health.set(&component, Status::Up);
+                        let health = self.health.clone();
+                        let db_path = PathBuf::from(&c.db);
+                        let interval = config.interval();
+                        let task =
+                            backup::schedule(node_backup, db, db_path, interval, health, component);
+                        self.tokio_rt.spawn(task);
+                    },
+                    Err(error) => self.health.set(&component, Status::Down(error.to_string())),
+                }
+            }
+        }
+
+        if self.need_bpf() {
+            self.health.set("bpf", Status::Starting);
+        }
+
+        let db_dirs = self
+            .config
+            .nodes
+            .iter()
+            .filter(|c| self.node_dbs.contains_key(&c.name) && Path::new(&c.db).is_dir())
+            .map(|c| (c.name.clone(), PathBuf::from(&c.db)))
+            .collect();
+        let task = self_monitor::schedule(self.self_monitor.clone(), db_dirs);
+        self.tokio_rt.spawn(task);
+
+        for c in &self.config.nodes {
+            if !c.p2p.as_ref().map_or(false, P2pConfig::conformance) {
+                continue;
+            }
+            // the capture agent has no database to check
+            let db = match self.node_dbs.get(&c.name) {
+                Some(db) if Path::new(&c.db).is_dir() => db.clone(),
+                _ => continue,
+            };
+            let task = conformance::schedule(db, c.name.clone());
+            self.tokio_rt.spawn(task);
+        }
+
+        for c in &self.config.nodes {
+            // the follower does not capture, the capture agent has no database
+            let db = match self.node_dbs.get(&c.name) {
+                Some(db) if c.follow.is_none() && Path::new(&c.db).is_dir() => db.clone(),
+                _ => continue,
+            };
+            let task = gaps::schedule(
+                db,
+                c.name.clone(),
+                self.control.clone(),
+                self.pipeline.clone(),
+                self.coverage.clone(),
+            );
+            self.tokio_rt.spawn(task);
+        }
+
+        // both control apis change the same config
+        let config = Arc::new(SharedConfig(Mutex::new(self.config.clone())));
         if let Some(port) = self.config.http_v2 {
-            let addr = ([0, 0, 0, 0], port);
-            let s = warp::serve(server::routes_old(self.node_dbs.clone())).run(addr);
+            let addr = (http_address, port);
+            let routes = server::routes_old(
+                self.node_dbs.clone(),
+                self.limiter.clone(),
+                self.control.clone(),
+                self.health.clone(),
+                self.pipeline.clone(),
+                self.coverage.clone(),
+                self.peer_scores.clone(),
+                self.decoder_stats.clone(),
+                self.samplers.clone(),
+                self.self_monitor.clone(),
+                config.clone(),
+            );
+            let s = warp::serve(routes).run(addr);
             self._old_server = Some(self.tokio_rt.spawn(s));
         }
+
+        if let Some(path) = &self.config.control_socket {
+            let socket = ControlSocket {
+                dbs: self.node_dbs.clone(),
+                control: self.control.clone(),
+                health: self.health.clone(),
+                pipeline: self.pipeline.clone(),
+                config,
+            };
+            if let Err(error) = socket.spawn(path) {
+                log::error!("cannot listen on control socket {}: {}", path.display(), error);
+            }
+        }
     }
 
     pub fn handle_bind(&mut self, pid: u32, port: u16) -> Result<()> {
-        let info = if let Some(old_pid) = self.port_to_pid.remove(&port) {
+        let nodes = &self.config.nodes;
+        let on_port = |c: &&NodeConfig| c.p2p.as_ref().map(|p2p| p2p.port) == Some(port);
+        // the nodes in the other namespaces may listen on the same port,
+        // the node in the namespace of the process is preferred
+        let mut in_namespace = None;
+        for c in nodes.iter().filter(on_port) {
+            if let Some(namespace) = &c.p2p.as_ref().unwrap().namespace {
+                match namespace.contains(pid) {
+                    Ok(true) => {
+                        in_namespace = Some(c);
+                        break;
+                    },
+                    Ok(false) => (),
+                    Err(error) => log::warn!("node {}: {}", c.name, error),
+                }
+            }
+        }
+        let c = match in_namespace.or_else(|| {
+            nodes
+                .iter()
+                .filter(on_port)
+                .find(|c| c.p2p.as_ref().unwrap().namespace.is_none())
+        }) {
+            Some(c) => c,
+            None => {
+                log::info!("pid: {} bound port: {} outside of the namespaces", pid, port);
+                return Ok(());
+            },
+        };
+        let info = if let Some(old_pid) = self.pid_by_node.remove(&c.name) {
             log::info!("detaching from pid: {} at port: {}", old_pid, port);
             self.node_info.remove(&old_pid).unwrap()
         } else {
-            let c = self
-                .config
-                .nodes
-                .iter()
-                .filter(|c| c.p2p.is_some())
-                .find(|c| c.p2p.as_ref().unwrap().port == port)
-                .unwrap();
-            let p2p = c.p2p.as_ref().unwrap();
-            NodeInfo::new(&p2p.identity, c.name.clone())?
+            NodeInfo::new(c.p2p.as_ref().unwrap(), c.name.clone())?
         };
         log::info!("attaching to pid: {} at port: {}", pid, port);
-        self.port_to_pid.insert(port, pid);
+        self.pid_by_node.insert(c.name.clone(), pid);
         self.node_info.insert(pid, info);
 
         Ok(())
     }
 
+    /// Attaches the node to the `pid` as if the node bound its p2p port,
+    /// the connections of the node are resumed after the restart before any bind is seen
+    pub fn attach(&mut self, pid: u32, node_name: &str) -> Option<(&mut NodeInfo, Arc<Db>)> {
+        if !self.node_info.contains_key(&pid) {
+            let port = self
+                .config
+                .nodes
+                .iter()
+                .find(|node| node.name == node_name)
+                .and_then(|node| node.p2p.as_ref())?
+                .port;
+            if let Err(error) = self.handle_bind(pid, port) {
+                log::error!("failed to attach {} to pid: {}, {}", node_name, pid, error);
+                return None;
+            }
+        }
+        self.get_mut(pid)
+    }
+
+    /// Reads the identity of the node again, the connections established from now on
+    /// are decrypted with it, the node which is not attached yet reads it when attached
+    pub fn reload_identity(&mut self, node_name: &str) -> Result<(), NodeError> {
+        let identity = self.config.identity(node_name)?;
+        if let Some(pid) = self.pid_by_node.get(node_name) {
+            if let Some(info) = self.node_info.get_mut(pid) {
+                info.identity = identity;
+                log::info!("reloaded identity of {}", node_name);
+            }
+        }
+        Ok(())
+    }
+
+    /// The pids of the node processes and the names of the nodes
+    pub fn p2p_port(&self, node_name: &str) -> Option<u16> {
+        self.config
+            .nodes
+            .iter()
+            .find(|node| node.name == node_name)
+            .and_then(|node| node.p2p.as_ref())
+            .map(|p2p| p2p.port)
+    }
+
+    pub fn attached(&self) -> Vec<(u32, String)> {
+        self.pid_by_node
+            .iter()
+            .map(|(name, pid)| (*pid, name.clone()))
+            .collect()
+    }
+
+    pub fn is_attached(&self, pid: u32) -> bool {
+        self.node_info.contains_key(&pid)
+    }
+
     pub fn get_mut(&mut self, pid: u32) -> Option<(&mut NodeInfo, Arc<Db>)> {
         let db = self
             .node_info
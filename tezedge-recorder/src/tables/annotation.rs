@@ -0,0 +1,84 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+use storage::persistent::{BincodeEncoded, KeyValueSchema, database::RocksDbKeyValueSchema};
+
+/// The label and the note the user attached to a message or to a time interval,
+/// the timestamps are milliseconds, the interval of the message annotation is its timestamp
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Item {
+    pub message_id: Option<u64>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    pub label: String,
+    #[serde(default)]
+    pub note: String,
+    pub author: Option<String>,
+}
+
+impl Item {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.label.is_empty() {
+            return Err("the label is empty".to_string());
+        }
+        match (self.message_id, self.from, self.to) {
+            (Some(_), None, None) => Ok(()),
+            (Some(_), _, _) => Err("`message_id` together with `from` or `to`".to_string()),
+            (None, None, _) => Err("either `message_id` or `from` is expected".to_string()),
+            (None, Some(from), Some(to)) if from > to => Err("`from` is after `to`".to_string()),
+            (None, Some(_), _) => Ok(()),
+        }
+    }
+
+    fn covers(&self, timestamp: u64) -> bool {
+        let from = self.from.unwrap_or(0);
+        from <= timestamp && timestamp <= self.to.unwrap_or(from)
+    }
+}
+
+impl BincodeEncoded for Item {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItemWithId {
+    pub id: u64,
+    #[serde(flatten)]
+    pub item: Item,
+}
+
+impl ItemWithId {
+    /// The annotations of the message and of the intervals the message is in
+    pub fn of_message(annotations: &[Self], id: u64, timestamp: u64) -> Vec<Self> {
+        annotations
+            .iter()
+            .filter(|a| match a.item.message_id {
+                Some(message_id) => message_id == id,
+                None => a.item.covers(timestamp),
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The annotations of the intervals the moment is in
+    pub fn of_moment(annotations: &[Self], timestamp: u64) -> Vec<Self> {
+        annotations
+            .iter()
+            .filter(|a| a.item.message_id.is_none() && a.item.covers(timestamp))
+            .cloned()
+            .collect()
+    }
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = u64;
+    type Value = Item;
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn name() -> &'static str {
+        "annotation_storage"
+    }
+}
@@ -3,15 +3,25 @@
 
 use std::{convert::TryFrom, fmt, str::FromStr, num::ParseIntError};
 use thiserror::Error;
+use bytes::Bytes;
 use serde::{
     Serialize,
     ser::{self, SerializeStruct},
 };
-use rocksdb::{Cache, ColumnFamilyDescriptor};
+use rocksdb::{Cache, ColumnFamilyDescriptor, Options, SliceTransform};
 use storage::persistent::{
     KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
 };
-use super::{common::Sender, connection};
+use super::{common::Sender, connection, delta};
+
+/// The read or the write syscall which completed the chunk
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SyscallTime {
+    /// nanoseconds of the monotonic clock of the kernel when the syscall returned
+    pub ktime: u64,
+    /// nanoseconds the syscall took
+    pub latency: u64,
+}
 
 #[derive(Clone)]
 pub struct Item {
@@ -20,8 +30,9 @@ pub struct Item {
     pub counter: u64,
     timestamp: u64,
     net: bool,
-    pub bytes: Vec<u8>,
-    pub plain: Vec<u8>,
+    syscall: Option<SyscallTime>,
+    pub bytes: Bytes,
+    pub plain: Bytes,
 }
 
 impl Item {
@@ -29,8 +40,8 @@ impl Item {
         cn_id: connection::Key,
         sender: Sender,
         counter: u64,
-        bytes: Vec<u8>,
-        plain: Vec<u8>,
+        bytes: Bytes,
+        plain: Bytes,
     ) -> Self {
         use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -45,6 +56,7 @@ impl Item {
             counter,
             net: true,
             timestamp,
+            syscall: None,
             bytes,
             plain,
         }
@@ -54,10 +66,26 @@ impl Item {
         self.net = net;
     }
 
+    pub fn set_syscall(&mut self, syscall: Option<SyscallTime>) {
+        self.syscall = syscall;
+    }
+
+    pub fn syscall(&self) -> Option<&SyscallTime> {
+        self.syscall.as_ref()
+    }
+
     #[rustfmt::skip]
     pub fn split(self) -> (Key, Value) {
-        let Item { cn_id, counter, sender, net, timestamp, bytes, plain } = self;
-        (Key { cn_id, counter, sender }, Value { net, timestamp, bytes, plain })
+        let Item { cn_id, counter, sender, net, timestamp, syscall, bytes, plain } = self;
+        let value = Value { net, timestamp, syscall, base: None, bytes, plain };
+        (Key { cn_id, counter, sender }, value)
+    }
+
+    #[rustfmt::skip]
+    pub fn unite(key: Key, value: Value) -> Self {
+        let (Key { cn_id, counter, sender }, value) = (key, value);
+        let Value { net, timestamp, syscall, bytes, plain, .. } = value;
+        Item { cn_id, sender, counter, timestamp, net, syscall, bytes, plain }
     }
 }
 
@@ -68,6 +96,7 @@ impl fmt::Debug for Item {
             .field("sender", &self.sender)
             .field("counter", &self.counter)
             .field("timestamp", &self.timestamp)
+            .field("syscall", &self.syscall)
             .field("bytes", &hex::encode(&self.bytes))
             .field("plain", &hex::encode(&self.plain))
             .finish()
@@ -185,23 +214,79 @@ impl Decoder for Key {
     }
 }
 
+// * bytes layout:
+// `[timestamp(8)][length(8)][flags(1)][ktime(8)][latency(8)][base(8)][bytes][plain]`,
+// the flags are `net`, whether the syscall time is present, it is absent in the old database,
+// and whether the plain is the delta from the base
+#[derive(Clone)]
 pub struct Value {
     net: bool,
     timestamp: u64,
-    pub bytes: Vec<u8>,
-    pub plain: Vec<u8>,
+    syscall: Option<SyscallTime>,
+    // the counter of the chunk of the same connection and sender,
+    // the `plain` is stored as the difference from its plain
+    base: Option<u64>,
+    pub bytes: Bytes,
+    pub plain: Bytes,
 }
 
 pub struct ValueTruncated(pub Value);
 
+impl Value {
+    /// Seconds since the unix epoch
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn syscall(&self) -> Option<&SyscallTime> {
+        self.syscall.as_ref()
+    }
+
+    /// The counter of the chunk the `plain` is the difference from, `None` if it is as is
+    pub fn delta_base(&self) -> Option<u64> {
+        self.base
+    }
+
+    /// Replaces the `plain` with the difference from the plain of the chunk `base`,
+    /// unless the difference is more than a half of the plain, returns whether replaced
+    pub fn compress(&mut self, base: u64, base_plain: &[u8]) -> bool {
+        if self.base.is_some() {
+            return false;
+        }
+        let delta = delta::encode(base_plain, &self.plain);
+        if delta.len() * 2 > self.plain.len() {
+            return false;
+        }
+        self.base = Some(base);
+        self.plain = Bytes::from(delta);
+        true
+    }
+
+    /// Restores the `plain` from the difference, the `base` is the plain of the base chunk
+    pub fn resolve(&mut self, base: &[u8]) -> bool {
+        if self.base.is_none() {
+            return true;
+        }
+        match delta::apply(base, &self.plain) {
+            Some(plain) => {
+                self.base = None;
+                self.plain = Bytes::from(plain);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
 impl Serialize for Value {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: ser::Serializer,
     {
-        let mut s = serializer.serialize_struct("Chunk", 4)?;
+        let mut s = serializer.serialize_struct("Chunk", 5)?;
         s.serialize_field("net", &self.net)?;
         s.serialize_field("timestamp", &self.timestamp)?;
+        s.serialize_field("syscall", &self.syscall)?;
         s.serialize_field("bytes", &hex::encode(&self.bytes))?;
         s.serialize_field("plain", &hex::encode(&self.plain))?;
         s.end()
@@ -225,21 +310,42 @@ impl Serialize for ValueTruncated {
             }
         };
 
-        let mut s = serializer.serialize_struct("Chunk", 3)?;
+        let mut s = serializer.serialize_struct("Chunk", 5)?;
         s.serialize_field("net", &self.0.net)?;
         s.serialize_field("timestamp", &self.0.timestamp)?;
+        s.serialize_field("syscall", &self.0.syscall)?;
         s.serialize_field("bytes", &truncated_hex(&self.0.bytes))?;
         s.serialize_field("plain", &truncated_hex(&self.0.plain))?;
         s.end()
     }
 }
 
+impl Value {
+    const NET: u8 = 0x01;
+    const SYSCALL: u8 = 0x02;
+    const DELTA: u8 = 0x04;
+}
+
 impl Encoder for Value {
     fn encode(&self) -> Result<Vec<u8>, SchemaError> {
-        let mut v = Vec::with_capacity(self.bytes.len() + self.plain.len() + 17);
+        let mut v = Vec::with_capacity(self.bytes.len() + self.plain.len() + 41);
         v.extend_from_slice(&self.timestamp.to_le_bytes());
         v.extend_from_slice(&(self.bytes.len() as u64).to_le_bytes());
-        v.push(if self.net { 1 } else { 0 });
+        let mut flags = if self.net { Self::NET } else { 0 };
+        if self.syscall.is_some() {
+            flags |= Self::SYSCALL;
+        }
+        if self.base.is_some() {
+            flags |= Self::DELTA;
+        }
+        v.push(flags);
+        if let Some(syscall) = &self.syscall {
+            v.extend_from_slice(&syscall.ktime.to_le_bytes());
+            v.extend_from_slice(&syscall.latency.to_le_bytes());
+        }
+        if let Some(base) = &self.base {
+            v.extend_from_slice(&base.to_le_bytes());
+        }
         v.extend_from_slice(&self.bytes);
         v.extend_from_slice(&self.plain);
         Ok(v)
@@ -252,17 +358,39 @@ impl Decoder for Value {
             return Err(SchemaError::DecodeError);
         }
 
-        let len = u64::from_le_bytes(TryFrom::try_from(&bytes[8..16]).unwrap()) as usize;
+        let le64 = |b: &[u8]| u64::from_le_bytes(TryFrom::try_from(b).unwrap());
+        let len = le64(&bytes[8..16]) as usize;
+        let flags = bytes[16];
+        let (syscall, rest) = if flags & Self::SYSCALL != 0 {
+            if bytes.len() < 33 {
+                return Err(SchemaError::DecodeError);
+            }
+            let syscall = SyscallTime {
+                ktime: le64(&bytes[17..25]),
+                latency: le64(&bytes[25..33]),
+            };
+            (Some(syscall), &bytes[33..])
+        } else {
+            (None, &bytes[17..])
+        };
+        let (base, rest) = if flags & Self::DELTA != 0 {
+            if rest.len() < 8 {
+                return Err(SchemaError::DecodeError);
+            }
+            (Some(le64(&rest[..8])), &rest[8..])
+        } else {
+            (None, rest)
+        };
+        if rest.len() < len {
+            return Err(SchemaError::DecodeError);
+        }
         Ok(Value {
-            net: bytes[16] != 0,
-            timestamp: u64::from_le_bytes(TryFrom::try_from(&bytes[..8]).unwrap()),
-            bytes: {
-                if bytes.len() < 16 + len {
-                    return Err(SchemaError::DecodeError);
-                }
-                bytes[17..(17 + len)].to_vec()
-            },
-            plain: bytes[(17 + len)..].to_vec(),
+            net: flags & Self::NET != 0,
+            timestamp: le64(&bytes[..8]),
+            syscall,
+            base,
+            bytes: Bytes::copy_from_slice(&rest[..len]),
+            plain: Bytes::copy_from_slice(&rest[len..]),
         })
     }
 }
@@ -274,14 +402,19 @@ impl KeyValueSchema for Schema {
     type Value = Value;
 }
 
-impl RocksDbKeyValueSchema for Schema {
-    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
-        use rocksdb::{Options, SliceTransform};
-
+impl Schema {
+    /// The options are shared by all day shards of the chunk storage
+    pub fn options() -> Options {
         let mut cf_opts = Options::default();
         cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(12));
         cf_opts.set_memtable_prefix_bloom_ratio(0.2);
-        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+        cf_opts
+    }
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        ColumnFamilyDescriptor::new(Self::name(), Self::options())
     }
 
     fn name() -> &'static str {
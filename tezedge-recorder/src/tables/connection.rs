@@ -1,16 +1,22 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{convert::TryFrom, net::SocketAddr, num::ParseIntError, str::FromStr, fmt};
+use std::{
+    collections::VecDeque, convert::TryFrom, net::SocketAddr, num::ParseIntError, str::FromStr,
+    fmt,
+};
 use thiserror::Error;
 use serde::{
-    Serialize,
+    Serialize, Deserialize,
     ser::{self, SerializeSeq, SerializeStruct},
 };
+use schemars::JsonSchema;
 use typenum::Bit;
 use storage::persistent::{
     KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
 };
+use tezos_messages::p2p::encoding::ack::{self, AckMessage};
+use bpf_recorder::{PacketHeader, tcp_flags};
 use super::common::{Initiator, Sender};
 
 #[derive(Debug, Clone, Default)]
@@ -20,11 +26,15 @@ pub struct Comments {
     pub incoming_uncertain: bool,
     pub incoming_cannot_decrypt: Option<u64>,
     pub incoming_suspicious: Option<u64>,
+    pub incoming_resynced: u64,
     pub outgoing_wrong_pow: Option<f64>,
     pub outgoing_too_short: Option<usize>,
     pub outgoing_uncertain: bool,
     pub outgoing_wrong_pk: bool,
     pub outgoing_cannot_decrypt: Option<u64>,
+    pub outgoing_resynced: u64,
+    // the payload of the port is limited, only the handshake is recorded
+    pub partial: bool,
 }
 
 impl Comments {
@@ -37,6 +47,7 @@ impl Comments {
             .cloned()
             .unwrap_or(u8::MAX as _) as u8;
         i[2] = if self.incoming_uncertain { 1 } else { 0 };
+        i[3] = if self.partial { 1 } else { 0 };
         let c = self
             .incoming_cannot_decrypt
             .as_ref()
@@ -44,6 +55,8 @@ impl Comments {
             .unwrap_or(u64::MAX);
         i[4..12].clone_from_slice(&c.to_le_bytes());
         i[12..16].clone_from_slice(&(self.incoming_suspicious.unwrap_or(0) as u32).to_le_bytes());
+        let r = self.incoming_resynced.min(u16::MAX as u64) as u16;
+        i[16..18].clone_from_slice(&r.to_le_bytes());
         let mut o = [0; 18];
         o[0] = self.outgoing_wrong_pow.as_ref().cloned().unwrap_or(0.0) as u8;
         o[1] = self
@@ -59,6 +72,8 @@ impl Comments {
             .cloned()
             .unwrap_or(u64::MAX);
         o[4..12].clone_from_slice(&c.to_le_bytes());
+        let r = self.outgoing_resynced.min(u16::MAX as u64) as u16;
+        o[16..18].clone_from_slice(&r.to_le_bytes());
 
         (i, o)
     }
@@ -67,6 +82,8 @@ impl Comments {
         let i_c = u64::from_le_bytes(TryFrom::try_from(&i[4..12]).unwrap());
         let i_s = u32::from_le_bytes(TryFrom::try_from(&i[12..16]).unwrap()) as u64;
         let o_c = u64::from_le_bytes(TryFrom::try_from(&o[4..12]).unwrap());
+        let i_r = u16::from_le_bytes(TryFrom::try_from(&i[16..18]).unwrap()) as u64;
+        let o_r = u16::from_le_bytes(TryFrom::try_from(&o[16..18]).unwrap()) as u64;
         Comments {
             incoming_wrong_pow: if i[0] == 0 { None } else { Some(i[0] as f64) },
             incoming_too_short: if i[1] == u8::MAX {
@@ -77,6 +94,7 @@ impl Comments {
             incoming_uncertain: i[2] != 0,
             incoming_suspicious: if i_s == 0 { None } else { Some(i_c) },
             incoming_cannot_decrypt: if i_c == u64::MAX { None } else { Some(i_c) },
+            incoming_resynced: i_r,
             outgoing_wrong_pow: if o[0] == 0 { None } else { Some(o[0] as f64) },
             outgoing_too_short: if o[1] == u8::MAX {
                 None
@@ -86,6 +104,8 @@ impl Comments {
             outgoing_uncertain: o[2] != 0,
             outgoing_wrong_pk: o[3] != 0,
             outgoing_cannot_decrypt: if o_c == u64::MAX { None } else { Some(o_c) },
+            outgoing_resynced: o_r,
+            partial: i[3] != 0,
         }
     }
 }
@@ -119,6 +139,10 @@ impl Serialize for Comments {
             let msg = format!("incoming chunk cannot decrypt, position: {}", position);
             s.serialize_element(&msg)?;
         }
+        if self.incoming_resynced != 0 {
+            let msg = format!("incoming stream resynced, skipped: {}", self.incoming_resynced);
+            s.serialize_element(&msg)?;
+        }
         if let Some(target) = self.outgoing_wrong_pow {
             let msg = format!(
                 "outgoing connection message bad proof-of-work, target: {}",
@@ -142,11 +166,765 @@ impl Serialize for Comments {
             let msg = format!("outgoing chunk cannot decrypt, position: {}", position);
             s.serialize_element(&msg)?;
         }
+        if self.outgoing_resynced != 0 {
+            let msg = format!("outgoing stream resynced, skipped: {}", self.outgoing_resynced);
+            s.serialize_element(&msg)?;
+        }
+        if self.partial {
+            let msg = "payload is partially captured, the messages after the handshake are missed";
+            s.serialize_element(&msg)?;
+        }
+
+        s.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NackMotive {
+    NoMotive,
+    TooManyConnections,
+    UnknownChainName,
+    DeprecatedP2pVersion,
+    DeprecatedDistributedDbVersion,
+    AlreadyConnected,
+}
+
+impl NackMotive {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(NackMotive::NoMotive),
+            1 => Some(NackMotive::TooManyConnections),
+            2 => Some(NackMotive::UnknownChainName),
+            3 => Some(NackMotive::DeprecatedP2pVersion),
+            4 => Some(NackMotive::DeprecatedDistributedDbVersion),
+            5 => Some(NackMotive::AlreadyConnected),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> From<&'a ack::NackMotive> for NackMotive {
+    fn from(v: &'a ack::NackMotive) -> Self {
+        match v {
+            ack::NackMotive::NoMotive => NackMotive::NoMotive,
+            ack::NackMotive::TooManyConnections => NackMotive::TooManyConnections,
+            ack::NackMotive::UnknownChainName => NackMotive::UnknownChainName,
+            ack::NackMotive::DeprecatedP2pVersion => NackMotive::DeprecatedP2pVersion,
+            ack::NackMotive::DeprecatedDistributedDbVersion => {
+                NackMotive::DeprecatedDistributedDbVersion
+            },
+            ack::NackMotive::AlreadyConnected => NackMotive::AlreadyConnected,
+        }
+    }
+}
+
+/// The decoded acknowledge message of the handshake
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum AckInfo {
+    Ack,
+    NackV0,
+    Nack {
+        motive: NackMotive,
+        potential_peers: Vec<String>,
+    },
+}
+
+impl<'a> From<&'a AckMessage> for AckInfo {
+    fn from(v: &'a AckMessage) -> Self {
+        match v {
+            AckMessage::Ack => AckInfo::Ack,
+            AckMessage::NackV0 => AckInfo::NackV0,
+            AckMessage::Nack(info) => AckInfo::Nack {
+                motive: info.motive().into(),
+                potential_peers: info.potential_peers_to_connect().clone(),
+            },
+        }
+    }
+}
+
+impl AckInfo {
+    pub fn nack_motive(&self) -> Option<NackMotive> {
+        match self {
+            AckInfo::Nack { motive, .. } => Some(*motive),
+            _ => None,
+        }
+    }
+
+    // * bytes layout: `[kind(1)][motive(1)][peers count(2)]([peer length(1)][peer])*`
+    fn ser(this: &Option<Self>, v: &mut Vec<u8>) {
+        match this {
+            None => v.extend_from_slice(&[0, 0, 0, 0]),
+            Some(AckInfo::Ack) => v.extend_from_slice(&[1, 0, 0, 0]),
+            Some(AckInfo::NackV0) => v.extend_from_slice(&[2, 0, 0, 0]),
+            Some(AckInfo::Nack {
+                motive,
+                potential_peers,
+            }) => {
+                let peers = potential_peers.iter().filter(|p| p.len() <= u8::MAX as usize);
+                v.push(3);
+                v.push(*motive as u8);
+                v.extend_from_slice(&(peers.clone().count() as u16).to_le_bytes());
+                for peer in peers {
+                    v.push(peer.len() as u8);
+                    v.extend_from_slice(peer.as_bytes());
+                }
+            },
+        }
+    }
+
+    fn de(bytes: &mut &[u8]) -> Result<Option<Self>, SchemaError> {
+        if bytes.len() < 4 {
+            return Err(SchemaError::DecodeError);
+        }
+        let (header, rest) = bytes.split_at(4);
+        *bytes = rest;
+        match header[0] {
+            0 => Ok(None),
+            1 => Ok(Some(AckInfo::Ack)),
+            2 => Ok(Some(AckInfo::NackV0)),
+            3 => {
+                let motive = NackMotive::from_u8(header[1]).ok_or(SchemaError::DecodeError)?;
+                let count = u16::from_le_bytes([header[2], header[3]]) as usize;
+                let mut potential_peers = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let (len, rest) = bytes.split_first().ok_or(SchemaError::DecodeError)?;
+                    let len = *len as usize;
+                    if rest.len() < len {
+                        return Err(SchemaError::DecodeError);
+                    }
+                    let (peer, rest) = rest.split_at(len);
+                    potential_peers.push(String::from_utf8_lossy(peer).into_owned());
+                    *bytes = rest;
+                }
+                Ok(Some(AckInfo::Nack {
+                    motive,
+                    potential_peers,
+                }))
+            },
+            _ => Err(SchemaError::DecodeError),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TerminationKind {
+    /// the local node closed the socket
+    Close,
+    /// the remote peer closed its side, the local node read the end of the stream
+    Fin,
+    /// the remote peer reset the connection, the local node got `ECONNRESET`
+    Reset,
+}
+
+/// How and when the connection ended
+#[derive(Debug, Clone)]
+pub struct Termination {
+    pub kind: TerminationKind,
+    /// unix nanoseconds
+    pub timestamp: u64,
+}
+
+impl Termination {
+    pub fn new(kind: TerminationKind) -> Self {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        Termination { kind, timestamp }
+    }
+
+    /// Who ended the connection
+    pub fn by(&self) -> Sender {
+        Sender::new(self.kind != TerminationKind::Close)
+    }
+
+    // * bytes layout: `[kind(1)][timestamp(8)]`, absent in the old database
+    fn ser(this: &Option<Self>, v: &mut Vec<u8>) {
+        if let Some(Termination { kind, timestamp }) = this {
+            v.push(*kind as u8 + 1);
+            v.extend_from_slice(&timestamp.to_le_bytes());
+        }
+    }
+
+    fn de(bytes: &mut &[u8]) -> Result<Option<Self>, SchemaError> {
+        if bytes.first().map_or(true, |b| *b >= TcpStats::TAG) {
+            return Ok(None);
+        }
+        if bytes.len() < 9 {
+            return Err(SchemaError::DecodeError);
+        }
+        let kind = match bytes[0] {
+            1 => TerminationKind::Close,
+            2 => TerminationKind::Fin,
+            3 => TerminationKind::Reset,
+            _ => return Err(SchemaError::DecodeError),
+        };
+        let timestamp = u64::from_le_bytes(TryFrom::try_from(&bytes[1..9]).unwrap());
+        *bytes = &bytes[9..];
+        Ok(Some(Termination { kind, timestamp }))
+    }
+}
+
+impl Serialize for Termination {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        let mut s = serializer.serialize_struct("Termination", 3)?;
+        s.serialize_field("kind", &self.kind)?;
+        s.serialize_field("by", &self.by())?;
+        s.serialize_field("timestamp", &self.timestamp)?;
+        s.end()
+    }
+}
+
+/// The tcp and ip headers of the packets of one direction, as the interface saw them
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TcpSide {
+    pub packets: u32,
+    /// the ip packets with the headers
+    pub bytes: u64,
+    /// the ttl changing during the connection is the route changing,
+    /// or the middlebox which injects the packets
+    pub ttl_min: u8,
+    pub ttl_max: u8,
+    /// as is, not scaled
+    pub window_min: u16,
+    pub window_max: u16,
+    /// the options of the syn
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    /// the largest ip packet, with `mss` it tells the path mtu
+    pub max_packet: u16,
+    /// every packet forbids the fragmentation
+    pub dont_fragment: bool,
+    /// some packet has the ecn flags
+    pub ecn: bool,
+    pub syn: u16,
+    pub fin: u16,
+    pub rst: u16,
+    /// unix nanoseconds of the first and the last packet
+    pub first: u64,
+    pub last: u64,
+}
+
+impl TcpSide {
+    const SIZE: usize = 47;
+
+    pub fn add(&mut self, header: &PacketHeader) {
+        let count = |flag: u8| (header.flags & flag != 0) as u16;
+
+        if self.packets == 0 {
+            self.ttl_min = header.ttl;
+            self.window_min = header.window;
+            self.dont_fragment = true;
+            self.first = header.timestamp;
+        }
+        self.packets = self.packets.saturating_add(1);
+        self.bytes += header.length as u64;
+        self.ttl_min = self.ttl_min.min(header.ttl);
+        self.ttl_max = self.ttl_max.max(header.ttl);
+        self.window_min = self.window_min.min(header.window);
+        self.window_max = self.window_max.max(header.window);
+        self.mss = header.mss.or(self.mss);
+        self.window_scale = header.window_scale.or(self.window_scale);
+        self.max_packet = self.max_packet.max(header.length);
+        self.dont_fragment &= header.dont_fragment;
+        self.ecn |= header.flags & (tcp_flags::ECE | tcp_flags::CWR) != 0;
+        self.syn = self.syn.saturating_add(count(tcp_flags::SYN));
+        self.fin = self.fin.saturating_add(count(tcp_flags::FIN));
+        self.rst = self.rst.saturating_add(count(tcp_flags::RST));
+        self.last = header.timestamp;
+    }
+
+    // * bytes layout: `[packets(4)][bytes(8)][ttl_min(1)][ttl_max(1)][window_min(2)]`
+    // `[window_max(2)][mss(2)][window_scale(1)][max_packet(2)][dont_fragment(1)][ecn(1)]`
+    // `[syn(2)][fin(2)][rst(2)][first(8)][last(8)]`, the absent mss is 0, the scale is 0xff
+    fn ser(&self, v: &mut Vec<u8>) {
+        v.extend_from_slice(&self.packets.to_le_bytes());
+        v.extend_from_slice(&self.bytes.to_le_bytes());
+        v.push(self.ttl_min);
+        v.push(self.ttl_max);
+        v.extend_from_slice(&self.window_min.to_le_bytes());
+        v.extend_from_slice(&self.window_max.to_le_bytes());
+        v.extend_from_slice(&self.mss.unwrap_or(0).to_le_bytes());
+        v.push(self.window_scale.unwrap_or(u8::MAX));
+        v.extend_from_slice(&self.max_packet.to_le_bytes());
+        v.push(self.dont_fragment as u8);
+        v.push(self.ecn as u8);
+        v.extend_from_slice(&self.syn.to_le_bytes());
+        v.extend_from_slice(&self.fin.to_le_bytes());
+        v.extend_from_slice(&self.rst.to_le_bytes());
+        v.extend_from_slice(&self.first.to_le_bytes());
+        v.extend_from_slice(&self.last.to_le_bytes());
+    }
+
+    fn de(b: &[u8]) -> Self {
+        let le16 = |offset: usize| u16::from_le_bytes([b[offset], b[offset + 1]]);
+        let le64 = |offset: usize| {
+            u64::from_le_bytes(TryFrom::try_from(&b[offset..(offset + 8)]).unwrap())
+        };
+        TcpSide {
+            packets: u32::from_le_bytes(TryFrom::try_from(&b[0..4]).unwrap()),
+            bytes: le64(4),
+            ttl_min: b[12],
+            ttl_max: b[13],
+            window_min: le16(14),
+            window_max: le16(16),
+            mss: Some(le16(18)).filter(|mss| *mss != 0),
+            window_scale: Some(b[20]).filter(|scale| *scale != u8::MAX),
+            max_packet: le16(21),
+            dont_fragment: b[23] != 0,
+            ecn: b[24] != 0,
+            syn: le16(25),
+            fin: le16(27),
+            rst: le16(29),
+            first: le64(31),
+            last: le64(39),
+        }
+    }
+}
+
+/// The packets of the connection, captured if the `tcp_metadata` is configured
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TcpStats {
+    pub incoming: TcpSide,
+    pub outgoing: TcpSide,
+    /// the interfaces the incoming packets came on, more than one on the bonded
+    /// or the multi-homed host
+    pub ingress: Vec<String>,
+    /// the interfaces the outgoing packets went out of
+    pub egress: Vec<String>,
+}
+
+impl TcpStats {
+    // distinct from the kind of the termination, which goes before,
+    // the sections which go after have the greater tags
+    const TAG: u8 = 0x80;
+    pub const MAX_INTERFACES: usize = 4;
+
+    // * bytes layout: `[tag(1)][incoming(47)][outgoing(47)][ingress][egress]`,
+    // each list of the interfaces is `[count(1)]([length(1)][name])*`, absent in the old database
+    fn ser(this: &Option<Self>, v: &mut Vec<u8>) {
+        if let Some(this) = this {
+            v.push(Self::TAG);
+            this.incoming.ser(v);
+            this.outgoing.ser(v);
+            for names in &[&this.ingress, &this.egress] {
+                let names = names.iter().take(Self::MAX_INTERFACES);
+                v.push(names.len() as u8);
+                for name in names {
+                    let name = &name.as_bytes()[..name.len().min(u8::MAX as usize)];
+                    v.push(name.len() as u8);
+                    v.extend_from_slice(name);
+                }
+            }
+        }
+    }
+
+    fn de(bytes: &mut &[u8]) -> Result<Option<Self>, SchemaError> {
+        match bytes.split_first() {
+            Some((&Self::TAG, rest)) if rest.len() >= TcpSide::SIZE * 2 => {
+                let (sides, rest) = rest.split_at(TcpSide::SIZE * 2);
+                *bytes = rest;
+                Ok(Some(TcpStats {
+                    incoming: TcpSide::de(&sides[..TcpSide::SIZE]),
+                    outgoing: TcpSide::de(&sides[TcpSide::SIZE..]),
+                    ingress: Self::de_names(bytes)?,
+                    egress: Self::de_names(bytes)?,
+                }))
+            },
+            Some((&Self::TAG, _)) => Err(SchemaError::DecodeError),
+            _ => Ok(None),
+        }
+    }
+
+    // the tags of the next sections are greater than the count
+    fn de_names(bytes: &mut &[u8]) -> Result<Vec<String>, SchemaError> {
+        let count = match bytes.first() {
+            Some(&count) if (count as usize) <= Self::MAX_INTERFACES => count as usize,
+            _ => return Ok(vec![]),
+        };
+        *bytes = &bytes[1..];
+        let mut names = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (length, rest) = bytes.split_first().ok_or(SchemaError::DecodeError)?;
+            let length = *length as usize;
+            if rest.len() < length {
+                return Err(SchemaError::DecodeError);
+            }
+            names.push(String::from_utf8_lossy(&rest[..length]).into_owned());
+            *bytes = &rest[length..];
+        }
+        Ok(names)
+    }
+
+    pub fn add(&mut self, incoming: bool, header: &PacketHeader) {
+        if incoming {
+            self.incoming.add(header);
+        } else {
+            self.outgoing.add(header);
+        }
+    }
+
+    /// The name of the interface of the packet which came (`incoming`) or went
+    pub fn add_interface(&mut self, incoming: bool, name: &str) {
+        let names = if incoming {
+            &mut self.ingress
+        } else {
+            &mut self.egress
+        };
+        if names.len() < Self::MAX_INTERFACES && !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+}
+
+/// The bytes the connection transferred in the buckets of `BUCKET_SECS` seconds,
+/// the latest `MAX_BUCKETS` are kept, enough for the sparkline of the connection
+#[derive(Debug, Clone, Default)]
+pub struct Throughput {
+    // unix seconds divided by `BUCKET_SECS`, of the front bucket
+    start: u64,
+    // incoming and outgoing bytes
+    buckets: VecDeque<(u32, u32)>,
+}
+
+impl Throughput {
+    const TAG: u8 = 0x81;
+    pub const BUCKET_SECS: u64 = 10;
+    pub const MAX_BUCKETS: usize = 60;
 
+    /// `true` if the bucket is the new one, the previous is complete
+    pub fn add(&mut self, now_secs: u64, incoming: bool, bytes: usize) -> bool {
+        let bucket = now_secs / Self::BUCKET_SECS;
+        if self.buckets.is_empty() {
+            self.start = bucket;
+        }
+        // the clock went back, the bytes go to the latest bucket
+        let bucket = bucket.max(self.start + self.buckets.len().max(1) as u64 - 1);
+        let new = bucket >= self.start + self.buckets.len() as u64;
+        // the connection was idle longer than the buckets cover
+        if bucket >= self.start + (self.buckets.len() + Self::MAX_BUCKETS) as u64 {
+            self.buckets.clear();
+            self.start = bucket;
+        }
+        while bucket >= self.start + self.buckets.len() as u64 {
+            self.buckets.push_back((0, 0));
+            if self.buckets.len() > Self::MAX_BUCKETS {
+                self.buckets.pop_front();
+                self.start += 1;
+            }
+        }
+        let (i, o) = self.buckets.back_mut().unwrap();
+        let counter = if incoming { i } else { o };
+        *counter = counter.saturating_add(bytes as u32);
+        new
+    }
+
+    // * bytes layout: `[tag(1)][start(8)][count(1)]([incoming(4)][outgoing(4)])*`,
+    // absent if nothing is transferred or in the old database
+    fn ser(&self, v: &mut Vec<u8>) {
+        if self.buckets.is_empty() {
+            return;
+        }
+        v.push(Self::TAG);
+        v.extend_from_slice(&self.start.to_le_bytes());
+        v.push(self.buckets.len() as u8);
+        for (i, o) in &self.buckets {
+            v.extend_from_slice(&i.to_le_bytes());
+            v.extend_from_slice(&o.to_le_bytes());
+        }
+    }
+
+    fn de(bytes: &mut &[u8]) -> Result<Self, SchemaError> {
+        let rest = match bytes.split_first() {
+            Some((&Self::TAG, rest)) if rest.len() >= 9 => rest,
+            Some((&Self::TAG, _)) => return Err(SchemaError::DecodeError),
+            _ => return Ok(Throughput::default()),
+        };
+        let start = u64::from_le_bytes(TryFrom::try_from(&rest[..8]).unwrap());
+        let count = rest[8] as usize;
+        let rest = &rest[9..];
+        if rest.len() < count * 8 {
+            return Err(SchemaError::DecodeError);
+        }
+        let le32 = |b: &[u8]| u32::from_le_bytes(TryFrom::try_from(b).unwrap());
+        let buckets = rest[..(count * 8)]
+            .chunks(8)
+            .map(|b| (le32(&b[..4]), le32(&b[4..])))
+            .collect();
+        *bytes = &rest[(count * 8)..];
+        Ok(Throughput { start, buckets })
+    }
+}
+
+impl Serialize for Throughput {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        let mut s = serializer.serialize_struct("Throughput", 4)?;
+        s.serialize_field("bucket_secs", &Self::BUCKET_SECS)?;
+        s.serialize_field("start", &(self.start * Self::BUCKET_SECS))?;
+        let incoming = self.buckets.iter().map(|(i, _)| *i).collect::<Vec<_>>();
+        let outgoing = self.buckets.iter().map(|(_, o)| *o).collect::<Vec<_>>();
+        s.serialize_field("incoming", &incoming)?;
+        s.serialize_field("outgoing", &outgoing)?;
         s.end()
     }
 }
 
+/// How long the read and the write syscalls of the node took on the connection,
+/// the histogram of the powers of two of nanoseconds, the slow node shows up here,
+/// while the slow network shows up in the round trip time of the tcp metadata
+#[derive(Debug, Clone)]
+pub struct SyscallLatency {
+    incoming: [u32; SyscallLatency::BUCKETS],
+    outgoing: [u32; SyscallLatency::BUCKETS],
+}
+
+impl Default for SyscallLatency {
+    fn default() -> Self {
+        SyscallLatency {
+            incoming: [0; Self::BUCKETS],
+            outgoing: [0; Self::BUCKETS],
+        }
+    }
+}
+
+impl SyscallLatency {
+    const TAG: u8 = 0x82;
+    // the last bucket takes everything longer than a second
+    const BUCKETS: usize = 32;
+
+    /// `incoming` is the read, `latency` in nanoseconds
+    pub fn add(&mut self, incoming: bool, latency: u64) {
+        let bucket = (64 - latency.leading_zeros() as usize).min(Self::BUCKETS - 1);
+        let buckets = if incoming {
+            &mut self.incoming
+        } else {
+            &mut self.outgoing
+        };
+        buckets[bucket] = buckets[bucket].saturating_add(1);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.incoming.iter().chain(self.outgoing.iter()).all(|c| *c == 0)
+    }
+
+    // the upper bound of the bucket the percentile falls in, nanoseconds
+    fn percentile(buckets: &[u32; Self::BUCKETS], percent: u64) -> Option<u64> {
+        let total = buckets.iter().map(|c| *c as u64).sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+        let rank = (total * percent + 99) / 100;
+        let mut seen = 0;
+        for (bucket, count) in buckets.iter().enumerate() {
+            seen += *count as u64;
+            if seen >= rank {
+                return Some(1 << bucket);
+            }
+        }
+        None
+    }
+
+    // * bytes layout: `[tag(1)]([incoming(4)]*32)([outgoing(4)]*32)`,
+    // absent if no syscall is timed or in the old database
+    fn ser(&self, v: &mut Vec<u8>) {
+        if self.is_empty() {
+            return;
+        }
+        v.push(Self::TAG);
+        for count in self.incoming.iter().chain(self.outgoing.iter()) {
+            v.extend_from_slice(&count.to_le_bytes());
+        }
+    }
+
+    fn de(bytes: &mut &[u8]) -> Result<Self, SchemaError> {
+        let rest = match bytes.split_first() {
+            Some((&Self::TAG, rest)) if rest.len() >= Self::BUCKETS * 8 => rest,
+            Some((&Self::TAG, _)) => return Err(SchemaError::DecodeError),
+            _ => return Ok(SyscallLatency::default()),
+        };
+        let mut latency = SyscallLatency::default();
+        let counts = rest[..(Self::BUCKETS * 8)]
+            .chunks(4)
+            .map(|b| u32::from_le_bytes(TryFrom::try_from(b).unwrap()));
+        let buckets = latency.incoming.iter_mut().chain(latency.outgoing.iter_mut());
+        for (bucket, count) in buckets.zip(counts) {
+            *bucket = count;
+        }
+        *bytes = &rest[(Self::BUCKETS * 8)..];
+        Ok(latency)
+    }
+}
+
+#[derive(Serialize)]
+struct Percentiles {
+    count: u64,
+    p50: Option<u64>,
+    p90: Option<u64>,
+    p99: Option<u64>,
+}
+
+impl Percentiles {
+    fn new(buckets: &[u32; SyscallLatency::BUCKETS]) -> Self {
+        Percentiles {
+            count: buckets.iter().map(|c| *c as u64).sum(),
+            p50: SyscallLatency::percentile(buckets, 50),
+            p90: SyscallLatency::percentile(buckets, 90),
+            p99: SyscallLatency::percentile(buckets, 99),
+        }
+    }
+}
+
+impl Serialize for SyscallLatency {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        let mut s = serializer.serialize_struct("SyscallLatency", 2)?;
+        s.serialize_field("incoming", &Percentiles::new(&self.incoming))?;
+        s.serialize_field("outgoing", &Percentiles::new(&self.outgoing))?;
+        s.end()
+    }
+}
+
+/// When the steps of the handshake were sent and received, nanoseconds of the monotonic clock
+/// of the kernel as the syscall which completed the chunk tells them, 0 if unknown
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeTimes {
+    // the connection message, the metadata and the acknowledge, `[sent, received]`
+    steps: [[u64; 2]; 3],
+}
+
+impl HandshakeTimes {
+    const TAG: u8 = 0x83;
+    const STEPS: usize = 3;
+
+    /// The `step` is the number of the chunk, the first time is kept, `false` if nothing is changed
+    pub fn set(&mut self, step: u64, incoming: bool, ktime: u64) -> bool {
+        match self.steps.get_mut(step as usize) {
+            Some(times) if times[incoming as usize] == 0 && ktime != 0 => {
+                times[incoming as usize] = ktime;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.steps.iter().flatten().all(|t| *t == 0)
+    }
+
+    /// When the local node sent the chunk `step`, `None` if unknown
+    pub fn sent(&self, step: u64) -> Option<u64> {
+        self.steps.get(step as usize).map(|s| s[0]).filter(|t| *t != 0)
+    }
+
+    /// When the local node received the chunk `step` of the peer, `None` if unknown
+    pub fn received(&self, step: u64) -> Option<u64> {
+        self.steps.get(step as usize).map(|s| s[1]).filter(|t| *t != 0)
+    }
+
+    // both sides did the step, the later one completes it
+    fn done(&self, step: usize) -> Option<u64> {
+        let [sent, received] = self.steps[step];
+        if sent == 0 || received == 0 {
+            None
+        } else {
+            Some(sent.max(received))
+        }
+    }
+
+    // the first connection message
+    fn start(&self) -> Option<u64> {
+        let [sent, received] = self.steps[0];
+        match (sent, received) {
+            (0, 0) => None,
+            (0, t) | (t, 0) => Some(t),
+            (sent, received) => Some(sent.min(received)),
+        }
+    }
+
+    /// Nanoseconds from the first connection message to the last acknowledge,
+    /// `None` if the handshake is not complete
+    pub fn total(&self) -> Option<u64> {
+        Some(self.done(2)?.saturating_sub(self.start()?))
+    }
+
+    // * bytes layout: `[tag(1)]([sent(8)][received(8)]*3)`,
+    // absent if no step is timed or in the old database
+    fn ser(&self, v: &mut Vec<u8>) {
+        if self.is_empty() {
+            return;
+        }
+        v.push(Self::TAG);
+        for time in self.steps.iter().flatten() {
+            v.extend_from_slice(&time.to_le_bytes());
+        }
+    }
+
+    fn de(bytes: &mut &[u8]) -> Result<Self, SchemaError> {
+        let rest = match bytes.split_first() {
+            Some((&Self::TAG, rest)) if rest.len() >= Self::STEPS * 16 => rest,
+            Some((&Self::TAG, _)) => return Err(SchemaError::DecodeError),
+            _ => return Ok(HandshakeTimes::default()),
+        };
+        let mut times = HandshakeTimes::default();
+        let values = rest[..(Self::STEPS * 16)]
+            .chunks(8)
+            .map(|b| u64::from_le_bytes(TryFrom::try_from(b).unwrap()));
+        for (time, value) in times.steps.iter_mut().flatten().zip(values) {
+            *time = value;
+        }
+        *bytes = &rest[(Self::STEPS * 16)..];
+        Ok(times)
+    }
+}
+
+impl Serialize for HandshakeTimes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        let cm = self.done(0);
+        let since = |later: Option<u64>, earlier: Option<u64>| {
+            Some(later?.saturating_sub(earlier?))
+        };
+        let mut s = serializer.serialize_struct("HandshakeTimes", 4)?;
+        // the round trip of the connection messages
+        s.serialize_field("connection_message", &since(cm, self.start()))?;
+        s.serialize_field("metadata", &since(self.done(1), cm))?;
+        s.serialize_field("ack", &since(self.done(2), self.done(1)))?;
+        s.serialize_field("total", &self.total())?;
+        s.end()
+    }
+}
+
+/// Acknowledge messages received from the remote peer and sent by the local node
+#[derive(Debug, Clone, Default)]
+pub struct Acks {
+    pub incoming: Option<AckInfo>,
+    pub outgoing: Option<AckInfo>,
+}
+
+impl Acks {
+    /// The motive if any side rejected the connection
+    pub fn nack_motive(&self) -> Option<NackMotive> {
+        let incoming = self.incoming.as_ref().and_then(AckInfo::nack_motive);
+        let outgoing = self.outgoing.as_ref().and_then(AckInfo::nack_motive);
+        incoming.or(outgoing)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Item {
     pub ts: u64,
@@ -155,6 +933,13 @@ pub struct Item {
     pub remote_addr: SocketAddr,
     peer_pk: [u8; 32],
     comments: Comments,
+    acks: Acks,
+    termination: Option<Termination>,
+    tcp: Option<TcpStats>,
+    throughput: Throughput,
+    syscalls: SyscallLatency,
+    handshake: HandshakeTimes,
+    external: bool,
 }
 
 impl Item {
@@ -176,6 +961,42 @@ impl Item {
             remote_addr,
             peer_pk: [0; 32],
             comments: Comments::default(),
+            acks: Acks::default(),
+            termination: None,
+            tcp: None,
+            throughput: Throughput::default(),
+            syscalls: SyscallLatency::default(),
+            handshake: HandshakeTimes::default(),
+            external: false,
+        }
+    }
+
+    /// The outbound connection of the node to some other service, only the address and the time
+    /// it is opened are known, the payload is not recorded
+    pub fn external(remote_addr: SocketAddr) -> Self {
+        Item {
+            external: true,
+            ..Item::new(Initiator::Local, remote_addr)
+        }
+    }
+
+    /// The first end is kept, the remote half-close is usually followed by the local close,
+    /// but the reset is kept anyway, `false` if nothing is changed
+    pub fn terminate(&mut self, kind: TerminationKind) -> bool {
+        match &self.termination {
+            Some(t) if t.kind == TerminationKind::Reset || kind != TerminationKind::Reset => false,
+            _ => {
+                self.termination = Some(Termination::new(kind));
+                true
+            },
+        }
+    }
+
+    pub fn set_ack(&mut self, sender: &Sender, ack: AckInfo) {
+        if sender.incoming() {
+            self.acks.incoming = Some(ack);
+        } else {
+            self.acks.outgoing = Some(ack);
         }
     }
 
@@ -183,6 +1004,36 @@ impl Item {
         self.peer_pk = peer_pk;
     }
 
+    pub fn set_tcp(&mut self, tcp: TcpStats) {
+        self.tcp = Some(tcp);
+    }
+
+    /// `true` if the bucket is the new one, see `Throughput::add`
+    pub fn count_bytes(&mut self, incoming: bool, bytes: usize) -> bool {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.throughput.add(now, incoming, bytes)
+    }
+
+    /// `latency` of the read or the write in nanoseconds
+    pub fn count_syscall(&mut self, incoming: bool, latency: u64) {
+        self.syscalls.add(incoming, latency);
+    }
+
+    /// The chunk `step` of the handshake is sent or received at `ktime`,
+    /// `false` if it is timed already
+    pub fn set_handshake_time(&mut self, step: u64, incoming: bool, ktime: u64) -> bool {
+        self.handshake.set(step, incoming, ktime)
+    }
+
+    pub fn is_partial(&self) -> bool {
+        self.comments.partial
+    }
+
     pub fn add_comment(&mut self) -> &mut Comments {
         &mut self.comments
     }
@@ -219,16 +1070,43 @@ impl Item {
         }
     }
 
+    /// The decryption of the side locked onto the stream again,
+    /// `skipped` chunks are missed, or recorded as is
+    pub fn mark_resynced<S>(&mut self, skipped: u64)
+    where
+        S: Bit,
+    {
+        log::info!("resynced: {}-{}, skipped: {}", self.key(), Sender::new(S::BOOL), skipped);
+        if S::BOOL {
+            self.add_comment().incoming_resynced += skipped;
+        } else {
+            self.add_comment().outgoing_resynced += skipped;
+        }
+    }
+
     #[rustfmt::skip]
     pub fn split(self) -> (Key, Value) {
-        let Item { ts, ts_nanos, initiator, remote_addr, peer_pk, comments } = self;
-        (Key { ts, ts_nanos }, Value { initiator, remote_addr, peer_pk, comments })
+        let Item {
+            ts, ts_nanos, initiator, remote_addr, peer_pk, comments, acks, termination, tcp,
+            throughput, syscalls, handshake, external,
+        } = self;
+        let value = Value {
+            initiator, remote_addr, peer_pk, comments, acks, termination, tcp, throughput,
+            syscalls, handshake, external,
+        };
+        (Key { ts, ts_nanos }, value)
     }
 
     #[rustfmt::skip]
     pub fn unite(key: Key, value: Value) -> Self {
-        let (Key { ts, ts_nanos }, Value { initiator, remote_addr, peer_pk, comments }) = (key, value);
-        Item { ts, ts_nanos, initiator, remote_addr, peer_pk, comments }
+        let (Key { ts, ts_nanos }, Value {
+            initiator, remote_addr, peer_pk, comments, acks, termination, tcp, throughput,
+            syscalls, handshake, external,
+        }) = (key, value);
+        Item {
+            ts, ts_nanos, initiator, remote_addr, peer_pk, comments, acks, termination, tcp,
+            throughput, syscalls, handshake, external,
+        }
     }
 
     pub fn key(&self) -> Key {
@@ -244,6 +1122,13 @@ impl Item {
             remote_addr: self.remote_addr,
             peer_pk: self.peer_pk,
             comments: self.comments.clone(),
+            acks: self.acks.clone(),
+            termination: self.termination.clone(),
+            tcp: self.tcp.clone(),
+            throughput: self.throughput.clone(),
+            syscalls: self.syscalls.clone(),
+            handshake: self.handshake.clone(),
+            external: self.external,
         }
     }
 }
@@ -320,12 +1205,74 @@ impl Decoder for Key {
     }
 }
 
-// ip 16 bytes, port 2 bytes, initiator 1 byte, padding 1 byte, comments 36 bytes, peer_pk 32 bytes
+// ip 16 bytes, port 2 bytes, initiator 1 byte, flags 1 byte, the lowest bit is `external`,
+// comments 36 bytes, peer_pk 32 bytes,
+// incoming and outgoing acknowledge message, variable length, absent in the old database,
+// termination 9 bytes, absent if the connection is alive or in the old database,
+// tcp metadata 95 bytes, absent if it is not captured or in the old database,
+// throughput buckets, variable length, absent if nothing is transferred or in the old database,
+// syscall latency 257 bytes, absent if no syscall is timed or in the old database,
+// handshake times 49 bytes, absent if no step is timed or in the old database
 pub struct Value {
     initiator: Initiator,
     remote_addr: SocketAddr,
     peer_pk: [u8; 32],
     comments: Comments,
+    acks: Acks,
+    termination: Option<Termination>,
+    tcp: Option<TcpStats>,
+    throughput: Throughput,
+    syscalls: SyscallLatency,
+    handshake: HandshakeTimes,
+    external: bool,
+}
+
+impl Value {
+    pub fn initiator(&self) -> &Initiator {
+        &self.initiator
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Zeros if the connection message of the peer is missed
+    pub fn peer_pk(&self) -> &[u8; 32] {
+        &self.peer_pk
+    }
+
+    pub fn acks(&self) -> &Acks {
+        &self.acks
+    }
+
+    pub fn comments(&self) -> &Comments {
+        &self.comments
+    }
+
+    pub fn termination(&self) -> Option<&Termination> {
+        self.termination.as_ref()
+    }
+
+    pub fn tcp(&self) -> Option<&TcpStats> {
+        self.tcp.as_ref()
+    }
+
+    pub fn throughput(&self) -> &Throughput {
+        &self.throughput
+    }
+
+    pub fn syscalls(&self) -> &SyscallLatency {
+        &self.syscalls
+    }
+
+    pub fn handshake(&self) -> &HandshakeTimes {
+        &self.handshake
+    }
+
+    /// Not the tezos peer, see `Item::external`
+    pub fn is_external(&self) -> bool {
+        self.external
+    }
 }
 
 impl Encoder for Value {
@@ -342,7 +1289,7 @@ impl Encoder for Value {
         v.extend_from_slice(&self.remote_addr.port().to_le_bytes());
 
         v.push(if self.initiator.incoming() { 1 } else { 0 });
-        v.push(0);
+        v.push(if self.external { 1 } else { 0 });
 
         let (i, o) = self.comments.ser();
         v.extend_from_slice(&i);
@@ -350,16 +1297,43 @@ impl Encoder for Value {
 
         v.extend_from_slice(&self.peer_pk);
 
+        AckInfo::ser(&self.acks.incoming, &mut v);
+        AckInfo::ser(&self.acks.outgoing, &mut v);
+
+        Termination::ser(&self.termination, &mut v);
+        TcpStats::ser(&self.tcp, &mut v);
+        self.throughput.ser(&mut v);
+        self.syscalls.ser(&mut v);
+        self.handshake.ser(&mut v);
+
         Ok(v)
     }
 }
 
 impl Decoder for Value {
     fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
-        if bytes.len() != 88 {
+        if bytes.len() < 88 {
             return Err(SchemaError::DecodeError);
         }
 
+        let (acks, termination, tcp, throughput, syscalls, handshake) = if bytes.len() == 88 {
+            let syscalls = SyscallLatency::default();
+            let handshake = HandshakeTimes::default();
+            (Acks::default(), None, None, Throughput::default(), syscalls, handshake)
+        } else {
+            let mut rest = &bytes[88..];
+            let acks = Acks {
+                incoming: AckInfo::de(&mut rest)?,
+                outgoing: AckInfo::de(&mut rest)?,
+            };
+            let termination = Termination::de(&mut rest)?;
+            let tcp = TcpStats::de(&mut rest)?;
+            let throughput = Throughput::de(&mut rest)?;
+            let syscalls = SyscallLatency::de(&mut rest)?;
+            let handshake = HandshakeTimes::de(&mut rest)?;
+            (acks, termination, tcp, throughput, syscalls, handshake)
+        };
+
         Ok(Value {
             initiator: Initiator::new(bytes[18] != 0),
             remote_addr: {
@@ -373,6 +1347,13 @@ impl Decoder for Value {
                 let o = TryFrom::try_from(&bytes[38..56]).unwrap();
                 Comments::de((i, o))
             },
+            acks,
+            termination,
+            tcp,
+            throughput,
+            syscalls,
+            handshake,
+            external: bytes[19] & 1 != 0,
         })
     }
 }
@@ -382,27 +1363,25 @@ impl Serialize for Value {
     where
         S: ser::Serializer,
     {
-        use crypto::{blake2b, hash::HashType};
-
-        let calc_peer_id = || -> Result<String, String> {
-            if self.peer_pk == [0; 32] {
-                return Err("unknown".to_string());
-            }
-            let hash = blake2b::digest_128(&self.peer_pk).map_err(|e| e.to_string())?;
-            HashType::CryptoboxPublicKeyHash
-                .hash_to_b58check(&hash)
-                .map_err(|e| e.to_string())
-        };
-        let peer_id = match calc_peer_id() {
+        let peer_id = match super::peer::peer_id(&self.peer_pk) {
             Ok(s) => s,
             Err(s) => s,
         };
 
-        let mut s = serializer.serialize_struct("Connection", 4)?;
+        let mut s = serializer.serialize_struct("Connection", 12)?;
         s.serialize_field("initiator", &self.initiator)?;
         s.serialize_field("remote_addr", &self.remote_addr)?;
         s.serialize_field("peer_id", &peer_id)?;
         s.serialize_field("comments", &self.comments)?;
+        s.serialize_field("incoming_ack", &self.acks.incoming)?;
+        s.serialize_field("outgoing_ack", &self.acks.outgoing)?;
+        s.serialize_field("termination", &self.termination)?;
+        s.serialize_field("tcp", &self.tcp)?;
+        s.serialize_field("throughput", &self.throughput)?;
+        s.serialize_field("syscalls", &self.syscalls)?;
+        let handshake = Some(&self.handshake).filter(|h| !h.is_empty());
+        s.serialize_field("handshake", &handshake)?;
+        s.serialize_field("external", &self.external)?;
         s.end()
     }
 }
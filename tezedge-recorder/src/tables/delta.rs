@@ -0,0 +1,173 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+//! The difference of two byte strings which mostly share the bytes at the same offsets,
+//! like the consecutive `current_head` messages of the peer, the level, the timestamp
+//! and the hashes change, the layout stays.
+//!
+//! * bytes layout: `[target length(4)]([copy(varint)][insert(varint)][inserted bytes])*`,
+//! the copy takes the bytes of the base at the offset the target is written at
+
+use std::convert::TryFrom;
+
+// the shorter run of the same bytes costs more than it saves
+const MIN_RUN: usize = 4;
+
+pub fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut v = Vec::with_capacity(target.len() / 4 + 4);
+    v.extend_from_slice(&(target.len() as u32).to_le_bytes());
+    let same = |i: usize| base.get(i) == Some(&target[i]);
+    let run = |i: usize| (i..target.len()).take_while(|j| same(*j)).take(MIN_RUN).count();
+
+    let mut i = 0;
+    while i < target.len() {
+        let copy_start = i;
+        while i < target.len() && same(i) {
+            i += 1;
+        }
+        let insert_start = i;
+        while i < target.len() {
+            let run = run(i);
+            if run == MIN_RUN {
+                break;
+            }
+            i += run.max(1);
+        }
+        write_varint(&mut v, insert_start - copy_start);
+        write_varint(&mut v, i - insert_start);
+        v.extend_from_slice(&target[insert_start..i]);
+    }
+    v
+}
+
+/// `None` if the delta is malformed or does not match the base
+pub fn apply(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let len = u32::from_le_bytes(<[u8; 4]>::try_from(delta.get(..4)?).ok()?) as usize;
+    let mut rest = &delta[4..];
+    // the length is untrusted, the target is copied from the base and the delta
+    if len > base.len() + rest.len() {
+        return None;
+    }
+    let mut v = Vec::with_capacity(len);
+    while v.len() < len {
+        let copy = read_varint(&mut rest)?;
+        let insert = read_varint(&mut rest)?;
+        if copy == 0 && insert == 0 {
+            return None;
+        }
+        let offset = v.len();
+        v.extend_from_slice(base.get(offset..offset.checked_add(copy)?)?);
+        v.extend_from_slice(rest.get(..insert)?);
+        rest = &rest[insert..];
+    }
+    if v.len() != len || !rest.is_empty() {
+        return None;
+    }
+    Some(v)
+}
+
+fn write_varint(v: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        v.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    v.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, apply, write_varint};
+
+    fn round_trip(base: &[u8], target: &[u8]) -> Vec<u8> {
+        let delta = encode(base, target);
+        assert_eq!(apply(base, &delta).as_deref(), Some(target));
+        delta
+    }
+
+    fn head(level: u32) -> Vec<u8> {
+        let mut v = vec![0, 0, 0, 0x80, 0, 0x13];
+        v.extend_from_slice(&level.to_be_bytes());
+        v.extend_from_slice(&[0xab; 0x78]);
+        v.extend_from_slice(&(level * 7).to_be_bytes());
+        v
+    }
+
+    #[test]
+    fn identical() {
+        let delta = round_trip(&head(1), &head(1));
+        // the length and the single copy
+        assert!(delta.len() <= 8);
+    }
+
+    #[test]
+    fn similar() {
+        let delta = round_trip(&head(1), &head(2));
+        assert!(delta.len() * 4 < head(2).len());
+    }
+
+    #[test]
+    fn shorter() {
+        let base = head(1);
+        round_trip(&base, &base[..0x40]);
+        round_trip(&base, &[]);
+    }
+
+    #[test]
+    fn longer() {
+        let base = head(1);
+        let mut target = base.clone();
+        target.extend_from_slice(b"appended after the end of the base");
+        round_trip(&base, &target);
+        round_trip(&[], &target);
+    }
+
+    #[test]
+    fn different() {
+        let base = vec![0x55; 0x100];
+        let target = (0..0x100).map(|i| i as u8).collect::<Vec<_>>();
+        let delta = round_trip(&base, &target);
+        assert!(delta.len() > target.len());
+    }
+
+    #[test]
+    fn malformed() {
+        let base = head(1);
+        let delta = encode(&base, &head(2));
+        // truncated
+        assert_eq!(apply(&base, &delta[..2]), None);
+        assert_eq!(apply(&base, &delta[..(delta.len() - 1)]), None);
+        // trailing bytes
+        let mut v = delta.clone();
+        v.push(0);
+        assert_eq!(apply(&base, &v), None);
+        // the copy beyond the base
+        assert_eq!(apply(&base[..10], &delta), None);
+        // the empty step would loop forever
+        let mut v = 1u32.to_le_bytes().to_vec();
+        v.extend_from_slice(&[0, 0]);
+        assert_eq!(apply(&base, &v), None);
+        // the varint which does not end
+        let mut v = 1u32.to_le_bytes().to_vec();
+        v.extend_from_slice(&[0xff; 12]);
+        assert_eq!(apply(&base, &v), None);
+        // the huge length is refused before anything is allocated
+        let mut v = u32::MAX.to_le_bytes().to_vec();
+        write_varint(&mut v, 0);
+        write_varint(&mut v, 1);
+        v.push(0);
+        assert_eq!(apply(&base, &v), None);
+    }
+}
@@ -0,0 +1,60 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::net::SocketAddr;
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+use storage::persistent::{BincodeEncoded, KeyValueSchema, database::RocksDbKeyValueSchema};
+use super::common::Sender;
+
+/// The part of the p2p specification the handshake violates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// the length in the header of the chunk is not the length of the chunk,
+    /// or of the encrypted message
+    ChunkLength,
+    /// the nonce of the connection message is reused, or the chunks are not encrypted
+    /// with the nonces the connection messages imply
+    Nonce,
+    /// the step of the handshake is sent before the step of the peer it depends on
+    Ordering,
+    /// the versions of the connection messages do not match, yet the side acknowledged
+    VersionNegotiation,
+}
+
+/// The violation of the p2p specification found in the recorded handshake
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Item {
+    /// the connection id, like `1617005682.953928051`
+    pub connection: String,
+    pub remote_addr: SocketAddr,
+    /// the side which violates
+    pub sender: Sender,
+    /// the number of the chunk, 0 is the connection message, 1 the metadata, 2 the acknowledge
+    pub counter: u64,
+    pub rule: Rule,
+    pub detail: String,
+}
+
+impl BincodeEncoded for Item {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItemWithId {
+    pub id: u64,
+    #[serde(flatten)]
+    pub item: Item,
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = u64;
+    type Value = Item;
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn name() -> &'static str {
+        "conformance_storage"
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+use storage::persistent::{BincodeEncoded, KeyValueSchema, database::RocksDbKeyValueSchema};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// the recorder was not running
+    Down,
+    /// the capture was paused through the api
+    Paused,
+    /// the data was dropped by the overflown decoder queue or lost in the ring buffer
+    Lost,
+}
+
+/// The interval when the capture missed the traffic of the node, the timestamps are
+/// unix milliseconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Item {
+    pub kind: Kind,
+    pub from: u64,
+    /// `None` while it lasts
+    pub to: Option<u64>,
+}
+
+impl Item {
+    pub fn overlaps(&self, from: u64, to: u64) -> bool {
+        self.from <= to && self.to.map_or(true, |end| end >= from)
+    }
+}
+
+impl BincodeEncoded for Item {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItemWithId {
+    pub id: u64,
+    #[serde(flatten)]
+    pub item: Item,
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = u64;
+    type Value = Item;
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn name() -> &'static str {
+        "gap_storage"
+    }
+}
+
+/// The single entry, the key is zero, the value is the last time the recorder was alive,
+/// unix milliseconds
+pub struct HeartbeatSchema;
+
+impl HeartbeatSchema {
+    pub const KEY: u64 = 0;
+}
+
+impl KeyValueSchema for HeartbeatSchema {
+    type Key = u64;
+    type Value = u64;
+}
+
+impl RocksDbKeyValueSchema for HeartbeatSchema {
+    fn name() -> &'static str {
+        "heartbeat_storage"
+    }
+}
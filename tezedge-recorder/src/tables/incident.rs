@@ -0,0 +1,77 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::net::IpAddr;
+use serde::{Serialize, Deserialize};
+use storage::persistent::{BincodeEncoded, KeyValueSchema, database::RocksDbKeyValueSchema};
+
+/// The handshakes and the closed connections from the range during one minute
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Minute {
+    /// unix milliseconds of the beginning of the minute
+    pub timestamp: u64,
+    pub accepted: u64,
+    pub closed: u64,
+}
+
+/// The connection flood from one address range, the timestamps are unix milliseconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Item {
+    /// like `51.15.220.0/24`
+    pub range: String,
+    pub started: u64,
+    /// the end of the last minute of the flood, `None` while it lasts
+    pub ended: Option<u64>,
+    pub handshakes: u64,
+    /// the distinct addresses of the range seen during the flood, at most `MAX_ADDRESSES`
+    pub addresses: Vec<IpAddr>,
+    /// every minute of the flood
+    pub timeline: Vec<Minute>,
+}
+
+impl Item {
+    pub const MAX_ADDRESSES: usize = 256;
+
+    pub fn new(range: String, started: u64) -> Self {
+        Item {
+            range,
+            started,
+            ended: None,
+            handshakes: 0,
+            addresses: vec![],
+            timeline: vec![],
+        }
+    }
+
+    pub fn add_address(&mut self, address: IpAddr) {
+        if self.addresses.len() < Self::MAX_ADDRESSES && !self.addresses.contains(&address) {
+            self.addresses.push(address);
+        }
+    }
+
+    pub fn overlaps(&self, from: u64, to: u64) -> bool {
+        self.started <= to && self.ended.map_or(true, |ended| ended >= from)
+    }
+}
+
+impl BincodeEncoded for Item {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItemWithId {
+    pub id: u64,
+    #[serde(flatten)]
+    pub item: Item,
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = u64;
+    type Value = Item;
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn name() -> &'static str {
+        "incident_storage"
+    }
+}
@@ -1,7 +1,12 @@
 // Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
 // SPDX-License-Identifier: MIT
 
-use std::{net::SocketAddr, ops::Range, convert::TryFrom};
+use std::{
+    net::SocketAddr,
+    ops::Range,
+    convert::TryFrom,
+    sync::{Arc, RwLock},
+};
 use serde::{Deserialize, Serialize, ser};
 use storage::persistent::{KeyValueSchema, BincodeEncoded, database::RocksDbKeyValueSchema};
 use tezos_messages::p2p::{
@@ -15,7 +20,8 @@ use tezos_messages::p2p::{
 };
 use super::{
     common::{Initiator, Sender, MessageCategory, MessageKind, MessageType},
-    connection, chunk,
+    connection, chunk, annotation,
+    message_hash::ContentHash,
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,14 +34,23 @@ pub struct Item {
     pub sender: Sender,
     pub ty: MessageType,
     chunks: Range<u64>,
+    /// the blocks, operations and protocols the message mentions
+    pub hashes: Vec<ContentHash>,
+    /// the bytes of its chunks on the wire, counted in the bandwidth, it is not stored
+    #[serde(skip)]
+    pub wire_bytes: u64,
 }
 
 impl Item {
-    pub fn chunks(&self) -> impl Iterator<Item = chunk::Key> + '_ {
-        let cn_id = connection::Key {
+    pub fn cn_id(&self) -> connection::Key {
+        connection::Key {
             ts: self.cn_ts,
             ts_nanos: self.cn_ts_nanos,
-        };
+        }
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = chunk::Key> + '_ {
+        let cn_id = self.cn_id();
         let sender = self.sender.clone();
         self.chunks.clone().map(move |counter| chunk::Key {
             cn_id: cn_id.clone(),
@@ -48,17 +63,33 @@ impl Item {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageFrontend {
     pub id: u64,
-    timestamp: u128,
-    remote_addr: SocketAddr,
+    pub timestamp: u128,
+    pub remote_addr: SocketAddr,
     pub source_type: Initiator,
     pub incoming: bool,
     pub category: MessageCategory,
     pub kind: Option<MessageKind>,
-    message_preview: Option<String>,
+    pub message_preview: Option<String>,
+    /// the length of the decrypted message in bytes
+    #[serde(default)]
+    pub size: usize,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<annotation::ItemWithId>,
 }
 
 impl MessageFrontend {
-    pub fn new(item: Item, id: u64, message_preview: Option<String>) -> Self {
+    pub const CSV_COLUMNS: &'static [&'static str] = &[
+        "id",
+        "timestamp",
+        "remote_addr",
+        "source_type",
+        "incoming",
+        "category",
+        "kind",
+        "size",
+    ];
+
+    pub fn new(item: Item, id: u64, message_preview: Option<String>, size: usize) -> Self {
         let (category, kind) = item.ty.split();
         MessageFrontend {
             id,
@@ -69,16 +100,144 @@ impl MessageFrontend {
             category,
             kind,
             message_preview,
+            size,
+            annotations: vec![],
+        }
+    }
+
+    pub fn annotate(&mut self, annotations: &[annotation::ItemWithId]) {
+        let timestamp = (self.timestamp / 1_000_000) as u64;
+        self.annotations = annotation::ItemWithId::of_message(annotations, self.id, timestamp);
+    }
+
+    /// Serializes only the fields of the projection
+    pub fn project<'a>(&'a self, projection: &'a Projection) -> Projected<'a> {
+        Projected {
+            message: self,
+            projection,
         }
     }
 }
 
+/// The fields of `MessageFrontend` the client asked for, in the order of the struct
+#[derive(Clone)]
+pub struct Projection(Vec<&'static str>);
+
+impl Projection {
+    const FIELDS: &'static [&'static str] = &[
+        "id",
+        "timestamp",
+        "remote_addr",
+        "source_type",
+        "incoming",
+        "category",
+        "kind",
+        "message_preview",
+        "size",
+        "annotations",
+    ];
+
+    /// Comma separated names of the fields, `type` stands for `category` and `kind`,
+    /// `peer` for `remote_addr` and `preview` for `message_preview`, `None` means every field
+    pub fn parse(fields: Option<&str>) -> Result<Option<Self>, String> {
+        let fields = match fields {
+            Some(fields) => fields,
+            None => return Ok(None),
+        };
+        let mut selected = vec![];
+        for name in fields.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name {
+                "type" => selected.extend_from_slice(&["category", "kind"]),
+                "peer" => selected.push("remote_addr"),
+                "preview" => selected.push("message_preview"),
+                name => match Self::FIELDS.iter().find(|field| **field == name) {
+                    Some(field) => selected.push(*field),
+                    None => return Err(format!("unknown field {:?}", name)),
+                },
+            }
+        }
+        let fields = Self::FIELDS
+            .iter()
+            .filter(|field| selected.contains(field))
+            .cloned()
+            .collect();
+        Ok(Some(Projection(fields)))
+    }
+
+    pub fn contains(&self, field: &str) -> bool {
+        self.0.iter().any(|f| *f == field)
+    }
+
+    /// The csv columns of the projection
+    pub fn columns(&self) -> Vec<&'static str> {
+        MessageFrontend::CSV_COLUMNS
+            .iter()
+            .filter(|column| self.contains(column))
+            .cloned()
+            .collect()
+    }
+}
+
+pub struct Projected<'a> {
+    message: &'a MessageFrontend,
+    projection: &'a Projection,
+}
+
+impl<'a> Serialize for Projected<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        use self::ser::SerializeMap;
+
+        let m = self.message;
+        let fields = self
+            .projection
+            .0
+            .iter()
+            .filter(|field| **field != "annotations" || !m.annotations.is_empty());
+        let mut s = serializer.serialize_map(Some(fields.clone().count()))?;
+        for field in fields {
+            match *field {
+                "id" => s.serialize_entry(field, &m.id)?,
+                "timestamp" => s.serialize_entry(field, &m.timestamp)?,
+                "remote_addr" => s.serialize_entry(field, &m.remote_addr)?,
+                "source_type" => s.serialize_entry(field, &m.source_type)?,
+                "incoming" => s.serialize_entry(field, &m.incoming)?,
+                "category" => s.serialize_entry(field, &m.category)?,
+                "kind" => s.serialize_entry(field, &m.kind)?,
+                "message_preview" => s.serialize_entry(field, &m.message_preview)?,
+                "size" => s.serialize_entry(field, &m.size)?,
+                _ => s.serialize_entry(field, &m.annotations)?,
+            }
+        }
+        s.end()
+    }
+}
+
 #[derive(Debug)]
 pub enum TezosMessage {
     ConnectionMessage(ConnectionMessage),
     MetadataMessage(MetadataMessage),
     AckMessage(AckMessage),
     PeerMessage(PeerMessage),
+    /// decoded by the registered decoder
+    Custom(serde_json::Value),
+}
+
+impl Serialize for TezosMessage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        match self {
+            TezosMessage::ConnectionMessage(m) => m.serialize(serializer),
+            TezosMessage::MetadataMessage(m) => m.serialize(serializer),
+            TezosMessage::AckMessage(m) => m.serialize(serializer),
+            TezosMessage::PeerMessage(m) => m.serialize(serializer),
+            TezosMessage::Custom(m) => m.serialize(serializer),
+        }
+    }
 }
 
 impl TezosMessage {
@@ -88,10 +247,64 @@ impl TezosMessage {
             TezosMessage::MetadataMessage(m) => serde_json::to_string(m),
             TezosMessage::AckMessage(m) => serde_json::to_string(m),
             TezosMessage::PeerMessage(m) => serde_json::to_string(m),
+            TezosMessage::Custom(m) => serde_json::to_string(m),
         }
     }
 }
 
+/// Decodes the message from its decrypted bytes, the custom or experimental messages
+/// are decoded by the decoders registered in the `DecoderRegistry`
+pub trait MessageDecoder: Send + Sync {
+    /// `None` if the decoder does not handle the message, the next decoder is tried then
+    fn decode(&self, ty: &MessageType, bytes: &[u8]) -> Option<Result<TezosMessage, String>>;
+}
+
+/// The messages of the tezos protocol, as the `tezos_messages` crate decodes them
+pub struct TezosDecoder;
+
+impl MessageDecoder for TezosDecoder {
+    fn decode(&self, ty: &MessageType, bytes: &[u8]) -> Option<Result<TezosMessage, String>> {
+        let message = match ty {
+            MessageType::Connection => ConnectionMessage::from_bytes(bytes)
+                .map_err(|e| e.to_string())
+                .map(TezosMessage::ConnectionMessage),
+            MessageType::Meta => MetadataMessage::from_bytes(bytes)
+                .map_err(|e| e.to_string())
+                .map(TezosMessage::MetadataMessage),
+            MessageType::Ack => AckMessage::from_bytes(bytes)
+                .map_err(|e| e.to_string())
+                .map(TezosMessage::AckMessage),
+            MessageType::P2p(_) => PeerMessageResponse::from_bytes(bytes)
+                .map_err(|e| e.to_string())
+                .map(|n| TezosMessage::PeerMessage(n.message().clone())),
+        };
+        Some(message)
+    }
+}
+
+/// The decoders of the messages, the one registered last is tried first,
+/// the `TezosDecoder` is the last resort, the clones share the decoders
+#[derive(Clone, Default)]
+pub struct DecoderRegistry {
+    decoders: Arc<RwLock<Vec<Arc<dyn MessageDecoder>>>>,
+}
+
+impl DecoderRegistry {
+    pub fn register(&self, decoder: Arc<dyn MessageDecoder>) {
+        self.decoders.write().unwrap().push(decoder);
+    }
+
+    pub fn decode(&self, ty: &MessageType, bytes: &[u8]) -> Result<TezosMessage, String> {
+        let decoders = self.decoders.read().unwrap();
+        decoders
+            .iter()
+            .rev()
+            .find_map(|decoder| decoder.decode(ty, bytes))
+            .or_else(|| TezosDecoder.decode(ty, bytes))
+            .unwrap_or_else(|| Err("no decoder".to_string()))
+    }
+}
+
 #[derive(Debug)]
 pub struct MessageDetails {
     id: u64,
@@ -131,13 +344,7 @@ impl Serialize for MessageDetails {
 
         let mut s = serializer.serialize_struct("MessageDetails", 5)?;
         s.serialize_field("id", &self.id)?;
-        match &self.message {
-            Some(TezosMessage::ConnectionMessage(m)) => s.serialize_field("message", m)?,
-            Some(TezosMessage::MetadataMessage(m)) => s.serialize_field("message", m)?,
-            Some(TezosMessage::AckMessage(m)) => s.serialize_field("message", m)?,
-            Some(TezosMessage::PeerMessage(m)) => s.serialize_field("message", m)?,
-            None => s.serialize_field("message", &None::<()>)?,
-        }
+        s.serialize_field("message", &self.message)?;
         s.serialize_field("original_bytes", &HexString(&self.original_bytes))?;
         s.serialize_field("decrypted_bytes", &HexString(&self.decrypted_bytes))?;
         s.serialize_field("error", &self.error)?;
@@ -145,42 +352,63 @@ impl Serialize for MessageDetails {
     }
 }
 
+/// The decoded payload of the message and its decrypted bytes as one hex string,
+/// it is loaded on demand, the list of the messages carries only the preview
+pub struct MessageBody<'a>(&'a MessageDetails);
+
+impl<'a> Serialize for MessageBody<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        use self::ser::SerializeStruct;
+
+        let details = self.0;
+        let mut s = serializer.serialize_struct("MessageBody", 5)?;
+        s.serialize_field("id", &details.id)?;
+        s.serialize_field("size", &details.size())?;
+        s.serialize_field("message", &details.message)?;
+        s.serialize_field("hex", &hex::encode(details.decrypted_bytes.concat()))?;
+        s.serialize_field("error", &details.error)?;
+        s.end()
+    }
+}
+
 impl MessageDetails {
-    pub fn new(id: u64, ty: &MessageType, chunks: &[chunk::Value]) -> Self {
+    pub fn new(
+        id: u64,
+        ty: &MessageType,
+        chunks: &[chunk::Value],
+        decoders: &DecoderRegistry,
+    ) -> Self {
         let mut bytes = Vec::with_capacity(chunks.iter().map(|c| c.plain.len()).sum());
         for c in chunks {
             bytes.extend_from_slice(&c.plain);
         }
-        let message = match ty {
-            MessageType::Connection => ConnectionMessage::from_bytes(&bytes)
-                .map_err(|e| e.to_string())
-                .map(TezosMessage::ConnectionMessage),
-            MessageType::Meta => MetadataMessage::from_bytes(&bytes)
-                .map_err(|e| e.to_string())
-                .map(TezosMessage::MetadataMessage),
-            MessageType::Ack => AckMessage::from_bytes(&bytes)
-                .map_err(|e| e.to_string())
-                .map(TezosMessage::AckMessage),
-            MessageType::P2p(_) => PeerMessageResponse::from_bytes(&bytes)
-                .map_err(|e| e.to_string())
-                .map(|n| TezosMessage::PeerMessage(n.message().clone())),
-        };
-        let (message, error) = match message {
+        let (message, error) = match decoders.decode(ty, &bytes) {
             Ok(m) => (Some(m), None),
             Err(e) => (None, Some(e)),
         };
         MessageDetails {
             id,
             message,
-            original_bytes: chunks.iter().map(|c| c.bytes.clone()).collect(),
-            decrypted_bytes: chunks.iter().map(|c| c.plain.clone()).collect(),
+            original_bytes: chunks.iter().map(|c| c.bytes.to_vec()).collect(),
+            decrypted_bytes: chunks.iter().map(|c| c.plain.to_vec()).collect(),
             error,
         }
     }
 
+    pub fn body(&self) -> MessageBody<'_> {
+        MessageBody(self)
+    }
+
     pub fn json_string(&self) -> Result<Option<String>, serde_json::Error> {
         self.message.as_ref().map(|m| m.json_string()).transpose()
     }
+
+    pub fn size(&self) -> usize {
+        self.decrypted_bytes.iter().map(Vec::len).sum()
+    }
 }
 
 pub struct MessageBuilder {
@@ -266,6 +494,8 @@ impl MessageBuilderFull {
             sender: sender.clone(),
             ty: self.0.ty,
             chunks: self.0.chunks,
+            hashes: vec![],
+            wire_bytes: 0,
         }
     }
 }
@@ -7,6 +7,14 @@ pub mod connection;
 pub mod chunk;
 pub mod message;
 pub mod node_log;
+pub mod peer;
+pub mod annotation;
+pub mod session;
+pub mod incident;
+pub mod finding;
+pub mod preset;
+pub mod gap;
 
+mod delta;
 mod secondary_indexes;
 pub use self::secondary_indexes::*;
@@ -5,6 +5,7 @@ use std::{str::FromStr, convert::TryFrom};
 use thiserror::Error;
 use serde::{Serialize, Deserialize};
 use storage::persistent::{BincodeEncoded, KeyValueSchema, database::RocksDbKeyValueSchema};
+use super::annotation;
 
 #[derive(Serialize, Deserialize)]
 pub struct ItemWithId {
@@ -16,6 +17,8 @@ pub struct ItemWithId {
     pub section: String,
     #[serde(alias = "msg")]
     pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotations: Vec<annotation::ItemWithId>,
 }
 
 impl ItemWithId {
@@ -26,8 +29,14 @@ impl ItemWithId {
             timestamp: item.timestamp,
             section: item.section,
             message: item.message,
+            annotations: vec![],
         }
     }
+
+    pub fn annotate(&mut self, annotations: &[annotation::ItemWithId]) {
+        let timestamp = (self.timestamp / 1_000_000) as u64;
+        self.annotations = annotation::ItemWithId::of_moment(annotations, timestamp);
+    }
 }
 
 /// Received logs saved in the database
@@ -64,14 +73,15 @@ impl FromStr for LogLevel {
 
     fn from_str(level: &str) -> Result<Self, Self::Err> {
         let level = level.to_lowercase();
+        // slog writes the short names, `TRCE`, `DEBG`, `ERRO`, `CRIT`
         Ok(match level.as_ref() {
-            "trace" => LogLevel::Trace,
-            "debug" => LogLevel::Debug,
+            "trace" | "trce" => LogLevel::Trace,
+            "debug" | "debg" => LogLevel::Debug,
             "info" => LogLevel::Info,
             "notice" => LogLevel::Notice,
             "warn" | "warning" => LogLevel::Warning,
-            "error" => LogLevel::Error,
-            "fatal" => LogLevel::Fatal,
+            "error" | "erro" => LogLevel::Error,
+            "fatal" | "crit" | "critical" => LogLevel::Fatal,
             _ => return Err(ParseLogLevelError::InvalidName(level)),
         })
     }
@@ -143,6 +153,13 @@ where
             });
         let line = msg.msg.as_ref();
 
+        if let Some(item) = json_log_line(line) {
+            return Item {
+                timestamp: item.timestamp.unwrap_or(timestamp),
+                ..item.item
+            };
+        }
+
         let pos = line.find('.').unwrap_or_default();
         #[allow(clippy::collapsible_else_if)]
         if pos == 15 {
@@ -181,6 +198,115 @@ where
     }
 }
 
+struct JsonLogLine {
+    timestamp: Option<u128>,
+    item: Item,
+}
+
+/// Parse json formatted log, either tezedge (slog json drain) format:
+/// {"msg":"Blacklisting IP","level":"INFO","ts":"2021-07-01T10:32:37.026683+02:00","module":"shell::peer_manager", ...}
+/// or octez file descriptor sink format:
+/// {"fd-sink-item.v0":{"hostname":"...","time_stamp":1625136757.026,"section":["p2p","maintenance"],"event":{...}}}
+/// returns `None` if the line is not a json object, so the caller falls back to the raw text
+fn json_log_line(line: &str) -> Option<JsonLogLine> {
+    use serde_json::{Map, Value};
+
+    fn get<'a>(object: &'a Map<String, Value>, keys: &[&str]) -> Option<&'a Value> {
+        keys.iter().find_map(|key| object.get(*key))
+    }
+
+    fn text(value: &Value) -> String {
+        match value {
+            Value::String(s) => s.clone(),
+            value => value.to_string(),
+        }
+    }
+
+    /// Accepts rfc3339 string or number of seconds, milliseconds, microseconds or nanoseconds
+    fn timestamp(value: &Value) -> Option<u128> {
+        match value {
+            Value::String(s) => chrono::DateTime::parse_from_rfc3339(s)
+                .ok()
+                .map(|dt| dt.timestamp_nanos() as u128)
+                .or_else(|| s.parse::<f64>().ok().and_then(|f| timestamp(&Value::from(f)))),
+            Value::Number(n) => {
+                let f = n.as_f64()?;
+                if f < 0.0 {
+                    return None;
+                }
+                let nanos = if f < 1e11 {
+                    f * 1e9
+                } else if f < 1e14 {
+                    f * 1e6
+                } else if f < 1e17 {
+                    f * 1e3
+                } else {
+                    f
+                };
+                Some(nanos as u128)
+            },
+            _ => None,
+        }
+    }
+
+    fn section(value: &Value) -> String {
+        match value {
+            Value::Array(parts) => parts.iter().map(text).collect::<Vec<_>>().join("."),
+            value => text(value),
+        }
+    }
+
+    let line = line.trim();
+    if !line.starts_with('{') {
+        return None;
+    }
+    let object = match serde_json::from_str::<Value>(line).ok()? {
+        Value::Object(object) => object,
+        _ => return None,
+    };
+
+    if let Some(Value::Object(item)) = object.get("fd-sink-item.v0") {
+        let event = item.get("event");
+        let level = event
+            .and_then(Value::as_object)
+            .and_then(|event| get(event, &["level", "lvl"]))
+            .and_then(Value::as_str)
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(LogLevel::Info);
+        return Some(JsonLogLine {
+            timestamp: item.get("time_stamp").and_then(timestamp),
+            item: Item {
+                level,
+                timestamp: 0,
+                section: item.get("section").map(section).unwrap_or_default(),
+                message: event.map(text).unwrap_or_default(),
+            },
+        });
+    }
+
+    // the unknown level is not escalated
+    let level = get(&object, &["level", "lvl", "severity"])
+        .and_then(Value::as_str)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(LogLevel::Info);
+    let message = match get(&object, &["msg", "message"]) {
+        Some(message) => text(message),
+        // not a log record, keep the whole line
+        None => line.to_string(),
+    };
+    Some(JsonLogLine {
+        timestamp: get(&object, &["ts", "time", "timestamp"]).and_then(timestamp),
+        item: Item {
+            level,
+            timestamp: 0,
+            section: get(&object, &["module", "section", "target"])
+                .map(section)
+                .unwrap_or_default(),
+            message,
+        },
+    })
+}
+
 impl BincodeEncoded for Item {}
 
 pub struct Schema;
@@ -195,3 +321,66 @@ impl RocksDbKeyValueSchema for Schema {
         "log_storage"
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{json_log_line, LogLevel};
+
+    fn level(line: &str) -> u8 {
+        json_log_line(line).unwrap().item.level as u8
+    }
+
+    #[test]
+    fn slog_line() {
+        let line = concat!(
+            r#"{"msg":"Blacklisting IP","level":"INFO","ts":"2021-07-01T10:32:37.026683+02:00","#,
+            r#""module":"shell::peer_manager","ip":"1.2.3.4"}"#,
+        );
+        let parsed = json_log_line(line).unwrap();
+        assert_eq!(parsed.item.message, "Blacklisting IP");
+        assert_eq!(parsed.item.section, "shell::peer_manager");
+        assert_eq!(parsed.timestamp, Some(1625128357026683000));
+        assert_eq!(parsed.item.level as u8, LogLevel::Info as u8);
+    }
+
+    #[test]
+    fn slog_short_levels() {
+        let line = |level: &str| format!(r#"{{"msg":"m","level":"{}"}}"#, level);
+        assert_eq!(level(&line("TRCE")), LogLevel::Trace as u8);
+        assert_eq!(level(&line("DEBG")), LogLevel::Debug as u8);
+        assert_eq!(level(&line("INFO")), LogLevel::Info as u8);
+        assert_eq!(level(&line("WARN")), LogLevel::Warning as u8);
+        assert_eq!(level(&line("ERRO")), LogLevel::Error as u8);
+        assert_eq!(level(&line("CRIT")), LogLevel::Fatal as u8);
+        // the unknown or absent level is not escalated
+        assert_eq!(level(&line("VERBOSE")), LogLevel::Info as u8);
+        assert_eq!(level(r#"{"msg":"m"}"#), LogLevel::Info as u8);
+    }
+
+    #[test]
+    fn octez_line() {
+        let line = concat!(
+            r#"{"fd-sink-item.v0":{"hostname":"h","time_stamp":1625136757.5,"#,
+            r#""section":["p2p","maintenance"],"#,
+            r#""event":{"level":"debug","msg":"too few connections"}}}"#,
+        );
+        let parsed = json_log_line(line).unwrap();
+        assert_eq!(parsed.item.section, "p2p.maintenance");
+        assert_eq!(parsed.timestamp, Some(1625136757500000000));
+        assert_eq!(parsed.item.level as u8, LogLevel::Debug as u8);
+        assert!(parsed.item.message.contains("too few connections"));
+    }
+
+    #[test]
+    fn not_a_record() {
+        assert!(json_log_line("Jul  1 10:32:37 node started").is_none());
+        assert!(json_log_line("{broken").is_none());
+        assert!(json_log_line("[1, 2]").is_none());
+        // the object without a message is kept whole
+        let line = r#"{"level":"warn","ts":1625136757}"#;
+        let parsed = json_log_line(line).unwrap();
+        assert_eq!(parsed.item.message, line);
+        assert_eq!(parsed.timestamp, Some(1625136757000000000));
+        assert_eq!(parsed.item.level as u8, LogLevel::Warning as u8);
+    }
+}
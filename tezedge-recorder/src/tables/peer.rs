@@ -0,0 +1,238 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{convert::TryFrom, net::SocketAddr};
+use serde::{Serialize, Deserialize};
+use storage::persistent::{
+    KeyValueSchema, Encoder, Decoder, SchemaError, BincodeEncoded,
+    database::RocksDbKeyValueSchema,
+};
+use tezos_messages::p2p::encoding::connection::ConnectionMessage;
+use super::connection;
+
+/// The id of the peer as the node shows it, error if the key is unknown
+pub fn peer_id(pk: &[u8; 32]) -> Result<String, String> {
+    use crypto::{blake2b, hash::HashType};
+
+    if pk == &[0; 32] {
+        return Err("unknown".to_string());
+    }
+    let hash = blake2b::digest_128(pk).map_err(|e| e.to_string())?;
+    HashType::CryptoboxPublicKeyHash
+        .hash_to_b58check(&hash)
+        .map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    pub chain_name: String,
+    pub distributed_db_version: u16,
+    pub p2p_version: u16,
+}
+
+impl Version {
+    pub fn new(msg: &ConnectionMessage) -> Self {
+        let version = msg.version();
+        Version {
+            chain_name: version.chain_name().clone(),
+            distributed_db_version: *version.distributed_db_version(),
+            p2p_version: *version.p2p_version(),
+        }
+    }
+}
+
+/// The peer as observed in the connection message it sent
+#[derive(Clone)]
+pub struct Item {
+    pub pk: [u8; 32],
+    pub cn: connection::Key,
+    pub remote_addr: SocketAddr,
+    pub version: Version,
+}
+
+impl Item {
+    pub fn new(cn: &connection::Item, msg: &ConnectionMessage) -> Option<Self> {
+        Some(Item {
+            pk: <[u8; 32]>::try_from(msg.public_key().as_slice()).ok()?,
+            cn: cn.key(),
+            remote_addr: cn.remote_addr,
+            version: Version::new(msg),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ItemTail {
+    remote_addr: SocketAddr,
+    version: Version,
+}
+
+impl BincodeEncoded for ItemTail {}
+
+// the item is forwarded by the remote capture agent
+impl Encoder for Item {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut v = self.pk.to_vec();
+        v.extend_from_slice(&self.cn.encode()?);
+        let tail = ItemTail {
+            remote_addr: self.remote_addr,
+            version: self.version.clone(),
+        };
+        v.extend_from_slice(&tail.encode()?);
+        Ok(v)
+    }
+}
+
+impl Decoder for Item {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() < 44 {
+            return Err(SchemaError::DecodeError);
+        }
+        let ItemTail {
+            remote_addr,
+            version,
+        } = ItemTail::decode(&bytes[44..])?;
+        Ok(Item {
+            pk: TryFrom::try_from(&bytes[..32]).unwrap(),
+            cn: connection::Key::decode(&bytes[32..44])?,
+            remote_addr,
+            version,
+        })
+    }
+}
+
+pub struct Key(pub [u8; 32]);
+
+impl Encoder for Key {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        Ok(self.0.to_vec())
+    }
+}
+
+impl Decoder for Key {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        <[u8; 32]>::try_from(bytes)
+            .map(Key)
+            .map_err(|_| SchemaError::DecodeError)
+    }
+}
+
+/// What is seen about the peer, the recent distinct addresses and versions,
+/// the most recently seen last, and the most recent connections, each list is capped,
+/// the inbound connections come from the ephemeral ports, so the addresses are many
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Value {
+    /// the connection key of the first contact, seconds and nanos
+    pub first_seen: (u64, u32),
+    pub addresses: Vec<SocketAddr>,
+    pub versions: Vec<Version>,
+    pub connections: Vec<(u64, u32)>,
+}
+
+impl Value {
+    const MAX_CONNECTIONS: usize = 1024;
+    const MAX_ADDRESSES: usize = 64;
+    const MAX_VERSIONS: usize = 16;
+
+    pub fn new(item: &Item) -> Self {
+        Value {
+            first_seen: (item.cn.ts, item.cn.ts_nanos),
+            ..Default::default()
+        }
+    }
+
+    // the value goes last, the least recently seen goes away if the list is full
+    fn touch<T>(list: &mut Vec<T>, value: T, max: usize)
+    where
+        T: PartialEq,
+    {
+        list.retain(|v| v != &value);
+        if list.len() >= max {
+            list.remove(0);
+        }
+        list.push(value);
+    }
+
+    pub fn update(&mut self, item: Item) {
+        Self::touch(&mut self.addresses, item.remote_addr, Self::MAX_ADDRESSES);
+        Self::touch(&mut self.versions, item.version, Self::MAX_VERSIONS);
+        let cn = (item.cn.ts, item.cn.ts_nanos);
+        if !self.connections.contains(&cn) {
+            if self.connections.len() >= Self::MAX_CONNECTIONS {
+                self.connections.remove(0);
+            }
+            self.connections.push(cn);
+        }
+    }
+
+    pub fn connection_keys(&self) -> impl Iterator<Item = connection::Key> + '_ {
+        self.connections
+            .iter()
+            .map(|&(ts, ts_nanos)| connection::Key { ts, ts_nanos })
+    }
+}
+
+impl BincodeEncoded for Value {}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+    use super::{Item, Value, Version, connection};
+
+    fn item(port: u16, ts: u64, chain_name: &str) -> Item {
+        Item {
+            pk: [1; 32],
+            cn: connection::Key { ts, ts_nanos: 0 },
+            remote_addr: SocketAddr::from(([10, 0, 0, 1], port)),
+            version: Version {
+                chain_name: chain_name.to_string(),
+                distributed_db_version: 0,
+                p2p_version: 1,
+            },
+        }
+    }
+
+    #[test]
+    fn update_is_capped() {
+        let mut value = Value::new(&item(9732, 0, "main"));
+        for n in 0..1000 {
+            value.update(item(40000 + n, u64::from(n), &format!("chain {}", n % 100)));
+        }
+        assert_eq!(value.addresses.len(), Value::MAX_ADDRESSES);
+        assert_eq!(value.versions.len(), Value::MAX_VERSIONS);
+        assert_eq!(value.addresses.last().unwrap().port(), 40999);
+        assert_eq!(value.versions.last().unwrap().chain_name, "chain 99");
+        assert_eq!(value.connections.len(), 1000);
+
+        // the address seen again goes last, it is not duplicated
+        let first = value.addresses[0];
+        value.update(item(first.port(), 1000, "chain 99"));
+        assert_eq!(value.addresses.len(), Value::MAX_ADDRESSES);
+        assert_eq!(value.addresses.last(), Some(&first));
+        assert_eq!(value.addresses.iter().filter(|a| **a == first).count(), 1);
+    }
+}
+
+/// The peer with its connection history, as the api returns
+#[derive(Serialize)]
+pub struct Details {
+    pub peer_id: String,
+    pub public_key: String,
+    pub first_seen: connection::Key,
+    pub addresses: Vec<SocketAddr>,
+    pub versions: Vec<Version>,
+    pub connections: Vec<(connection::Key, connection::Value)>,
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = Key;
+    type Value = Value;
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn name() -> &'static str {
+        "peer_storage"
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+use storage::persistent::{
+    KeyValueSchema, Encoder, Decoder, SchemaError, BincodeEncoded,
+    database::RocksDbKeyValueSchema,
+};
+use super::common::Initiator;
+
+/// The saved combination of the arguments of the message and the log queries, the query refers
+/// to it by the name, the arguments given in the query take precedence
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Item {
+    #[serde(default)]
+    pub description: String,
+    pub author: Option<String>,
+    // the messages
+    pub remote_addr: Option<String>,
+    pub source_type: Option<Initiator>,
+    pub incoming: Option<bool>,
+    pub types: Option<String>,
+    pub block_hash: Option<String>,
+    pub operation_hash: Option<String>,
+    pub protocol_hash: Option<String>,
+    pub fields: Option<String>,
+    // the messages and the logs, milliseconds
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    // the logs
+    pub log_level: Option<String>,
+    pub module: Option<String>,
+    pub query: Option<String>,
+}
+
+impl Item {
+    const MAX_NAME: usize = 64;
+
+    pub fn validate_name(name: &str) -> Result<(), String> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+        if name.is_empty() || name.len() > Self::MAX_NAME || !name.chars().all(valid) {
+            return Err(format!(
+                "the name must be 1 to {} letters, digits, `-`, `_` or `.`",
+                Self::MAX_NAME,
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.from, self.to) {
+            (Some(from), Some(to)) if from > to => Err("`from` is after `to`".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl BincodeEncoded for Item {}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ItemWithName {
+    pub name: String,
+    #[serde(flatten)]
+    pub item: Item,
+}
+
+pub struct Key(pub String);
+
+impl Encoder for Key {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        Ok(self.0.as_bytes().to_vec())
+    }
+}
+
+impl Decoder for Key {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        String::from_utf8(bytes.to_vec())
+            .map(Key)
+            .map_err(|_| SchemaError::DecodeError)
+    }
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = Key;
+    type Value = Item;
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn name() -> &'static str {
+        "preset_storage"
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    convert::TryFrom,
+    net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr},
+};
+use serde::Serialize;
+use storage::persistent::{
+    KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
+};
+use rocksdb::{ColumnFamilyDescriptor, Cache, MergeOperands, Options};
+use super::{*, log_count::Count};
+
+/// Bytes of the messages of the type, which the peer sent or received in the minute,
+/// as they went over the wire, the value is updated by the merge operator as the log counts
+/// * bytes layout: `[minute(8)][incoming(1)][type(1)][addr(16)][port(2)]`
+pub struct Item {
+    pub minute: u64,
+    pub incoming: bool,
+    pub ty: MessageType,
+    pub addr: SocketAddr,
+}
+
+impl Item {
+    /// `timestamp` in milliseconds, as in the timestamp index
+    pub fn new(timestamp: u64, incoming: bool, ty: MessageType, addr: SocketAddr) -> Self {
+        Item {
+            minute: timestamp / 60_000,
+            incoming,
+            ty,
+            addr,
+        }
+    }
+
+    /// The keys of the minutes of the day are in the range
+    pub fn day_range(day: u64) -> (Vec<u8>, Vec<u8>) {
+        const MINUTES: u64 = 24 * 60;
+        let begin = (day * MINUTES).to_be_bytes().to_vec();
+        let end = ((day + 1) * MINUTES).to_be_bytes().to_vec();
+        (begin, end)
+    }
+
+    /// The name of the type, like `current_head`, or of the category if it has no kind
+    pub fn type_name(&self) -> String {
+        self.ty.name()
+    }
+}
+
+impl Encoder for Item {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut v = Vec::with_capacity(28);
+
+        let ip = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        v.extend_from_slice(&self.minute.to_be_bytes());
+        v.push(self.incoming as u8);
+        v.push(self.ty.clone().into_int());
+        v.extend_from_slice(&ip);
+        v.extend_from_slice(&self.addr.port().to_le_bytes());
+
+        Ok(v)
+    }
+}
+
+impl Decoder for Item {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() != 28 {
+            return Err(SchemaError::DecodeError);
+        }
+
+        Ok(Item {
+            minute: u64::from_be_bytes(<[u8; 8]>::try_from(&bytes[..8]).unwrap()),
+            incoming: bytes[8] != 0,
+            ty: MessageType::from_int(bytes[9]),
+            addr: {
+                let ip = <[u8; 16]>::try_from(&bytes[10..26]).unwrap();
+                let port = u16::from_le_bytes(TryFrom::try_from(&bytes[26..]).unwrap());
+                // the ipv4 address is stored mapped, it is shown as it was
+                let ip = match ip {
+                    [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                        IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+                    },
+                    ip => IpAddr::V6(Ipv6Addr::from(ip)),
+                };
+                (ip, port).into()
+            },
+        })
+    }
+}
+
+fn add(_key: &[u8], existing: Option<&[u8]>, operands: &mut MergeOperands) -> Option<Vec<u8>> {
+    let decode = |bytes: &[u8]| Count::decode(bytes).map(|c| c.0).unwrap_or(0);
+    let sum = existing.map(decode).unwrap_or(0) + operands.map(decode).sum::<i64>();
+    Some(Count::bytes(sum).to_vec())
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = Item;
+    type Value = Count;
+}
+
+impl Schema {
+    pub fn options() -> Options {
+        let mut cf_opts = Options::default();
+        cf_opts.set_merge_operator_associative("bandwidth_add", add);
+        cf_opts
+    }
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        ColumnFamilyDescriptor::new(Self::name(), Self::options())
+    }
+
+    fn name() -> &'static str {
+        "bandwidth_per_minute"
+    }
+}
+
+/// The series as the json datasource of Grafana expects it,
+/// each point is the bytes and the unix milliseconds of the minute start
+#[derive(Serialize)]
+pub struct Series {
+    pub target: String,
+    pub datapoints: Vec<(u64, u64)>,
+}
@@ -0,0 +1,145 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::convert::TryFrom;
+use serde::Serialize;
+use storage::persistent::{
+    KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
+};
+use rocksdb::{ColumnFamilyDescriptor, Cache, MergeOperands, Options};
+use super::*;
+
+/// Number of logs of the level received in the minute, the value is updated
+/// by the merge operator, so storing a log does not need to read the counter
+/// * bytes layout: `[minute(8)][level(1)]`
+pub struct Item {
+    pub minute: u64,
+    pub lv: LogLevel,
+}
+
+impl Item {
+    /// `timestamp` in milliseconds, as in the timestamp index
+    pub fn new(timestamp: u64, lv: LogLevel) -> Self {
+        Item {
+            minute: timestamp / 60_000,
+            lv,
+        }
+    }
+}
+
+impl Encoder for Item {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut v = Vec::with_capacity(9);
+
+        v.extend_from_slice(&self.minute.to_be_bytes());
+        v.push(self.lv.clone() as u8);
+
+        Ok(v)
+    }
+}
+
+impl Decoder for Item {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() != 9 {
+            return Err(SchemaError::DecodeError);
+        }
+
+        Ok(Item {
+            minute: u64::from_be_bytes(<[u8; 8]>::try_from(&bytes[..8]).unwrap()),
+            lv: LogLevel::try_from(bytes[8])
+                .map_err(|e| SchemaError::DecodeValidationError(e.to_string()))?,
+        })
+    }
+}
+
+pub struct Count(pub i64);
+
+impl Count {
+    pub fn bytes(v: i64) -> [u8; 8] {
+        v.to_le_bytes()
+    }
+}
+
+impl Encoder for Count {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        Ok(Self::bytes(self.0).to_vec())
+    }
+}
+
+impl Decoder for Count {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        let bytes = <[u8; 8]>::try_from(bytes).map_err(|_| SchemaError::DecodeError)?;
+        Ok(Count(i64::from_le_bytes(bytes)))
+    }
+}
+
+fn add(_key: &[u8], existing: Option<&[u8]>, operands: &mut MergeOperands) -> Option<Vec<u8>> {
+    let decode = |bytes: &[u8]| Count::decode(bytes).map(|c| c.0).unwrap_or(0);
+    let sum = existing.map(decode).unwrap_or(0) + operands.map(decode).sum::<i64>();
+    Some(Count::bytes(sum).to_vec())
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = Item;
+    type Value = Count;
+}
+
+impl Schema {
+    pub fn options() -> Options {
+        let mut cf_opts = Options::default();
+        cf_opts.set_merge_operator_associative("log_count_add", add);
+        cf_opts
+    }
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        ColumnFamilyDescriptor::new(Self::name(), Self::options())
+    }
+
+    fn name() -> &'static str {
+        "log_count_per_minute"
+    }
+}
+
+/// Counts by level in one minute
+#[derive(Default, Serialize)]
+pub struct LevelCounts {
+    /// unix timestamp of the minute start, in milliseconds
+    pub minute: u64,
+    pub trace: u64,
+    pub debug: u64,
+    pub info: u64,
+    pub notice: u64,
+    pub warning: u64,
+    pub error: u64,
+    pub fatal: u64,
+}
+
+impl LevelCounts {
+    pub const CSV_COLUMNS: &'static [&'static str] = &[
+        "minute", "trace", "debug", "info", "notice", "warning", "error", "fatal",
+    ];
+
+    pub fn new(minute: u64) -> Self {
+        LevelCounts {
+            minute: minute * 60_000,
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, lv: LogLevel, count: i64) {
+        let count = count.max(0) as u64;
+        match lv {
+            LogLevel::Trace => self.trace += count,
+            LogLevel::Debug => self.debug += count,
+            LogLevel::Info => self.info += count,
+            LogLevel::Notice => self.notice += count,
+            LogLevel::Warning => self.warning += count,
+            LogLevel::Error => self.error += count,
+            LogLevel::Fatal => self.fatal += count,
+        }
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::convert::TryFrom;
+use storage::persistent::{
+    KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
+};
+use rocksdb::{ColumnFamilyDescriptor, Cache, Options};
+
+/// The module name has arbitrary length, so the index stores its hash
+/// * bytes layout: `[module_hash(8)][index(8)]`
+pub struct Item {
+    pub module_hash: u64,
+    pub index: u64,
+}
+
+impl Item {
+    pub fn new(module: &str, index: u64) -> Self {
+        Item {
+            module_hash: hash(module),
+            index,
+        }
+    }
+}
+
+/// FNV-1a, must be stable between runs, because the hash is persisted
+fn hash(module: &str) -> u64 {
+    module
+        .to_lowercase()
+        .bytes()
+        .fold(0xcbf29ce484222325, |h, b| {
+            (h ^ (b as u64)).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// The full module path and each of its segments, so `validator` finds `shell::validator`
+/// and `validator` finds `validator.block` in octez sections
+pub fn segments(section: &str) -> impl Iterator<Item = &str> {
+    let mut segments = section
+        .split(|c| c == ':' || c == '.')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    if segments.len() > 1 {
+        segments.push(section);
+    }
+    segments.sort_unstable();
+    segments.dedup();
+    segments.into_iter()
+}
+
+impl Encoder for Item {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut v = Vec::with_capacity(16);
+
+        v.extend_from_slice(&self.module_hash.to_be_bytes());
+        v.extend_from_slice(&self.index.to_be_bytes());
+
+        Ok(v)
+    }
+}
+
+impl Decoder for Item {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() != 16 {
+            return Err(SchemaError::DecodeError);
+        }
+
+        Ok(Item {
+            module_hash: u64::from_be_bytes(<[u8; 8]>::try_from(&bytes[..8]).unwrap()),
+            index: u64::from_be_bytes(<[u8; 8]>::try_from(&bytes[8..]).unwrap()),
+        })
+    }
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = Item;
+    type Value = ();
+}
+
+impl Schema {
+    pub fn options() -> Options {
+        use rocksdb::SliceTransform;
+
+        let mut cf_opts = Options::default();
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(8));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        cf_opts
+    }
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        ColumnFamilyDescriptor::new(Self::name(), Self::options())
+    }
+
+    fn name() -> &'static str {
+        "log_module_secondary_index"
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{convert::TryFrom, fmt};
+use serde::{Serialize, Deserialize};
+use storage::persistent::{
+    KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
+};
+use rocksdb::{ColumnFamilyDescriptor, Cache, Options};
+use crypto::hash::HashType;
+use tezos_messages::p2p::{
+    binary_message::MessageHash,
+    encoding::peer::{PeerMessage, PeerMessageResponse},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HashKind {
+    Block,
+    Operation,
+    Protocol,
+}
+
+impl HashKind {
+    fn hash_type(&self) -> HashType {
+        match self {
+            HashKind::Block => HashType::BlockHash,
+            HashKind::Operation => HashType::OperationHash,
+            HashKind::Protocol => HashType::ProtocolHash,
+        }
+    }
+}
+
+/// The hash of a block, an operation or a protocol the message mentions
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ContentHash {
+    pub kind: HashKind,
+    pub hash: [u8; 32],
+}
+
+impl ContentHash {
+    fn new(kind: HashKind, hash: &[u8]) -> Option<Self> {
+        Some(ContentHash {
+            kind,
+            hash: <[u8; 32]>::try_from(hash).ok()?,
+        })
+    }
+
+    /// Parses the base58 representation, like `BL...`, `oo...` or `Ps...`
+    pub fn parse(kind: HashKind, s: &str) -> Result<Self, String> {
+        let hash = kind
+            .hash_type()
+            .b58check_to_hash(s)
+            .map_err(|e| format!("bad {:?} hash {}: {}", kind, s, e))?;
+        ContentHash::new(kind, &hash).ok_or_else(|| format!("bad {:?} hash {}", kind, s))
+    }
+
+    /// The hashes referenced by the decoded peer message,
+    /// the hashes of the block headers, operations and protocols it carries are included
+    pub fn referenced(message: &PeerMessageResponse) -> Vec<Self> {
+        use self::HashKind::{Block, Operation, Protocol};
+
+        let mut hashes = Vec::new();
+        let mut push = |kind, hash: &[u8]| {
+            if let Some(h) = ContentHash::new(kind, hash) {
+                if !hashes.contains(&h) {
+                    hashes.push(h);
+                }
+            }
+        };
+
+        match message.message() {
+            PeerMessage::CurrentBranch(m) => {
+                let header = m.current_branch().current_head();
+                push(Block, &computed(Block, header.message_hash()));
+                push(Block, &header.predecessor().0);
+                for hash in m.current_branch().history() {
+                    push(Block, &hash.0);
+                }
+            },
+            PeerMessage::CurrentHead(m) => {
+                let header = m.current_block_header();
+                push(Block, &computed(Block, header.message_hash()));
+                push(Block, &header.predecessor().0);
+                let mempool = m.current_mempool();
+                for hash in mempool.known_valid().iter().chain(mempool.pending()) {
+                    push(Operation, &hash.0);
+                }
+            },
+            PeerMessage::GetBlockHeaders(m) => {
+                for hash in m.get_block_headers() {
+                    push(Block, &hash.0);
+                }
+            },
+            PeerMessage::BlockHeader(m) => {
+                let header = m.block_header();
+                push(Block, &computed(Block, header.message_hash()));
+                push(Block, &header.predecessor().0);
+            },
+            PeerMessage::GetOperations(m) => {
+                for hash in m.get_operations() {
+                    push(Operation, &hash.0);
+                }
+            },
+            PeerMessage::Operation(m) => {
+                let operation = m.operation();
+                push(Operation, &computed(Operation, operation.message_hash()));
+                push(Block, &operation.branch().0);
+            },
+            PeerMessage::GetProtocols(m) => {
+                for hash in m.get_protocols() {
+                    push(Protocol, &hash.0);
+                }
+            },
+            PeerMessage::Protocol(m) => {
+                push(Protocol, &computed(Protocol, m.protocol().message_hash()));
+            },
+            PeerMessage::GetOperationHashesForBlocks(m) => {
+                for block in m.get_operation_hashes_for_blocks() {
+                    push(Block, &block.hash().0);
+                }
+            },
+            PeerMessage::OperationHashesForBlock(m) => {
+                push(Block, &m.operation_hashes_for_block().hash().0);
+                for hash in m.operation_hashes() {
+                    push(Operation, &hash.0);
+                }
+            },
+            PeerMessage::GetOperationsForBlocks(m) => {
+                for block in m.get_operations_for_blocks() {
+                    push(Block, &block.hash().0);
+                }
+            },
+            PeerMessage::OperationsForBlocks(m) => {
+                push(Block, &m.operations_for_block().hash().0);
+                for operation in m.operations() {
+                    push(Operation, &computed(Operation, operation.message_hash()));
+                }
+            },
+            _ => (),
+        }
+        hashes
+    }
+}
+
+fn computed<E>(kind: HashKind, hash: Result<Vec<u8>, E>) -> Vec<u8>
+where
+    E: fmt::Debug,
+{
+    hash.unwrap_or_else(|error| {
+        log::warn!("cannot calculate the {:?} hash: {:?}", kind, error);
+        vec![]
+    })
+}
+
+/// WARNING: this index work only with 56 bit index, should be enough
+/// * bytes layout: `[kind(1)][hash(32)][index(7)]`
+pub struct Item {
+    pub hash: ContentHash,
+    pub index: u64,
+}
+
+impl Encoder for Item {
+    fn encode(&self) -> Result<Vec<u8>, SchemaError> {
+        let mut v = Vec::with_capacity(40);
+        v.push(self.hash.kind as u8);
+        v.extend_from_slice(&self.hash.hash);
+        v.extend_from_slice(&self.index.to_be_bytes()[1..]);
+        Ok(v)
+    }
+}
+
+impl Decoder for Item {
+    fn decode(bytes: &[u8]) -> Result<Self, SchemaError> {
+        if bytes.len() != 40 {
+            return Err(SchemaError::DecodeError);
+        }
+
+        let kind = match bytes[0] {
+            0 => HashKind::Block,
+            1 => HashKind::Operation,
+            2 => HashKind::Protocol,
+            _ => return Err(SchemaError::DecodeError),
+        };
+        Ok(Item {
+            hash: ContentHash {
+                kind,
+                hash: <[u8; 32]>::try_from(&bytes[1..33]).unwrap(),
+            },
+            index: {
+                let mut index = <[u8; 8]>::try_from(&bytes[32..]).unwrap();
+                index[0] = 0;
+                u64::from_be_bytes(index)
+            },
+        })
+    }
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = Item;
+    type Value = ();
+}
+
+impl Schema {
+    pub fn options() -> Options {
+        use rocksdb::{SliceTransform, BlockBasedOptions};
+
+        // most of the requested hashes are never seen, the bloom filter of the prefix
+        // `[kind][hash]` in each table file answers the miss without reading the data blocks
+        let mut table_opts = BlockBasedOptions::default();
+        table_opts.set_bloom_filter(10, false);
+        table_opts.set_cache_index_and_filter_blocks(true);
+
+        let mut cf_opts = Options::default();
+        cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(33));
+        cf_opts.set_memtable_prefix_bloom_ratio(0.2);
+        cf_opts.set_block_based_table_factory(&table_opts);
+        cf_opts
+    }
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        ColumnFamilyDescriptor::new(Self::name(), Self::options())
+    }
+
+    fn name() -> &'static str {
+        "message_hash_secondary_index"
+    }
+}
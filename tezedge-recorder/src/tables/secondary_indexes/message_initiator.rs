@@ -5,7 +5,7 @@ use std::convert::TryFrom;
 use storage::persistent::{
     KeyValueSchema, Encoder, Decoder, SchemaError, database::RocksDbKeyValueSchema,
 };
-use rocksdb::{ColumnFamilyDescriptor, Cache};
+use rocksdb::{ColumnFamilyDescriptor, Cache, Options};
 use super::*;
 
 /// WARNING: this index work only with 56 bit index, should be enough
@@ -46,14 +46,20 @@ impl KeyValueSchema for Schema {
     type Value = ();
 }
 
-impl RocksDbKeyValueSchema for Schema {
-    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
-        use rocksdb::{Options, SliceTransform};
+impl Schema {
+    pub fn options() -> Options {
+        use rocksdb::SliceTransform;
 
         let mut cf_opts = Options::default();
         cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(1));
         cf_opts.set_memtable_prefix_bloom_ratio(0.2);
-        ColumnFamilyDescriptor::new(Self::name(), cf_opts)
+        cf_opts
+    }
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn descriptor(_cache: &Cache) -> ColumnFamilyDescriptor {
+        ColumnFamilyDescriptor::new(Self::name(), Self::options())
     }
 
     fn name() -> &'static str {
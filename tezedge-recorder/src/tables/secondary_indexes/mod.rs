@@ -10,5 +10,9 @@ pub mod message_ty;
 pub mod message_sender;
 pub mod message_initiator;
 pub mod message_addr;
+pub mod message_hash;
 pub mod timestamp;
 pub mod log_level;
+pub mod log_module;
+pub mod log_count;
+pub mod bandwidth;
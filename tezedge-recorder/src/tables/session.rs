@@ -0,0 +1,109 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{net::SocketAddr, ops::Range};
+use serde::{Serialize, Deserialize};
+use storage::persistent::{BincodeEncoded, KeyValueSchema, database::RocksDbKeyValueSchema};
+use super::{common::Initiator, message, node_log, connection};
+
+/// What is known about the environment when the session starts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Info {
+    pub name: String,
+    pub node_version: Option<String>,
+    pub debugger_version: String,
+    pub config_hash: String,
+}
+
+/// The session owns the messages and the logs stored while it runs,
+/// they are tagged by the ranges of their indexes, the end is `None` while the session runs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Item {
+    #[serde(flatten)]
+    pub info: Info,
+    /// unix milliseconds
+    pub started: u64,
+    pub stopped: Option<u64>,
+    pub messages: (u64, Option<u64>),
+    pub logs: (u64, Option<u64>),
+}
+
+impl Item {
+    pub fn new(info: Info, message_counter: u64, log_counter: u64) -> Self {
+        Item {
+            info,
+            started: now(),
+            stopped: None,
+            messages: (message_counter, None),
+            logs: (log_counter, None),
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.stopped.is_none()
+    }
+
+    pub fn stop(&mut self, message_counter: u64, log_counter: u64) {
+        self.stopped = Some(now());
+        self.messages.1 = Some(message_counter);
+        self.logs.1 = Some(log_counter);
+    }
+
+    /// The indexes of the messages, up to the `message_counter` if the session runs
+    pub fn message_range(&self, message_counter: u64) -> Range<u64> {
+        self.messages.0..self.messages.1.unwrap_or(message_counter)
+    }
+
+    pub fn log_range(&self, log_counter: u64) -> Range<u64> {
+        self.logs.0..self.logs.1.unwrap_or(log_counter)
+    }
+}
+
+fn now() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+impl BincodeEncoded for Item {}
+
+#[derive(Serialize)]
+pub struct ItemWithId {
+    pub id: u64,
+    #[serde(flatten)]
+    pub item: Item,
+}
+
+/// Everything the session recorded, the messages are decoded
+#[derive(Serialize)]
+pub struct Export {
+    pub session: ItemWithId,
+    pub messages: Vec<message::MessageDetails>,
+    pub logs: Vec<node_log::ItemWithId>,
+}
+
+/// The connection messages of both sides as they went over the wire,
+/// the nonces and the key of the connection are derived from them
+pub struct Handshake {
+    pub cn_id: connection::Key,
+    pub remote_addr: SocketAddr,
+    pub initiator: Initiator,
+    pub local: Vec<u8>,
+    pub remote: Vec<u8>,
+}
+
+pub struct Schema;
+
+impl KeyValueSchema for Schema {
+    type Key = u64;
+    type Value = Item;
+}
+
+impl RocksDbKeyValueSchema for Schema {
+    fn name() -> &'static str {
+        "session_storage"
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+};
+use bpf_recorder::{PacketCapture, PacketHeader, SocketId, tcp_flags};
+use serde::{Serialize, Deserialize};
+use super::tables::connection::TcpStats;
+
+/// The headers of the tcp packets of the recorded connections are captured from the interfaces
+/// and aggregated per connection, disabled without the `tcp_metadata` section, the recorder
+/// needs `CAP_NET_RAW` and must run in the network namespace of the node
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcpMetaConfig {
+    /// like `["eth0", "eth1"]`, every interface by default, or if the list has `any`
+    interfaces: Option<Vec<String>>,
+    /// the sockets per interface, the kernel spreads the connections among them, 1 by default
+    workers: Option<usize>,
+}
+
+impl TcpMetaConfig {
+    // `None` is every interface
+    fn interfaces(&self) -> Vec<Option<&str>> {
+        match &self.interfaces {
+            Some(names) if !names.is_empty() && !names.iter().any(|name| name == "any") => {
+                names.iter().map(|name| Some(name.as_str())).collect()
+            },
+            _ => vec![None],
+        }
+    }
+
+    fn workers(&self) -> usize {
+        self.workers.unwrap_or(1).max(1)
+    }
+}
+
+struct Entry {
+    remote: SocketAddr,
+    stats: TcpStats,
+    // the packets came since the last snapshot
+    dirty: bool,
+}
+
+#[derive(Default)]
+struct Inner {
+    by_addr: HashMap<SocketAddr, SocketId>,
+    entries: HashMap<SocketId, Entry>,
+    // the handshake of the tcp is done before the recorder learns about the connection,
+    // the recent syns are kept, they carry the options
+    syns: VecDeque<PacketHeader>,
+    // the names of the interfaces by the index
+    names: HashMap<i32, String>,
+}
+
+impl Inner {
+    fn add(
+        names: &mut HashMap<i32, String>,
+        stats: &mut TcpStats,
+        incoming: bool,
+        header: &PacketHeader,
+    ) {
+        stats.add(incoming, header);
+        let name = names.entry(header.interface).or_insert_with(|| {
+            PacketCapture::interface_name(header.interface)
+                .unwrap_or_else(|| header.interface.to_string())
+        });
+        stats.add_interface(incoming, name);
+    }
+}
+
+/// The connection is found by the remote address, the packets of the connections
+/// which are not recorded are dropped
+#[derive(Default)]
+pub struct TcpMeta {
+    inner: Mutex<Inner>,
+}
+
+impl TcpMeta {
+    const MAX_SYNS: usize = 0x400;
+
+    /// One thread per socket, `workers` sockets per interface share its packets,
+    /// each connection is handled by one of them
+    pub fn spawn(
+        config: &TcpMetaConfig,
+        running: Arc<AtomicBool>,
+    ) -> io::Result<(Arc<Self>, Vec<thread::JoinHandle<()>>)> {
+        let workers = config.workers();
+        let mut captures = vec![];
+        for (i, interface) in config.interfaces().into_iter().enumerate() {
+            // the fanout group is unique in the namespace
+            let group = (std::process::id() as u16).wrapping_add(i as u16);
+            for worker in 0..workers {
+                let name = format!("{}-{}", interface.unwrap_or("any"), worker);
+                let capture = PacketCapture::open(interface)?;
+                if workers > 1 {
+                    capture.join_fanout(group)?;
+                }
+                captures.push((name, capture));
+            }
+        }
+        let meta = Arc::new(TcpMeta::default());
+        let mut handles = vec![];
+        for (name, mut capture) in captures {
+            let meta = meta.clone();
+            let running = running.clone();
+            let handle = thread::Builder::new()
+                .name(format!("tcp-metadata-{}", name))
+                .spawn(move || {
+                    while running.load(Ordering::Relaxed) {
+                        match capture.recv() {
+                            Ok(Some(header)) => meta.packet(&header),
+                            Ok(None) => (),
+                            Err(error) => {
+                                log::error!("tcp metadata capture on {} failed: {}", name, error);
+                                break;
+                            },
+                        }
+                    }
+                })?;
+            handles.push(handle);
+        }
+        Ok((meta, handles))
+    }
+
+    pub fn watch(&self, socket_id: SocketId, remote: SocketAddr) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.by_addr.insert(remote, socket_id);
+        let mut entry = Entry {
+            remote,
+            stats: TcpStats::default(),
+            dirty: false,
+        };
+        let Inner { syns, names, .. } = &mut *inner;
+        for syn in syns.iter() {
+            if syn.source == remote {
+                Inner::add(names, &mut entry.stats, true, syn);
+            } else if syn.destination == remote {
+                Inner::add(names, &mut entry.stats, false, syn);
+            }
+        }
+        syns.retain(|syn| syn.source != remote && syn.destination != remote);
+        if let Some(old) = inner.entries.insert(socket_id, entry) {
+            if old.remote != remote {
+                inner.by_addr.remove(&old.remote);
+            }
+        }
+    }
+
+    fn packet(&self, header: &PacketHeader) {
+        let mut inner = self.inner.lock().unwrap();
+        let (socket_id, incoming) = match inner.by_addr.get(&header.source) {
+            Some(socket_id) => (*socket_id, true),
+            None => match inner.by_addr.get(&header.destination) {
+                Some(socket_id) => (*socket_id, false),
+                None => {
+                    if header.flags & tcp_flags::SYN != 0 {
+                        if inner.syns.len() == Self::MAX_SYNS {
+                            inner.syns.pop_front();
+                        }
+                        inner.syns.push_back(header.clone());
+                    }
+                    return;
+                },
+            },
+        };
+        let Inner { entries, names, .. } = &mut *inner;
+        if let Some(entry) = entries.get_mut(&socket_id) {
+            Inner::add(names, &mut entry.stats, incoming, header);
+            entry.dirty = true;
+        }
+    }
+
+    /// The connections which got the packets since the last snapshot
+    pub fn snapshot(&self) -> Vec<(SocketId, TcpStats)> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .entries
+            .iter_mut()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(socket_id, entry)| {
+                entry.dirty = false;
+                (*socket_id, entry.stats.clone())
+            })
+            .collect()
+    }
+
+    /// The connection is closed, `None` if it is not watched or got no packets
+    pub fn take(&self, socket_id: &SocketId) -> Option<TcpStats> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.remove(socket_id)?;
+        if inner.by_addr.get(&entry.remote) == Some(socket_id) {
+            inner.by_addr.remove(&entry.remote);
+        }
+        Some(entry.stats).filter(|s| s.incoming.packets != 0 || s.outgoing.packets != 0)
+    }
+}
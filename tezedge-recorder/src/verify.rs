@@ -0,0 +1,152 @@
+// Copyright (c) SimpleStaking, Viable Systems and Tezedge Contributors
+// SPDX-License-Identifier: MIT
+
+use std::path::Path;
+use serde::Serialize;
+use super::{
+    system::Identity,
+    processor::Keys,
+    common::Sender,
+    tables::chunk,
+    database::{
+        DatabaseNew, DatabaseFetch, ConnectionsFilter, ChunksFilter,
+        rocks::{self, DbError},
+    },
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Divergence {
+    /// the raw bytes cannot be decrypted with the nonce of the chunk
+    DecryptionFailed,
+    /// decrypted, but the stored plaintext is different
+    PlaintextDiffers,
+    /// decrypted, but the recorder stored no plaintext
+    PlaintextMissing,
+}
+
+#[derive(Serialize)]
+pub struct Diverged {
+    pub connection: String,
+    pub sender: Sender,
+    pub counter: u64,
+    pub divergence: Divergence,
+}
+
+/// The connection which cannot be checked, it has not both connection messages,
+/// or the keys cannot be derived from them
+#[derive(Serialize)]
+pub struct Skipped {
+    pub connection: String,
+    pub reason: String,
+}
+
+#[derive(Default, Serialize)]
+pub struct Report {
+    pub connections: u64,
+    pub chunks: u64,
+    pub skipped: Vec<Skipped>,
+    pub diverged: Vec<Diverged>,
+}
+
+/// Derives the keys of each connection recorded in the database at `db` from its connection
+/// messages again, decrypts every chunk from its raw bytes and compares the result with
+/// the stored plaintext, only the connection `connection` if it is given, the `identity` must be
+/// the identity of the node which recorded the database, the recorder must not run on it
+pub fn run<P>(db: P, identity: &Identity, connection: Option<&str>) -> Result<Report, DbError>
+where
+    P: AsRef<Path>,
+{
+    let db = rocks::Db::open(db, false, None, None, None, Some(0), None)?;
+
+    let filter = ConnectionsFilter {
+        limit: Some(u64::MAX),
+        nack_motive: None,
+        termination: None,
+        handshake_slower_than: None,
+        after: None,
+        external: None,
+    };
+    let mut report = Report::default();
+    for (key, value) in db.fetch_connections(&filter)? {
+        let cn_id = key.to_string();
+        if connection.map_or(false, |c| c != cn_id) {
+            continue;
+        }
+        report.connections += 1;
+
+        let filter = ChunksFilter {
+            limit: Some(u64::MAX),
+            cn: Some(cn_id.clone()),
+            sender: None,
+            node_name: None,
+        };
+        let chunks = db.fetch_chunks_truncated(&filter)?;
+        report.chunks += chunks.len() as u64;
+
+        let first = |incoming: bool| {
+            chunks
+                .iter()
+                .find(|(k, _)| k.counter == 0 && k.sender.incoming() == incoming)
+                .map(|(_, chunk::ValueTruncated(v))| &v.bytes[..])
+        };
+        let keys = match (first(false), first(true)) {
+            (Some(local), Some(remote)) => {
+                Keys::new(identity, local, remote, value.initiator().clone())
+                    .map_err(|error| error.to_string())
+            },
+            _ => Err("no connection message".to_string()),
+        };
+        let mut keys = match keys {
+            Ok(keys) => keys,
+            Err(reason) => {
+                report.skipped.push(Skipped {
+                    connection: cn_id,
+                    reason,
+                });
+                continue;
+            },
+        };
+
+        // the counter of the next chunk of each side, the nonce follows it,
+        // so the chunks missing in the database do not break the rest
+        let mut next = [1, 1];
+        for (key, chunk::ValueTruncated(value)) in chunks {
+            if key.counter == 0 {
+                continue;
+            }
+            let incoming = key.sender.incoming();
+            let (side_key, next) = if incoming {
+                (&mut keys.remote, &mut next[1])
+            } else {
+                (&mut keys.local, &mut next[0])
+            };
+            side_key.skip(key.counter - *next);
+            *next = key.counter + 1;
+
+            let decrypted = if value.bytes.len() < 2 {
+                None
+            } else {
+                side_key.decrypt(&value.bytes).ok()
+            };
+            let divergence = match decrypted {
+                // the failed decryption does not move the nonce
+                None => {
+                    side_key.skip(1);
+                    Divergence::DecryptionFailed
+                },
+                Some(plain) if plain == value.plain => continue,
+                Some(_) if value.plain.is_empty() => Divergence::PlaintextMissing,
+                Some(_) => Divergence::PlaintextDiffers,
+            };
+            report.diverged.push(Diverged {
+                connection: cn_id.clone(),
+                sender: key.sender,
+                counter: key.counter,
+                divergence,
+            });
+        }
+    }
+
+    Ok(report)
+}
@@ -40,5 +40,14 @@ run_recorder
 ./target/none/release/pseudonode p2p-initiator 29732 29733 && wait $RESPONDER_PID && sleep 5
 ./target/none/release/deps/p2p-???????????????? --nocapture check_messages || fail
 ./target/none/release/pseudonode log 2 && sleep 4 # populate words log messages
-./target/none/release/deps/log-???????????????? --nocapture full_text_search || fail
+./target/none/release/deps/log-???????????????? --nocapture full_text_search counts || fail
 stop_recorder
+
+# the store limit removes the oldest logs at the start and while storing, the counts follow
+export LOG_STORE_LIMIT=5000
+run_recorder
+./target/none/release/deps/log-???????????????? --nocapture counts || fail
+./target/none/release/pseudonode log 2 && sleep 4
+./target/none/release/deps/log-???????????????? --nocapture counts || fail
+stop_recorder
+unset LOG_STORE_LIMIT
@@ -183,3 +183,55 @@ async fn full_text_search() {
         case.run().await;
     }
 }
+
+#[tokio::test]
+async fn counts() {
+    use std::collections::BTreeMap;
+    use tezedge_recorder::tables::node_log::LogLevel;
+
+    fn level_name(level: LogLevel) -> &'static str {
+        match level {
+            LogLevel::Trace => "trace",
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Notice => "notice",
+            LogLevel::Warning => "warning",
+            LogLevel::Error => "error",
+            LogLevel::Fatal => "fatal",
+        }
+    }
+
+    // count the stored logs per minute and level, walking backward from the last one
+    let mut expected = BTreeMap::<(u64, &str), u64>::new();
+    let last = get_log("limit=1").await.unwrap();
+    let mut cursor = last.last().unwrap().id;
+    loop {
+        let items = get_log(&format!("cursor={}&limit=1000", cursor)).await.unwrap();
+        for item in &items {
+            let minute = (item.timestamp / 1_000_000) as u64 / 60_000 * 60_000;
+            *expected.entry((minute, level_name(item.level.clone()))).or_default() += 1;
+        }
+        match items.iter().map(|item| item.id).min() {
+            Some(id) if id > 0 => cursor = id - 1,
+            _ => break,
+        }
+    }
+    assert!(!expected.is_empty());
+
+    let debugger = env::var("DEBUGGER_URL").unwrap();
+    let counts = reqwest::get(&format!("{}/v2/log/counts?node_name=initiator", debugger))
+        .await.unwrap()
+        .json::<Vec<serde_json::Value>>()
+        .await.unwrap();
+    let mut actual = BTreeMap::<(u64, &str), u64>::new();
+    for count in counts {
+        let minute = count["minute"].as_u64().unwrap();
+        for &level in &["trace", "debug", "info", "notice", "warning", "error", "fatal"] {
+            let n = count[level].as_u64().unwrap();
+            if n != 0 {
+                actual.insert((minute, level), n);
+            }
+        }
+    }
+    assert_eq!(actual, expected);
+}